@@ -0,0 +1,79 @@
+// Copyright 2024 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Compares the bit-by-bit and bulk paths of the bit writer when
+//! re-packaging slice payloads with emulation prevention.
+//!
+//! Run with `cargo bench --bench bit_writer`.
+
+use criterion::criterion_group;
+use criterion::criterion_main;
+use criterion::BenchmarkId;
+use criterion::Criterion;
+use criterion::Throughput;
+use cros_codecs::codec::common::bit_writer::BitWriter;
+
+const PAYLOAD_SIZE: usize = 1 << 20;
+
+/// Generates a pseudo-random payload with the given ratio of zero bytes.
+fn payload(zero_percent: u32) -> Vec<u8> {
+    let mut state = 0x1234_5678u32;
+    (0..PAYLOAD_SIZE)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            if state % 100 < zero_percent {
+                0x00
+            } else {
+                (state >> 24) as u8
+            }
+        })
+        .collect()
+}
+
+/// Writing of payloads with an increasing ratio of zero bytes, byte by byte and in bulk.
+fn bit_writer(c: &mut Criterion) {
+    let mut group = c.benchmark_group("bit_writer");
+    group.throughput(Throughput::Bytes(PAYLOAD_SIZE as u64));
+    let mut out = Vec::with_capacity(PAYLOAD_SIZE * 2);
+
+    for zero_percent in [0, 1, 10, 50] {
+        let input = payload(zero_percent);
+
+        group.bench_with_input(
+            BenchmarkId::new("write_f", format!("{zero_percent}% zeros")),
+            &input,
+            |b, input| {
+                b.iter(|| {
+                    out.clear();
+                    let mut writer = BitWriter::new(&mut out, true);
+                    for byte in input {
+                        writer.write_f(8, *byte).unwrap();
+                    }
+                    drop(writer);
+                    out.len()
+                })
+            },
+        );
+        group.bench_with_input(
+            BenchmarkId::new("write_bytes", format!("{zero_percent}% zeros")),
+            &input,
+            |b, input| {
+                b.iter(|| {
+                    out.clear();
+                    let mut writer = BitWriter::new(&mut out, true);
+                    writer.write_bytes(input).unwrap();
+                    drop(writer);
+                    out.len()
+                })
+            },
+        );
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, bit_writer);
+criterion_main!(benches);
@@ -0,0 +1,224 @@
+// Copyright 2024 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Throughput of the bitstream parsers and synthesizers on the test corpora of the crate.
+//!
+//! Run with `cargo bench --bench parsers`, optionally followed by a filter such as `-- vp9`.
+
+use std::hint::black_box;
+use std::io::Cursor;
+
+use criterion::criterion_group;
+use criterion::criterion_main;
+use criterion::BenchmarkId;
+use criterion::Criterion;
+use criterion::Throughput;
+use cros_codecs::codec::av1::parser as av1;
+use cros_codecs::codec::common::bit_reader::BitReader;
+use cros_codecs::codec::common::bit_writer::BitWriter;
+use cros_codecs::codec::fuzz::parse_av1_obus;
+use cros_codecs::codec::fuzz::FuzzTarget;
+use cros_codecs::codec::h264::parser as h264;
+use cros_codecs::codec::h264::synthesizer::Synthesizer;
+use cros_codecs::codec::vp9::parser as vp9;
+use cros_codecs::utils::corpus::load_corpus;
+use cros_codecs::utils::corpus::CorpusStream;
+
+fn corpus(target: FuzzTarget) -> Vec<CorpusStream> {
+    load_corpus(target).expect("failed to load the test corpus")
+}
+
+/// Splitting of Annex B streams into NAL units, and parsing of their headers as a decoder does.
+fn nal_parsing(c: &mut Criterion) {
+    let mut group = c.benchmark_group("nal_parsing");
+
+    for target in [FuzzTarget::H264, FuzzTarget::H265] {
+        for stream in corpus(target) {
+            group.throughput(Throughput::Bytes(stream.data.len() as u64));
+
+            group.bench_with_input(
+                BenchmarkId::new("split", &stream.name),
+                &stream,
+                |b, stream| b.iter(|| stream.units().len()),
+            );
+            group.bench_with_input(
+                BenchmarkId::new("headers", &stream.name),
+                &stream,
+                |b, stream| b.iter(|| target.parse(black_box(&stream.data))),
+            );
+        }
+    }
+
+    group.finish();
+}
+
+/// Synthesis of the parameter sets of the H.264 corpus.
+fn h264_synthesis(c: &mut Criterion) {
+    let mut group = c.benchmark_group("h264_synthesis");
+    let mut out = Vec::new();
+
+    for stream in corpus(FuzzTarget::H264) {
+        let mut parser = h264::Parser::default();
+        let mut cursor = Cursor::new(stream.data.as_slice());
+        let mut sps = None;
+        let mut pps = None;
+
+        while let Ok(nalu) = h264::Nalu::next(&mut cursor) {
+            match nalu.header.type_ {
+                h264::NaluType::Sps if sps.is_none() => {
+                    sps = parser.parse_sps(&nalu).ok().cloned();
+                }
+                h264::NaluType::Pps if pps.is_none() => {
+                    let pps_id = parser
+                        .parse_pps(&nalu)
+                        .ok()
+                        .map(|pps| pps.pic_parameter_set_id);
+                    pps = pps_id.and_then(|id| parser.get_pps(id)).cloned();
+                }
+                _ => (),
+            }
+        }
+
+        let (Some(sps), Some(pps)) = (sps, pps) else {
+            continue;
+        };
+
+        group.throughput(Throughput::Elements(1));
+        group.bench_function(BenchmarkId::new("sps", &stream.name), |b| {
+            b.iter(|| {
+                out.clear();
+                Synthesizer::<h264::Sps, _>::synthesize(3, &sps, &mut out, true).unwrap();
+                out.len()
+            })
+        });
+        group.bench_function(BenchmarkId::new("pps", &stream.name), |b| {
+            b.iter(|| {
+                out.clear();
+                Synthesizer::<h264::Pps, _>::synthesize(3, &pps, &mut out, true).unwrap();
+                out.len()
+            })
+        });
+    }
+
+    group.finish();
+}
+
+/// Insertion and removal of emulation prevention bytes on the slice data of the H.264 corpus.
+fn emulation_prevention(c: &mut Criterion) {
+    let mut group = c.benchmark_group("emulation_prevention");
+    let mut out = Vec::new();
+
+    for stream in corpus(FuzzTarget::H264) {
+        // Skip the start codes and NAL headers.
+        let payload = stream
+            .units()
+            .into_iter()
+            .filter_map(|unit| {
+                let start = unit.windows(3).position(|w| w == [0, 0, 1])? + 4;
+                unit.get(start..)
+            })
+            .flatten()
+            .copied()
+            .collect::<Vec<_>>();
+
+        group.throughput(Throughput::Bytes(payload.len() as u64));
+        group.bench_with_input(
+            BenchmarkId::new("insert", &stream.name),
+            &payload,
+            |b, payload| {
+                b.iter(|| {
+                    out.clear();
+                    let mut writer = BitWriter::new(&mut out, true);
+                    writer.write_bytes(payload).unwrap();
+                    drop(writer);
+                    out.len()
+                })
+            },
+        );
+        group.bench_with_input(
+            BenchmarkId::new("remove", &stream.name),
+            &payload,
+            |b, payload| {
+                b.iter(|| {
+                    let mut reader = BitReader::new(payload, true);
+                    let mut sum = 0u32;
+                    while let Ok(byte) = reader.read_bits::<u32>(8) {
+                        sum = sum.wrapping_add(byte);
+                    }
+                    sum
+                })
+            },
+        );
+    }
+
+    group.finish();
+}
+
+/// Parsing of the uncompressed headers of every frame of the VP9 corpus.
+fn vp9_headers(c: &mut Criterion) {
+    let mut group = c.benchmark_group("vp9_headers");
+
+    for stream in corpus(FuzzTarget::Vp9) {
+        let frames = stream.units();
+        group.throughput(Throughput::Bytes(
+            frames.iter().map(|f| f.len() as u64).sum(),
+        ));
+
+        group.bench_with_input(
+            BenchmarkId::from_parameter(&stream.name),
+            &frames,
+            |b, frames| {
+                b.iter(|| {
+                    let mut parser = vp9::Parser::default();
+                    frames
+                        .iter()
+                        .filter_map(|frame| parser.parse_chunk(frame).ok())
+                        .map(|frames| frames.len())
+                        .sum::<usize>()
+                })
+            },
+        );
+    }
+
+    group.finish();
+}
+
+/// Parsing of the OBUs of every temporal unit of the AV1 corpus.
+fn av1_headers(c: &mut Criterion) {
+    let mut group = c.benchmark_group("av1_headers");
+
+    for stream in corpus(FuzzTarget::Av1) {
+        let temporal_units = stream.units();
+        group.throughput(Throughput::Bytes(
+            temporal_units.iter().map(|tu| tu.len() as u64).sum(),
+        ));
+
+        group.bench_with_input(
+            BenchmarkId::from_parameter(&stream.name),
+            &temporal_units,
+            |b, temporal_units| {
+                b.iter(|| {
+                    // The parser keeps the sequence header and reference state across units.
+                    let mut parser = av1::Parser::default();
+                    temporal_units
+                        .iter()
+                        .map(|tu| parse_av1_obus(&mut parser, tu))
+                        .sum::<usize>()
+                })
+            },
+        );
+    }
+
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    nal_parsing,
+    h264_synthesis,
+    emulation_prevention,
+    vp9_headers,
+    av1_headers
+);
+criterion_main!(benches);
@@ -0,0 +1,127 @@
+// Copyright 2024 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+// C API of cros-codecs. See capi/src/lib.rs for the documentation of each item.
+
+#ifndef CROS_CODECS_H_
+#define CROS_CODECS_H_
+
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+#define CC_MAX_PLANES 4
+
+#define CC_CODEC_H264 0
+#define CC_CODEC_H265 1
+#define CC_CODEC_VP8 2
+#define CC_CODEC_VP9 3
+#define CC_CODEC_AV1 4
+
+typedef enum {
+  CC_STATUS_OK = 0,
+  CC_STATUS_INVALID_ARGUMENT = -1,
+  CC_STATUS_UNSUPPORTED = -2,
+  CC_STATUS_OUT_OF_RESOURCES = -3,
+  CC_STATUS_ERROR = -4,
+} CcStatus;
+
+// Returns a description of the last error of the calling thread, valid until the next failing
+// call on the same thread.
+const char* cros_codecs_last_error(void);
+
+// Decoders.
+
+typedef struct CcDecoder CcDecoder;
+
+typedef struct {
+  uint64_t timestamp;
+  uint32_t width;
+  uint32_t height;
+  // Tightly packed NV12, valid until the callback returns.
+  const uint8_t* data;
+  size_t size;
+} CcDecodedFrame;
+
+typedef void (*CcDecodedCallback)(void* user_data, const CcDecodedFrame* frame);
+
+CcStatus cros_codecs_decoder_new(uint32_t codec,
+                                 CcDecodedCallback callback,
+                                 void* user_data,
+                                 CcDecoder** decoder);
+CcStatus cros_codecs_decoder_decode(CcDecoder* decoder,
+                                    const uint8_t* data,
+                                    size_t size,
+                                    uint64_t timestamp);
+CcStatus cros_codecs_decoder_flush(CcDecoder* decoder);
+void cros_codecs_decoder_free(CcDecoder* decoder);
+
+// H.264 encoder.
+
+typedef struct CcEncoder CcEncoder;
+
+typedef struct {
+  uint32_t width;
+  uint32_t height;
+  uint32_t framerate;
+  uint64_t bitrate;
+  // 66 (Baseline), 77 (Main) or 100 (High).
+  uint32_t profile_idc;
+  // Maximum number of frames between two keyframes, or 0 for the default.
+  uint32_t keyframe_interval;
+  bool low_power;
+} CcEncoderConfig;
+
+typedef struct {
+  uint64_t timestamp;
+  // Annex B access unit, valid until the callback returns.
+  const uint8_t* data;
+  size_t size;
+} CcCodedBuffer;
+
+typedef void (*CcCodedCallback)(void* user_data, const CcCodedBuffer* buffer);
+
+typedef struct {
+  uint32_t fd_index;
+  uint32_t offset;
+  uint32_t stride;
+} CcDmabufPlane;
+
+typedef struct {
+  // File descriptors remain owned by the caller.
+  int fds[CC_MAX_PLANES];
+  uint32_t num_fds;
+  // DRM fourcc and format modifier.
+  uint32_t fourcc;
+  uint64_t modifier;
+  CcDmabufPlane planes[CC_MAX_PLANES];
+  uint32_t num_planes;
+} CcDmabuf;
+
+CcStatus cros_codecs_encoder_new(const CcEncoderConfig* config,
+                                 CcCodedCallback callback,
+                                 void* user_data,
+                                 CcEncoder** encoder);
+// Encodes a tightly packed NV12 frame, which is copied before returning.
+CcStatus cros_codecs_encoder_encode_frame(CcEncoder* encoder,
+                                          const uint8_t* data,
+                                          size_t size,
+                                          uint64_t timestamp,
+                                          bool force_keyframe);
+CcStatus cros_codecs_encoder_encode_dmabuf(CcEncoder* encoder,
+                                           const CcDmabuf* dmabuf,
+                                           uint64_t timestamp,
+                                           bool force_keyframe);
+CcStatus cros_codecs_encoder_drain(CcEncoder* encoder);
+void cros_codecs_encoder_free(CcEncoder* encoder);
+
+#ifdef __cplusplus
+}  // extern "C"
+#endif
+
+#endif  // CROS_CODECS_H_
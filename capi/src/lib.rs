@@ -0,0 +1,823 @@
+// Copyright 2024 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! C API of cros-codecs.
+//!
+//! The VAAPI decoders and H.264 encoder are exposed as opaque handles, created with
+//! `cros_codecs_*_new` and destroyed with `cros_codecs_*_free`. Input is submitted one unit of
+//! bitstream or one frame per call, and output is returned through a callback given at creation
+//! time. The callback is only ever called from within a call on the handle that produced the
+//! output, and the data it receives is only valid until it returns. Handles are not thread-safe
+//! and must be used from the thread that created them.
+//!
+//! All functions return a [`CcStatus`]. If it is not [`CcStatus::Ok`], a description of the
+//! error can be obtained with [`cros_codecs_last_error`].
+//!
+//! The C declarations are in `include/cros_codecs.h`, which must be kept in sync with this file.
+
+use std::borrow::Borrow;
+use std::cell::RefCell;
+use std::ffi::c_char;
+use std::ffi::c_int;
+use std::ffi::c_void;
+use std::ffi::CString;
+use std::os::fd::BorrowedFd;
+use std::panic::AssertUnwindSafe;
+use std::rc::Rc;
+
+use anyhow::anyhow;
+use cros_codecs::backend::vaapi::decoder::VaapiBackend as VaapiDecoderBackend;
+use cros_codecs::backend::vaapi::encoder::upload_nv12;
+use cros_codecs::backend::vaapi::encoder::VaapiBackend as VaapiEncoderBackend;
+use cros_codecs::backend::vaapi::surface_pool::PooledVaSurface;
+use cros_codecs::backend::vaapi::surface_pool::VaSurfacePool;
+use cros_codecs::codec::h264::parser::Profile;
+use cros_codecs::decoder::stateless::av1::Av1;
+use cros_codecs::decoder::stateless::h264::H264;
+use cros_codecs::decoder::stateless::h265::H265;
+use cros_codecs::decoder::stateless::vp8::Vp8;
+use cros_codecs::decoder::stateless::vp9::Vp9;
+use cros_codecs::decoder::stateless::DecodeError;
+use cros_codecs::decoder::stateless::PoolLayer;
+use cros_codecs::decoder::stateless::StatelessDecoder;
+use cros_codecs::decoder::stateless::StatelessVideoDecoder;
+use cros_codecs::decoder::DecodedHandle;
+use cros_codecs::decoder::DecoderEvent;
+use cros_codecs::decoder::FramePool;
+use cros_codecs::encoder::stateless::h264::EncoderConfig;
+use cros_codecs::encoder::stateless::h264::PredictionStructure;
+use cros_codecs::encoder::stateless::h264::StatelessEncoder;
+use cros_codecs::encoder::stateless::StatelessVideoEncoder;
+use cros_codecs::encoder::Bitrate;
+use cros_codecs::encoder::FrameMetadata;
+use cros_codecs::libva;
+use cros_codecs::libva::Display;
+use cros_codecs::libva::Surface;
+use cros_codecs::utils::DmabufFrame;
+use cros_codecs::BlockingMode;
+use cros_codecs::DecodedFormat;
+use cros_codecs::Fourcc;
+use cros_codecs::FrameLayout;
+use cros_codecs::PlaneLayout;
+use cros_codecs::Resolution;
+
+/// Maximum number of planes and file descriptors of a [`CcDmabuf`].
+pub const CC_MAX_PLANES: usize = 4;
+
+/// Decoder codecs, to be passed to [`cros_codecs_decoder_new`].
+pub const CC_CODEC_H264: u32 = 0;
+pub const CC_CODEC_H265: u32 = 1;
+pub const CC_CODEC_VP8: u32 = 2;
+pub const CC_CODEC_VP9: u32 = 3;
+pub const CC_CODEC_AV1: u32 = 4;
+
+/// Maximum number of input surfaces an encoder allocates for frames submitted from CPU memory.
+const MAX_INPUT_SURFACES: usize = 16;
+
+/// Result of a call.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CcStatus {
+    Ok = 0,
+    /// A pointer was null, or a value was out of range.
+    InvalidArgument = -1,
+    /// The requested codec or configuration is not supported by the hardware or the library.
+    Unsupported = -2,
+    /// No more resources are available to process the input. Retry once some output has been
+    /// received.
+    OutOfResources = -3,
+    /// Any other error.
+    Error = -4,
+}
+
+/// Error of a call, reported to the caller as `status` and recorded as the last error.
+struct CallError {
+    status: CcStatus,
+    error: anyhow::Error,
+}
+
+impl CallError {
+    fn new(status: CcStatus, message: &str) -> Self {
+        Self {
+            status,
+            error: anyhow!("{}", message),
+        }
+    }
+}
+
+impl<E: Into<anyhow::Error>> From<E> for CallError {
+    fn from(error: E) -> Self {
+        Self {
+            status: CcStatus::Error,
+            error: error.into(),
+        }
+    }
+}
+
+thread_local! {
+    static LAST_ERROR: RefCell<CString> = RefCell::new(CString::default());
+}
+
+/// Runs the body `f` of an exported function, turning its result into a status. Panics are
+/// caught so they never unwind into C code.
+fn ffi_call(f: impl FnOnce() -> Result<(), CallError>) -> CcStatus {
+    let (status, message) = match std::panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Ok(())) => return CcStatus::Ok,
+        Ok(Err(e)) => (e.status, format!("{:#}", e.error)),
+        Err(_) => (CcStatus::Error, String::from("internal error (panic)")),
+    };
+
+    log::debug!("C API call failed: {}", message);
+    // Interior NUL bytes cannot be represented, so replace them.
+    let message = CString::new(message.replace('\0', " ")).unwrap_or_default();
+    LAST_ERROR.with(|last| *last.borrow_mut() = message);
+
+    status
+}
+
+/// Turns a pointer and size received from C into a slice.
+///
+/// # Safety
+///
+/// `data` must be null or point to `size` readable bytes that outlive the returned slice.
+unsafe fn input_slice<'a>(data: *const u8, size: usize) -> Result<&'a [u8], CallError> {
+    if size == 0 {
+        Ok(&[])
+    } else if data.is_null() {
+        Err(CallError::new(CcStatus::InvalidArgument, "null data"))
+    } else {
+        Ok(std::slice::from_raw_parts(data, size))
+    }
+}
+
+fn open_display() -> Result<Rc<Display>, CallError> {
+    Display::open().ok_or_else(|| CallError::new(CcStatus::Unsupported, "cannot open VA display"))
+}
+
+/// Returns a description of the last error that occurred on the calling thread.
+///
+/// The returned string is valid until the next failing call on the same thread.
+#[no_mangle]
+pub extern "C" fn cros_codecs_last_error() -> *const c_char {
+    LAST_ERROR.with(|last| last.borrow().as_ptr())
+}
+
+/// A decoded frame, passed to the decoded frame callback.
+#[repr(C)]
+pub struct CcDecodedFrame {
+    /// Timestamp of the bitstream unit the frame has been decoded from.
+    pub timestamp: u64,
+    /// Display resolution of the frame.
+    pub width: u32,
+    pub height: u32,
+    /// The frame, as tightly packed NV12.
+    pub data: *const u8,
+    pub size: usize,
+}
+
+/// Called for every decoded frame, in display order.
+pub type CcDecodedCallback =
+    Option<unsafe extern "C" fn(user_data: *mut c_void, frame: *const CcDecodedFrame)>;
+
+/// A decoder, outputting NV12 frames into CPU memory.
+pub struct CcDecoder {
+    decoder: Box<dyn StatelessVideoDecoder<VaapiDecoderBackend<()>>>,
+    callback: unsafe extern "C" fn(*mut c_void, *const CcDecodedFrame),
+    user_data: *mut c_void,
+}
+
+impl CcDecoder {
+    /// Processes the pending events of the decoder: negotiates the output format and hands the
+    /// decoded frames to the callback.
+    fn process_events(&mut self) -> anyhow::Result<()> {
+        while let Some(event) = self.decoder.next_event() {
+            match event {
+                DecoderEvent::FrameReady(handle) => {
+                    handle.sync()?;
+                    let picture = handle.dyn_picture();
+                    let mut mappable = picture.dyn_mappable_handle()?;
+                    let mut data = vec![0u8; mappable.image_size()];
+                    mappable.read(&mut data)?;
+
+                    let resolution = handle.display_resolution();
+                    let frame = CcDecodedFrame {
+                        timestamp: handle.timestamp(),
+                        width: resolution.width,
+                        height: resolution.height,
+                        data: data.as_ptr(),
+                        size: data.len(),
+                    };
+                    // SAFETY: the callback has been provided by the client along with `user_data`,
+                    // and `frame` outlives the call.
+                    unsafe { (self.callback)(self.user_data, &frame) };
+                }
+                DecoderEvent::FormatChanged(mut format_setter) => {
+                    format_setter.try_format(DecodedFormat::NV12)?;
+                    let min_num_frames = format_setter.stream_info().min_num_frames;
+                    let pools = format_setter.frame_pool(PoolLayer::All);
+                    let nb_pools = pools.len();
+                    for pool in pools {
+                        let pool_num_frames = pool.num_managed_frames();
+                        if pool_num_frames < (min_num_frames / nb_pools) {
+                            pool.add_frames(vec![(); min_num_frames - pool_num_frames])?;
+                        }
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Creates a decoder for `codec`, one of the `CC_CODEC_*` values, and stores it into `decoder`.
+///
+/// `callback` is called with `user_data` for every decoded frame.
+///
+/// # Safety
+///
+/// `decoder` must be a valid pointer to write the decoder to. `callback` must be safe to call
+/// with `user_data` until the decoder is freed.
+#[no_mangle]
+pub unsafe extern "C" fn cros_codecs_decoder_new(
+    codec: u32,
+    callback: CcDecodedCallback,
+    user_data: *mut c_void,
+    decoder: *mut *mut CcDecoder,
+) -> CcStatus {
+    ffi_call(|| {
+        if decoder.is_null() {
+            return Err(CallError::new(CcStatus::InvalidArgument, "null decoder"));
+        }
+        let callback =
+            callback.ok_or_else(|| CallError::new(CcStatus::InvalidArgument, "null callback"))?;
+        if codec > CC_CODEC_AV1 {
+            return Err(CallError::new(CcStatus::InvalidArgument, "unknown codec"));
+        }
+
+        let display = open_display()?;
+        let blocking_mode = BlockingMode::Blocking;
+        let inner = match codec {
+            CC_CODEC_H264 => Box::new(StatelessDecoder::<H264, _>::new_vaapi(
+                display,
+                blocking_mode,
+            )) as Box<dyn StatelessVideoDecoder<_>>,
+            CC_CODEC_H265 => Box::new(StatelessDecoder::<H265, _>::new_vaapi(
+                display,
+                blocking_mode,
+            )) as Box<dyn StatelessVideoDecoder<_>>,
+            CC_CODEC_VP8 => Box::new(StatelessDecoder::<Vp8, _>::new_vaapi(
+                display,
+                blocking_mode,
+            )) as Box<dyn StatelessVideoDecoder<_>>,
+            CC_CODEC_VP9 => Box::new(StatelessDecoder::<Vp9, _>::new_vaapi(
+                display,
+                blocking_mode,
+            )) as Box<dyn StatelessVideoDecoder<_>>,
+            CC_CODEC_AV1 => Box::new(StatelessDecoder::<Av1, _>::new_vaapi(
+                display,
+                blocking_mode,
+            )) as Box<dyn StatelessVideoDecoder<_>>,
+            _ => return Err(CallError::new(CcStatus::InvalidArgument, "unknown codec")),
+        };
+
+        *decoder = Box::into_raw(Box::new(CcDecoder {
+            decoder: inner,
+            callback,
+            user_data,
+        }));
+
+        Ok(())
+    })
+}
+
+/// Decodes the `size` bytes at `data`: an Annex B access unit for H.264 and H.265, a frame
+/// otherwise. Frames decoded from it carry `timestamp`.
+///
+/// # Safety
+///
+/// `decoder` must have been created with [`cros_codecs_decoder_new`], and `data` must point to
+/// `size` readable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn cros_codecs_decoder_decode(
+    decoder: *mut CcDecoder,
+    data: *const u8,
+    size: usize,
+    timestamp: u64,
+) -> CcStatus {
+    ffi_call(|| {
+        let decoder = decoder
+            .as_mut()
+            .ok_or_else(|| CallError::new(CcStatus::InvalidArgument, "null decoder"))?;
+        let mut bitstream = input_slice(data, size)?;
+
+        while !bitstream.is_empty() {
+            match decoder.decoder.decode(timestamp, bitstream) {
+                Ok(processed) => {
+                    bitstream = &bitstream[processed..];
+                    decoder.process_events()?;
+                }
+                Err(DecodeError::CheckEvents) | Err(DecodeError::NotEnoughOutputBuffers(_)) => {
+                    decoder.process_events()?
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        Ok(())
+    })
+}
+
+/// Decodes all the pending input, and outputs all the remaining frames.
+///
+/// # Safety
+///
+/// `decoder` must have been created with [`cros_codecs_decoder_new`].
+#[no_mangle]
+pub unsafe extern "C" fn cros_codecs_decoder_flush(decoder: *mut CcDecoder) -> CcStatus {
+    ffi_call(|| {
+        let decoder = decoder
+            .as_mut()
+            .ok_or_else(|| CallError::new(CcStatus::InvalidArgument, "null decoder"))?;
+
+        decoder.decoder.flush()?;
+        decoder.process_events()?;
+
+        Ok(())
+    })
+}
+
+/// Frees `decoder`. Pending frames are dropped, call [`cros_codecs_decoder_flush`] first to
+/// receive them.
+///
+/// # Safety
+///
+/// `decoder` must be null or have been created with [`cros_codecs_decoder_new`], and not be used
+/// afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn cros_codecs_decoder_free(decoder: *mut CcDecoder) {
+    if !decoder.is_null() {
+        drop(Box::from_raw(decoder));
+    }
+}
+
+/// Configuration of an H.264 encoder.
+#[repr(C)]
+pub struct CcEncoderConfig {
+    /// Resolution of the input frames.
+    pub width: u32,
+    pub height: u32,
+    /// Frames per second.
+    pub framerate: u32,
+    /// Constant bitrate, in bits per second.
+    pub bitrate: u64,
+    /// `profile_idc` of the stream: 66 (Baseline), 77 (Main) or 100 (High).
+    pub profile_idc: u32,
+    /// Maximum number of frames between two keyframes, or 0 for the default.
+    pub keyframe_interval: u32,
+    /// Use the low power encoding entrypoint of the hardware.
+    pub low_power: bool,
+}
+
+/// A unit of encoded bitstream, passed to the coded buffer callback.
+#[repr(C)]
+pub struct CcCodedBuffer {
+    /// Timestamp of the frame the buffer holds.
+    pub timestamp: u64,
+    /// The encoded frame, as an Annex B access unit.
+    pub data: *const u8,
+    pub size: usize,
+}
+
+/// Called for every unit of encoded bitstream, in decoding order.
+pub type CcCodedCallback =
+    Option<unsafe extern "C" fn(user_data: *mut c_void, buffer: *const CcCodedBuffer)>;
+
+/// A plane of a [`CcDmabuf`].
+#[repr(C)]
+pub struct CcDmabufPlane {
+    /// Index of the file descriptor of [`CcDmabuf::fds`] holding the plane.
+    pub fd_index: u32,
+    pub offset: u32,
+    pub stride: u32,
+}
+
+/// A frame backed by DMA buffers.
+#[repr(C)]
+pub struct CcDmabuf {
+    pub fds: [c_int; CC_MAX_PLANES],
+    pub num_fds: u32,
+    /// DRM fourcc and modifier of the frame.
+    pub fourcc: u32,
+    pub modifier: u64,
+    pub planes: [CcDmabufPlane; CC_MAX_PLANES],
+    pub num_planes: u32,
+}
+
+impl CcDmabuf {
+    /// Duplicates the file descriptors of the frame, so it can outlive the call it has been
+    /// received in.
+    ///
+    /// # Safety
+    ///
+    /// The first `num_fds` file descriptors must be open.
+    unsafe fn to_dmabuf_frame(&self, size: Resolution) -> Result<DmabufFrame, CallError> {
+        let num_fds = self.num_fds as usize;
+        let num_planes = self.num_planes as usize;
+        if !(1..=CC_MAX_PLANES).contains(&num_fds) || !(1..=CC_MAX_PLANES).contains(&num_planes) {
+            return Err(CallError::new(
+                CcStatus::InvalidArgument,
+                "invalid number of planes or file descriptors",
+            ));
+        }
+
+        let planes = self.planes[..num_planes]
+            .iter()
+            .map(|plane| {
+                if plane.fd_index as usize >= num_fds {
+                    return Err(CallError::new(
+                        CcStatus::InvalidArgument,
+                        "plane file descriptor index out of range",
+                    ));
+                }
+
+                Ok(PlaneLayout {
+                    buffer_index: plane.fd_index as usize,
+                    offset: plane.offset as usize,
+                    stride: plane.stride as usize,
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let fds = self.fds[..num_fds]
+            .iter()
+            .map(|&fd| BorrowedFd::borrow_raw(fd).try_clone_to_owned())
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(DmabufFrame {
+            fds,
+            layout: FrameLayout {
+                format: (Fourcc::from(self.fourcc), self.modifier),
+                size,
+                planes,
+            },
+        })
+    }
+}
+
+/// Memory backing the input frames of an encoder: allocated by the backend for frames uploaded
+/// from CPU memory, or imported DMA buffers.
+enum InputDescriptor {
+    Managed(()),
+    Dmabuf(DmabufFrame),
+}
+
+impl libva::SurfaceMemoryDescriptor for InputDescriptor {
+    fn add_attrs(
+        &mut self,
+        attrs: &mut Vec<libva::VASurfaceAttrib>,
+    ) -> Option<Box<dyn std::any::Any>> {
+        match self {
+            InputDescriptor::Managed(desc) => desc.add_attrs(attrs),
+            InputDescriptor::Dmabuf(desc) => desc.add_attrs(attrs),
+        }
+    }
+}
+
+/// Input frame of an encoder.
+enum InputSurface {
+    Pooled(PooledVaSurface<InputDescriptor>),
+    Imported(Surface<InputDescriptor>),
+}
+
+impl Borrow<Surface<InputDescriptor>> for InputSurface {
+    fn borrow(&self) -> &Surface<InputDescriptor> {
+        match self {
+            InputSurface::Pooled(surface) => surface.borrow(),
+            InputSurface::Imported(surface) => surface,
+        }
+    }
+}
+
+/// An H.264 encoder.
+pub struct CcEncoder {
+    display: Rc<Display>,
+    encoder: StatelessEncoder<InputSurface, VaapiEncoderBackend<InputDescriptor, InputSurface>>,
+    pool: VaSurfacePool<InputDescriptor>,
+    resolution: Resolution,
+    callback: unsafe extern "C" fn(*mut c_void, *const CcCodedBuffer),
+    user_data: *mut c_void,
+}
+
+impl CcEncoder {
+    /// Hands all the available encoded output to the callback.
+    fn poll(&mut self) -> Result<(), CallError> {
+        while let Some(coded) = self.encoder.poll()? {
+            let buffer = CcCodedBuffer {
+                timestamp: coded.metadata.timestamp,
+                data: coded.bitstream.as_ptr(),
+                size: coded.bitstream.len(),
+            };
+            // SAFETY: the callback has been provided by the client along with `user_data`, and
+            // `buffer` outlives the call.
+            unsafe { (self.callback)(self.user_data, &buffer) };
+        }
+
+        Ok(())
+    }
+
+    /// Returns a free input surface, allocating one if needed.
+    fn get_input_surface(&mut self) -> Result<PooledVaSurface<InputDescriptor>, CallError> {
+        if self.pool.num_free_frames() == 0 {
+            if self.pool.num_managed_frames() >= MAX_INPUT_SURFACES {
+                return Err(CallError::new(
+                    CcStatus::OutOfResources,
+                    "all input surfaces are in use",
+                ));
+            }
+            self.pool.add_frames(vec![InputDescriptor::Managed(())])?;
+        }
+
+        self.pool
+            .get_surface()
+            .ok_or_else(|| CallError::new(CcStatus::OutOfResources, "no free input surface"))
+    }
+
+    fn encode(
+        &mut self,
+        layout: FrameLayout,
+        surface: InputSurface,
+        timestamp: u64,
+        force_keyframe: bool,
+    ) -> Result<(), CallError> {
+        let meta = FrameMetadata {
+            timestamp,
+            display_resolution: self.resolution,
+            layout,
+            force_keyframe,
+            damage: None,
+        };
+
+        self.encoder.encode(meta, surface)?;
+        self.poll()
+    }
+}
+
+/// Creates an H.264 encoder with `config` and stores it into `encoder`. Input frames are NV12.
+///
+/// `callback` is called with `user_data` for every unit of encoded bitstream.
+///
+/// # Safety
+///
+/// `config` must point to a valid configuration and `encoder` must be a valid pointer to write
+/// the encoder to. `callback` must be safe to call with `user_data` until the encoder is freed.
+#[no_mangle]
+pub unsafe extern "C" fn cros_codecs_encoder_new(
+    config: *const CcEncoderConfig,
+    callback: CcCodedCallback,
+    user_data: *mut c_void,
+    encoder: *mut *mut CcEncoder,
+) -> CcStatus {
+    ffi_call(|| {
+        let config = config
+            .as_ref()
+            .ok_or_else(|| CallError::new(CcStatus::InvalidArgument, "null config"))?;
+        if encoder.is_null() {
+            return Err(CallError::new(CcStatus::InvalidArgument, "null encoder"));
+        }
+        let callback =
+            callback.ok_or_else(|| CallError::new(CcStatus::InvalidArgument, "null callback"))?;
+
+        if config.width == 0 || config.height == 0 || config.framerate == 0 {
+            return Err(CallError::new(
+                CcStatus::InvalidArgument,
+                "invalid resolution or framerate",
+            ));
+        }
+        let profile = u8::try_from(config.profile_idc)
+            .ok()
+            .and_then(Profile::n)
+            .ok_or_else(|| CallError::new(CcStatus::InvalidArgument, "invalid profile_idc"))?;
+
+        let resolution = Resolution::from((config.width, config.height));
+        let mut encoder_config = EncoderConfig {
+            bitrate: Bitrate::Constant(config.bitrate),
+            framerate: config.framerate,
+            resolution,
+            profile,
+            ..Default::default()
+        };
+        if config.keyframe_interval != 0 {
+            encoder_config.pred_structure = PredictionStructure::LowDelay {
+                tail: 1,
+                limit: config.keyframe_interval.try_into().unwrap_or(u16::MAX),
+            };
+        }
+
+        let display = open_display()?;
+        let inner = StatelessEncoder::new_vaapi(
+            Rc::clone(&display),
+            encoder_config,
+            Fourcc::from(b"NV12"),
+            resolution,
+            config.low_power,
+            BlockingMode::Blocking,
+        )
+        .map_err(|e| CallError {
+            status: CcStatus::Unsupported,
+            error: e.into(),
+        })?;
+
+        let pool = VaSurfacePool::new(
+            Rc::clone(&display),
+            libva::constants::VA_RT_FORMAT_YUV420,
+            Some(libva::UsageHint::USAGE_HINT_ENCODER),
+            resolution,
+        );
+
+        *encoder = Box::into_raw(Box::new(CcEncoder {
+            display,
+            encoder: inner,
+            pool,
+            resolution,
+            callback,
+            user_data,
+        }));
+
+        Ok(())
+    })
+}
+
+/// Encodes the tightly packed NV12 frame of `size` bytes at `data`, with `timestamp`. The data is
+/// copied before returning.
+///
+/// # Safety
+///
+/// `encoder` must have been created with [`cros_codecs_encoder_new`], and `data` must point to
+/// `size` readable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn cros_codecs_encoder_encode_frame(
+    encoder: *mut CcEncoder,
+    data: *const u8,
+    size: usize,
+    timestamp: u64,
+    force_keyframe: bool,
+) -> CcStatus {
+    ffi_call(|| {
+        let encoder = encoder
+            .as_mut()
+            .ok_or_else(|| CallError::new(CcStatus::InvalidArgument, "null encoder"))?;
+        let frame = input_slice(data, size)?;
+
+        let width = encoder.resolution.width as usize;
+        let height = encoder.resolution.height as usize;
+        let expected_size = width * height + width.next_multiple_of(2) * height.div_ceil(2);
+        if frame.len() < expected_size {
+            return Err(CallError::new(
+                CcStatus::InvalidArgument,
+                "frame is too small for the resolution",
+            ));
+        }
+
+        let surface = encoder.get_input_surface()?;
+        let layout = upload_nv12(
+            &encoder.display,
+            surface.borrow(),
+            encoder.resolution,
+            frame,
+        )?;
+
+        encoder.encode(
+            layout,
+            InputSurface::Pooled(surface),
+            timestamp,
+            force_keyframe,
+        )
+    })
+}
+
+/// Encodes the frame backed by `dmabuf`, with `timestamp`. The file descriptors of `dmabuf` are
+/// duplicated and remain owned by the caller, who must not write to the buffers until the
+/// encoded frame has been received.
+///
+/// # Safety
+///
+/// `encoder` must have been created with [`cros_codecs_encoder_new`], and `dmabuf` must point to
+/// a valid [`CcDmabuf`] with open file descriptors.
+#[no_mangle]
+pub unsafe extern "C" fn cros_codecs_encoder_encode_dmabuf(
+    encoder: *mut CcEncoder,
+    dmabuf: *const CcDmabuf,
+    timestamp: u64,
+    force_keyframe: bool,
+) -> CcStatus {
+    ffi_call(|| {
+        let encoder = encoder
+            .as_mut()
+            .ok_or_else(|| CallError::new(CcStatus::InvalidArgument, "null encoder"))?;
+        let dmabuf = dmabuf
+            .as_ref()
+            .ok_or_else(|| CallError::new(CcStatus::InvalidArgument, "null dmabuf"))?;
+
+        let frame = dmabuf.to_dmabuf_frame(encoder.resolution)?;
+        let layout = frame.layout.clone();
+        let surface = encoder
+            .display
+            .create_surfaces(
+                libva::constants::VA_RT_FORMAT_YUV420,
+                None,
+                encoder.resolution.width,
+                encoder.resolution.height,
+                Some(libva::UsageHint::USAGE_HINT_ENCODER),
+                vec![InputDescriptor::Dmabuf(frame)],
+            )
+            .map_err(|e| anyhow!(e))?
+            .pop()
+            .ok_or_else(|| anyhow!("failed to import dmabuf"))?;
+
+        encoder.encode(
+            layout,
+            InputSurface::Imported(surface),
+            timestamp,
+            force_keyframe,
+        )
+    })
+}
+
+/// Encodes all the pending frames, and outputs all the remaining encoded bitstream.
+///
+/// # Safety
+///
+/// `encoder` must have been created with [`cros_codecs_encoder_new`].
+#[no_mangle]
+pub unsafe extern "C" fn cros_codecs_encoder_drain(encoder: *mut CcEncoder) -> CcStatus {
+    ffi_call(|| {
+        let encoder = encoder
+            .as_mut()
+            .ok_or_else(|| CallError::new(CcStatus::InvalidArgument, "null encoder"))?;
+
+        encoder.encoder.drain()?;
+        encoder.poll()
+    })
+}
+
+/// Frees `encoder`. Pending frames are dropped, call [`cros_codecs_encoder_drain`] first to
+/// encode them.
+///
+/// # Safety
+///
+/// `encoder` must be null or have been created with [`cros_codecs_encoder_new`], and not be used
+/// afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn cros_codecs_encoder_free(encoder: *mut CcEncoder) {
+    if !encoder.is_null() {
+        drop(Box::from_raw(encoder));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::CStr;
+    use std::ptr;
+
+    use super::*;
+
+    fn last_error() -> String {
+        // SAFETY: the pointer is valid until the next failing call.
+        unsafe { CStr::from_ptr(cros_codecs_last_error()) }
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    #[test]
+    fn invalid_arguments() {
+        unsafe extern "C" fn on_frame(_: *mut c_void, _: *const CcDecodedFrame) {}
+
+        let mut decoder = ptr::null_mut();
+        let status =
+            unsafe { cros_codecs_decoder_new(42, Some(on_frame), ptr::null_mut(), &mut decoder) };
+        assert_eq!(status, CcStatus::InvalidArgument);
+        assert!(decoder.is_null());
+
+        let status =
+            unsafe { cros_codecs_decoder_new(CC_CODEC_H264, None, ptr::null_mut(), &mut decoder) };
+        assert_eq!(status, CcStatus::InvalidArgument);
+        assert_eq!(last_error(), "null callback");
+
+        let status = unsafe { cros_codecs_decoder_decode(ptr::null_mut(), ptr::null(), 0, 0) };
+        assert_eq!(status, CcStatus::InvalidArgument);
+        assert_eq!(last_error(), "null decoder");
+
+        // Freeing null is a no-op.
+        unsafe { cros_codecs_decoder_free(ptr::null_mut()) };
+        unsafe { cros_codecs_encoder_free(ptr::null_mut()) };
+    }
+
+    #[test]
+    fn panics_do_not_unwind() {
+        let status = ffi_call(|| panic!("boom"));
+        assert_eq!(status, CcStatus::Error);
+        assert_eq!(last_error(), "internal error (panic)");
+    }
+}
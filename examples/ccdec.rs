@@ -3,13 +3,13 @@
 // found in the LICENSE file.
 
 //! ccdec, a simple decoder program using cros-codecs. Capable of computing MD5 checksums from the
-//! input and writing the raw decoded frames to a file.
+//! input, writing the raw decoded frames to a file and producing a JSON report of the decoded
+//! frames.
 
 use std::borrow::Cow;
 use std::cell::RefCell;
 use std::ffi::OsStr;
 use std::fs::File;
-use std::io::Cursor;
 use std::io::Read;
 use std::io::Write;
 use std::os::fd::AsFd;
@@ -34,6 +34,8 @@ use cros_codecs::decoder::BlockingMode;
 use cros_codecs::decoder::DecodedHandle;
 use cros_codecs::decoder::StreamInfo;
 use cros_codecs::multiple_desc_type;
+use cros_codecs::utils::demux::TrackCodec;
+use cros_codecs::utils::probe::probe;
 use cros_codecs::utils::simple_playback_loop;
 use cros_codecs::utils::simple_playback_loop_owned_frames;
 use cros_codecs::utils::simple_playback_loop_userptr_frames;
@@ -46,8 +48,6 @@ use cros_codecs::Fourcc;
 use cros_codecs::FrameLayout;
 use cros_codecs::PlaneLayout;
 use cros_codecs::Resolution;
-use matroska_demuxer::Frame;
-use matroska_demuxer::MatroskaFile;
 
 // Our buffer descriptor type.
 //
@@ -76,14 +76,14 @@ fn export_gbm_bo<T>(obj: &gbm::BufferObject<T>) -> anyhow::Result<DmabufFrame> {
         .collect();
     let size = Resolution::from((obj.width().unwrap(), obj.height().unwrap()));
 
-    Ok(DmabufFrame {
-        fds: vec![fd],
-        layout: FrameLayout {
+    DmabufFrame::new(
+        vec![fd],
+        FrameLayout {
             format: (Fourcc::from(format as u32), modifier.into()),
             size,
             planes,
         },
-    })
+    )
 }
 
 /// Buffer allocation callback for `simple_playback_loop` to allocate and export buffers from a GBM
@@ -160,40 +160,6 @@ impl FromStr for FrameMemoryType {
     }
 }
 
-struct MkvFrameIterator<T: AsRef<[u8]>> {
-    input: MatroskaFile<Cursor<T>>,
-    video_track: u64,
-}
-
-impl<T: AsRef<[u8]>> MkvFrameIterator<T> {
-    fn new(input: T) -> anyhow::Result<Self> {
-        let input = MatroskaFile::open(Cursor::new(input))?;
-        let video_track = input
-            .tracks()
-            .iter()
-            .find(|t| t.track_type() == matroska_demuxer::TrackType::Video)
-            .map(|t| t.track_number().get())
-            .ok_or_else(|| anyhow::anyhow!("no video track in input file"))?;
-
-        Ok(Self { input, video_track })
-    }
-}
-
-impl<T: AsRef<[u8]>> Iterator for MkvFrameIterator<T> {
-    type Item = Vec<u8>;
-
-    fn next(&mut self) -> Option<Self::Item> {
-        let mut frame = Frame::default();
-        while self.input.next_frame(&mut frame).unwrap() {
-            if frame.track == self.video_track {
-                return Some(frame.data);
-            }
-        }
-
-        None
-    }
-}
-
 #[derive(Debug)]
 enum Md5Computation {
     Stream,
@@ -212,10 +178,218 @@ impl FromStr for Md5Computation {
     }
 }
 
+/// Checksums of one plane of a decoded frame.
+struct PlaneChecksums {
+    md5: md5::Digest,
+    crc32: u32,
+}
+
+/// Information about a decoded frame, as written in the JSON report.
+struct FrameReport {
+    timestamp: u64,
+    coded_resolution: Resolution,
+    display_resolution: Resolution,
+    corrupted: bool,
+    md5: md5::Digest,
+    planes: Vec<PlaneChecksums>,
+}
+
+/// Machine-readable report of a decoding session, suitable for automated comparison with
+/// reference decoders.
+#[derive(Default)]
+struct Report {
+    frames: Vec<FrameReport>,
+    /// Index of the first frame of each new resolution, with its coded and display resolutions.
+    resolution_changes: Vec<(usize, Resolution, Resolution)>,
+    /// Error that interrupted decoding, if any.
+    error: Option<String>,
+}
+
+/// Returns `s` as a quoted JSON string.
+fn json_string(s: &str) -> String {
+    let mut res = String::with_capacity(s.len() + 2);
+
+    res.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => res.push_str("\\\""),
+            '\\' => res.push_str("\\\\"),
+            '\n' => res.push_str("\\n"),
+            '\r' => res.push_str("\\r"),
+            '\t' => res.push_str("\\t"),
+            c if (c as u32) < 0x20 => res.push_str(&format!("\\u{:04x}", c as u32)),
+            c => res.push(c),
+        }
+    }
+    res.push('"');
+
+    res
+}
+
+/// Returns `resolution` as a JSON object.
+fn json_resolution(resolution: Resolution) -> String {
+    format!(
+        "{{\"width\": {}, \"height\": {}}}",
+        resolution.width, resolution.height
+    )
+}
+
+impl Report {
+    /// Records the frame decoded into `frame_data`, of `format`.
+    fn add_frame<H: DecodedHandle>(
+        &mut self,
+        handle: &H,
+        format: DecodedFormat,
+        frame_data: &[u8],
+    ) {
+        let coded_resolution = handle.coded_resolution();
+        let display_resolution = handle.display_resolution();
+
+        let resolution_changed = match self.frames.last() {
+            None => true,
+            Some(last) => {
+                last.coded_resolution != coded_resolution
+                    || last.display_resolution != display_resolution
+            }
+        };
+        if resolution_changed {
+            self.resolution_changes
+                .push((self.frames.len(), coded_resolution, display_resolution));
+        }
+
+        let mut planes = Vec::new();
+        let mut data = frame_data;
+        for size in plane_sizes(format, display_resolution) {
+            let (plane, rest) = data.split_at(size.min(data.len()));
+            planes.push(PlaneChecksums {
+                md5: md5::compute(plane),
+                crc32: crc32fast::hash(plane),
+            });
+            data = rest;
+        }
+
+        self.frames.push(FrameReport {
+            timestamp: handle.timestamp(),
+            coded_resolution,
+            display_resolution,
+            corrupted: handle.is_corrupted(),
+            md5: md5::compute(frame_data),
+            planes,
+        });
+    }
+
+    /// Writes the report as JSON into `writer`.
+    fn write_json<W: Write>(
+        &self,
+        writer: &mut W,
+        args: &Args,
+        input_format: EncodedFormat,
+        stream_md5: md5::Digest,
+    ) -> std::io::Result<()> {
+        writeln!(writer, "{{")?;
+        writeln!(
+            writer,
+            "  \"input\": {},",
+            json_string(&args.input.to_string_lossy())
+        )?;
+        writeln!(
+            writer,
+            "  \"input_format\": {},",
+            json_string(&format!("{:?}", input_format))
+        )?;
+        writeln!(
+            writer,
+            "  \"output_format\": {},",
+            json_string(&format!("{:?}", args.output_format))
+        )?;
+        writeln!(writer, "  \"frame_count\": {},", self.frames.len())?;
+        writeln!(writer, "  \"md5\": \"{:x}\",", stream_md5)?;
+
+        writeln!(writer, "  \"resolution_changes\": [")?;
+        for (i, (frame, coded, display)) in self.resolution_changes.iter().enumerate() {
+            let sep = if i + 1 < self.resolution_changes.len() {
+                ","
+            } else {
+                ""
+            };
+            writeln!(
+                writer,
+                "    {{\"frame\": {}, \"coded_resolution\": {}, \"display_resolution\": {}}}{}",
+                frame,
+                json_resolution(*coded),
+                json_resolution(*display),
+                sep
+            )?;
+        }
+        writeln!(writer, "  ],")?;
+
+        writeln!(writer, "  \"frames\": [")?;
+        for (i, frame) in self.frames.iter().enumerate() {
+            let planes = frame
+                .planes
+                .iter()
+                .map(|p| {
+                    format!(
+                        "{{\"md5\": \"{:x}\", \"crc32\": \"{:08x}\"}}",
+                        p.md5, p.crc32
+                    )
+                })
+                .collect::<Vec<_>>()
+                .join(", ");
+            let sep = if i + 1 < self.frames.len() { "," } else { "" };
+            writeln!(
+                writer,
+                "    {{\"index\": {}, \"timestamp\": {}, \"coded_resolution\": {}, \"display_resolution\": {}, \"corrupted\": {}, \"md5\": \"{:x}\", \"planes\": [{}]}}{}",
+                i,
+                frame.timestamp,
+                json_resolution(frame.coded_resolution),
+                json_resolution(frame.display_resolution),
+                frame.corrupted,
+                frame.md5,
+                planes,
+                sep
+            )?;
+        }
+        writeln!(writer, "  ],")?;
+
+        match &self.error {
+            Some(error) => writeln!(writer, "  \"error\": {}", json_string(error))?,
+            None => writeln!(writer, "  \"error\": null")?,
+        }
+        writeln!(writer, "}}")
+    }
+}
+
+/// Returns the sizes of the planes of a frame of `format` and `resolution`, as produced by
+/// `MappableHandle::read`.
+fn plane_sizes(format: DecodedFormat, resolution: Resolution) -> Vec<usize> {
+    let width = resolution.width as usize;
+    let height = resolution.height as usize;
+
+    let (sub_h, sub_v, bytes_per_sample) = match format {
+        DecodedFormat::I420 | DecodedFormat::NV12 => (true, true, 1),
+        DecodedFormat::I422 => (true, false, 1),
+        DecodedFormat::I444 => (false, false, 1),
+        DecodedFormat::I010 | DecodedFormat::I012 => (true, true, 2),
+        DecodedFormat::I210 | DecodedFormat::I212 => (true, false, 2),
+        DecodedFormat::I410 | DecodedFormat::I412 => (false, false, 2),
+    };
+    let uv_width = if sub_h { width.div_ceil(2) } else { width };
+    let uv_height = if sub_v { height.div_ceil(2) } else { height };
+    let luma_size = width * height * bytes_per_sample;
+    let chroma_size = uv_width * uv_height * bytes_per_sample;
+
+    match format {
+        DecodedFormat::NV12 => vec![luma_size, 2 * chroma_size],
+        _ => vec![luma_size, chroma_size, chroma_size],
+    }
+}
+
 /// Simple player using cros-codecs
 #[derive(Debug, FromArgs)]
 struct Args {
-    /// input file
+    /// input file, either an MP4 or Matroska file, or an elementary stream (Annex B for h264 and
+    /// h265, IVF otherwise)
     #[argh(positional)]
     input: PathBuf,
 
@@ -227,9 +401,9 @@ struct Args {
     #[argh(switch)]
     multiple_output_files: bool,
 
-    /// input format to decode from.
+    /// input format to decode from. Detected from the input if not set.
     #[argh(option)]
-    input_format: EncodedFormat,
+    input_format: Option<EncodedFormat>,
 
     /// pixel format to decode into. Default: i420
     #[argh(option, default = "DecodedFormat::I420")]
@@ -251,15 +425,47 @@ struct Args {
     /// frame)
     #[argh(option)]
     compute_md5: Option<Md5Computation>,
+
+    /// file to write a JSON report of the decoded frames to, including the checksums of their
+    /// planes and the resolution changes of the stream
+    #[argh(option)]
+    report: Option<PathBuf>,
 }
 
-/// Detects the container type (IVF or MKV) and returns the corresponding frame iterator.
-fn create_vpx_frame_iterator(input: &[u8]) -> Box<dyn Iterator<Item = Cow<[u8]>> + '_> {
-    if input.starts_with(&[0x1a, 0x45, 0xdf, 0xa3]) {
-        Box::new(MkvFrameIterator::new(input).unwrap().map(Cow::Owned))
-    } else {
-        Box::new(IvfIterator::new(input).map(Cow::Borrowed))
+/// Returns the format of `input` and an iterator over its frames. `input` can be an MP4, Matroska
+/// or IVF file, or an elementary stream, whose format is detected unless `format` is given.
+fn create_frame_iterator(
+    input: &[u8],
+    format: Option<EncodedFormat>,
+) -> (EncodedFormat, Box<dyn Iterator<Item = Cow<[u8]>> + '_>) {
+    let Some(probed) = probe(input) else {
+        let format = format.expect("unable to detect the input format, please specify it");
+        let frame_iter: Box<dyn Iterator<Item = Cow<[u8]>>> = match format {
+            EncodedFormat::H264 => Box::new(NalIterator::<H264Nalu>::new(input).map(Cow::Borrowed)),
+            EncodedFormat::H265 => Box::new(NalIterator::<H265Nalu>::new(input).map(Cow::Borrowed)),
+            EncodedFormat::VP8 | EncodedFormat::VP9 | EncodedFormat::AV1 => {
+                Box::new(IvfIterator::new(input).map(Cow::Borrowed))
+            }
+        };
+        return (format, frame_iter);
+    };
+
+    let probed_format = match probed.codec {
+        TrackCodec::H264 => EncodedFormat::H264,
+        TrackCodec::H265 => EncodedFormat::H265,
+        TrackCodec::Vp8 => EncodedFormat::VP8,
+        TrackCodec::Vp9 => EncodedFormat::VP9,
+        TrackCodec::Av1 => EncodedFormat::AV1,
+    };
+    if let Some(format) = format.filter(|&format| format != probed_format) {
+        panic!(
+            "input file contains a {:?} stream, not {:?}",
+            probed_format, format
+        );
     }
+
+    let frame_iter = probed.chunks(input).expect("error demuxing input file");
+    (probed_format, frame_iter)
 }
 
 /// Decide the output file name when multiple_output_files is set
@@ -285,7 +491,7 @@ fn main() {
 
     let input = {
         let mut buf = Vec::new();
-        File::open(args.input)
+        File::open(&args.input)
             .expect("error opening input file")
             .read_to_end(&mut buf)
             .expect("error reading input file");
@@ -332,6 +538,7 @@ fn main() {
 
             let gbm_path = args
                 .gbm_device
+                .clone()
                 .unwrap_or(PathBuf::from("/dev/dri/renderD128"));
             let gbm = GbmDevice::open(gbm_path)
                 .and_then(gbm::Device::new)
@@ -342,72 +549,42 @@ fn main() {
     };
 
     let display = libva::Display::open().expect("failed to open libva display");
-    let (mut decoder, frame_iter) = match args.input_format {
-        EncodedFormat::H264 => {
-            let frame_iter = Box::new(NalIterator::<H264Nalu>::new(&input).map(Cow::Borrowed))
-                as Box<dyn Iterator<Item = Cow<[u8]>>>;
-
-            let decoder = Box::new(StatelessDecoder::<H264, _>::new_vaapi(
-                display,
-                blocking_mode,
-            )) as Box<dyn StatelessVideoDecoder<_>>;
-
-            (decoder, frame_iter)
-        }
-        EncodedFormat::VP8 => {
-            let frame_iter = create_vpx_frame_iterator(&input);
-
-            let decoder = Box::new(StatelessDecoder::<Vp8, _>::new_vaapi(
-                display,
-                blocking_mode,
-            )) as Box<dyn StatelessVideoDecoder<_>>;
-
-            (decoder, frame_iter)
-        }
-        EncodedFormat::VP9 => {
-            let frame_iter = create_vpx_frame_iterator(&input);
-
-            let decoder = Box::new(StatelessDecoder::<Vp9, _>::new_vaapi(
-                display,
-                blocking_mode,
-            )) as Box<dyn StatelessVideoDecoder<_>>;
-
-            (decoder, frame_iter)
-        }
-        EncodedFormat::H265 => {
-            let frame_iter = Box::new(NalIterator::<H265Nalu>::new(&input).map(Cow::Borrowed))
-                as Box<dyn Iterator<Item = Cow<[u8]>>>;
-
-            let decoder = Box::new(StatelessDecoder::<H265, _>::new_vaapi(
-                display,
-                blocking_mode,
-            )) as Box<dyn StatelessVideoDecoder<_>>;
-
-            (decoder, frame_iter)
-        }
-        EncodedFormat::AV1 => {
-            let frame_iter = create_vpx_frame_iterator(&input);
-
-            let decoder = Box::new(StatelessDecoder::<Av1, _>::new_vaapi(
-                display,
-                blocking_mode,
-            )) as Box<dyn StatelessVideoDecoder<_>>;
-
-            (decoder, frame_iter)
-        }
+    let (input_format, frame_iter) = create_frame_iterator(&input, args.input_format);
+    let mut decoder = match input_format {
+        EncodedFormat::H264 => Box::new(StatelessDecoder::<H264, _>::new_vaapi(
+            display,
+            blocking_mode,
+        )) as Box<dyn StatelessVideoDecoder<_>>,
+        EncodedFormat::VP8 => Box::new(StatelessDecoder::<Vp8, _>::new_vaapi(
+            display,
+            blocking_mode,
+        )) as Box<dyn StatelessVideoDecoder<_>>,
+        EncodedFormat::VP9 => Box::new(StatelessDecoder::<Vp9, _>::new_vaapi(
+            display,
+            blocking_mode,
+        )) as Box<dyn StatelessVideoDecoder<_>>,
+        EncodedFormat::H265 => Box::new(StatelessDecoder::<H265, _>::new_vaapi(
+            display,
+            blocking_mode,
+        )) as Box<dyn StatelessVideoDecoder<_>>,
+        EncodedFormat::AV1 => Box::new(StatelessDecoder::<Av1, _>::new_vaapi(
+            display,
+            blocking_mode,
+        )) as Box<dyn StatelessVideoDecoder<_>>,
     };
 
     let mut md5_context = md5::Context::new();
     let mut output_filename_idx = 0;
+    let mut report = Report::default();
 
     let mut on_new_frame = |handle: Rc<RefCell<VaapiDecodedHandle<_>>>| {
-        if args.output.is_some() || args.compute_md5.is_some() {
+        if args.output.is_some() || args.compute_md5.is_some() || args.report.is_some() {
             handle.sync().unwrap();
             let picture = handle.dyn_picture();
-            let mut handle = picture.dyn_mappable_handle().unwrap();
-            let buffer_size = handle.image_size();
+            let mut mappable = picture.dyn_mappable_handle().unwrap();
+            let buffer_size = mappable.image_size();
             let mut frame_data = vec![0; buffer_size];
-            handle.read(&mut frame_data).unwrap();
+            mappable.read(&mut frame_data).unwrap();
 
             if args.multiple_output_files {
                 let file_name = decide_output_file_name(
@@ -433,10 +610,14 @@ fn main() {
                 Some(Md5Computation::Frame) => println!("{:x}", md5::compute(&frame_data)),
                 Some(Md5Computation::Stream) => md5_context.consume(&frame_data),
             }
+
+            if args.report.is_some() {
+                report.add_frame(&handle, args.output_format, &frame_data);
+            }
         }
     };
 
-    simple_playback_loop(
+    let res = simple_playback_loop(
         decoder.as_mut(),
         frame_iter,
         &mut on_new_frame,
@@ -466,10 +647,21 @@ fn main() {
         },
         args.output_format,
         blocking_mode,
-    )
-    .expect("error during playback loop");
+    );
+
+    let stream_md5 = md5_context.compute();
+
+    if let Some(path) = &args.report {
+        report.error = res.as_ref().err().map(|e| format!("{:#}", e));
+        let mut file = File::create(path).expect("error creating report file");
+        report
+            .write_json(&mut file, &args, input_format, stream_md5)
+            .expect("error writing report file");
+    }
+
+    res.expect("error during playback loop");
 
     if let Some(Md5Computation::Stream) = args.compute_md5 {
-        println!("{:x}", md5_context.compute());
+        println!("{:x}", stream_md5);
     }
 }
@@ -2,64 +2,176 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file.
 
+//! ccenc, a simple encoder program using cros-codecs. Reads raw NV12/I420 or y4m frames and
+//! writes the encoded stream as Annex B or IVF.
+
 use std::borrow::Borrow;
+use std::ffi::OsStr;
 use std::fs::File;
-use std::io::Read;
+use std::io::BufReader;
 use std::io::Write;
 use std::path::PathBuf;
 use std::rc::Rc;
+use std::str::FromStr;
 
 use argh::FromArgs;
 use cros_codecs::backend::vaapi::surface_pool::VaSurfacePool;
 use cros_codecs::codec::h264::parser::Profile;
 use cros_codecs::decoder::FramePool;
 use cros_codecs::encoder::stateless::h264::EncoderConfig;
+use cros_codecs::encoder::stateless::h264::PredictionStructure;
 use cros_codecs::encoder::stateless::h264::StatelessEncoder;
+use cros_codecs::encoder::stateless::simple_encode_loop;
 use cros_codecs::encoder::stateless::StatelessVideoEncoder;
 use cros_codecs::encoder::Bitrate;
+use cros_codecs::encoder::CodedBitstreamBuffer;
 use cros_codecs::encoder::FrameMetadata;
+use cros_codecs::utils::ivf::IvfWriter;
+use cros_codecs::utils::raw::packed_layout;
+use cros_codecs::utils::raw::RawFrameProducer;
+use cros_codecs::utils::y4m::Y4MFrameProducer;
+use cros_codecs::utils::y4m::Y4MReader;
+use cros_codecs::utils::IvfFileHeader;
 use cros_codecs::BlockingMode;
 use cros_codecs::Fourcc;
 use cros_codecs::FrameLayout;
 use cros_codecs::PlaneLayout;
 use cros_codecs::Resolution;
 
-/// Simple encoder
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+enum EncodedFormat {
+    H264,
+    H265,
+    VP8,
+    VP9,
+    AV1,
+}
+
+impl FromStr for EncodedFormat {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "h264" | "H264" => Ok(EncodedFormat::H264),
+            "h265" | "H265" => Ok(EncodedFormat::H265),
+            "vp8" | "VP8" => Ok(EncodedFormat::VP8),
+            "vp9" | "VP9" => Ok(EncodedFormat::VP9),
+            "av1" | "AV1" => Ok(EncodedFormat::AV1),
+            _ => Err("unrecognized codec. Valid values: h264, h265, vp8, vp9, av1"),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+enum InputFormat {
+    Y4m,
+    Nv12,
+    I420,
+}
+
+impl FromStr for InputFormat {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "y4m" => Ok(InputFormat::Y4m),
+            "nv12" | "NV12" => Ok(InputFormat::Nv12),
+            "i420" | "I420" => Ok(InputFormat::I420),
+            _ => Err("unrecognized input format. Valid values: y4m, nv12, i420"),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+enum OutputFormat {
+    AnnexB,
+    Ivf,
+}
+
+impl FromStr for OutputFormat {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "annexb" => Ok(OutputFormat::AnnexB),
+            "ivf" => Ok(OutputFormat::Ivf),
+            _ => Err("unrecognized output format. Valid values: annexb, ivf"),
+        }
+    }
+}
+
+fn parse_h264_profile(s: &str) -> Result<Profile, String> {
+    match s {
+        "baseline" => Ok(Profile::Baseline),
+        "main" => Ok(Profile::Main),
+        "high" => Ok(Profile::High),
+        _ => Err("unrecognized profile. Valid values: baseline, main, high".into()),
+    }
+}
+
+/// Simple encoder using cros-codecs
 #[derive(Debug, FromArgs)]
 struct Args {
     /// input file
     #[argh(positional)]
     input: PathBuf,
 
-    /// input frames width
+    /// format of the input file (y4m, nv12 or i420). Default: y4m if the input file has the .y4m
+    /// extension, nv12 otherwise.
     #[argh(option)]
-    width: u32,
+    input_format: Option<InputFormat>,
 
-    /// input frames height
+    /// input frames width, required for raw input
     #[argh(option)]
-    height: u32,
+    width: Option<u32>,
+
+    /// input frames height, required for raw input
+    #[argh(option)]
+    height: Option<u32>,
 
-    /// input frames count
+    /// maximum number of frames to encode. Default: all the frames of the input
     #[argh(option)]
-    count: usize,
+    count: Option<usize>,
+
+    /// codec to encode into. Default: h264
+    #[argh(option, default = "EncodedFormat::H264")]
+    codec: EncodedFormat,
+
+    /// H.264 profile (baseline, main or high). Default: baseline
+    #[argh(option, default = "Profile::Baseline", from_str_fn(parse_h264_profile))]
+    profile: Profile,
+
+    /// target bitrate in bits per second
+    #[argh(option)]
+    bitrate: Option<u64>,
+
+    /// number of frames between two keyframes
+    #[argh(option)]
+    gop: Option<u16>,
 
     /// default quantization parameter
     #[argh(option)]
     default_qp: Option<u8>,
 
-    /// framerate
+    /// framerate. Default: the framerate of y4m input, or 30
     #[argh(option)]
     framerate: Option<u32>,
 
-    /// output file to write the decoded frames to
+    /// output file to write the encoded stream to
     #[argh(option)]
     output: Option<PathBuf>,
 
+    /// format of the output file (annexb or ivf). Default: annexb for h264 and h265, ivf otherwise
+    #[argh(option)]
+    output_format: Option<OutputFormat>,
+
     /// set to true if low power version of the API shall be used
     #[argh(switch)]
     low_power: bool,
 }
 
+/// Uploads the NV12 frame `data`, packed without padding, to `surface` and returns the layout of
+/// the surface.
 fn upload_img<M: libva::SurfaceMemoryDescriptor>(
     display: &Rc<libva::Display>,
     surface: &libva::Surface<M>,
@@ -96,13 +208,14 @@ fn upload_img<M: libva::SurfaceMemoryDescriptor>(
     let mut src = &data[width * height..];
     let mut dst = &mut dest[va_image.offsets[1] as usize..];
 
-    let height = height / 2;
+    let height = height.div_ceil(2);
+    let uv_width = width.next_multiple_of(2);
 
     // Copy chroma
     for _ in 0..height {
-        dst[..width].copy_from_slice(&src[..width]);
+        dst[..uv_width].copy_from_slice(&src[..uv_width]);
         dst = &mut dst[va_image.pitches[1] as usize..];
-        src = &src[width..];
+        src = &src[uv_width..];
     }
     drop(image);
 
@@ -114,34 +227,127 @@ fn upload_img<M: libva::SurfaceMemoryDescriptor>(
         planes: vec![
             PlaneLayout {
                 buffer_index: 0,
-                offset: 0,
+                offset: va_image.offsets[0] as usize,
                 stride: va_image.pitches[0] as usize,
             },
             PlaneLayout {
                 buffer_index: 0,
-                offset: va_image.offsets[0] as usize,
+                offset: va_image.offsets[1] as usize,
                 stride: va_image.pitches[1] as usize,
             },
         ],
     }
 }
 
+/// Destination of the encoded stream.
+enum Output {
+    AnnexB(File),
+    Ivf(IvfWriter<File>),
+}
+
+impl Output {
+    fn write(&mut self, coded: &CodedBitstreamBuffer) -> std::io::Result<()> {
+        match self {
+            Output::AnnexB(file) => file.write_all(&coded.bitstream),
+            Output::Ivf(writer) => writer.write_frame(&coded.bitstream, coded.metadata.timestamp),
+        }
+    }
+
+    fn finish(self) -> std::io::Result<()> {
+        match self {
+            Output::AnnexB(mut file) => file.flush(),
+            Output::Ivf(writer) => writer.finish().map(drop),
+        }
+    }
+}
+
+/// Encodes at most `count` frames of `frames`, whose handles are paired with the layout of their
+/// surface.
+fn encode<E, H, I>(
+    encoder: &mut E,
+    frames: I,
+    count: Option<usize>,
+    coded_consumer: impl FnMut(CodedBitstreamBuffer),
+) where
+    E: StatelessVideoEncoder<H>,
+    I: Iterator<Item = (FrameMetadata, (H, FrameLayout))>,
+{
+    let mut frames =
+        frames
+            .take(count.unwrap_or(usize::MAX))
+            .map(|(mut meta, (handle, layout))| {
+                meta.layout = layout;
+                (meta, handle)
+            });
+
+    simple_encode_loop(encoder, &mut frames, coded_consumer).expect("error while encoding");
+}
+
 fn main() {
     env_logger::init();
 
     let args: Args = argh::from_env();
 
-    let mut input = File::open(args.input).expect("error opening input file");
+    // Only H.264 can be encoded for now.
+    if args.codec != EncodedFormat::H264 {
+        eprintln!("{:?} encoding is not supported, only h264 is", args.codec);
+        std::process::exit(1);
+    }
 
-    let resolution = Resolution {
-        width: args.width,
-        height: args.height,
+    let is_annexb_codec = matches!(args.codec, EncodedFormat::H264 | EncodedFormat::H265);
+    let output_format = args.output_format.unwrap_or(if is_annexb_codec {
+        OutputFormat::AnnexB
+    } else {
+        OutputFormat::Ivf
+    });
+    let ivf_codec = match args.codec {
+        EncodedFormat::VP8 => Some(IvfFileHeader::CODEC_VP8),
+        EncodedFormat::VP9 => Some(IvfFileHeader::CODEC_VP9),
+        EncodedFormat::AV1 => Some(IvfFileHeader::CODEC_AV1),
+        EncodedFormat::H264 | EncodedFormat::H265 => None,
     };
+    if (output_format == OutputFormat::Ivf) != ivf_codec.is_some() {
+        eprintln!(
+            "{:?} output is not supported for {:?}",
+            output_format, args.codec
+        );
+        std::process::exit(1);
+    }
+
+    let input_format =
+        args.input_format
+            .unwrap_or(if args.input.extension() == Some(OsStr::new("y4m")) {
+                InputFormat::Y4m
+            } else {
+                InputFormat::Nv12
+            });
+
+    let input = File::open(&args.input).expect("error opening input file");
+    let (y4m_reader, resolution, input_framerate) = match input_format {
+        InputFormat::Y4m => {
+            let reader = Y4MReader::new(BufReader::new(input)).expect("error reading y4m header");
+            let header = reader.header().clone();
+            let (num, den) = header.frame_rate;
+            let framerate = (num + den / 2) / den;
+
+            (Some(reader), header.resolution, Some(framerate))
+        }
+        InputFormat::Nv12 | InputFormat::I420 => {
+            let (Some(width), Some(height)) = (args.width, args.height) else {
+                eprintln!("--width and --height are required for raw input");
+                std::process::exit(1);
+            };
+
+            (None, Resolution { width, height }, None)
+        }
+    };
+
+    let framerate = args.framerate.or(input_framerate).unwrap_or(30);
 
     let mut config = EncoderConfig {
-        bitrate: Bitrate::Constant(2_000_000_000),
-        profile: Profile::Baseline,
-        framerate: 30,
+        bitrate: Bitrate::Constant(args.bitrate.unwrap_or(2_000_000_000)),
+        profile: args.profile,
+        framerate,
         resolution,
 
         ..Default::default()
@@ -151,8 +357,11 @@ fn main() {
         config.default_qp = default_qp;
     }
 
-    if let Some(framerate) = args.framerate {
-        config.framerate = framerate;
+    if let Some(gop) = args.gop {
+        config.pred_structure = PredictionStructure::LowDelay {
+            tail: 1,
+            limit: gop,
+        };
     }
 
     let display = libva::Display::open().unwrap();
@@ -171,46 +380,76 @@ fn main() {
         Rc::clone(&display),
         libva::constants::VA_RT_FORMAT_YUV420,
         Some(libva::UsageHint::USAGE_HINT_ENCODER),
-        Resolution {
-            width: args.width,
-            height: args.height,
-        },
+        resolution,
     );
 
     pool.add_frames(vec![(); 16]).unwrap();
 
-    let frame_size: usize = (args.width * args.height + args.width * args.height / 2) as usize;
-
-    let mut output = args.output.map(|output| File::create(output).unwrap());
-
-    let mut buf = vec![0u8; frame_size];
-    for i in 0..args.count {
-        input.read_exact(&mut buf[..]).unwrap();
-        let handle = pool.get_surface().unwrap();
-        let layout = upload_img(&display, handle.borrow(), args.width, args.height, &buf[..]);
+    let mut output = args.output.as_ref().map(|path| {
+        let file = File::create(path).expect("error creating output file");
+        match ivf_codec {
+            Some(codec) => {
+                let header = IvfFileHeader::new(
+                    codec,
+                    resolution.width as u16,
+                    resolution.height as u16,
+                    framerate,
+                    0,
+                );
+                Output::Ivf(IvfWriter::new(file, &header).expect("error writing IVF header"))
+            }
+            None => Output::AnnexB(file),
+        }
+    });
+    let coded_consumer = |coded: CodedBitstreamBuffer| {
+        if let Some(ref mut output) = output {
+            output.write(&coded).expect("error writing output");
+        }
+    };
 
-        let input_frame = FrameMetadata {
-            display_resolution: Resolution {
-                width: args.width,
-                height: args.height,
-            },
-            layout,
-            timestamp: i as u64,
-            force_keyframe: false,
-        };
+    // Frames are converted to NV12 without padding, then uploaded to a surface of the pool.
+    let frame_layout = packed_layout(fourcc, resolution).unwrap();
+    let upload = |_: &FrameMetadata, data: &[u8]| {
+        let handle = pool.get_surface().expect("no free surface in the pool");
+        let layout = upload_img(
+            &display,
+            handle.borrow(),
+            resolution.width,
+            resolution.height,
+            data,
+        );
+
+        (handle, layout)
+    };
 
-        encoder.encode(input_frame, handle).unwrap();
-        while let Some(coded) = encoder.poll().unwrap() {
-            if let Some(ref mut output) = output {
-                output.write_all(&coded.bitstream).unwrap();
+    match y4m_reader {
+        Some(reader) => {
+            let mut producer =
+                Y4MFrameProducer::new(reader, frame_layout, upload).expect("unsupported y4m input");
+            encode(&mut encoder, producer.by_ref(), args.count, coded_consumer);
+            if let Some(e) = producer.take_error() {
+                panic!("error reading y4m input: {:#}", e);
             }
         }
+        None => {
+            let input_fourcc = match input_format {
+                InputFormat::I420 => Fourcc::from(b"I420"),
+                _ => Fourcc::from(b"NV12"),
+            };
+            let input = File::open(&args.input).expect("error opening input file");
+            let producer = RawFrameProducer::new(
+                &input,
+                packed_layout(input_fourcc, resolution).unwrap(),
+                resolution,
+                frame_layout,
+                upload,
+            )
+            .expect("unsupported raw input");
+            encode(&mut encoder, producer, args.count, coded_consumer);
+        }
     }
 
-    encoder.drain().unwrap();
-    while let Some(coded) = encoder.poll().unwrap() {
-        if let Some(ref mut output) = output {
-            output.write_all(&coded.bitstream).unwrap();
-        }
+    if let Some(output) = output {
+        output.finish().expect("error finishing output");
     }
 }
@@ -0,0 +1,16 @@
+// Copyright 2024 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Runs all the codec parsers on arbitrary data.
+//!
+//! Run with `cargo fuzz run parsers` from the root of the repository.
+
+#![no_main]
+
+use cros_codecs::codec::fuzz::FuzzInput;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|input: FuzzInput| {
+    input.run();
+});
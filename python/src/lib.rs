@@ -0,0 +1,503 @@
+// Copyright 2024 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Python bindings of cros-codecs, to script experiments against the same decoders, encoders and
+//! parsers as the ones used in production.
+//!
+//! The `cros_codecs_py` module provides:
+//!
+//! * `decode(codec, data)`, decoding a complete stream with VAAPI into a list of `DecodedFrame`s,
+//!   whose NV12 planes are numpy arrays,
+//! * `encode_h264(frames, bitrate, framerate)`, encoding a list of `(y, uv)` NV12 planes with
+//!   VAAPI into an H.264 Annex B stream,
+//! * `h264_nal_units(data)`, `ivf_frames(data)` and the `Vp9Parser` class, exposing the parsers.
+//!
+//! `codec` is one of `"h264"`, `"h265"`, `"vp8"`, `"vp9"` or `"av1"`. H.264 and H.265 streams are
+//! Annex B, other streams are IVF files. Build the module with `maturin develop` from this
+//! directory.
+
+use std::borrow::Borrow;
+use std::io::Cursor;
+use std::rc::Rc;
+
+use anyhow::anyhow;
+use cros_codecs::backend::vaapi::decoder::VaapiBackend as VaapiDecoderBackend;
+use cros_codecs::backend::vaapi::encoder::upload_nv12;
+use cros_codecs::backend::vaapi::encoder::VaapiBackend as VaapiEncoderBackend;
+use cros_codecs::backend::vaapi::surface_pool::PooledVaSurface;
+use cros_codecs::backend::vaapi::surface_pool::VaSurfacePool;
+use cros_codecs::codec::fuzz::FuzzTarget;
+use cros_codecs::codec::h264::parser as h264;
+use cros_codecs::codec::h265::parser::Nalu as H265Nalu;
+use cros_codecs::codec::vp9::parser as vp9;
+use cros_codecs::decoder::stateless::av1::Av1;
+use cros_codecs::decoder::stateless::h264::H264;
+use cros_codecs::decoder::stateless::h265::H265;
+use cros_codecs::decoder::stateless::vp8::Vp8;
+use cros_codecs::decoder::stateless::vp9::Vp9;
+use cros_codecs::decoder::stateless::StatelessDecoder;
+use cros_codecs::decoder::stateless::StatelessVideoDecoder;
+use cros_codecs::decoder::DecodedHandle;
+use cros_codecs::decoder::FramePool;
+use cros_codecs::encoder::stateless::h264::EncoderConfig;
+use cros_codecs::encoder::stateless::h264::StatelessEncoder;
+use cros_codecs::encoder::stateless::StatelessVideoEncoder;
+use cros_codecs::encoder::Bitrate;
+use cros_codecs::encoder::FrameMetadata;
+use cros_codecs::libva;
+use cros_codecs::libva::Display;
+use cros_codecs::utils::simple_playback_loop;
+use cros_codecs::utils::simple_playback_loop_owned_frames;
+use cros_codecs::utils::IvfIterator;
+use cros_codecs::utils::NalIterator;
+use cros_codecs::BlockingMode;
+use cros_codecs::DecodedFormat;
+use cros_codecs::Fourcc;
+use cros_codecs::Resolution;
+use numpy::ndarray::Array2;
+use numpy::IntoPyArray;
+use numpy::PyArray2;
+use numpy::PyReadonlyArray2;
+use pyo3::exceptions::PyValueError;
+use pyo3::prelude::*;
+use pyo3::types::PyBytes;
+
+/// Number of input surfaces allocated by `encode_h264`.
+const NUM_INPUT_SURFACES: usize = 16;
+
+fn codec_target(codec: &str) -> PyResult<FuzzTarget> {
+    match codec {
+        "h264" => Ok(FuzzTarget::H264),
+        "h265" => Ok(FuzzTarget::H265),
+        "vp8" => Ok(FuzzTarget::Vp8),
+        "vp9" => Ok(FuzzTarget::Vp9),
+        "av1" => Ok(FuzzTarget::Av1),
+        _ => Err(PyValueError::new_err(format!("unknown codec {:?}", codec))),
+    }
+}
+
+fn open_display() -> anyhow::Result<Rc<Display>> {
+    Display::open().ok_or_else(|| anyhow!("cannot open VA display"))
+}
+
+/// Returns the shapes of the luma and interleaved chroma planes of an NV12 frame of `size`.
+fn nv12_shapes(size: Resolution) -> ((usize, usize), (usize, usize)) {
+    let width = size.width as usize;
+    let height = size.height as usize;
+
+    ((height, width), (height.div_ceil(2), width.div_ceil(2) * 2))
+}
+
+/// Splits the tightly packed NV12 `frame` of `size` into its two planes.
+fn split_nv12(size: Resolution, frame: &[u8]) -> anyhow::Result<(Array2<u8>, Array2<u8>)> {
+    let (y_shape, uv_shape) = nv12_shapes(size);
+    let y_size = y_shape.0 * y_shape.1;
+    let uv_size = uv_shape.0 * uv_shape.1;
+    anyhow::ensure!(
+        frame.len() >= y_size + uv_size,
+        "decoded frame of {} bytes is too small for {}x{} NV12",
+        frame.len(),
+        size.width,
+        size.height
+    );
+
+    Ok((
+        Array2::from_shape_vec(y_shape, frame[..y_size].to_vec())?,
+        Array2::from_shape_vec(uv_shape, frame[y_size..y_size + uv_size].to_vec())?,
+    ))
+}
+
+/// A decoded frame.
+#[pyclass(get_all)]
+struct DecodedFrame {
+    /// Index of the unit of the input stream the frame has been decoded from.
+    timestamp: u64,
+    width: u32,
+    height: u32,
+    /// Luma plane, of shape `(height, width)`.
+    y: Py<PyArray2<u8>>,
+    /// Interleaved chroma plane, of shape `(ceil(height / 2), 2 * ceil(width / 2))`.
+    uv: Py<PyArray2<u8>>,
+}
+
+/// Decodes the complete stream `data`, returning the timestamp, resolution and tightly packed
+/// NV12 data of every frame.
+fn decode_stream(
+    target: FuzzTarget,
+    data: &[u8],
+) -> anyhow::Result<Vec<(u64, Resolution, Vec<u8>)>> {
+    let display = open_display()?;
+    let blocking_mode = BlockingMode::Blocking;
+    let (mut decoder, units) = match target {
+        FuzzTarget::H264 => (
+            Box::new(StatelessDecoder::<H264, _>::new_vaapi(
+                display,
+                blocking_mode,
+            )) as Box<dyn StatelessVideoDecoder<VaapiDecoderBackend<()>>>,
+            NalIterator::<h264::Nalu>::new(data).collect::<Vec<_>>(),
+        ),
+        FuzzTarget::H265 => (
+            Box::new(StatelessDecoder::<H265, _>::new_vaapi(
+                display,
+                blocking_mode,
+            )) as Box<dyn StatelessVideoDecoder<_>>,
+            NalIterator::<H265Nalu>::new(data).collect(),
+        ),
+        FuzzTarget::Vp8 => (
+            Box::new(StatelessDecoder::<Vp8, _>::new_vaapi(
+                display,
+                blocking_mode,
+            )) as Box<dyn StatelessVideoDecoder<_>>,
+            IvfIterator::new(data).collect(),
+        ),
+        FuzzTarget::Vp9 => (
+            Box::new(StatelessDecoder::<Vp9, _>::new_vaapi(
+                display,
+                blocking_mode,
+            )) as Box<dyn StatelessVideoDecoder<_>>,
+            IvfIterator::new(data).collect(),
+        ),
+        FuzzTarget::Av1 => (
+            Box::new(StatelessDecoder::<Av1, _>::new_vaapi(
+                display,
+                blocking_mode,
+            )) as Box<dyn StatelessVideoDecoder<_>>,
+            IvfIterator::new(data).collect(),
+        ),
+    };
+
+    let mut frames = Vec::new();
+    let mut read_error = None;
+    simple_playback_loop(
+        decoder.as_mut(),
+        units.into_iter(),
+        &mut |handle| {
+            if read_error.is_some() {
+                return;
+            }
+
+            let res = handle.sync().and_then(|()| {
+                let picture = handle.dyn_picture();
+                let mut mappable = picture.dyn_mappable_handle()?;
+                let mut frame = vec![0u8; mappable.image_size()];
+                mappable.read(&mut frame)?;
+                Ok(frame)
+            });
+            match res {
+                Ok(frame) => frames.push((handle.timestamp(), handle.display_resolution(), frame)),
+                Err(e) => read_error = Some(e),
+            }
+        },
+        &mut simple_playback_loop_owned_frames,
+        DecodedFormat::NV12,
+        blocking_mode,
+    )?;
+
+    match read_error {
+        Some(e) => Err(e),
+        None => Ok(frames),
+    }
+}
+
+/// Decodes the complete stream `data` encoded with `codec`.
+#[pyfunction]
+fn decode(py: Python<'_>, codec: &str, data: &[u8]) -> PyResult<Vec<DecodedFrame>> {
+    let target = codec_target(codec)?;
+    let frames = py.allow_threads(|| decode_stream(target, data))?;
+
+    frames
+        .into_iter()
+        .map(|(timestamp, size, frame)| {
+            let (y, uv) = split_nv12(size, &frame)?;
+
+            Ok(DecodedFrame {
+                timestamp,
+                width: size.width,
+                height: size.height,
+                y: y.into_pyarray_bound(py).unbind(),
+                uv: uv.into_pyarray_bound(py).unbind(),
+            })
+        })
+        .collect()
+}
+
+/// Encodes the tightly packed NV12 `frames` of `size` into an H.264 Annex B stream.
+fn encode_frames(
+    size: Resolution,
+    frames: &[Vec<u8>],
+    bitrate: u64,
+    framerate: u32,
+) -> anyhow::Result<Vec<u8>> {
+    let display = open_display()?;
+    let config = EncoderConfig {
+        bitrate: Bitrate::Constant(bitrate),
+        framerate,
+        resolution: size,
+        ..Default::default()
+    };
+    let mut encoder =
+        StatelessEncoder::<_, VaapiEncoderBackend<(), PooledVaSurface<()>>>::new_vaapi(
+            Rc::clone(&display),
+            config,
+            Fourcc::from(b"NV12"),
+            size,
+            false,
+            BlockingMode::Blocking,
+        )?;
+
+    let mut pool = VaSurfacePool::new(
+        Rc::clone(&display),
+        libva::constants::VA_RT_FORMAT_YUV420,
+        Some(libva::UsageHint::USAGE_HINT_ENCODER),
+        size,
+    );
+    pool.add_frames(vec![(); NUM_INPUT_SURFACES])?;
+
+    let mut stream = Vec::new();
+    for (timestamp, frame) in frames.iter().enumerate() {
+        let handle = pool
+            .get_surface()
+            .ok_or_else(|| anyhow!("no free input surface"))?;
+        let layout = upload_nv12(&display, handle.borrow(), size, frame)?;
+        let meta = FrameMetadata {
+            timestamp: timestamp as u64,
+            display_resolution: size,
+            layout,
+            force_keyframe: false,
+            damage: None,
+        };
+
+        encoder.encode(meta, handle)?;
+        while let Some(coded) = encoder.poll()? {
+            stream.extend(coded.bitstream);
+        }
+    }
+
+    encoder.drain()?;
+    while let Some(coded) = encoder.poll()? {
+        stream.extend(coded.bitstream);
+    }
+
+    Ok(stream)
+}
+
+/// Encodes `frames`, a list of `(y, uv)` NV12 planes shaped as the ones of `DecodedFrame`, into an
+/// H.264 Annex B stream.
+#[pyfunction]
+#[pyo3(signature = (frames, bitrate = 2_000_000, framerate = 30))]
+fn encode_h264(
+    py: Python<'_>,
+    frames: Vec<(PyReadonlyArray2<u8>, PyReadonlyArray2<u8>)>,
+    bitrate: u64,
+    framerate: u32,
+) -> PyResult<Py<PyBytes>> {
+    let Some((first_y, _)) = frames.first() else {
+        return Err(PyValueError::new_err("no frames to encode"));
+    };
+    let (height, width) = first_y.as_array().dim();
+    let size = Resolution::from((width as u32, height as u32));
+    let (y_shape, uv_shape) = nv12_shapes(size);
+
+    let mut packed = Vec::with_capacity(frames.len());
+    for (index, (y, uv)) in frames.iter().enumerate() {
+        let (y, uv) = (y.as_array(), uv.as_array());
+        if y.dim() != y_shape || uv.dim() != uv_shape {
+            return Err(PyValueError::new_err(format!(
+                "frame {} has planes of shapes {:?} and {:?}, expected {:?} and {:?}",
+                index,
+                y.dim(),
+                uv.dim(),
+                y_shape,
+                uv_shape
+            )));
+        }
+
+        packed.push(y.iter().chain(uv.iter()).copied().collect::<Vec<_>>());
+    }
+
+    let stream = py.allow_threads(|| encode_frames(size, &packed, bitrate, framerate))?;
+
+    Ok(PyBytes::new_bound(py, &stream).unbind())
+}
+
+/// A NAL unit of an H.264 stream. The fields that do not apply to the type of the NAL unit, or
+/// that could not be parsed, are `None`.
+#[pyclass(get_all)]
+#[derive(Clone, Debug)]
+struct H264NalUnit {
+    nal_unit_type: u8,
+    nal_ref_idc: u8,
+    /// Offset of the NAL unit in the stream, after its start code.
+    offset: usize,
+    size: usize,
+    /// Fields of SPS NAL units.
+    profile_idc: Option<u8>,
+    level_idc: Option<u8>,
+    width: Option<u32>,
+    height: Option<u32>,
+    /// Fields of slice NAL units.
+    slice_type: Option<String>,
+    frame_num: Option<u16>,
+    pic_order_cnt_lsb: Option<u16>,
+}
+
+fn parse_h264_nal_units(data: &[u8]) -> Vec<H264NalUnit> {
+    let mut parser = h264::Parser::default();
+    let mut cursor = Cursor::new(data);
+    let mut units = Vec::new();
+
+    while let Ok(nalu) = h264::Nalu::next(&mut cursor) {
+        let mut unit = H264NalUnit {
+            nal_unit_type: nalu.header.type_ as u8,
+            nal_ref_idc: nalu.header.ref_idc,
+            offset: nalu.offset,
+            size: nalu.size,
+            profile_idc: None,
+            level_idc: None,
+            width: None,
+            height: None,
+            slice_type: None,
+            frame_num: None,
+            pic_order_cnt_lsb: None,
+        };
+
+        match nalu.header.type_ {
+            h264::NaluType::Sps => {
+                if let Ok(sps) = parser.parse_sps(&nalu) {
+                    let visible = sps.visible_rectangle();
+                    unit.profile_idc = Some(sps.profile_idc);
+                    unit.level_idc = Some(sps.level_idc as u8);
+                    unit.width = Some(visible.max.x - visible.min.x);
+                    unit.height = Some(visible.max.y - visible.min.y);
+                }
+            }
+            h264::NaluType::Pps => {
+                // Needed to parse the following slices.
+                let _ = parser.parse_pps(&nalu);
+            }
+            h264::NaluType::Slice | h264::NaluType::SliceIdr => {
+                if let Ok(slice) = parser.parse_slice_header(nalu) {
+                    unit.slice_type = Some(format!("{:?}", slice.header.slice_type));
+                    unit.frame_num = Some(slice.header.frame_num);
+                    unit.pic_order_cnt_lsb = Some(slice.header.pic_order_cnt_lsb);
+                }
+            }
+            _ => (),
+        }
+
+        units.push(unit);
+    }
+
+    units
+}
+
+/// Returns the NAL units of the H.264 Annex B stream `data`.
+#[pyfunction]
+fn h264_nal_units(data: &[u8]) -> Vec<H264NalUnit> {
+    parse_h264_nal_units(data)
+}
+
+/// Returns the frames of the IVF file `data`.
+#[pyfunction]
+fn ivf_frames(py: Python<'_>, data: &[u8]) -> Vec<Py<PyBytes>> {
+    IvfIterator::new(data)
+        .map(|frame| PyBytes::new_bound(py, frame).unbind())
+        .collect()
+}
+
+/// Uncompressed header of a VP9 frame.
+#[pyclass(get_all)]
+#[derive(Clone, Debug)]
+struct Vp9FrameHeader {
+    key_frame: bool,
+    intra_only: bool,
+    show_frame: bool,
+    show_existing_frame: bool,
+    error_resilient_mode: bool,
+    width: u32,
+    height: u32,
+    base_q_idx: u8,
+}
+
+/// VP9 parser, keeping the state needed to parse the frames of a stream one after the other.
+#[pyclass(unsendable)]
+#[derive(Default)]
+struct Vp9Parser(vp9::Parser);
+
+#[pymethods]
+impl Vp9Parser {
+    #[new]
+    fn new() -> Self {
+        Self::default()
+    }
+
+    /// Parses the headers of the frames of `chunk`, a frame or superframe of the stream.
+    fn parse(&mut self, chunk: &[u8]) -> PyResult<Vec<Vp9FrameHeader>> {
+        Ok(self
+            .0
+            .parse_chunk(chunk)?
+            .into_iter()
+            .map(|frame| {
+                let header = frame.header;
+                Vp9FrameHeader {
+                    key_frame: header.frame_type == vp9::FrameType::KeyFrame,
+                    intra_only: header.intra_only,
+                    show_frame: header.show_frame,
+                    show_existing_frame: header.show_existing_frame,
+                    error_resilient_mode: header.error_resilient_mode,
+                    width: header.width,
+                    height: header.height,
+                    base_q_idx: header.quant.base_q_idx,
+                }
+            })
+            .collect())
+    }
+}
+
+#[pymodule]
+fn cros_codecs_py(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_class::<DecodedFrame>()?;
+    m.add_class::<H264NalUnit>()?;
+    m.add_class::<Vp9FrameHeader>()?;
+    m.add_class::<Vp9Parser>()?;
+    m.add_function(wrap_pyfunction!(decode, m)?)?;
+    m.add_function(wrap_pyfunction!(encode_h264, m)?)?;
+    m.add_function(wrap_pyfunction!(h264_nal_units, m)?)?;
+    m.add_function(wrap_pyfunction!(ivf_frames, m)?)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STREAM_H264: &[u8] = include_bytes!("../../src/codec/h264/test_data/test-25fps.h264");
+
+    #[test]
+    fn h264_units() {
+        let units = parse_h264_nal_units(STREAM_H264);
+
+        let sps = units.iter().find(|unit| unit.nal_unit_type == 7).unwrap();
+        assert_eq!(sps.width, Some(320));
+        assert_eq!(sps.height, Some(240));
+
+        let slices = units.iter().filter(|unit| unit.slice_type.is_some());
+        assert!(slices.count() > 1);
+        assert_eq!(units[0].offset, 4);
+    }
+
+    #[test]
+    fn nv12_planes() {
+        let size = Resolution::from((5, 3));
+        let frame = (0..5 * 3 + 6 * 2).map(|i| i as u8).collect::<Vec<_>>();
+
+        let (y, uv) = split_nv12(size, &frame).unwrap();
+        assert_eq!(y.dim(), (3, 5));
+        assert_eq!(uv.dim(), (2, 6));
+        assert_eq!(uv[(0, 0)], 15);
+
+        assert!(split_nv12(size, &frame[..20]).is_err());
+    }
+}
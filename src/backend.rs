@@ -7,8 +7,18 @@
 //! A backend is a provider of codec decoding or encoding, most likely hardware-accelerated like
 //! VAAPI. This module contains backend-related code that is not tied to any particular codec and
 //! can be shared between various parts of this crate.
+//!
+//! Each backend is enabled by the Cargo feature of the same name, e.g. `vaapi`, or `d3d12` for
+//! the H.264 encoder of Windows. The `v4l2` and `vulkan` features are reserved for the planned
+//! V4L2 and Vulkan Video backends and are empty for now.
+//!
+//! Without any backend the crate still builds on all platforms, and provides the parsers,
+//! synthesizers, container utilities and codec state machines. The latter can be exercised with
+//! the `dummy` backend, which parses the streams and manages the frames without decoding them.
 
-#[cfg(test)]
-pub(crate) mod dummy;
+#[cfg(all(windows, feature = "d3d12"))]
+pub mod d3d12;
+#[cfg(any(test, feature = "dummy"))]
+pub mod dummy;
 #[cfg(feature = "vaapi")]
 pub mod vaapi;
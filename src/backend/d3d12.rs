@@ -0,0 +1,7 @@
+// Copyright 2024 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! D3D12 video encode backend for the stateless encoders on Windows.
+
+pub mod encoder;
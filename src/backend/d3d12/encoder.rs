@@ -0,0 +1,468 @@
+// Copyright 2024 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+use std::any::Any;
+use std::borrow::Borrow;
+use std::cell::RefCell;
+use std::marker::PhantomData;
+use std::mem::ManuallyDrop;
+use std::rc::Rc;
+
+use windows::core::Interface;
+use windows::Win32::Foundation::HANDLE;
+use windows::Win32::Graphics::Direct3D12::ID3D12CommandAllocator;
+use windows::Win32::Graphics::Direct3D12::ID3D12CommandList;
+use windows::Win32::Graphics::Direct3D12::ID3D12CommandQueue;
+use windows::Win32::Graphics::Direct3D12::ID3D12Device;
+use windows::Win32::Graphics::Direct3D12::ID3D12Fence;
+use windows::Win32::Graphics::Direct3D12::ID3D12Resource;
+use windows::Win32::Graphics::Direct3D12::D3D12_COMMAND_LIST_TYPE_VIDEO_ENCODE;
+use windows::Win32::Graphics::Direct3D12::D3D12_COMMAND_QUEUE_DESC;
+use windows::Win32::Graphics::Direct3D12::D3D12_CPU_PAGE_PROPERTY_WRITE_BACK;
+use windows::Win32::Graphics::Direct3D12::D3D12_FENCE_FLAG_NONE;
+use windows::Win32::Graphics::Direct3D12::D3D12_HEAP_FLAG_NONE;
+use windows::Win32::Graphics::Direct3D12::D3D12_HEAP_PROPERTIES;
+use windows::Win32::Graphics::Direct3D12::D3D12_HEAP_TYPE_CUSTOM;
+use windows::Win32::Graphics::Direct3D12::D3D12_HEAP_TYPE_DEFAULT;
+use windows::Win32::Graphics::Direct3D12::D3D12_MEMORY_POOL_L0;
+use windows::Win32::Graphics::Direct3D12::D3D12_RANGE;
+use windows::Win32::Graphics::Direct3D12::D3D12_RESOURCE_BARRIER;
+use windows::Win32::Graphics::Direct3D12::D3D12_RESOURCE_BARRIER_0;
+use windows::Win32::Graphics::Direct3D12::D3D12_RESOURCE_BARRIER_ALL_SUBRESOURCES;
+use windows::Win32::Graphics::Direct3D12::D3D12_RESOURCE_BARRIER_FLAG_NONE;
+use windows::Win32::Graphics::Direct3D12::D3D12_RESOURCE_BARRIER_TYPE_TRANSITION;
+use windows::Win32::Graphics::Direct3D12::D3D12_RESOURCE_DESC;
+use windows::Win32::Graphics::Direct3D12::D3D12_RESOURCE_DIMENSION_BUFFER;
+use windows::Win32::Graphics::Direct3D12::D3D12_RESOURCE_DIMENSION_TEXTURE2D;
+use windows::Win32::Graphics::Direct3D12::D3D12_RESOURCE_FLAGS;
+use windows::Win32::Graphics::Direct3D12::D3D12_RESOURCE_FLAG_NONE;
+use windows::Win32::Graphics::Direct3D12::D3D12_RESOURCE_STATES;
+use windows::Win32::Graphics::Direct3D12::D3D12_RESOURCE_STATE_COMMON;
+use windows::Win32::Graphics::Direct3D12::D3D12_RESOURCE_TRANSITION_BARRIER;
+use windows::Win32::Graphics::Direct3D12::D3D12_TEXTURE_LAYOUT_ROW_MAJOR;
+use windows::Win32::Graphics::Direct3D12::D3D12_TEXTURE_LAYOUT_UNKNOWN;
+use windows::Win32::Graphics::Dxgi::Common::DXGI_FORMAT_NV12;
+use windows::Win32::Graphics::Dxgi::Common::DXGI_FORMAT_UNKNOWN;
+use windows::Win32::Graphics::Dxgi::Common::DXGI_SAMPLE_DESC;
+use windows::Win32::Media::MediaFoundation::ID3D12VideoDevice3;
+use windows::Win32::Media::MediaFoundation::ID3D12VideoEncodeCommandList2;
+use windows::Win32::Media::MediaFoundation::ID3D12VideoEncoder;
+use windows::Win32::Media::MediaFoundation::ID3D12VideoEncoderHeap;
+use windows::Win32::Media::MediaFoundation::D3D12_VIDEO_ENCODER_OUTPUT_METADATA;
+
+use crate::encoder::stateless::BackendPromise;
+use crate::encoder::stateless::StatelessBackendError;
+use crate::encoder::stateless::StatelessBackendResult;
+use crate::encoder::stateless::StatelessEncoderBackendImport;
+use crate::encoder::FrameMetadata;
+use crate::Fourcc;
+use crate::Resolution;
+
+impl From<windows::core::Error> for StatelessBackendError {
+    fn from(value: windows::core::Error) -> Self {
+        Self::Other(value.into())
+    }
+}
+
+/// Reconstructed picture of the backend, returned to its pool once no longer referenced.
+pub struct Reconstructed {
+    texture: Option<ID3D12Resource>,
+    pool: Rc<RefCell<Vec<ID3D12Resource>>>,
+}
+
+impl Reconstructed {
+    pub(crate) fn texture(&self) -> &ID3D12Resource {
+        self.texture.as_ref().unwrap()
+    }
+}
+
+impl Drop for Reconstructed {
+    fn drop(&mut self) {
+        if let Some(texture) = self.texture.take() {
+            self.pool.borrow_mut().push(texture);
+        }
+    }
+}
+
+/// Video encoder and heap created for a codec configuration. The codec backends recreate it when
+/// the configuration changes, e.g. when a new sequence starts with other parameters.
+pub(crate) struct Session {
+    /// Codec specific parameters the session was created for.
+    pub(crate) params: Box<dyn Any>,
+    pub(crate) encoder: ID3D12VideoEncoder,
+    pub(crate) heap: ID3D12VideoEncoderHeap,
+    /// Size of the opaque metadata written by the encoder for each frame.
+    pub(crate) metadata_size: u64,
+    /// Alignment of the size of the bitstream buffers.
+    pub(crate) bitstream_alignment: u64,
+    /// Target bitrate and framerate of the last submitted frame, to signal their changes.
+    pub(crate) rate_control: Option<(u64, (u32, u32))>,
+}
+
+/// Encoder backend submitting the frames to a video encode queue of a D3D12 device. The input
+/// frames are NV12 textures of the device in the common state, which are encoded as is.
+pub struct D3d12Backend<H> {
+    device: ID3D12Device,
+    video_device: ID3D12VideoDevice3,
+    queue: ID3D12CommandQueue,
+    fence: ID3D12Fence,
+    /// Value signaled by the queue once the last submitted frame is encoded.
+    fence_value: u64,
+    coded_size: Resolution,
+    /// Flags of the reconstructed textures, which depend on the session.
+    recon_flags: D3D12_RESOURCE_FLAGS,
+    recon_pool: Rc<RefCell<Vec<ID3D12Resource>>>,
+    session: Option<Session>,
+    _phantom: PhantomData<H>,
+}
+
+impl<H> D3d12Backend<H> {
+    pub fn new(device: ID3D12Device, coded_size: Resolution) -> StatelessBackendResult<Self> {
+        let video_device: ID3D12VideoDevice3 = device
+            .cast()
+            .map_err(|e| StatelessBackendError::Other(anyhow::anyhow!("no video encoding: {e}")))?;
+        let queue: ID3D12CommandQueue = unsafe {
+            device.CreateCommandQueue(&D3D12_COMMAND_QUEUE_DESC {
+                Type: D3D12_COMMAND_LIST_TYPE_VIDEO_ENCODE,
+                ..Default::default()
+            })?
+        };
+        let fence: ID3D12Fence = unsafe { device.CreateFence(0, D3D12_FENCE_FLAG_NONE)? };
+
+        Ok(Self {
+            device,
+            video_device,
+            queue,
+            fence,
+            fence_value: 0,
+            coded_size,
+            recon_flags: D3D12_RESOURCE_FLAG_NONE,
+            recon_pool: Default::default(),
+            session: None,
+            _phantom: Default::default(),
+        })
+    }
+
+    pub(crate) fn video_device(&self) -> &ID3D12VideoDevice3 {
+        &self.video_device
+    }
+
+    pub(crate) fn coded_size(&self) -> Resolution {
+        self.coded_size
+    }
+
+    pub(crate) fn session(&self) -> Option<&Session> {
+        self.session.as_ref()
+    }
+
+    pub(crate) fn session_mut(&mut self) -> Option<&mut Session> {
+        self.session.as_mut()
+    }
+
+    /// Replaces the session, whose reconstructed pictures are textures with `recon_flags`.
+    pub(crate) fn set_session(&mut self, session: Session, recon_flags: D3D12_RESOURCE_FLAGS) {
+        if recon_flags != self.recon_flags {
+            self.recon_flags = recon_flags;
+            self.recon_pool.borrow_mut().clear();
+        }
+        self.session = Some(session);
+    }
+
+    /// Creates a buffer of `size` bytes, which the CPU can read back if `readable`.
+    pub(crate) fn create_buffer(
+        &self,
+        size: u64,
+        readable: bool,
+    ) -> StatelessBackendResult<ID3D12Resource> {
+        let heap = if readable {
+            D3D12_HEAP_PROPERTIES {
+                Type: D3D12_HEAP_TYPE_CUSTOM,
+                CPUPageProperty: D3D12_CPU_PAGE_PROPERTY_WRITE_BACK,
+                MemoryPoolPreference: D3D12_MEMORY_POOL_L0,
+                ..Default::default()
+            }
+        } else {
+            D3D12_HEAP_PROPERTIES {
+                Type: D3D12_HEAP_TYPE_DEFAULT,
+                ..Default::default()
+            }
+        };
+        let desc = D3D12_RESOURCE_DESC {
+            Dimension: D3D12_RESOURCE_DIMENSION_BUFFER,
+            Width: size,
+            Height: 1,
+            DepthOrArraySize: 1,
+            MipLevels: 1,
+            Format: DXGI_FORMAT_UNKNOWN,
+            SampleDesc: DXGI_SAMPLE_DESC {
+                Count: 1,
+                Quality: 0,
+            },
+            Layout: D3D12_TEXTURE_LAYOUT_ROW_MAJOR,
+            ..Default::default()
+        };
+
+        self.create_resource(&heap, &desc)
+    }
+
+    fn create_resource(
+        &self,
+        heap: &D3D12_HEAP_PROPERTIES,
+        desc: &D3D12_RESOURCE_DESC,
+    ) -> StatelessBackendResult<ID3D12Resource> {
+        let mut resource: Option<ID3D12Resource> = None;
+        unsafe {
+            self.device.CreateCommittedResource(
+                heap,
+                D3D12_HEAP_FLAG_NONE,
+                desc,
+                D3D12_RESOURCE_STATE_COMMON,
+                None,
+                &mut resource,
+            )?
+        };
+
+        resource.ok_or(StatelessBackendError::OutOfResources)
+    }
+
+    /// Returns a texture from the pool to reconstruct the next frame into.
+    pub(crate) fn new_reconstructed(&self) -> StatelessBackendResult<Reconstructed> {
+        let texture = match self.recon_pool.borrow_mut().pop() {
+            Some(texture) => texture,
+            None => {
+                let heap = D3D12_HEAP_PROPERTIES {
+                    Type: D3D12_HEAP_TYPE_DEFAULT,
+                    ..Default::default()
+                };
+                let desc = D3D12_RESOURCE_DESC {
+                    Dimension: D3D12_RESOURCE_DIMENSION_TEXTURE2D,
+                    Width: self.coded_size.width as u64,
+                    Height: self.coded_size.height,
+                    DepthOrArraySize: 1,
+                    MipLevels: 1,
+                    Format: DXGI_FORMAT_NV12,
+                    SampleDesc: DXGI_SAMPLE_DESC {
+                        Count: 1,
+                        Quality: 0,
+                    },
+                    Layout: D3D12_TEXTURE_LAYOUT_UNKNOWN,
+                    Flags: self.recon_flags,
+                    ..Default::default()
+                };
+                self.create_resource(&heap, &desc)?
+            }
+        };
+
+        Ok(Reconstructed {
+            texture: Some(texture),
+            pool: Rc::clone(&self.recon_pool),
+        })
+    }
+
+    /// Creates a command list recording into a new allocator, both of which must be kept alive
+    /// until the queue executed the list.
+    pub(crate) fn new_command_list(
+        &self,
+    ) -> StatelessBackendResult<(ID3D12CommandAllocator, ID3D12VideoEncodeCommandList2)> {
+        let allocator: ID3D12CommandAllocator = unsafe {
+            self.device
+                .CreateCommandAllocator(D3D12_COMMAND_LIST_TYPE_VIDEO_ENCODE)?
+        };
+        let list: ID3D12VideoEncodeCommandList2 = unsafe {
+            self.device.CreateCommandList(
+                0,
+                D3D12_COMMAND_LIST_TYPE_VIDEO_ENCODE,
+                &allocator,
+                None,
+            )?
+        };
+
+        Ok((allocator, list))
+    }
+
+    /// Closes `list` and submits it to the queue, returning the fence value signaled once it is
+    /// executed.
+    pub(crate) fn submit(
+        &mut self,
+        list: &ID3D12VideoEncodeCommandList2,
+    ) -> StatelessBackendResult<u64> {
+        unsafe {
+            list.Close()?;
+            self.queue
+                .ExecuteCommandLists(&[Some(list.cast::<ID3D12CommandList>()?)]);
+            self.fence_value += 1;
+            self.queue.Signal(&self.fence, self.fence_value)?;
+        }
+
+        Ok(self.fence_value)
+    }
+
+    pub(crate) fn fence(&self) -> &ID3D12Fence {
+        &self.fence
+    }
+}
+
+/// Returns the barrier transitioning all the subresources of `resource` from `before` to
+/// `after`. The barrier holds a reference to `resource`, released by [`resource_barriers`].
+pub(crate) fn transition(
+    resource: &ID3D12Resource,
+    before: D3D12_RESOURCE_STATES,
+    after: D3D12_RESOURCE_STATES,
+) -> D3D12_RESOURCE_BARRIER {
+    D3D12_RESOURCE_BARRIER {
+        Type: D3D12_RESOURCE_BARRIER_TYPE_TRANSITION,
+        Flags: D3D12_RESOURCE_BARRIER_FLAG_NONE,
+        Anonymous: D3D12_RESOURCE_BARRIER_0 {
+            Transition: ManuallyDrop::new(D3D12_RESOURCE_TRANSITION_BARRIER {
+                pResource: ManuallyDrop::new(Some(resource.clone())),
+                Subresource: D3D12_RESOURCE_BARRIER_ALL_SUBRESOURCES,
+                StateBefore: before,
+                StateAfter: after,
+            }),
+        },
+    }
+}
+
+/// Records the [`transition`] `barriers` into `list`, then releases their resources.
+pub(crate) fn resource_barriers(
+    list: &ID3D12VideoEncodeCommandList2,
+    barriers: Vec<D3D12_RESOURCE_BARRIER>,
+) {
+    unsafe { list.ResourceBarrier(&barriers) };
+    for barrier in barriers {
+        // SAFETY: the barriers built by `transition` are all transitions.
+        let barrier = ManuallyDrop::into_inner(unsafe { barrier.Anonymous.Transition });
+        drop(ManuallyDrop::into_inner(barrier.pResource));
+    }
+}
+
+/// Promise of the bitstream of a frame, ready once the queue signaled the fence value of its
+/// submission.
+pub struct CodedOutputPromise<H> {
+    fence: ID3D12Fence,
+    fence_value: u64,
+
+    /// Buffer the encoder writes the slices into.
+    bitstream: ID3D12Resource,
+
+    /// Buffer the opaque metadata written by the encoder is resolved into with
+    /// `ResolveEncoderOutputMetadata`, holding the [`D3D12_VIDEO_ENCODER_OUTPUT_METADATA`] of the
+    /// frame once the submission completed.
+    metadata: ID3D12Resource,
+
+    /// Container for the request output. Moved from [`StatelessVideoEncoderBackend`] request.
+    /// The output will be appended to it.
+    ///
+    /// [`StatelessVideoEncoderBackend`]: crate::encoder::stateless::StatelessVideoEncoderBackend
+    coded_output: Vec<u8>,
+
+    /// Objects used by the queue until the frame is encoded: the input picture, the command list
+    /// and its allocator, the opaque metadata of the encoder and the references.
+    _input: H,
+    _keepalive: Vec<Box<dyn Any>>,
+    _references: Vec<Rc<dyn Any>>,
+}
+
+impl<H> CodedOutputPromise<H> {
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn new(
+        fence: ID3D12Fence,
+        fence_value: u64,
+        bitstream: ID3D12Resource,
+        metadata: ID3D12Resource,
+        coded_output: Vec<u8>,
+        input: H,
+        keepalive: Vec<Box<dyn Any>>,
+        references: Vec<Rc<dyn Any>>,
+    ) -> Self {
+        Self {
+            fence,
+            fence_value,
+            bitstream,
+            metadata,
+            coded_output,
+            _input: input,
+            _keepalive: keepalive,
+            _references: references,
+        }
+    }
+}
+
+/// Maps `buffer` and copies its first `len` bytes.
+fn read_buffer(buffer: &ID3D12Resource, len: usize) -> StatelessBackendResult<Vec<u8>> {
+    let mut data = std::ptr::null_mut();
+    let range = D3D12_RANGE { Begin: 0, End: len };
+    unsafe {
+        buffer.Map(0, Some(&range), Some(&mut data))?;
+        let bytes = std::slice::from_raw_parts(data as *const u8, len).to_vec();
+        // Nothing was written by the CPU.
+        buffer.Unmap(0, Some(&D3D12_RANGE::default()));
+
+        Ok(bytes)
+    }
+}
+
+impl<H> BackendPromise for CodedOutputPromise<H> {
+    type Output = Vec<u8>;
+
+    fn sync(self) -> StatelessBackendResult<Self::Output> {
+        // Blocks until the fence reaches the value without an event to signal.
+        unsafe {
+            self.fence
+                .SetEventOnCompletion(self.fence_value, HANDLE::default())?
+        };
+
+        let metadata = read_buffer(
+            &self.metadata,
+            std::mem::size_of::<D3D12_VIDEO_ENCODER_OUTPUT_METADATA>(),
+        )?;
+        // SAFETY: the submission resolved the opaque metadata into this buffer before signaling
+        // the fence, so it holds a `D3D12_VIDEO_ENCODER_OUTPUT_METADATA`.
+        let metadata = unsafe {
+            std::ptr::read_unaligned(metadata.as_ptr() as *const D3D12_VIDEO_ENCODER_OUTPUT_METADATA)
+        };
+        if metadata.EncodeErrorFlags != 0 {
+            return Err(StatelessBackendError::Other(anyhow::anyhow!(
+                "failed to encode the frame, error flags {:#x}",
+                metadata.EncodeErrorFlags
+            )));
+        }
+
+        let mut bitstream = self.coded_output;
+        bitstream.extend(read_buffer(
+            &self.bitstream,
+            metadata.EncodedBitstreamWrittenBytesCount as usize,
+        )?);
+
+        Ok(bitstream)
+    }
+
+    fn is_ready(&self) -> bool {
+        unsafe { self.fence.GetCompletedValue() >= self.fence_value }
+    }
+}
+
+impl<H> StatelessEncoderBackendImport<H, H> for D3d12Backend<H>
+where
+    H: Borrow<ID3D12Resource>,
+{
+    fn import_picture(&mut self, metadata: &FrameMetadata, handle: H) -> StatelessBackendResult<H> {
+        if metadata.layout.format.0 != Fourcc::from(b"NV12") {
+            return Err(StatelessBackendError::UnsupportedFormat);
+        }
+        let desc = unsafe { handle.borrow().GetDesc() };
+        let size = Resolution {
+            width: desc.Width as u32,
+            height: desc.Height,
+        };
+        if desc.Format != DXGI_FORMAT_NV12 || !size.can_contain(metadata.layout.size) {
+            return Err(StatelessBackendError::Other(anyhow::anyhow!(
+                "frame of {:?} is not in a NV12 texture of at least its size",
+                metadata.layout.size
+            )));
+        }
+
+        Ok(handle)
+    }
+}
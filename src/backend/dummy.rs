@@ -5,4 +5,4 @@
 //! This file contains a dummy backends whose only purpose is to let the codec
 //! run so we can test it in isolation.
 
-pub(crate) mod decoder;
+pub mod decoder;
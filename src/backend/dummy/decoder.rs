@@ -8,6 +8,7 @@
 use std::cell::RefCell;
 use std::rc::Rc;
 
+use crate::decoder::stateless::Deinterlacing;
 use crate::decoder::stateless::PoolLayer;
 use crate::decoder::stateless::StatelessCodec;
 use crate::decoder::stateless::StatelessDecoderBackend;
@@ -15,14 +16,25 @@ use crate::decoder::stateless::StatelessDecoderBackendPicture;
 use crate::decoder::stateless::TryFormat;
 use crate::decoder::DecodedHandle;
 use crate::decoder::DynHandle;
+use crate::decoder::FieldOrder;
 use crate::decoder::FramePool;
 use crate::decoder::MappableHandle;
+use crate::decoder::MappedFrame;
+use crate::decoder::StereoView;
 use crate::decoder::StreamInfo;
 use crate::DecodedFormat;
+use crate::Fourcc;
+use crate::FrameLayout;
+use crate::PlaneLayout;
 use crate::Resolution;
 
 #[derive(Default)]
-pub struct BackendHandle(());
+pub struct BackendHandle {
+    resource: (),
+    corrupted: bool,
+    view: Option<StereoView>,
+    field_order: Option<FieldOrder>,
+}
 
 impl MappableHandle for BackendHandle {
     fn read(&mut self, _: &mut [u8]) -> anyhow::Result<()> {
@@ -32,6 +44,21 @@ impl MappableHandle for BackendHandle {
     fn image_size(&mut self) -> usize {
         1
     }
+
+    fn map(&mut self) -> anyhow::Result<MappedFrame<'_>> {
+        let plane = PlaneLayout {
+            buffer_index: 0,
+            offset: 0,
+            stride: 0,
+        };
+        let layout = FrameLayout {
+            format: (Fourcc::from(b"NV12"), 0),
+            size: Default::default(),
+            planes: vec![plane.clone(), plane],
+        };
+
+        MappedFrame::new(&[], &layout, true)
+    }
 }
 
 impl<'a> DynHandle for std::cell::Ref<'a, BackendHandle> {
@@ -79,18 +106,53 @@ impl DecodedHandle for Handle {
         true
     }
 
+    fn is_corrupted(&self) -> bool {
+        self.handle.borrow().corrupted
+    }
+
+    fn set_corrupted(&self) {
+        self.handle.borrow_mut().corrupted = true;
+    }
+
+    fn view(&self) -> Option<StereoView> {
+        self.handle.borrow().view
+    }
+
+    fn set_view(&self, view: StereoView) {
+        self.handle.borrow_mut().view = Some(view);
+    }
+
+    fn field_order(&self) -> Option<FieldOrder> {
+        self.handle.borrow().field_order
+    }
+
+    fn set_field_order(&self, order: FieldOrder) {
+        self.handle.borrow_mut().field_order = Some(order);
+    }
+
     fn resource(&self) -> std::cell::Ref<()> {
-        std::cell::Ref::map(self.handle.borrow(), |h| &h.0)
+        std::cell::Ref::map(self.handle.borrow(), |h| &h.resource)
     }
 }
 
 /// Dummy backend that can be used for any codec.
-pub(crate) struct Backend {
+pub struct Backend {
     stream_info: StreamInfo,
+    /// Number of frames added to the pool.
+    pub(crate) num_managed_frames: usize,
+    /// Number of frames of the pool considered held by the client, for tests to simulate
+    /// frame starvation.
+    pub(crate) num_held_frames: usize,
+}
+
+impl Default for Backend {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Backend {
-    pub(crate) fn new() -> Self {
+    pub fn new() -> Self {
         Self {
             stream_info: StreamInfo {
                 format: DecodedFormat::I420,
@@ -98,6 +160,8 @@ impl Backend {
                 coded_resolution: Resolution::from((320, 200)),
                 display_resolution: Resolution::from((320, 200)),
             },
+            num_managed_frames: 4,
+            num_held_frames: 0,
         }
     }
 }
@@ -111,16 +175,17 @@ impl FramePool for Backend {
 
     fn set_coded_resolution(&mut self, _resolution: Resolution) {}
 
-    fn add_frames(&mut self, _descriptors: Vec<Self::Descriptor>) -> Result<(), anyhow::Error> {
+    fn add_frames(&mut self, descriptors: Vec<Self::Descriptor>) -> Result<(), anyhow::Error> {
+        self.num_managed_frames += descriptors.len();
         Ok(())
     }
 
     fn num_free_frames(&self) -> usize {
-        4
+        self.num_managed_frames.saturating_sub(self.num_held_frames)
     }
 
     fn num_managed_frames(&self) -> usize {
-        4
+        self.num_managed_frames
     }
 
     fn clear(&mut self) {}
@@ -148,4 +213,12 @@ impl StatelessDecoderBackend for Backend {
     fn frame_pool(&mut self, _: PoolLayer) -> Vec<&mut Self::FramePool> {
         vec![self]
     }
+
+    fn set_output_resolution(&mut self, _: Option<Resolution>) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    fn set_deinterlacing(&mut self, _: Deinterlacing) -> anyhow::Result<()> {
+        Ok(())
+    }
 }
@@ -22,6 +22,7 @@ use crate::DecodedFormat;
 pub mod decoder;
 pub mod encoder;
 pub mod surface_pool;
+pub(crate) mod vpp;
 
 fn va_rt_format_to_string(va_rt_format: u32) -> String {
     String::from(match va_rt_format {
@@ -406,7 +407,17 @@ impl libva::ExternalBufferDescriptor for DmabufFrame {
             libva::VADRMPRIMESurfaceDescriptorLayer {
                 drm_format: self.layout.format.0.into(),
                 num_planes: self.layout.planes.len() as u32,
-                object_index: [0, 0, 0, 0],
+                // Planes can be in different buffers, e.g. when coming from a camera.
+                object_index: self
+                    .layout
+                    .planes
+                    .iter()
+                    .map(|p| p.buffer_index as u32)
+                    .chain(std::iter::repeat(0))
+                    .take(4)
+                    .collect::<Vec<_>>()
+                    .try_into()
+                    .unwrap(),
                 offset: self
                     .layout
                     .planes
@@ -438,7 +449,7 @@ impl libva::ExternalBufferDescriptor for DmabufFrame {
             fourcc: self.layout.format.0.into(),
             width: self.layout.size.width,
             height: self.layout.size.height,
-            num_objects: 1,
+            num_objects: self.fds.len() as u32,
             objects,
             num_layers: 1,
             layers,
@@ -2,6 +2,7 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file.
 
+use std::cell::OnceCell;
 use std::cell::RefCell;
 use std::collections::HashSet;
 use std::rc::Rc;
@@ -23,9 +24,12 @@ use crate::backend::vaapi::p01x_to_i01x;
 use crate::backend::vaapi::surface_pool::PooledVaSurface;
 use crate::backend::vaapi::surface_pool::VaSurfacePool;
 use crate::backend::vaapi::va_rt_format_to_string;
+use crate::backend::vaapi::vpp::Vpp;
 use crate::backend::vaapi::y21x_to_i21x;
 use crate::backend::vaapi::FormatMap;
 use crate::backend::vaapi::FORMAT_MAP;
+use crate::decoder::stateless::scaled_output_resolution;
+use crate::decoder::stateless::Deinterlacing;
 use crate::decoder::stateless::PoolLayer;
 use crate::decoder::stateless::StatelessBackendResult;
 use crate::decoder::stateless::StatelessCodec;
@@ -34,14 +38,19 @@ use crate::decoder::stateless::StatelessDecoderBackendPicture;
 use crate::decoder::stateless::TryFormat;
 use crate::decoder::DecodedHandle as DecodedHandleTrait;
 use crate::decoder::DynHandle;
+use crate::decoder::FieldOrder;
 use crate::decoder::FramePool;
 use crate::decoder::MappableHandle;
+use crate::decoder::MappedFrame;
+use crate::decoder::StereoView;
 use crate::decoder::StreamInfo;
 use crate::i4xx_copy;
 use crate::nv12_copy;
 use crate::y410_to_i410;
 use crate::DecodedFormat;
 use crate::Fourcc;
+use crate::FrameLayout;
+use crate::PlaneLayout;
 use crate::Resolution;
 
 use super::supported_formats_for_rt_format;
@@ -68,7 +77,11 @@ impl<M: SurfaceMemoryDescriptor> DecodedHandleTrait for DecodedHandle<M> {
     }
 
     fn display_resolution(&self) -> Resolution {
-        self.borrow().display_resolution
+        let handle = self.borrow();
+        match handle.vpp() {
+            Some(vpp) => vpp.output_resolution(),
+            None => handle.display_resolution,
+        }
     }
 
     fn timestamp(&self) -> u64 {
@@ -89,6 +102,30 @@ impl<M: SurfaceMemoryDescriptor> DecodedHandleTrait for DecodedHandle<M> {
         Ok(())
     }
 
+    fn is_corrupted(&self) -> bool {
+        self.borrow().corrupted
+    }
+
+    fn set_corrupted(&self) {
+        self.borrow_mut().corrupted = true;
+    }
+
+    fn view(&self) -> Option<StereoView> {
+        self.borrow().view
+    }
+
+    fn set_view(&self, view: StereoView) {
+        self.borrow_mut().view = Some(view);
+    }
+
+    fn field_order(&self) -> Option<FieldOrder> {
+        self.borrow().field_order
+    }
+
+    fn set_field_order(&self, order: FieldOrder) {
+        self.borrow_mut().field_order = Some(order);
+    }
+
     fn resource(&self) -> std::cell::Ref<M> {
         std::cell::Ref::map(self.borrow(), |r| match &r.state {
             PictureState::Ready(p) => p.surface().as_ref(),
@@ -243,6 +280,18 @@ impl StreamMetadataState {
             }
             // Create new context.
             _ => {
+                // Fail with a clear error for the profiles the driver does not advertise, e.g.
+                // the HEVC range extensions and screen content coding ones.
+                let entrypoints = display
+                    .query_config_entrypoints(va_profile)
+                    .unwrap_or_default();
+                if !entrypoints.contains(&libva::VAEntrypoint::VAEntrypointVLD) {
+                    return Err(anyhow!(
+                        "profile {} is not supported for decoding by your hardware",
+                        va_profile
+                    ));
+                }
+
                 let config = display.create_config(
                     vec![libva::VAConfigAttrib {
                         type_: libva::VAConfigAttribType::VAConfigAttribRTFormat,
@@ -386,6 +435,16 @@ pub struct VaapiDecodedHandle<M: SurfaceMemoryDescriptor> {
     display_resolution: Resolution,
     /// Image format for this surface, taken from the pool it originates from.
     map_format: Rc<libva::VAImageFormat>,
+    /// Whether this picture has been decoded from partially received input.
+    corrupted: bool,
+    /// View of a stereoscopic stream this picture belongs to.
+    view: Option<StereoView>,
+    /// Post-processor producing the output frame from the decoded one, if any.
+    vpp: Option<Rc<Vpp>>,
+    /// Order of the fields of this picture if it is interlaced.
+    field_order: Option<FieldOrder>,
+    /// Output frame produced by `vpp` the first time this picture is mapped.
+    processed: OnceCell<Picture<PictureSync, libva::Surface<()>>>,
 }
 
 impl<M: SurfaceMemoryDescriptor> VaapiDecodedHandle<M> {
@@ -393,12 +452,18 @@ impl<M: SurfaceMemoryDescriptor> VaapiDecodedHandle<M> {
     fn new(
         picture: Picture<PictureNew, PooledVaSurface<M>>,
         metadata: &ParsedStreamMetadata,
+        vpp: Option<Rc<Vpp>>,
     ) -> anyhow::Result<Self> {
         let picture = picture.begin()?.render()?.end()?;
         Ok(Self {
             state: PictureState::Pending(picture),
             display_resolution: metadata.stream_info.display_resolution,
             map_format: Rc::clone(&metadata.map_format),
+            corrupted: false,
+            view: None,
+            vpp,
+            field_order: None,
+            processed: OnceCell::new(),
         })
     }
 
@@ -413,6 +478,29 @@ impl<M: SurfaceMemoryDescriptor> VaapiDecodedHandle<M> {
     /// Note that DynMappableHandle is downcastable.
     fn image(&self) -> anyhow::Result<Image> {
         match &self.state {
+            PictureState::Ready(picture) if self.vpp().is_some() => {
+                let vpp = self.vpp().unwrap();
+                // Only post-process once, as all the fields of the picture have been decoded by
+                // the time it can be mapped.
+                if self.processed.get().is_none() {
+                    let processed = vpp.process(
+                        picture.surface(),
+                        self.display_resolution,
+                        self.field_order,
+                        self.timestamp(),
+                    )?;
+                    let _ = self.processed.set(processed);
+                }
+
+                let output_resolution = vpp.output_resolution();
+                let image = self.processed.get().unwrap().create_image(
+                    *self.map_format,
+                    output_resolution.into(),
+                    output_resolution.into(),
+                )?;
+
+                Ok(image)
+            }
             PictureState::Ready(picture) => {
                 // Map the VASurface onto our address space.
                 let image = picture.create_image(
@@ -430,6 +518,14 @@ impl<M: SurfaceMemoryDescriptor> VaapiDecodedHandle<M> {
         }
     }
 
+    /// Returns the post-processor to apply to this picture before output, if it needs any.
+    fn vpp(&self) -> Option<&Vpp> {
+        self.vpp.as_deref().filter(|vpp| {
+            vpp.output_resolution() != self.display_resolution
+                || (vpp.deinterlacing() != Deinterlacing::Off && self.field_order.is_some())
+        })
+    }
+
     /// Creates a new picture from the surface backing the current one. Useful for interlaced
     /// decoding.
     pub(crate) fn new_picture_from_same_surface(
@@ -443,6 +539,16 @@ impl<M: SurfaceMemoryDescriptor> VaapiDecodedHandle<M> {
         self.state.surface()
     }
 
+    /// Returns the format the surface is mapped with.
+    pub(crate) fn map_fourcc(&self) -> Fourcc {
+        Fourcc::from(self.map_format.fourcc)
+    }
+
+    /// Returns the resolution of the visible rectangle of the surface, before any post-processing.
+    pub(crate) fn decoded_resolution(&self) -> Resolution {
+        self.display_resolution
+    }
+
     /// Returns the timestamp of this handle.
     fn timestamp(&self) -> u64 {
         self.state.timestamp()
@@ -550,6 +656,26 @@ impl<'a> MappableHandle for Image<'a> {
             display_resolution.1 as usize,
         )
     }
+
+    fn map(&mut self) -> anyhow::Result<MappedFrame<'_>> {
+        let image_inner = self.image();
+        let layout = FrameLayout {
+            format: (Fourcc::from(image_inner.format.fourcc), 0),
+            size: Resolution::from(self.display_resolution()),
+            planes: (0..image_inner.num_planes as usize)
+                .map(|i| PlaneLayout {
+                    buffer_index: 0,
+                    offset: image_inner.offsets[i] as usize,
+                    stride: image_inner.pitches[i] as usize,
+                })
+                .collect(),
+        };
+
+        // Derived images map the surface itself, while the others have been copied into the
+        // image's buffer by `vaGetImage`.
+        let direct = self.is_derived();
+        MappedFrame::new(self.as_ref(), &layout, direct)
+    }
 }
 
 pub struct VaapiBackend<M>
@@ -569,6 +695,13 @@ where
     supports_context_reuse: bool,
     /// Controls the creation of surface pools.
     pool_creation_mode: PoolCreationMode,
+    /// Resolution requested by the client for the output frames.
+    output_resolution: Option<Resolution>,
+    /// Deinterlacing requested by the client for the output frames.
+    deinterlacing: Deinterlacing,
+    /// Post-processor scaling the decoded frames to `output_resolution` and deinterlacing them,
+    /// if they need to be.
+    vpp: Option<Rc<Vpp>>,
 }
 
 impl<M> VaapiBackend<M>
@@ -590,9 +723,51 @@ where
             metadata_state: StreamMetadataState::Unparsed,
             supports_context_reuse,
             pool_creation_mode: PoolCreationMode::Highest,
+            output_resolution: None,
+            deinterlacing: Default::default(),
+            vpp: None,
         }
     }
 
+    /// Creates the post-processor required to output frames of the current stream at the
+    /// resolution and with the deinterlacing requested by the client, if any.
+    fn update_vpp(&mut self) -> anyhow::Result<()> {
+        let Ok(metadata) = self.metadata_state.get_parsed() else {
+            self.vpp = None;
+            return Ok(());
+        };
+
+        let display_resolution = metadata.stream_info.display_resolution;
+        let output_resolution =
+            match scaled_output_resolution(display_resolution, self.output_resolution) {
+                Some(output_resolution) => output_resolution,
+                None if self.deinterlacing != Deinterlacing::Off => display_resolution,
+                None => {
+                    self.vpp = None;
+                    return Ok(());
+                }
+            };
+
+        self.vpp = match self.vpp.take() {
+            // Keep the current post-processor if it is still suitable.
+            Some(vpp)
+                if vpp.output_resolution() == output_resolution
+                    && vpp.rt_format() == metadata.rt_format
+                    && vpp.deinterlacing() == self.deinterlacing =>
+            {
+                Some(vpp)
+            }
+            _ => Some(Rc::new(Vpp::new(
+                &self.display,
+                metadata.rt_format,
+                output_resolution,
+                self.deinterlacing,
+            )?)),
+        };
+
+        Ok(())
+    }
+
     pub(crate) fn new_sequence<StreamData>(
         &mut self,
         stream_params: &StreamData,
@@ -616,6 +791,7 @@ where
         )?;
 
         self.pool_creation_mode = pool_creation_mode;
+        self.update_vpp()?;
 
         Ok(())
     }
@@ -631,7 +807,9 @@ where
         let metadata = self.metadata_state.get_parsed()?;
 
         Ok(Rc::new(RefCell::new(VaapiDecodedHandle::new(
-            picture, metadata,
+            picture,
+            metadata,
+            self.vpp.clone(),
         )?)))
     }
 
@@ -711,6 +889,16 @@ where
             .ok()
             .map(|m| &m.stream_info)
     }
+
+    fn set_output_resolution(&mut self, resolution: Option<Resolution>) -> anyhow::Result<()> {
+        self.output_resolution = resolution;
+        self.update_vpp()
+    }
+
+    fn set_deinterlacing(&mut self, deinterlacing: Deinterlacing) -> anyhow::Result<()> {
+        self.deinterlacing = deinterlacing;
+        self.update_vpp()
+    }
 }
 
 impl<Codec: StatelessCodec, M> TryFormat<Codec> for VaapiBackend<M>
@@ -761,7 +949,7 @@ where
                 self.pool_creation_mode.clone(),
             )?;
 
-            Ok(())
+            self.update_vpp()
         } else {
             Err(anyhow!("Format {:?} is unsupported.", format))
         }
@@ -3,6 +3,7 @@
 // found in the LICENSE file.
 
 use std::any::Any;
+use std::cell::RefCell;
 use std::marker::PhantomData;
 use std::rc::Rc;
 
@@ -23,14 +24,26 @@ use libva::VASurfaceStatus;
 
 use crate::backend::vaapi::surface_pool::PooledVaSurface;
 use crate::backend::vaapi::surface_pool::VaSurfacePool;
+use crate::backend::vaapi::vpp::Vpp;
 use crate::backend::vaapi::FORMAT_MAP;
+use crate::decoder::FieldOrder;
 use crate::decoder::FramePool;
+use crate::encoder::aq::AdaptiveQuantization;
+use crate::encoder::aq::QpMap;
+use crate::encoder::aq::MB_SIZE;
+use crate::encoder::preprocess::Preprocessing;
 use crate::encoder::stateless::BackendPromise;
 use crate::encoder::stateless::StatelessBackendError;
 use crate::encoder::stateless::StatelessBackendResult;
 use crate::encoder::stateless::StatelessEncoderBackendImport;
+use crate::encoder::AdvancedConfig;
 use crate::encoder::FrameMetadata;
+use crate::encoder::MemFrame;
+use crate::encoder::Preset;
+use crate::encoder::Trellis;
 use crate::Fourcc;
+use crate::FrameLayout;
+use crate::PlaneLayout;
 use crate::Resolution;
 
 /// The number of frames that encoder backend should initialize scratch pool with.
@@ -38,6 +51,8 @@ const INITIAL_SCRATCH_POOL_SIZE: usize = 16;
 /// The maximum size of scratch pool size, after which the backend will refure to allocate more
 /// scratch frames.
 const MAX_SCRATCH_POOL_SIZE: usize = INITIAL_SCRATCH_POOL_SIZE * 4;
+/// The maximum number of surfaces holding frames uploaded from CPU memory.
+const MAX_UPLOAD_POOL_SIZE: usize = 16;
 
 impl From<libva::VaError> for StatelessBackendError {
     fn from(value: libva::VaError) -> Self {
@@ -45,12 +60,101 @@ impl From<libva::VaError> for StatelessBackendError {
     }
 }
 
-pub struct Reconstructed(PooledVaSurface<()>);
+/// Copies `frame` into `surface` as `fourcc`, and returns the layout of the frame within the
+/// surface. NV21, I420, YV12 and BGRA frames are converted when uploaded as NV12, other frames
+/// must already be in `fourcc`.
+pub fn upload_frame<M: SurfaceMemoryDescriptor, T: AsRef<[u8]>>(
+    display: &Display,
+    surface: &Surface<M>,
+    frame: &MemFrame<T>,
+    fourcc: Fourcc,
+) -> anyhow::Result<FrameLayout> {
+    let layout = frame.layout();
+    let size = layout.size;
+    let nv12 = Fourcc::from(b"NV12");
+    anyhow::ensure!(
+        layout.format.0 == fourcc || fourcc == nv12,
+        "cannot upload {} frames as {}",
+        layout.format.0,
+        fourcc
+    );
+
+    let image_fmt = display
+        .query_image_formats()?
+        .into_iter()
+        .find(|f| f.fourcc == fourcc.0)
+        .ok_or_else(|| anyhow::anyhow!("{} images are not supported by the display", fourcc))?;
+
+    let mut image = libva::Image::create_from(
+        surface,
+        image_fmt,
+        (size.width, size.height),
+        (size.width, size.height),
+    )?;
+
+    let va_image = *image.image();
+    let num_planes = va_image.num_planes as usize;
+    let dest = image.as_mut();
+
+    if layout.format.0 == fourcc {
+        for i in 0..num_planes {
+            let dst_rows =
+                dest[va_image.offsets[i] as usize..].chunks_mut(va_image.pitches[i] as usize);
+            for (src, dst) in frame.plane_rows(i).zip(dst_rows) {
+                dst[..src.len()].copy_from_slice(src);
+            }
+        }
+    } else {
+        let (luma, chroma) = dest.split_at_mut(va_image.offsets[1] as usize);
+        frame.write_nv12(
+            &mut luma[va_image.offsets[0] as usize..],
+            va_image.pitches[0] as usize,
+            chroma,
+            va_image.pitches[1] as usize,
+        )?;
+    }
+    drop(image);
+
+    surface.sync()?;
+
+    Ok(FrameLayout {
+        format: (fourcc, 0),
+        size,
+        planes: (0..num_planes)
+            .map(|i| PlaneLayout {
+                buffer_index: 0,
+                offset: va_image.offsets[i] as usize,
+                stride: va_image.pitches[i] as usize,
+            })
+            .collect(),
+    })
+}
+
+/// Copies the tightly packed NV12 frame `data` of `size` into `surface`, and returns the layout
+/// of the frame within the surface. Useful to feed the encoder with frames from CPU memory.
+pub fn upload_nv12<M: SurfaceMemoryDescriptor>(
+    display: &Display,
+    surface: &Surface<M>,
+    size: Resolution,
+    data: &[u8],
+) -> anyhow::Result<FrameLayout> {
+    upload_frame(
+        display,
+        surface,
+        &MemFrame::nv12(data, size)?,
+        Fourcc::from(b"NV12"),
+    )
+}
+
+/// Surface holding a reconstructed picture. It is shared, as the pictures allocated ahead of the
+/// submission are held by the DPB while their frame is reconstructed into them.
+#[derive(Clone)]
+pub struct Reconstructed(Rc<PooledVaSurface<()>>);
 
 impl Reconstructed {
     pub(crate) fn surface(&self) -> &Surface<()> {
         use std::borrow::Borrow;
-        Borrow::<Surface<()>>::borrow(&self.0)
+        Borrow::<Surface<()>>::borrow(self.0.as_ref())
     }
 
     pub(crate) fn surface_id(&self) -> u32 {
@@ -70,8 +174,37 @@ where
     /// VA context used for encoding.
     context: Rc<Context>,
 
+    /// VA display, used to read surfaces back.
+    display: Rc<Display>,
+
+    /// Fourcc of the encoded surfaces.
+    fourcc: Fourcc,
+    /// Size of the encoded surfaces.
+    coded_size: Resolution,
+
     _va_profile: VAProfile::Type,
     scratch_pool: VaSurfacePool<()>,
+    /// Surfaces holding the [`MemFrame`]s being encoded, allocated on demand.
+    upload_pool: VaSurfacePool<()>,
+    /// Filters applied to the frames before they are encoded.
+    preprocessing: Preprocessing,
+    /// Video processor applying the filters of [`Self::preprocessing`], and the surface the
+    /// frames are uploaded into before being processed into the surfaces of
+    /// [`Self::upload_pool`].
+    vpp: Option<(Vpp, Surface<()>)>,
+    /// Whether the QP of each macroblock can be set with a QP buffer.
+    qp_map_supported: bool,
+    /// Number of quality levels of the encoder, 0 if it has none.
+    quality_range: u32,
+    /// Quality level of the encoder, from 1 for the best quality to [`Self::quality_range`] for
+    /// the fastest encoding. The driver default is used if not set.
+    quality_level: Option<u32>,
+    /// Whether the encoder supports trellis quantization.
+    trellis_supported: bool,
+    /// Trellis quantization of the frames, the driver default is used if not set.
+    trellis: Option<Trellis>,
+    /// Whether the slice headers are written by the encoder rather than by the driver.
+    packed_slice_headers: bool,
     _phantom: PhantomData<(M, H)>,
 }
 
@@ -94,25 +227,74 @@ where
             .ok_or_else(|| StatelessBackendError::UnsupportedFormat)?;
 
         let rt_format = format_map.rt_format;
-
-        let va_config = display.create_config(
-            vec![
-                libva::VAConfigAttrib {
-                    type_: libva::VAConfigAttribType::VAConfigAttribRTFormat,
-                    value: rt_format,
-                },
-                libva::VAConfigAttrib {
-                    type_: libva::VAConfigAttribType::VAConfigAttribRateControl,
-                    value: bitrate_control,
-                },
-            ],
-            va_profile,
-            if low_power {
-                VAEntrypointEncSliceLP
-            } else {
-                VAEntrypointEncSlice
+        let entrypoint = if low_power {
+            VAEntrypointEncSliceLP
+        } else {
+            VAEntrypointEncSlice
+        };
+
+        let mut attrs = vec![
+            libva::VAConfigAttrib {
+                type_: libva::VAConfigAttribType::VAConfigAttribEncQualityRange,
+                value: 0,
             },
-        )?;
+            libva::VAConfigAttrib {
+                type_: libva::VAConfigAttribType::VAConfigAttribEncQuantization,
+                value: 0,
+            },
+            libva::VAConfigAttrib {
+                type_: libva::VAConfigAttribType::VAConfigAttribEncPackedHeaders,
+                value: 0,
+            },
+            libva::VAConfigAttrib {
+                type_: libva::VAConfigAttribType::VAConfigAttribQPBlockSize,
+                value: 0,
+            },
+            libva::VAConfigAttrib {
+                type_: libva::VAConfigAttribType::VAConfigAttribRateControl,
+                value: 0,
+            },
+        ];
+        display.get_config_attributes(va_profile, entrypoint, &mut attrs)?;
+        let quality_range = match attrs[0].value {
+            libva::constants::VA_ATTRIB_NOT_SUPPORTED => 0,
+            range => range,
+        };
+        let trellis_supported = attrs[1].value != libva::constants::VA_ATTRIB_NOT_SUPPORTED
+            && attrs[1].value & libva::constants::VA_ENC_QUANTIZATION_TRELLIS_SUPPORTED != 0;
+        let packed_slice_headers = attrs[2].value != libva::constants::VA_ATTRIB_NOT_SUPPORTED
+            && attrs[2].value & libva::constants::VA_ENC_PACKED_HEADER_SLICE != 0;
+        // The QP buffers hold one QP per block of this size. They are only honored in CQP mode,
+        // or in CBR and VBR modes with the macroblock level rate control.
+        let mb_rate_control =
+            bitrate_control & (libva::constants::VA_RC_CBR | libva::constants::VA_RC_VBR) != 0
+                && attrs[4].value != libva::constants::VA_ATTRIB_NOT_SUPPORTED
+                && attrs[4].value & libva::constants::VA_RC_MB != 0;
+        let qp_map_supported = attrs[3].value == MB_SIZE as u32
+            && (bitrate_control == libva::constants::VA_RC_CQP || mb_rate_control);
+        let bitrate_control = if qp_map_supported && mb_rate_control {
+            bitrate_control | libva::constants::VA_RC_MB
+        } else {
+            bitrate_control
+        };
+
+        let mut config_attrs = vec![
+            libva::VAConfigAttrib {
+                type_: libva::VAConfigAttribType::VAConfigAttribRTFormat,
+                value: rt_format,
+            },
+            libva::VAConfigAttrib {
+                type_: libva::VAConfigAttribType::VAConfigAttribRateControl,
+                value: bitrate_control,
+            },
+        ];
+        if packed_slice_headers {
+            config_attrs.push(libva::VAConfigAttrib {
+                type_: libva::VAConfigAttribType::VAConfigAttribEncPackedHeaders,
+                value: libva::constants::VA_ENC_PACKED_HEADER_SLICE,
+            });
+        }
+        let va_config = display.create_config(config_attrs, va_profile, entrypoint)?;
 
         let context = display.create_context::<M>(
             &va_config,
@@ -132,19 +314,295 @@ where
         // TODO: Allow initial size to be changed
         scratch_pool.add_frames(vec![(); INITIAL_SCRATCH_POOL_SIZE])?;
 
+        let upload_pool = VaSurfacePool::new(
+            Rc::clone(&display),
+            rt_format,
+            Some(UsageHint::USAGE_HINT_ENCODER),
+            coded_size,
+        );
+
         Ok(Self {
             va_config,
             context,
+            display,
+            fourcc,
+            coded_size,
             scratch_pool,
+            upload_pool,
+            preprocessing: Default::default(),
+            vpp: None,
+            qp_map_supported,
+            quality_range,
+            quality_level: None,
+            trellis_supported,
+            trellis: None,
+            packed_slice_headers,
             _va_profile: va_profile,
             _phantom: Default::default(),
         })
     }
 
+    /// Returns true if the QP of each macroblock can be set and is honored by the rate control
+    /// mode, as needed by the adaptive quantization and the damage of the frames.
+    pub fn supports_qp_map(&self) -> bool {
+        self.qp_map_supported
+    }
+
     pub(crate) fn context(&self) -> &Rc<Context> {
         &self.context
     }
 
+    /// Sets the filters applied to the frames before they are encoded. Filters are only supported
+    /// by NV12 encoders, and like mirroring and rotation only for frames encoded from CPU memory.
+    pub fn set_preprocessing(
+        &mut self,
+        preprocessing: Preprocessing,
+    ) -> StatelessBackendResult<()> {
+        if preprocessing.has_filters() && self.fourcc != Fourcc::from(b"NV12") {
+            return Err(StatelessBackendError::UnsupportedFormat);
+        }
+        if let Some(overlay) = &preprocessing.overlay {
+            overlay.validate().map_err(StatelessBackendError::Other)?;
+        }
+
+        // The video processor is only recreated when its filters change, not e.g. the overlay.
+        if preprocessing.video_processing() != self.preprocessing.video_processing() {
+            self.vpp = None;
+            if preprocessing.has_video_processing() {
+                let vpp = Vpp::new_preprocessor(
+                    &self.display,
+                    self.fourcc,
+                    self.coded_size,
+                    &preprocessing,
+                )
+                .map_err(StatelessBackendError::Other)?;
+                let staging = self
+                    .display
+                    .create_surfaces(
+                        vpp.rt_format(),
+                        Some(self.fourcc.0),
+                        self.coded_size.width,
+                        self.coded_size.height,
+                        Some(UsageHint::USAGE_HINT_VPP_READ),
+                        vec![()],
+                    )?
+                    .pop()
+                    .ok_or(StatelessBackendError::OutOfResources)?;
+                self.vpp = Some((vpp, staging));
+            }
+        }
+
+        self.preprocessing = preprocessing;
+        Ok(())
+    }
+
+    /// Translates `preset` into the quality level of the encoder, or restores the driver default
+    /// if it is `None`.
+    pub fn set_preset(&mut self, preset: Option<Preset>) {
+        self.quality_level = match (preset, self.quality_range) {
+            (None, _) | (_, 0) => None,
+            (Some(Preset::Realtime), range) => Some(range),
+            (Some(Preset::Balanced), range) => Some(range.div_ceil(2)),
+            (Some(Preset::Quality), _) => Some(1),
+        };
+    }
+
+    /// Returns true if the slice headers are written by the encoder rather than by the driver,
+    /// which is needed to signal the reference list modifications and memory management
+    /// operations.
+    pub fn packed_slice_headers(&self) -> bool {
+        self.packed_slice_headers
+    }
+
+    /// Returns the number of quality levels of the encoder, 0 if it has none.
+    pub fn quality_range(&self) -> u32 {
+        self.quality_range
+    }
+
+    /// Sets the quality level of the encoder, from 1 for the best quality to
+    /// [`VaapiBackend::quality_range`] for the fastest encoding. Levels out of the range are
+    /// clamped to it.
+    pub fn set_quality_level(&mut self, quality_level: u32) {
+        if self.quality_range == 0 {
+            log::warn!(
+                "Quality level {} ignored, the encoder has none",
+                quality_level
+            );
+            return;
+        }
+
+        let clamped = quality_level.clamp(1, self.quality_range);
+        if clamped != quality_level {
+            log::warn!(
+                "Quality level {} clamped to {}, the encoder supports 1 to {}",
+                quality_level,
+                clamped,
+                self.quality_range
+            );
+        }
+
+        self.quality_level = Some(clamped);
+    }
+
+    /// Applies the quantization controls of `advanced` supported by the encoder and ignores the
+    /// others.
+    pub fn set_advanced(&mut self, advanced: &AdvancedConfig) {
+        self.trellis = match advanced.trellis {
+            Some(_) if !self.trellis_supported => {
+                log::warn!("Trellis quantization ignored, the encoder does not support it");
+                None
+            }
+            trellis => trellis,
+        };
+
+        // cros-libva has no wrapper of the custom rounding control buffer of the Intel drivers.
+        if advanced.rounding.is_some() {
+            log::warn!("Quantization rounding ignored, the encoder does not support it");
+        }
+    }
+
+    /// Returns the trellis quantization the frames shall be encoded with, if set.
+    pub(crate) fn trellis(&self) -> Option<Trellis> {
+        self.trellis
+    }
+
+    /// Returns the quality level the frames shall be encoded with, if set.
+    pub(crate) fn quality_level(&self) -> Option<u32> {
+        self.quality_level
+    }
+
+    /// Copies the CPU memory `frame` into `surface` and applies the preprocessing filters to it.
+    /// The frame goes through the staging surface of the video processor if it has filters to
+    /// apply.
+    fn upload_mem_frame<T: AsRef<[u8]>>(
+        &self,
+        metadata: &FrameMetadata,
+        surface: &Surface<()>,
+        frame: &MemFrame<T>,
+    ) -> StatelessBackendResult<()> {
+        validate_frame(metadata, frame.layout(), Resolution::from(surface.size()))?;
+        match &self.vpp {
+            Some((vpp, staging)) => {
+                upload_frame(&self.display, staging, frame, self.fourcc)?;
+                // The encoders take progressive frames, so the top field is kept.
+                let field_order = self
+                    .preprocessing
+                    .deinterlace
+                    .map(|_| FieldOrder::TopFieldFirst);
+                vpp.process_into(staging, frame.layout().size, field_order, surface)
+                    .map_err(StatelessBackendError::Other)?;
+            }
+            None => {
+                upload_frame(&self.display, surface, frame, self.fourcc)?;
+            }
+        }
+
+        self.blend_overlay(surface, frame.layout().size)
+    }
+
+    /// Blends the overlay of the preprocessing over the frame of `size` in `surface`, if any.
+    fn blend_overlay(&self, surface: &Surface<()>, size: Resolution) -> StatelessBackendResult<()> {
+        let Some(overlay) = &self.preprocessing.overlay else {
+            return Ok(());
+        };
+
+        surface.sync()?;
+
+        let image_fmt = self
+            .display
+            .query_image_formats()?
+            .into_iter()
+            .find(|f| f.fourcc == self.fourcc.0)
+            .ok_or(StatelessBackendError::UnsupportedFormat)?;
+
+        let mut image =
+            libva::Image::create_from(surface, image_fmt, surface.size(), surface.size())?;
+        let va_image = *image.image();
+        let (luma, chroma) = image.as_mut().split_at_mut(va_image.offsets[1] as usize);
+        overlay.blend_nv12(
+            size,
+            &mut luma[va_image.offsets[0] as usize..],
+            va_image.pitches[0] as usize,
+            chroma,
+            va_image.pitches[1] as usize,
+        );
+        drop(image);
+
+        surface.sync()?;
+
+        Ok(())
+    }
+
+    /// Computes the adaptive quantization [`QpMap`] of the `width_in_mbs` by `height_in_mbs`
+    /// macroblocks of the frame of `size` in `surface`.
+    pub(crate) fn qp_map<D: SurfaceMemoryDescriptor>(
+        &self,
+        aq: &AdaptiveQuantization,
+        surface: &Surface<D>,
+        size: Resolution,
+        width_in_mbs: usize,
+        height_in_mbs: usize,
+    ) -> StatelessBackendResult<QpMap> {
+        if self.fourcc != Fourcc::from(b"NV12") {
+            return Err(StatelessBackendError::UnsupportedFormat);
+        }
+
+        surface.sync()?;
+
+        let image_fmt = self
+            .display
+            .query_image_formats()?
+            .into_iter()
+            .find(|f| f.fourcc == self.fourcc.0)
+            .ok_or(StatelessBackendError::UnsupportedFormat)?;
+
+        let image = libva::Image::create_from(surface, image_fmt, surface.size(), surface.size())?;
+        let va_image = *image.image();
+
+        Ok(QpMap::from_luma(
+            aq,
+            size,
+            &image.as_ref()[va_image.offsets[0] as usize..],
+            va_image.pitches[0] as usize,
+            width_in_mbs,
+            height_in_mbs,
+        ))
+    }
+
+    /// Reads `surface` back into CPU memory, once pending operations on it are completed.
+    pub(crate) fn read_surface<D: SurfaceMemoryDescriptor>(
+        &self,
+        surface: &Surface<D>,
+    ) -> StatelessBackendResult<(Vec<u8>, FrameLayout)> {
+        surface.sync()?;
+
+        let image_fmt = self
+            .display
+            .query_image_formats()?
+            .into_iter()
+            .find(|f| f.fourcc == self.fourcc.0)
+            .ok_or(StatelessBackendError::UnsupportedFormat)?;
+
+        let image = libva::Image::create_from(surface, image_fmt, surface.size(), surface.size())?;
+        let va_image = *image.image();
+
+        let planes = (0..va_image.num_planes as usize)
+            .map(|i| PlaneLayout {
+                buffer_index: 0,
+                offset: va_image.offsets[i] as usize,
+                stride: va_image.pitches[i] as usize,
+            })
+            .collect();
+
+        let layout = FrameLayout {
+            format: (self.fourcc, 0),
+            size: Resolution::from(surface.size()),
+            planes,
+        };
+
+        Ok((image.as_ref().to_vec(), layout))
+    }
+
     // Creates an empty surface that will be filled with reconstructed picture during encoding
     // which will be later used as frame reference
     pub(crate) fn new_scratch_picture(&mut self) -> StatelessBackendResult<Reconstructed> {
@@ -166,10 +624,36 @@ where
             .get_surface()
             .ok_or(StatelessBackendError::OutOfResources)?;
 
-        Ok(Reconstructed(surface))
+        Ok(Reconstructed(Rc::new(surface)))
     }
 }
 
+/// Checks that a frame of `layout` fits in a surface of `surface_size`, and that the visible part
+/// of the frame described by `metadata` lies within it.
+fn validate_frame(
+    metadata: &FrameMetadata,
+    layout: &FrameLayout,
+    surface_size: Resolution,
+) -> anyhow::Result<()> {
+    layout
+        .validate_planes()
+        .map_err(|e| anyhow::anyhow!("invalid frame layout: {:#}", e))?;
+    anyhow::ensure!(
+        surface_size.can_contain(layout.size),
+        "frame of {:?} does not fit in a surface of {:?}",
+        layout.size,
+        surface_size
+    );
+    anyhow::ensure!(
+        layout.size.can_contain(metadata.display_resolution),
+        "display resolution {:?} is larger than the frame of {:?}",
+        metadata.display_resolution,
+        layout.size
+    );
+
+    Ok(())
+}
+
 impl<M, Handle> StatelessEncoderBackendImport<Handle, Handle> for VaapiBackend<M, Handle>
 where
     M: SurfaceMemoryDescriptor,
@@ -177,21 +661,96 @@ where
 {
     fn import_picture(
         &mut self,
-        _metadata: &FrameMetadata,
+        metadata: &FrameMetadata,
         handle: Handle,
     ) -> StatelessBackendResult<Handle> {
+        let format = metadata.layout.format.0;
+        if format != self.fourcc {
+            return Err(StatelessBackendError::Other(anyhow::anyhow!(
+                "cannot encode {} frames with a {} encoder",
+                format,
+                self.fourcc
+            )));
+        }
+        // The frames of the client are encoded as is, as the filters would otherwise need to
+        // write into them.
+        if self.preprocessing.is_enabled() {
+            return Err(StatelessBackendError::Other(anyhow::anyhow!(
+                "frames can only be preprocessed when encoded from CPU memory"
+            )));
+        }
+        let surface: &Surface<M> = std::borrow::Borrow::borrow(&handle);
+        validate_frame(metadata, &metadata.layout, Resolution::from(surface.size()))?;
+
         Ok(handle)
     }
 }
 
+impl<T: AsRef<[u8]>> StatelessEncoderBackendImport<MemFrame<T>, PooledVaSurface<()>>
+    for VaapiBackend<(), PooledVaSurface<()>>
+{
+    fn import_picture(
+        &mut self,
+        metadata: &FrameMetadata,
+        handle: MemFrame<T>,
+    ) -> StatelessBackendResult<PooledVaSurface<()>> {
+        let format = handle.layout().format.0;
+        let convertible = self.fourcc == Fourcc::from(b"NV12")
+            && matches!(
+                &<[u8; 4]>::from(format),
+                b"NV21" | b"I420" | b"YV12" | b"BGRA"
+            );
+        if format != self.fourcc && !convertible {
+            return Err(StatelessBackendError::Other(anyhow::anyhow!(
+                "cannot encode {} frames with a {} encoder",
+                format,
+                self.fourcc
+            )));
+        }
+
+        if self.upload_pool.num_free_frames() == 0 {
+            if self.upload_pool.num_managed_frames() >= MAX_UPLOAD_POOL_SIZE {
+                log::error!("Upload pool is exhausted and hit the size limit");
+                return Err(StatelessBackendError::OutOfResources);
+            }
+
+            self.upload_pool.add_frames(vec![()])?;
+        }
+
+        let surface = self
+            .upload_pool
+            .get_surface()
+            .ok_or(StatelessBackendError::OutOfResources)?;
+        let va_surface: &Surface<()> = std::borrow::Borrow::borrow(&surface);
+        if self.preprocessing.has_transform() {
+            let frame = handle.transformed(&self.preprocessing)?;
+            self.upload_mem_frame(metadata, va_surface, &frame)?;
+        } else {
+            self.upload_mem_frame(metadata, va_surface, &handle)?;
+        }
+
+        Ok(surface)
+    }
+
+    fn can_import(&self, _metadata: &FrameMetadata) -> bool {
+        self.upload_pool.num_free_frames() > 0
+            || self.upload_pool.num_managed_frames() < MAX_UPLOAD_POOL_SIZE
+    }
+}
+
 /// Vaapi's implementation of [`crate::encoder::stateless::BackendPromise`]
 pub struct CodedOutputPromise<M, P>
 where
     M: SurfaceMemoryDescriptor,
     P: std::borrow::Borrow<Surface<M>>,
 {
-    /// Currently processed picture/surface.
-    handle: Picture<PictureEnd, P>,
+    /// Currently processed picture/surface, `None` if it is the last picture of a batch, held by
+    /// [`Self::sync_point`].
+    handle: Option<Picture<PictureEnd, P>>,
+
+    /// Last picture of the batch the picture was submitted with, synced for all of them, see
+    /// [`CodedOutputPromise::batch`].
+    sync_point: Option<Rc<RefCell<Option<Picture<PictureEnd, P>>>>>,
 
     /// Hold reference frames/object from being dropped while [`handle`]
     /// is processed.
@@ -220,13 +779,44 @@ where
         coded_output: Vec<u8>,
     ) -> Self {
         Self {
-            handle,
+            handle: Some(handle),
+            sync_point: None,
             references,
             coded_buf,
             coded_output,
             _phantom: Default::default(),
         }
     }
+
+    /// Creates the promises of the pictures submitted back to back, with their references, coded
+    /// buffers and outputs. The pictures of a context are processed in submission order, so they
+    /// are all done once the last one is, which is the only one synced.
+    #[allow(clippy::type_complexity)]
+    pub fn batch(
+        submitted: Vec<(
+            Picture<PictureEnd, P>,
+            Vec<Rc<dyn Any>>,
+            EncCodedBuffer,
+            Vec<u8>,
+        )>,
+    ) -> Vec<Self> {
+        let mut promises: Vec<_> = submitted
+            .into_iter()
+            .map(|(handle, references, coded_buf, coded_output)| {
+                Self::new(handle, references, coded_buf, coded_output)
+            })
+            .collect();
+
+        let last = promises
+            .last_mut()
+            .and_then(|promise| promise.handle.take());
+        let sync_point = Rc::new(RefCell::new(last));
+        for promise in &mut promises {
+            promise.sync_point = Some(Rc::clone(&sync_point));
+        }
+
+        promises
+    }
 }
 
 impl<M, H> BackendPromise for CodedOutputPromise<M, H>
@@ -237,9 +827,23 @@ where
     type Output = Vec<u8>;
 
     fn sync(mut self) -> StatelessBackendResult<Self::Output> {
-        if let Err((err, _)) = self.handle.sync() {
-            // TODO consider going back to PictureEnd
-            return Err(err.into());
+        match &self.sync_point {
+            Some(sync_point) => {
+                let mut last = sync_point.borrow_mut();
+                if let Some(picture) = last.take() {
+                    if let Err((err, picture)) = picture.sync() {
+                        // Kept for the other pictures of the batch to fail as well.
+                        *last = Some(picture);
+                        return Err(err.into());
+                    }
+                }
+            }
+            None => {
+                if let Some(Err((err, _))) = self.handle.take().map(|handle| handle.sync()) {
+                    // TODO consider going back to PictureEnd
+                    return Err(err.into());
+                }
+            }
         }
 
         // Drop all references as processing is finished
@@ -261,7 +865,20 @@ where
     }
 
     fn is_ready(&self) -> bool {
-        match self.handle.surface().query_status() {
+        let last = self
+            .sync_point
+            .as_ref()
+            .map(|sync_point| sync_point.borrow());
+        let handle = match &last {
+            Some(last) => (**last).as_ref(),
+            None => self.handle.as_ref(),
+        };
+        // The picture was synced already.
+        let Some(handle) = handle else {
+            return true;
+        };
+
+        match handle.surface().query_status() {
             Ok(status) => status == VASurfaceStatus::VASurfaceReady,
             Err(_) => {
                 // An error occurred while processing or checking the status of the underlying
@@ -374,6 +991,7 @@ pub(crate) mod tests {
                 display_resolution: self.display_resolution,
                 layout: self.frame_layout.clone(),
                 force_keyframe: false,
+                damage: None,
                 timestamp: self.counter,
             };
 
@@ -0,0 +1,433 @@
+// Copyright 2024 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Post-processing of decoded surfaces and preprocessing of the input surfaces of encoders using
+//! the VAAPI video processing (VPP) entrypoint.
+
+use std::borrow::Borrow;
+use std::rc::Rc;
+
+use anyhow::anyhow;
+use anyhow::Context as AnyhowContext;
+use libva::Config;
+use libva::Context;
+use libva::Display;
+use libva::Picture;
+use libva::PictureSync;
+use libva::Surface;
+use libva::SurfaceMemoryDescriptor;
+
+use crate::decoder::stateless::Deinterlacing;
+use crate::decoder::FieldOrder;
+use crate::encoder::preprocess::Deinterlace;
+use crate::encoder::preprocess::Preprocessing;
+use crate::Fourcc;
+use crate::Resolution;
+
+use super::FORMAT_MAP;
+
+/// Processes decoded surfaces into new surfaces of a different size or format, or deinterlaced.
+pub(crate) struct Vpp {
+    display: Rc<Display>,
+    /// The VAConfig that created the context. It must kept here so that it does not get dropped
+    /// while it is in use.
+    #[allow(dead_code)]
+    config: Config,
+    context: Rc<Context>,
+    /// RT format of the decoded surfaces.
+    rt_format: u32,
+    /// RT format of the processed surfaces.
+    output_rt_format: u32,
+    /// Fourcc of the processed surfaces, or `None` to use the default one of `output_rt_format`.
+    output_fourcc: Option<u32>,
+    /// Size of the processed surfaces.
+    output_resolution: Resolution,
+    /// Deinterlacing applied to interlaced surfaces.
+    deinterlacing: Deinterlacing,
+    /// Value of the noise reduction filter in the range of the driver, if enabled.
+    denoise: Option<f32>,
+    /// Value of the sharpening filter in the range of the driver, if enabled.
+    sharpen: Option<f32>,
+    /// Values of the color balance filter in the range of the driver, empty if disabled.
+    color_balance: Vec<(libva::VAProcColorBalanceType, f32)>,
+}
+
+impl Vpp {
+    /// Creates a new post-processor producing surfaces of `rt_format` and `output_resolution`,
+    /// deinterlaced according to `deinterlacing`.
+    pub(crate) fn new(
+        display: &Rc<Display>,
+        rt_format: u32,
+        output_resolution: Resolution,
+        deinterlacing: Deinterlacing,
+    ) -> anyhow::Result<Self> {
+        Self::with_formats(
+            display,
+            rt_format,
+            rt_format,
+            None,
+            output_resolution,
+            deinterlacing,
+        )
+    }
+
+    /// Creates a new post-processor converting surfaces of `fourcc` into surfaces of
+    /// `output_fourcc` and `output_resolution`.
+    pub(crate) fn new_converter(
+        display: &Rc<Display>,
+        fourcc: Fourcc,
+        output_fourcc: Fourcc,
+        output_resolution: Resolution,
+    ) -> anyhow::Result<Self> {
+        let rt_format = |fourcc: Fourcc| {
+            FORMAT_MAP
+                .iter()
+                .find(|map| map.va_fourcc == fourcc.0)
+                .map(|map| map.rt_format)
+                .ok_or_else(|| anyhow!("cannot post-process {} surfaces", fourcc))
+        };
+
+        Self::with_formats(
+            display,
+            rt_format(fourcc)?,
+            rt_format(output_fourcc)?,
+            Some(output_fourcc.0),
+            output_resolution,
+            Deinterlacing::Off,
+        )
+    }
+
+    /// Creates a new preprocessor applying the deinterlacing, denoise, sharpen and color balance
+    /// filters of `preprocessing` to surfaces of `fourcc` and `resolution`.
+    pub(crate) fn new_preprocessor(
+        display: &Rc<Display>,
+        fourcc: Fourcc,
+        resolution: Resolution,
+        preprocessing: &Preprocessing,
+    ) -> anyhow::Result<Self> {
+        let deinterlacing = match preprocessing.deinterlace {
+            None => Deinterlacing::Off,
+            Some(Deinterlace::Bob) => Deinterlacing::Bob,
+            Some(Deinterlace::MotionAdaptive) => Deinterlacing::MotionAdaptive,
+        };
+        let mut vpp = Self::new_converter(display, fourcc, fourcc, resolution)?;
+        vpp.deinterlacing = deinterlacing;
+        vpp.check_deinterlacing()?;
+
+        let filters = display
+            .query_video_proc_filters(&vpp.context)
+            .unwrap_or_default();
+        let filter = |filter_type, name| {
+            if filters.contains(&filter_type) {
+                Ok(display.query_video_proc_filter_caps_range(&vpp.context, filter_type)?)
+            } else {
+                Err(anyhow!("{} is not supported by your hardware", name))
+            }
+        };
+        // The strengths go from the minimum to the maximum of the range of the driver.
+        let scale = |range: libva::VAProcFilterValueRange, strength: u8| {
+            range.min_value
+                + (range.max_value - range.min_value) * f32::from(strength.min(100)) / 100.0
+        };
+
+        if let Some(strength) = preprocessing.denoise {
+            let range = filter(
+                libva::VAProcFilterType::VAProcFilterNoiseReduction,
+                "denoise",
+            )?;
+            vpp.denoise = Some(scale(range, strength));
+        }
+        if let Some(strength) = preprocessing.sharpen {
+            let range = filter(libva::VAProcFilterType::VAProcFilterSharpening, "sharpen")?;
+            vpp.sharpen = Some(scale(range, strength));
+        }
+        if let Some(balance) = preprocessing.color_balance {
+            if !filters.contains(&libva::VAProcFilterType::VAProcFilterColorBalance) {
+                return Err(anyhow!("color balance is not supported by your hardware"));
+            }
+            let caps = display.query_video_proc_filter_caps_color_balance(&vpp.context)?;
+            for (balance_type, value) in [
+                (
+                    libva::VAProcColorBalanceType::VAProcColorBalanceBrightness,
+                    f32::from(balance.brightness),
+                ),
+                (
+                    libva::VAProcColorBalanceType::VAProcColorBalanceContrast,
+                    balance.contrast,
+                ),
+                (
+                    libva::VAProcColorBalanceType::VAProcColorBalanceSaturation,
+                    balance.saturation,
+                ),
+            ] {
+                let range = caps
+                    .iter()
+                    .find(|cap| cap.type_ == balance_type)
+                    .map(|cap| cap.range)
+                    .ok_or_else(|| {
+                        anyhow!("{:?} is not supported by your hardware", balance_type)
+                    })?;
+                vpp.color_balance
+                    .push((balance_type, value.clamp(range.min_value, range.max_value)));
+            }
+        }
+
+        Ok(vpp)
+    }
+
+    fn with_formats(
+        display: &Rc<Display>,
+        rt_format: u32,
+        output_rt_format: u32,
+        output_fourcc: Option<u32>,
+        output_resolution: Resolution,
+        deinterlacing: Deinterlacing,
+    ) -> anyhow::Result<Self> {
+        let entrypoints = display
+            .query_config_entrypoints(libva::VAProfile::VAProfileNone)
+            .unwrap_or_default();
+        if !entrypoints.contains(&libva::VAEntrypoint::VAEntrypointVideoProc) {
+            return Err(anyhow!(
+                "video post-processing is not supported by your hardware"
+            ));
+        }
+
+        let config = display.create_config(
+            vec![libva::VAConfigAttrib {
+                type_: libva::VAConfigAttribType::VAConfigAttribRTFormat,
+                value: rt_format | output_rt_format,
+            }],
+            libva::VAProfile::VAProfileNone,
+            libva::VAEntrypoint::VAEntrypointVideoProc,
+        )?;
+
+        let context = display.create_context::<()>(
+            &config,
+            output_resolution.width,
+            output_resolution.height,
+            None,
+            true,
+        )?;
+
+        let vpp = Self {
+            display: Rc::clone(display),
+            config,
+            context,
+            rt_format,
+            output_rt_format,
+            output_fourcc,
+            output_resolution,
+            deinterlacing,
+            denoise: None,
+            sharpen: None,
+            color_balance: vec![],
+        };
+        vpp.check_deinterlacing()?;
+
+        Ok(vpp)
+    }
+
+    /// Checks that the deinterlacing algorithm is supported by the driver.
+    fn check_deinterlacing(&self) -> anyhow::Result<()> {
+        if self.deinterlacing != Deinterlacing::Off {
+            let algorithms = self
+                .display
+                .query_video_proc_filter_caps_deinterlacing(&self.context)
+                .unwrap_or_default();
+            if !algorithms.contains(&Self::deinterlacing_algorithm(self.deinterlacing)) {
+                return Err(anyhow!(
+                    "{:?} deinterlacing is not supported by your hardware",
+                    self.deinterlacing
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns the VA deinterlacing algorithm implementing `deinterlacing`.
+    fn deinterlacing_algorithm(deinterlacing: Deinterlacing) -> libva::VAProcDeinterlacingType {
+        match deinterlacing {
+            Deinterlacing::Off => libva::VAProcDeinterlacingType::VAProcDeinterlacingNone,
+            Deinterlacing::Bob => libva::VAProcDeinterlacingType::VAProcDeinterlacingBob,
+            Deinterlacing::MotionAdaptive => {
+                libva::VAProcDeinterlacingType::VAProcDeinterlacingMotionAdaptive
+            }
+        }
+    }
+
+    /// Returns the size of the processed surfaces.
+    pub(crate) fn output_resolution(&self) -> Resolution {
+        self.output_resolution
+    }
+
+    /// Returns the RT format of the decoded surfaces.
+    pub(crate) fn rt_format(&self) -> u32 {
+        self.rt_format
+    }
+
+    /// Returns the deinterlacing applied to interlaced surfaces.
+    pub(crate) fn deinterlacing(&self) -> Deinterlacing {
+        self.deinterlacing
+    }
+
+    /// Scales the `visible_resolution` top-left area of `surface` into a new surface, and waits
+    /// for the operation to complete. `surface` is also deinterlaced if it has a `field_order`.
+    pub(crate) fn process<M: SurfaceMemoryDescriptor>(
+        &self,
+        surface: &Surface<M>,
+        visible_resolution: Resolution,
+        field_order: Option<FieldOrder>,
+        timestamp: u64,
+    ) -> anyhow::Result<Picture<PictureSync, Surface<()>>> {
+        let output = self
+            .display
+            .create_surfaces(
+                self.output_rt_format,
+                self.output_fourcc,
+                self.output_resolution.width,
+                self.output_resolution.height,
+                Some(libva::UsageHint::USAGE_HINT_VPP_WRITE),
+                vec![()],
+            )?
+            .pop()
+            .ok_or(anyhow!("no post-processing surface created"))?;
+
+        self.render(
+            surface,
+            visible_resolution,
+            field_order,
+            output,
+            self.output_resolution,
+            timestamp,
+        )
+    }
+
+    /// Processes the `visible_resolution` top-left area of `surface` into the same area of
+    /// `output`, and waits for the operation to complete. `surface` is also deinterlaced if it
+    /// has a `field_order`.
+    pub(crate) fn process_into<M: SurfaceMemoryDescriptor, D: SurfaceMemoryDescriptor>(
+        &self,
+        surface: &Surface<M>,
+        visible_resolution: Resolution,
+        field_order: Option<FieldOrder>,
+        output: &Surface<D>,
+    ) -> anyhow::Result<()> {
+        self.render(
+            surface,
+            visible_resolution,
+            field_order,
+            output,
+            visible_resolution,
+            0,
+        )
+        .map(drop)
+    }
+
+    fn render<M, D, S>(
+        &self,
+        surface: &Surface<M>,
+        visible_resolution: Resolution,
+        field_order: Option<FieldOrder>,
+        output: S,
+        output_resolution: Resolution,
+        timestamp: u64,
+    ) -> anyhow::Result<Picture<PictureSync, S>>
+    where
+        M: SurfaceMemoryDescriptor,
+        D: SurfaceMemoryDescriptor,
+        S: Borrow<Surface<D>>,
+    {
+        // Only the first field of the frame is output, and the previous frames are not kept
+        // around, so motion is detected between the two fields of the frame.
+        let deinterlacing_filter = match field_order {
+            Some(field_order) if self.deinterlacing != Deinterlacing::Off => {
+                let flags = match field_order {
+                    FieldOrder::TopFieldFirst => 0,
+                    FieldOrder::BottomFieldFirst => {
+                        libva::constants::VA_DEINTERLACING_BOTTOM_FIELD_FIRST
+                            | libva::constants::VA_DEINTERLACING_BOTTOM_FIELD
+                    }
+                };
+
+                Some(
+                    self.context
+                        .create_buffer(libva::BufferType::ProcFilterParameter(
+                            libva::ProcFilterParameterBufferDeinterlacing::new(
+                                Self::deinterlacing_algorithm(self.deinterlacing),
+                                flags,
+                            ),
+                        ))
+                        .context("while creating deinterlacing filter buffer")?,
+                )
+            }
+            _ => None,
+        };
+        let mut filters = deinterlacing_filter.into_iter().collect::<Vec<_>>();
+        for (filter_type, value) in [
+            (
+                libva::VAProcFilterType::VAProcFilterNoiseReduction,
+                self.denoise,
+            ),
+            (
+                libva::VAProcFilterType::VAProcFilterSharpening,
+                self.sharpen,
+            ),
+        ] {
+            if let Some(value) = value {
+                filters.push(
+                    self.context
+                        .create_buffer(libva::BufferType::ProcFilterParameter(
+                            libva::ProcFilterParameterBuffer::new(filter_type, value),
+                        ))
+                        .context("while creating filter buffer")?,
+                );
+            }
+        }
+        if !self.color_balance.is_empty() {
+            filters.push(
+                self.context
+                    .create_buffer(libva::BufferType::ProcFilterParameter(
+                        libva::ProcFilterParameterBufferColorBalance::new(
+                            self.color_balance.clone(),
+                        ),
+                    ))
+                    .context("while creating color balance filter buffer")?,
+            );
+        }
+
+        let pipeline_param = self
+            .context
+            .create_buffer(libva::BufferType::ProcPipelineParameter(
+                libva::ProcPipelineParameterBuffer::new(
+                    surface.id(),
+                    libva::VARectangle {
+                        x: 0,
+                        y: 0,
+                        width: visible_resolution.width as u16,
+                        height: visible_resolution.height as u16,
+                    },
+                    libva::VARectangle {
+                        x: 0,
+                        y: 0,
+                        width: output_resolution.width as u16,
+                        height: output_resolution.height as u16,
+                    },
+                    filters.iter().map(|filter| filter.id()).collect(),
+                ),
+            ))
+            .context("while creating pipeline params buffer")?;
+
+        let mut picture = Picture::new(timestamp, Rc::clone(&self.context), output);
+        picture.add_buffer(pipeline_param);
+
+        picture
+            .begin()?
+            .render()?
+            .end()?
+            .sync()
+            .map_err(|(e, _)| anyhow!(e))
+            .context("while post-processing surface")
+    }
+}
@@ -12,7 +12,11 @@
 //! can be turned into a crate of its own if needed in the future.
 
 pub mod av1;
+pub mod common;
+pub mod fuzz;
 pub mod h264;
 pub mod h265;
+pub mod param_sets;
+pub mod timecode;
 pub mod vp8;
 pub mod vp9;
@@ -2,6 +2,9 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file.
 
+pub mod annexb;
+pub mod av1c;
 mod helpers;
 pub mod parser;
 pub mod reader;
+pub mod synthesizer;
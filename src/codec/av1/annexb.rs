@@ -0,0 +1,300 @@
+// Copyright 2024 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Conversion between the low-overhead bitstream format of section 5 and the
+//! length-delimited bitstream format of Annex B.
+//!
+//! The [`Parser`](super::parser::Parser) accepts both formats, so these are
+//! only needed to feed other components, e.g. a muxer expecting low-overhead
+//! OBUs from an Annex B stream.
+
+use anyhow::anyhow;
+use anyhow::Context;
+
+use crate::codec::av1::parser::ObuType;
+
+/// Maximum number of bytes of a leb128() value, as per 4.10.5.
+const MAX_LEB128_BYTES: usize = 8;
+
+/// Reads a leb128() value from the start of `data`, returning it along with
+/// the number of bytes it takes.
+pub(crate) fn read_leb128(data: &[u8]) -> anyhow::Result<(usize, usize)> {
+    let mut value = 0u64;
+
+    for (i, byte) in data.iter().take(MAX_LEB128_BYTES).enumerate() {
+        value |= u64::from(byte & 0x7f) << (i * 7);
+        if byte & 0x80 == 0 {
+            let value = usize::try_from(value).context("leb128 value too large")?;
+            return Ok((value, i + 1));
+        }
+    }
+
+    Err(anyhow!("Invalid or truncated leb128 value"))
+}
+
+/// Appends `value` to `out` as a leb128() value.
+pub(crate) fn write_leb128(mut value: usize, out: &mut Vec<u8>) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            out.push(byte);
+            break;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+/// Returns the number of bytes `value` takes once encoded as leb128().
+pub(crate) fn leb128_len(value: usize) -> usize {
+    let bits = usize::BITS - value.leading_zeros();
+    std::cmp::max(1, bits.div_ceil(7) as usize)
+}
+
+/// An OBU split into its header and its payload.
+pub(crate) struct RawObu<'a> {
+    /// obu_header(), without any size field.
+    pub(crate) header: &'a [u8],
+    /// The OBU data after obu_size.
+    pub(crate) payload: &'a [u8],
+}
+
+impl<'a> RawObu<'a> {
+    /// Splits the OBU at the start of `data`. `obu_length` is the size of the
+    /// OBU if it is known from the Annex B length prefix. Returns the OBU and
+    /// the number of bytes it takes in `data`.
+    pub(crate) fn split(
+        data: &'a [u8],
+        obu_length: Option<usize>,
+    ) -> anyhow::Result<(Self, usize)> {
+        let first_byte = *data.first().ok_or(anyhow!("Empty OBU"))?;
+        let extension_flag = first_byte & 0x4 != 0;
+        let has_size_field = first_byte & 0x2 != 0;
+        let header_len = 1 + usize::from(extension_flag);
+
+        if data.len() < header_len {
+            return Err(anyhow!("Truncated OBU header"));
+        }
+
+        let (payload_start, payload_len) = if has_size_field {
+            let (obu_size, size_len) = read_leb128(&data[header_len..])?;
+            (header_len + size_len, obu_size)
+        } else {
+            let obu_length = obu_length.ok_or(anyhow!(
+                "OBU without size field, the stream is not in low-overhead format"
+            ))?;
+            let payload_len = obu_length
+                .checked_sub(header_len)
+                .ok_or(anyhow!("OBU length smaller than its header"))?;
+            (header_len, payload_len)
+        };
+
+        let end = payload_start + payload_len;
+        if end > data.len() || obu_length.is_some_and(|length| end > length) {
+            return Err(anyhow!("Truncated OBU"));
+        }
+
+        let obu = RawObu {
+            header: &data[..header_len],
+            payload: &data[payload_start..end],
+        };
+
+        Ok((obu, end))
+    }
+
+    pub(crate) fn obu_type(&self) -> Option<ObuType> {
+        ObuType::n((self.header[0] >> 3) & 0xf)
+    }
+
+    /// Appends the OBU to `out` with the size field set.
+    fn write_low_overhead(&self, out: &mut Vec<u8>) {
+        out.push(self.header[0] | 0x2);
+        out.extend_from_slice(&self.header[1..]);
+        write_leb128(self.payload.len(), out);
+        out.extend_from_slice(self.payload);
+    }
+
+    /// Size of the OBU without size field.
+    fn annexb_len(&self) -> usize {
+        self.header.len() + self.payload.len()
+    }
+
+    /// Appends the OBU to `out` preceded by its length and without size
+    /// field.
+    fn write_annexb(&self, out: &mut Vec<u8>) {
+        write_leb128(self.annexb_len(), out);
+        out.push(self.header[0] & !0x2);
+        out.extend_from_slice(&self.header[1..]);
+        out.extend_from_slice(self.payload);
+    }
+}
+
+/// Converts an Annex B stream, made of complete temporal units, to the
+/// low-overhead format.
+pub fn annexb_to_low_overhead(mut data: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let mut out = Vec::with_capacity(data.len());
+
+    while !data.is_empty() {
+        // temporal_unit()
+        let (temporal_unit_size, len) = read_leb128(data)?;
+        let mut temporal_unit = data
+            .get(len..len + temporal_unit_size)
+            .ok_or(anyhow!("Truncated temporal unit"))?;
+        data = &data[len + temporal_unit_size..];
+
+        while !temporal_unit.is_empty() {
+            // frame_unit()
+            let (frame_unit_size, len) = read_leb128(temporal_unit)?;
+            let mut frame_unit = temporal_unit
+                .get(len..len + frame_unit_size)
+                .ok_or(anyhow!("Truncated frame unit"))?;
+            temporal_unit = &temporal_unit[len + frame_unit_size..];
+
+            while !frame_unit.is_empty() {
+                let (obu_length, len) = read_leb128(frame_unit)?;
+                let (obu, _) = RawObu::split(&frame_unit[len..], Some(obu_length))?;
+                obu.write_low_overhead(&mut out);
+                frame_unit = frame_unit
+                    .get(len + obu_length..)
+                    .ok_or(anyhow!("Truncated OBU"))?;
+            }
+        }
+    }
+
+    Ok(out)
+}
+
+/// Converts a low-overhead stream to the Annex B format.
+///
+/// `data` must be made of complete temporal units, each starting with a
+/// temporal delimiter OBU. A new frame unit is started at every frame or frame
+/// header OBU following another one in the same temporal unit.
+pub fn low_overhead_to_annexb(mut data: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let mut temporal_units: Vec<Vec<Vec<RawObu>>> = Vec::new();
+
+    while !data.is_empty() {
+        let (obu, len) = RawObu::split(data, None)?;
+        data = &data[len..];
+
+        match obu.obu_type() {
+            Some(ObuType::TemporalDelimiter) => temporal_units.push(vec![vec![]]),
+            None => return Err(anyhow!("Invalid OBU type")),
+            _ => (),
+        }
+
+        let frame_units = temporal_units
+            .last_mut()
+            .ok_or(anyhow!("Stream does not start with a temporal delimiter"))?;
+
+        let starts_frame = matches!(
+            obu.obu_type(),
+            Some(ObuType::Frame) | Some(ObuType::FrameHeader)
+        );
+        let has_frame = frame_units.last().is_some_and(|obus| {
+            obus.iter().any(|obu| {
+                matches!(
+                    obu.obu_type(),
+                    Some(ObuType::Frame) | Some(ObuType::FrameHeader)
+                )
+            })
+        });
+        if starts_frame && has_frame {
+            frame_units.push(vec![]);
+        }
+
+        // There is always at least one frame unit per temporal unit.
+        frame_units.last_mut().unwrap().push(obu);
+    }
+
+    let obu_unit_size = |obu: &RawObu| leb128_len(obu.annexb_len()) + obu.annexb_len();
+    let frame_unit_size = |obus: &[RawObu]| obus.iter().map(obu_unit_size).sum::<usize>();
+
+    let mut out = Vec::new();
+    for frame_units in temporal_units {
+        let temporal_unit_size = frame_units
+            .iter()
+            .map(|obus| {
+                let size = frame_unit_size(obus);
+                leb128_len(size) + size
+            })
+            .sum();
+
+        write_leb128(temporal_unit_size, &mut out);
+        for obus in frame_units {
+            write_leb128(frame_unit_size(&obus), &mut out);
+            for obu in obus {
+                obu.write_annexb(&mut out);
+            }
+        }
+    }
+
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec::av1::parser::ObuFormat;
+    use crate::codec::av1::parser::ParsedObu;
+    use crate::codec::av1::parser::Parser;
+    use crate::utils::IvfIterator;
+
+    const STREAM_TEST_25_FPS: &[u8] = include_bytes!("test_data/test-25fps.ivf.av1");
+    const STREAM_ANNEXB: &[u8] = include_bytes!("test_data/av1-annexb.ivf.av1");
+
+    /// Returns the type and payload of all the OBUs of `temporal_unit`.
+    fn parse_obus(temporal_unit: &[u8], format: ObuFormat) -> Vec<(ObuType, Vec<u8>)> {
+        let mut parser = Parser::default();
+        parser.set_format(Some(format));
+        let mut obus = vec![];
+        let mut consumed = 0;
+
+        while consumed < temporal_unit.len() {
+            match parser.parse_obu(&temporal_unit[consumed..]).unwrap() {
+                ParsedObu::Process(obu) => {
+                    consumed += obu.data.len();
+                    obus.push((obu.header.obu_type, obu.as_ref().to_vec()));
+                }
+                ParsedObu::Drop(length) => consumed += usize::try_from(length).unwrap(),
+            }
+        }
+
+        obus
+    }
+
+    #[test]
+    fn leb128() {
+        for value in [0, 1, 127, 128, 16383, 16384, u32::MAX as usize] {
+            let mut buf = vec![];
+            write_leb128(value, &mut buf);
+            assert_eq!(buf.len(), leb128_len(value));
+            assert_eq!(read_leb128(&buf).unwrap(), (value, buf.len()));
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        for stream in [STREAM_TEST_25_FPS, STREAM_ANNEXB] {
+            for temporal_unit in IvfIterator::new(stream) {
+                let (annexb, low_overhead) = match Parser::detect_format(temporal_unit) {
+                    ObuFormat::AnnexB => (
+                        temporal_unit.to_vec(),
+                        annexb_to_low_overhead(temporal_unit).unwrap(),
+                    ),
+                    ObuFormat::LowOverhead => (
+                        low_overhead_to_annexb(temporal_unit).unwrap(),
+                        temporal_unit.to_vec(),
+                    ),
+                };
+
+                assert_eq!(Parser::detect_format(&low_overhead), ObuFormat::LowOverhead);
+                assert_eq!(
+                    parse_obus(&annexb, ObuFormat::AnnexB),
+                    parse_obus(&low_overhead, ObuFormat::LowOverhead)
+                );
+                assert_eq!(annexb_to_low_overhead(&annexb).unwrap(), low_overhead);
+            }
+        }
+    }
+}
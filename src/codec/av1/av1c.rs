@@ -0,0 +1,115 @@
+// Copyright 2024 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Support for the `av1C` box of the AV1 ISOBMFF binding, also used as the
+//! `CodecPrivate` of AV1 Matroska tracks.
+
+use std::io::Write;
+
+use anyhow::anyhow;
+
+use crate::codec::av1::parser::ObuFormat;
+use crate::codec::av1::parser::ObuType;
+use crate::codec::av1::parser::ParsedObu;
+use crate::codec::av1::parser::Parser;
+
+/// AV1CodecConfigurationRecord, as per section 2.3 of the AV1 Codec ISO Media
+/// File Format Binding.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Av1CodecConfigurationRecord {
+    pub seq_profile: u8,
+    pub seq_level_idx_0: u8,
+    pub seq_tier_0: bool,
+    pub high_bitdepth: bool,
+    pub twelve_bit: bool,
+    pub monochrome: bool,
+    pub chroma_subsampling_x: bool,
+    pub chroma_subsampling_y: bool,
+    pub chroma_sample_position: u8,
+    /// The sequence header OBU, in the low-overhead format, followed by any
+    /// metadata OBU applying to the whole sequence.
+    pub config_obus: Vec<u8>,
+}
+
+impl Av1CodecConfigurationRecord {
+    /// Builds a record from `data`, which starts with a sequence header OBU in
+    /// the low-overhead format.
+    pub fn from_sequence_header_obu(data: &[u8]) -> anyhow::Result<Self> {
+        let mut parser = Parser::default();
+        parser.set_format(Some(ObuFormat::LowOverhead));
+
+        let obu = match parser.parse_obu(data)? {
+            ParsedObu::Process(obu) if obu.header.obu_type == ObuType::SequenceHeader => obu,
+            _ => return Err(anyhow!("Expected a sequence header OBU")),
+        };
+        let seq = parser.parse_sequence_header_obu(&obu)?;
+        let cc = &seq.color_config;
+
+        Ok(Self {
+            seq_profile: seq.seq_profile as u8,
+            seq_level_idx_0: seq.operating_points[0].seq_level_idx as u8,
+            seq_tier_0: seq.operating_points[0].seq_tier != 0,
+            high_bitdepth: cc.high_bitdepth,
+            twelve_bit: cc.twelve_bit,
+            monochrome: cc.mono_chrome,
+            chroma_subsampling_x: cc.subsampling_x,
+            chroma_subsampling_y: cc.subsampling_y,
+            chroma_sample_position: cc.chroma_sample_position as u8,
+            config_obus: obu.data.to_vec(),
+        })
+    }
+
+    /// Writes the contents of an `av1C` box into `writer`.
+    pub fn write_into(&self, writer: &mut impl Write) -> anyhow::Result<()> {
+        writer.write_all(&[
+            // marker and version.
+            0x81,
+            (self.seq_profile & 0x7) << 5 | (self.seq_level_idx_0 & 0x1f),
+            u8::from(self.seq_tier_0) << 7
+                | u8::from(self.high_bitdepth) << 6
+                | u8::from(self.twelve_bit) << 5
+                | u8::from(self.monochrome) << 4
+                | u8::from(self.chroma_subsampling_x) << 3
+                | u8::from(self.chroma_subsampling_y) << 2
+                | (self.chroma_sample_position & 0x3),
+            // No initial_presentation_delay.
+            0x00,
+        ])?;
+        writer.write_all(&self.config_obus)?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::IvfIterator;
+
+    const STREAM_TEST_25_FPS: &[u8] = include_bytes!("test_data/test-25fps.ivf.av1");
+
+    #[test]
+    fn from_sequence_header() {
+        let temporal_unit = IvfIterator::new(STREAM_TEST_25_FPS).next().unwrap();
+        // Skip the temporal delimiter.
+        assert_eq!(temporal_unit[..2], [0x12, 0x00]);
+        let data = &temporal_unit[2..];
+
+        let record = Av1CodecConfigurationRecord::from_sequence_header_obu(data).unwrap();
+        assert_eq!(record.seq_profile, 0);
+        assert!(record.chroma_subsampling_x && record.chroma_subsampling_y);
+        assert!(!record.monochrome && !record.high_bitdepth);
+        assert_eq!(record.config_obus, data[..record.config_obus.len()]);
+        assert_eq!(record.config_obus[0] >> 3, ObuType::SequenceHeader as u8);
+
+        let mut av1c = vec![];
+        record.write_into(&mut av1c).unwrap();
+        assert_eq!(av1c[0], 0x81);
+        assert_eq!(av1c[1] & 0x1f, record.seq_level_idx_0);
+        assert_eq!(av1c[2], 0b0000_1100);
+        assert_eq!(av1c[4..], record.config_obus);
+
+        assert!(Av1CodecConfigurationRecord::from_sequence_header_obu(temporal_unit).is_err());
+    }
+}
@@ -11,6 +11,11 @@ use enumn::N;
 
 use crate::codec::av1::helpers;
 use crate::codec::av1::reader::Reader;
+use crate::codec::param_sets::ChromaFormat;
+use crate::codec::param_sets::SequenceFormat;
+use crate::codec::param_sets::SequenceParams;
+use crate::codec::timecode::Timecode;
+use crate::Resolution;
 
 pub const TOTAL_REFS_PER_FRAME: usize = 8;
 pub const NUM_REF_FRAMES: usize = 8;
@@ -51,6 +56,39 @@ pub const GM_ABS_TRANS_ONLY_BITS: u32 = 9;
 pub const GM_TRANS_ONLY_PREC_BITS: u32 = 3;
 pub const GM_ABS_TRANS_BITS: u32 = 12;
 pub const GM_TRANS_PREC_BITS: u32 = 6;
+pub const REF_SCALE_SHIFT: u32 = 14;
+
+/// Returns the width at which a frame of `upscaled_width` is coded with the superres
+/// denominator `superres_denom`, which goes from [`SUPERRES_DENOM_MIN`] to 16, or is
+/// [`SUPERRES_NUM`] without superres (5.9.8 and 7.21).
+pub fn superres_downscaled_width(upscaled_width: u32, superres_denom: u32) -> u32 {
+    (upscaled_width * SUPERRES_NUM as u32 + (superres_denom / 2)) / superres_denom
+}
+
+/// Returns the horizontal and vertical scale factors, in units of `1 / (1 << REF_SCALE_SHIFT)`,
+/// with which a frame of size `frame` predicts from a reference frame of size `reference`, as per
+/// 7.11.3.3. The width of `frame` is its coded width, after the superres downscaling if any,
+/// while the width of `reference` is its upscaled width. Returns `None` if the frame cannot use
+/// the reference, i.e. if the reference is more than twice as large or more than 16 times smaller
+/// in either dimension.
+///
+/// Scaled references let the resolution change on inter frames, either of the whole frame or of
+/// its coded width with superres, e.g. for the dynamic resize of real-time encoders.
+pub fn reference_scale(frame: Resolution, reference: Resolution) -> Option<(u32, u32)> {
+    let scale = |size: u32, ref_size: u32| {
+        let (size, ref_size) = (u64::from(size), u64::from(ref_size));
+        if size == 0 || 2 * size < ref_size || size > 16 * ref_size {
+            return None;
+        }
+
+        u32::try_from(((ref_size << REF_SCALE_SHIFT) + size / 2) / size).ok()
+    };
+
+    Some((
+        scale(frame.width, reference.width)?,
+        scale(frame.height, reference.height)?,
+    ))
+}
 
 pub enum ParsedObu<'a> {
     /// We should process the OBU normally.
@@ -120,6 +158,9 @@ pub struct Obu<'a> {
     pub start_offset: usize,
     /// The OBU size as per the specification after `start_offset`.
     pub size: usize,
+    /// Whether the OBU has been cut short, i.e. `data` contains less bytes than announced by the
+    /// OBU header. This can only happen if the parser tolerates truncated OBUs.
+    pub truncated: bool,
 }
 
 impl<'a> AsRef<[u8]> for Obu<'a> {
@@ -506,12 +547,74 @@ pub struct SequenceHeaderObu {
     pub num_planes: u32,
 }
 
+impl SequenceParams for SequenceHeaderObu {
+    fn sequence_format(&self) -> SequenceFormat {
+        let resolution = Resolution {
+            width: self.max_frame_width_minus_1 + 1,
+            height: self.max_frame_height_minus_1 + 1,
+        };
+        let bit_depth = match self.bit_depth {
+            BitDepth::Depth8 => 8,
+            BitDepth::Depth10 => 10,
+            BitDepth::Depth12 => 12,
+        };
+
+        SequenceFormat {
+            coded_resolution: resolution,
+            // The render size is only signaled in the frame headers.
+            display_resolution: resolution,
+            bit_depth_luma: bit_depth,
+            bit_depth_chroma: bit_depth,
+            chroma_format: ChromaFormat::from_subsampling(
+                self.color_config.mono_chrome,
+                self.color_config.subsampling_x,
+                self.color_config.subsampling_y,
+            ),
+        }
+    }
+}
+
 /// A TemporalDelimiterOBU
 #[derive(Clone, Debug, Default, PartialEq, Eq)]
 pub struct TemporalDelimiterObu {
     pub obu_header: ObuHeader,
 }
 
+/// The metadata types of 6.7.1.
+#[derive(N, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MetadataType {
+    HdrCll = 1,
+    HdrMdcv = 2,
+    Scalability = 3,
+    ItutT35 = 4,
+    Timecode = 5,
+}
+
+/// A MetadataOBU.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum MetadataObu {
+    /// Timecode metadata. The values that are not present when
+    /// `full_timestamp_flag` is unset are taken from the previous timecode
+    /// metadata, and the timecodes are always synthesized in full.
+    Timecode(Timecode),
+    /// A metadata type that is not parsed, along with its raw payload,
+    /// trailing bits included.
+    Unknown {
+        metadata_type: u32,
+        payload: Vec<u8>,
+    },
+}
+
+impl MetadataObu {
+    /// Returns the `metadata_type` of the OBU.
+    pub fn metadata_type(&self) -> u32 {
+        match self {
+            MetadataObu::Timecode(_) => MetadataType::Timecode as u32,
+            MetadataObu::Unknown { metadata_type, .. } => *metadata_type,
+        }
+    }
+}
+
 #[derive(N, Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
 pub enum InterpolationFilter {
     #[default]
@@ -1118,16 +1221,37 @@ pub struct AnnexBState {
     pub frame_unit_consumed: u32,
 }
 
+/// Format of an AV1 bitstream.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ObuFormat {
+    /// Low-overhead bitstream format of section 5, where every OBU carries its
+    /// size. This is the format used by IVF, MP4 and Matroska.
+    LowOverhead,
+    /// Length-delimited bitstream format of Annex B, where temporal units,
+    /// frame units and OBUs are prefixed by their size.
+    AnnexB,
+}
+
 #[derive(Clone, Debug)]
 enum StreamFormat {
     LowOverhead,
     AnnexB(AnnexBState),
 }
 
+/// Position of the parser in the stream, as saved by [`Parser::stream_state`].
+#[derive(Clone, Debug)]
+pub(crate) struct StreamState {
+    format: StreamFormat,
+    should_probe_for_annexb: bool,
+}
+
 #[derive(Debug)]
 pub struct Parser {
     stream_format: StreamFormat,
     operating_point: u32,
+    /// The operating point requested by the client, selected on each new
+    /// sequence header.
+    requested_operating_point: u32,
     /// Same as SeenFrameHeader in the specification
     seen_frame_header: bool,
     /// We keep this to implement frame_header_copy() in the specification.
@@ -1150,6 +1274,13 @@ pub struct Parser {
     tile_rows: u32,
     tile_size_bytes: u32,
 
+    /// Whether OBUs announcing more data than available should be accepted.
+    tolerate_truncation: bool,
+
+    /// The last timecode metadata parsed, from which the values of partial
+    /// timecodes are inferred.
+    last_timecode: Option<Timecode>,
+
     /// The last SequenceHeaderObu parsed.
     pub sequence_header: Option<Rc<SequenceHeaderObu>>,
 }
@@ -1246,8 +1377,7 @@ impl Parser {
         }
 
         fh.upscaled_width = fh.frame_width;
-        fh.frame_width =
-            (fh.upscaled_width * SUPERRES_NUM as u32 + (fh.superres_denom / 2)) / fh.superres_denom;
+        fh.frame_width = superres_downscaled_width(fh.upscaled_width, fh.superres_denom);
 
         Ok(())
     }
@@ -1472,8 +1602,9 @@ impl Parser {
             spatial_id: Default::default(),
         };
 
-        let obu_reserved_1bit = r.read_bit()?;
-        assert!(!obu_reserved_1bit); // Must be set to zero as per spec.
+        // obu_reserved_1bit must be set to zero, but is ignored by decoders as
+        // per the spec.
+        let _ = r.read_bit()?;
 
         if header.extension_flag {
             header.temporal_id = r.read_bits(3)?;
@@ -1484,6 +1615,62 @@ impl Parser {
         Ok(header)
     }
 
+    /// Guesses the format of a stream starting with `data`.
+    pub fn detect_format(data: &[u8]) -> ObuFormat {
+        if matches!(Self::annexb_probe(data), Ok(true)) {
+            ObuFormat::AnnexB
+        } else {
+            ObuFormat::LowOverhead
+        }
+    }
+
+    /// Sets the format of the stream, or `None` to detect it from the first
+    /// data passed to [`Parser::parse_obu`], which is the default.
+    ///
+    /// This must be called before parsing the first OBU or after a seek, as
+    /// any Annex B parsing state is lost.
+    pub fn set_format(&mut self, format: Option<ObuFormat>) {
+        match format {
+            None => self.should_probe_for_annexb = true,
+            Some(format) => {
+                self.stream_format = match format {
+                    ObuFormat::LowOverhead => StreamFormat::LowOverhead,
+                    ObuFormat::AnnexB => StreamFormat::AnnexB(Default::default()),
+                };
+                self.should_probe_for_annexb = false;
+            }
+        }
+    }
+
+    /// Returns the format of the stream, or `None` if it is still to be
+    /// detected.
+    pub fn format(&self) -> Option<ObuFormat> {
+        if self.should_probe_for_annexb {
+            return None;
+        }
+
+        match self.stream_format {
+            StreamFormat::LowOverhead => Some(ObuFormat::LowOverhead),
+            StreamFormat::AnnexB(_) => Some(ObuFormat::AnnexB),
+        }
+    }
+
+    /// Returns the current position of the parser in the stream. Parsing
+    /// Annex B data updates it, so it has to be restored with
+    /// [`Parser::restore_stream_state`] before parsing the same data again.
+    pub(crate) fn stream_state(&self) -> StreamState {
+        StreamState {
+            format: self.stream_format.clone(),
+            should_probe_for_annexb: self.should_probe_for_annexb,
+        }
+    }
+
+    /// Restores a position saved with [`Parser::stream_state`].
+    pub(crate) fn restore_stream_state(&mut self, state: StreamState) {
+        self.stream_format = state.format;
+        self.should_probe_for_annexb = state.should_probe_for_annexb;
+    }
+
     /// Parses one OBU from `data`, which can be in Annex B or low-overhead
     /// format.
     ///
@@ -1497,15 +1684,9 @@ impl Parser {
 
         if self.should_probe_for_annexb {
             // Try probing for Annex B data.
-            self.stream_format = if matches!(Self::annexb_probe(data), Ok(true)) {
-                log::debug!("Parsing an Annex B stream");
-                StreamFormat::AnnexB(AnnexBState::default())
-            } else {
-                log::debug!("Parsing a low-overhead stream");
-                StreamFormat::LowOverhead
-            };
-
-            self.should_probe_for_annexb = false;
+            let format = Self::detect_format(data);
+            log::debug!("Parsing a {:?} stream", format);
+            self.set_format(Some(format));
         }
 
         let obu_length = if let StreamFormat::AnnexB(annexb_state) = &mut self.stream_format {
@@ -1525,19 +1706,18 @@ impl Parser {
         // Both "low-overhead" and Annex B are now at the same point, i.e.: a
         // open_bitstream_unit() follows.
         let header = Self::parse_obu_header(&mut reader)?;
-        if matches!(self.stream_format, StreamFormat::LowOverhead) {
-            assert!(header.has_size_field);
+        if matches!(self.stream_format, StreamFormat::LowOverhead) && !header.has_size_field {
+            return Err(anyhow!(
+                "OBU without size field, the stream is not in low-overhead format"
+            ));
         }
 
-        let obu_size = if header.has_size_field {
+        let mut obu_size = if header.has_size_field {
             reader.read_leb128()? as usize
         } else {
-            /* trap any bugs when computing the final length */
             obu_length
-                .checked_sub(1)
-                .unwrap()
-                .checked_sub(usize::from(header.extension_flag))
-                .unwrap()
+                .checked_sub(1 + usize::from(header.extension_flag))
+                .ok_or(anyhow!("OBU length is smaller than its header"))?
         };
 
         let consumed = reader.consumed(start_pos);
@@ -1553,6 +1733,24 @@ impl Parser {
         assert!(reader.position() % 8 == 0);
         let start_offset: usize = (reader.position() / 8).try_into().unwrap();
 
+        let truncated = start_offset + obu_size > data.len();
+        if truncated {
+            if !self.tolerate_truncation {
+                return Err(anyhow!(
+                    "Truncated OBU: expected {} bytes, got {}",
+                    start_offset + obu_size,
+                    data.len()
+                ));
+            }
+
+            log::warn!(
+                "Accepting truncated OBU of type {:?}: {} bytes missing",
+                header.obu_type,
+                start_offset + obu_size - data.len()
+            );
+            obu_size = data.len() - start_offset;
+        }
+
         log::debug!(
             "Identified OBU type {:?}, data size: {}, obu_size: {}",
             header.obu_type,
@@ -1569,7 +1767,7 @@ impl Parser {
             let in_spatial_layer = ((self.operating_point_idc >> (header.spatial_id + 8)) & 1) != 0;
             if !in_temporal_layer || !in_spatial_layer {
                 log::debug!("Dropping obu as per drop_obu() in the specification",);
-                return Ok(ParsedObu::Drop(reader.consumed(0)));
+                return Ok(ParsedObu::Drop(u32::try_from(start_offset + obu_size)?));
             }
         }
 
@@ -1578,6 +1776,7 @@ impl Parser {
             data: Cow::from(&data[..start_offset + obu_size]),
             start_offset,
             size: obu_size,
+            truncated,
         }))
     }
 
@@ -1904,8 +2103,12 @@ impl Parser {
         self.sequence_header = Some(rc.clone());
 
         /* Client is supposed to set the operating point through external means,
-         * here we just set 0 as default. */
-        self.choose_operating_point(0)?;
+         * see set_operating_point(). Fall back to the highest one available if
+         * the stream does not have as many. */
+        self.choose_operating_point(
+            self.requested_operating_point
+                .min(rc.operating_points_cnt_minus_1),
+        )?;
 
         Ok(rc)
     }
@@ -2093,7 +2296,7 @@ impl Parser {
                 self.mi_col_starts[i] = start_sb << sb_shift;
 
                 let max_width = std::cmp::min(sb_cols - start_sb, max_tile_width_sb);
-                ti.width_in_sbs_minus_1[i] = r.read_ns(max_width.try_into().unwrap())?;
+                ti.width_in_sbs_minus_1[i] = r.read_ns(max_width)?;
 
                 let size_sb = ti.width_in_sbs_minus_1[i] + 1;
                 widest_tile_sb = std::cmp::max(size_sb, widest_tile_sb);
@@ -2118,7 +2321,7 @@ impl Parser {
             while start_sb < sb_rows {
                 self.mi_row_starts[i] = start_sb << sb_shift;
                 let max_height = std::cmp::min(sb_rows - start_sb, max_tile_height_sb);
-                ti.height_in_sbs_minus_1[i] = r.read_ns(max_height.try_into().unwrap())?;
+                ti.height_in_sbs_minus_1[i] = r.read_ns(max_height)?;
 
                 let size_sb = ti.height_in_sbs_minus_1[i] + 1;
                 start_sb += size_sb;
@@ -3583,6 +3786,11 @@ impl Parser {
 
         let mut sz: u64 = r.remaining_bits() / 8;
 
+        // The tile layout is given by the frame header the tile group belongs to.
+        if self.tile_cols == 0 || self.tile_rows == 0 {
+            return Err(anyhow!("Broken stream: tile group without a frame header"));
+        }
+
         let num_tiles = self.tile_rows * self.tile_cols;
         let start_bit_pos = r.position();
 
@@ -3599,6 +3807,15 @@ impl Parser {
             tg.tg_end = r.read_bits(tile_bits)?;
         }
 
+        if tg.tg_start > tg.tg_end || tg.tg_end >= num_tiles {
+            return Err(anyhow!(
+                "Invalid tile group: tiles {} to {} out of {}",
+                tg.tg_start,
+                tg.tg_end,
+                num_tiles
+            ));
+        }
+
         r.byte_alignment()?;
 
         let end_bit_pos = r.position();
@@ -3610,13 +3827,34 @@ impl Parser {
             let tile_row = tile_num / self.tile_cols;
             let tile_col = tile_num % self.tile_cols;
             let last_tile = tile_num == tg.tg_end;
-            let tile_size;
+            let mut tile_size;
+
+            let mut partial_tile = false;
 
             if last_tile {
                 tile_size = u32::try_from(sz).unwrap();
             } else {
-                tile_size = r.read_le(self.tile_size_bytes.try_into().unwrap())? + 1;
-                sz -= u64::from(tile_size + self.tile_size_bytes);
+                tile_size = match r.read_le(self.tile_size_bytes.try_into().unwrap()) {
+                    Ok(tile_size_minus_1) => tile_size_minus_1 + 1,
+                    // The data stops in the middle of the tile size, nothing more to decode.
+                    Err(_) if tg.obu.truncated => break,
+                    Err(e) => return Err(e),
+                };
+
+                if u64::from(tile_size + self.tile_size_bytes) <= sz {
+                    sz -= u64::from(tile_size + self.tile_size_bytes);
+                } else if tg.obu.truncated {
+                    // Only the beginning of this tile has been received. Let the accelerator
+                    // decode what is available, and ignore the following tiles.
+                    tile_size = u32::try_from(sz - u64::from(self.tile_size_bytes)).unwrap();
+                    partial_tile = true;
+                } else {
+                    return Err(anyhow!(
+                        "Broken stream: tile size {} exceeds the remaining {} bytes",
+                        tile_size,
+                        sz
+                    ));
+                }
             }
 
             let tile = Tile {
@@ -3630,6 +3868,13 @@ impl Parser {
                 mi_col_end: self.mi_row_starts[tile_col as usize + 1],
             };
 
+            if partial_tile {
+                if tile.tile_size > 0 {
+                    tg.tiles.push(tile);
+                }
+                break;
+            }
+
             tg.tiles.push(tile);
 
             // init_symbol, decode_tile() and exit_symbol() left to the accelerator.
@@ -3668,6 +3913,7 @@ impl Parser {
             data: obu.data,
             start_offset: obu.start_offset + frame_header_obu.header_bytes,
             size: obu.size - frame_header_obu.header_bytes,
+            truncated: obu.truncated,
         };
         let tile_group_obu = self.parse_tile_group_obu(obu)?;
 
@@ -3677,6 +3923,76 @@ impl Parser {
         })
     }
 
+    /// Parses a MetadataOBU, see 5.8.
+    pub fn parse_metadata_obu(&mut self, obu: &Obu) -> anyhow::Result<MetadataObu> {
+        if !matches!(obu.header.obu_type, ObuType::Metadata) {
+            return Err(anyhow!(
+                "Expected a MetadataOBU, got {:?}",
+                obu.header.obu_type
+            ));
+        }
+
+        let data = obu.as_ref();
+        let mut r = Reader::new(data);
+        let metadata_type = r.read_leb128()?;
+
+        match MetadataType::n(metadata_type) {
+            Some(MetadataType::Timecode) => {
+                let timecode = self.parse_metadata_timecode(&mut r)?;
+                Ok(MetadataObu::Timecode(timecode))
+            }
+            _ => Ok(MetadataObu::Unknown {
+                metadata_type,
+                payload: data[(r.position() / 8) as usize..].to_vec(),
+            }),
+        }
+    }
+
+    /// Parses a metadata_timecode() syntax element, see 5.8.7.
+    fn parse_metadata_timecode(&mut self, r: &mut Reader) -> anyhow::Result<Timecode> {
+        let previous = self.last_timecode.unwrap_or_default();
+        let mut timecode = Timecode {
+            counting_type: r.read_bits(5)? as u8,
+            field_based: false,
+            ..previous
+        };
+
+        let full_timestamp_flag = r.read_bit()?;
+        timecode.discontinuity = r.read_bit()?;
+        timecode.cnt_dropped = r.read_bit()?;
+        timecode.frames = r.read_bits(9)? as u16;
+
+        if full_timestamp_flag {
+            timecode.seconds = r.read_bits(6)? as u8;
+            timecode.minutes = r.read_bits(6)? as u8;
+            timecode.hours = r.read_bits(5)? as u8;
+        } else {
+            let seconds_flag = r.read_bit()?;
+            if seconds_flag {
+                timecode.seconds = r.read_bits(6)? as u8;
+                let minutes_flag = r.read_bit()?;
+                if minutes_flag {
+                    timecode.minutes = r.read_bits(6)? as u8;
+                    let hours_flag = r.read_bit()?;
+                    if hours_flag {
+                        timecode.hours = r.read_bits(5)? as u8;
+                    }
+                }
+            }
+        }
+
+        let time_offset_length = r.read_bits(5)? as u8;
+        // At most 31 bits, so the value always fits.
+        timecode.time_offset = if time_offset_length > 0 {
+            r.read_bits(time_offset_length)? as i32
+        } else {
+            0
+        };
+
+        self.last_timecode = Some(timecode);
+        Ok(timecode)
+    }
+
     pub fn parse_frame_header_obu(&mut self, obu: &Obu) -> anyhow::Result<FrameHeaderObu> {
         if !matches!(obu.header.obu_type, ObuType::FrameHeader | ObuType::Frame) {
             return Err(anyhow!(
@@ -3689,7 +4005,6 @@ impl Parser {
             Ok(self
                 .last_frame_header
                 .clone()
-                .take()
                 .ok_or(anyhow!("Broken stream: no previous frame header to copy"))?)
         } else {
             self.seen_frame_header = true;
@@ -3767,6 +4082,26 @@ impl Parser {
         Ok(())
     }
 
+    /// Sets whether OBUs announcing more data than available should be accepted instead of
+    /// rejected. Accepted OBUs are cut to the available data and have their `truncated` member
+    /// set, and tile groups only contain the tiles that have been at least partially received.
+    pub fn set_tolerate_truncation(&mut self, tolerate: bool) {
+        self.tolerate_truncation = tolerate;
+    }
+
+    /// Requests `operating_point` to be selected for the next sequence headers.
+    /// OBUs that do not belong to the temporal and spatial layers of the
+    /// selected operating point are then dropped by `parse_obu()`. Operating
+    /// point 0 is the default and usually includes all the layers.
+    pub fn set_operating_point(&mut self, operating_point: u32) {
+        self.requested_operating_point = operating_point;
+    }
+
+    /// Returns the operating point currently selected.
+    pub fn operating_point(&self) -> u32 {
+        self.operating_point
+    }
+
     pub fn highest_operating_point(&self) -> Option<u32> {
         if self.operating_point_idc == 0 {
             /* No scalability information, all OBUs must be decoded */
@@ -3782,6 +4117,7 @@ impl Default for Parser {
         Self {
             stream_format: StreamFormat::LowOverhead,
             operating_point: Default::default(),
+            requested_operating_point: Default::default(),
             seen_frame_header: Default::default(),
             last_frame_header: Default::default(),
             operating_point_idc: Default::default(),
@@ -3799,6 +4135,8 @@ impl Default for Parser {
             tile_rows_log2: Default::default(),
             tile_rows: Default::default(),
             tile_size_bytes: Default::default(),
+            tolerate_truncation: Default::default(),
+            last_timecode: Default::default(),
             sequence_header: Default::default(),
         }
     }
@@ -3814,6 +4152,7 @@ impl Clone for Parser {
         Self {
             stream_format: self.stream_format.clone(),
             operating_point: self.operating_point,
+            requested_operating_point: self.requested_operating_point,
             seen_frame_header: self.seen_frame_header,
             last_frame_header: self.last_frame_header.clone(),
             operating_point_idc: self.operating_point_idc,
@@ -3831,17 +4170,146 @@ impl Clone for Parser {
             tile_rows_log2: self.tile_rows_log2,
             tile_rows: self.tile_rows,
             tile_size_bytes: self.tile_size_bytes,
+            tolerate_truncation: self.tolerate_truncation,
+            last_timecode: self.last_timecode,
             sequence_header,
         }
     }
 }
 
+/// Incremental parser for low-overhead bitstreams (i.e. OBUs with
+/// `obu_has_size_field` set), for data that is received in arbitrary chunks
+/// rather than as complete temporal units.
+///
+/// Data is added with [`ObuStreamParser::push`], and complete OBUs, header
+/// included, are returned by [`ObuStreamParser::next_obu`] as soon as all
+/// their bytes have been received. They can then be passed to
+/// [`Parser::parse_obu`].
+#[derive(Debug)]
+pub struct ObuStreamParser {
+    /// Data received but not yet returned.
+    buffer: Vec<u8>,
+    /// Maximum number of bytes held in `buffer`.
+    max_pending: usize,
+}
+
+impl Default for ObuStreamParser {
+    fn default() -> Self {
+        Self::with_max_pending(Self::DEFAULT_MAX_PENDING)
+    }
+}
+
+impl ObuStreamParser {
+    /// Default maximum number of bytes received but not returned yet.
+    pub const DEFAULT_MAX_PENDING: usize = 32 << 20;
+
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Creates a parser holding at most `max_pending` bytes received but not
+    /// returned yet, which is also the size of the largest OBU it returns.
+    pub fn with_max_pending(max_pending: usize) -> Self {
+        Self {
+            buffer: Default::default(),
+            max_pending,
+        }
+    }
+
+    /// Appends `data` to the stream. Fails without appending anything if the
+    /// data pending would then exceed the maximum of the parser.
+    pub fn push(&mut self, data: &[u8]) -> anyhow::Result<()> {
+        if self.buffer.len() + data.len() > self.max_pending {
+            return Err(anyhow!(
+                "{} bytes pending exceed the maximum of {}",
+                self.buffer.len() + data.len(),
+                self.max_pending
+            ));
+        }
+
+        self.buffer.extend_from_slice(data);
+        Ok(())
+    }
+
+    /// Number of bytes received but not returned yet.
+    pub fn pending(&self) -> usize {
+        self.buffer.len()
+    }
+
+    /// Returns the total size of the OBU at the start of the buffer, or `None`
+    /// if not enough data is available to tell.
+    fn obu_len(&self) -> anyhow::Result<Option<usize>> {
+        let Some(&header) = self.buffer.first() else {
+            return Ok(None);
+        };
+
+        let extension_flag = header & 0x4 != 0;
+        let has_size_field = header & 0x2 != 0;
+        if !has_size_field {
+            return Err(anyhow!(
+                "OBU without size field, the stream is not in low-overhead format"
+            ));
+        }
+
+        let header_len = 1 + usize::from(extension_flag);
+        let mut obu_size = 0u64;
+
+        // leb128()
+        for i in 0..8 {
+            let Some(&byte) = self.buffer.get(header_len + i) else {
+                return Ok(None);
+            };
+
+            obu_size |= u64::from(byte & 0x7f) << (i * 7);
+            if byte & 0x80 == 0 {
+                // It is a requirement of bitstream conformance that the value
+                // returned from the leb128 parsing process is less than or
+                // equal to (1 << 32) - 1.
+                if obu_size > u64::from(u32::MAX) {
+                    return Err(anyhow!("Invalid OBU size {}", obu_size));
+                }
+
+                return usize::try_from(obu_size)?
+                    .checked_add(header_len + i + 1)
+                    .map(Some)
+                    .ok_or(anyhow!("Invalid OBU size {}", obu_size));
+            }
+        }
+
+        Err(anyhow!("Invalid leb128 OBU size"))
+    }
+
+    /// Returns the next complete OBU, or `None` if more data is needed.
+    pub fn next_obu(&mut self) -> anyhow::Result<Option<Vec<u8>>> {
+        match self.obu_len()? {
+            Some(len) if len > self.max_pending => Err(anyhow!(
+                "OBU of {} bytes exceeds the maximum of {} bytes pending",
+                len,
+                self.max_pending
+            )),
+            Some(len) if len <= self.buffer.len() => Ok(Some(self.buffer.drain(..len).collect())),
+            _ => Ok(None),
+        }
+    }
+
+    /// Discards any buffered data, e.g. to start parsing a new stream.
+    pub fn reset(&mut self) {
+        self.buffer.clear();
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::codec::av1::parser::{ParsedObu, Parser, StreamFormat};
+    use crate::codec::av1::parser::{ObuStreamParser, ParsedObu, Parser, StreamFormat};
+    use crate::codec::common::bit_writer::BitWriter;
     use crate::utils::IvfIterator;
 
+    use super::reference_scale;
+    use super::superres_downscaled_width;
     use super::ObuType;
+    use super::REF_SCALE_SHIFT;
+    use super::SUPERRES_NUM;
+    use crate::Resolution;
 
     /// Same as test-25fps.av1.ivf from Chromium
     const STREAM_TEST_25_FPS: &[u8] = include_bytes!("test_data/test-25fps.ivf.av1");
@@ -4008,4 +4476,239 @@ mod tests {
             }
         }
     }
+
+    #[test]
+    /// Test that a requested operating point is clamped to the ones available
+    /// in the stream.
+    fn select_operating_point() {
+        let mut parser = Parser::default();
+        parser.set_operating_point(3);
+
+        let packet = IvfIterator::new(STREAM_TEST_25_FPS).next().unwrap();
+        let mut consumed = 0;
+        loop {
+            let obu = match parser.parse_obu(&packet[consumed..]).unwrap() {
+                ParsedObu::Process(obu) => obu,
+                ParsedObu::Drop(length) => {
+                    consumed += usize::try_from(length).unwrap();
+                    continue;
+                }
+            };
+            consumed += obu.data.len();
+
+            if obu.header.obu_type == ObuType::SequenceHeader {
+                let sequence = parser.parse_sequence_header_obu(&obu).unwrap();
+                assert_eq!(sequence.operating_points_cnt_minus_1, 0);
+                break;
+            }
+        }
+
+        assert_eq!(parser.operating_point(), 0);
+        assert_eq!(parser.highest_operating_point(), None);
+    }
+
+    #[test]
+    /// Test that the OBUs outside of the temporal and spatial layers of the
+    /// selected operating point are dropped as a whole.
+    fn drop_obus_outside_operating_point() {
+        // Sequence header with two operating points: the first one with two
+        // temporal layers, and the second one with the base layer only.
+        let mut payload = Vec::new();
+        let mut w = BitWriter::new(&mut payload, false);
+        // seq_profile, still_picture, reduced_still_picture_header,
+        // timing_info_present_flag and initial_display_delay_present_flag.
+        w.write_f(7, 0u32).unwrap();
+        // operating_points_cnt_minus_1
+        w.write_f(5, 1u32).unwrap();
+        // operating_point_idc and seq_level_idx of each operating point.
+        for idc in [0x103u32, 0x101] {
+            w.write_f(12, idc).unwrap();
+            w.write_f(5, 0u32).unwrap();
+        }
+        // frame_width_bits_minus_1, frame_height_bits_minus_1 and the
+        // maximum frame size.
+        w.write_f(4, 15u32).unwrap();
+        w.write_f(4, 15u32).unwrap();
+        w.write_f(16, 319u32).unwrap();
+        w.write_f(16, 239u32).unwrap();
+        // frame_id_numbers_present_flag and the coding tools, all disabled.
+        w.write_f(9, 0u32).unwrap();
+        // seq_choose_screen_content_tools and seq_choose_integer_mv.
+        w.write_f(2, 3u32).unwrap();
+        // Superres, CDEF, loop restoration, 8-bit 4:2:0 color config without
+        // color description and film grain.
+        w.write_f(11, 0u32).unwrap();
+        // Trailing bits.
+        w.write_f(4, 0b1000u32).unwrap();
+        drop(w);
+
+        let mut sequence = vec![0x0a, payload.len() as u8];
+        sequence.extend_from_slice(&payload);
+
+        // Padding OBUs of temporal layers 0 and 1, with a 1 byte payload.
+        let base_layer = [0x7e, 0x00, 0x01, 0x00];
+        let enhancement_layer = [0x7e, 0x20, 0x01, 0x00];
+
+        for (operating_point, enhancement_layer_dropped) in [(0, false), (1, true)] {
+            let mut parser = Parser::default();
+            parser.set_operating_point(operating_point);
+            let ParsedObu::Process(obu) = parser.parse_obu(&sequence).unwrap() else {
+                panic!("sequence header dropped");
+            };
+            parser.parse_sequence_header_obu(&obu).unwrap();
+            assert_eq!(parser.operating_point(), operating_point);
+
+            assert!(matches!(
+                parser.parse_obu(&base_layer).unwrap(),
+                ParsedObu::Process(_)
+            ));
+            match parser.parse_obu(&enhancement_layer).unwrap() {
+                ParsedObu::Drop(length) => {
+                    assert!(enhancement_layer_dropped);
+                    assert_eq!(length as usize, enhancement_layer.len());
+                }
+                ParsedObu::Process(_) => assert!(!enhancement_layer_dropped),
+            }
+        }
+    }
+
+    #[test]
+    /// Test that truncated OBUs are rejected by default, and accepted and flagged as such when
+    /// the parser tolerates truncation.
+    fn parse_truncated_obu() {
+        let mut ivf_iter = IvfIterator::new(STREAM_TEST_25_FPS);
+        let packet = ivf_iter.next().unwrap();
+        // Cut the end of the tile data of the frame OBU.
+        let packet = &packet[..packet.len() - 16];
+
+        for tolerate_truncation in [false, true] {
+            let mut parser = Parser::default();
+            parser.set_tolerate_truncation(tolerate_truncation);
+            let mut consumed = 0;
+
+            loop {
+                let obu = match parser.parse_obu(&packet[consumed..]) {
+                    Ok(ParsedObu::Process(obu)) => obu,
+                    Ok(ParsedObu::Drop(length)) => {
+                        consumed += usize::try_from(length).unwrap();
+                        continue;
+                    }
+                    Err(_) => {
+                        assert!(!tolerate_truncation);
+                        assert!(consumed < packet.len());
+                        break;
+                    }
+                };
+
+                consumed += obu.data.len();
+
+                match obu.header.obu_type {
+                    ObuType::SequenceHeader => {
+                        assert!(!obu.truncated);
+                        parser.parse_sequence_header_obu(&obu).unwrap();
+                    }
+                    ObuType::Frame => {
+                        assert!(tolerate_truncation);
+                        assert!(obu.truncated);
+                        assert_eq!(consumed, packet.len());
+
+                        let frame = parser.parse_frame_obu(obu).unwrap();
+                        assert!(frame.tile_group.obu.truncated);
+                        assert!(!frame.tile_group.tiles.is_empty());
+                        break;
+                    }
+                    _ => assert!(!obu.truncated),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn stream_parser() {
+        let stream = IvfIterator::new(STREAM_TEST_25_FPS)
+            .flatten()
+            .copied()
+            .collect::<Vec<_>>();
+
+        let mut expected = 0;
+        let mut parser = Parser::default();
+        for packet in IvfIterator::new(STREAM_TEST_25_FPS) {
+            let mut consumed = 0;
+            while consumed < packet.len() {
+                let len = match parser.parse_obu(&packet[consumed..]).unwrap() {
+                    ParsedObu::Process(obu) => obu.data.len(),
+                    ParsedObu::Drop(length) => usize::try_from(length).unwrap(),
+                };
+                consumed += len;
+                expected += 1;
+            }
+        }
+
+        for chunk_size in [1, 2, 7, 500, stream.len()] {
+            let mut stream_parser = ObuStreamParser::new();
+            let mut obus = Vec::new();
+
+            for chunk in stream.chunks(chunk_size) {
+                stream_parser.push(chunk).unwrap();
+                while let Some(obu) = stream_parser.next_obu().unwrap() {
+                    obus.push(obu);
+                }
+            }
+
+            assert_eq!(stream_parser.pending(), 0);
+            assert_eq!(obus.len(), expected, "chunk size {}", chunk_size);
+            assert_eq!(obus.concat(), stream);
+        }
+    }
+
+    #[test]
+    fn stream_parser_limits() {
+        // Padding OBU with a size of 1 << 32 bytes.
+        let mut stream_parser = ObuStreamParser::new();
+        stream_parser
+            .push(&[0x7a, 0x80, 0x80, 0x80, 0x80, 0x10])
+            .unwrap();
+        assert!(stream_parser.next_obu().is_err());
+
+        // Padding OBU of 8 bytes, larger than the data the parser can hold.
+        let mut stream_parser = ObuStreamParser::with_max_pending(4);
+        stream_parser.push(&[0x7a, 0x06, 0x00]).unwrap();
+        assert!(stream_parser.next_obu().is_err());
+        assert!(stream_parser.push(&[0x00, 0x00]).is_err());
+        assert_eq!(stream_parser.pending(), 3);
+    }
+
+    #[test]
+    fn superres_downscaling() {
+        assert_eq!(superres_downscaled_width(1920, SUPERRES_NUM as u32), 1920);
+        assert_eq!(superres_downscaled_width(1920, 9), 1707);
+        assert_eq!(superres_downscaled_width(1920, 16), 960);
+    }
+
+    #[test]
+    fn reference_scaling() {
+        // A frame coded at half its width with superres predicts from a full width reference.
+        let unscaled = 1 << REF_SCALE_SHIFT;
+        let reference = Resolution::from((1920, 1080));
+        assert_eq!(
+            reference_scale(Resolution::from((960, 1080)), reference),
+            Some((2 * unscaled, unscaled))
+        );
+
+        // The scale factors are rounded.
+        assert_eq!(
+            reference_scale(Resolution::from((3, 3)), Resolution::from((5, 3))),
+            Some((27307, unscaled))
+        );
+
+        // More than twice as large, or more than 16 times smaller.
+        assert_eq!(
+            reference_scale(Resolution::from((959, 1080)), reference),
+            None
+        );
+        assert_eq!(
+            reference_scale(Resolution::from((1920, 1080)), Resolution::from((1920, 67))),
+            None
+        );
+    }
 }
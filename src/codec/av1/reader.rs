@@ -3,29 +3,30 @@
 // found in the LICENSE file.
 
 use anyhow::anyhow;
-use bitreader::BitReader;
 
 use crate::codec::av1::helpers;
+use crate::codec::common::bit_reader::BitReader;
 
 use super::parser::AnnexBState;
 
+#[derive(Clone)]
 pub struct Reader<'a>(BitReader<'a>);
 
 impl<'a> Reader<'a> {
     pub fn new(data: &'a [u8]) -> Self {
-        Self(BitReader::new(data))
+        Self(BitReader::new(data, false))
     }
 
     /// Read a single bit from the spec. Implements f(1) to return a bool for
     /// convenience.
     pub fn read_bit(&mut self) -> anyhow::Result<bool> {
-        self.0.read_bool().map_err(|e| anyhow!(e))
+        Ok(self.0.read_bit()?)
     }
 
     /// Implements f(n): Unsigned n-bit number appearing directly in the
     /// bitstream. The bits are read from high to low order. See 4.10.2
     pub fn read_bits(&mut self, num_bits: u8) -> anyhow::Result<u32> {
-        self.0.read_u32(num_bits).map_err(|e| anyhow!(e))
+        Ok(self.0.read_bits(usize::from(num_bits))?)
     }
 
     /// Implements uvlc(): Variable length unsigned n-bit number appearing
@@ -53,7 +54,9 @@ impl<'a> Reader<'a> {
     /// Implements le(n): Unsigned little-endian n-byte number appearing
     /// directly in the bitstream. See 4.10.4
     pub fn read_le(&mut self, num_bits: u8) -> anyhow::Result<u32> {
-        assert!(self.0.is_aligned(1));
+        if !self.0.is_aligned() || num_bits > 4 {
+            return Err(anyhow!("Invalid le({}) read", num_bits));
+        }
         let mut t = 0;
 
         for i in 0..num_bits {
@@ -67,31 +70,32 @@ impl<'a> Reader<'a> {
     /// Implements leb128(): Unsigned integer represented by a variable number
     /// of little-endian bytes. See 4.10.5
     pub fn read_leb128(&mut self) -> anyhow::Result<u32> {
-        assert!(self.0.is_aligned(1));
+        if !self.0.is_aligned() {
+            return Err(anyhow!("leb128() is not byte aligned"));
+        }
 
         let mut value = 0u64;
-        let mut leb128bytes = 0;
 
         for i in 0..8 {
             let byte = u64::from(self.read_bits(8)?);
             value |= (byte & 0x7f) << (i * 7);
 
-            leb128bytes += 1;
-
             if byte & 0x80 == 0 {
-                break;
+                return u32::try_from(value).map_err(|_| anyhow!("leb128() value too large"));
             }
         }
 
-        assert!(leb128bytes < 8);
-        assert!(value < u32::MAX.into());
-        Ok(value as u32)
+        Err(anyhow!("leb128() value longer than 8 bytes"))
     }
 
     /// Implements su(n): Signed integer converted from an n bits unsigned
     /// integer in the bitstream. (The unsigned integer corresponds to the
     /// bottom n bits of the signed integer.). See 4.10.6
     pub fn read_su(&mut self, num_bits: u8) -> anyhow::Result<i32> {
+        if num_bits == 0 || num_bits > 31 {
+            return Err(anyhow!("Invalid su({}) read", num_bits));
+        }
+
         let mut value = self.read_bits(num_bits)? as i32;
         let sign_mask = 1 << (num_bits - 1);
 
@@ -104,17 +108,22 @@ impl<'a> Reader<'a> {
 
     /// Implements ns(n): Unsigned encoded integer with maximum number of values
     /// n (i.e. output in range 0..n-1). See 4.10.7
-    pub fn read_ns(&mut self, num_bits: u8) -> anyhow::Result<u32> {
-        let w = helpers::floor_log2(u32::from(num_bits)) + 1;
-        let m = (1 << w) - num_bits;
+    pub fn read_ns(&mut self, n: u32) -> anyhow::Result<u32> {
+        if n == 0 {
+            return Err(anyhow!("Invalid ns(0) read"));
+        }
+
+        let w = helpers::floor_log2(n) + 1;
+        let m = (1u64 << w) - u64::from(n);
         let v = self.read_bits(u8::try_from(w)? - 1)?;
 
-        if v < m.into() {
+        if u64::from(v) < m {
             return Ok(v);
         }
 
         let extra_bit = self.read_bit()?;
-        Ok((v << 1) - u32::from(m) + u32::from(extra_bit))
+        // Cannot fail, as m is smaller than 2^31.
+        Ok((v << 1) - m as u32 + u32::from(extra_bit))
     }
 
     /// Implements 5.9.13: Delta quantizer syntax.
@@ -129,11 +138,11 @@ impl<'a> Reader<'a> {
     }
 
     pub fn more_data_in_bitstream(&self) -> bool {
-        self.0.remaining() != 0
+        self.0.num_bits_left() != 0
     }
 
     pub(crate) fn consumed(&self, start_pos: u32) -> u32 {
-        assert!(self.position() % 8 == 0);
+        debug_assert!(self.0.is_aligned());
         (self.position() / 8) as u32 - start_pos
     }
 
@@ -149,6 +158,7 @@ impl<'a> Reader<'a> {
         #[allow(clippy::comparison_chain)]
         if annexb_state.temporal_unit_consumed == annexb_state.temporal_unit_size {
             annexb_state.temporal_unit_size = 0;
+            annexb_state.temporal_unit_consumed = 0;
         } else if annexb_state.temporal_unit_consumed > annexb_state.temporal_unit_size {
             return Err(anyhow!(
                 "temporal_unit_size is {} but we consumed {} bytes",
@@ -169,6 +179,7 @@ impl<'a> Reader<'a> {
         #[allow(clippy::comparison_chain)]
         if annexb_state.frame_unit_consumed == annexb_state.frame_unit_size {
             annexb_state.frame_unit_size = 0;
+            annexb_state.frame_unit_consumed = 0;
         } else if annexb_state.frame_unit_consumed > annexb_state.frame_unit_size {
             return Err(anyhow!(
                 "frame_unit_size is {} but we consumed {} bytes",
@@ -192,20 +203,24 @@ impl<'a> Reader<'a> {
         annexb_state.temporal_unit_consumed += consumed;
         annexb_state.frame_unit_consumed += consumed;
 
-        Ok(Some(obu_length.try_into().unwrap()))
+        Ok(Some(obu_length.try_into()?))
     }
 
     /// Skips `num_bits` bits.
     pub fn skip(&mut self, num_bits: u64) -> anyhow::Result<()> {
-        self.0.skip(num_bits).map_err(|e| anyhow!(e))
+        Ok(self.0.skip_bits(usize::try_from(num_bits)?)?)
     }
 
     pub fn position(&self) -> u64 {
-        self.0.position()
+        self.0.position() as u64
     }
 
     /// Implements 5.3.4.
     pub fn read_trailing_bits(&mut self, mut num_bits: u64) -> anyhow::Result<()> {
+        if num_bits == 0 {
+            return Err(anyhow!("bad padding: no trailing bits"));
+        }
+
         let trailing_one_bit = self.read_bit()?;
         num_bits -= 1;
 
@@ -233,8 +248,7 @@ impl<'a> Reader<'a> {
             let b2 = if i != 0 { k + i - 1 } else { k };
             let a = 1 << b2;
             if num_syms <= mk + 3 * a {
-                let num_bits = u8::try_from(num_syms - mk).unwrap();
-                let subexp_final_bits = self.read_ns(num_bits)?;
+                let subexp_final_bits = self.read_ns(u32::try_from(num_syms - mk)?)?;
                 return Ok(subexp_final_bits);
             } else {
                 let subexp_more_bits = self.read_bit()?;
@@ -242,7 +256,7 @@ impl<'a> Reader<'a> {
                     i += 1;
                     mk += a;
                 } else {
-                    let num_bits = u8::try_from(b2).unwrap();
+                    let num_bits = u8::try_from(b2)?;
                     let subexp_bits = self.read_bits(num_bits)?;
                     return Ok(subexp_bits + mk as u32);
                 }
@@ -254,12 +268,10 @@ impl<'a> Reader<'a> {
     pub fn decode_unsigned_subexp_with_ref(&mut self, mx: i32, r: i32) -> anyhow::Result<u32> {
         let v = self.decode_subexp(mx)?;
         if (r << 1) <= mx {
-            Ok(helpers::inverse_recenter(r, v.try_into().unwrap())
-                .try_into()
-                .unwrap())
+            Ok(helpers::inverse_recenter(r, v.try_into()?).try_into()?)
         } else {
-            let res = mx - 1 - helpers::inverse_recenter(mx - 1 - r, v.try_into().unwrap());
-            Ok(res.try_into().unwrap())
+            let res = mx - 1 - helpers::inverse_recenter(mx - 1 - r, v.try_into()?);
+            Ok(res.try_into()?)
         }
     }
 
@@ -271,7 +283,7 @@ impl<'a> Reader<'a> {
         r: i32,
     ) -> anyhow::Result<i32> {
         let x = self.decode_unsigned_subexp_with_ref(high - low, r - low)?;
-        Ok(i32::try_from(x).unwrap() + low)
+        Ok(i32::try_from(x)? + low)
     }
 
     /// Implements 5.3.5 Byte alignment syntax
@@ -284,12 +296,6 @@ impl<'a> Reader<'a> {
     }
 
     pub fn remaining_bits(&self) -> u64 {
-        self.0.remaining()
-    }
-}
-
-impl<'a> Clone for Reader<'a> {
-    fn clone(&self) -> Self {
-        Self(self.0.relative_reader())
+        self.0.num_bits_left() as u64
     }
 }
@@ -0,0 +1,146 @@
+// Copyright 2024 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Synthesis of AV1 OBUs.
+//!
+//! Only metadata OBUs are supported for now, so they can be inserted into
+//! existing streams.
+
+use std::io::Write;
+
+use crate::codec::av1::annexb::write_leb128;
+use crate::codec::av1::parser::MetadataObu;
+use crate::codec::av1::parser::ObuType;
+use crate::codec::common::bit_writer::BitWriter;
+use crate::codec::common::bit_writer::BitWriterError;
+use crate::codec::common::bit_writer::BitWriterResult;
+use crate::codec::timecode::Timecode;
+
+/// Writes `metadata` as a MetadataOBU in the low-overhead bitstream format,
+/// i.e. with `obu_has_size_field` set (see 5.3.1 and 5.8.1).
+pub fn synthesize_metadata_obu<W: Write>(
+    metadata: &MetadataObu,
+    writer: &mut W,
+) -> BitWriterResult<()> {
+    let mut payload = Vec::<u8>::new();
+    write_leb128(metadata.metadata_type() as usize, &mut payload);
+
+    match metadata {
+        MetadataObu::Timecode(timecode) => {
+            let mut w = BitWriter::new(&mut payload, false);
+            synthesize_metadata_timecode(timecode, &mut w)?;
+
+            // trailing_bits()
+            w.write_f(1, 1u32)?;
+            while !w.aligned() {
+                w.write_f(1, 0u32)?;
+            }
+        }
+        MetadataObu::Unknown { payload: data, .. } => payload.extend_from_slice(data),
+    }
+
+    // obu_header(), with obu_has_size_field set.
+    let mut obu = vec![(ObuType::Metadata as u8) << 3 | 0b10];
+    write_leb128(payload.len(), &mut obu);
+    obu.extend_from_slice(&payload);
+
+    writer.write_all(&obu)?;
+    Ok(())
+}
+
+/// Writes a metadata_timecode() syntax element (5.8.7). The timecode is
+/// always written in full.
+fn synthesize_metadata_timecode<W: Write>(
+    timecode: &Timecode,
+    writer: &mut BitWriter<W>,
+) -> BitWriterResult<()> {
+    // time_offset_value is unsigned and at most 31 bits long in AV1.
+    let time_offset = u32::try_from(timecode.time_offset).map_err(|_| BitWriterError::Overflow)?;
+    let time_offset_length = (u32::BITS - time_offset.leading_zeros()) as usize;
+    if time_offset_length > 31 {
+        return Err(BitWriterError::Overflow);
+    }
+
+    writer.write_f(5, timecode.counting_type)?;
+    writer.write_f(1, /* full_timestamp_flag */ true)?;
+    writer.write_f(1, timecode.discontinuity)?;
+    writer.write_f(1, timecode.cnt_dropped)?;
+    writer.write_f(9, timecode.frames)?;
+    writer.write_f(6, timecode.seconds)?;
+    writer.write_f(6, timecode.minutes)?;
+    writer.write_f(5, timecode.hours)?;
+    writer.write_f(5, time_offset_length as u32)?;
+    if time_offset_length > 0 {
+        writer.write_f(time_offset_length, time_offset)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec::av1::parser::ParsedObu;
+    use crate::codec::av1::parser::Parser;
+
+    fn parse(parser: &mut Parser, data: &[u8]) -> MetadataObu {
+        let obu = match parser.parse_obu(data).unwrap() {
+            ParsedObu::Process(obu) => obu,
+            ParsedObu::Drop(_) => panic!("metadata OBU dropped"),
+        };
+        assert_eq!(obu.data.len(), data.len());
+        parser.parse_metadata_obu(&obu).unwrap()
+    }
+
+    #[test]
+    fn metadata_timecode_round_trip() {
+        let mut parser = Parser::default();
+
+        for timecode in [
+            "01:02:03:04".parse::<Timecode>().unwrap(),
+            Timecode {
+                hours: 23,
+                minutes: 59,
+                seconds: 59,
+                frames: 511,
+                discontinuity: true,
+                cnt_dropped: true,
+                time_offset: 1000,
+                ..Timecode::from_frame_number(0, 30, true).unwrap()
+            },
+        ] {
+            let metadata = MetadataObu::Timecode(timecode);
+            let mut data = Vec::new();
+            synthesize_metadata_obu(&metadata, &mut data).unwrap();
+            assert_eq!(parse(&mut parser, &data), metadata);
+        }
+
+        // The values absent from a partial timecode are the previous ones.
+        // counting_type 0, full_timestamp_flag 0, discontinuity_flag 0,
+        // cnt_dropped_flag 0, n_frames 5, seconds_flag 1, seconds_value 10,
+        // minutes_flag 0, time_offset_length 0, then trailing bits.
+        let data = [0x2a, 0x05, 0x05, 0x00, 0x02, 0xca, 0x02];
+        let MetadataObu::Timecode(timecode) = parse(&mut parser, &data) else {
+            panic!("timecode metadata expected");
+        };
+        assert_eq!(timecode.to_string(), "23:59:10:05");
+
+        let negative = MetadataObu::Timecode(Timecode {
+            time_offset: -1,
+            ..Default::default()
+        });
+        assert!(synthesize_metadata_obu(&negative, &mut Vec::new()).is_err());
+    }
+
+    #[test]
+    fn metadata_unknown_round_trip() {
+        let metadata = MetadataObu::Unknown {
+            metadata_type: 1,
+            payload: vec![0x12, 0x34, 0x56, 0x78, 0x80],
+        };
+        let mut data = Vec::new();
+        synthesize_metadata_obu(&metadata, &mut data).unwrap();
+        assert_eq!(parse(&mut Parser::default(), &data), metadata);
+    }
+}
@@ -0,0 +1,8 @@
+// Copyright 2024 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Tools shared by the parsers and synthesizers of all codecs.
+
+pub mod bit_reader;
+pub mod bit_writer;
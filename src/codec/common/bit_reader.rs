@@ -0,0 +1,340 @@
+// Copyright 2024 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+use thiserror::Error;
+
+/// Maximum number of bits that can be read at once.
+const MAX_READ_BITS: usize = 32;
+
+#[derive(Debug, Error, Clone, PartialEq, Eq)]
+pub enum BitReaderError {
+    #[error("reader ran out of bits")]
+    OutOfBits,
+    #[error("more than 32 ({0}) bits were requested")]
+    TooManyBitsRequested(usize),
+    #[error("failed to convert read input to target type")]
+    ConversionFailed,
+    #[error("invalid Exp-Golomb code")]
+    InvalidExpGolomb,
+    #[error("value out of bounds: expected {min} - {max}, got {value}")]
+    OutOfBounds { value: i64, min: i64, max: i64 },
+}
+
+pub type BitReaderResult<T> = std::result::Result<T, BitReaderError>;
+
+/// A bounds-checked bit reader reading bits from high to low order. It can
+/// optionally remove the emulation-prevention bytes of H.264 and H.265
+/// bitstreams.
+///
+/// All reads past the end of the data return [`BitReaderError::OutOfBits`],
+/// so malformed input cannot cause a panic.
+#[derive(Clone, Debug)]
+pub struct BitReader<'a> {
+    data: &'a [u8],
+    /// Index of the next byte to load from `data`.
+    next_byte: usize,
+    /// Contents of the current byte. First unread bit starting at position 8 -
+    /// num_remaining_bits_in_curr_bytes.
+    curr_byte: u32,
+    /// Number of bits remaining in `curr_byte`
+    num_remaining_bits_in_curr_byte: usize,
+    /// Used in epb detection.
+    prev_two_bytes: u32,
+    /// Number of epbs (i.e. 0x000003) we found.
+    num_epb: usize,
+    /// Whether emulation-prevention bytes are removed.
+    ep_enabled: bool,
+}
+
+impl<'a> BitReader<'a> {
+    pub fn new(data: &'a [u8], ep_enabled: bool) -> Self {
+        Self {
+            data,
+            next_byte: 0,
+            curr_byte: Default::default(),
+            num_remaining_bits_in_curr_byte: Default::default(),
+            prev_two_bytes: 0xffff,
+            num_epb: Default::default(),
+            ep_enabled,
+        }
+    }
+
+    /// Read a single bit from the stream.
+    pub fn read_bit(&mut self) -> BitReaderResult<bool> {
+        Ok(self.read_bits::<u32>(1)? == 1)
+    }
+
+    /// Read up to 32 bits from the stream.
+    pub fn read_bits<U: TryFrom<u32>>(&mut self, num_bits: usize) -> BitReaderResult<U> {
+        if num_bits > MAX_READ_BITS {
+            return Err(BitReaderError::TooManyBitsRequested(num_bits));
+        }
+
+        let mut bits_left = num_bits;
+        let mut out = 0u64;
+
+        while self.num_remaining_bits_in_curr_byte < bits_left {
+            out |= u64::from(self.curr_byte) << (bits_left - self.num_remaining_bits_in_curr_byte);
+            bits_left -= self.num_remaining_bits_in_curr_byte;
+            self.update_curr_byte()?;
+        }
+
+        out |= u64::from(self.curr_byte >> (self.num_remaining_bits_in_curr_byte - bits_left));
+        out &= (1 << num_bits) - 1;
+        self.num_remaining_bits_in_curr_byte -= bits_left;
+
+        // Cannot fail as at most 32 bits were kept.
+        let out = out as u32;
+        U::try_from(out).map_err(|_| BitReaderError::ConversionFailed)
+    }
+
+    /// Skip `num_bits` bits from the stream.
+    pub fn skip_bits(&mut self, mut num_bits: usize) -> BitReaderResult<()> {
+        while num_bits > 0 {
+            let n = std::cmp::min(num_bits, MAX_READ_BITS);
+            self.read_bits::<u32>(n)?;
+            num_bits -= n;
+        }
+
+        Ok(())
+    }
+
+    /// Returns the amount of bits left in the stream
+    pub fn num_bits_left(&self) -> usize {
+        (self.data.len() - self.next_byte) * 8 + self.num_remaining_bits_in_curr_byte
+    }
+
+    /// Returns the number of bits read so far, including emulation-prevention
+    /// bytes.
+    pub fn position(&self) -> usize {
+        self.next_byte * 8 - self.num_remaining_bits_in_curr_byte
+    }
+
+    /// Returns `true` if the next bit to read is the first of a byte.
+    pub fn is_aligned(&self) -> bool {
+        self.num_remaining_bits_in_curr_byte == 0
+    }
+
+    /// Returns the number of emulation-prevention bytes read so far.
+    pub fn num_epb(&self) -> usize {
+        self.num_epb
+    }
+
+    /// Whether the stream still has RBSP data. Implements more_rbsp_data(). See
+    /// the spec for more details.
+    pub fn has_more_rsbp_data(&mut self) -> bool {
+        if self.num_remaining_bits_in_curr_byte == 0 && self.update_curr_byte().is_err() {
+            // no more data at all in the rbsp
+            return false;
+        }
+
+        // If the next bit is the stop bit, then we should only see unset bits
+        // until the end of the data.
+        if (self.curr_byte & ((1 << (self.num_remaining_bits_in_curr_byte - 1)) - 1)) != 0 {
+            return true;
+        }
+
+        if self.data[self.next_byte..].iter().any(|byte| *byte != 0) {
+            return true;
+        }
+
+        self.next_byte = self.data.len();
+        false
+    }
+
+    /// Reads an unsigned Exp-Golomb-coded value. Corresponds to `ue(v)` in the
+    /// H.264 spec.
+    pub fn read_ue<U: TryFrom<u32>>(&mut self) -> BitReaderResult<U> {
+        let mut num_bits = 0;
+
+        while !self.read_bit()? {
+            num_bits += 1;
+            if num_bits > 31 {
+                return Err(BitReaderError::InvalidExpGolomb);
+            }
+        }
+
+        let value = (1u32 << num_bits) - 1;
+
+        // Check for overflow
+        let value = if num_bits == 31 {
+            match self.read_bits::<u32>(num_bits)? {
+                0 => value,
+                _ => return Err(BitReaderError::InvalidExpGolomb),
+            }
+        } else {
+            value + self.read_bits::<u32>(num_bits)?
+        };
+
+        U::try_from(value).map_err(|_| BitReaderError::ConversionFailed)
+    }
+
+    pub fn read_ue_bounded<U: TryFrom<u32>>(&mut self, min: u32, max: u32) -> BitReaderResult<U> {
+        let ue = self.read_ue::<u32>()?;
+        if ue > max || ue < min {
+            Err(BitReaderError::OutOfBounds {
+                value: ue.into(),
+                min: min.into(),
+                max: max.into(),
+            })
+        } else {
+            U::try_from(ue).map_err(|_| BitReaderError::ConversionFailed)
+        }
+    }
+
+    pub fn read_ue_max<U: TryFrom<u32>>(&mut self, max: u32) -> BitReaderResult<U> {
+        self.read_ue_bounded(0, max)
+    }
+
+    /// Reads a signed Exp-Golomb-coded value. Corresponds to `se(v)` in the
+    /// H.264 spec.
+    pub fn read_se<U: TryFrom<i32>>(&mut self) -> BitReaderResult<U> {
+        let ue = i64::from(self.read_ue::<u32>()?);
+
+        let value = if ue % 2 == 0 { -ue / 2 } else { ue / 2 + 1 };
+
+        // Cannot overflow as ue is at most 2^31 - 1.
+        let value = value as i32;
+        U::try_from(value).map_err(|_| BitReaderError::ConversionFailed)
+    }
+
+    pub fn read_se_bounded<U: TryFrom<i32>>(&mut self, min: i32, max: i32) -> BitReaderResult<U> {
+        let se = self.read_se::<i32>()?;
+        if se < min || se > max {
+            Err(BitReaderError::OutOfBounds {
+                value: se.into(),
+                min: min.into(),
+                max: max.into(),
+            })
+        } else {
+            U::try_from(se).map_err(|_| BitReaderError::ConversionFailed)
+        }
+    }
+
+    fn get_byte(&mut self) -> BitReaderResult<u8> {
+        let byte = *self
+            .data
+            .get(self.next_byte)
+            .ok_or(BitReaderError::OutOfBits)?;
+        self.next_byte += 1;
+
+        Ok(byte)
+    }
+
+    fn update_curr_byte(&mut self) -> BitReaderResult<()> {
+        let mut byte = self.get_byte()?;
+
+        if self.ep_enabled && (self.prev_two_bytes & 0xffff) == 0 && byte == 0x03 {
+            // We found an epb
+            self.num_epb += 1;
+            // Read another byte
+            byte = self.get_byte()?;
+            // We need another 3 bytes before another epb can happen.
+            self.prev_two_bytes = 0xffff;
+        }
+
+        self.num_remaining_bits_in_curr_byte = 8;
+        self.prev_two_bytes = ((self.prev_two_bytes & 0xff) << 8) | u32::from(byte);
+
+        self.curr_byte = u32::from(byte);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // These tests are adapted from the chromium tests at media/video/h264_bit_reader_unitttest.cc
+
+    #[test]
+    fn read_stream_without_escape_and_trailing_zero_bytes() {
+        const RBSP: [u8; 6] = [0x01, 0x23, 0x45, 0x67, 0x89, 0xa0];
+
+        let mut reader = BitReader::new(&RBSP, true);
+        assert_eq!(reader.read_bits::<u32>(1).unwrap(), 0);
+        assert_eq!(reader.num_bits_left(), 47);
+        assert!(reader.has_more_rsbp_data());
+
+        assert_eq!(reader.read_bits::<u32>(8).unwrap(), 0x02);
+        assert_eq!(reader.num_bits_left(), 39);
+        assert!(reader.has_more_rsbp_data());
+
+        assert_eq!(reader.read_bits::<u32>(31).unwrap(), 0x23456789);
+        assert_eq!(reader.num_bits_left(), 8);
+        assert!(reader.has_more_rsbp_data());
+
+        assert_eq!(reader.read_bits::<u32>(1).unwrap(), 1);
+        assert_eq!(reader.num_bits_left(), 7);
+        assert!(reader.has_more_rsbp_data());
+
+        assert_eq!(reader.read_bits::<u32>(1).unwrap(), 0);
+        assert_eq!(reader.num_bits_left(), 6);
+        assert!(!reader.has_more_rsbp_data());
+    }
+
+    #[test]
+    fn single_byte_stream() {
+        const RBSP: [u8; 1] = [0x18];
+
+        let mut reader = BitReader::new(&RBSP, true);
+        assert_eq!(reader.num_bits_left(), 8);
+        assert!(reader.has_more_rsbp_data());
+        assert_eq!(reader.read_bits::<u32>(4).unwrap(), 1);
+        assert!(!reader.has_more_rsbp_data());
+    }
+
+    #[test]
+    fn stop_bit_occupy_full_byte() {
+        const RBSP: [u8; 2] = [0xab, 0x80];
+
+        let mut reader = BitReader::new(&RBSP, true);
+        assert_eq!(reader.num_bits_left(), 16);
+        assert!(reader.has_more_rsbp_data());
+
+        assert_eq!(reader.read_bits::<u32>(8).unwrap(), 0xab);
+        assert_eq!(reader.num_bits_left(), 8);
+
+        assert!(!reader.has_more_rsbp_data());
+    }
+
+    #[test]
+    fn malformed_input() {
+        const DATA: [u8; 5] = [0x00, 0x00, 0x03, 0xff, 0xff];
+
+        let mut reader = BitReader::new(&DATA, false);
+        assert_eq!(
+            reader.read_bits::<u32>(33),
+            Err(BitReaderError::TooManyBitsRequested(33))
+        );
+        assert_eq!(
+            reader.read_bits::<u8>(32),
+            Err(BitReaderError::ConversionFailed)
+        );
+        assert_eq!(reader.position(), 32);
+        assert_eq!(reader.read_bits::<u32>(8).unwrap(), 0xff);
+        assert_eq!(reader.read_bit(), Err(BitReaderError::OutOfBits));
+        assert_eq!(reader.skip_bits(1), Err(BitReaderError::OutOfBits));
+
+        // The emulation-prevention byte is skipped.
+        let mut reader = BitReader::new(&DATA, true);
+        assert_eq!(reader.read_bits::<u32>(32).unwrap(), 0x0000ffff);
+        assert_eq!(reader.num_epb(), 1);
+        assert_eq!(reader.read_bit(), Err(BitReaderError::OutOfBits));
+
+        // Too many leading zeros for an Exp-Golomb code.
+        let mut reader = BitReader::new(&[0, 0, 0, 0, 0xff], false);
+        assert_eq!(
+            reader.read_ue::<u32>(),
+            Err(BitReaderError::InvalidExpGolomb)
+        );
+        let mut reader = BitReader::new(&[0x01], false);
+        assert_eq!(reader.read_ue::<u32>(), Err(BitReaderError::OutOfBits));
+        let mut reader = BitReader::new(&[0x20], false);
+        assert!(matches!(
+            reader.read_ue_max::<u32>(2),
+            Err(BitReaderError::OutOfBounds { value: 3, .. })
+        ));
+    }
+}
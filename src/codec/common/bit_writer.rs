@@ -4,10 +4,11 @@
 use std::io::Write;
 
 use log::error;
+use memchr::memchr;
 use thiserror::Error;
 
 #[derive(Error, Debug)]
-pub enum NaluWriterError {
+pub enum BitWriterError {
     #[error("value increment caused value overflow")]
     Overflow,
     #[error("invalid bit count")]
@@ -16,27 +17,29 @@ pub enum NaluWriterError {
     Io(#[from] std::io::Error),
 }
 
-pub type NaluWriterResult<T> = std::result::Result<T, NaluWriterError>;
+pub type BitWriterResult<T> = std::result::Result<T, BitWriterError>;
 
-/// A writer for H.264 bitstream. It is capable of outputing bitstream with
-/// emulation-prevention.
-pub struct NaluWriter<'w, W: Write> {
+/// A bit writer writing bits from high to low order. It is capable of
+/// outputing H.264 and H.265 bitstream with emulation-prevention.
+pub struct BitWriter<'w, W: Write> {
     out: &'w mut W,
 
     nth_bit: usize,
     curr_byte: u8,
-    prev_bytes: [Option<u8>; 2],
+    /// Number of consecutive zero bytes at the end of the output, used for
+    /// emulation prevention.
+    zeros: usize,
 
     /// Emulation prevention enabled.
     ep_enabled: bool,
 }
 
-impl<'w, W: Write> NaluWriter<'w, W> {
+impl<'w, W: Write> BitWriter<'w, W> {
     pub fn new(writer: &'w mut W, ep_enabled: bool) -> Self {
         Self {
             out: writer,
             curr_byte: 0,
-            prev_bytes: [None; 2],
+            zeros: 0,
             nth_bit: 0,
             ep_enabled,
         }
@@ -44,11 +47,11 @@ impl<'w, W: Write> NaluWriter<'w, W> {
 
     /// Writes fixed bit size integer (up to 32 bit) output with emulation
     /// prevention if enabled. Corresponds to `f(n)` in H.264 spec.
-    pub fn write_f<T: Into<u32>>(&mut self, bits: usize, value: T) -> NaluWriterResult<usize> {
+    pub fn write_f<T: Into<u32>>(&mut self, bits: usize, value: T) -> BitWriterResult<usize> {
         let value = value.into();
 
         if bits > 32 {
-            return Err(NaluWriterError::InvalidBitCount);
+            return Err(BitWriterError::InvalidBitCount);
         }
 
         let mut written = 0;
@@ -63,13 +66,13 @@ impl<'w, W: Write> NaluWriter<'w, W> {
     }
 
     /// An alias to [`Self::write_f`] Corresponds to `n(n)` in H.264 spec.
-    pub fn write_u<T: Into<u32>>(&mut self, bits: usize, value: T) -> NaluWriterResult<usize> {
+    pub fn write_u<T: Into<u32>>(&mut self, bits: usize, value: T) -> BitWriterResult<usize> {
         self.write_f(bits, value)
     }
 
     /// Writes a number in exponential golumb format.
-    pub fn write_exp_golumb(&mut self, value: u32) -> NaluWriterResult<()> {
-        let value = value.checked_add(1).ok_or(NaluWriterError::Overflow)?;
+    pub fn write_exp_golumb(&mut self, value: u32) -> BitWriterResult<()> {
+        let value = value.checked_add(1).ok_or(BitWriterError::Overflow)?;
         let bits = 32 - value.leading_zeros() as usize;
         let zeros = bits - 1;
 
@@ -81,7 +84,7 @@ impl<'w, W: Write> NaluWriter<'w, W> {
 
     /// Writes a unsigned integer in exponential golumb format.
     /// Coresponds to `ue(v)` in H.264 spec.
-    pub fn write_ue<T: Into<u32>>(&mut self, value: T) -> NaluWriterResult<()> {
+    pub fn write_ue<T: Into<u32>>(&mut self, value: T) -> BitWriterResult<()> {
         let value = value.into();
 
         self.write_exp_golumb(value)
@@ -89,7 +92,7 @@ impl<'w, W: Write> NaluWriter<'w, W> {
 
     /// Writes a signed integer in exponential golumb format.
     /// Coresponds to `se(v)` in H.264 spec.
-    pub fn write_se<T: Into<i32>>(&mut self, value: T) -> NaluWriterResult<()> {
+    pub fn write_se<T: Into<i32>>(&mut self, value: T) -> BitWriterResult<()> {
         let value: i32 = value.into();
         let abs_value: u32 = value.unsigned_abs();
 
@@ -100,13 +103,54 @@ impl<'w, W: Write> NaluWriter<'w, W> {
         }
     }
 
+    /// Writes a slice of bytes with emulation prevention if enabled.
+    ///
+    /// When the writer is byte aligned, runs of bytes that cannot form a start
+    /// code prefix are located with [`memchr`] and copied at once, which is
+    /// considerably faster than writing them with [`Self::write_f`].
+    pub fn write_bytes(&mut self, bytes: &[u8]) -> BitWriterResult<()> {
+        if !self.aligned() {
+            for byte in bytes {
+                self.write_f(8, *byte)?;
+            }
+            return Ok(());
+        }
+
+        if !self.ep_enabled {
+            self.out.write_all(bytes)?;
+            return Ok(());
+        }
+
+        let mut rest = bytes;
+        while let Some(&byte) = rest.first() {
+            if byte == 0x00 || self.zeros >= 2 {
+                self.escape_byte(byte)?;
+                rest = &rest[1..];
+                continue;
+            }
+
+            // Nothing needs escaping until the next zero byte.
+            let end = memchr(0x00, rest).unwrap_or(rest.len());
+            self.out.write_all(&rest[..end])?;
+            self.zeros = 0;
+            rest = &rest[end..];
+        }
+
+        Ok(())
+    }
+
     /// Returns `true` if ['Self`] hold data that wasn't written to [`std::io::Write`]
     pub fn has_data_pending(&self) -> bool {
-        self.nth_bit != 0 || self.prev_bytes[0].is_some() || self.prev_bytes[1].is_some()
+        self.nth_bit != 0
+    }
+
+    /// Returns the number of bits held until the next byte is complete.
+    pub fn pending_bits(&self) -> usize {
+        self.nth_bit
     }
 
     /// Takes a single bit that will be outputed to [`std::io::Write`]
-    fn write_bit(&mut self, bit: bool) -> NaluWriterResult<()> {
+    fn write_bit(&mut self, bit: bool) -> BitWriterResult<()> {
         self.curr_byte |= (bit as u8) << (7u8 - self.nth_bit as u8);
         self.nth_bit += 1;
 
@@ -119,7 +163,7 @@ impl<'w, W: Write> NaluWriter<'w, W> {
 
     /// Outputs a currently cached bits value and writes to [`std::io::Write`]
     /// with emulation-prevention if enabled.
-    fn output_byte(&mut self) -> NaluWriterResult<()> {
+    fn output_byte(&mut self) -> BitWriterResult<()> {
         if self.nth_bit == 0 {
             return Ok(());
         } else if !self.ep_enabled {
@@ -129,20 +173,7 @@ impl<'w, W: Write> NaluWriter<'w, W> {
             return Ok(());
         }
 
-        if self.prev_bytes[1] == Some(0x00)
-            && self.prev_bytes[0] == Some(0x00)
-            && self.curr_byte <= 0x03
-        {
-            self.out.write_all(&[0x00, 0x00, 0x03, self.curr_byte])?;
-            self.prev_bytes = [None; 2];
-        } else {
-            if let Some(byte) = self.prev_bytes[1] {
-                self.out.write_all(&[byte])?;
-            }
-
-            self.prev_bytes[1] = self.prev_bytes[0];
-            self.prev_bytes[0] = Some(self.curr_byte);
-        }
+        self.escape_byte(self.curr_byte)?;
 
         self.nth_bit = 0;
         self.curr_byte = 0;
@@ -150,8 +181,28 @@ impl<'w, W: Write> NaluWriter<'w, W> {
         Ok(())
     }
 
+    /// Writes a single byte to [`std::io::Write`], preceded by an
+    /// emulation-prevention byte if it would otherwise form a start code
+    /// prefix with the previous bytes.
+    fn escape_byte(&mut self, byte: u8) -> BitWriterResult<()> {
+        if self.zeros >= 2 && byte <= 0x03 {
+            self.out.write_all(&[0x03, byte])?;
+            self.zeros = 0;
+        } else {
+            self.out.write_all(&[byte])?;
+        }
+
+        if byte == 0x00 {
+            self.zeros += 1;
+        } else {
+            self.zeros = 0;
+        }
+
+        Ok(())
+    }
+
     /// Writes a H.264 NALU header.
-    pub fn write_header(&mut self, idc: u8, _type: u8) -> NaluWriterResult<()> {
+    pub fn write_header(&mut self, idc: u8, _type: u8) -> BitWriterResult<()> {
         self.flush()?;
 
         self.out.write_all(&[
@@ -171,15 +222,8 @@ impl<'w, W: Write> NaluWriter<'w, W> {
     }
 
     /// Immediately outputs any cached bits to [`std::io::Write`]
-    fn flush(&mut self) -> NaluWriterResult<()> {
-        if let Some(byte) = self.prev_bytes[1] {
-            self.out.write_all(&[byte])?;
-        }
-        if let Some(byte) = self.prev_bytes[0] {
-            self.out.write_all(&[byte])?;
-        }
-
-        self.prev_bytes = [None; 2];
+    fn flush(&mut self) -> BitWriterResult<()> {
+        self.zeros = 0;
         if self.nth_bit != 0 {
             self.out.write_all(&[self.curr_byte])?;
             self.nth_bit = 0;
@@ -190,7 +234,7 @@ impl<'w, W: Write> NaluWriter<'w, W> {
     }
 }
 
-impl<W: Write> Drop for NaluWriter<'_, W> {
+impl<W: Write> Drop for BitWriter<'_, W> {
     fn drop(&mut self) {
         if let Err(e) = self.flush() {
             error!("Unable to flush bits {e:?}");
@@ -201,13 +245,13 @@ impl<W: Write> Drop for NaluWriter<'_, W> {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::codec::h264::nalu_reader::NaluReader;
+    use crate::codec::common::bit_reader::BitReader;
 
     #[test]
     fn simple_bits() {
         let mut buf = Vec::<u8>::new();
         {
-            let mut writer = NaluWriter::new(&mut buf, false);
+            let mut writer = BitWriter::new(&mut buf, false);
             writer.write_f(1, true).unwrap();
             writer.write_f(1, false).unwrap();
             writer.write_f(1, false).unwrap();
@@ -225,7 +269,7 @@ mod tests {
         fn single_ue(value: u32) -> Vec<u8> {
             let mut buf = Vec::<u8>::new();
             {
-                let mut writer = NaluWriter::new(&mut buf, false);
+                let mut writer = BitWriter::new(&mut buf, false);
                 writer.write_ue(value).unwrap();
             }
             buf
@@ -247,14 +291,14 @@ mod tests {
     fn writer_reader() {
         let mut buf = Vec::<u8>::new();
         {
-            let mut writer = NaluWriter::new(&mut buf, false);
+            let mut writer = BitWriter::new(&mut buf, false);
             writer.write_ue(10u32).unwrap();
             writer.write_se(-42).unwrap();
             writer.write_se(3).unwrap();
             writer.write_ue(5u32).unwrap();
         }
 
-        let mut reader = NaluReader::new(&buf);
+        let mut reader = BitReader::new(&buf, true);
 
         assert_eq!(reader.read_ue::<u32>().unwrap(), 10);
         assert_eq!(reader.read_se::<i32>().unwrap(), -42);
@@ -263,14 +307,14 @@ mod tests {
 
         let mut buf = Vec::<u8>::new();
         {
-            let mut writer = NaluWriter::new(&mut buf, false);
+            let mut writer = BitWriter::new(&mut buf, false);
             writer.write_se(30).unwrap();
             writer.write_ue(100u32).unwrap();
             writer.write_se(-402).unwrap();
             writer.write_ue(50u32).unwrap();
         }
 
-        let mut reader = NaluReader::new(&buf);
+        let mut reader = BitReader::new(&buf, true);
 
         assert_eq!(reader.read_se::<i32>().unwrap(), 30);
         assert_eq!(reader.read_ue::<u32>().unwrap(), 100);
@@ -283,14 +327,14 @@ mod tests {
         fn test(input: &[u8], bitstream: &[u8]) {
             let mut buf = Vec::<u8>::new();
             {
-                let mut writer = NaluWriter::new(&mut buf, true);
+                let mut writer = BitWriter::new(&mut buf, true);
                 for byte in input {
                     writer.write_f(8, *byte).unwrap();
                 }
             }
             assert_eq!(buf, bitstream);
             {
-                let mut reader = NaluReader::new(&buf);
+                let mut reader = BitReader::new(&buf, true);
                 for byte in input {
                     assert_eq!(*byte, reader.read_bits::<u8>(8).unwrap());
                 }
@@ -307,4 +351,59 @@ mod tests {
         test(&[0x00, 0x00, 0x00, 0x02], &[0x00, 0x00, 0x03, 0x00, 0x02]);
         test(&[0x00, 0x00, 0x00, 0x03], &[0x00, 0x00, 0x03, 0x00, 0x03]);
     }
+
+    #[test]
+    fn writer_emulation_prevention_zero_run() {
+        let input = [0u8; 7];
+        let mut buf = Vec::<u8>::new();
+        {
+            let mut writer = BitWriter::new(&mut buf, true);
+            for byte in input {
+                writer.write_f(8, byte).unwrap();
+            }
+        }
+        assert_eq!(
+            buf,
+            [0x00, 0x00, 0x03, 0x00, 0x00, 0x03, 0x00, 0x00, 0x03, 0x00]
+        );
+
+        let mut reader = BitReader::new(&buf, true);
+        for byte in input {
+            assert_eq!(byte, reader.read_bits::<u8>(8).unwrap());
+        }
+    }
+
+    #[test]
+    fn write_bytes_matches_write_f() {
+        let input: Vec<u8> = (0..4096u32)
+            .map(|i| match i % 13 {
+                0..=5 => 0x00,
+                6 => (i % 4) as u8,
+                _ => (i.wrapping_mul(2654435761) >> 24) as u8,
+            })
+            .collect();
+
+        for ep_enabled in [false, true] {
+            for prefix_bits in [0, 3, 8] {
+                let mut expected = Vec::<u8>::new();
+                {
+                    let mut writer = BitWriter::new(&mut expected, ep_enabled);
+                    writer.write_f(prefix_bits, 0u32).unwrap();
+                    for byte in &input {
+                        writer.write_f(8, *byte).unwrap();
+                    }
+                }
+
+                let mut buf = Vec::<u8>::new();
+                {
+                    let mut writer = BitWriter::new(&mut buf, ep_enabled);
+                    writer.write_f(prefix_bits, 0u32).unwrap();
+                    writer.write_bytes(&input[..1000]).unwrap();
+                    writer.write_bytes(&input[1000..]).unwrap();
+                }
+
+                assert_eq!(buf, expected);
+            }
+        }
+    }
 }
@@ -0,0 +1,281 @@
+// Copyright 2024 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Entry points for running the parsers of this module on untrusted input.
+//!
+//! Each [`FuzzTarget`] drives one parser the way a decoder would, parsing
+//! every unit of the input it can make sense of and moving on when a unit
+//! fails to parse. Errors are expected, but no input must ever cause a panic
+//! or an endless loop.
+//!
+//! With the `arbitrary` feature, [`FuzzInput`] implements
+//! `arbitrary::Arbitrary` so all parsers can be fuzzed from a single
+//! `cargo fuzz` target, see the `fuzz` directory at the root of the
+//! repository.
+
+use std::io::Cursor;
+
+use crate::codec::av1::parser as av1;
+use crate::codec::h264::parser as h264;
+use crate::codec::h265::parser as h265;
+use crate::codec::vp8::parser as vp8;
+use crate::codec::vp9::parser as vp9;
+
+/// A parser that can be run on arbitrary data.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub enum FuzzTarget {
+    /// H.264 Annex B stream.
+    H264,
+    /// H.265 Annex B stream.
+    H265,
+    /// Single VP8 frame.
+    Vp8,
+    /// Single VP9 frame or superframe.
+    Vp9,
+    /// AV1 temporal unit, in low-overhead or Annex B format.
+    Av1,
+}
+
+impl FuzzTarget {
+    pub const ALL: [FuzzTarget; 5] = [
+        FuzzTarget::H264,
+        FuzzTarget::H265,
+        FuzzTarget::Vp8,
+        FuzzTarget::Vp9,
+        FuzzTarget::Av1,
+    ];
+
+    /// Parses `data` with the parser of this target, and returns the number
+    /// of units (NAL units, frames or OBUs) that were parsed successfully.
+    pub fn parse(self, data: &[u8]) -> usize {
+        match self {
+            FuzzTarget::H264 => parse_h264(data),
+            FuzzTarget::H265 => parse_h265(data),
+            FuzzTarget::Vp8 => usize::from(vp8::Parser::default().parse_frame(data).is_ok()),
+            FuzzTarget::Vp9 => vp9::Parser::default()
+                .parse_chunk(data)
+                .map_or(0, |frames| frames.len()),
+            FuzzTarget::Av1 => parse_av1(data),
+        }
+    }
+}
+
+/// Input of a fuzzing iteration.
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
+pub struct FuzzInput {
+    pub target: FuzzTarget,
+    pub data: Vec<u8>,
+}
+
+impl FuzzInput {
+    /// Runs the parser of `target` on `data`. See [`FuzzTarget::parse`].
+    pub fn run(&self) -> usize {
+        self.target.parse(&self.data)
+    }
+}
+
+fn parse_h264(data: &[u8]) -> usize {
+    let mut parser = h264::Parser::default();
+    let mut cursor = Cursor::new(data);
+    let mut parsed = 0;
+
+    while let Ok(nalu) = h264::Nalu::next(&mut cursor) {
+        let res = match nalu.header.type_ {
+            h264::NaluType::Sps => parser.parse_sps(&nalu).map(|_| ()),
+            h264::NaluType::Pps => parser.parse_pps(&nalu).map(|_| ()),
+            h264::NaluType::Sei => parser.parse_sei(&nalu).map(|_| ()),
+            h264::NaluType::Slice | h264::NaluType::SliceIdr => {
+                parser.parse_slice_header(nalu).map(|slice| {
+                    // Only some slices have a macroblock layer we can parse.
+                    let _ = parser.parse_slice_data(&slice);
+                })
+            }
+            _ => continue,
+        };
+        parsed += usize::from(res.is_ok());
+    }
+
+    parsed
+}
+
+fn parse_h265(data: &[u8]) -> usize {
+    let mut parser = h265::Parser::default();
+    let mut cursor = Cursor::new(data);
+    let mut parsed = 0;
+
+    while let Ok(nalu) = h265::Nalu::next(&mut cursor) {
+        let res = match nalu.header.type_ {
+            h265::NaluType::VpsNut => parser.parse_vps(&nalu).map(|_| ()),
+            h265::NaluType::SpsNut => parser.parse_sps(&nalu).map(|_| ()),
+            h265::NaluType::PpsNut => parser.parse_pps(&nalu).map(|_| ()),
+            h265::NaluType::PrefixSeiNut | h265::NaluType::SuffixSeiNut => {
+                parser.parse_sei(&nalu).map(|_| ())
+            }
+            type_ if type_ as u32 <= h265::NaluType::CraNut as u32 => {
+                parser.parse_slice_header(nalu).map(|_| ())
+            }
+            _ => continue,
+        };
+        parsed += usize::from(res.is_ok());
+    }
+
+    parsed
+}
+
+fn parse_av1(data: &[u8]) -> usize {
+    parse_av1_obus(&mut av1::Parser::default(), data)
+}
+
+/// Parses all the OBUs of `data` with `parser`, and returns the number of OBUs that were parsed
+/// successfully. The state of `parser` is kept, so consecutive temporal units of a stream can be
+/// parsed by calling this function on each of them.
+pub fn parse_av1_obus(parser: &mut av1::Parser, data: &[u8]) -> usize {
+    let mut consumed = 0;
+    let mut parsed = 0;
+
+    while consumed < data.len() {
+        let obu = match parser.parse_obu(&data[consumed..]) {
+            Ok(av1::ParsedObu::Process(obu)) => obu,
+            Ok(av1::ParsedObu::Drop(0)) | Err(_) => break,
+            Ok(av1::ParsedObu::Drop(length)) => {
+                consumed += length as usize;
+                continue;
+            }
+        };
+
+        // Always make progress, even on an empty OBU.
+        consumed += std::cmp::max(obu.data.len(), 1);
+
+        let res = match obu.header.obu_type {
+            av1::ObuType::SequenceHeader => parser.parse_sequence_header_obu(&obu).map(|_| ()),
+            av1::ObuType::TemporalDelimiter => parser.parse_temporal_delimiter_obu(&obu),
+            av1::ObuType::FrameHeader | av1::ObuType::RedundantFrameHeader => {
+                parser.parse_frame_header_obu(&obu).map(|_| ())
+            }
+            av1::ObuType::Metadata => parser.parse_metadata_obu(&obu).map(|_| ()),
+            av1::ObuType::Frame => parser.parse_frame_obu(obu).map(|_| ()),
+            av1::ObuType::TileGroup => parser.parse_tile_group_obu(obu).map(|_| ()),
+            _ => continue,
+        };
+        parsed += usize::from(res.is_ok());
+    }
+
+    parsed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::IvfIterator;
+
+    const STREAM_H264: &[u8] = include_bytes!("h264/test_data/test-25fps.h264");
+    const STREAM_H265: &[u8] = include_bytes!("h265/test_data/test-25fps.h265");
+    const FRAME_VP8: &[u8] = include_bytes!("vp8/test_data/vp8-parser-test-0-intra.bin");
+    const STREAM_VP9: &[u8] = include_bytes!("vp9/test_data/test-25fps.vp9");
+    const STREAM_AV1: &[u8] = include_bytes!("av1/test_data/test-25fps.ivf.av1");
+
+    /// Number of mutated inputs to run through each parser.
+    const NUM_ITERATIONS: usize = 300;
+
+    /// Minimal xorshift generator, so the mutations are the same on every run.
+    struct Rng(u32);
+
+    impl Rng {
+        fn next(&mut self) -> u32 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 17;
+            self.0 ^= self.0 << 5;
+            self.0
+        }
+
+        fn below(&mut self, max: usize) -> usize {
+            self.next() as usize % max
+        }
+    }
+
+    /// Corrupts a few bytes of `data` and possibly truncates it.
+    fn mutate(rng: &mut Rng, data: &[u8]) -> Vec<u8> {
+        let mut data = data.to_vec();
+
+        for _ in 0..1 + rng.below(8) {
+            let pos = rng.below(data.len());
+            data[pos] = match rng.below(3) {
+                0 => data[pos] ^ (1 << rng.below(8)),
+                1 => 0x00,
+                _ => rng.next() as u8,
+            };
+        }
+
+        if rng.below(4) == 0 {
+            data.truncate(rng.below(data.len()));
+        }
+
+        data
+    }
+
+    fn seed(target: FuzzTarget) -> Vec<u8> {
+        match target {
+            // The parameter sets and first slices are enough to exercise the parsers.
+            FuzzTarget::H264 => STREAM_H264[..4096].to_vec(),
+            FuzzTarget::H265 => STREAM_H265[..4096].to_vec(),
+            FuzzTarget::Vp8 => FRAME_VP8.to_vec(),
+            FuzzTarget::Vp9 => IvfIterator::new(STREAM_VP9).next().unwrap().to_vec(),
+            FuzzTarget::Av1 => IvfIterator::new(STREAM_AV1).next().unwrap().to_vec(),
+        }
+    }
+
+    #[test]
+    fn mutated_streams() {
+        let mut rng = Rng(0x1234_5678);
+
+        for target in FuzzTarget::ALL {
+            let seed = seed(target);
+            assert!(
+                target.parse(&seed) > 0,
+                "{:?} failed on a valid input",
+                target
+            );
+
+            for _ in 0..NUM_ITERATIONS {
+                let input = FuzzInput {
+                    target,
+                    data: mutate(&mut rng, &seed),
+                };
+                input.run();
+            }
+
+            // Degenerate inputs.
+            for data in [&[][..], &[0x00], &[0x00, 0x00, 0x01], &[0xff; 64]] {
+                target.parse(data);
+            }
+        }
+    }
+
+    #[test]
+    fn av1_tile_group_without_frame_header() {
+        // A sequence header followed by a tile group OBU, without any frame header to give the
+        // tile layout.
+        let mut data = IvfIterator::new(STREAM_AV1).next().unwrap().to_vec();
+        let mut parser = av1::Parser::default();
+        let obu = match parser.parse_obu(&data).unwrap() {
+            av1::ParsedObu::Process(obu) => obu,
+            av1::ParsedObu::Drop(_) => panic!("first OBU dropped"),
+        };
+        assert_eq!(obu.header.obu_type, av1::ObuType::TemporalDelimiter);
+        let start = obu.data.len();
+        let obu = match parser.parse_obu(&data[start..]).unwrap() {
+            av1::ParsedObu::Process(obu) => obu,
+            av1::ParsedObu::Drop(_) => panic!("sequence header dropped"),
+        };
+        assert_eq!(obu.header.obu_type, av1::ObuType::SequenceHeader);
+        let end = start + obu.data.len();
+        data.truncate(end);
+        // obu_type 4 (OBU_TILE_GROUP) with obu_has_size_field, and a 2 bytes payload.
+        data.extend_from_slice(&[0x22, 0x02, 0x00, 0x00]);
+
+        parse_av1(&data);
+    }
+}
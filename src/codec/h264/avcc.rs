@@ -0,0 +1,281 @@
+// Copyright 2024 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Support for the `avcC` box of ISO/IEC 14496-15, which stores the parameter
+//! sets of length-prefixed H.264 streams, e.g. in MP4 files.
+
+use std::io::Cursor;
+use std::io::Write;
+
+use anyhow::anyhow;
+use bytes::Buf;
+
+use crate::codec::h264::nalu::split_annexb;
+use crate::codec::h264::parser::Nalu;
+use crate::codec::h264::parser::Parser;
+
+const NALU_TYPE_SPS: u8 = 7;
+const NALU_TYPE_PPS: u8 = 8;
+const NALU_TYPE_SPS_EXT: u8 = 13;
+
+/// Profiles for which the chroma format and bit depth are present in the
+/// record.
+fn has_chroma_info(profile_indication: u8) -> bool {
+    matches!(profile_indication, 100 | 110 | 122 | 144)
+}
+
+/// AVCDecoderConfigurationRecord, as per ISO/IEC 14496-15 5.3.3.1.
+///
+/// The parameter set NAL units are stored without start code nor length
+/// prefix.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct AvcDecoderConfigurationRecord {
+    pub configuration_version: u8,
+    pub avc_profile_indication: u8,
+    pub profile_compatibility: u8,
+    pub avc_level_indication: u8,
+    /// Size in bytes of the NAL unit length prefix, minus one.
+    pub length_size_minus_one: u8,
+    pub sps: Vec<Vec<u8>>,
+    pub pps: Vec<Vec<u8>>,
+    /// Only present for the High profiles.
+    pub chroma_format: u8,
+    pub bit_depth_luma_minus8: u8,
+    pub bit_depth_chroma_minus8: u8,
+    pub sps_ext: Vec<Vec<u8>>,
+}
+
+impl AvcDecoderConfigurationRecord {
+    /// Builds a record from the SPS and PPS NAL units of a stream, which is
+    /// to use a NAL unit length prefix of `length_size` bytes.
+    ///
+    /// The profile, level and chroma information are taken from the first
+    /// SPS.
+    pub fn from_parameter_sets(
+        sps: Vec<Vec<u8>>,
+        pps: Vec<Vec<u8>>,
+        length_size: usize,
+    ) -> anyhow::Result<Self> {
+        if !matches!(length_size, 1 | 2 | 4) {
+            return Err(anyhow!("Invalid NALU length size {}", length_size));
+        }
+
+        let first_sps = sps.first().ok_or(anyhow!("No SPS"))?;
+        if first_sps.len() < 4 {
+            return Err(anyhow!("SPS is too short"));
+        }
+
+        let annexb = [&[0x00, 0x00, 0x01], &first_sps[..]].concat();
+        let nalu = Nalu::next(&mut Cursor::new(&annexb[..]))?;
+        let mut parser = Parser::default();
+        let parsed_sps = parser.parse_sps(&nalu)?;
+
+        let mut record = Self {
+            configuration_version: 1,
+            // profile_idc, the constraint flags and level_idc directly follow
+            // the NAL unit header.
+            avc_profile_indication: first_sps[1],
+            profile_compatibility: first_sps[2],
+            avc_level_indication: first_sps[3],
+            length_size_minus_one: (length_size - 1) as u8,
+            sps,
+            pps,
+            ..Default::default()
+        };
+
+        if has_chroma_info(record.avc_profile_indication) {
+            record.chroma_format = parsed_sps.chroma_format_idc;
+            record.bit_depth_luma_minus8 = parsed_sps.bit_depth_luma_minus8;
+            record.bit_depth_chroma_minus8 = parsed_sps.bit_depth_chroma_minus8;
+        }
+
+        Ok(record)
+    }
+
+    /// Builds a record from the parameter sets found in the Annex B `data`,
+    /// e.g. the first output of an encoder. Repeated parameter sets are only
+    /// included once.
+    pub fn from_annexb(data: &[u8], length_size: usize) -> anyhow::Result<Self> {
+        let mut sps = vec![];
+        let mut pps = vec![];
+        let mut sps_ext = vec![];
+
+        for nalu in split_annexb(data) {
+            let nalus = match nalu.first().map(|b| b & 0x1f) {
+                Some(NALU_TYPE_SPS) => &mut sps,
+                Some(NALU_TYPE_PPS) => &mut pps,
+                Some(NALU_TYPE_SPS_EXT) => &mut sps_ext,
+                _ => continue,
+            };
+
+            if !nalus.contains(&nalu) {
+                nalus.push(nalu);
+            }
+        }
+
+        let mut record = Self::from_parameter_sets(sps, pps, length_size)?;
+        record.sps_ext = sps_ext;
+
+        Ok(record)
+    }
+
+    /// Size in bytes of the NAL unit length prefix.
+    pub fn nal_length_size(&self) -> usize {
+        usize::from(self.length_size_minus_one) + 1
+    }
+
+    fn read_nalus(cursor: &mut Cursor<&[u8]>, count: usize) -> anyhow::Result<Vec<Vec<u8>>> {
+        let mut nalus = Vec::with_capacity(count);
+
+        for _ in 0..count {
+            if cursor.remaining() < 2 {
+                return Err(anyhow!("Truncated avcC record"));
+            }
+            let len = usize::from(cursor.get_u16());
+            if cursor.remaining() < len {
+                return Err(anyhow!("Truncated avcC record"));
+            }
+            nalus.push(cursor.chunk()[..len].to_vec());
+            cursor.advance(len);
+        }
+
+        Ok(nalus)
+    }
+
+    /// Parses the contents of an `avcC` box.
+    pub fn parse(data: &[u8]) -> anyhow::Result<Self> {
+        let mut cursor = Cursor::new(data);
+        if cursor.remaining() < 6 {
+            return Err(anyhow!("Truncated avcC record"));
+        }
+
+        let configuration_version = cursor.get_u8();
+        if configuration_version != 1 {
+            return Err(anyhow!(
+                "Unsupported avcC version {}",
+                configuration_version
+            ));
+        }
+
+        let avc_profile_indication = cursor.get_u8();
+        let profile_compatibility = cursor.get_u8();
+        let avc_level_indication = cursor.get_u8();
+        let length_size_minus_one = cursor.get_u8() & 0x3;
+        let num_sps = usize::from(cursor.get_u8() & 0x1f);
+        let sps = Self::read_nalus(&mut cursor, num_sps)?;
+
+        if !cursor.has_remaining() {
+            return Err(anyhow!("Truncated avcC record"));
+        }
+        let num_pps = usize::from(cursor.get_u8());
+        let pps = Self::read_nalus(&mut cursor, num_pps)?;
+
+        let mut record = Self {
+            configuration_version,
+            avc_profile_indication,
+            profile_compatibility,
+            avc_level_indication,
+            length_size_minus_one,
+            sps,
+            pps,
+            ..Default::default()
+        };
+
+        // Some writers omit the extension even for the High profiles, so only
+        // parse it if it is there.
+        if has_chroma_info(avc_profile_indication) && cursor.remaining() >= 4 {
+            record.chroma_format = cursor.get_u8() & 0x3;
+            record.bit_depth_luma_minus8 = cursor.get_u8() & 0x7;
+            record.bit_depth_chroma_minus8 = cursor.get_u8() & 0x7;
+            let num_sps_ext = usize::from(cursor.get_u8());
+            record.sps_ext = Self::read_nalus(&mut cursor, num_sps_ext)?;
+        }
+
+        Ok(record)
+    }
+
+    fn write_nalus(writer: &mut impl Write, nalus: &[Vec<u8>]) -> anyhow::Result<()> {
+        for nalu in nalus {
+            let len = u16::try_from(nalu.len())?;
+            writer.write_all(&len.to_be_bytes())?;
+            writer.write_all(nalu)?;
+        }
+
+        Ok(())
+    }
+
+    /// Writes the contents of an `avcC` box into `writer`.
+    pub fn write_into(&self, writer: &mut impl Write) -> anyhow::Result<()> {
+        if self.sps.len() > 0x1f || self.pps.len() > 0xff || self.sps_ext.len() > 0xff {
+            return Err(anyhow!("Too many parameter sets"));
+        }
+
+        writer.write_all(&[
+            self.configuration_version,
+            self.avc_profile_indication,
+            self.profile_compatibility,
+            self.avc_level_indication,
+            0xfc | (self.length_size_minus_one & 0x3),
+            0xe0 | self.sps.len() as u8,
+        ])?;
+        Self::write_nalus(writer, &self.sps)?;
+        writer.write_all(&[self.pps.len() as u8])?;
+        Self::write_nalus(writer, &self.pps)?;
+
+        if has_chroma_info(self.avc_profile_indication) {
+            writer.write_all(&[
+                0xfc | (self.chroma_format & 0x3),
+                0xf8 | (self.bit_depth_luma_minus8 & 0x7),
+                0xf8 | (self.bit_depth_chroma_minus8 & 0x7),
+                self.sps_ext.len() as u8,
+            ])?;
+            Self::write_nalus(writer, &self.sps_ext)?;
+        }
+
+        Ok(())
+    }
+
+    /// Returns the parameter sets of the record as an Annex B stream, which
+    /// can be fed to a decoder before the rest of the stream.
+    pub fn to_annexb(&self) -> Vec<u8> {
+        let mut output = vec![];
+
+        for nalu in self.sps.iter().chain(&self.sps_ext).chain(&self.pps) {
+            output.extend_from_slice(&[0x00, 0x00, 0x00, 0x01]);
+            output.extend_from_slice(nalu);
+        }
+
+        output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STREAM: &[u8] = include_bytes!("test_data/test-25fps.h264");
+    const STREAM_HIGH: &[u8] = include_bytes!("test_data/64x64-I-P-B-P-high.h264");
+
+    #[test]
+    fn avcc_round_trip() {
+        for (stream, profile, chroma_format) in [(STREAM, 77, 0), (STREAM_HIGH, 100, 1)] {
+            let record = AvcDecoderConfigurationRecord::from_annexb(stream, 4).unwrap();
+            assert_eq!(record.avc_profile_indication, profile);
+            assert_eq!(record.nal_length_size(), 4);
+            assert_eq!(record.sps.len(), 1);
+            assert_eq!(record.pps.len(), 1);
+            assert_eq!(record.chroma_format, chroma_format);
+
+            let mut avcc = vec![];
+            record.write_into(&mut avcc).unwrap();
+            assert_eq!(AvcDecoderConfigurationRecord::parse(&avcc).unwrap(), record);
+
+            let parameter_sets = record.to_annexb();
+            assert_eq!(
+                split_annexb(&parameter_sets),
+                [&record.sps[..], &record.pps[..]].concat()
+            );
+        }
+    }
+}
@@ -18,6 +18,7 @@ use crate::codec::h264::picture::Field;
 use crate::codec::h264::picture::IsIdr;
 use crate::codec::h264::picture::PictureData;
 use crate::codec::h264::picture::Reference;
+use crate::instrument::trace_event;
 
 pub type DpbPicRefList<'a, H> = Vec<&'a DpbEntry<H>>;
 
@@ -326,7 +327,9 @@ impl<T: Clone> Dpb<T> {
                 .pictures()
                 .filter(|pic| {
                     !pic.is_second_field()
-                        && (matches!(pic.field, Field::Frame) || pic.other_field().is_some())
+                        && (matches!(pic.field, Field::Frame)
+                            || pic.other_field().is_some()
+                            || pic.non_paired)
                 })
                 .count();
 
@@ -367,7 +370,7 @@ impl<T: Clone> Dpb<T> {
         }
 
         let lowest_poc = match self.find_lowest_poc_for_bumping() {
-            Some(handle) => handle.pic.borrow().pic_order_cnt,
+            Some(handle) => handle.pic.borrow().frame_pic_order_cnt(),
             None => return false,
         };
 
@@ -389,13 +392,14 @@ impl<T: Clone> Dpb<T> {
                 match pic.field {
                     // Progressive frames in the DPB are fully decoded.
                     Field::Frame => true,
-                    // Only return the first field of fully decoded interlaced frames.
+                    // Only return the first field of fully decoded interlaced frames, or
+                    // non-paired fields.
                     Field::Top | Field::Bottom => {
-                        !pic.is_second_field() && pic.other_field().is_some()
+                        !pic.is_second_field() && (pic.other_field().is_some() || pic.non_paired)
                     }
                 }
             })
-            .min_by_key(|handle| handle.pic.borrow().pic_order_cnt)
+            .min_by_key(|handle| handle.pic.borrow().frame_pic_order_cnt())
     }
 
     /// Gets the position of `needle` in the DPB, if any.
@@ -574,7 +578,7 @@ impl<T: Clone> Dpb<T> {
             pic.pic_num - (i32::try_from(marking.difference_of_pic_nums_minus1).unwrap() + 1);
 
         log::debug!("MMCO op 1 for pic_num_x {}", pic_num_x);
-        log::trace!("Dpb state before MMCO=1: {:#?}", self);
+        trace_event!("Dpb state before MMCO=1: {:#?}", self);
 
         let to_mark = self
             .find_short_term_with_pic_num(pic_num_x)
@@ -598,7 +602,7 @@ impl<T: Clone> Dpb<T> {
             marking.long_term_pic_num
         );
 
-        log::trace!("Dpb state before MMCO=2: {:#?}", self);
+        trace_event!("Dpb state before MMCO=2: {:#?}", self);
 
         let to_mark = self
             .find_long_term_with_long_term_pic_num(marking.long_term_pic_num)
@@ -621,7 +625,7 @@ impl<T: Clone> Dpb<T> {
             pic.pic_num - (i32::try_from(marking.difference_of_pic_nums_minus1).unwrap() + 1);
 
         log::debug!("MMCO op 3 for pic_num_x {}", pic_num_x);
-        log::trace!("Dpb state before MMCO=3: {:#?}", self);
+        trace_event!("Dpb state before MMCO=3: {:#?}", self);
 
         let to_mark_as_long_pos = self
             .find_short_term_with_pic_num_pos(pic_num_x)
@@ -727,7 +731,7 @@ impl<T: Clone> Dpb<T> {
             marking.max_long_term_frame_idx
         );
 
-        log::trace!("Dpb state before MMCO=4: {:#?}", self);
+        trace_event!("Dpb state before MMCO=4: {:#?}", self);
 
         for mut dpb_pic in self
             .pictures_mut()
@@ -743,7 +747,7 @@ impl<T: Clone> Dpb<T> {
     /// Returns the new `max_long_term_frame_idx`.
     pub fn mmco_op_5(&mut self, pic: &mut PictureData) -> MaxLongTermFrameIdx {
         log::debug!("MMCO op 5, marking all pictures in the DPB as unused for reference");
-        log::trace!("Dpb state before MMCO=5: {:#?}", self);
+        trace_event!("Dpb state before MMCO=5: {:#?}", self);
 
         self.mark_all_as_unused_for_ref();
 
@@ -788,7 +792,7 @@ impl<T: Clone> Dpb<T> {
         let long_term_frame_idx = marking.long_term_frame_idx;
 
         log::debug!("MMCO op 6, long_term_frame_idx: {}", long_term_frame_idx);
-        log::trace!("Dpb state before MMCO=6: {:#?}", self);
+        trace_event!("Dpb state before MMCO=6: {:#?}", self);
 
         for mut dpb_pic in self.pictures_mut() {
             // When a variable LongTermFrameIdx equal to long_term_frame_idx is
@@ -989,7 +993,7 @@ impl<T: Clone> Dpb<T> {
 
     /// 8.2.4.2.1 Initialization process for the reference picture list for P
     /// and SP slices in frames
-    fn build_ref_pic_list_p(&self) -> DpbPicRefList<T> {
+    fn build_ref_pic_list_p(&self) -> DpbPicRefList<'_, T> {
         let mut ref_pic_list_p0: Vec<_> = self
             .short_term_refs_iter()
             .filter(|h| !h.pic.borrow().is_second_field())
@@ -1012,7 +1016,7 @@ impl<T: Clone> Dpb<T> {
 
     /// 8.2.4.2.2 Initialization process for the reference picture list for P
     /// and SP slices in fields
-    fn build_ref_field_pic_list_p(&self, cur_pic: &PictureData) -> DpbPicRefList<T> {
+    fn build_ref_field_pic_list_p(&self, cur_pic: &PictureData) -> DpbPicRefList<'_, T> {
         let mut ref_pic_list_p0 = vec![];
 
         let mut ref_frame_list_0_short_term: Vec<_> = self.short_term_refs_iter().collect();
@@ -1043,7 +1047,10 @@ impl<T: Clone> Dpb<T> {
 
     // 8.2.4.2.3 Initialization process for reference picture lists for B slices
     // in frames
-    fn build_ref_pic_list_b(&self, cur_pic: &PictureData) -> (DpbPicRefList<T>, DpbPicRefList<T>) {
+    fn build_ref_pic_list_b(
+        &self,
+        cur_pic: &PictureData,
+    ) -> (DpbPicRefList<'_, T>, DpbPicRefList<'_, T>) {
         let mut short_term_refs: Vec<_> = self
             .short_term_refs_iter()
             .filter(|h| !h.pic.borrow().is_second_field())
@@ -1125,7 +1132,7 @@ impl<T: Clone> Dpb<T> {
     fn build_ref_field_pic_list_b(
         &self,
         cur_pic: &PictureData,
-    ) -> (DpbPicRefList<T>, DpbPicRefList<T>) {
+    ) -> (DpbPicRefList<'_, T>, DpbPicRefList<'_, T>) {
         let mut ref_pic_list_b0 = vec![];
         let mut ref_pic_list_b1 = vec![];
         let mut ref_frame_list_0_short_term = vec![];
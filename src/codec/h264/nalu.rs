@@ -86,6 +86,55 @@ where
             sc_offset: start_code_offset,
         })
     }
+
+    /// Read the next NAL unit of a length-prefixed (e.g. AVCC or HVCC)
+    /// stream, where each unit is preceded by its size in `length_size`
+    /// big-endian bytes instead of a start code.
+    pub fn next_length_prefixed(
+        cursor: &mut Cursor<&'a [u8]>,
+        length_size: usize,
+    ) -> anyhow::Result<Nalu<'a, U>> {
+        if !(1..=4).contains(&length_size) {
+            return Err(anyhow!("Invalid NALU length size {}", length_size));
+        }
+
+        let bitstream = cursor.clone().into_inner();
+        let length_offset = usize::try_from(cursor.position())?;
+        if cursor.remaining() < length_size {
+            return Err(anyhow!("No NAL found"));
+        }
+
+        let nal_size = usize::try_from(cursor.get_uint(length_size))?;
+        let nalu_offset = length_offset + length_size;
+        if nal_size == 0 {
+            return Err(anyhow!("Empty NALU"));
+        }
+        if cursor.remaining() < nal_size {
+            return Err(anyhow!(
+                "NALU of {} bytes exceeds the remaining {} bytes of data",
+                nal_size,
+                cursor.remaining()
+            ));
+        }
+
+        let hdr = U::parse(cursor)?;
+        if nal_size < hdr.len() {
+            return Err(anyhow!(
+                "NALU of {} bytes is smaller than its {} bytes header",
+                nal_size,
+                hdr.len()
+            ));
+        }
+        cursor.set_position(u64::try_from(nalu_offset + nal_size)?);
+
+        Ok(Nalu {
+            header: hdr,
+            data: bitstream,
+            size: nal_size,
+            offset: nalu_offset,
+            sc_offset: length_offset,
+        })
+    }
 }
 
 impl<'a, U> Nalu<'a, U>
@@ -105,3 +154,340 @@ impl<'a, U> AsRef<[u8]> for Nalu<'a, U> {
         &self.data[self.offset..self.offset + self.size]
     }
 }
+
+/// Incremental Annex B parser, for bitstreams that are received in arbitrary
+/// chunks (e.g. from a socket) rather than as a complete buffer.
+///
+/// Data is added with [`NaluStreamParser::push`], and complete NAL units,
+/// including their start code, are returned by
+/// [`NaluStreamParser::next_nalu`] once the start code of the following unit
+/// has been received. Since the end of the last unit cannot be detected that
+/// way, it must be retrieved with [`NaluStreamParser::flush`] once the stream
+/// is over.
+///
+/// This works for both H.264 and H.265, which share the same byte stream
+/// format.
+#[derive(Debug)]
+pub struct NaluStreamParser {
+    /// Data received but not yet returned.
+    buffer: Vec<u8>,
+    /// Position in `buffer` from which to look for the start code ending the
+    /// current NAL unit, so data is only scanned once.
+    search_pos: usize,
+    /// Maximum number of bytes held in `buffer`.
+    max_pending: usize,
+}
+
+impl Default for NaluStreamParser {
+    fn default() -> Self {
+        Self::with_max_pending(Self::DEFAULT_MAX_PENDING)
+    }
+}
+
+impl NaluStreamParser {
+    /// Default maximum number of bytes received but not returned yet.
+    pub const DEFAULT_MAX_PENDING: usize = 32 << 20;
+
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Creates a parser holding at most `max_pending` bytes received but not
+    /// returned yet, which also bounds the size of the NAL units it returns.
+    pub fn with_max_pending(max_pending: usize) -> Self {
+        Self {
+            buffer: Default::default(),
+            search_pos: 0,
+            max_pending,
+        }
+    }
+
+    /// Appends `data` to the stream. Fails without appending anything if the
+    /// data pending would then exceed the maximum of the parser.
+    pub fn push(&mut self, data: &[u8]) -> anyhow::Result<()> {
+        if self.buffer.len() + data.len() > self.max_pending {
+            return Err(anyhow!(
+                "{} bytes pending exceed the maximum of {}",
+                self.buffer.len() + data.len(),
+                self.max_pending
+            ));
+        }
+
+        self.buffer.extend_from_slice(data);
+        Ok(())
+    }
+
+    /// Number of bytes received but not returned yet.
+    pub fn pending(&self) -> usize {
+        self.buffer.len()
+    }
+
+    fn find_start_code(data: &[u8]) -> Option<usize> {
+        data.windows(3)
+            .position(|window| window == [0x00, 0x00, 0x01])
+    }
+
+    /// Returns the next complete NAL unit, start code included, or `None` if
+    /// more data is needed.
+    pub fn next_nalu(&mut self) -> Option<Vec<u8>> {
+        let Some(start) = Self::find_start_code(&self.buffer) else {
+            // Drop the garbage before the first start code, but keep the last
+            // three bytes which may be the beginning of a split 4 bytes start
+            // code.
+            let garbage = self.buffer.len().saturating_sub(3);
+            self.buffer.drain(..garbage);
+            self.search_pos = 0;
+            return None;
+        };
+
+        // Keep a 4 bytes start code as a whole, but drop any other leading
+        // zero.
+        let sc_start = start.saturating_sub(usize::from(start > 0 && self.buffer[start - 1] == 0));
+        if sc_start > 0 {
+            self.buffer.drain(..sc_start);
+            self.search_pos = self.search_pos.saturating_sub(sc_start);
+        }
+        let nalu_start = start - sc_start + 3;
+
+        let search_pos = std::cmp::max(self.search_pos, nalu_start);
+        let Some(next) = Self::find_start_code(&self.buffer[search_pos..]) else {
+            // The next start code may begin in the last two bytes.
+            self.search_pos = std::cmp::max(nalu_start, self.buffer.len().saturating_sub(2));
+            return None;
+        };
+        let next = search_pos + next;
+
+        // Discard trailing_zero_8bits, as well as the zero_byte of a 4 bytes
+        // start code, which will be returned with the next unit.
+        let mut end = next;
+        while end > nalu_start && self.buffer[end - 1] == 0 {
+            end -= 1;
+        }
+
+        let nalu = self.buffer[..end].to_vec();
+        self.buffer.drain(..end);
+        self.search_pos = 0;
+
+        Some(nalu)
+    }
+
+    /// Signals the end of the stream, returning the last NAL unit if there is
+    /// one. The parser can be reused for a new stream afterwards.
+    pub fn flush(&mut self) -> Option<Vec<u8>> {
+        let nalu = self.next_nalu();
+        if nalu.is_some() {
+            return nalu;
+        }
+
+        let buffer = std::mem::take(&mut self.buffer);
+        self.search_pos = 0;
+
+        let start = Self::find_start_code(&buffer)?;
+        let sc_start = start.saturating_sub(usize::from(start > 0 && buffer[start - 1] == 0));
+        let mut end = buffer.len();
+        while end > start + 3 && buffer[end - 1] == 0 {
+            end -= 1;
+        }
+
+        Some(buffer[sc_start..end].to_vec())
+    }
+}
+
+/// Splits the Annex B `data` into its NAL units, without their start code.
+fn annexb_nalus(data: &[u8]) -> impl Iterator<Item = Vec<u8>> {
+    let mut parser = NaluStreamParser {
+        buffer: data.to_vec(),
+        search_pos: 0,
+        max_pending: data.len(),
+    };
+
+    let mut flushed = false;
+    std::iter::from_fn(move || match parser.next_nalu() {
+        Some(nalu) => Some(nalu),
+        None if !flushed => {
+            flushed = true;
+            parser.flush()
+        }
+        None => None,
+    })
+    .map(|nalu| {
+        let start = nalu
+            .windows(3)
+            .position(|window| window == [0x00, 0x00, 0x01])
+            .unwrap_or(0)
+            + 3;
+        nalu[start..].to_vec()
+    })
+}
+
+/// Returns the NAL units of the Annex B `data`, without their start code.
+pub fn split_annexb(data: &[u8]) -> Vec<Vec<u8>> {
+    annexb_nalus(data).collect()
+}
+
+/// Converts the Annex B `data` into a length-prefixed stream, with each NAL
+/// unit preceded by its size in `length_size` big-endian bytes.
+pub fn annexb_to_length_prefixed(data: &[u8], length_size: usize) -> anyhow::Result<Vec<u8>> {
+    if !(1..=4).contains(&length_size) {
+        return Err(anyhow!("Invalid NALU length size {}", length_size));
+    }
+
+    let mut output = Vec::with_capacity(data.len());
+    for nalu in annexb_nalus(data) {
+        let len = u64::try_from(nalu.len())?;
+        if len >> (length_size * 8) != 0 {
+            return Err(anyhow!(
+                "NALU of {} bytes cannot be prefixed by a {} bytes length",
+                len,
+                length_size
+            ));
+        }
+
+        output.extend_from_slice(&len.to_be_bytes()[8 - length_size..]);
+        output.extend_from_slice(&nalu);
+    }
+
+    Ok(output)
+}
+
+/// Converts the length-prefixed `data`, with each NAL unit preceded by its
+/// size in `length_size` big-endian bytes, into an Annex B stream.
+pub fn length_prefixed_to_annexb(data: &[u8], length_size: usize) -> anyhow::Result<Vec<u8>> {
+    if !(1..=4).contains(&length_size) {
+        return Err(anyhow!("Invalid NALU length size {}", length_size));
+    }
+
+    let mut cursor = Cursor::new(data);
+    let mut output = Vec::with_capacity(data.len() + data.len() / 8);
+    while cursor.has_remaining() {
+        if cursor.remaining() < length_size {
+            return Err(anyhow!("Truncated NALU length"));
+        }
+
+        let len = usize::try_from(cursor.get_uint(length_size))?;
+        if cursor.remaining() < len {
+            return Err(anyhow!(
+                "NALU of {} bytes exceeds the remaining {} bytes of data",
+                len,
+                cursor.remaining()
+            ));
+        }
+
+        output.extend_from_slice(&[0x00, 0x00, 0x00, 0x01]);
+        output.extend_from_slice(&cursor.chunk()[..len]);
+        cursor.advance(len);
+    }
+
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::codec::h264::parser::Nalu;
+    use crate::codec::h265::parser::Nalu as H265Nalu;
+    use crate::utils::NalIterator;
+
+    const STREAM: &[u8] = include_bytes!("test_data/test-25fps.h264");
+
+    #[test]
+    fn stream_parser_matches_nal_iterator() {
+        let expected = NalIterator::<Nalu>::new(STREAM).collect::<Vec<_>>();
+
+        // Chunk sizes that split start codes in all possible ways.
+        for chunk_size in [1, 2, 3, 5, 7, 64, 1000, STREAM.len()] {
+            let mut parser = NaluStreamParser::new();
+            let mut nalus = Vec::new();
+
+            for chunk in STREAM.chunks(chunk_size) {
+                parser.push(chunk).unwrap();
+                while let Some(nalu) = parser.next_nalu() {
+                    nalus.push(nalu);
+                }
+            }
+            nalus.extend(parser.flush());
+
+            assert_eq!(nalus.len(), expected.len(), "chunk size {}", chunk_size);
+            for (nalu, expected) in nalus.iter().zip(&expected) {
+                assert_eq!(nalu, expected, "chunk size {}", chunk_size);
+            }
+            assert_eq!(parser.pending(), 0);
+        }
+    }
+
+    #[test]
+    fn stream_parser_skips_garbage() {
+        let mut parser = NaluStreamParser::new();
+
+        parser.push(&[0xaa, 0xbb, 0xcc, 0x00]).unwrap();
+        assert_eq!(parser.next_nalu(), None);
+        parser.push(&[0x00, 0x01, 0x09, 0xf0, 0x00, 0x00]).unwrap();
+        assert_eq!(parser.next_nalu(), None);
+        parser.push(&[0x00, 0x01, 0x0b]).unwrap();
+        assert_eq!(parser.next_nalu(), Some(vec![0x00, 0x00, 0x01, 0x09, 0xf0]));
+        assert_eq!(parser.next_nalu(), None);
+        assert_eq!(parser.flush(), Some(vec![0x00, 0x00, 0x00, 0x01, 0x0b]));
+        assert_eq!(parser.flush(), None);
+    }
+
+    #[test]
+    fn stream_parser_max_pending() {
+        let mut parser = NaluStreamParser::with_max_pending(8);
+
+        parser.push(&[0x00, 0x00, 0x01, 0x09, 0xf0]).unwrap();
+        assert!(parser.push(&[0x00, 0x00, 0x01, 0x0b]).is_err());
+        assert_eq!(parser.pending(), 5);
+
+        // Returning the NAL units makes room for more data.
+        parser.push(&[0x00, 0x00, 0x01]).unwrap();
+        assert_eq!(parser.next_nalu(), Some(vec![0x00, 0x00, 0x01, 0x09, 0xf0]));
+        parser.push(&[0x0b]).unwrap();
+        assert_eq!(parser.flush(), Some(vec![0x00, 0x00, 0x01, 0x0b]));
+    }
+
+    #[test]
+    fn length_prefixed_round_trip() {
+        let expected = NalIterator::<Nalu>::new(STREAM).count();
+
+        for length_size in [2, 4] {
+            let avcc = annexb_to_length_prefixed(STREAM, length_size).unwrap();
+
+            let mut cursor = Cursor::new(&avcc[..]);
+            let mut num_nalus = 0;
+            while let Ok(nalu) = Nalu::next_length_prefixed(&mut cursor, length_size) {
+                assert_eq!(nalu.sc_offset + length_size, nalu.offset);
+                num_nalus += 1;
+            }
+            assert_eq!(num_nalus, expected);
+            assert_eq!(cursor.position() as usize, avcc.len());
+
+            let annexb = length_prefixed_to_annexb(&avcc, length_size).unwrap();
+            let nalus = NalIterator::<Nalu>::new(&annexb)
+                .map(|nalu| nalu.to_vec())
+                .collect::<Vec<_>>();
+            assert_eq!(nalus.len(), expected);
+            assert_eq!(split_annexb(&annexb), split_annexb(STREAM));
+        }
+
+        // Some NALUs of this stream are too large for a single byte length.
+        assert!(annexb_to_length_prefixed(STREAM, 1).is_err());
+        assert!(length_prefixed_to_annexb(&[0x00, 0x05, 0x67], 2).is_err());
+    }
+
+    #[test]
+    fn length_prefixed_invalid_sizes() {
+        // Empty NALU.
+        let mut cursor = Cursor::new(&[0x00, 0x00, 0x00, 0x01, 0x09][..]);
+        assert!(Nalu::next_length_prefixed(&mut cursor, 2).is_err());
+
+        // H.265 NALU of 1 byte, smaller than its 2 bytes header.
+        let mut cursor = Cursor::new(&[0x00, 0x01, 0x40, 0x01][..]);
+        assert!(H265Nalu::next_length_prefixed(&mut cursor, 2).is_err());
+
+        let mut cursor = Cursor::new(&[0x00, 0x02, 0x40, 0x01][..]);
+        let nalu = H265Nalu::next_length_prefixed(&mut cursor, 2).unwrap();
+        assert_eq!(nalu.size, 2);
+    }
+}
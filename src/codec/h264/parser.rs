@@ -5,7 +5,6 @@
 // Can't reasonably expect client code to consume everything that has been parsed.
 #![allow(dead_code)]
 
-use std::collections::BTreeMap;
 use std::io::Cursor;
 use std::rc::Rc;
 
@@ -14,10 +13,18 @@ use anyhow::Context;
 use bytes::Buf;
 use enumn::N;
 
+use crate::codec::common::bit_reader::BitReader;
 use crate::codec::h264::nalu;
 use crate::codec::h264::nalu::Header;
-use crate::codec::h264::nalu_reader::NaluReader;
 use crate::codec::h264::picture::Field;
+use crate::codec::h264::slice_data::SliceData;
+use crate::codec::param_sets::ChromaFormat;
+use crate::codec::param_sets::ParamSetStore;
+use crate::codec::param_sets::SequenceChange;
+use crate::codec::param_sets::SequenceFormat;
+use crate::codec::param_sets::SequenceParams;
+use crate::codec::timecode::Timecode;
+use crate::Resolution;
 
 pub type Nalu<'a> = nalu::Nalu<'a, NaluHeader>;
 
@@ -395,6 +402,11 @@ impl SliceHeaderBuilder {
         self
     }
 
+    pub fn frame_num(mut self, value: u16) -> Self {
+        self.0.frame_num = value;
+        self
+    }
+
     pub fn pic_order_cnt_lsb(mut self, value: u16) -> Self {
         self.0.pic_order_cnt_lsb = value;
         self
@@ -430,6 +442,17 @@ impl SliceHeaderBuilder {
         self.num_ref_idx_l1_active_minus1(value - 1)
     }
 
+    pub fn ref_pic_list_modification_l0(mut self, value: Vec<RefPicListModification>) -> Self {
+        self.0.ref_pic_list_modification_flag_l0 = !value.is_empty();
+        self.0.ref_pic_list_modification_l0 = value;
+        self
+    }
+
+    pub fn dec_ref_pic_marking(mut self, value: RefPicMarking) -> Self {
+        self.0.dec_ref_pic_marking = value;
+        self
+    }
+
     pub fn build(self) -> SliceHeader {
         self.0
     }
@@ -523,6 +546,96 @@ pub enum Level {
     L6_2 = 62,
 }
 
+impl Level {
+    /// Returns the maximum number of frames of `width_mb` by `height_mb` macroblocks the DPB can
+    /// hold at this level.
+    pub fn max_dpb_frames(self, width_mb: u32, height_mb: u32) -> usize {
+        // Table A.1
+        let max_dpb_mbs = match self {
+            Level::L1 => 396,
+            Level::L1B => 396,
+            Level::L1_1 => 900,
+            Level::L1_2 => 2376,
+            Level::L1_3 => 2376,
+            Level::L2_0 => 2376,
+            Level::L2_1 => 4752,
+            Level::L2_2 => 8100,
+            Level::L3 => 8100,
+            Level::L3_1 => 18000,
+            Level::L3_2 => 20480,
+            Level::L4 => 32768,
+            Level::L4_1 => 32768,
+            Level::L4_2 => 34816,
+            Level::L5 => 110400,
+            Level::L5_1 => 184320,
+            Level::L5_2 => 184320,
+            Level::L6 => 696320,
+            Level::L6_1 => 696320,
+            Level::L6_2 => 696320,
+        };
+
+        std::cmp::min(max_dpb_mbs / (width_mb * height_mb), DPB_MAX_SIZE as u32) as usize
+    }
+}
+
+/// A view of a MVC stream and its inter-view references, as signaled by the
+/// `seq_parameter_set_mvc_extension()` of its subset SPS.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct MvcView {
+    /// Identifier of the view, as found in the NAL unit headers of its view components.
+    pub view_id: u16,
+    /// Identifiers of the views used by the anchor pictures of this view in their initial
+    /// RefPicList0.
+    pub anchor_refs_l0: Vec<u16>,
+    /// Identifiers of the views used by the anchor pictures of this view in their initial
+    /// RefPicList1.
+    pub anchor_refs_l1: Vec<u16>,
+    /// Identifiers of the views used by the non-anchor pictures of this view in their initial
+    /// RefPicList0.
+    pub non_anchor_refs_l0: Vec<u16>,
+    /// Identifiers of the views used by the non-anchor pictures of this view in their initial
+    /// RefPicList1.
+    pub non_anchor_refs_l1: Vec<u16>,
+}
+
+/// An operation point of a MVC stream, that is the subset of its views needed to decode some
+/// target views.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct MvcOperationPoint {
+    /// Same as `applicable_op_temporal_id` in the specification.
+    pub temporal_id: u8,
+    /// Same as `applicable_op_target_view_id` in the specification.
+    pub target_view_ids: Vec<u16>,
+    /// Same as `applicable_op_num_views_minus1` in the specification.
+    pub num_views_minus1: u16,
+}
+
+/// A level signaled for some operation points of a MVC stream.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct MvcLevelValue {
+    /// Same as `level_idc` in the specification.
+    pub level_idc: u8,
+    /// Operation points this level applies to.
+    pub operation_points: Vec<MvcOperationPoint>,
+}
+
+/// The `seq_parameter_set_mvc_extension()` of a subset SPS. See H.7.3.2.1.4 in the
+/// specification.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SpsMvcExtension {
+    /// The views of the stream, in view order: the first one is the base view.
+    pub views: Vec<MvcView>,
+    /// The levels signaled for the operation points of the stream.
+    pub level_values: Vec<MvcLevelValue>,
+}
+
+impl SpsMvcExtension {
+    /// Returns the view order index of the view identified by `view_id`, if any.
+    pub fn view_order_idx(&self, view_id: u16) -> Option<usize> {
+        self.views.iter().position(|view| view.view_id == view_id)
+    }
+}
+
 /// A H264 Sequence Parameter Set. A syntax structure containing syntax elements
 /// that apply to zero or more entire coded video sequences as determined by the
 /// content of a seq_parameter_set_id syntax element found in the picture
@@ -714,9 +827,23 @@ pub struct Sps {
 
     pub vui_parameters_present_flag: bool,
     pub vui_parameters: VuiParams,
+
+    /// MVC extension of subset SPSs, see [`Parser::parse_subset_sps`].
+    pub mvc_extension: Option<SpsMvcExtension>,
 }
 
 impl Sps {
+    /// Kind of the scaling matrix used by the sequence.
+    pub fn scaling_matrix_kind(&self) -> ScalingMatrixKind {
+        if !self.seq_scaling_matrix_present_flag {
+            // Flat_4x4_16 and Flat_8x8_16
+            return ScalingMatrixKind::Flat;
+        }
+
+        let num_8x8 = if self.chroma_format_idc != 3 { 2 } else { 6 };
+        ScalingMatrixKind::of(&self.scaling_lists_4x4, &self.scaling_lists_8x8, num_8x8)
+    }
+
     /// Same as MaxFrameNum. See 7-10 in the specification.
     pub fn max_frame_num(&self) -> u32 {
         1 << (self.log2_max_frame_num_minus4 + 4)
@@ -775,35 +902,9 @@ impl Sps {
             level = Level::L1B;
         };
 
-        // Table A.1
-        let max_dpb_mbs = match level {
-            Level::L1 => 396,
-            Level::L1B => 396,
-            Level::L1_1 => 900,
-            Level::L1_2 => 2376,
-            Level::L1_3 => 2376,
-            Level::L2_0 => 2376,
-            Level::L2_1 => 4752,
-            Level::L2_2 => 8100,
-            Level::L3 => 8100,
-            Level::L3_1 => 18000,
-            Level::L3_2 => 20480,
-            Level::L4 => 32768,
-            Level::L4_1 => 32768,
-            Level::L4_2 => 34816,
-            Level::L5 => 110400,
-            Level::L5_1 => 184320,
-            Level::L5_2 => 184320,
-            Level::L6 => 696320,
-            Level::L6_1 => 696320,
-            Level::L6_2 => 696320,
-        };
-
         let width_mb = self.width / 16;
         let height_mb = self.height / 16;
-
-        let max_dpb_frames =
-            std::cmp::min(max_dpb_mbs / (width_mb * height_mb), DPB_MAX_SIZE as u32) as usize;
+        let max_dpb_frames = level.max_dpb_frames(width_mb, height_mb);
 
         let mut max_dpb_frames = std::cmp::max(max_dpb_frames, self.max_num_ref_frames as usize);
 
@@ -889,6 +990,28 @@ impl Default for Sps {
             expected_delta_per_pic_order_cnt_cycle: Default::default(),
             vui_parameters_present_flag: Default::default(),
             vui_parameters: Default::default(),
+            mvc_extension: Default::default(),
+        }
+    }
+}
+
+impl SequenceParams for Sps {
+    fn sequence_format(&self) -> SequenceFormat {
+        let visible_rect = self.visible_rectangle();
+
+        SequenceFormat {
+            coded_resolution: Resolution {
+                width: self.width,
+                height: self.height,
+            },
+            display_resolution: Resolution {
+                width: visible_rect.max.x.saturating_sub(visible_rect.min.x),
+                height: visible_rect.max.y.saturating_sub(visible_rect.min.y),
+            },
+            bit_depth_luma: self.bit_depth_luma_minus8 + 8,
+            bit_depth_chroma: self.bit_depth_chroma_minus8 + 8,
+            chroma_format: ChromaFormat::from_chroma_format_idc(self.chroma_format_idc)
+                .unwrap_or_default(),
         }
     }
 }
@@ -911,6 +1034,17 @@ impl SpsBuilder {
         self
     }
 
+    /// Sets `constraint_set0_flag` to `constraint_set5_flag`, in this order.
+    pub fn constraint_set_flags(mut self, flags: [bool; 6]) -> Self {
+        self.0.constraint_set0_flag = flags[0];
+        self.0.constraint_set1_flag = flags[1];
+        self.0.constraint_set2_flag = flags[2];
+        self.0.constraint_set3_flag = flags[3];
+        self.0.constraint_set4_flag = flags[4];
+        self.0.constraint_set5_flag = flags[5];
+        self
+    }
+
     pub fn level_idc(mut self, value: Level) -> Self {
         self.0.level_idc = value;
         self
@@ -974,6 +1108,11 @@ impl SpsBuilder {
         self
     }
 
+    pub fn gaps_in_frame_num_value_allowed_flag(mut self, value: bool) -> Self {
+        self.0.gaps_in_frame_num_value_allowed_flag = value;
+        self
+    }
+
     pub fn frame_mbs_only_flag(mut self, value: bool) -> Self {
         self.0.frame_mbs_only_flag = value;
         self
@@ -1066,6 +1205,83 @@ impl SpsBuilder {
         self
     }
 
+    pub fn video_signal_type(mut self, video_format: u8, video_full_range_flag: bool) -> Self {
+        self = self.vui_parameters_present();
+        self.0.vui_parameters.video_signal_type_present_flag = true;
+        self.0.vui_parameters.video_format = video_format;
+        self.0.vui_parameters.video_full_range_flag = video_full_range_flag;
+        self
+    }
+
+    pub fn colour_description(
+        mut self,
+        colour_primaries: u8,
+        transfer_characteristics: u8,
+        matrix_coefficients: u8,
+    ) -> Self {
+        if !self.0.vui_parameters.video_signal_type_present_flag {
+            // H.264 Table E-2: Unspecified video format
+            self = self.video_signal_type(5, false);
+        }
+        self.0.vui_parameters.colour_description_present_flag = true;
+        self.0.vui_parameters.colour_primaries = colour_primaries;
+        self.0.vui_parameters.transfer_characteristics = transfer_characteristics;
+        self.0.vui_parameters.matrix_coefficients = matrix_coefficients;
+        self
+    }
+
+    pub fn chroma_loc_info(mut self, top_field: u8, bottom_field: u8) -> Self {
+        self = self.vui_parameters_present();
+        self.0.vui_parameters.chroma_loc_info_present_flag = true;
+        self.0.vui_parameters.chroma_sample_loc_type_top_field = top_field;
+        self.0.vui_parameters.chroma_sample_loc_type_bottom_field = bottom_field;
+        self
+    }
+
+    pub fn nal_hrd_parameters(mut self, hrd: HrdParams) -> Self {
+        self = self.vui_parameters_present();
+        self.0.vui_parameters.nal_hrd_parameters_present_flag = true;
+        self.0.vui_parameters.nal_hrd_parameters = hrd;
+        self
+    }
+
+    pub fn vcl_hrd_parameters(mut self, hrd: HrdParams) -> Self {
+        self = self.vui_parameters_present();
+        self.0.vui_parameters.vcl_hrd_parameters_present_flag = true;
+        self.0.vui_parameters.vcl_hrd_parameters = hrd;
+        self
+    }
+
+    pub fn low_delay_hrd_flag(mut self, value: bool) -> Self {
+        self.0.vui_parameters.low_delay_hrd_flag = value;
+        self
+    }
+
+    pub fn pic_struct_present_flag(mut self, value: bool) -> Self {
+        self = self.vui_parameters_present();
+        self.0.vui_parameters.pic_struct_present_flag = value;
+        self
+    }
+
+    pub fn bitstream_restriction(
+        mut self,
+        max_num_reorder_frames: u32,
+        max_dec_frame_buffering: u32,
+    ) -> Self {
+        self = self.vui_parameters_present();
+        let vui = &mut self.0.vui_parameters;
+        vui.bitstream_restriction_flag = true;
+        // H.264 E.2.1 inferred values
+        vui.motion_vectors_over_pic_boundaries_flag = true;
+        vui.max_bytes_per_pic_denom = 2;
+        vui.max_bits_per_mb_denom = 1;
+        vui.log2_max_mv_length_horizontal = 15;
+        vui.log2_max_mv_length_vertical = 15;
+        vui.max_num_reorder_frames = max_num_reorder_frames;
+        vui.max_dec_frame_buffering = max_dec_frame_buffering;
+        self
+    }
+
     pub fn log2_max_frame_num_minus4(mut self, value: u8) -> Self {
         self.0.log2_max_frame_num_minus4 = value;
         self
@@ -1112,6 +1328,15 @@ impl SpsBuilder {
         self.bit_depth_luma_minus8(value - 8u8)
     }
 
+    /// Sets a custom scaling matrix. Lists that are zeroed are not present in
+    /// the SPS, and inferred using H.264 Table 7-2 fall-back rule A.
+    pub fn scaling_lists(mut self, lists_4x4: [[u8; 16]; 6], lists_8x8: [[u8; 64]; 6]) -> Self {
+        self.0.seq_scaling_matrix_present_flag = true;
+        self.0.scaling_lists_4x4 = lists_4x4;
+        self.0.scaling_lists_8x8 = lists_8x8;
+        self
+    }
+
     pub fn build(self) -> Rc<Sps> {
         Rc::new(self.0)
     }
@@ -1157,6 +1382,56 @@ pub struct HrdParams {
     pub time_offset_length: u8,
 }
 
+impl HrdParams {
+    /// Creates HRD parameters with a single CPB specification of `bit_rate`
+    /// bits per second and `cpb_size` bits, using the smallest scales that can
+    /// represent them. The lengths of the delay syntax elements are set to 24
+    /// bits, their value when no HRD parameters are present.
+    pub fn single_cpb(bit_rate: u64, cpb_size: u64, cbr_flag: bool) -> Self {
+        // H.264 E.2.2: BitRate = (bit_rate_value_minus1 + 1) * 2^(6 + bit_rate_scale)
+        // and CpbSize = (cpb_size_value_minus1 + 1) * 2^(4 + cpb_size_scale).
+        fn value_and_scale(value: u64, base_shift: u32) -> (u32, u8) {
+            let mut scale = 0;
+            while scale < 15 && (value >> (base_shift + scale)) > u64::from(u32::MAX - 1) {
+                scale += 1;
+            }
+
+            let value = (value >> (base_shift + scale)).clamp(1, u64::from(u32::MAX - 1));
+            (value as u32 - 1, scale as u8)
+        }
+
+        let (bit_rate_value_minus1, bit_rate_scale) = value_and_scale(bit_rate, 6);
+        let (cpb_size_value_minus1, cpb_size_scale) = value_and_scale(cpb_size, 4);
+
+        let mut hrd = Self {
+            cpb_cnt_minus1: 0,
+            bit_rate_scale,
+            cpb_size_scale,
+            initial_cpb_removal_delay_length_minus1: 23,
+            cpb_removal_delay_length_minus1: 23,
+            dpb_output_delay_length_minus1: 23,
+            time_offset_length: 24,
+            ..Default::default()
+        };
+        hrd.bit_rate_value_minus1[0] = bit_rate_value_minus1;
+        hrd.cpb_size_value_minus1[0] = cpb_size_value_minus1;
+        hrd.cbr_flag[0] = cbr_flag;
+
+        hrd
+    }
+
+    /// Bit rate of the `sched_sel_idx`-th CPB specification, in bits per
+    /// second.
+    pub fn bit_rate(&self, sched_sel_idx: usize) -> u64 {
+        (u64::from(self.bit_rate_value_minus1[sched_sel_idx]) + 1) << (6 + self.bit_rate_scale)
+    }
+
+    /// Size of the `sched_sel_idx`-th CPB, in bits.
+    pub fn cpb_size(&self, sched_sel_idx: usize) -> u64 {
+        (u64::from(self.cpb_size_value_minus1[sched_sel_idx]) + 1) << (4 + self.cpb_size_scale)
+    }
+}
+
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub struct VuiParams {
     /// Specifies whether `aspect_ratio_idc` is present.
@@ -1368,6 +1643,55 @@ impl Default for VuiParams {
     }
 }
 
+/// How a scaling matrix relates to the ones defined by the specification.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ScalingMatrixKind {
+    /// All the scaling factors are equal to 16, i.e. no scaling.
+    Flat,
+    /// All the lists are the default ones of Table 7-3 and Table 7-4.
+    Default,
+    /// Any other matrix.
+    Custom,
+}
+
+impl ScalingMatrixKind {
+    /// Classifies the first `num_8x8` 8x8 lists and all the 4x4 lists of a
+    /// scaling matrix.
+    pub fn of(lists_4x4: &[[u8; 16]; 6], lists_8x8: &[[u8; 64]; 6], num_8x8: usize) -> Self {
+        let lists_8x8 = &lists_8x8[..num_8x8];
+
+        if lists_4x4.iter().flatten().all(|&v| v == 16)
+            && lists_8x8.iter().flatten().all(|&v| v == 16)
+        {
+            return Self::Flat;
+        }
+
+        // H.264 Table 7-2
+        let is_default_4x4 = lists_4x4.iter().enumerate().all(|(i, list)| {
+            *list
+                == if i < 3 {
+                    DEFAULT_4X4_INTRA
+                } else {
+                    DEFAULT_4X4_INTER
+                }
+        });
+        let is_default_8x8 = lists_8x8.iter().enumerate().all(|(i, list)| {
+            *list
+                == if i % 2 == 0 {
+                    DEFAULT_8X8_INTRA
+                } else {
+                    DEFAULT_8X8_INTER
+                }
+        });
+
+        if is_default_4x4 && is_default_8x8 {
+            Self::Default
+        } else {
+            Self::Custom
+        }
+    }
+}
+
 /// A H264 Picture Parameter Set. A syntax structure containing syntax elements
 /// that apply to zero or more entire coded pictures as determined by the
 /// `pic_parameter_set_id` syntax element found in each slice header.
@@ -1492,6 +1816,23 @@ pub struct Pps {
     pub sps: Rc<Sps>,
 }
 
+impl Pps {
+    /// Kind of the scaling matrix used by the pictures referring to this PPS,
+    /// taking the one of the SPS into account if the PPS does not override it.
+    pub fn scaling_matrix_kind(&self) -> ScalingMatrixKind {
+        if !self.pic_scaling_matrix_present_flag {
+            return self.sps.scaling_matrix_kind();
+        }
+
+        let num_8x8 = match (self.transform_8x8_mode_flag, self.sps.chroma_format_idc) {
+            (false, _) => 0,
+            (true, 3) => 6,
+            (true, _) => 2,
+        };
+        ScalingMatrixKind::of(&self.scaling_lists_4x4, &self.scaling_lists_8x8, num_8x8)
+    }
+}
+
 pub struct PpsBuilder(Pps);
 
 impl PpsBuilder {
@@ -1558,15 +1899,297 @@ impl PpsBuilder {
         self.num_ref_idx_l1_default_active_minus1(value - 1)
     }
 
+    pub fn transform_8x8_mode_flag(mut self, value: bool) -> Self {
+        self.0.transform_8x8_mode_flag = value;
+        self
+    }
+
+    /// Sets a custom scaling matrix. Lists that are zeroed are not present in
+    /// the PPS, and inferred using H.264 Table 7-2 fall-back rule A or B.
+    pub fn scaling_lists(mut self, lists_4x4: [[u8; 16]; 6], lists_8x8: [[u8; 64]; 6]) -> Self {
+        self.0.pic_scaling_matrix_present_flag = true;
+        self.0.scaling_lists_4x4 = lists_4x4;
+        self.0.scaling_lists_8x8 = lists_8x8;
+        self
+    }
+
     pub fn build(self) -> Rc<Pps> {
         Rc::new(self.0)
     }
 }
 
+/// SEI payload types handled by [`Parser::parse_sei`], as per Annex D.
+#[derive(N, Debug, PartialEq, Eq, Clone, Copy)]
+pub enum SeiPayloadType {
+    BufferingPeriod = 0,
+    PicTiming = 1,
+    UserDataUnregistered = 5,
+    RecoveryPoint = 6,
+    FramePackingArrangement = 45,
+}
+
+/// Buffering period SEI message. See D.1.2 and D.2.2.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct BufferingPeriod {
+    /// Specifies the sequence parameter set that contains the sequence HRD
+    /// attributes.
+    pub seq_parameter_set_id: u8,
+    /// Specifies, for each `SchedSelIdx`, the delay between the time of arrival
+    /// in the CPB of the first bit of the coded data associated with the access
+    /// unit and its time of removal, for the NAL HRD.
+    pub nal_initial_cpb_removal_delay: Vec<u32>,
+    /// Used in combination with `nal_initial_cpb_removal_delay` to specify the
+    /// initial delivery time of coded access units to the CPB.
+    pub nal_initial_cpb_removal_delay_offset: Vec<u32>,
+    /// Same as `nal_initial_cpb_removal_delay`, for the VCL HRD.
+    pub vcl_initial_cpb_removal_delay: Vec<u32>,
+    /// Same as `nal_initial_cpb_removal_delay_offset`, for the VCL HRD.
+    pub vcl_initial_cpb_removal_delay_offset: Vec<u32>,
+}
+
+/// Clock timestamp of a picture timing SEI message. See D.1.3 and D.2.3.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ClockTimestamp {
+    /// Indicates the scan type (progressive, interlaced or unknown) of the
+    /// source material.
+    pub ct_type: u8,
+    /// Used in the computation of `clockTimestamp`.
+    pub nuit_field_based_flag: bool,
+    /// Specifies the method of dropping values of `n_frames`, as per Table
+    /// D-3.
+    pub counting_type: u8,
+    /// If set, specifies that `seconds_value`, `minutes_value` and
+    /// `hours_value` are all present.
+    pub full_timestamp_flag: bool,
+    /// Indicates that the difference between the current and previous clock
+    /// timestamps may not be interpreted as the time difference between the
+    /// times of origin of the frames.
+    pub discontinuity_flag: bool,
+    /// Specifies the skipping of one or more values of `n_frames` using the
+    /// counting method specified by `counting_type`.
+    pub cnt_dropped_flag: bool,
+    /// Specifies the value of `nFrames` used to compute `clockTimestamp`.
+    pub n_frames: u8,
+    /// Whether `seconds_value` is present when `full_timestamp_flag` is unset.
+    pub seconds_flag: bool,
+    pub seconds_value: u8,
+    /// Whether `minutes_value` is present when `full_timestamp_flag` is unset.
+    pub minutes_flag: bool,
+    pub minutes_value: u8,
+    /// Whether `hours_value` is present when `full_timestamp_flag` is unset.
+    pub hours_flag: bool,
+    pub hours_value: u8,
+    /// Specifies the value of `tOffset` used to compute `clockTimestamp`.
+    pub time_offset: i32,
+}
+
+impl ClockTimestamp {
+    /// Returns the timecode of this clock timestamp. When
+    /// `full_timestamp_flag` is unset, the values that are not present are
+    /// taken from `previous`, the timecode of the previous clock timestamp in
+    /// decoding order, if any.
+    pub fn timecode(&self, previous: Option<&Timecode>) -> Timecode {
+        let previous = previous.copied().unwrap_or_default();
+        let full = self.full_timestamp_flag;
+
+        Timecode {
+            hours: if full || self.hours_flag {
+                self.hours_value
+            } else {
+                previous.hours
+            },
+            minutes: if full || self.minutes_flag {
+                self.minutes_value
+            } else {
+                previous.minutes
+            },
+            seconds: if full || self.seconds_flag {
+                self.seconds_value
+            } else {
+                previous.seconds
+            },
+            frames: u16::from(self.n_frames),
+            counting_type: self.counting_type,
+            field_based: self.nuit_field_based_flag,
+            discontinuity: self.discontinuity_flag,
+            cnt_dropped: self.cnt_dropped_flag,
+            time_offset: self.time_offset,
+        }
+    }
+}
+
+impl TryFrom<&Timecode> for ClockTimestamp {
+    type Error = anyhow::Error;
+
+    /// Builds a full clock timestamp of progressive content out of
+    /// `timecode`.
+    fn try_from(timecode: &Timecode) -> Result<Self, Self::Error> {
+        Ok(ClockTimestamp {
+            ct_type: 0,
+            nuit_field_based_flag: timecode.field_based,
+            counting_type: timecode.counting_type,
+            full_timestamp_flag: true,
+            discontinuity_flag: timecode.discontinuity,
+            cnt_dropped_flag: timecode.cnt_dropped,
+            n_frames: u8::try_from(timecode.frames)
+                .map_err(|_| anyhow!("n_frames {} does not fit in 8 bits", timecode.frames))?,
+            seconds_value: timecode.seconds,
+            minutes_value: timecode.minutes,
+            hours_value: timecode.hours,
+            time_offset: timecode.time_offset,
+            ..Default::default()
+        })
+    }
+}
+
+/// Picture timing SEI message. See D.1.3 and D.2.3.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PicTiming {
+    /// Specifies how many clock ticks to wait after removal from the CPB of
+    /// the access unit associated with the most recent buffering period SEI
+    /// message before removing the current access unit from the CPB.
+    pub cpb_removal_delay: u32,
+    /// Used to compute the DPB output time of the picture.
+    pub dpb_output_delay: u32,
+    /// Indicates whether a picture should be displayed as a frame or one or
+    /// more fields, as per Table D-1.
+    pub pic_struct: u8,
+    /// The clock timestamps of the picture. Their number depends on
+    /// `pic_struct`, and absent ones are `None`.
+    pub clock_timestamps: [Option<ClockTimestamp>; 3],
+}
+
+impl PicTiming {
+    /// Returns `NumClockTS` for `pic_struct`, as per Table D-1.
+    pub fn num_clock_ts(pic_struct: u8) -> usize {
+        match pic_struct {
+            0..=2 => 1,
+            3 | 4 | 7 => 2,
+            5 | 6 | 8 => 3,
+            _ => 0,
+        }
+    }
+
+    /// Returns the timecode of the first clock timestamp of the picture, if
+    /// any. See [`ClockTimestamp::timecode`] for the meaning of `previous`.
+    pub fn timecode(&self, previous: Option<&Timecode>) -> Option<Timecode> {
+        self.clock_timestamps
+            .iter()
+            .flatten()
+            .next()
+            .map(|ct| ct.timecode(previous))
+    }
+}
+
+/// Unregistered user data SEI message. See D.1.6 and D.2.6.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct UserDataUnregistered {
+    /// UUID identifying the format of the user data.
+    pub uuid_iso_iec_11578: [u8; 16],
+    /// The user data.
+    pub payload: Vec<u8>,
+}
+
+/// Recovery point SEI message. See D.1.7 and D.2.7.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RecoveryPoint {
+    /// Specifies the recovery point of output pictures in output order, in
+    /// frames.
+    pub recovery_frame_cnt: u32,
+    /// Indicates whether decoding from this point results in an exact match
+    /// at the recovery point.
+    pub exact_match_flag: bool,
+    /// Indicates the presence of a broken link in the NAL unit stream at the
+    /// location of the recovery point SEI message.
+    pub broken_link_flag: bool,
+    /// Indicates whether decoded slice group information is needed to reach
+    /// the recovery point.
+    pub changing_slice_group_idc: u8,
+}
+
+/// Frame packing arrangement SEI message. See D.1.26 and D.2.26.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct FramePackingArrangement {
+    /// Identifies the frame packing arrangement.
+    pub frame_packing_arrangement_id: u32,
+    /// If set, cancels the persistence of any previous frame packing
+    /// arrangement SEI message.
+    pub frame_packing_arrangement_cancel_flag: bool,
+    /// The type of packing arrangement of the frames, as per Table D-8.
+    pub frame_packing_arrangement_type: u8,
+    /// Whether each color component plane of each constituent frame is
+    /// quincunx sampled.
+    pub quincunx_sampling_flag: bool,
+    /// The intended interpretation of the constituent frames, as per Table
+    /// D-9.
+    pub content_interpretation_type: u8,
+    /// Whether one of the two constituent frames is spatially flipped.
+    pub spatial_flipping_flag: bool,
+    /// Which constituent frame is flipped if `spatial_flipping_flag` is set.
+    pub frame0_flipped_flag: bool,
+    /// Whether the constituent frames are fields of the same frame.
+    pub field_views_flag: bool,
+    /// Whether the current decoded frame is constituent frame 0.
+    pub current_frame_is_frame0_flag: bool,
+    /// Whether constituent frame 0 only references itself.
+    pub frame0_self_contained_flag: bool,
+    /// Whether constituent frame 1 only references itself.
+    pub frame1_self_contained_flag: bool,
+    pub frame0_grid_position_x: u8,
+    pub frame0_grid_position_y: u8,
+    pub frame1_grid_position_x: u8,
+    pub frame1_grid_position_y: u8,
+    /// Specifies the persistence of the message.
+    pub frame_packing_arrangement_repetition_period: u32,
+    /// Whether additional data follows, which is ignored.
+    pub frame_packing_arrangement_extension_flag: bool,
+}
+
+/// A SEI message, as per D.1.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SeiMessage {
+    BufferingPeriod(BufferingPeriod),
+    PicTiming(PicTiming),
+    UserDataUnregistered(UserDataUnregistered),
+    RecoveryPoint(RecoveryPoint),
+    FramePackingArrangement(FramePackingArrangement),
+    /// A message type that is not parsed, along with its raw payload.
+    Unknown {
+        payload_type: u32,
+        payload: Vec<u8>,
+    },
+}
+
+impl SeiMessage {
+    /// Returns the `payloadType` of the message.
+    pub fn payload_type(&self) -> u32 {
+        match self {
+            SeiMessage::BufferingPeriod(_) => SeiPayloadType::BufferingPeriod as u32,
+            SeiMessage::PicTiming(_) => SeiPayloadType::PicTiming as u32,
+            SeiMessage::UserDataUnregistered(_) => SeiPayloadType::UserDataUnregistered as u32,
+            SeiMessage::RecoveryPoint(_) => SeiPayloadType::RecoveryPoint as u32,
+            SeiMessage::FramePackingArrangement(_) => {
+                SeiPayloadType::FramePackingArrangement as u32
+            }
+            SeiMessage::Unknown { payload_type, .. } => *payload_type,
+        }
+    }
+}
+
+/// A SEI NAL unit, containing one or more SEI messages.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Sei {
+    pub messages: Vec<SeiMessage>,
+}
+
 #[derive(Debug, Default)]
 pub struct Parser {
-    active_spses: BTreeMap<u8, Rc<Sps>>,
-    active_ppses: BTreeMap<u8, Rc<Pps>>,
+    active_spses: ParamSetStore<Sps>,
+    active_subset_spses: ParamSetStore<Sps>,
+    active_ppses: ParamSetStore<Pps>,
+    /// The SPS used to interpret SEI messages: the one referenced by the last
+    /// buffering period SEI message, or the last parsed one.
+    sei_sps_id: Option<u8>,
 }
 
 impl Parser {
@@ -1645,7 +2268,7 @@ impl Parser {
     }
 
     fn parse_scaling_list<U: AsMut<[u8]>>(
-        r: &mut NaluReader,
+        r: &mut BitReader,
         scaling_list: &mut U,
         use_default: &mut bool,
     ) -> anyhow::Result<()> {
@@ -1675,7 +2298,7 @@ impl Parser {
         Ok(())
     }
 
-    fn parse_sps_scaling_lists(r: &mut NaluReader, sps: &mut Sps) -> anyhow::Result<()> {
+    fn parse_sps_scaling_lists(r: &mut BitReader, sps: &mut Sps) -> anyhow::Result<()> {
         let scaling_lists4x4 = &mut sps.scaling_lists_4x4;
         let scaling_lisst8x8 = &mut sps.scaling_lists_8x8;
 
@@ -1723,7 +2346,7 @@ impl Parser {
         Ok(())
     }
 
-    fn parse_pps_scaling_lists(r: &mut NaluReader, pps: &mut Pps, sps: &Sps) -> anyhow::Result<()> {
+    fn parse_pps_scaling_lists(r: &mut BitReader, pps: &mut Pps, sps: &Sps) -> anyhow::Result<()> {
         let scaling_lists4x4 = &mut pps.scaling_lists_4x4;
         let scaling_lists8x8 = &mut pps.scaling_lists_8x8;
 
@@ -1767,7 +2390,7 @@ impl Parser {
                     Parser::parse_scaling_list(r, &mut scaling_lists8x8[i], &mut use_default)?;
 
                     if use_default {
-                        Parser::fill_default_scaling_list_4x4(&mut scaling_lists4x4[i], i);
+                        Parser::fill_default_scaling_list_8x8(&mut scaling_lists8x8[i], i);
                     }
                 } else if !sps.seq_scaling_matrix_present_flag {
                     // Table 7-2: Fallback rule A
@@ -1792,7 +2415,7 @@ impl Parser {
         Ok(())
     }
 
-    fn parse_hrd(r: &mut NaluReader, hrd: &mut HrdParams) -> anyhow::Result<()> {
+    fn parse_hrd(r: &mut BitReader, hrd: &mut HrdParams) -> anyhow::Result<()> {
         hrd.cpb_cnt_minus1 = r.read_ue_max(31)?;
         hrd.bit_rate_scale = r.read_bits(4)?;
         hrd.cpb_size_scale = r.read_bits(4)?;
@@ -1810,7 +2433,7 @@ impl Parser {
         Ok(())
     }
 
-    fn parse_vui(r: &mut NaluReader, sps: &mut Sps) -> anyhow::Result<()> {
+    fn parse_vui(r: &mut BitReader, sps: &mut Sps) -> anyhow::Result<()> {
         let vui = &mut sps.vui_parameters;
 
         vui.aspect_ratio_info_present_flag = r.read_bit()?;
@@ -1908,7 +2531,122 @@ impl Parser {
 
         let data = nalu.as_ref();
         // Skip the header
-        let mut r = NaluReader::new(&data[nalu.header.len()..]);
+        let mut r = BitReader::new(&data[nalu.header.len()..], true);
+        let sps = Parser::parse_sps_data(&mut r)?;
+
+        let key = sps.seq_parameter_set_id;
+        self.active_spses.insert(key, sps)?;
+        self.sei_sps_id = Some(key);
+
+        if self.active_spses.len() > MAX_SPS_COUNT as usize {
+            return Err(anyhow!(
+                "Broken data: Number of active SPSs > MAX_SPS_COUNT"
+            ));
+        }
+
+        Ok(self.get_sps(key).unwrap())
+    }
+
+    /// Parse a subset SPS, as found in MVC streams, and add it to the list of
+    /// active subset SPSes. Subset SPSes have their own identifiers, and are only
+    /// referred to by the slices of the non-base views.
+    ///
+    /// Returns a reference to the new subset SPS, whose `mvc_extension` is set.
+    pub fn parse_subset_sps(&mut self, nalu: &Nalu) -> anyhow::Result<&Rc<Sps>> {
+        if !matches!(nalu.header.type_, NaluType::SubsetSps) {
+            return Err(anyhow!(
+                "Invalid NALU type, expected {:?}, got {:?}",
+                NaluType::SubsetSps,
+                nalu.header.type_
+            ));
+        }
+
+        let data = nalu.as_ref();
+        // Skip the header
+        let mut r = BitReader::new(&data[nalu.header.len()..], true);
+        let mut sps = Parser::parse_sps_data(&mut r)?;
+
+        match sps.profile_idc {
+            118 | 128 | 134 => {
+                let bit_equal_to_one = r.read_bit()?;
+                if !bit_equal_to_one {
+                    return Err(anyhow!("Broken Data: bit_equal_to_one is not set"));
+                }
+
+                sps.mvc_extension = Some(Parser::parse_sps_mvc_extension(&mut r)?);
+                // The MVC VUI parameters that may follow are not needed for decoding.
+            }
+            profile_idc => {
+                return Err(anyhow!(
+                    "Unsupported subset SPS for profile_idc {}",
+                    profile_idc
+                ))
+            }
+        }
+
+        let key = sps.seq_parameter_set_id;
+        self.active_subset_spses.insert(key, sps)?;
+
+        Ok(self.get_subset_sps(key).unwrap())
+    }
+
+    fn parse_sps_mvc_extension(r: &mut BitReader) -> anyhow::Result<SpsMvcExtension> {
+        let mut ext = SpsMvcExtension::default();
+
+        let num_views_minus1: u16 = r.read_ue_max(1023)?;
+        for _ in 0..=num_views_minus1 {
+            ext.views.push(MvcView {
+                view_id: r.read_ue_max(1023)?,
+                ..Default::default()
+            });
+        }
+
+        let read_refs = |r: &mut BitReader| -> anyhow::Result<Vec<u16>> {
+            let num_refs: u8 = r.read_ue_max(15)?;
+            (0..num_refs).map(|_| Ok(r.read_ue_max(1023)?)).collect()
+        };
+
+        for view in ext.views.iter_mut().skip(1) {
+            view.anchor_refs_l0 = read_refs(r)?;
+            view.anchor_refs_l1 = read_refs(r)?;
+        }
+
+        for view in ext.views.iter_mut().skip(1) {
+            view.non_anchor_refs_l0 = read_refs(r)?;
+            view.non_anchor_refs_l1 = read_refs(r)?;
+        }
+
+        let num_level_values_signalled_minus1: u8 = r.read_ue_max(63)?;
+        for _ in 0..=num_level_values_signalled_minus1 {
+            let mut level_value = MvcLevelValue {
+                level_idc: r.read_bits(8)?,
+                ..Default::default()
+            };
+
+            let num_applicable_ops_minus1: u16 = r.read_ue_max(1023)?;
+            for _ in 0..=num_applicable_ops_minus1 {
+                let temporal_id = r.read_bits(3)?;
+                let num_target_views_minus1: u16 = r.read_ue_max(1023)?;
+                let target_view_ids = (0..=num_target_views_minus1)
+                    .map(|_| Ok(r.read_ue_max(1023)?))
+                    .collect::<anyhow::Result<_>>()?;
+
+                level_value.operation_points.push(MvcOperationPoint {
+                    temporal_id,
+                    target_view_ids,
+                    num_views_minus1: r.read_ue_max(1023)?,
+                });
+            }
+
+            ext.level_values.push(level_value);
+        }
+
+        Ok(ext)
+    }
+
+    /// Parses a `seq_parameter_set_data()` syntax structure, shared by SPSes
+    /// and subset SPSes.
+    fn parse_sps_data(r: &mut BitReader) -> anyhow::Result<Sps> {
         let mut sps = Sps {
             profile_idc: r.read_bits(8)?,
             constraint_set0_flag: r.read_bit()?,
@@ -1952,7 +2690,7 @@ impl Parser {
             sps.seq_scaling_matrix_present_flag = r.read_bit()?;
 
             if sps.seq_scaling_matrix_present_flag {
-                Parser::parse_sps_scaling_lists(&mut r, &mut sps)?;
+                Parser::parse_sps_scaling_lists(r, &mut sps)?;
             } else {
                 Parser::fill_scaling_list_flat(
                     &mut sps.scaling_lists_4x4,
@@ -2016,7 +2754,7 @@ impl Parser {
 
         sps.vui_parameters_present_flag = r.read_bit()?;
         if sps.vui_parameters_present_flag {
-            Parser::parse_vui(&mut r, &mut sps)?;
+            Parser::parse_vui(r, &mut sps)?;
         }
 
         let mut width = (sps.pic_width_in_mbs_minus1 + 1) * 16;
@@ -2054,16 +2792,7 @@ impl Parser {
             sps.crop_rect_y = sps.frame_crop_top_offset * crop_unit_y;
         }
 
-        let key = sps.seq_parameter_set_id;
-        self.active_spses.insert(key, Rc::new(sps));
-
-        if self.active_spses.keys().len() > MAX_SPS_COUNT as usize {
-            return Err(anyhow!(
-                "Broken data: Number of active SPSs > MAX_SPS_COUNT"
-            ));
-        }
-
-        Ok(self.get_sps(key).unwrap())
+        Ok(sps)
     }
 
     pub fn parse_pps(&mut self, nalu: &Nalu) -> anyhow::Result<&Pps> {
@@ -2077,12 +2806,16 @@ impl Parser {
 
         let data = nalu.as_ref();
         // Skip the header
-        let mut r = NaluReader::new(&data[nalu.header.len()..]);
+        let mut r = BitReader::new(&data[nalu.header.len()..], true);
         let pic_parameter_set_id = r.read_ue_max(MAX_PPS_COUNT as u32 - 1)?;
         let seq_parameter_set_id = r.read_ue_max(MAX_SPS_COUNT as u32 - 1)?;
-        let sps = self.get_sps(seq_parameter_set_id).context(
-            "Broken stream: stream references a SPS that has not been successfully parsed",
-        )?;
+        // PPSes referred to by the non-base views of MVC streams may refer to a subset SPS.
+        let sps = self
+            .get_sps(seq_parameter_set_id)
+            .or_else(|| self.get_subset_sps(seq_parameter_set_id))
+            .context(
+                "Broken stream: stream references a SPS that has not been successfully parsed",
+            )?;
         let mut pps = Pps {
             pic_parameter_set_id,
             seq_parameter_set_id,
@@ -2156,9 +2889,9 @@ impl Parser {
         }
 
         let key = pps.pic_parameter_set_id;
-        self.active_ppses.insert(key, Rc::new(pps));
+        self.active_ppses.insert(key, pps)?;
 
-        if self.active_ppses.keys().len() > MAX_PPS_COUNT as usize {
+        if self.active_ppses.len() > MAX_PPS_COUNT as usize {
             return Err(anyhow!(
                 "Broken Data: number of active PPSs > MAX_PPS_COUNT"
             ));
@@ -2167,18 +2900,23 @@ impl Parser {
         Ok(self.get_pps(key).unwrap())
     }
 
+    /// Parses a `ref_pic_list_modification()`, or a `ref_pic_list_mvc_modification()` for the
+    /// slices of the non-base views of MVC streams if `mvc` is set.
     fn parse_ref_pic_list_modification(
-        r: &mut NaluReader,
+        r: &mut BitReader,
         num_ref_idx_active_minus1: u8,
         ref_list_mods: &mut Vec<RefPicListModification>,
+        mvc: bool,
     ) -> anyhow::Result<()> {
         if num_ref_idx_active_minus1 >= 32 {
             return Err(anyhow!("Broken Data: num_ref_idx_active_minus1 >= 32"));
         }
 
+        let max_idc = if mvc { 5 } else { 3 };
+
         loop {
             let mut pic_num_mod = RefPicListModification {
-                modification_of_pic_nums_idc: r.read_ue_max(3)?,
+                modification_of_pic_nums_idc: r.read_ue_max(max_idc)?,
                 ..Default::default()
             };
 
@@ -2196,7 +2934,16 @@ impl Parser {
                     break;
                 }
 
-                _ => return Err(anyhow!("Broken Data: modification_of_pic_nums_idc > 3")),
+                4 | 5 => {
+                    pic_num_mod.abs_diff_view_idx_minus1 = r.read_ue()?;
+                }
+
+                _ => {
+                    return Err(anyhow!(
+                        "Broken Data: modification_of_pic_nums_idc > {}",
+                        max_idc
+                    ))
+                }
             }
 
             ref_list_mods.push(pic_num_mod);
@@ -2206,8 +2953,9 @@ impl Parser {
     }
 
     fn parse_ref_pic_list_modifications(
-        r: &mut NaluReader,
+        r: &mut BitReader,
         header: &mut SliceHeader,
+        mvc: bool,
     ) -> anyhow::Result<()> {
         if !header.slice_type.is_i() && !header.slice_type.is_si() {
             header.ref_pic_list_modification_flag_l0 = r.read_bit()?;
@@ -2216,6 +2964,7 @@ impl Parser {
                     r,
                     header.num_ref_idx_l0_active_minus1,
                     &mut header.ref_pic_list_modification_l0,
+                    mvc,
                 )?;
             }
         }
@@ -2227,6 +2976,7 @@ impl Parser {
                     r,
                     header.num_ref_idx_l1_active_minus1,
                     &mut header.ref_pic_list_modification_l1,
+                    mvc,
                 )?;
             }
         }
@@ -2235,7 +2985,7 @@ impl Parser {
     }
 
     fn parse_pred_weight_table(
-        r: &mut NaluReader,
+        r: &mut BitReader,
         sps: &Sps,
         header: &mut SliceHeader,
     ) -> anyhow::Result<()> {
@@ -2326,7 +3076,7 @@ impl Parser {
     }
 
     fn parse_dec_ref_pic_marking(
-        r: &mut NaluReader,
+        r: &mut BitReader,
         nalu: &Nalu,
         header: &mut SliceHeader,
     ) -> anyhow::Result<()> {
@@ -2392,7 +3142,7 @@ impl Parser {
 
         let data = nalu.as_ref();
         // Skip the header
-        let mut r = NaluReader::new(&data[nalu.header.len()..]);
+        let mut r = BitReader::new(&data[nalu.header.len()..], true);
 
         let mut header = SliceHeader {
             first_mb_in_slice: r.read_ue()?,
@@ -2409,7 +3159,15 @@ impl Parser {
             "Broken stream: slice references PPS that has not been successfully parsed.",
         )?;
 
-        let sps = &pps.sps;
+        // The slices of the non-base views of MVC streams use the subset SPS.
+        let mvc = nalu.header.type_ == NaluType::SliceExt;
+        let sps = if mvc {
+            self.get_subset_sps(pps.seq_parameter_set_id).context(
+                "Broken stream: slice references subset SPS that has not been successfully parsed.",
+            )?
+        } else {
+            &pps.sps
+        };
 
         if sps.separate_colour_plane_flag {
             header.colour_plane_id = r.read_bits(2)?;
@@ -2484,11 +3242,7 @@ impl Parser {
             return Err(anyhow!("Broken Data"));
         }
 
-        if let NaluType::SliceExt = nalu.header.type_ {
-            return Err(anyhow!("Stream contain unsupported/unimplemented NALs"));
-        }
-
-        Parser::parse_ref_pic_list_modifications(&mut r, &mut header)?;
+        Parser::parse_ref_pic_list_modifications(&mut r, &mut header, mvc)?;
 
         if (pps.weighted_pred_flag && (header.slice_type.is_p() || header.slice_type.is_sp()))
             || (pps.weighted_bipred_idc == 1 && header.slice_type.is_b())
@@ -2535,13 +3289,349 @@ impl Parser {
         Ok(Slice { header, nalu })
     }
 
+    /// Parses the macroblocks of `slice`, using the PPS it refers to. Only I
+    /// slices coded with CAVLC are supported, see [`SliceData`].
+    pub fn parse_slice_data(&self, slice: &Slice) -> anyhow::Result<SliceData> {
+        let pps = self.get_pps(slice.header.pic_parameter_set_id).context(
+            "Broken stream: slice references PPS that has not been successfully parsed.",
+        )?;
+
+        SliceData::parse(slice, pps)
+    }
+
+    /// Reads a value of up to 32 bits.
+    fn read_bits_u32(r: &mut BitReader, num_bits: usize) -> anyhow::Result<u32> {
+        if num_bits > 16 {
+            let high: u32 = r.read_bits(num_bits - 16)?;
+            let low: u32 = r.read_bits(16)?;
+            Ok((high << 16) | low)
+        } else {
+            Ok(r.read_bits(num_bits)?)
+        }
+    }
+
+    /// Reads the value of a `payloadType` or `payloadSize` syntax element.
+    fn read_sei_value(r: &mut BitReader) -> anyhow::Result<u32> {
+        let mut value = 0u32;
+        loop {
+            let byte: u32 = r.read_bits(8)?;
+            value = value
+                .checked_add(byte)
+                .ok_or(anyhow!("Broken data: SEI value overflow"))?;
+            if byte != 0xff {
+                return Ok(value);
+            }
+        }
+    }
+
+    /// Returns the number of RBSP bits read so far by `r` out of `total_bits`.
+    fn rbsp_position(r: &BitReader, total_bits: usize) -> usize {
+        total_bits - r.num_bits_left() - r.num_epb() * 8
+    }
+
+    fn parse_buffering_period(&mut self, r: &mut BitReader) -> anyhow::Result<BufferingPeriod> {
+        let mut bp = BufferingPeriod {
+            seq_parameter_set_id: r.read_ue_max(MAX_SPS_COUNT as u32 - 1)?,
+            ..Default::default()
+        };
+
+        let sps = self
+            .get_sps(bp.seq_parameter_set_id)
+            .context("Broken stream: buffering period references an unknown SPS")?;
+        let vui = &sps.vui_parameters;
+
+        for (present, hrd, delays, offsets) in [
+            (
+                vui.nal_hrd_parameters_present_flag,
+                &vui.nal_hrd_parameters,
+                &mut bp.nal_initial_cpb_removal_delay,
+                &mut bp.nal_initial_cpb_removal_delay_offset,
+            ),
+            (
+                vui.vcl_hrd_parameters_present_flag,
+                &vui.vcl_hrd_parameters,
+                &mut bp.vcl_initial_cpb_removal_delay,
+                &mut bp.vcl_initial_cpb_removal_delay_offset,
+            ),
+        ] {
+            if !present {
+                continue;
+            }
+
+            let len = usize::from(hrd.initial_cpb_removal_delay_length_minus1) + 1;
+            for _ in 0..=hrd.cpb_cnt_minus1 {
+                delays.push(Self::read_bits_u32(r, len)?);
+                offsets.push(Self::read_bits_u32(r, len)?);
+            }
+        }
+
+        self.sei_sps_id = Some(bp.seq_parameter_set_id);
+
+        Ok(bp)
+    }
+
+    fn parse_pic_timing(&self, r: &mut BitReader) -> anyhow::Result<PicTiming> {
+        let sps = self
+            .sei_sps_id
+            .and_then(|id| self.get_sps(id))
+            .context("Broken stream: picture timing SEI received before any SPS")?;
+        let vui = &sps.vui_parameters;
+        let mut pt = PicTiming::default();
+
+        // The lengths are the same for the NAL and VCL HRDs if both are present.
+        let hrd = if vui.nal_hrd_parameters_present_flag {
+            Some(&vui.nal_hrd_parameters)
+        } else if vui.vcl_hrd_parameters_present_flag {
+            Some(&vui.vcl_hrd_parameters)
+        } else {
+            None
+        };
+
+        if let Some(hrd) = hrd {
+            pt.cpb_removal_delay =
+                Self::read_bits_u32(r, usize::from(hrd.cpb_removal_delay_length_minus1) + 1)?;
+            pt.dpb_output_delay =
+                Self::read_bits_u32(r, usize::from(hrd.dpb_output_delay_length_minus1) + 1)?;
+        }
+
+        if vui.pic_struct_present_flag {
+            pt.pic_struct = r.read_bits(4)?;
+            let time_offset_length = hrd.map_or(24, |hrd| usize::from(hrd.time_offset_length));
+
+            for i in 0..PicTiming::num_clock_ts(pt.pic_struct) {
+                if !r.read_bit()? {
+                    continue;
+                }
+
+                let mut ct = ClockTimestamp {
+                    ct_type: r.read_bits(2)?,
+                    nuit_field_based_flag: r.read_bit()?,
+                    counting_type: r.read_bits(5)?,
+                    full_timestamp_flag: r.read_bit()?,
+                    discontinuity_flag: r.read_bit()?,
+                    cnt_dropped_flag: r.read_bit()?,
+                    n_frames: r.read_bits(8)?,
+                    ..Default::default()
+                };
+
+                if ct.full_timestamp_flag {
+                    ct.seconds_value = r.read_bits(6)?;
+                    ct.minutes_value = r.read_bits(6)?;
+                    ct.hours_value = r.read_bits(5)?;
+                } else {
+                    ct.seconds_flag = r.read_bit()?;
+                    if ct.seconds_flag {
+                        ct.seconds_value = r.read_bits(6)?;
+                        ct.minutes_flag = r.read_bit()?;
+                        if ct.minutes_flag {
+                            ct.minutes_value = r.read_bits(6)?;
+                            ct.hours_flag = r.read_bit()?;
+                            if ct.hours_flag {
+                                ct.hours_value = r.read_bits(5)?;
+                            }
+                        }
+                    }
+                }
+
+                if time_offset_length > 0 {
+                    let time_offset: u32 = r.read_bits(time_offset_length)?;
+                    // Sign-extend the two's complement value.
+                    let shift = 32 - time_offset_length;
+                    ct.time_offset = ((time_offset << shift) as i32) >> shift;
+                }
+
+                pt.clock_timestamps[i] = Some(ct);
+            }
+        }
+
+        Ok(pt)
+    }
+
+    fn parse_recovery_point(r: &mut BitReader) -> anyhow::Result<RecoveryPoint> {
+        Ok(RecoveryPoint {
+            recovery_frame_cnt: r.read_ue()?,
+            exact_match_flag: r.read_bit()?,
+            broken_link_flag: r.read_bit()?,
+            changing_slice_group_idc: r.read_bits(2)?,
+        })
+    }
+
+    fn parse_frame_packing_arrangement(
+        r: &mut BitReader,
+    ) -> anyhow::Result<FramePackingArrangement> {
+        let mut fpa = FramePackingArrangement {
+            frame_packing_arrangement_id: r.read_ue()?,
+            frame_packing_arrangement_cancel_flag: r.read_bit()?,
+            ..Default::default()
+        };
+
+        if !fpa.frame_packing_arrangement_cancel_flag {
+            fpa.frame_packing_arrangement_type = r.read_bits(7)?;
+            fpa.quincunx_sampling_flag = r.read_bit()?;
+            fpa.content_interpretation_type = r.read_bits(6)?;
+            fpa.spatial_flipping_flag = r.read_bit()?;
+            fpa.frame0_flipped_flag = r.read_bit()?;
+            fpa.field_views_flag = r.read_bit()?;
+            fpa.current_frame_is_frame0_flag = r.read_bit()?;
+            fpa.frame0_self_contained_flag = r.read_bit()?;
+            fpa.frame1_self_contained_flag = r.read_bit()?;
+
+            if !fpa.quincunx_sampling_flag && fpa.frame_packing_arrangement_type != 5 {
+                fpa.frame0_grid_position_x = r.read_bits(4)?;
+                fpa.frame0_grid_position_y = r.read_bits(4)?;
+                fpa.frame1_grid_position_x = r.read_bits(4)?;
+                fpa.frame1_grid_position_y = r.read_bits(4)?;
+            }
+
+            // frame_packing_arrangement_reserved_byte
+            r.skip_bits(8)?;
+            fpa.frame_packing_arrangement_repetition_period = r.read_ue_max(16384)?;
+        }
+
+        fpa.frame_packing_arrangement_extension_flag = r.read_bit()?;
+
+        Ok(fpa)
+    }
+
+    /// Parse a SEI NAL unit.
+    ///
+    /// Buffering period, picture timing, unregistered user data, recovery point
+    /// and frame packing arrangement messages are parsed into their typed
+    /// representation. Other messages are returned as
+    /// [`SeiMessage::Unknown`] with their raw payload.
+    ///
+    /// Picture timing messages are interpreted using the SPS referenced by the
+    /// last buffering period message, or the last parsed SPS if there is none.
+    pub fn parse_sei(&mut self, nalu: &Nalu) -> anyhow::Result<Sei> {
+        if !matches!(nalu.header.type_, NaluType::Sei) {
+            return Err(anyhow!(
+                "Invalid NALU type, expected {:?}, got {:?}",
+                NaluType::Sei,
+                nalu.header.type_
+            ));
+        }
+
+        // Skip the header
+        let data = &nalu.as_ref()[nalu.header.len()..];
+        let total_bits = data.len() * 8;
+        let mut r = BitReader::new(data, true);
+        let mut sei = Sei::default();
+
+        loop {
+            let payload_type = Self::read_sei_value(&mut r)?;
+            let payload_size = usize::try_from(Self::read_sei_value(&mut r)?)?;
+            let payload_start = Self::rbsp_position(&r, total_bits);
+
+            let message = match SeiPayloadType::n(payload_type) {
+                Some(SeiPayloadType::BufferingPeriod) => {
+                    SeiMessage::BufferingPeriod(self.parse_buffering_period(&mut r)?)
+                }
+                Some(SeiPayloadType::PicTiming) => {
+                    SeiMessage::PicTiming(self.parse_pic_timing(&mut r)?)
+                }
+                Some(SeiPayloadType::RecoveryPoint) => {
+                    SeiMessage::RecoveryPoint(Self::parse_recovery_point(&mut r)?)
+                }
+                Some(SeiPayloadType::FramePackingArrangement) => {
+                    SeiMessage::FramePackingArrangement(Self::parse_frame_packing_arrangement(
+                        &mut r,
+                    )?)
+                }
+                Some(SeiPayloadType::UserDataUnregistered) => {
+                    if payload_size < 16 {
+                        return Err(anyhow!("Broken data: user data SEI payload too short"));
+                    }
+
+                    let mut uuid_iso_iec_11578 = [0u8; 16];
+                    for byte in &mut uuid_iso_iec_11578 {
+                        *byte = r.read_bits(8)?;
+                    }
+                    let payload = (16..payload_size)
+                        .map(|_| r.read_bits(8))
+                        .collect::<Result<_, _>>()?;
+
+                    SeiMessage::UserDataUnregistered(UserDataUnregistered {
+                        uuid_iso_iec_11578,
+                        payload,
+                    })
+                }
+                None => SeiMessage::Unknown {
+                    payload_type,
+                    payload: (0..payload_size)
+                        .map(|_| r.read_bits(8))
+                        .collect::<Result<_, _>>()?,
+                },
+            };
+
+            // Skip whatever we did not parse of the payload, e.g. reserved
+            // extension data and alignment bits.
+            let consumed = Self::rbsp_position(&r, total_bits) - payload_start;
+            let payload_bits = payload_size * 8;
+            if consumed > payload_bits {
+                return Err(anyhow!(
+                    "Broken data: SEI message of type {} overflows its payload",
+                    payload_type
+                ));
+            }
+            r.skip_bits(payload_bits - consumed)?;
+
+            sei.messages.push(message);
+
+            if !r.has_more_rsbp_data() {
+                break;
+            }
+        }
+
+        Ok(sei)
+    }
+
     pub fn get_sps(&self, sps_id: u8) -> Option<&Rc<Sps>> {
-        self.active_spses.get(&sps_id)
+        self.active_spses.get(sps_id)
     }
 
     pub fn get_pps(&self, pps_id: u8) -> Option<&Rc<Pps>> {
-        self.active_ppses.get(&pps_id)
+        self.active_ppses.get(pps_id)
+    }
+
+    pub fn get_subset_sps(&self, sps_id: u8) -> Option<&Rc<Sps>> {
+        self.active_subset_spses.get(sps_id)
+    }
+
+    /// Returns the SPSs parsed so far.
+    pub fn sps_store(&self) -> &ParamSetStore<Sps> {
+        &self.active_spses
+    }
+
+    /// Returns the PPSs parsed so far.
+    pub fn pps_store(&self) -> &ParamSetStore<Pps> {
+        &self.active_ppses
     }
+
+    /// Makes the SPS with id `sps_id` the active one, returning the change of
+    /// sequence format this implies, if any.
+    pub fn activate_sps(&mut self, sps_id: u8) -> anyhow::Result<Option<SequenceChange>> {
+        self.active_spses.activate(sps_id)
+    }
+}
+
+/// `nal_unit_header_mvc_extension()` of the prefix and coded slice extension NAL units of MVC
+/// streams. See H.7.4.1.1 in the specification.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct NaluHeaderMvcExtension {
+    /// If not set, specifies that the view component is an IDR picture.
+    pub non_idr_flag: bool,
+    /// Priority identifier of the NAL unit, lower values meaning higher priorities.
+    pub priority_id: u8,
+    /// View identifier of the NAL unit.
+    pub view_id: u16,
+    /// Temporal identifier of the NAL unit.
+    pub temporal_id: u8,
+    /// If set, specifies that the view component is an anchor picture, which can only use
+    /// inter-view prediction.
+    pub anchor_pic_flag: bool,
+    /// If set, specifies that the view component may be used for the inter-view prediction of
+    /// other views.
+    pub inter_view_flag: bool,
 }
 
 #[derive(Debug)]
@@ -2549,6 +3639,8 @@ pub struct NaluHeader {
     pub ref_idc: u8,
     pub type_: NaluType,
     pub idr_pic_flag: bool,
+    /// MVC extension of prefix and coded slice extension NAL units.
+    pub mvc_extension: Option<NaluHeaderMvcExtension>,
 }
 
 impl Header for NaluHeader {
@@ -2561,17 +3653,45 @@ impl Header for NaluHeader {
 
         let type_ = NaluType::n(byte & 0x1f).ok_or(anyhow!("Broken Data"))?;
 
-        if let NaluType::SliceExt = type_ {
-            return Err(anyhow!("Stream contain unsupported/unimplemented NALs"));
-        }
-
         let ref_idc = (byte & 0x60) >> 5;
-        let idr_pic_flag = matches!(type_, NaluType::SliceIdr);
+        let mut idr_pic_flag = matches!(type_, NaluType::SliceIdr);
+
+        let mvc_extension = match type_ {
+            NaluType::PrefixUnit | NaluType::SliceExt => {
+                let data = cursor.chunk();
+                if data.len() < 4 {
+                    return Err(anyhow!("Broken Data"));
+                }
+
+                let mut r = BitReader::new(&data[1..4], false);
+                let svc_extension_flag = r.read_bit()?;
+                if svc_extension_flag {
+                    return Err(anyhow!("Stream contain unsupported/unimplemented NALs"));
+                }
+
+                let ext = NaluHeaderMvcExtension {
+                    non_idr_flag: r.read_bit()?,
+                    priority_id: r.read_bits(6)?,
+                    view_id: r.read_bits(10)?,
+                    temporal_id: r.read_bits(3)?,
+                    anchor_pic_flag: r.read_bit()?,
+                    inter_view_flag: r.read_bit()?,
+                };
+
+                if type_ == NaluType::SliceExt {
+                    idr_pic_flag = !ext.non_idr_flag;
+                }
+
+                Some(ext)
+            }
+            _ => None,
+        };
 
         Ok(NaluHeader {
             ref_idc,
             type_,
             idr_pic_flag,
+            mvc_extension,
         })
     }
 
@@ -2580,7 +3700,11 @@ impl Header for NaluHeader {
     }
 
     fn len(&self) -> usize {
-        1
+        if self.mvc_extension.is_some() {
+            4
+        } else {
+            1
+        }
     }
 }
 
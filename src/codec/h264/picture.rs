@@ -50,7 +50,7 @@ pub enum IsIdr {
     },
 }
 
-#[derive(Default)]
+#[derive(Clone, Default)]
 pub struct PictureData {
     pub pic_order_cnt_type: u8,
     pub top_field_order_cnt: i32,
@@ -96,8 +96,15 @@ pub struct PictureData {
 
     is_second_field: bool,
     other_field: Option<Weak<RefCell<Self>>>,
+    // First field that will never get a second field, see 3.92 and 3.94.
+    // Output on its own.
+    pub non_paired: bool,
 
     pub timestamp: u64,
+
+    // View of MVC streams this picture belongs to, 0 for the base view unless
+    // signaled otherwise.
+    pub view_id: u16,
 }
 
 impl PictureData {
@@ -196,6 +203,7 @@ impl PictureData {
             nal_ref_idc: nalu_hdr.ref_idc,
             is_idr,
             reference,
+            view_id: nalu_hdr.mvc_extension.map_or(0, |ext| ext.view_id),
             field,
             ref_pic_marking: hdr.dec_ref_pic_marking.clone(),
             coded_resolution,
@@ -259,6 +267,17 @@ impl PictureData {
                 .unwrap_or(false)
     }
 
+    /// Returns PicOrderCnt() of the frame or complementary field pair this
+    /// picture belongs to, which is the lowest POC of its fields as per 8.2.1.
+    pub fn frame_pic_order_cnt(&self) -> i32 {
+        match self.other_field() {
+            Some(other_field) if !matches!(self.field, Field::Frame) => {
+                std::cmp::min(self.pic_order_cnt, other_field.borrow().pic_order_cnt)
+            }
+            _ => self.pic_order_cnt,
+        }
+    }
+
     /// Set this picture's first field.
     pub fn set_first_field_to(&mut self, other_field: &Rc<RefCell<Self>>) {
         self.other_field = Some(Rc::downgrade(other_field));
@@ -294,6 +313,7 @@ impl PictureData {
             nonexisting: self.nonexisting,
             pic_order_cnt,
             field: self.field.opposite(),
+            view_id: self.view_id,
             ..Default::default()
         };
 
@@ -355,6 +375,8 @@ impl std::fmt::Debug for PictureData {
             .field("ref_pic_marking", &self.ref_pic_marking)
             .field("is_second_field", &self.is_second_field)
             .field("other_field", &self.other_field)
+            .field("non_paired", &self.non_paired)
+            .field("view_id", &self.view_id)
             .finish()
     }
 }
@@ -0,0 +1,997 @@
+// Copyright 2024 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Parsing of the macroblock layer of H.264 slices, for analysis purposes.
+//!
+//! This is not a decoder: the syntax elements are parsed and the values
+//! derived from them that are useful to assess the quality of a stream, like
+//! the QP or prediction mode of each macroblock, are returned. Only I slices
+//! coded with CAVLC are supported, without MBAFF and with 4:2:0 or monochrome
+//! sampling.
+
+use anyhow::anyhow;
+
+use crate::codec::common::bit_reader::BitReader;
+use crate::codec::h264::nalu::Header;
+use crate::codec::h264::parser::NaluType;
+use crate::codec::h264::parser::Pps;
+use crate::codec::h264::parser::Slice;
+
+/// Intra_4x4_DC and Intra_8x8_DC prediction modes, used when the mode of a
+/// block cannot be predicted from its neighbours.
+const DC_PRED_MODE: u8 = 2;
+
+/// Maximum length of a CAVLC code.
+const MAX_VLC_LEN: usize = 16;
+
+/// Largest `level_prefix` we accept, so the level values fit in 32 bits.
+const MAX_LEVEL_PREFIX: u32 = 25;
+
+/// Lengths of the `coeff_token` codes of Table 9-5 for `0 <= nC < 2`,
+/// `2 <= nC < 4`, `4 <= nC < 8` and `8 <= nC`, indexed by `TotalCoeff * 4 +
+/// TrailingOnes`. A length of 0 means that the combination has no code.
+const COEFF_TOKEN_LEN: [[u8; 4 * 17]; 4] = [
+    [
+        1, 0, 0, 0, 6, 2, 0, 0, 8, 6, 3, 0, 9, 8, 7, 5, 10, 9, 8, 6, 11, 10, 9, 7, 13, 11, 10, 8,
+        13, 13, 11, 9, 13, 13, 13, 10, 14, 14, 13, 11, 14, 14, 14, 13, 15, 15, 14, 14, 15, 15, 15,
+        14, 16, 15, 15, 15, 16, 16, 16, 15, 16, 16, 16, 16, 16, 16, 16, 16,
+    ],
+    [
+        2, 0, 0, 0, 6, 2, 0, 0, 6, 5, 3, 0, 7, 6, 6, 4, 8, 6, 6, 4, 8, 7, 7, 5, 9, 8, 8, 6, 11, 9,
+        9, 6, 11, 11, 11, 7, 12, 11, 11, 9, 12, 12, 12, 11, 12, 12, 12, 11, 13, 13, 13, 12, 13, 13,
+        13, 13, 13, 14, 13, 13, 14, 14, 14, 13, 14, 14, 14, 14,
+    ],
+    [
+        4, 0, 0, 0, 6, 4, 0, 0, 6, 5, 4, 0, 6, 5, 5, 4, 7, 5, 5, 4, 7, 5, 5, 4, 7, 6, 6, 4, 7, 6,
+        6, 4, 8, 7, 7, 5, 8, 8, 7, 6, 9, 8, 8, 7, 9, 9, 8, 8, 9, 9, 9, 8, 10, 9, 9, 9, 10, 10, 10,
+        10, 10, 10, 10, 10, 10, 10, 10, 10,
+    ],
+    [
+        6, 0, 0, 0, 6, 6, 0, 0, 6, 6, 6, 0, 6, 6, 6, 6, 6, 6, 6, 6, 6, 6, 6, 6, 6, 6, 6, 6, 6, 6,
+        6, 6, 6, 6, 6, 6, 6, 6, 6, 6, 6, 6, 6, 6, 6, 6, 6, 6, 6, 6, 6, 6, 6, 6, 6, 6, 6, 6, 6, 6,
+        6, 6, 6, 6, 6, 6, 6, 6,
+    ],
+];
+
+/// Values of the `coeff_token` codes of Table 9-5, see [`COEFF_TOKEN_LEN`].
+const COEFF_TOKEN_CODE: [[u8; 4 * 17]; 4] = [
+    [
+        1, 0, 0, 0, 5, 1, 0, 0, 7, 4, 1, 0, 7, 6, 5, 3, 7, 6, 5, 3, 7, 6, 5, 4, 15, 6, 5, 4, 11,
+        14, 5, 4, 8, 10, 13, 4, 15, 14, 9, 4, 11, 10, 13, 12, 15, 14, 9, 12, 11, 10, 13, 8, 15, 1,
+        9, 12, 11, 14, 13, 8, 7, 10, 9, 12, 4, 6, 5, 8,
+    ],
+    [
+        3, 0, 0, 0, 11, 2, 0, 0, 7, 7, 3, 0, 7, 10, 9, 5, 7, 6, 5, 4, 4, 6, 5, 6, 7, 6, 5, 8, 15,
+        6, 5, 4, 11, 14, 13, 4, 15, 10, 9, 4, 11, 14, 13, 12, 8, 10, 9, 8, 15, 14, 13, 12, 11, 10,
+        9, 12, 7, 11, 6, 8, 9, 8, 10, 1, 7, 6, 5, 4,
+    ],
+    [
+        15, 0, 0, 0, 15, 14, 0, 0, 11, 15, 13, 0, 8, 12, 14, 12, 15, 10, 11, 11, 11, 8, 9, 10, 9,
+        14, 13, 9, 8, 10, 9, 8, 15, 14, 13, 13, 11, 14, 10, 12, 15, 10, 13, 12, 11, 14, 9, 12, 8,
+        10, 13, 8, 13, 7, 9, 12, 9, 12, 11, 10, 5, 8, 7, 6, 1, 4, 3, 2,
+    ],
+    [
+        3, 0, 0, 0, 0, 1, 0, 0, 4, 5, 6, 0, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21,
+        22, 23, 24, 25, 26, 27, 28, 29, 30, 31, 32, 33, 34, 35, 36, 37, 38, 39, 40, 41, 42, 43, 44,
+        45, 46, 47, 48, 49, 50, 51, 52, 53, 54, 55, 56, 57, 58, 59, 60, 61, 62, 63,
+    ],
+];
+
+/// Lengths of the `coeff_token` codes of Table 9-5 for `nC == -1`, i.e. the
+/// chroma DC coefficients of 4:2:0 pictures.
+const CHROMA_DC_COEFF_TOKEN_LEN: [u8; 4 * 5] =
+    [2, 0, 0, 0, 6, 1, 0, 0, 6, 6, 3, 0, 6, 7, 7, 6, 6, 8, 8, 7];
+
+/// Values of the `coeff_token` codes of Table 9-5 for `nC == -1`.
+const CHROMA_DC_COEFF_TOKEN_CODE: [u8; 4 * 5] =
+    [1, 0, 0, 0, 7, 1, 0, 0, 4, 6, 1, 0, 3, 3, 2, 5, 2, 3, 2, 0];
+
+/// Lengths of the `total_zeros` codes of Tables 9-7 and 9-8, indexed by
+/// `TotalCoeff - 1` and `total_zeros`.
+const TOTAL_ZEROS_LEN: [[u8; 16]; 15] = [
+    [1, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 9],
+    [3, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 6, 6, 6, 6, 0],
+    [4, 3, 3, 3, 4, 4, 3, 3, 4, 5, 5, 6, 5, 6, 0, 0],
+    [5, 3, 4, 4, 3, 3, 3, 4, 3, 4, 5, 5, 5, 0, 0, 0],
+    [4, 4, 4, 3, 3, 3, 3, 3, 4, 5, 4, 5, 0, 0, 0, 0],
+    [6, 5, 3, 3, 3, 3, 3, 3, 4, 3, 6, 0, 0, 0, 0, 0],
+    [6, 5, 3, 3, 3, 2, 3, 4, 3, 6, 0, 0, 0, 0, 0, 0],
+    [6, 4, 5, 3, 2, 2, 3, 3, 6, 0, 0, 0, 0, 0, 0, 0],
+    [6, 6, 4, 2, 2, 3, 2, 5, 0, 0, 0, 0, 0, 0, 0, 0],
+    [5, 5, 3, 2, 2, 2, 4, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+    [4, 4, 3, 3, 1, 3, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+    [4, 4, 2, 1, 3, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+    [3, 3, 1, 2, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+    [2, 2, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+    [1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+];
+
+/// Values of the `total_zeros` codes of Tables 9-7 and 9-8.
+const TOTAL_ZEROS_CODE: [[u8; 16]; 15] = [
+    [1, 3, 2, 3, 2, 3, 2, 3, 2, 3, 2, 3, 2, 3, 2, 1],
+    [7, 6, 5, 4, 3, 5, 4, 3, 2, 3, 2, 3, 2, 1, 0, 0],
+    [5, 7, 6, 5, 4, 3, 4, 3, 2, 3, 2, 1, 1, 0, 0, 0],
+    [3, 7, 5, 4, 6, 5, 4, 3, 3, 2, 2, 1, 0, 0, 0, 0],
+    [5, 4, 3, 7, 6, 5, 4, 3, 2, 1, 1, 0, 0, 0, 0, 0],
+    [1, 1, 7, 6, 5, 4, 3, 2, 1, 1, 0, 0, 0, 0, 0, 0],
+    [1, 1, 5, 4, 3, 3, 2, 1, 1, 0, 0, 0, 0, 0, 0, 0],
+    [1, 1, 1, 3, 3, 2, 2, 1, 0, 0, 0, 0, 0, 0, 0, 0],
+    [1, 0, 1, 3, 2, 1, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0],
+    [1, 0, 1, 3, 2, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+    [0, 1, 1, 2, 1, 3, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+    [0, 1, 1, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+    [0, 1, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+    [0, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+    [0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+];
+
+/// Lengths of the `total_zeros` codes of Table 9-9 (a), for the chroma DC
+/// coefficients of 4:2:0 pictures.
+const CHROMA_DC_TOTAL_ZEROS_LEN: [[u8; 4]; 3] = [[1, 2, 3, 3], [1, 2, 2, 0], [1, 1, 0, 0]];
+
+/// Values of the `total_zeros` codes of Table 9-9 (a).
+const CHROMA_DC_TOTAL_ZEROS_CODE: [[u8; 4]; 3] = [[1, 1, 1, 0], [1, 1, 0, 0], [1, 0, 0, 0]];
+
+/// Lengths of the `run_before` codes of Table 9-10, indexed by
+/// `Min(zerosLeft, 7) - 1` and `run_before`.
+const RUN_BEFORE_LEN: [[u8; 15]; 7] = [
+    [1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+    [1, 2, 2, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+    [2, 2, 2, 2, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+    [2, 2, 2, 3, 3, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+    [2, 2, 3, 3, 3, 3, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+    [2, 3, 3, 3, 3, 3, 3, 0, 0, 0, 0, 0, 0, 0, 0],
+    [3, 3, 3, 3, 3, 3, 3, 4, 5, 6, 7, 8, 9, 10, 11],
+];
+
+/// Values of the `run_before` codes of Table 9-10.
+const RUN_BEFORE_CODE: [[u8; 15]; 7] = [
+    [1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+    [1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+    [3, 2, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+    [3, 2, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+    [3, 2, 3, 2, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+    [3, 0, 1, 3, 2, 5, 4, 0, 0, 0, 0, 0, 0, 0, 0],
+    [7, 6, 5, 4, 3, 2, 1, 1, 1, 1, 1, 1, 1, 1, 1],
+];
+
+/// Mapping of `coded_block_pattern` codeNum to its value for Intra_4x4 and
+/// Intra_8x8 macroblocks when `ChromaArrayType` is 1 or 2. See Table 9-4.
+const INTRA_CODED_BLOCK_PATTERN: [u8; 48] = [
+    47, 31, 15, 0, 23, 27, 29, 30, 7, 11, 13, 14, 39, 43, 45, 46, 16, 3, 5, 10, 12, 19, 21, 26, 28,
+    35, 37, 42, 44, 1, 2, 4, 8, 17, 18, 20, 24, 6, 9, 22, 25, 32, 33, 34, 36, 40, 38, 41,
+];
+
+/// Mapping of `coded_block_pattern` codeNum to its value for Intra_4x4 and
+/// Intra_8x8 macroblocks when `ChromaArrayType` is 0 or 3. See Table 9-4.
+const INTRA_CODED_BLOCK_PATTERN_NO_CHROMA: [u8; 16] =
+    [15, 0, 7, 11, 13, 14, 3, 5, 10, 12, 1, 2, 4, 8, 6, 9];
+
+/// Type and prediction modes of an intra macroblock.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum MbType {
+    /// I_NxN macroblock with `transform_size_8x8_flag` unset. Contains the
+    /// `Intra4x4PredMode` of each 4x4 luma block, indexed by
+    /// `luma4x4BlkIdx`.
+    I4x4([u8; 16]),
+    /// I_NxN macroblock with `transform_size_8x8_flag` set. Contains the
+    /// `Intra8x8PredMode` of each 8x8 luma block, indexed by
+    /// `luma8x8BlkIdx`.
+    I8x8([u8; 4]),
+    /// I_16x16 macroblock using the given `Intra16x16PredMode`.
+    I16x16(u8),
+    /// I_PCM macroblock, i.e. coded without prediction nor transform.
+    IPcm,
+}
+
+/// The syntax elements of a macroblock and the values derived from them.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Macroblock {
+    /// Address of the macroblock in the picture, i.e. `CurrMbAddr`.
+    pub mb_addr: u32,
+    /// Type and prediction modes of the macroblock.
+    pub mb_type: MbType,
+    /// Prediction mode of the chroma samples, 0 (DC) if the picture has no
+    /// chroma.
+    pub intra_chroma_pred_mode: u8,
+    /// `CodedBlockPatternLuma` in the 4 low bits, and
+    /// `CodedBlockPatternChroma` in the 2 bits above them.
+    pub coded_block_pattern: u8,
+    /// Change of QP coded in this macroblock.
+    pub mb_qp_delta: i32,
+    /// The luma quantization parameter `QPY` of the macroblock.
+    pub qp_y: i32,
+    /// `TotalCoeff(coeff_token)` of the DC coefficients of I_16x16
+    /// macroblocks.
+    pub total_coeff_luma_dc: u8,
+    /// `TotalCoeff(coeff_token)` of each 4x4 luma block, indexed by
+    /// `luma4x4BlkIdx`. Only the AC coefficients are counted for I_16x16
+    /// macroblocks, and I_PCM macroblocks count as 16 as per 9.2.1.
+    pub total_coeff_luma: [u8; 16],
+    /// `TotalCoeff(coeff_token)` of the DC coefficients of Cb and Cr.
+    pub total_coeff_chroma_dc: [u8; 2],
+    /// `TotalCoeff(coeff_token)` of the AC coefficients of each 4x4 block of
+    /// Cb and Cr, indexed by `chroma4x4BlkIdx`.
+    pub total_coeff_chroma_ac: [[u8; 4]; 2],
+    /// Size of the `macroblock_layer()` in bits, excluding emulation
+    /// prevention bytes.
+    pub size_in_bits: usize,
+}
+
+/// The macroblocks of a slice.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SliceData {
+    pub macroblocks: Vec<Macroblock>,
+}
+
+impl SliceData {
+    /// Parses the `slice_data()` of `slice`, which must use `pps`.
+    pub fn parse(slice: &Slice, pps: &Pps) -> anyhow::Result<Self> {
+        SliceDataParser::new(slice, pps)?.parse()
+    }
+
+    /// Returns the average `QPY` of the macroblocks of the slice.
+    pub fn average_qp(&self) -> Option<f64> {
+        if self.macroblocks.is_empty() {
+            return None;
+        }
+
+        let sum: i64 = self.macroblocks.iter().map(|mb| i64::from(mb.qp_y)).sum();
+        Some(sum as f64 / self.macroblocks.len() as f64)
+    }
+
+    /// Returns the number of I_4x4, I_8x8, I_16x16 and I_PCM macroblocks, in
+    /// this order.
+    pub fn mb_type_counts(&self) -> [usize; 4] {
+        let mut counts = [0; 4];
+
+        for mb in &self.macroblocks {
+            let idx = match mb.mb_type {
+                MbType::I4x4(_) => 0,
+                MbType::I8x8(_) => 1,
+                MbType::I16x16(_) => 2,
+                MbType::IPcm => 3,
+            };
+            counts[idx] += 1;
+        }
+
+        counts
+    }
+}
+
+/// State of a parsed macroblock needed to parse the following ones.
+#[derive(Clone, Default)]
+struct MbContext {
+    /// Intra4x4PredMode of each 4x4 luma block in raster order, or `None` if
+    /// the macroblock is not I_NxN. With the 8x8 transform, the mode of each
+    /// 8x8 block is repeated over its four 4x4 blocks.
+    intra_pred_modes: Option<[u8; 16]>,
+    /// TotalCoeff(coeff_token) of each 4x4 luma block in raster order.
+    total_coeff: [u8; 16],
+    /// TotalCoeff(coeff_token) of each 4x4 Cb and Cr block in raster order.
+    total_coeff_chroma: [[u8; 4]; 2],
+}
+
+/// Returns the position, in units of 4x4 blocks, of the 4x4 luma block with
+/// index `blk_idx`. See 6.4.3.
+fn luma4x4_blk_pos(blk_idx: usize) -> (usize, usize) {
+    let x = (blk_idx / 4 % 2) * 2 + blk_idx % 2;
+    let y = (blk_idx / 8) * 2 + blk_idx % 4 / 2;
+    (x, y)
+}
+
+/// Derives nC from the number of coefficients of the left and above blocks,
+/// as per 9.2.1.
+fn predict_nc(n_a: Option<u8>, n_b: Option<u8>) -> i32 {
+    match (n_a, n_b) {
+        (Some(a), Some(b)) => (i32::from(a) + i32::from(b) + 1) >> 1,
+        (Some(n), None) | (None, Some(n)) => i32::from(n),
+        (None, None) => 0,
+    }
+}
+
+/// Reads a variable-length code whose lengths and values are given by `lens`
+/// and `codes`, and returns its index in them.
+fn read_vlc(r: &mut BitReader, lens: &[u8], codes: &[u8]) -> anyhow::Result<usize> {
+    let mut code = 0u32;
+
+    for len in 1..=MAX_VLC_LEN {
+        code = (code << 1) | u32::from(r.read_bit()?);
+
+        let found = lens
+            .iter()
+            .zip(codes)
+            .position(|(&l, &c)| usize::from(l) == len && u32::from(c) == code);
+
+        if let Some(idx) = found {
+            return Ok(idx);
+        }
+    }
+
+    Err(anyhow!("Broken stream: invalid CAVLC code"))
+}
+
+struct SliceDataParser<'a> {
+    r: BitReader<'a>,
+    slice: &'a Slice<'a>,
+    pps: &'a Pps,
+    /// Whether the picture has 4:2:0 chroma. Monochrome otherwise.
+    has_chroma: bool,
+    width_in_mbs: u32,
+    pic_size_in_mbs: u32,
+    /// Context of the macroblocks parsed so far, indexed by their address
+    /// minus `first_mb_in_slice`.
+    mbs: Vec<MbContext>,
+}
+
+impl<'a> SliceDataParser<'a> {
+    fn new(slice: &'a Slice<'a>, pps: &'a Pps) -> anyhow::Result<Self> {
+        let hdr = &slice.header;
+        let sps = &pps.sps;
+
+        if !matches!(
+            slice.nalu.header.type_,
+            NaluType::Slice | NaluType::SliceIdr
+        ) {
+            return Err(anyhow!(
+                "Unsupported NALU type {:?} for slice data parsing",
+                slice.nalu.header.type_
+            ));
+        }
+
+        if !hdr.slice_type.is_i() {
+            return Err(anyhow!(
+                "Unsupported slice type {:?}: only I slices can be parsed",
+                hdr.slice_type
+            ));
+        }
+
+        if pps.entropy_coding_mode_flag {
+            return Err(anyhow!("Unsupported CABAC slice: only CAVLC can be parsed"));
+        }
+
+        if sps.mb_adaptive_frame_field_flag && !hdr.field_pic_flag {
+            return Err(anyhow!("Unsupported MBAFF slice"));
+        }
+
+        if pps.num_slice_groups_minus1 > 0 {
+            return Err(anyhow!("Unsupported slice groups"));
+        }
+
+        let chroma_array_type = if sps.separate_colour_plane_flag {
+            0
+        } else {
+            sps.chroma_format_idc
+        };
+
+        if chroma_array_type > 1 {
+            return Err(anyhow!(
+                "Unsupported ChromaArrayType {}: only 4:2:0 and monochrome can be parsed",
+                chroma_array_type
+            ));
+        }
+
+        let width_in_mbs = sps.pic_width_in_mbs_minus1 + 1;
+        let frame_height_in_mbs =
+            (2 - u32::from(sps.frame_mbs_only_flag)) * (sps.pic_height_in_map_units_minus1 + 1);
+        let pic_size_in_mbs =
+            width_in_mbs * frame_height_in_mbs / (1 + u32::from(hdr.field_pic_flag));
+
+        // The slice data starts right after the slice header.
+        let data = slice.nalu.as_ref();
+        let header_len = slice.nalu.header.len();
+        let mut r = BitReader::new(&data[header_len..], true);
+        let header_bits = hdr
+            .header_bit_size
+            .checked_sub(header_len * 8)
+            .ok_or_else(|| anyhow!("Invalid slice header size {}", hdr.header_bit_size))?;
+        r.skip_bits(header_bits)?;
+
+        Ok(Self {
+            r,
+            slice,
+            pps,
+            has_chroma: chroma_array_type == 1,
+            width_in_mbs,
+            pic_size_in_mbs,
+            mbs: vec![],
+        })
+    }
+
+    fn parse(mut self) -> anyhow::Result<SliceData> {
+        let hdr = &self.slice.header;
+        let mut qp_y = 26 + i32::from(self.pps.pic_init_qp_minus26) + i32::from(hdr.slice_qp_delta);
+        let mut mb_addr = hdr.first_mb_in_slice;
+        let mut macroblocks = vec![];
+
+        loop {
+            if mb_addr >= self.pic_size_in_mbs {
+                return Err(anyhow!(
+                    "Broken stream: slice data goes past the {} macroblocks of the picture",
+                    self.pic_size_in_mbs
+                ));
+            }
+
+            let mb = self.parse_macroblock(mb_addr, &mut qp_y)?;
+            macroblocks.push(mb);
+
+            if !self.r.has_more_rsbp_data() {
+                break;
+            }
+
+            mb_addr += 1;
+        }
+
+        Ok(SliceData { macroblocks })
+    }
+
+    /// Returns the number of bits read so far, excluding emulation prevention
+    /// bytes.
+    fn rbsp_position(&self) -> usize {
+        self.r.position() - self.r.num_epb() * 8
+    }
+
+    /// Returns the macroblock containing the block at position (`x`, `y`) in
+    /// a grid of `n`x`n` blocks relative to the macroblock at `mb_addr`, and
+    /// the raster index of the block in it. Returns `None` if the block is
+    /// not available for prediction. See 6.4.11.
+    fn neighbour<'c>(
+        &'c self,
+        mb_addr: u32,
+        cur: &'c MbContext,
+        x: isize,
+        y: isize,
+        n: isize,
+    ) -> Option<(&'c MbContext, usize)> {
+        let first_mb = self.slice.header.first_mb_in_slice;
+
+        let (mb_addr_n, x, y) = if x < 0 {
+            let mb_x = mb_addr % self.width_in_mbs;
+            if mb_x == 0 {
+                return None;
+            }
+            (mb_addr - 1, n - 1, y)
+        } else if y < 0 {
+            (mb_addr.checked_sub(self.width_in_mbs)?, x, n - 1)
+        } else {
+            return Some((cur, (y * n + x) as usize));
+        };
+
+        // Macroblocks from other slices are not available.
+        let idx = mb_addr_n.checked_sub(first_mb)?;
+        Some((&self.mbs[idx as usize], (y * n + x) as usize))
+    }
+
+    /// Derives nC for the luma block at position (`x`, `y`).
+    fn luma_nc(&self, mb_addr: u32, cur: &MbContext, x: usize, y: usize) -> i32 {
+        let (x, y) = (x as isize, y as isize);
+        let n_a = self
+            .neighbour(mb_addr, cur, x - 1, y, 4)
+            .map(|(mb, idx)| mb.total_coeff[idx]);
+        let n_b = self
+            .neighbour(mb_addr, cur, x, y - 1, 4)
+            .map(|(mb, idx)| mb.total_coeff[idx]);
+
+        predict_nc(n_a, n_b)
+    }
+
+    /// Derives nC for the chroma AC block at position (`x`, `y`) of component
+    /// `comp`.
+    fn chroma_nc(&self, mb_addr: u32, cur: &MbContext, comp: usize, x: usize, y: usize) -> i32 {
+        let (x, y) = (x as isize, y as isize);
+        let n_a = self
+            .neighbour(mb_addr, cur, x - 1, y, 2)
+            .map(|(mb, idx)| mb.total_coeff_chroma[comp][idx]);
+        let n_b = self
+            .neighbour(mb_addr, cur, x, y - 1, 2)
+            .map(|(mb, idx)| mb.total_coeff_chroma[comp][idx]);
+
+        predict_nc(n_a, n_b)
+    }
+
+    /// Derives the predicted intra mode of the 4x4 or 8x8 luma block whose
+    /// top-left 4x4 block is at position (`x`, `y`). See 8.3.1.1 and 8.3.2.1.
+    fn predicted_intra_mode(&self, mb_addr: u32, cur: &MbContext, x: usize, y: usize) -> u8 {
+        let (x, y) = (x as isize, y as isize);
+        let mode = |(mb, idx): (&MbContext, usize)| {
+            // Blocks of macroblocks not coded in Intra_4x4 or Intra_8x8 use DC.
+            mb.intra_pred_modes.map_or(DC_PRED_MODE, |modes| modes[idx])
+        };
+
+        let mode_a = self.neighbour(mb_addr, cur, x - 1, y, 4).map(mode);
+        let mode_b = self.neighbour(mb_addr, cur, x, y - 1, 4).map(mode);
+
+        match (mode_a, mode_b) {
+            (Some(a), Some(b)) => std::cmp::min(a, b),
+            _ => DC_PRED_MODE,
+        }
+    }
+
+    /// Parses the `macroblock_layer()` of the macroblock at `mb_addr`, and
+    /// updates `qp_y` with its `mb_qp_delta`.
+    fn parse_macroblock(&mut self, mb_addr: u32, qp_y: &mut i32) -> anyhow::Result<Macroblock> {
+        let start = self.rbsp_position();
+        let sps = &self.pps.sps;
+        let mut ctx = MbContext::default();
+
+        let mb_type_val: u8 = self.r.read_ue_max(25)?;
+        let mut mb = Macroblock {
+            mb_addr,
+            mb_type: MbType::IPcm,
+            intra_chroma_pred_mode: 0,
+            coded_block_pattern: 0,
+            mb_qp_delta: 0,
+            qp_y: *qp_y,
+            total_coeff_luma_dc: 0,
+            total_coeff_luma: [0; 16],
+            total_coeff_chroma_dc: [0; 2],
+            total_coeff_chroma_ac: [[0; 4]; 2],
+            size_in_bits: 0,
+        };
+
+        if mb_type_val == 25 {
+            while !self.r.is_aligned() {
+                if self.r.read_bit()? {
+                    return Err(anyhow!("Broken stream: pcm_alignment_zero_bit is set"));
+                }
+            }
+
+            let bit_depth_luma = usize::from(sps.bit_depth_luma_minus8) + 8;
+            let bit_depth_chroma = usize::from(sps.bit_depth_chroma_minus8) + 8;
+            let num_chroma_samples = if self.has_chroma { 2 * 8 * 8 } else { 0 };
+            self.r
+                .skip_bits(256 * bit_depth_luma + num_chroma_samples * bit_depth_chroma)?;
+
+            ctx.total_coeff = [16; 16];
+            ctx.total_coeff_chroma = [[16; 4]; 2];
+            mb.total_coeff_luma = [16; 16];
+            mb.total_coeff_chroma_ac = [[16; 4]; 2];
+        } else if mb_type_val == 0 {
+            let transform_size_8x8_flag = self.pps.transform_8x8_mode_flag && self.r.read_bit()?;
+            mb.mb_type = self.parse_intra_nxn_pred(mb_addr, &mut ctx, transform_size_8x8_flag)?;
+
+            if self.has_chroma {
+                mb.intra_chroma_pred_mode = self.r.read_ue_max(3)?;
+            }
+
+            mb.coded_block_pattern = if self.has_chroma {
+                INTRA_CODED_BLOCK_PATTERN[self.r.read_ue_max::<usize>(47)?]
+            } else {
+                INTRA_CODED_BLOCK_PATTERN_NO_CHROMA[self.r.read_ue_max::<usize>(15)?]
+            };
+        } else {
+            // I_16x16_<predmode>_<cbpchroma>_<cbpluma>, see Table 7-11.
+            let n = mb_type_val - 1;
+            let cbp_luma = if n >= 12 { 15 } else { 0 };
+            let cbp_chroma = (n / 4) % 3;
+
+            mb.mb_type = MbType::I16x16(n % 4);
+            mb.coded_block_pattern = cbp_luma | (cbp_chroma << 4);
+
+            if self.has_chroma {
+                mb.intra_chroma_pred_mode = self.r.read_ue_max(3)?;
+            }
+        }
+
+        let is_i16x16 = matches!(mb.mb_type, MbType::I16x16(_));
+        if mb.mb_type != MbType::IPcm && (mb.coded_block_pattern != 0 || is_i16x16) {
+            let qp_bd_offset_y = 6 * i32::from(sps.bit_depth_luma_minus8);
+            mb.mb_qp_delta = self
+                .r
+                .read_se_bounded(-(26 + qp_bd_offset_y / 2), 25 + qp_bd_offset_y / 2)?;
+
+            // Equation 7-37.
+            *qp_y = ((*qp_y + mb.mb_qp_delta + 52 + 2 * qp_bd_offset_y) % (52 + qp_bd_offset_y))
+                - qp_bd_offset_y;
+            mb.qp_y = *qp_y;
+
+            self.parse_residual(mb_addr, &mut ctx, &mut mb)?;
+        }
+
+        mb.size_in_bits = self.rbsp_position() - start;
+        self.mbs.push(ctx);
+
+        Ok(mb)
+    }
+
+    /// Parses the luma prediction modes of an I_NxN macroblock from its
+    /// `mb_pred()`, and derives their values.
+    fn parse_intra_nxn_pred(
+        &mut self,
+        mb_addr: u32,
+        ctx: &mut MbContext,
+        transform_size_8x8_flag: bool,
+    ) -> anyhow::Result<MbType> {
+        let num_blocks = if transform_size_8x8_flag { 4 } else { 16 };
+        let mut rem_intra_pred_modes = [None; 16];
+
+        for rem_intra_pred_mode in rem_intra_pred_modes.iter_mut().take(num_blocks) {
+            let prev_intra_pred_mode_flag = self.r.read_bit()?;
+            if !prev_intra_pred_mode_flag {
+                *rem_intra_pred_mode = Some(self.r.read_bits::<u8>(3)?);
+            }
+        }
+
+        let mut modes = [0; 16];
+        ctx.intra_pred_modes = Some([0; 16]);
+
+        for (blk_idx, rem_intra_pred_mode) in
+            rem_intra_pred_modes.iter().enumerate().take(num_blocks)
+        {
+            let (x, y) = if transform_size_8x8_flag {
+                luma4x4_blk_pos(blk_idx * 4)
+            } else {
+                luma4x4_blk_pos(blk_idx)
+            };
+
+            let pred_mode = self.predicted_intra_mode(mb_addr, ctx, x, y);
+            let mode = match *rem_intra_pred_mode {
+                None => pred_mode,
+                Some(rem) if rem < pred_mode => rem,
+                Some(rem) => rem + 1,
+            };
+            modes[blk_idx] = mode;
+
+            let size = if transform_size_8x8_flag { 2 } else { 1 };
+            if let Some(ctx_modes) = ctx.intra_pred_modes.as_mut() {
+                for i in 0..size * size {
+                    ctx_modes[(y + i / size) * 4 + x + i % size] = mode;
+                }
+            }
+        }
+
+        if transform_size_8x8_flag {
+            Ok(MbType::I8x8([modes[0], modes[1], modes[2], modes[3]]))
+        } else {
+            Ok(MbType::I4x4(modes))
+        }
+    }
+
+    /// Parses the `residual()` of a macroblock.
+    fn parse_residual(
+        &mut self,
+        mb_addr: u32,
+        ctx: &mut MbContext,
+        mb: &mut Macroblock,
+    ) -> anyhow::Result<()> {
+        let is_i16x16 = matches!(mb.mb_type, MbType::I16x16(_));
+        let cbp_luma = mb.coded_block_pattern & 0xf;
+        let cbp_chroma = mb.coded_block_pattern >> 4;
+
+        if is_i16x16 {
+            let nc = self.luma_nc(mb_addr, ctx, 0, 0);
+            mb.total_coeff_luma_dc = self.parse_residual_block(nc, 16)?;
+        }
+
+        // With CAVLC, the blocks of the 8x8 transform are coded as four
+        // interleaved 4x4 blocks, so both transform sizes are parsed the same.
+        let max_num_coeff = if is_i16x16 { 15 } else { 16 };
+        for blk_idx in 0..16 {
+            if cbp_luma & (1 << (blk_idx / 4)) == 0 {
+                continue;
+            }
+
+            let (x, y) = luma4x4_blk_pos(blk_idx);
+            let nc = self.luma_nc(mb_addr, ctx, x, y);
+            let total_coeff = self.parse_residual_block(nc, max_num_coeff)?;
+
+            ctx.total_coeff[y * 4 + x] = total_coeff;
+            mb.total_coeff_luma[blk_idx] = total_coeff;
+        }
+
+        if !self.has_chroma {
+            return Ok(());
+        }
+
+        if cbp_chroma & 3 != 0 {
+            for comp in 0..2 {
+                mb.total_coeff_chroma_dc[comp] = self.parse_residual_block(-1, 4)?;
+            }
+        }
+
+        if cbp_chroma & 2 != 0 {
+            for comp in 0..2 {
+                for blk_idx in 0..4 {
+                    let nc = self.chroma_nc(mb_addr, ctx, comp, blk_idx % 2, blk_idx / 2);
+                    let total_coeff = self.parse_residual_block(nc, 15)?;
+
+                    ctx.total_coeff_chroma[comp][blk_idx] = total_coeff;
+                    mb.total_coeff_chroma_ac[comp][blk_idx] = total_coeff;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Parses a `residual_block_cavlc()` of up to `max_num_coeff`
+    /// coefficients, and returns its `TotalCoeff(coeff_token)`.
+    fn parse_residual_block(&mut self, nc: i32, max_num_coeff: u8) -> anyhow::Result<u8> {
+        let coeff_token = match nc {
+            -1 => read_vlc(
+                &mut self.r,
+                &CHROMA_DC_COEFF_TOKEN_LEN,
+                &CHROMA_DC_COEFF_TOKEN_CODE,
+            )?,
+            0..=1 => read_vlc(&mut self.r, &COEFF_TOKEN_LEN[0], &COEFF_TOKEN_CODE[0])?,
+            2..=3 => read_vlc(&mut self.r, &COEFF_TOKEN_LEN[1], &COEFF_TOKEN_CODE[1])?,
+            4..=7 => read_vlc(&mut self.r, &COEFF_TOKEN_LEN[2], &COEFF_TOKEN_CODE[2])?,
+            _ => read_vlc(&mut self.r, &COEFF_TOKEN_LEN[3], &COEFF_TOKEN_CODE[3])?,
+        };
+
+        let total_coeff = (coeff_token / 4) as u8;
+        let trailing_ones = (coeff_token % 4) as u8;
+
+        if total_coeff > max_num_coeff {
+            return Err(anyhow!(
+                "Broken stream: {} coefficients in a block of {}",
+                total_coeff,
+                max_num_coeff
+            ));
+        }
+
+        if total_coeff == 0 {
+            return Ok(0);
+        }
+
+        // The levels are not returned, but must be parsed to know the size of
+        // the following ones. See 9.2.2.
+        let mut suffix_length = if total_coeff > 10 && trailing_ones < 3 {
+            1
+        } else {
+            0
+        };
+
+        for i in 0..total_coeff {
+            if i < trailing_ones {
+                let _trailing_ones_sign_flag = self.r.read_bit()?;
+                continue;
+            }
+
+            let mut level_prefix = 0;
+            while !self.r.read_bit()? {
+                level_prefix += 1;
+                if level_prefix > MAX_LEVEL_PREFIX {
+                    return Err(anyhow!("Broken stream: level_prefix is too large"));
+                }
+            }
+
+            let level_suffix_size = if level_prefix == 14 && suffix_length == 0 {
+                4
+            } else if level_prefix >= 15 {
+                level_prefix - 3
+            } else {
+                suffix_length
+            };
+
+            let mut level_code = (std::cmp::min(15, level_prefix) << suffix_length) as i32;
+            if level_suffix_size > 0 {
+                level_code += self.r.read_bits::<i32>(level_suffix_size as usize)?;
+            }
+            if level_prefix >= 15 && suffix_length == 0 {
+                level_code += 15;
+            }
+            if level_prefix >= 16 {
+                level_code += (1 << (level_prefix - 3)) - 4096;
+            }
+            if i == trailing_ones && trailing_ones < 3 {
+                level_code += 2;
+            }
+
+            let level_val = if level_code % 2 == 0 {
+                (level_code + 2) >> 1
+            } else {
+                (-level_code - 1) >> 1
+            };
+
+            if suffix_length == 0 {
+                suffix_length = 1;
+            }
+            if level_val.abs() > (3 << (suffix_length - 1)) && suffix_length < 6 {
+                suffix_length += 1;
+            }
+        }
+
+        let mut zeros_left = if total_coeff < max_num_coeff {
+            let tz_vlc_index = usize::from(total_coeff) - 1;
+            let total_zeros = if max_num_coeff == 4 {
+                read_vlc(
+                    &mut self.r,
+                    &CHROMA_DC_TOTAL_ZEROS_LEN[tz_vlc_index],
+                    &CHROMA_DC_TOTAL_ZEROS_CODE[tz_vlc_index],
+                )?
+            } else {
+                read_vlc(
+                    &mut self.r,
+                    &TOTAL_ZEROS_LEN[tz_vlc_index],
+                    &TOTAL_ZEROS_CODE[tz_vlc_index],
+                )?
+            };
+            total_zeros as u8
+        } else {
+            0
+        };
+
+        if total_coeff + zeros_left > max_num_coeff {
+            return Err(anyhow!(
+                "Broken stream: {} coefficients and {} zeros in a block of {}",
+                total_coeff,
+                zeros_left,
+                max_num_coeff
+            ));
+        }
+
+        for _ in 0..total_coeff - 1 {
+            if zeros_left == 0 {
+                break;
+            }
+
+            let table = usize::from(std::cmp::min(zeros_left, 7)) - 1;
+            let run_before =
+                read_vlc(&mut self.r, &RUN_BEFORE_LEN[table], &RUN_BEFORE_CODE[table])?;
+            zeros_left = zeros_left
+                .checked_sub(run_before as u8)
+                .ok_or_else(|| anyhow!("Broken stream: run_before is larger than zerosLeft"))?;
+        }
+
+        Ok(total_coeff)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::codec::h264::parser::Nalu;
+    use crate::codec::h264::parser::Parser;
+
+    const STREAM_64X64_I: &[u8] = include_bytes!("test_data/64x64-I.h264");
+    const STREAM_TEST_25_FPS: &[u8] = include_bytes!("test_data/test-25fps.h264");
+
+    /// Checks that no code of a VLC table is the prefix of another one.
+    fn check_prefix_free(lens: &[u8], codes: &[u8]) {
+        let entries: Vec<_> = lens
+            .iter()
+            .zip(codes)
+            .filter(|(&len, _)| len > 0)
+            .map(|(&len, &code)| (u32::from(len), u32::from(code)))
+            .collect();
+
+        for (i, &(len_a, code_a)) in entries.iter().enumerate() {
+            assert!(code_a < 1 << len_a);
+            for &(len_b, code_b) in &entries[i + 1..] {
+                let len = std::cmp::min(len_a, len_b);
+                assert_ne!(code_a >> (len_a - len), code_b >> (len_b - len));
+            }
+        }
+    }
+
+    #[test]
+    fn vlc_tables() {
+        for (lens, codes) in COEFF_TOKEN_LEN.iter().zip(COEFF_TOKEN_CODE.iter()) {
+            check_prefix_free(lens, codes);
+        }
+        check_prefix_free(&CHROMA_DC_COEFF_TOKEN_LEN, &CHROMA_DC_COEFF_TOKEN_CODE);
+        for (lens, codes) in TOTAL_ZEROS_LEN.iter().zip(TOTAL_ZEROS_CODE.iter()) {
+            check_prefix_free(lens, codes);
+        }
+        for (lens, codes) in CHROMA_DC_TOTAL_ZEROS_LEN
+            .iter()
+            .zip(CHROMA_DC_TOTAL_ZEROS_CODE.iter())
+        {
+            check_prefix_free(lens, codes);
+        }
+        for (lens, codes) in RUN_BEFORE_LEN.iter().zip(RUN_BEFORE_CODE.iter()) {
+            check_prefix_free(lens, codes);
+        }
+
+        let mut cbp = INTRA_CODED_BLOCK_PATTERN.to_vec();
+        cbp.sort();
+        assert!(cbp.iter().copied().eq(0..48));
+        let mut cbp = INTRA_CODED_BLOCK_PATTERN_NO_CHROMA.to_vec();
+        cbp.sort();
+        assert!(cbp.iter().copied().eq(0..16));
+    }
+
+    /// Parses the slice data of all the I slices of `stream`.
+    fn parse_i_slices(stream: &[u8]) -> Vec<SliceData> {
+        let mut parser = Parser::default();
+        let mut cursor = Cursor::new(stream);
+        let mut slices = vec![];
+
+        while let Ok(nalu) = Nalu::next(&mut cursor) {
+            match nalu.header.type_ {
+                NaluType::Sps => {
+                    parser.parse_sps(&nalu).unwrap();
+                }
+                NaluType::Pps => {
+                    parser.parse_pps(&nalu).unwrap();
+                }
+                NaluType::Slice | NaluType::SliceIdr => {
+                    let slice = parser.parse_slice_header(nalu).unwrap();
+                    if slice.header.slice_type.is_i() {
+                        slices.push(parser.parse_slice_data(&slice).unwrap());
+                    } else {
+                        assert!(parser.parse_slice_data(&slice).is_err());
+                    }
+                }
+                _ => (),
+            }
+        }
+
+        slices
+    }
+
+    #[test]
+    fn parse_64x64_i() {
+        let slices = parse_i_slices(STREAM_64X64_I);
+        assert_eq!(slices.len(), 1);
+
+        let slice = &slices[0];
+        assert_eq!(slice.macroblocks.len(), 16);
+        for (addr, mb) in slice.macroblocks.iter().enumerate() {
+            assert_eq!(mb.mb_addr, addr as u32);
+            assert!(mb.size_in_bits > 0);
+            assert!((0..=51).contains(&mb.qp_y));
+            if let MbType::I4x4(modes) = mb.mb_type {
+                assert!(modes.iter().all(|&mode| mode <= 8));
+            }
+        }
+
+        let counts = slice.mb_type_counts();
+        assert_eq!(counts.iter().sum::<usize>(), 16);
+        assert!(slice.average_qp().is_some());
+    }
+
+    #[test]
+    fn parse_test_25fps() {
+        let slices = parse_i_slices(STREAM_TEST_25_FPS);
+        // 4 I frames of 20x15 macroblocks, in 2 slices each.
+        assert_eq!(slices.len(), 8);
+        assert_eq!(
+            slices.iter().map(|s| s.macroblocks.len()).sum::<usize>(),
+            4 * 300
+        );
+
+        for slice in &slices {
+            let first = slice.macroblocks[0].mb_addr;
+            for (i, mb) in slice.macroblocks.iter().enumerate() {
+                assert_eq!(mb.mb_addr, first + i as u32);
+            }
+        }
+    }
+
+    #[test]
+    fn truncated_slice() {
+        let mut parser = Parser::default();
+        let mut cursor = Cursor::new(STREAM_64X64_I);
+
+        while let Ok(nalu) = Nalu::next(&mut cursor) {
+            match nalu.header.type_ {
+                NaluType::Sps => {
+                    parser.parse_sps(&nalu).unwrap();
+                }
+                NaluType::Pps => {
+                    parser.parse_pps(&nalu).unwrap();
+                }
+                NaluType::SliceIdr => {
+                    let slice = parser.parse_slice_header(nalu).unwrap();
+                    let start = slice.nalu.sc_offset;
+                    let header_end = slice.nalu.offset + slice.header.header_bit_size / 8 + 1;
+                    let end = slice.nalu.offset + slice.nalu.size;
+
+                    for len in header_end..end {
+                        let mut cursor = Cursor::new(&STREAM_64X64_I[start..len]);
+                        let truncated = Nalu::next(&mut cursor).unwrap();
+                        let truncated = parser.parse_slice_header(truncated).unwrap();
+                        // Must not panic.
+                        let _ = parser.parse_slice_data(&truncated);
+                    }
+                }
+                _ => (),
+            }
+        }
+    }
+}
@@ -5,12 +5,18 @@ use std::io::Write;
 
 use thiserror::Error;
 
-use crate::codec::h264::nalu_writer::NaluWriter;
-use crate::codec::h264::nalu_writer::NaluWriterError;
+use crate::codec::common::bit_writer::BitWriter;
+use crate::codec::common::bit_writer::BitWriterError;
 use crate::codec::h264::parser::HrdParams;
 use crate::codec::h264::parser::NaluType;
+use crate::codec::h264::parser::PicTiming;
 use crate::codec::h264::parser::Pps;
+use crate::codec::h264::parser::RefPicListModification;
+use crate::codec::h264::parser::Sei;
+use crate::codec::h264::parser::SeiMessage;
+use crate::codec::h264::parser::SliceHeader;
 use crate::codec::h264::parser::Sps;
+use crate::codec::h264::parser::SpsMvcExtension;
 use crate::codec::h264::parser::DEFAULT_4X4_INTER;
 use crate::codec::h264::parser::DEFAULT_4X4_INTRA;
 use crate::codec::h264::parser::DEFAULT_8X8_INTER;
@@ -24,19 +30,25 @@ impl private::NaluStruct for Sps {}
 
 impl private::NaluStruct for Pps {}
 
+impl private::NaluStruct for Sei {}
+
+impl private::NaluStruct for SeiMessage {}
+
+impl private::NaluStruct for SliceHeader {}
+
 #[derive(Error, Debug)]
 pub enum SynthesizerError {
     #[error("tried to synthesize unsupported settings")]
     Unsupported,
     #[error(transparent)]
-    NaluWriter(#[from] NaluWriterError),
+    BitWriter(#[from] BitWriterError),
 }
 
 pub type SynthesizerResult<T> = Result<T, SynthesizerError>;
 
-/// A helper to output typed NALUs to [`std::io::Write`] using [`NaluWriter`].
+/// A helper to output typed NALUs to [`std::io::Write`] using [`BitWriter`].
 pub struct Synthesizer<'n, N: private::NaluStruct, W: Write> {
-    writer: NaluWriter<'n, W>,
+    writer: BitWriter<'n, W>,
     nalu: &'n N,
 }
 
@@ -83,11 +95,15 @@ impl<N: private::NaluStruct, W: Write> Synthesizer<'_, N, W> {
             run -= 1;
         }
 
+        // delta_scale shall be in the range of -128 to 127, the decoder
+        // computing the next scale modulo 256.
+        let wrap = |delta: i32| (delta + 128).rem_euclid(256) - 128;
+
         // Encode deltas.
         let mut last_scale = 8;
         for scale in &list[0..run] {
             let delta_scale = *scale as i32 - last_scale;
-            self.se(delta_scale)?;
+            self.se(wrap(delta_scale))?;
             last_scale = *scale as i32;
         }
 
@@ -95,7 +111,82 @@ impl<N: private::NaluStruct, W: Write> Synthesizer<'_, N, W> {
         // |next_scale| (H.264 7.3.2.1.1.1) to zero, i.e. decoder should repeat
         // last values in matrix.
         if run < list.len() {
-            self.se(-last_scale)?;
+            self.se(wrap(-last_scale))?;
+        }
+
+        Ok(())
+    }
+
+    /// Infers the lists of a SPS or PPS scaling matrix as a decoder would.
+    /// `fallback` contains the lists to fall back to for the first 4x4 intra,
+    /// 4x4 inter, 8x8 intra and 8x8 inter lists respectively, as per H.264
+    /// Table 7-2.
+    ///
+    /// Lists that are zeroed or equal to the one the decoder would fall back
+    /// to do not need to be present, which is returned for each of the
+    /// `num_lists` first lists.
+    fn infer_scaling_matrix(
+        lists_4x4: &[[u8; 16]; 6],
+        lists_8x8: &[[u8; 64]; 6],
+        num_lists: usize,
+        fallback: [&[u8]; 4],
+    ) -> ([bool; 12], [[u8; 16]; 6], [[u8; 64]; 6]) {
+        let mut present = [false; 12];
+        let mut inferred_4x4 = [[0u8; 16]; 6];
+        let mut inferred_8x8 = [[0u8; 64]; 6];
+
+        for i in 0..num_lists {
+            let list: &[u8] = if i < 6 {
+                &lists_4x4[i]
+            } else {
+                &lists_8x8[i - 6]
+            };
+
+            let fallback_list = match i {
+                0 => fallback[0].to_vec(),
+                3 => fallback[1].to_vec(),
+                6 => fallback[2].to_vec(),
+                7 => fallback[3].to_vec(),
+                1 | 2 | 4 | 5 => inferred_4x4[i - 1].to_vec(),
+                _ => inferred_8x8[i - 8].to_vec(),
+            };
+
+            present[i] = list.iter().any(|&v| v != 0) && list != fallback_list;
+
+            let inferred = if i < 6 {
+                &mut inferred_4x4[i][..]
+            } else {
+                &mut inferred_8x8[i - 6][..]
+            };
+            inferred.copy_from_slice(if present[i] { list } else { &fallback_list });
+        }
+
+        (present, inferred_4x4, inferred_8x8)
+    }
+
+    /// Writes the `num_lists` first scaling lists of a SPS or PPS scaling
+    /// matrix, omitting the ones the decoder can infer. See
+    /// [`Self::infer_scaling_matrix`].
+    fn scaling_matrix(
+        &mut self,
+        lists_4x4: &[[u8; 16]; 6],
+        lists_8x8: &[[u8; 64]; 6],
+        num_lists: usize,
+        fallback: [&[u8]; 4],
+    ) -> SynthesizerResult<()> {
+        let (present, _, _) = Self::infer_scaling_matrix(lists_4x4, lists_8x8, num_lists, fallback);
+
+        for (i, present) in present.into_iter().take(num_lists).enumerate() {
+            self.u(1, /* scaling_list_present_flag */ present)?;
+
+            if present {
+                let list: &[u8] = if i < 6 {
+                    &lists_4x4[i]
+                } else {
+                    &lists_8x8[i - 6]
+                };
+                self.scaling_list(list, Self::default_scaling_list(i))?;
+            }
         }
 
         Ok(())
@@ -139,16 +230,96 @@ impl<'n, W: Write> Synthesizer<'n, Sps, W> {
         ep_enabled: bool,
     ) -> SynthesizerResult<()> {
         let mut s = Self {
-            writer: NaluWriter::<'n, W>::new(writer, ep_enabled),
+            writer: BitWriter::<'n, W>::new(writer, ep_enabled),
             nalu: sps,
         };
 
-        s.writer.write_header(ref_idc, NaluType::Sps as u8)?;
-        s.seq_parameter_set_data()?;
+        // SPSes with a MVC extension are written as subset SPSes.
+        match &sps.mvc_extension {
+            None => {
+                s.writer.write_header(ref_idc, NaluType::Sps as u8)?;
+                s.seq_parameter_set_data()?;
+            }
+            Some(mvc_extension) => {
+                s.writer.write_header(ref_idc, NaluType::SubsetSps as u8)?;
+                s.seq_parameter_set_data()?;
+                s.u(1, /* bit_equal_to_one */ 1u32)?;
+                s.seq_parameter_set_mvc_extension(mvc_extension)?;
+                s.u(1, /* mvc_vui_parameters_present_flag */ 0u32)?;
+                s.u(1, /* additional_extension2_flag */ 0u32)?;
+            }
+        }
+
         s.rbsp_trailing_bits()
     }
 
+    fn seq_parameter_set_mvc_extension(&mut self, ext: &SpsMvcExtension) -> SynthesizerResult<()> {
+        // H.264 H.7.3.2.1.4
+        if ext.views.is_empty() || ext.level_values.is_empty() {
+            return Err(SynthesizerError::Unsupported);
+        }
+
+        self.ue(ext.views.len() as u32 - 1)?;
+        for view in &ext.views {
+            self.ue(view.view_id)?;
+        }
+
+        for view in &ext.views[1..] {
+            for refs in [&view.anchor_refs_l0, &view.anchor_refs_l1] {
+                self.ue(refs.len() as u32)?;
+                for view_id in refs {
+                    self.ue(*view_id)?;
+                }
+            }
+        }
+
+        for view in &ext.views[1..] {
+            for refs in [&view.non_anchor_refs_l0, &view.non_anchor_refs_l1] {
+                self.ue(refs.len() as u32)?;
+                for view_id in refs {
+                    self.ue(*view_id)?;
+                }
+            }
+        }
+
+        self.ue(ext.level_values.len() as u32 - 1)?;
+        for level_value in &ext.level_values {
+            if level_value.operation_points.is_empty() {
+                return Err(SynthesizerError::Unsupported);
+            }
+
+            self.u(8, level_value.level_idc)?;
+            self.ue(level_value.operation_points.len() as u32 - 1)?;
+            for op in &level_value.operation_points {
+                if op.target_view_ids.is_empty() {
+                    return Err(SynthesizerError::Unsupported);
+                }
+
+                self.u(3, op.temporal_id)?;
+                self.ue(op.target_view_ids.len() as u32 - 1)?;
+                for view_id in &op.target_view_ids {
+                    self.ue(*view_id)?;
+                }
+                self.ue(op.num_views_minus1)?;
+            }
+        }
+
+        Ok(())
+    }
+
     fn hrd_parameters(&mut self, hrd_params: &HrdParams) -> SynthesizerResult<()> {
+        // H.264 E.1.2
+        if hrd_params.cpb_cnt_minus1 > 31
+            || hrd_params.bit_rate_scale > 15
+            || hrd_params.cpb_size_scale > 15
+            || hrd_params.initial_cpb_removal_delay_length_minus1 > 31
+            || hrd_params.cpb_removal_delay_length_minus1 > 31
+            || hrd_params.dpb_output_delay_length_minus1 > 31
+            || hrd_params.time_offset_length > 31
+        {
+            return Err(SynthesizerError::Unsupported);
+        }
+
         self.ue(hrd_params.cpb_cnt_minus1)?;
         self.u(4, hrd_params.bit_rate_scale)?;
         self.u(4, hrd_params.cpb_size_scale)?;
@@ -286,28 +457,18 @@ impl<'n, W: Write> Synthesizer<'n, Sps, W> {
                     12
                 };
 
-                for i in 0..scaling_list_count {
-                    // Assume if scaling lists are zeroed that they are not present.
-                    if i < 6 {
-                        if self.nalu.scaling_lists_4x4[i] == [0; 16] {
-                            self.u(1, /* seq_scaling_list_present_flag */ false)?;
-                        } else {
-                            self.u(1, /* seq_scaling_list_present_flag */ true)?;
-                            self.scaling_list(
-                                &self.nalu.scaling_lists_4x4[i],
-                                Self::default_scaling_list(i),
-                            )?;
-                        }
-                    } else if self.nalu.scaling_lists_8x8[i - 6] == [0; 64] {
-                        self.u(1, /* seq_scaling_list_present_flag */ false)?;
-                    } else {
-                        self.u(1, /* seq_scaling_list_present_flag */ true)?;
-                        self.scaling_list(
-                            &self.nalu.scaling_lists_8x8[i - 6],
-                            Self::default_scaling_list(i),
-                        )?;
-                    }
-                }
+                // Table 7-2: Fall-back rule A
+                self.scaling_matrix(
+                    &self.nalu.scaling_lists_4x4,
+                    &self.nalu.scaling_lists_8x8,
+                    scaling_list_count,
+                    [
+                        &DEFAULT_4X4_INTRA,
+                        &DEFAULT_4X4_INTER,
+                        &DEFAULT_8X8_INTRA,
+                        &DEFAULT_8X8_INTER,
+                    ],
+                )?;
             }
         }
 
@@ -362,7 +523,7 @@ impl<'n, W: Write> Synthesizer<'n, Pps, W> {
         ep_enabled: bool,
     ) -> SynthesizerResult<()> {
         let mut s = Self {
-            writer: NaluWriter::<'n, W>::new(writer, ep_enabled),
+            writer: BitWriter::<'n, W>::new(writer, ep_enabled),
             nalu: pps,
         };
 
@@ -413,45 +574,513 @@ impl<'n, W: Write> Synthesizer<'n, Pps, W> {
                 }
             }
 
-            for i in 0..scaling_list_count {
-                // Assume if scaling lists are zeroed that they are not present.
-                if i < 6 {
-                    if self.nalu.scaling_lists_4x4[i] == [0; 16] {
-                        self.u(1, /* seq_scaling_list_present_flag */ false)?;
-                    } else {
-                        self.u(1, /* seq_scaling_list_present_flag */ true)?;
-                        self.scaling_list(
-                            &self.nalu.scaling_lists_4x4[i],
-                            Self::default_scaling_list(i),
-                        )?;
+            let sps = &self.nalu.sps;
+            let rule_a: [&[u8]; 4] = [
+                &DEFAULT_4X4_INTRA,
+                &DEFAULT_4X4_INTER,
+                &DEFAULT_8X8_INTRA,
+                &DEFAULT_8X8_INTER,
+            ];
+
+            // Table 7-2: Fall-back rule B refers to the SPS lists as inferred
+            // by the decoder.
+            let sps_num_lists = if sps.chroma_format_idc != 3 { 8 } else { 12 };
+            let (_, sps_4x4, sps_8x8) = Self::infer_scaling_matrix(
+                &sps.scaling_lists_4x4,
+                &sps.scaling_lists_8x8,
+                sps_num_lists,
+                rule_a,
+            );
+
+            let fallback: [&[u8]; 4] = if !sps.seq_scaling_matrix_present_flag {
+                // Table 7-2: Fall-back rule A
+                rule_a
+            } else {
+                // Table 7-2: Fall-back rule B
+                [&sps_4x4[0], &sps_4x4[3], &sps_8x8[0], &sps_8x8[1]]
+            };
+
+            self.scaling_matrix(
+                &self.nalu.scaling_lists_4x4,
+                &self.nalu.scaling_lists_8x8,
+                scaling_list_count,
+                fallback,
+            )?;
+        }
+
+        self.se(self.nalu.second_chroma_qp_index_offset)?;
+
+        Ok(())
+    }
+}
+
+impl<'n, W: Write> Synthesizer<'n, Sei, W> {
+    /// Synthesizes a SEI NALU containing `sei`'s messages. `sps` provides the
+    /// HRD and VUI parameters the buffering period and picture timing messages
+    /// depend on.
+    pub fn synthesize(
+        ref_idc: u8,
+        sei: &'n Sei,
+        sps: &Sps,
+        writer: &'n mut W,
+        ep_enabled: bool,
+    ) -> SynthesizerResult<()> {
+        let mut s = Self {
+            writer: BitWriter::<'n, W>::new(writer, ep_enabled),
+            nalu: sei,
+        };
+
+        s.writer.write_header(ref_idc, NaluType::Sei as u8)?;
+
+        // H.264 7.3.2.3.1
+        for message in &s.nalu.messages {
+            let mut payload = Vec::<u8>::new();
+            Synthesizer::<'_, SeiMessage, _>::sei_payload(message, sps, &mut payload)?;
+
+            s.sei_value(message.payload_type())?;
+            s.sei_value(payload.len() as u32)?;
+            s.writer.write_bytes(&payload)?;
+        }
+
+        s.rbsp_trailing_bits()
+    }
+
+    /// Writes a `payloadType` or `payloadSize` value.
+    fn sei_value(&mut self, mut value: u32) -> SynthesizerResult<()> {
+        while value >= 0xff {
+            self.u(8, 0xffu32)?;
+            value -= 0xff;
+        }
+
+        self.u(8, value)
+    }
+}
+
+impl<'n, W: Write> Synthesizer<'n, SeiMessage, W> {
+    /// Writes the payload of `message` without emulation prevention, so its
+    /// size can be known before it is written into the SEI NALU.
+    fn sei_payload(message: &'n SeiMessage, sps: &Sps, writer: &'n mut W) -> SynthesizerResult<()> {
+        let mut s = Self {
+            writer: BitWriter::<'n, W>::new(writer, false),
+            nalu: message,
+        };
+
+        match s.nalu {
+            SeiMessage::BufferingPeriod(bp) => {
+                // H.264 D.1.2
+                s.ue(bp.seq_parameter_set_id)?;
+
+                let vui = &sps.vui_parameters;
+                for (present, hrd, delays, offsets) in [
+                    (
+                        vui.nal_hrd_parameters_present_flag,
+                        &vui.nal_hrd_parameters,
+                        &bp.nal_initial_cpb_removal_delay,
+                        &bp.nal_initial_cpb_removal_delay_offset,
+                    ),
+                    (
+                        vui.vcl_hrd_parameters_present_flag,
+                        &vui.vcl_hrd_parameters,
+                        &bp.vcl_initial_cpb_removal_delay,
+                        &bp.vcl_initial_cpb_removal_delay_offset,
+                    ),
+                ] {
+                    if !present {
+                        continue;
                     }
-                } else if self.nalu.scaling_lists_8x8[i - 6] == [0; 64] {
-                    self.u(1, /* seq_scaling_list_present_flag */ false)?;
-                } else {
-                    self.u(1, /* seq_scaling_list_present_flag */ true)?;
-                    self.scaling_list(
-                        &self.nalu.scaling_lists_8x8[i - 6],
-                        Self::default_scaling_list(i),
-                    )?;
+
+                    let len = usize::from(hrd.initial_cpb_removal_delay_length_minus1) + 1;
+                    for i in 0..=usize::from(hrd.cpb_cnt_minus1) {
+                        let delay = delays.get(i).ok_or(SynthesizerError::Unsupported)?;
+                        let offset = offsets.get(i).ok_or(SynthesizerError::Unsupported)?;
+                        s.u(len, *delay)?;
+                        s.u(len, *offset)?;
+                    }
+                }
+            }
+            SeiMessage::PicTiming(pt) => s.pic_timing(pt, sps)?,
+            SeiMessage::UserDataUnregistered(data) => {
+                // H.264 D.1.6
+                for byte in data.uuid_iso_iec_11578.iter().chain(data.payload.iter()) {
+                    s.u(8, *byte)?;
+                }
+            }
+            SeiMessage::RecoveryPoint(rp) => {
+                // H.264 D.1.7
+                s.ue(rp.recovery_frame_cnt)?;
+                s.u(1, rp.exact_match_flag)?;
+                s.u(1, rp.broken_link_flag)?;
+                s.u(2, rp.changing_slice_group_idc)?;
+            }
+            SeiMessage::FramePackingArrangement(fpa) => {
+                // H.264 D.1.26
+                s.ue(fpa.frame_packing_arrangement_id)?;
+                s.u(1, fpa.frame_packing_arrangement_cancel_flag)?;
+
+                if !fpa.frame_packing_arrangement_cancel_flag {
+                    s.u(7, fpa.frame_packing_arrangement_type)?;
+                    s.u(1, fpa.quincunx_sampling_flag)?;
+                    s.u(6, fpa.content_interpretation_type)?;
+                    s.u(1, fpa.spatial_flipping_flag)?;
+                    s.u(1, fpa.frame0_flipped_flag)?;
+                    s.u(1, fpa.field_views_flag)?;
+                    s.u(1, fpa.current_frame_is_frame0_flag)?;
+                    s.u(1, fpa.frame0_self_contained_flag)?;
+                    s.u(1, fpa.frame1_self_contained_flag)?;
+
+                    if !fpa.quincunx_sampling_flag && fpa.frame_packing_arrangement_type != 5 {
+                        s.u(4, fpa.frame0_grid_position_x)?;
+                        s.u(4, fpa.frame0_grid_position_y)?;
+                        s.u(4, fpa.frame1_grid_position_x)?;
+                        s.u(4, fpa.frame1_grid_position_y)?;
+                    }
+
+                    s.u(8, /* frame_packing_arrangement_reserved_byte */ 0u32)?;
+                    s.ue(fpa.frame_packing_arrangement_repetition_period)?;
+                }
+
+                s.u(1, fpa.frame_packing_arrangement_extension_flag)?;
+            }
+            SeiMessage::Unknown { payload, .. } => {
+                for byte in payload {
+                    s.u(8, *byte)?;
                 }
             }
         }
 
-        self.se(self.nalu.second_chroma_qp_index_offset)?;
+        // H.264 D.1.1
+        if !s.writer.aligned() {
+            s.f(1, /* bit_equal_to_one */ 1u32)?;
+            while !s.writer.aligned() {
+                s.f(1, /* bit_equal_to_zero */ 0u32)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn pic_timing(&mut self, pt: &PicTiming, sps: &Sps) -> SynthesizerResult<()> {
+        // H.264 D.1.3
+        let vui = &sps.vui_parameters;
+        let hrd = if vui.nal_hrd_parameters_present_flag {
+            Some(&vui.nal_hrd_parameters)
+        } else if vui.vcl_hrd_parameters_present_flag {
+            Some(&vui.vcl_hrd_parameters)
+        } else {
+            None
+        };
+
+        if let Some(hrd) = hrd {
+            self.u(
+                usize::from(hrd.cpb_removal_delay_length_minus1) + 1,
+                pt.cpb_removal_delay,
+            )?;
+            self.u(
+                usize::from(hrd.dpb_output_delay_length_minus1) + 1,
+                pt.dpb_output_delay,
+            )?;
+        }
+
+        if !vui.pic_struct_present_flag {
+            return Ok(());
+        }
+
+        self.u(4, pt.pic_struct)?;
+        // time_offset_length is inferred to be 24 in the absence of HRD parameters.
+        let time_offset_length = hrd.map_or(24, |hrd| usize::from(hrd.time_offset_length));
+
+        for ct in pt
+            .clock_timestamps
+            .iter()
+            .take(PicTiming::num_clock_ts(pt.pic_struct))
+        {
+            self.u(1, /* clock_timestamp_flag */ ct.is_some())?;
+            let Some(ct) = ct else {
+                continue;
+            };
+
+            self.u(2, ct.ct_type)?;
+            self.u(1, ct.nuit_field_based_flag)?;
+            self.u(5, ct.counting_type)?;
+            self.u(1, ct.full_timestamp_flag)?;
+            self.u(1, ct.discontinuity_flag)?;
+            self.u(1, ct.cnt_dropped_flag)?;
+            self.u(8, ct.n_frames)?;
+
+            if ct.full_timestamp_flag {
+                self.u(6, ct.seconds_value)?;
+                self.u(6, ct.minutes_value)?;
+                self.u(5, ct.hours_value)?;
+            } else {
+                self.u(1, ct.seconds_flag)?;
+                if ct.seconds_flag {
+                    self.u(6, ct.seconds_value)?;
+                    self.u(1, ct.minutes_flag)?;
+                    if ct.minutes_flag {
+                        self.u(6, ct.minutes_value)?;
+                        self.u(1, ct.hours_flag)?;
+                        if ct.hours_flag {
+                            self.u(5, ct.hours_value)?;
+                        }
+                    }
+                }
+            }
+
+            if time_offset_length > 0 {
+                let mask = u32::MAX >> (32 - time_offset_length);
+                self.u(time_offset_length, ct.time_offset as u32 & mask)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl<'n, W: Write> Synthesizer<'n, SliceHeader, W> {
+    /// Synthesizes the NAL unit header and `header` of a slice of `pps`, without the slice data.
+    /// Returns the size of the output in bits, the last byte being padded with zeros when the
+    /// header does not end on a byte boundary, as expected by the encoders appending the slice
+    /// data to a packed slice header.
+    pub fn synthesize(
+        ref_idc: u8,
+        nalu_type: NaluType,
+        header: &'n SliceHeader,
+        pps: &Pps,
+        writer: &'n mut W,
+        ep_enabled: bool,
+    ) -> SynthesizerResult<usize> {
+        if !matches!(nalu_type, NaluType::Slice | NaluType::SliceIdr) {
+            return Err(SynthesizerError::Unsupported);
+        }
+
+        let mut data = Vec::new();
+        let padding = {
+            let mut s = Synthesizer {
+                writer: BitWriter::new(&mut data, ep_enabled),
+                nalu: header,
+            };
+
+            s.writer.write_header(ref_idc, nalu_type as u8)?;
+            s.slice_header(ref_idc, nalu_type == NaluType::SliceIdr, pps)?;
+            (8 - s.writer.pending_bits()) % 8
+        };
+        writer.write_all(&data).map_err(BitWriterError::from)?;
+
+        Ok(data.len() * 8 - padding)
+    }
+
+    fn slice_header(&mut self, ref_idc: u8, idr: bool, pps: &Pps) -> SynthesizerResult<()> {
+        // H.264 7.3.3
+        let header = self.nalu;
+        let sps = &pps.sps;
+
+        // Only the slices of frames are supported.
+        if pps.num_slice_groups_minus1 > 0 || header.field_pic_flag {
+            return Err(SynthesizerError::Unsupported);
+        }
+
+        self.ue(header.first_mb_in_slice)?;
+        self.ue(header.slice_type as u8)?;
+        self.ue(header.pic_parameter_set_id)?;
+
+        if sps.separate_colour_plane_flag {
+            self.u(2, header.colour_plane_id)?;
+        }
+
+        self.u(
+            usize::from(sps.log2_max_frame_num_minus4) + 4,
+            header.frame_num,
+        )?;
+
+        if !sps.frame_mbs_only_flag {
+            self.u(1, /* field_pic_flag */ false)?;
+        }
+
+        if idr {
+            self.ue(header.idr_pic_id)?;
+        }
+
+        if sps.pic_order_cnt_type == 0 {
+            let bits = usize::from(sps.log2_max_pic_order_cnt_lsb_minus4) + 4;
+            self.u(bits, header.pic_order_cnt_lsb)?;
+            if pps.bottom_field_pic_order_in_frame_present_flag {
+                self.se(header.delta_pic_order_cnt_bottom)?;
+            }
+        }
+
+        if sps.pic_order_cnt_type == 1 && !sps.delta_pic_order_always_zero_flag {
+            self.se(header.delta_pic_order_cnt[0])?;
+            if pps.bottom_field_pic_order_in_frame_present_flag {
+                self.se(header.delta_pic_order_cnt[1])?;
+            }
+        }
+
+        if pps.redundant_pic_cnt_present_flag {
+            self.ue(header.redundant_pic_cnt)?;
+        }
+
+        let slice_type = header.slice_type;
+        if slice_type.is_b() {
+            self.u(1, header.direct_spatial_mv_pred_flag)?;
+        }
+
+        if slice_type.is_p() || slice_type.is_sp() || slice_type.is_b() {
+            self.u(1, header.num_ref_idx_active_override_flag)?;
+            if header.num_ref_idx_active_override_flag {
+                self.ue(header.num_ref_idx_l0_active_minus1)?;
+                if slice_type.is_b() {
+                    self.ue(header.num_ref_idx_l1_active_minus1)?;
+                }
+            }
+        }
+
+        if !slice_type.is_i() && !slice_type.is_si() {
+            self.ref_pic_list_modification(
+                header.ref_pic_list_modification_flag_l0,
+                &header.ref_pic_list_modification_l0,
+            )?;
+        }
+        if slice_type.is_b() {
+            self.ref_pic_list_modification(
+                header.ref_pic_list_modification_flag_l1,
+                &header.ref_pic_list_modification_l1,
+            )?;
+        }
+
+        if (pps.weighted_pred_flag && (slice_type.is_p() || slice_type.is_sp()))
+            || (pps.weighted_bipred_idc == 1 && slice_type.is_b())
+        {
+            return Err(SynthesizerError::Unsupported);
+        }
+
+        if ref_idc != 0 {
+            self.dec_ref_pic_marking(idr)?;
+        }
+
+        if pps.entropy_coding_mode_flag && !slice_type.is_i() && !slice_type.is_si() {
+            self.ue(header.cabac_init_idc)?;
+        }
+
+        self.se(header.slice_qp_delta)?;
+
+        if slice_type.is_sp() || slice_type.is_si() {
+            if slice_type.is_sp() {
+                self.u(1, header.sp_for_switch_flag)?;
+            }
+            self.se(header.slice_qs_delta)?;
+        }
+
+        if pps.deblocking_filter_control_present_flag {
+            self.ue(header.disable_deblocking_filter_idc)?;
+            if header.disable_deblocking_filter_idc != 1 {
+                self.se(header.slice_alpha_c0_offset_div2)?;
+                self.se(header.slice_beta_offset_div2)?;
+            }
+        }
 
         Ok(())
     }
+
+    fn ref_pic_list_modification(
+        &mut self,
+        flag: bool,
+        modifications: &[RefPicListModification],
+    ) -> SynthesizerResult<()> {
+        // H.264 7.3.3.1
+        self.u(1, flag)?;
+        if !flag {
+            return Ok(());
+        }
+
+        for modification in modifications {
+            match modification.modification_of_pic_nums_idc {
+                idc @ (0 | 1) => {
+                    self.ue(idc)?;
+                    self.ue(modification.abs_diff_pic_num_minus1)?;
+                }
+                2 => {
+                    self.ue(2u32)?;
+                    self.ue(modification.long_term_pic_num)?;
+                }
+                3 => break,
+                _ => return Err(SynthesizerError::Unsupported),
+            }
+        }
+
+        // end of the modifications
+        self.ue(3u32)
+    }
+
+    fn dec_ref_pic_marking(&mut self, idr: bool) -> SynthesizerResult<()> {
+        // H.264 7.3.3.3
+        let marking = &self.nalu.dec_ref_pic_marking;
+
+        if idr {
+            self.u(1, marking.no_output_of_prior_pics_flag)?;
+            self.u(1, marking.long_term_reference_flag)?;
+            return Ok(());
+        }
+
+        self.u(1, marking.adaptive_ref_pic_marking_mode_flag)?;
+        if !marking.adaptive_ref_pic_marking_mode_flag {
+            return Ok(());
+        }
+
+        for operation in &marking.inner {
+            let mmco = operation.memory_management_control_operation;
+            if mmco == 0 {
+                break;
+            }
+            if mmco > 6 {
+                return Err(SynthesizerError::Unsupported);
+            }
+
+            self.ue(mmco)?;
+            if mmco == 1 || mmco == 3 {
+                self.ue(operation.difference_of_pic_nums_minus1)?;
+            }
+            if mmco == 2 {
+                self.ue(operation.long_term_pic_num)?;
+            }
+            if mmco == 3 || mmco == 6 {
+                self.ue(operation.long_term_frame_idx)?;
+            }
+            if mmco == 4 {
+                self.ue(operation.max_long_term_frame_idx.to_value_plus1())?;
+            }
+        }
+
+        // end of the operations
+        self.ue(0u32)
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use std::io::Cursor;
+    use std::rc::Rc;
 
     use super::*;
+    use crate::codec::h264::parser::BufferingPeriod;
+    use crate::codec::h264::parser::ClockTimestamp;
+    use crate::codec::h264::parser::FramePackingArrangement;
+    use crate::codec::h264::parser::MaxLongTermFrameIdx;
+    use crate::codec::h264::parser::MvcLevelValue;
+    use crate::codec::h264::parser::MvcOperationPoint;
+    use crate::codec::h264::parser::MvcView;
     use crate::codec::h264::parser::Nalu;
     use crate::codec::h264::parser::NaluType;
     use crate::codec::h264::parser::Parser;
+    use crate::codec::h264::parser::PpsBuilder;
     use crate::codec::h264::parser::Profile;
+    use crate::codec::h264::parser::RecoveryPoint;
+    use crate::codec::h264::parser::RefPicMarking;
+    use crate::codec::h264::parser::RefPicMarkingInner;
+    use crate::codec::h264::parser::ScalingMatrixKind;
+    use crate::codec::h264::parser::SliceType;
+    use crate::codec::h264::parser::SpsBuilder;
+    use crate::codec::h264::parser::UserDataUnregistered;
+    use crate::codec::timecode::Timecode;
 
     #[test]
     fn synthesize_sps() {
@@ -537,6 +1166,203 @@ mod tests {
         assert_eq!(sps.scaling_lists_8x8, sps2.scaling_lists_8x8);
     }
 
+    #[test]
+    fn synthesize_pps_scaling_lists() {
+        let mut lists_4x4 = [[0u8; 16]; 6];
+        lists_4x4[0] = [
+            6, 13, 20, 28, 13, 20, 28, 32, 20, 28, 32, 37, 28, 32, 37, 42,
+        ];
+        lists_4x4[3] = [255; 16];
+        let mut lists_8x8 = [[0u8; 64]; 6];
+        lists_8x8[0] = DEFAULT_8X8_INTRA;
+        lists_8x8[1] = std::array::from_fn(|i| if i % 2 == 0 { 4 } else { 250 });
+
+        let sps = SpsBuilder::new()
+            .seq_parameter_set_id(0)
+            .profile_idc(Profile::High)
+            .resolution(320, 240)
+            .scaling_lists(lists_4x4, [[0; 64]; 6])
+            .build();
+        assert_eq!(sps.scaling_matrix_kind(), ScalingMatrixKind::Custom);
+
+        let mut pps_lists_4x4 = [[0u8; 16]; 6];
+        pps_lists_4x4[1] = [20; 16];
+        let pps = PpsBuilder::new(sps.clone())
+            .transform_8x8_mode_flag(true)
+            .scaling_lists(pps_lists_4x4, lists_8x8)
+            .build();
+
+        let mut buf = Vec::<u8>::new();
+        Synthesizer::<'_, Sps, _>::synthesize(3, &sps, &mut buf, true).unwrap();
+        Synthesizer::<'_, Pps, _>::synthesize(3, &pps, &mut buf, true).unwrap();
+
+        let mut cursor = Cursor::new(&buf[..]);
+        let mut parser = Parser::default();
+
+        let nalu = Nalu::next(&mut cursor).unwrap();
+        let sps2 = parser.parse_sps(&nalu).unwrap().clone();
+        let nalu = Nalu::next(&mut cursor).unwrap();
+        let pps2 = parser.parse_pps(&nalu).unwrap();
+
+        // Absent SPS lists follow fall-back rule A.
+        assert_eq!(sps2.scaling_lists_4x4[0], lists_4x4[0]);
+        assert_eq!(sps2.scaling_lists_4x4[1], lists_4x4[0]);
+        assert_eq!(sps2.scaling_lists_4x4[2], lists_4x4[0]);
+        assert_eq!(sps2.scaling_lists_4x4[3], lists_4x4[3]);
+        assert_eq!(sps2.scaling_lists_4x4[5], lists_4x4[3]);
+        assert_eq!(sps2.scaling_lists_8x8[0], DEFAULT_8X8_INTRA);
+        assert_eq!(sps2.scaling_lists_8x8[1], DEFAULT_8X8_INTER);
+
+        // Absent PPS lists follow fall-back rule B.
+        assert_eq!(pps2.scaling_lists_4x4[0], lists_4x4[0]);
+        assert_eq!(pps2.scaling_lists_4x4[1], [20; 16]);
+        assert_eq!(pps2.scaling_lists_4x4[2], [20; 16]);
+        assert_eq!(pps2.scaling_lists_4x4[3], lists_4x4[3]);
+        assert_eq!(pps2.scaling_lists_8x8[0], lists_8x8[0]);
+        assert_eq!(pps2.scaling_lists_8x8[1], lists_8x8[1]);
+        assert_eq!(pps2.scaling_matrix_kind(), ScalingMatrixKind::Custom);
+
+        // Synthesizing the parsed parameter sets gives the same bitstream.
+        let mut buf2 = Vec::<u8>::new();
+        Synthesizer::<'_, Sps, _>::synthesize(3, &sps2, &mut buf2, true).unwrap();
+        Synthesizer::<'_, Pps, _>::synthesize(3, pps2, &mut buf2, true).unwrap();
+        assert_eq!(buf, buf2);
+    }
+
+    #[test]
+    fn scaling_matrix_kind() {
+        let sps = SpsBuilder::new().profile_idc(Profile::High).build();
+        assert_eq!(sps.scaling_matrix_kind(), ScalingMatrixKind::Flat);
+
+        let sps = SpsBuilder::new()
+            .profile_idc(Profile::High)
+            .scaling_lists(
+                [
+                    DEFAULT_4X4_INTRA,
+                    DEFAULT_4X4_INTRA,
+                    DEFAULT_4X4_INTRA,
+                    DEFAULT_4X4_INTER,
+                    DEFAULT_4X4_INTER,
+                    DEFAULT_4X4_INTER,
+                ],
+                [
+                    DEFAULT_8X8_INTRA,
+                    DEFAULT_8X8_INTER,
+                    [0; 64],
+                    [0; 64],
+                    [0; 64],
+                    [0; 64],
+                ],
+            )
+            .build();
+        assert_eq!(sps.scaling_matrix_kind(), ScalingMatrixKind::Default);
+
+        let pps = PpsBuilder::new(sps)
+            .scaling_lists([[16; 16]; 6], [[0; 64]; 6])
+            .build();
+        assert_eq!(pps.scaling_matrix_kind(), ScalingMatrixKind::Flat);
+    }
+
+    #[test]
+    fn synthesize_subset_sps() {
+        let mut sps = Rc::try_unwrap(
+            SpsBuilder::new()
+                .seq_parameter_set_id(1)
+                .profile_idc(Profile::High)
+                .frame_mbs_only_flag(true)
+                .resolution(1920, 1080)
+                .build(),
+        )
+        .unwrap();
+        // Stereo High
+        sps.profile_idc = 118;
+        sps.mvc_extension = Some(SpsMvcExtension {
+            views: vec![
+                MvcView {
+                    view_id: 0,
+                    ..Default::default()
+                },
+                MvcView {
+                    view_id: 1,
+                    anchor_refs_l0: vec![0],
+                    non_anchor_refs_l0: vec![0],
+                    ..Default::default()
+                },
+            ],
+            level_values: vec![MvcLevelValue {
+                level_idc: 41,
+                operation_points: vec![MvcOperationPoint {
+                    temporal_id: 0,
+                    target_view_ids: vec![1],
+                    num_views_minus1: 1,
+                }],
+            }],
+        });
+
+        let mut buf = Vec::<u8>::new();
+        Synthesizer::<'_, Sps, _>::synthesize(3, &sps, &mut buf, true).unwrap();
+
+        let mut cursor = Cursor::new(&buf[..]);
+        let nalu = Nalu::next(&mut cursor).unwrap();
+        assert_eq!(nalu.header.type_, NaluType::SubsetSps);
+
+        let mut parser = Parser::default();
+        let sps2 = parser.parse_subset_sps(&nalu).unwrap();
+        assert_eq!(sps2.profile_idc, 118);
+        assert_eq!((sps2.width, sps2.height), (1920, 1088));
+        assert_eq!(sps.mvc_extension, sps2.mvc_extension);
+
+        // Subset SPSes do not replace the SPS of the same id.
+        assert!(parser.get_sps(1).is_none());
+        assert!(parser.get_subset_sps(1).is_some());
+    }
+
+    #[test]
+    fn synthesize_sps_vui_hrd() {
+        let mut vcl_hrd = HrdParams::single_cpb(1_000_000, 2_000_000, false);
+        vcl_hrd.cpb_cnt_minus1 = 1;
+        vcl_hrd.bit_rate_value_minus1[1] = 31_249;
+        vcl_hrd.cpb_size_value_minus1[1] = 62_499;
+        vcl_hrd.time_offset_length = 0;
+
+        let sps = SpsBuilder::new()
+            .seq_parameter_set_id(0)
+            .profile_idc(Profile::High)
+            .resolution(1920, 1080)
+            .aspect_ratio(16, 11)
+            .timing_info(1, 60, true)
+            .colour_description(1, 1, 1)
+            .chroma_loc_info(1, 1)
+            .nal_hrd_parameters(HrdParams::single_cpb(5_000_000, 10_000_000, true))
+            .vcl_hrd_parameters(vcl_hrd)
+            .low_delay_hrd_flag(true)
+            .pic_struct_present_flag(true)
+            .bitstream_restriction(0, 4)
+            .build();
+
+        let hrd = &sps.vui_parameters.nal_hrd_parameters;
+        assert_eq!(hrd.bit_rate(0), 5_000_000);
+        assert_eq!(hrd.cpb_size(0), 10_000_000);
+
+        let mut buf = Vec::<u8>::new();
+        Synthesizer::<'_, Sps, _>::synthesize(3, &sps, &mut buf, true).unwrap();
+
+        let mut cursor = Cursor::new(&buf[..]);
+        let nalu = Nalu::next(&mut cursor).unwrap();
+        let mut parser = Parser::default();
+        let sps2 = parser.parse_sps(&nalu).unwrap();
+
+        assert!(sps2.vui_parameters_present_flag);
+        assert_eq!(sps.vui_parameters, sps2.vui_parameters);
+
+        let mut sps = Rc::try_unwrap(sps).unwrap();
+        sps.vui_parameters.nal_hrd_parameters.cpb_cnt_minus1 = 32;
+        assert!(matches!(
+            Synthesizer::<'_, Sps, _>::synthesize(3, &sps, &mut Vec::new(), true),
+            Err(SynthesizerError::Unsupported)
+        ));
+    }
+
     #[test]
     fn synthesize_pps() {
         let raw_sps_pps = [
@@ -578,4 +1404,221 @@ mod tests {
 
         assert_eq!(buf, raw_sps_pps);
     }
+
+    #[test]
+    fn synthesize_sei() {
+        let sps = SpsBuilder::new()
+            .seq_parameter_set_id(0)
+            .profile_idc(Profile::Main)
+            .resolution(320, 240)
+            .vui_parameters_present()
+            .build();
+        let mut sps = Rc::try_unwrap(sps).unwrap();
+        sps.vui_parameters.nal_hrd_parameters_present_flag = true;
+        sps.vui_parameters.nal_hrd_parameters.cpb_cnt_minus1 = 1;
+        sps.vui_parameters
+            .nal_hrd_parameters
+            .initial_cpb_removal_delay_length_minus1 = 31;
+        sps.vui_parameters
+            .nal_hrd_parameters
+            .cpb_removal_delay_length_minus1 = 15;
+        sps.vui_parameters
+            .nal_hrd_parameters
+            .dpb_output_delay_length_minus1 = 7;
+        sps.vui_parameters.nal_hrd_parameters.time_offset_length = 10;
+        sps.vui_parameters.pic_struct_present_flag = true;
+
+        let timecode = Timecode {
+            time_offset: 100,
+            ..Timecode::from_frame_number(1_000_000, 30, true).unwrap()
+        };
+
+        let sei = Sei {
+            messages: vec![
+                SeiMessage::BufferingPeriod(BufferingPeriod {
+                    seq_parameter_set_id: 0,
+                    nal_initial_cpb_removal_delay: vec![0xdeadbeef, 90000],
+                    nal_initial_cpb_removal_delay_offset: vec![0, 1],
+                    ..Default::default()
+                }),
+                SeiMessage::PicTiming(PicTiming {
+                    cpb_removal_delay: 0x1234,
+                    dpb_output_delay: 2,
+                    pic_struct: 5,
+                    clock_timestamps: [
+                        None,
+                        Some(ClockTimestamp {
+                            ct_type: 1,
+                            counting_type: 4,
+                            n_frames: 24,
+                            seconds_flag: true,
+                            seconds_value: 59,
+                            minutes_flag: true,
+                            minutes_value: 1,
+                            time_offset: -3,
+                            ..Default::default()
+                        }),
+                        Some(ClockTimestamp::try_from(&timecode).unwrap()),
+                    ],
+                }),
+                SeiMessage::RecoveryPoint(RecoveryPoint {
+                    recovery_frame_cnt: 7,
+                    exact_match_flag: true,
+                    ..Default::default()
+                }),
+                SeiMessage::UserDataUnregistered(UserDataUnregistered {
+                    uuid_iso_iec_11578: [0xaa; 16],
+                    // Long enough for payloadSize to span several bytes, and
+                    // requiring emulation prevention.
+                    payload: [0u8; 300].to_vec(),
+                }),
+                SeiMessage::FramePackingArrangement(FramePackingArrangement {
+                    frame_packing_arrangement_id: 1,
+                    frame_packing_arrangement_type: 3,
+                    content_interpretation_type: 1,
+                    frame1_grid_position_x: 8,
+                    frame_packing_arrangement_repetition_period: 1,
+                    ..Default::default()
+                }),
+                SeiMessage::Unknown {
+                    payload_type: 300,
+                    payload: vec![1, 2, 3],
+                },
+            ],
+        };
+
+        let mut buf = Vec::<u8>::new();
+        Synthesizer::<'_, Sps, _>::synthesize(3, &sps, &mut buf, true).unwrap();
+        Synthesizer::<'_, Sei, _>::synthesize(0, &sei, &sps, &mut buf, true).unwrap();
+
+        let mut cursor = Cursor::new(&buf[..]);
+        let mut parser = Parser::default();
+
+        let nalu = Nalu::next(&mut cursor).unwrap();
+        parser.parse_sps(&nalu).unwrap();
+
+        let nalu = Nalu::next(&mut cursor).unwrap();
+        assert_eq!(nalu.header.type_, NaluType::Sei);
+        let sei2 = parser.parse_sei(&nalu).unwrap();
+
+        assert_eq!(sei, sei2);
+
+        let SeiMessage::PicTiming(pt) = &sei2.messages[1] else {
+            panic!("picture timing message expected");
+        };
+        // The hours of the partial timestamp come from the previous timecode.
+        let partial = pt.timecode(Some(&timecode)).unwrap();
+        assert_eq!(partial.to_string(), "09:01:59;24");
+        assert_eq!(partial.time_offset, -3);
+        assert_eq!(
+            pt.clock_timestamps[2].as_ref().unwrap().timecode(None),
+            timecode
+        );
+    }
+
+    #[test]
+    fn synthesize_slice_header() {
+        let sps = SpsBuilder::new()
+            .seq_parameter_set_id(0)
+            .profile_idc(Profile::Main)
+            .max_frame_num(16)
+            .pic_order_cnt_type(0)
+            .max_pic_order_cnt_lsb(32)
+            .max_num_ref_frames(4)
+            .resolution(320, 240)
+            .build();
+        let pps = PpsBuilder::new(Rc::clone(&sps))
+            .pic_parameter_set_id(0)
+            .deblocking_filter_control_present_flag(true)
+            .build();
+
+        let idr = SliceHeader {
+            slice_type: SliceType::I,
+            idr_pic_id: 1,
+            dec_ref_pic_marking: RefPicMarking {
+                long_term_reference_flag: true,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let modification =
+            |idc, abs_diff_pic_num_minus1, long_term_pic_num| RefPicListModification {
+                modification_of_pic_nums_idc: idc,
+                abs_diff_pic_num_minus1,
+                long_term_pic_num,
+                ..Default::default()
+            };
+        let marking = |mmco, long_term_frame_idx, max_long_term_frame_idx| RefPicMarkingInner {
+            memory_management_control_operation: mmco,
+            long_term_frame_idx,
+            max_long_term_frame_idx,
+            ..Default::default()
+        };
+        let p = SliceHeader {
+            slice_type: SliceType::P,
+            frame_num: 5,
+            pic_order_cnt_lsb: 10,
+            num_ref_idx_active_override_flag: true,
+            num_ref_idx_l0_active_minus1: 1,
+            ref_pic_list_modification_flag_l0: true,
+            ref_pic_list_modification_l0: vec![
+                modification(0, 0, 0),
+                modification(2, 0, 1),
+                modification(3, 0, 0),
+            ],
+            dec_ref_pic_marking: RefPicMarking {
+                adaptive_ref_pic_marking_mode_flag: true,
+                inner: vec![
+                    marking(4, 0, MaxLongTermFrameIdx::Idx(1)),
+                    marking(6, 1, Default::default()),
+                    marking(0, 0, Default::default()),
+                ],
+                ..Default::default()
+            },
+            slice_qp_delta: -3,
+            ..Default::default()
+        };
+
+        let mut buf = Vec::<u8>::new();
+        Synthesizer::<'_, Sps, _>::synthesize(3, &sps, &mut buf, true).unwrap();
+        Synthesizer::<'_, Pps, _>::synthesize(3, &pps, &mut buf, true).unwrap();
+        for (nalu_type, header) in [(NaluType::SliceIdr, &idr), (NaluType::Slice, &p)] {
+            let mut slice = Vec::new();
+            let bits = Synthesizer::<'_, SliceHeader, _>::synthesize(
+                2, nalu_type, header, &pps, &mut slice, true,
+            )
+            .unwrap();
+            assert_eq!(bits.div_ceil(8), slice.len());
+            buf.extend(slice);
+        }
+
+        let mut cursor = Cursor::new(&buf[..]);
+        let mut parser = Parser::default();
+        parser.parse_sps(&Nalu::next(&mut cursor).unwrap()).unwrap();
+        parser.parse_pps(&Nalu::next(&mut cursor).unwrap()).unwrap();
+
+        let slice = parser
+            .parse_slice_header(Nalu::next(&mut cursor).unwrap())
+            .unwrap();
+        assert!(slice.nalu.header.idr_pic_flag);
+        assert_eq!(slice.header.idr_pic_id, 1);
+        assert_eq!(slice.header.dec_ref_pic_marking, idr.dec_ref_pic_marking);
+
+        let slice = parser
+            .parse_slice_header(Nalu::next(&mut cursor).unwrap())
+            .unwrap();
+        assert_eq!(slice.header.frame_num, 5);
+        assert_eq!(slice.header.pic_order_cnt_lsb, 10);
+        assert_eq!(slice.header.num_ref_idx_l0_active_minus1, 1);
+        assert_eq!(
+            slice.header.ref_pic_list_modification_l0,
+            p.ref_pic_list_modification_l0
+        );
+        // The parser does not keep the end of the operations.
+        assert_eq!(
+            slice.header.dec_ref_pic_marking.inner,
+            p.dec_ref_pic_marking.inner[..2]
+        );
+        assert_eq!(slice.header.slice_qp_delta, -3);
+    }
 }
@@ -3,5 +3,7 @@
 // found in the LICENSE file.
 
 pub mod dpb;
+pub mod hvcc;
 pub mod parser;
 pub mod picture;
+pub mod synthesizer;
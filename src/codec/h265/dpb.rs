@@ -12,6 +12,7 @@ use anyhow::anyhow;
 use crate::codec::h265::parser::Sps;
 use crate::codec::h265::picture::PictureData;
 use crate::codec::h265::picture::Reference;
+use crate::instrument::trace_event;
 
 // Shortcut to refer to a DPB entry.
 //
@@ -166,7 +167,7 @@ impl<T: Clone> Dpb<T> {
 
         pic.needed_for_output = false;
         log::debug!("Bumping POC {} from the dpb", pic.pic_order_cnt_val);
-        log::trace!("{:#?}", pic);
+        trace_event!("{:#?}", pic);
 
         if !pic.is_ref() || flush {
             let index = self.get_position(&handle.0).unwrap();
@@ -177,7 +178,7 @@ impl<T: Clone> Dpb<T> {
                 pic.is_ref(),
                 flush
             );
-            log::trace!("{:#?}", pic);
+            trace_event!("{:#?}", pic);
 
             self.entries.remove(index);
         }
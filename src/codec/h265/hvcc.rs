@@ -0,0 +1,379 @@
+// Copyright 2024 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Support for the `hvcC` box of ISO/IEC 14496-15, which stores the parameter
+//! sets of length-prefixed H.265 streams, e.g. in MP4 files.
+
+use std::io::Cursor;
+use std::io::Write;
+
+use anyhow::anyhow;
+use bytes::Buf;
+
+use crate::codec::h264::nalu::split_annexb;
+use crate::codec::h265::parser::Nalu;
+use crate::codec::h265::parser::NaluType;
+use crate::codec::h265::parser::Parser;
+
+/// Size of the general part of profile_tier_level(), which is copied as-is in
+/// the record.
+const GENERAL_PTL_SIZE: usize = 12;
+
+/// Array of NAL units of the same type in the record.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct HvccArray {
+    /// Whether all the NAL units of this type are in the array, as opposed to
+    /// some of them being in the stream.
+    pub array_completeness: bool,
+    pub nal_unit_type: u8,
+    /// The NAL units, without start code nor length prefix.
+    pub nalus: Vec<Vec<u8>>,
+}
+
+/// HEVCDecoderConfigurationRecord, as per ISO/IEC 14496-15 8.3.3.1.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct HevcDecoderConfigurationRecord {
+    pub configuration_version: u8,
+    pub general_profile_space: u8,
+    pub general_tier_flag: bool,
+    pub general_profile_idc: u8,
+    pub general_profile_compatibility_flags: u32,
+    /// 48 bits.
+    pub general_constraint_indicator_flags: u64,
+    pub general_level_idc: u8,
+    pub min_spatial_segmentation_idc: u16,
+    pub parallelism_type: u8,
+    pub chroma_format_idc: u8,
+    pub bit_depth_luma_minus8: u8,
+    pub bit_depth_chroma_minus8: u8,
+    pub avg_frame_rate: u16,
+    pub constant_frame_rate: u8,
+    pub num_temporal_layers: u8,
+    pub temporal_id_nested: bool,
+    /// Size in bytes of the NAL unit length prefix, minus one.
+    pub length_size_minus_one: u8,
+    pub arrays: Vec<HvccArray>,
+}
+
+/// Removes the emulation prevention bytes from the start of `nalu`, up to
+/// `len` bytes of payload.
+fn unescape(nalu: &[u8], len: usize) -> Vec<u8> {
+    let mut output = Vec::with_capacity(len);
+    let mut zeros = 0;
+
+    for &byte in nalu {
+        if output.len() == len {
+            break;
+        }
+
+        if zeros >= 2 && byte == 0x03 {
+            zeros = 0;
+            continue;
+        }
+
+        zeros = if byte == 0 { zeros + 1 } else { 0 };
+        output.push(byte);
+    }
+
+    output
+}
+
+fn nalu_type(nalu: &[u8]) -> Option<u8> {
+    nalu.first().map(|b| (b >> 1) & 0x3f)
+}
+
+impl HevcDecoderConfigurationRecord {
+    /// Builds a record from the VPS, SPS and PPS NAL units of a stream, which
+    /// is to use a NAL unit length prefix of `length_size` bytes.
+    ///
+    /// The profile, tier, level and format information are taken from the
+    /// first SPS, and the parallelism type from the first PPS.
+    pub fn from_parameter_sets(
+        vps: Vec<Vec<u8>>,
+        sps: Vec<Vec<u8>>,
+        pps: Vec<Vec<u8>>,
+        length_size: usize,
+    ) -> anyhow::Result<Self> {
+        if !matches!(length_size, 1 | 2 | 4) {
+            return Err(anyhow!("Invalid NALU length size {}", length_size));
+        }
+
+        let first_sps = sps.first().ok_or(anyhow!("No SPS"))?;
+        // The general profile_tier_level() starts after the NAL unit header
+        // and the byte containing sps_video_parameter_set_id,
+        // sps_max_sub_layers_minus1 and sps_temporal_id_nesting_flag.
+        let header = unescape(first_sps, 3 + GENERAL_PTL_SIZE);
+        if header.len() < 3 + GENERAL_PTL_SIZE {
+            return Err(anyhow!("SPS is too short"));
+        }
+        let ptl = &header[3..];
+
+        let mut parser = Parser::default();
+        let annexb = [&[0x00, 0x00, 0x01], &first_sps[..]].concat();
+        let nalu = Nalu::next(&mut Cursor::new(&annexb[..]))?;
+        let parsed_sps = parser.parse_sps(&nalu)?;
+
+        let min_spatial_segmentation_idc = if parsed_sps.vui_parameters_present_flag
+            && parsed_sps.vui_parameters.bitstream_restriction_flag
+        {
+            u16::try_from(parsed_sps.vui_parameters.min_spatial_segmentation_idc)?
+        } else {
+            0
+        };
+
+        let mut record = Self {
+            configuration_version: 1,
+            general_profile_space: ptl[0] >> 6,
+            general_tier_flag: ptl[0] & 0x20 != 0,
+            general_profile_idc: ptl[0] & 0x1f,
+            general_profile_compatibility_flags: u32::from_be_bytes(ptl[1..5].try_into()?),
+            general_constraint_indicator_flags: ptl[5..11]
+                .iter()
+                .fold(0, |flags, &byte| (flags << 8) | u64::from(byte)),
+            general_level_idc: ptl[11],
+            min_spatial_segmentation_idc,
+            parallelism_type: 0,
+            chroma_format_idc: parsed_sps.chroma_format_idc,
+            bit_depth_luma_minus8: parsed_sps.bit_depth_luma_minus8,
+            bit_depth_chroma_minus8: parsed_sps.bit_depth_chroma_minus8,
+            avg_frame_rate: 0,
+            constant_frame_rate: 0,
+            num_temporal_layers: parsed_sps.max_sub_layers_minus1 + 1,
+            temporal_id_nested: parsed_sps.temporal_id_nesting_flag,
+            length_size_minus_one: (length_size - 1) as u8,
+            arrays: vec![],
+        };
+
+        if let Some(first_pps) = pps.first() {
+            let annexb = [&[0x00, 0x00, 0x01], &first_pps[..]].concat();
+            let nalu = Nalu::next(&mut Cursor::new(&annexb[..]))?;
+            let pps = parser.parse_pps(&nalu)?;
+
+            record.parallelism_type =
+                match (pps.entropy_coding_sync_enabled_flag, pps.tiles_enabled_flag) {
+                    (true, true) => 0,
+                    (true, false) => 3,
+                    (false, true) => 2,
+                    (false, false) => 1,
+                };
+        }
+
+        for (nal_unit_type, nalus) in [
+            (NaluType::VpsNut, vps),
+            (NaluType::SpsNut, sps),
+            (NaluType::PpsNut, pps),
+        ] {
+            if !nalus.is_empty() {
+                record.arrays.push(HvccArray {
+                    array_completeness: true,
+                    nal_unit_type: nal_unit_type as u8,
+                    nalus,
+                });
+            }
+        }
+
+        Ok(record)
+    }
+
+    /// Builds a record from the parameter sets found in the Annex B `data`,
+    /// e.g. the first output of an encoder. Repeated parameter sets are only
+    /// included once.
+    pub fn from_annexb(data: &[u8], length_size: usize) -> anyhow::Result<Self> {
+        let mut vps = vec![];
+        let mut sps = vec![];
+        let mut pps = vec![];
+
+        for nalu in split_annexb(data) {
+            let nalus = match nalu_type(&nalu) {
+                Some(t) if t == NaluType::VpsNut as u8 => &mut vps,
+                Some(t) if t == NaluType::SpsNut as u8 => &mut sps,
+                Some(t) if t == NaluType::PpsNut as u8 => &mut pps,
+                _ => continue,
+            };
+
+            if !nalus.contains(&nalu) {
+                nalus.push(nalu);
+            }
+        }
+
+        Self::from_parameter_sets(vps, sps, pps, length_size)
+    }
+
+    /// Size in bytes of the NAL unit length prefix.
+    pub fn nal_length_size(&self) -> usize {
+        usize::from(self.length_size_minus_one) + 1
+    }
+
+    /// Parses the contents of an `hvcC` box.
+    pub fn parse(data: &[u8]) -> anyhow::Result<Self> {
+        let mut cursor = Cursor::new(data);
+        if cursor.remaining() < 23 {
+            return Err(anyhow!("Truncated hvcC record"));
+        }
+
+        let configuration_version = cursor.get_u8();
+        if configuration_version != 1 {
+            return Err(anyhow!(
+                "Unsupported hvcC version {}",
+                configuration_version
+            ));
+        }
+
+        let byte = cursor.get_u8();
+        let general_profile_compatibility_flags = cursor.get_u32();
+        let general_constraint_indicator_flags = cursor.get_uint(6);
+        let general_level_idc = cursor.get_u8();
+        let min_spatial_segmentation_idc = cursor.get_u16() & 0x0fff;
+        let parallelism_type = cursor.get_u8() & 0x3;
+        let chroma_format_idc = cursor.get_u8() & 0x3;
+        let bit_depth_luma_minus8 = cursor.get_u8() & 0x7;
+        let bit_depth_chroma_minus8 = cursor.get_u8() & 0x7;
+        let avg_frame_rate = cursor.get_u16();
+        let flags = cursor.get_u8();
+        let num_arrays = cursor.get_u8();
+
+        let mut arrays = Vec::with_capacity(usize::from(num_arrays));
+        for _ in 0..num_arrays {
+            if cursor.remaining() < 3 {
+                return Err(anyhow!("Truncated hvcC record"));
+            }
+
+            let byte = cursor.get_u8();
+            let num_nalus = cursor.get_u16();
+            let mut nalus = Vec::with_capacity(usize::from(num_nalus));
+
+            for _ in 0..num_nalus {
+                if cursor.remaining() < 2 {
+                    return Err(anyhow!("Truncated hvcC record"));
+                }
+                let len = usize::from(cursor.get_u16());
+                if cursor.remaining() < len {
+                    return Err(anyhow!("Truncated hvcC record"));
+                }
+                nalus.push(cursor.chunk()[..len].to_vec());
+                cursor.advance(len);
+            }
+
+            arrays.push(HvccArray {
+                array_completeness: byte & 0x80 != 0,
+                nal_unit_type: byte & 0x3f,
+                nalus,
+            });
+        }
+
+        Ok(Self {
+            configuration_version,
+            general_profile_space: byte >> 6,
+            general_tier_flag: byte & 0x20 != 0,
+            general_profile_idc: byte & 0x1f,
+            general_profile_compatibility_flags,
+            general_constraint_indicator_flags,
+            general_level_idc,
+            min_spatial_segmentation_idc,
+            parallelism_type,
+            chroma_format_idc,
+            bit_depth_luma_minus8,
+            bit_depth_chroma_minus8,
+            avg_frame_rate,
+            constant_frame_rate: flags >> 6,
+            num_temporal_layers: (flags >> 3) & 0x7,
+            temporal_id_nested: flags & 0x4 != 0,
+            length_size_minus_one: flags & 0x3,
+            arrays,
+        })
+    }
+
+    /// Writes the contents of an `hvcC` box into `writer`.
+    pub fn write_into(&self, writer: &mut impl Write) -> anyhow::Result<()> {
+        let num_arrays = u8::try_from(self.arrays.len())?;
+
+        writer.write_all(&[
+            self.configuration_version,
+            (self.general_profile_space << 6)
+                | (u8::from(self.general_tier_flag) << 5)
+                | (self.general_profile_idc & 0x1f),
+        ])?;
+        writer.write_all(&self.general_profile_compatibility_flags.to_be_bytes())?;
+        writer.write_all(&self.general_constraint_indicator_flags.to_be_bytes()[2..])?;
+        writer.write_all(&[self.general_level_idc])?;
+        writer.write_all(&(0xf000 | self.min_spatial_segmentation_idc).to_be_bytes())?;
+        writer.write_all(&[
+            0xfc | (self.parallelism_type & 0x3),
+            0xfc | (self.chroma_format_idc & 0x3),
+            0xf8 | (self.bit_depth_luma_minus8 & 0x7),
+            0xf8 | (self.bit_depth_chroma_minus8 & 0x7),
+        ])?;
+        writer.write_all(&self.avg_frame_rate.to_be_bytes())?;
+        writer.write_all(&[
+            (self.constant_frame_rate << 6)
+                | ((self.num_temporal_layers & 0x7) << 3)
+                | (u8::from(self.temporal_id_nested) << 2)
+                | (self.length_size_minus_one & 0x3),
+            num_arrays,
+        ])?;
+
+        for array in &self.arrays {
+            writer.write_all(&[
+                (u8::from(array.array_completeness) << 7) | (array.nal_unit_type & 0x3f)
+            ])?;
+            writer.write_all(&u16::try_from(array.nalus.len())?.to_be_bytes())?;
+            for nalu in &array.nalus {
+                writer.write_all(&u16::try_from(nalu.len())?.to_be_bytes())?;
+                writer.write_all(nalu)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns the parameter sets of the record as an Annex B stream, which
+    /// can be fed to a decoder before the rest of the stream.
+    pub fn to_annexb(&self) -> Vec<u8> {
+        let mut output = vec![];
+
+        for nalu in self.arrays.iter().flat_map(|array| &array.nalus) {
+            output.extend_from_slice(&[0x00, 0x00, 0x00, 0x01]);
+            output.extend_from_slice(nalu);
+        }
+
+        output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STREAM: &[u8] = include_bytes!("test_data/test-25fps.h265");
+
+    #[test]
+    fn hvcc_round_trip() {
+        let record = HevcDecoderConfigurationRecord::from_annexb(STREAM, 4).unwrap();
+        assert_eq!(record.general_profile_idc, 1);
+        assert_eq!(record.chroma_format_idc, 1);
+        assert_eq!(record.nal_length_size(), 4);
+        assert_eq!(record.arrays.len(), 3);
+
+        let mut hvcc = vec![];
+        record.write_into(&mut hvcc).unwrap();
+        assert_eq!(
+            HevcDecoderConfigurationRecord::parse(&hvcc).unwrap(),
+            record
+        );
+
+        let parameter_sets = split_annexb(&record.to_annexb());
+        assert_eq!(parameter_sets.len(), 3);
+        assert_eq!(nalu_type(&parameter_sets[0]), Some(NaluType::VpsNut as u8));
+        assert_eq!(nalu_type(&parameter_sets[1]), Some(NaluType::SpsNut as u8));
+        assert_eq!(nalu_type(&parameter_sets[2]), Some(NaluType::PpsNut as u8));
+    }
+
+    #[test]
+    fn unescape_ptl() {
+        assert_eq!(
+            unescape(&[0x00, 0x00, 0x03, 0x01, 0x00, 0x00, 0x03, 0x00, 0x05], 6),
+            [0x00, 0x00, 0x01, 0x00, 0x00, 0x00]
+        );
+    }
+}
@@ -6,19 +6,25 @@
 //!
 //! Parses VPSs, SPSs, PPSs and Slices from NALUs.
 
-use std::collections::BTreeMap;
-
 use anyhow::anyhow;
 use anyhow::Context;
-use bitreader::BitReader;
 use bytes::Buf;
 use enumn::N;
 
+use crate::codec::common::bit_reader::BitReader;
 use crate::codec::h264::nalu;
 use crate::codec::h264::nalu::Header;
-use crate::codec::h264::nalu_reader::NaluReader;
 use crate::codec::h264::parser::Point;
 use crate::codec::h264::parser::Rect;
+use crate::codec::h264::parser::ScalingMatrixKind;
+use crate::codec::param_sets::ChromaFormat;
+use crate::codec::param_sets::ParamSetStore;
+use crate::codec::param_sets::SequenceChange;
+use crate::codec::param_sets::SequenceFormat;
+use crate::codec::param_sets::SequenceParams;
+use crate::codec::timecode::Timecode;
+use crate::utils::picture_hash::PictureHash;
+use crate::Resolution;
 
 // Given the max VPS id.
 const MAX_VPS_COUNT: usize = 16;
@@ -28,6 +34,9 @@ const MAX_SPS_COUNT: usize = 16;
 const MAX_PPS_COUNT: usize = 64;
 // 7.4.7.1
 const MAX_REF_IDX_ACTIVE: u32 = 15;
+// Table A.8, maximum number of tile columns and rows of all levels.
+const MAX_TILE_COLUMNS: usize = 20;
+const MAX_TILE_ROWS: usize = 22;
 
 // 7.4.3.2.1:
 // num_short_term_ref_pic_sets specifies the number of st_ref_pic_set( ) syntax
@@ -183,17 +192,26 @@ pub struct NaluHeader {
 
 impl Header for NaluHeader {
     fn parse<T: AsRef<[u8]>>(cursor: &std::io::Cursor<T>) -> anyhow::Result<Self> {
-        let data = &cursor.chunk()[0..2];
-        let mut r = BitReader::new(data);
+        let data = cursor
+            .chunk()
+            .get(0..2)
+            .ok_or(anyhow!("NALU header is truncated"))?;
+        let mut r = BitReader::new(data, false);
 
         // Skip forbidden_zero_bit
-        r.skip(1)?;
+        r.skip_bits(1)?;
+
+        let header = Self {
+            type_: NaluType::n(r.read_bits::<u32>(6)?).ok_or(anyhow!("Invalid NALU type"))?,
+            nuh_layer_id: r.read_bits(6)?,
+            nuh_temporal_id_plus1: r.read_bits(3)?,
+        };
+
+        if header.nuh_temporal_id_plus1 == 0 {
+            return Err(anyhow!("Invalid nuh_temporal_id_plus1 of 0"));
+        }
 
-        Ok(Self {
-            type_: NaluType::n(r.read_u32(6)?).ok_or(anyhow!("Invalid NALU type"))?,
-            nuh_layer_id: r.read_u8(6)?,
-            nuh_temporal_id_plus1: r.read_u8(3)?,
-        })
+        Ok(header)
     }
 
     fn is_end(&self) -> bool {
@@ -898,6 +916,27 @@ impl Sps {
     }
 }
 
+impl SequenceParams for Sps {
+    fn sequence_format(&self) -> SequenceFormat {
+        let visible_rect = self.visible_rectangle();
+
+        SequenceFormat {
+            coded_resolution: Resolution {
+                width: u32::from(self.width()),
+                height: u32::from(self.height()),
+            },
+            display_resolution: Resolution {
+                width: visible_rect.max.x.saturating_sub(visible_rect.min.x),
+                height: visible_rect.max.y.saturating_sub(visible_rect.min.y),
+            },
+            bit_depth_luma: self.bit_depth_luma_minus8 + 8,
+            bit_depth_chroma: self.bit_depth_chroma_minus8 + 8,
+            chroma_format: ChromaFormat::from_chroma_format_idc(self.chroma_format_idc)
+                .unwrap_or_default(),
+        }
+    }
+}
+
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub struct PpsSccExtension {
     /// When set, specifies that a picture referring to the PPS may be included
@@ -1125,11 +1164,13 @@ pub struct Pps {
     /// `row_height_minus1[ i ]`.
     pub uniform_spacing_flag: bool,
     /// `column_width_minus1[ i ]` plus 1 specifies the width of the i-th tile
-    /// column in units of CTBs.
-    pub column_width_minus1: [u32; 19],
+    /// column in units of CTBs. Unlike in the bitstream, the width of the last
+    /// column is also filled.
+    pub column_width_minus1: [u32; MAX_TILE_COLUMNS],
     /// `row_height_minus1[ i ]` plus 1 specifies the height of the i-th tile row
-    /// in units of CTBs.
-    pub row_height_minus1: [u32; 21],
+    /// in units of CTBs. Unlike in the bitstream, the height of the last row is
+    /// also filled.
+    pub row_height_minus1: [u32; MAX_TILE_ROWS],
     /// When set, specifies that in-loop filtering operations may be performed
     /// across tile boundaries in pictures referring to the PPS.  When not set,
     /// specifies that in-loop filtering operations are not performed across
@@ -1276,6 +1317,52 @@ impl Default for Pps {
     }
 }
 
+impl Pps {
+    /// Returns the number of tiles in pictures referring to this PPS.
+    pub fn num_tiles(&self) -> usize {
+        if !self.tiles_enabled_flag {
+            return 1;
+        }
+
+        (usize::from(self.num_tile_columns_minus1) + 1)
+            * (usize::from(self.num_tile_rows_minus1) + 1)
+    }
+
+    /// Returns the location of the tile column boundaries in units of CTBs,
+    /// i.e. colBd in 6-3. The last element is the width of the picture in
+    /// CTBs.
+    pub fn column_boundaries(&self) -> Vec<u32> {
+        let num_columns = if self.tiles_enabled_flag {
+            usize::from(self.num_tile_columns_minus1) + 1
+        } else {
+            1
+        };
+
+        Self::boundaries(&self.column_width_minus1[..num_columns])
+    }
+
+    /// Returns the location of the tile row boundaries in units of CTBs, i.e.
+    /// rowBd in 6-4. The last element is the height of the picture in CTBs.
+    pub fn row_boundaries(&self) -> Vec<u32> {
+        let num_rows = if self.tiles_enabled_flag {
+            usize::from(self.num_tile_rows_minus1) + 1
+        } else {
+            1
+        };
+
+        Self::boundaries(&self.row_height_minus1[..num_rows])
+    }
+
+    fn boundaries(sizes_minus1: &[u32]) -> Vec<u32> {
+        std::iter::once(0)
+            .chain(sizes_minus1.iter().scan(0, |bd, size_minus1| {
+                *bd += size_minus1 + 1;
+                Some(*bd)
+            }))
+            .collect()
+    }
+}
+
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub struct ScalingLists {
     /// plus 8 specifies the value of the variable `ScalingFactor[ 2 ][ matrixId
@@ -1307,6 +1394,92 @@ impl Default for ScalingLists {
     }
 }
 
+impl ScalingLists {
+    /// Scaling lists where all the factors are equal to 16, as used when
+    /// `scaling_list_enabled_flag` is not set.
+    pub fn flat() -> Self {
+        Self {
+            scaling_list_dc_coef_minus8_16x16: [8; 6],
+            scaling_list_dc_coef_minus8_32x32: [8; 6],
+            scaling_list_4x4: [[16; 16]; 6],
+            scaling_list_8x8: [[16; 64]; 6],
+            scaling_list_16x16: [[16; 64]; 6],
+            scaling_list_32x32: [[16; 64]; 6],
+        }
+    }
+
+    /// The default scaling lists of Table 7-5 and Table 7-6.
+    pub fn default_lists() -> Self {
+        let mut sl = Self::flat();
+
+        for matrix_id in 0..6 {
+            let list = if matrix_id < 3 {
+                DEFAULT_SCALING_LIST_1
+            } else {
+                DEFAULT_SCALING_LIST_2
+            };
+
+            sl.scaling_list_4x4[matrix_id] = DEFAULT_SCALING_LIST_0;
+            sl.scaling_list_8x8[matrix_id] = list;
+            sl.scaling_list_16x16[matrix_id] = list;
+            sl.scaling_list_32x32[matrix_id] = list;
+        }
+
+        sl
+    }
+
+    /// Returns the coefficients of the `matrix_id`-th list of size `size_id`,
+    /// in coding order, or `None` if there is no such list.
+    pub fn list(&self, size_id: usize, matrix_id: usize) -> Option<&[u8]> {
+        match size_id {
+            0 => self.scaling_list_4x4.get(matrix_id).map(|list| &list[..]),
+            1 => self.scaling_list_8x8.get(matrix_id).map(|list| &list[..]),
+            2 => self.scaling_list_16x16.get(matrix_id).map(|list| &list[..]),
+            3 => self.scaling_list_32x32.get(matrix_id).map(|list| &list[..]),
+            _ => None,
+        }
+    }
+
+    /// Returns `scaling_list_dc_coef_minus8` for the `matrix_id`-th list of
+    /// size `size_id`, if this size has a DC coefficient.
+    pub fn dc_coef_minus8(&self, size_id: usize, matrix_id: usize) -> Option<i16> {
+        match size_id {
+            2 => self
+                .scaling_list_dc_coef_minus8_16x16
+                .get(matrix_id)
+                .copied(),
+            3 => self
+                .scaling_list_dc_coef_minus8_32x32
+                .get(matrix_id)
+                .copied(),
+            _ => None,
+        }
+    }
+
+    /// Classifies the lists signaled in scaling_list_data(), i.e. all of them
+    /// except the chroma 32x32 ones.
+    pub fn kind(&self) -> ScalingMatrixKind {
+        let matches = |other: &Self| {
+            (0..4).all(|size_id| {
+                let step = if size_id == 3 { 3 } else { 1 };
+                (0..6).step_by(step).all(|matrix_id| {
+                    self.list(size_id, matrix_id) == other.list(size_id, matrix_id)
+                        && self.dc_coef_minus8(size_id, matrix_id)
+                            == other.dc_coef_minus8(size_id, matrix_id)
+                })
+            })
+        };
+
+        if matches(&Self::flat()) {
+            ScalingMatrixKind::Flat
+        } else if matches(&Self::default_lists()) {
+            ScalingMatrixKind::Default
+        } else {
+            ScalingMatrixKind::Custom
+        }
+    }
+}
+
 #[derive(Clone, Debug, Default, PartialEq, Eq)]
 pub struct RefPicListModification {
     /// Whenset, indicates that reference picture list 0 is specified explicitly
@@ -1349,8 +1522,10 @@ pub struct PredWeightTable {
     /// `RefPicList0[ i ]`.
     pub delta_luma_weight_l0: [i8; 15],
     /// `luma_offset_l0[ i ]` is the additive offset applied to the luma
-    /// prediction value for list 0 prediction using `RefPicList0[ i ]`.
-    pub luma_offset_l0: [i8; 15],
+    /// prediction value for list 0 prediction using `RefPicList0[ i ]`. Its
+    /// range exceeds 8 bits with the high precision offsets of the range
+    /// extensions.
+    pub luma_offset_l0: [i16; 15],
     /// `delta_chroma_weight_l0[ i ][ j ]` is the difference of the weighting
     /// factor applied to the chroma prediction values for list 0 prediction
     /// using `RefPicList0[ i ]` with j equal to 0 for Cb and j equal to 1 for Cr.
@@ -1370,7 +1545,7 @@ pub struct PredWeightTable {
     pub luma_weight_l1_flag: [bool; 15],
     pub chroma_weight_l1_flag: [bool; 15],
     pub delta_luma_weight_l1: [i8; 15],
-    pub luma_offset_l1: [i8; 15],
+    pub luma_offset_l1: [i16; 15],
 
     pub delta_chroma_weight_l1: [[i8; 2]; 15],
     pub delta_chroma_offset_l1: [[i16; 2]; 15],
@@ -1643,7 +1818,7 @@ pub struct SliceHeader {
     /// num_entry_point_offsets + 1 subsets, with subset index values ranging
     /// from 0 to num_entry_point_offsets, inclusive. See the specification for
     /// more details.
-    pub entry_point_offset_minus1: [u32; 32],
+    pub entry_point_offset_minus1: Vec<u32>,
     /// Same as NumPicTotalCurr in the specification.
     pub num_pic_total_curr: u32,
     // Size of slice_header() in bits.
@@ -1716,6 +1891,26 @@ impl Default for SliceHeader {
     }
 }
 
+impl SliceHeader {
+    /// Returns the byte offset of each of the `num_entry_point_offsets + 1`
+    /// subsets of the slice segment data, relative to its first byte.
+    ///
+    /// As per 7.4.7.1, the offsets count the emulation prevention bytes of the
+    /// slice segment data.
+    pub fn entry_points(&self) -> Vec<u64> {
+        std::iter::once(0)
+            .chain(
+                self.entry_point_offset_minus1
+                    .iter()
+                    .scan(0u64, |first_byte, offset_minus1| {
+                        *first_byte += u64::from(*offset_minus1) + 1;
+                        Some(*first_byte)
+                    }),
+            )
+            .collect()
+    }
+}
+
 /// A H265 slice. An integer number of macroblocks or macroblock pairs ordered
 /// consecutively in the raster scan within a particular slice group
 pub struct Slice<'a> {
@@ -1728,43 +1923,30 @@ pub struct Slice<'a> {
 impl<'a> Slice<'a> {
     /// Sets the header for dependent slices by copying from an independent
     /// slice.
+    ///
+    /// Only the syntax elements present in the dependent slice segment header
+    /// are kept, all the others are inferred from `header`, as per 7.4.7.1.
     pub fn replace_header(&mut self, header: SliceHeader) -> anyhow::Result<()> {
         if !self.header.dependent_slice_segment_flag {
-            Err(anyhow!(
+            return Err(anyhow!(
                 "Replacing the slice header is only possible for dependent slices"
-            ))
-        } else {
-            let first_slice_segment_in_pic_flag = self.header.first_slice_segment_in_pic_flag;
-            let no_output_of_prior_pics_flag = self.header.no_output_of_prior_pics_flag;
-            let pic_parameter_set_id = self.header.pic_parameter_set_id;
-            let dependent_slice_segment_flag = self.header.dependent_slice_segment_flag;
-            let segment_address = self.header.segment_address;
-
-            let offset_len_minus1 = self.header.offset_len_minus1;
-            let entry_point_offset_minus1 = self.header.entry_point_offset_minus1;
-            let num_pic_total_curr = self.header.num_pic_total_curr;
-            let header_bit_size = self.header.header_bit_size;
-            let n_emulation_prevention_bytes = self.header.n_emulation_prevention_bytes;
-            let curr_rps_idx = self.header.curr_rps_idx;
-            let st_rps_bits = self.header.st_rps_bits;
-
-            self.header = header;
-
-            self.header.first_slice_segment_in_pic_flag = first_slice_segment_in_pic_flag;
-            self.header.no_output_of_prior_pics_flag = no_output_of_prior_pics_flag;
-            self.header.pic_parameter_set_id = pic_parameter_set_id;
-            self.header.dependent_slice_segment_flag = dependent_slice_segment_flag;
-            self.header.segment_address = segment_address;
-            self.header.offset_len_minus1 = offset_len_minus1;
-            self.header.entry_point_offset_minus1 = entry_point_offset_minus1;
-            self.header.num_pic_total_curr = num_pic_total_curr;
-            self.header.header_bit_size = header_bit_size;
-            self.header.n_emulation_prevention_bytes = n_emulation_prevention_bytes;
-            self.header.curr_rps_idx = curr_rps_idx;
-            self.header.st_rps_bits = st_rps_bits;
-
-            Ok(())
+            ));
         }
+
+        let dependent = std::mem::replace(&mut self.header, header);
+
+        self.header.first_slice_segment_in_pic_flag = dependent.first_slice_segment_in_pic_flag;
+        self.header.no_output_of_prior_pics_flag = dependent.no_output_of_prior_pics_flag;
+        self.header.pic_parameter_set_id = dependent.pic_parameter_set_id;
+        self.header.dependent_slice_segment_flag = dependent.dependent_slice_segment_flag;
+        self.header.segment_address = dependent.segment_address;
+        self.header.num_entry_point_offsets = dependent.num_entry_point_offsets;
+        self.header.offset_len_minus1 = dependent.offset_len_minus1;
+        self.header.entry_point_offset_minus1 = dependent.entry_point_offset_minus1;
+        self.header.header_bit_size = dependent.header_bit_size;
+        self.header.n_emulation_prevention_bytes = dependent.n_emulation_prevention_bytes;
+
+        Ok(())
     }
 }
 
@@ -2133,11 +2315,71 @@ impl Default for VuiParams {
     }
 }
 
+/// The SEI payload types that are parsed. See D.2.1.
+#[derive(N, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SeiPayloadType {
+    DecodedPictureHash = 132,
+    TimeCode = 136,
+}
+
+/// Time code SEI message. See D.2.27 and D.3.27.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TimeCode {
+    /// One entry per clock timestamp, i.e. `num_clock_ts` entries, `None` for
+    /// those whose `clock_timestamp_flag` is unset.
+    ///
+    /// The values that are not present when `full_timestamp_flag` is unset
+    /// are taken from the previous clock timestamp in decoding order, and the
+    /// timestamps are always synthesized in full.
+    pub clock_timestamps: Vec<Option<Timecode>>,
+}
+
+impl TimeCode {
+    /// Maximum value of `num_clock_ts`.
+    pub const MAX_NUM_CLOCK_TS: usize = 3;
+}
+
+/// A SEI message, as per D.2.1.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SeiMessage {
+    /// Decoded picture hash, carried in suffix SEI NALUs. See D.2.19.
+    DecodedPictureHash(PictureHash),
+    TimeCode(TimeCode),
+    /// A message type that is not parsed, along with its raw payload.
+    Unknown {
+        payload_type: u32,
+        payload: Vec<u8>,
+    },
+}
+
+impl SeiMessage {
+    /// Returns the `payloadType` of the message.
+    pub fn payload_type(&self) -> u32 {
+        match self {
+            SeiMessage::DecodedPictureHash(_) => SeiPayloadType::DecodedPictureHash as u32,
+            SeiMessage::TimeCode(_) => SeiPayloadType::TimeCode as u32,
+            SeiMessage::Unknown { payload_type, .. } => *payload_type,
+        }
+    }
+}
+
+/// A prefix or suffix SEI NAL unit, containing one or more SEI messages.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Sei {
+    pub messages: Vec<SeiMessage>,
+}
+
 #[derive(Clone, Debug, Default)]
 pub struct Parser {
-    active_vpses: BTreeMap<u8, Vps>,
-    active_spses: BTreeMap<u8, Sps>,
-    active_ppses: BTreeMap<u8, Pps>,
+    active_vpses: ParamSetStore<Vps>,
+    active_spses: ParamSetStore<Sps>,
+    active_ppses: ParamSetStore<Pps>,
+    /// Header of the last independent slice segment, from which the headers
+    /// of the dependent slice segments that follow it are inferred.
+    last_independent_slice_header: Option<SliceHeader>,
+    /// Last clock timestamp of a time code SEI message, from which the values
+    /// of partial timestamps are inferred.
+    last_timecode: Option<Timecode>,
 }
 
 impl Parser {
@@ -2155,7 +2397,7 @@ impl Parser {
         let header = &nalu.header;
         let hdr_len = header.len();
         // Skip the header
-        let mut r = NaluReader::new(&data[hdr_len..]);
+        let mut r = BitReader::new(&data[hdr_len..], true);
 
         let mut vps = Vps {
             video_parameter_set_id: r.read_bits(4)?,
@@ -2273,9 +2515,9 @@ impl Parser {
         vps.extension_flag = r.read_bit()?;
 
         let key = vps.video_parameter_set_id;
-        self.active_vpses.insert(key, vps);
+        self.active_vpses.insert(key, vps)?;
 
-        if self.active_spses.keys().len() > MAX_VPS_COUNT {
+        if self.active_spses.len() > MAX_VPS_COUNT {
             return Err(anyhow!(
                 "Broken data: Number of active SPSs > MAX_SPS_COUNT"
             ));
@@ -2286,10 +2528,18 @@ impl Parser {
 
     fn parse_profile_tier_level(
         ptl: &mut ProfileTierLevel,
-        r: &mut NaluReader,
+        r: &mut BitReader,
         profile_present_flag: bool,
         sps_max_sub_layers_minus_1: u8,
     ) -> anyhow::Result<()> {
+        // The value 7 is reserved in both the VPS and SPS.
+        if usize::from(sps_max_sub_layers_minus_1) > ptl.sub_layer_profile_present_flag.len() {
+            return Err(anyhow!(
+                "Invalid max_sub_layers_minus1 {}",
+                sps_max_sub_layers_minus_1
+            ));
+        }
+
         if profile_present_flag {
             ptl.general_profile_space = r.read_bits(2)?;
             ptl.general_tier_flag = r.read_bit()?;
@@ -2529,7 +2779,7 @@ impl Parser {
         }
     }
 
-    fn parse_scaling_list_data(sl: &mut ScalingLists, r: &mut NaluReader) -> anyhow::Result<()> {
+    fn parse_scaling_list_data(sl: &mut ScalingLists, r: &mut BitReader) -> anyhow::Result<()> {
         // 7.4.5
         for size_id in 0..4 {
             let mut matrix_id = 0;
@@ -2547,8 +2797,9 @@ impl Parser {
                     } else {
                         // Equation 7-42
                         let factor = if size_id == 3 { 3 } else { 1 };
-                        let ref_matrix_id =
-                            matrix_id as u32 - scaling_list_pred_matrix_id_delta * factor;
+                        let ref_matrix_id = (matrix_id as u32)
+                            .checked_sub(scaling_list_pred_matrix_id_delta * factor)
+                            .ok_or(anyhow!("Invalid scaling_list_pred_matrix_id_delta"))?;
                         if size_id == 0 {
                             sl.scaling_list_4x4[matrix_id as usize] =
                                 sl.scaling_list_4x4[ref_matrix_id as usize];
@@ -2620,7 +2871,7 @@ impl Parser {
     fn parse_short_term_ref_pic_set(
         sps: &Sps,
         st: &mut ShortTermRefPicSet,
-        r: &mut NaluReader,
+        r: &mut BitReader,
         st_rps_idx: u8,
     ) -> anyhow::Result<()> {
         if st_rps_idx != 0 {
@@ -2782,7 +3033,7 @@ impl Parser {
         h: &mut SublayerHrdParameters,
         cpb_cnt: u32,
         sub_pic_hrd_params_present_flag: bool,
-        r: &mut NaluReader,
+        r: &mut BitReader,
     ) -> anyhow::Result<()> {
         for i in 0..cpb_cnt as usize {
             h.bit_rate_value_minus1[i] = r.read_ue_max((2u64.pow(32) - 2) as u32)?;
@@ -2802,7 +3053,7 @@ impl Parser {
         common_inf_present_flag: bool,
         max_num_sublayers_minus1: u8,
         hrd: &mut HrdParams,
-        r: &mut NaluReader,
+        r: &mut BitReader,
     ) -> anyhow::Result<()> {
         if common_inf_present_flag {
             hrd.nal_hrd_parameters_present_flag = r.read_bit()?;
@@ -2863,7 +3114,7 @@ impl Parser {
         Ok(())
     }
 
-    fn parse_vui_parameters(sps: &mut Sps, r: &mut NaluReader) -> anyhow::Result<()> {
+    fn parse_vui_parameters(sps: &mut Sps, r: &mut BitReader) -> anyhow::Result<()> {
         let vui = &mut sps.vui_parameters;
 
         vui.aspect_ratio_info_present_flag = r.read_bit()?;
@@ -2958,7 +3209,7 @@ impl Parser {
         Ok(())
     }
 
-    fn parse_sps_scc_extension(sps: &mut Sps, r: &mut NaluReader) -> anyhow::Result<()> {
+    fn parse_sps_scc_extension(sps: &mut Sps, r: &mut BitReader) -> anyhow::Result<()> {
         let scc = &mut sps.scc_extension;
 
         scc.curr_pic_ref_enabled_flag = r.read_bit()?;
@@ -2994,7 +3245,7 @@ impl Parser {
         Ok(())
     }
 
-    fn parse_sps_range_extension(sps: &mut Sps, r: &mut NaluReader) -> anyhow::Result<()> {
+    fn parse_sps_range_extension(sps: &mut Sps, r: &mut BitReader) -> anyhow::Result<()> {
         let ext = &mut sps.range_extension;
 
         ext.transform_skip_rotation_enabled_flag = r.read_bit()?;
@@ -3024,7 +3275,7 @@ impl Parser {
         let header = &nalu.header;
         let hdr_len = header.len();
         // Skip the header
-        let mut r = NaluReader::new(&data[hdr_len..]);
+        let mut r = BitReader::new(&data[hdr_len..], true);
 
         let mut sps = Sps {
             video_parameter_set_id: r.read_bits(4)?,
@@ -3172,22 +3423,25 @@ impl Parser {
 
         sps.extension_present_flag = r.read_bit()?;
         if sps.extension_present_flag {
+            // All the extension flags precede the extensions themselves.
             sps.range_extension_flag = r.read_bit()?;
-            if sps.range_extension_flag {
-                Self::parse_sps_range_extension(&mut sps, &mut r)?;
-            }
-
             let multilayer_extension_flag = r.read_bit()?;
+            let three_d_extension_flag = r.read_bit()?;
+            sps.scc_extension_flag = r.read_bit()?;
+            r.skip_bits(4)?; // sps_extension_4bits
+
             if multilayer_extension_flag {
                 return Err(anyhow!("Multilayer extension not supported."));
             }
 
-            let three_d_extension_flag = r.read_bit()?;
             if three_d_extension_flag {
                 return Err(anyhow!("3D extension not supported."));
             }
 
-            sps.scc_extension_flag = r.read_bit()?;
+            if sps.range_extension_flag {
+                Self::parse_sps_range_extension(&mut sps, &mut r)?;
+            }
+
             if sps.scc_extension_flag {
                 Self::parse_sps_scc_extension(&mut sps, &mut r)?;
             }
@@ -3218,9 +3472,9 @@ impl Parser {
         );
 
         let key = sps.seq_parameter_set_id;
-        self.active_spses.insert(key, sps);
+        self.active_spses.insert(key, sps)?;
 
-        if self.active_spses.keys().len() > MAX_SPS_COUNT {
+        if self.active_spses.len() > MAX_SPS_COUNT {
             return Err(anyhow!(
                 "Broken data: Number of active SPSs > MAX_SPS_COUNT"
             ));
@@ -3229,7 +3483,7 @@ impl Parser {
         Ok(self.get_sps(key).unwrap())
     }
 
-    fn parse_pps_scc_extension(pps: &mut Pps, sps: &Sps, r: &mut NaluReader) -> anyhow::Result<()> {
+    fn parse_pps_scc_extension(pps: &mut Pps, sps: &Sps, r: &mut BitReader) -> anyhow::Result<()> {
         let scc = &mut pps.scc_extension;
         scc.curr_pic_ref_enabled_flag = r.read_bit()?;
         scc.residual_adaptive_colour_transform_enabled_flag = r.read_bit()?;
@@ -3278,7 +3532,7 @@ impl Parser {
     fn parse_pps_range_extension(
         pps: &mut Pps,
         sps: &Sps,
-        r: &mut NaluReader,
+        r: &mut BitReader,
     ) -> anyhow::Result<()> {
         let rext = &mut pps.range_extension;
 
@@ -3299,10 +3553,12 @@ impl Parser {
         }
 
         let bit_depth_y = sps.bit_depth_luma_minus8 + 8;
-        let max = u32::from(std::cmp::max(0, bit_depth_y - 10));
+        let bit_depth_c = sps.bit_depth_chroma_minus8 + 8;
 
-        rext.log2_sao_offset_scale_luma = r.read_ue_max(max)?;
-        rext.log2_sao_offset_scale_chroma = r.read_ue_max(max)?;
+        rext.log2_sao_offset_scale_luma =
+            r.read_ue_max(u32::from(bit_depth_y.saturating_sub(10)))?;
+        rext.log2_sao_offset_scale_chroma =
+            r.read_ue_max(u32::from(bit_depth_c.saturating_sub(10)))?;
 
         Ok(())
     }
@@ -3321,7 +3577,7 @@ impl Parser {
         let header = &nalu.header;
         let hdr_len = header.len();
         // Skip the header
-        let mut r = NaluReader::new(&data[hdr_len..]);
+        let mut r = BitReader::new(&data[hdr_len..], true);
 
         let mut pps = Pps {
             loop_filter_across_tiles_enabled_flag: true,
@@ -3372,8 +3628,10 @@ impl Parser {
 
         // A mix of the rbsp data and the algorithm in 6.5.1
         if pps.tiles_enabled_flag {
-            pps.num_tile_columns_minus1 = r.read_ue_max(sps.pic_width_in_ctbs_y - 1)?;
-            pps.num_tile_rows_minus1 = r.read_ue_max(sps.pic_height_in_ctbs_y - 1)?;
+            pps.num_tile_columns_minus1 =
+                r.read_ue_max(std::cmp::min(sps.pic_width_in_ctbs_y, MAX_TILE_COLUMNS as u32) - 1)?;
+            pps.num_tile_rows_minus1 =
+                r.read_ue_max(std::cmp::min(sps.pic_height_in_ctbs_y, MAX_TILE_ROWS as u32) - 1)?;
             pps.uniform_spacing_flag = r.read_bit()?;
             if !pps.uniform_spacing_flag {
                 pps.column_width_minus1[usize::from(pps.num_tile_columns_minus1)] =
@@ -3381,7 +3639,9 @@ impl Parser {
 
                 for i in 0..usize::from(pps.num_tile_columns_minus1) {
                     pps.column_width_minus1[i] = r.read_ue_max(
-                        pps.column_width_minus1[usize::from(pps.num_tile_columns_minus1)] - 1,
+                        pps.column_width_minus1[usize::from(pps.num_tile_columns_minus1)]
+                            .checked_sub(1)
+                            .ok_or(anyhow!("Tile columns exceed the picture width"))?,
                     )?;
                     pps.column_width_minus1[usize::from(pps.num_tile_columns_minus1)] -=
                         pps.column_width_minus1[i] + 1;
@@ -3392,7 +3652,9 @@ impl Parser {
 
                 for i in 0..usize::from(pps.num_tile_rows_minus1) {
                     pps.row_height_minus1[i] = r.read_ue_max(
-                        pps.row_height_minus1[usize::from(pps.num_tile_rows_minus1)] - 1,
+                        pps.row_height_minus1[usize::from(pps.num_tile_rows_minus1)]
+                            .checked_sub(1)
+                            .ok_or(anyhow!("Tile rows exceed the picture height"))?,
                     )?;
                     pps.row_height_minus1[usize::from(pps.num_tile_rows_minus1)] -=
                         pps.row_height_minus1[i] + 1;
@@ -3417,6 +3679,10 @@ impl Parser {
             }
 
             pps.loop_filter_across_tiles_enabled_flag = r.read_bit()?;
+        } else {
+            // A single tile covering the whole picture.
+            pps.column_width_minus1[0] = sps.pic_width_in_ctbs_y - 1;
+            pps.row_height_minus1[0] = sps.pic_height_in_ctbs_y - 1;
         }
 
         pps.loop_filter_across_slices_enabled_flag = r.read_bit()?;
@@ -3452,28 +3718,28 @@ impl Parser {
 
         pps.extension_present_flag = r.read_bit()?;
         if pps.extension_present_flag {
+            // All the extension flags precede the extensions themselves.
             pps.range_extension_flag = r.read_bit()?;
-
-            if pps.range_extension_flag {
-                Self::parse_pps_range_extension(&mut pps, sps, &mut r)?;
-            }
-
             let multilayer_extension_flag = r.read_bit()?;
+            let three_d_extension_flag = r.read_bit()?;
+            pps.scc_extension_flag = r.read_bit()?;
+            r.skip_bits(4)?; // pps_extension_4bits
+
             if multilayer_extension_flag {
                 return Err(anyhow!("Multilayer extension is not supported"));
             }
 
-            let three_d_extension_flag = r.read_bit()?;
             if three_d_extension_flag {
                 return Err(anyhow!("3D extension is not supported"));
             }
 
-            pps.scc_extension_flag = r.read_bit()?;
+            if pps.range_extension_flag {
+                Self::parse_pps_range_extension(&mut pps, sps, &mut r)?;
+            }
+
             if pps.scc_extension_flag {
                 Self::parse_pps_scc_extension(&mut pps, sps, &mut r)?;
             }
-
-            r.skip_bits(4)?; // pps_extension_4bits
         }
 
         pps.temporal_id = nalu.header.nuh_temporal_id_plus1 - 1;
@@ -3485,9 +3751,9 @@ impl Parser {
         );
 
         let key = pps.pic_parameter_set_id;
-        self.active_ppses.insert(key, pps);
+        self.active_ppses.insert(key, pps)?;
 
-        if self.active_ppses.keys().len() > MAX_PPS_COUNT {
+        if self.active_ppses.len() > MAX_PPS_COUNT {
             return Err(anyhow!(
                 "Broken Data: number of active PPSs > MAX_PPS_COUNT"
             ));
@@ -3498,7 +3764,7 @@ impl Parser {
 
     fn parse_pred_weight_table(
         hdr: &mut SliceHeader,
-        r: &mut NaluReader,
+        r: &mut BitReader,
         sps: &Sps,
     ) -> anyhow::Result<()> {
         let pwt = &mut hdr.pred_weight_table;
@@ -3524,7 +3790,10 @@ impl Parser {
         for i in 0..=usize::from(hdr.num_ref_idx_l0_active_minus1) {
             if pwt.luma_weight_l0_flag[i] {
                 pwt.delta_luma_weight_l0[i] = r.read_se_bounded(-128, 127)?;
-                pwt.luma_offset_l0[i] = r.read_se_bounded(-128, 127)?;
+                pwt.luma_offset_l0[i] = r.read_se_bounded(
+                    -(sps.wp_offset_half_range_y as i32),
+                    sps.wp_offset_half_range_y as i32 - 1,
+                )?;
             }
 
             if pwt.chroma_weight_l0_flag[i] {
@@ -3543,7 +3812,7 @@ impl Parser {
                 pwt.luma_weight_l1_flag[i] = r.read_bit()?;
             }
 
-            if sps.chroma_format_idc != 0 {
+            if sps.chroma_array_type != 0 {
                 for i in 0..=usize::from(hdr.num_ref_idx_l1_active_minus1) {
                     pwt.chroma_weight_l1_flag[i] = r.read_bit()?;
                 }
@@ -3552,7 +3821,10 @@ impl Parser {
             for i in 0..=usize::from(hdr.num_ref_idx_l1_active_minus1) {
                 if pwt.luma_weight_l1_flag[i] {
                     pwt.delta_luma_weight_l1[i] = r.read_se_bounded(-128, 127)?;
-                    pwt.luma_offset_l1[i] = r.read_se_bounded(-128, 127)?;
+                    pwt.luma_offset_l1[i] = r.read_se_bounded(
+                        -(sps.wp_offset_half_range_y as i32),
+                        sps.wp_offset_half_range_y as i32 - 1,
+                    )?;
                 }
 
                 if pwt.chroma_weight_l1_flag[i] {
@@ -3572,7 +3844,7 @@ impl Parser {
 
     fn parse_ref_pic_lists_modification(
         hdr: &mut SliceHeader,
-        r: &mut NaluReader,
+        r: &mut BitReader,
     ) -> anyhow::Result<()> {
         let rplm = &mut hdr.ref_pic_list_modification;
 
@@ -3661,7 +3933,7 @@ impl Parser {
         let nalu_header = &nalu.header;
         let hdr_len = nalu_header.len();
         // Skip the header
-        let mut r = NaluReader::new(&data[hdr_len..]);
+        let mut r = BitReader::new(&data[hdr_len..], true);
 
         let mut hdr = SliceHeader {
             first_slice_segment_in_pic_flag: r.read_bit()?,
@@ -4002,7 +4274,9 @@ impl Parser {
             let max = if !pps.tiles_enabled_flag && pps.entropy_coding_sync_enabled_flag {
                 sps.pic_height_in_ctbs_y - 1
             } else if pps.tiles_enabled_flag && !pps.entropy_coding_sync_enabled_flag {
-                u32::from((pps.num_tile_columns_minus1 + 1) * (pps.num_tile_rows_minus1 + 1) - 1)
+                (u32::from(pps.num_tile_columns_minus1) + 1)
+                    * (u32::from(pps.num_tile_rows_minus1) + 1)
+                    - 1
             } else {
                 (u32::from(pps.num_tile_columns_minus1) + 1) * sps.pic_height_in_ctbs_y - 1
             };
@@ -4010,10 +4284,10 @@ impl Parser {
             hdr.num_entry_point_offsets = r.read_ue_max(max)?;
             if hdr.num_entry_point_offsets > 0 {
                 hdr.offset_len_minus1 = r.read_ue_max(31)?;
-                for i in 0..hdr.num_entry_point_offsets as usize {
-                    let num_bits = usize::from(hdr.offset_len_minus1 + 1);
-                    hdr.entry_point_offset_minus1[i] = r.read_bits(num_bits)?;
-                }
+                let num_bits = usize::from(hdr.offset_len_minus1 + 1);
+                hdr.entry_point_offset_minus1 = (0..hdr.num_entry_point_offsets)
+                    .map(|_| r.read_bits(num_bits))
+                    .collect::<Result<_, _>>()?;
             }
         }
 
@@ -4040,22 +4314,195 @@ impl Parser {
             nalu.size
         );
 
-        Ok(Slice { header: hdr, nalu })
+        let mut slice = Slice { header: hdr, nalu };
+
+        if slice.header.dependent_slice_segment_flag {
+            let independent_header = self.last_independent_slice_header.clone().ok_or(anyhow!(
+                "Broken stream: dependent slice segment without a preceding independent one"
+            ))?;
+            slice.replace_header(independent_header)?;
+        } else {
+            self.last_independent_slice_header = Some(slice.header.clone());
+        }
+
+        Ok(slice)
     }
 
     /// Returns a previously parsed vps given `vps_id`, if any.
+    /// Reads a `payloadType` or `payloadSize` value.
+    fn read_sei_value(r: &mut BitReader) -> anyhow::Result<u32> {
+        let mut value = 0u32;
+        loop {
+            let byte: u32 = r.read_bits(8)?;
+            value = value
+                .checked_add(byte)
+                .ok_or(anyhow!("Broken data: SEI value overflow"))?;
+            if byte != 0xff {
+                return Ok(value);
+            }
+        }
+    }
+
+    /// Returns the number of RBSP bits read so far by `r` out of `total_bits`.
+    fn rbsp_position(r: &BitReader, total_bits: usize) -> usize {
+        total_bits - r.num_bits_left() - r.num_epb() * 8
+    }
+
+    fn parse_time_code(&mut self, r: &mut BitReader) -> anyhow::Result<TimeCode> {
+        let num_clock_ts: usize = r.read_bits(2)?;
+        let mut tc = TimeCode::default();
+
+        for _ in 0..num_clock_ts {
+            let clock_timestamp_flag = r.read_bit()?;
+            if !clock_timestamp_flag {
+                tc.clock_timestamps.push(None);
+                continue;
+            }
+
+            let previous = self.last_timecode.unwrap_or_default();
+            let mut timecode = Timecode {
+                field_based: r.read_bit()?,
+                counting_type: r.read_bits(5)?,
+                ..previous
+            };
+
+            let full_timestamp_flag = r.read_bit()?;
+            timecode.discontinuity = r.read_bit()?;
+            timecode.cnt_dropped = r.read_bit()?;
+            timecode.frames = r.read_bits(9)?;
+
+            if full_timestamp_flag {
+                timecode.seconds = r.read_bits(6)?;
+                timecode.minutes = r.read_bits(6)?;
+                timecode.hours = r.read_bits(5)?;
+            } else {
+                let seconds_flag = r.read_bit()?;
+                if seconds_flag {
+                    timecode.seconds = r.read_bits(6)?;
+                    let minutes_flag = r.read_bit()?;
+                    if minutes_flag {
+                        timecode.minutes = r.read_bits(6)?;
+                        let hours_flag = r.read_bit()?;
+                        if hours_flag {
+                            timecode.hours = r.read_bits(5)?;
+                        }
+                    }
+                }
+            }
+
+            let time_offset_length: usize = r.read_bits(5)?;
+            timecode.time_offset = if time_offset_length > 0 {
+                let time_offset: u32 = r.read_bits(time_offset_length)?;
+                // Sign-extend the two's complement value.
+                let shift = 32 - time_offset_length;
+                ((time_offset << shift) as i32) >> shift
+            } else {
+                0
+            };
+
+            self.last_timecode = Some(timecode);
+            tc.clock_timestamps.push(Some(timecode));
+        }
+
+        Ok(tc)
+    }
+
+    /// Parses a prefix or suffix SEI NALU.
+    pub fn parse_sei(&mut self, nalu: &Nalu) -> anyhow::Result<Sei> {
+        if !matches!(
+            nalu.header.type_,
+            NaluType::PrefixSeiNut | NaluType::SuffixSeiNut
+        ) {
+            return Err(anyhow!(
+                "Invalid NALU type, expected a SEI NALU, got {:?}",
+                nalu.header.type_
+            ));
+        }
+
+        // Skip the header
+        let data = &nalu.as_ref()[nalu.header.len()..];
+        let total_bits = data.len() * 8;
+        let mut r = BitReader::new(data, true);
+        let mut sei = Sei::default();
+
+        loop {
+            let payload_type = Self::read_sei_value(&mut r)?;
+            let payload_size = usize::try_from(Self::read_sei_value(&mut r)?)?;
+            let payload_start = Self::rbsp_position(&r, total_bits);
+
+            let message = match SeiPayloadType::n(payload_type) {
+                Some(SeiPayloadType::DecodedPictureHash) => {
+                    let payload = (0..payload_size)
+                        .map(|_| r.read_bits(8))
+                        .collect::<Result<Vec<u8>, _>>()?;
+                    SeiMessage::DecodedPictureHash(PictureHash::from_payload(&payload)?)
+                }
+                Some(SeiPayloadType::TimeCode) => {
+                    SeiMessage::TimeCode(self.parse_time_code(&mut r)?)
+                }
+                None => SeiMessage::Unknown {
+                    payload_type,
+                    payload: (0..payload_size)
+                        .map(|_| r.read_bits(8))
+                        .collect::<Result<_, _>>()?,
+                },
+            };
+
+            // Skip whatever we did not parse of the payload, e.g. reserved
+            // extension data and alignment bits.
+            let consumed = Self::rbsp_position(&r, total_bits) - payload_start;
+            let payload_bits = payload_size * 8;
+            if consumed > payload_bits {
+                return Err(anyhow!(
+                    "Broken data: SEI message of type {} overflows its payload",
+                    payload_type
+                ));
+            }
+            r.skip_bits(payload_bits - consumed)?;
+
+            sei.messages.push(message);
+
+            if !r.has_more_rsbp_data() {
+                break;
+            }
+        }
+
+        Ok(sei)
+    }
+
     pub fn get_vps(&self, vps_id: u8) -> Option<&Vps> {
-        self.active_vpses.get(&vps_id)
+        self.active_vpses.get(vps_id).map(|vps| vps.as_ref())
     }
 
     /// Returns a previously parsed sps given `sps_id`, if any.
     pub fn get_sps(&self, sps_id: u8) -> Option<&Sps> {
-        self.active_spses.get(&sps_id)
+        self.active_spses.get(sps_id).map(|sps| sps.as_ref())
     }
 
     /// Returns a previously parsed pps given `pps_id`, if any.
     pub fn get_pps(&self, pps_id: u8) -> Option<&Pps> {
-        self.active_ppses.get(&pps_id)
+        self.active_ppses.get(pps_id).map(|pps| pps.as_ref())
+    }
+
+    /// Returns the VPSs parsed so far.
+    pub fn vps_store(&self) -> &ParamSetStore<Vps> {
+        &self.active_vpses
+    }
+
+    /// Returns the SPSs parsed so far.
+    pub fn sps_store(&self) -> &ParamSetStore<Sps> {
+        &self.active_spses
+    }
+
+    /// Returns the PPSs parsed so far.
+    pub fn pps_store(&self) -> &ParamSetStore<Pps> {
+        &self.active_ppses
+    }
+
+    /// Makes the SPS with id `sps_id` the active one, returning the change of
+    /// sequence format this implies, if any.
+    pub fn activate_sps(&mut self, sps_id: u8) -> anyhow::Result<Option<SequenceChange>> {
+        self.active_spses.activate(sps_id)
     }
 }
 
@@ -4063,12 +4510,20 @@ impl Parser {
 mod tests {
     use std::io::Cursor;
 
+    use crate::codec::common::bit_reader::BitReader;
+    use crate::codec::common::bit_writer::BitWriter;
     use crate::codec::h264::nalu::Nalu;
+    use crate::codec::h264::parser::ScalingMatrixKind;
     use crate::codec::h265::parser::Level;
     use crate::codec::h265::parser::NaluHeader;
     use crate::codec::h265::parser::NaluType;
     use crate::codec::h265::parser::Parser;
+    use crate::codec::h265::parser::Profile;
+    use crate::codec::h265::parser::ScalingLists;
     use crate::codec::h265::parser::SliceType;
+    use crate::codec::h265::synthesizer::synthesize_scaling_list_data;
+    use crate::codec::param_sets::ChromaFormat;
+    use crate::codec::param_sets::SequenceParams;
 
     const STREAM_BEAR: &[u8] = include_bytes!("test_data/bear.h265");
     const STREAM_BEAR_NUM_NALUS: usize = 35;
@@ -4167,6 +4622,29 @@ mod tests {
 
     /// Parse the syntax, making sure we can parse the files without crashing.
     /// Does not check whether the parsed values are correct.
+    #[test]
+    fn sps_activation() {
+        let mut cursor = Cursor::new(STREAM_BEAR);
+        let mut parser = Parser::default();
+
+        while let Ok(nalu) = Nalu::<NaluHeader>::next(&mut cursor) {
+            dispatch_parse_call(&mut parser, nalu).unwrap();
+        }
+
+        assert_eq!(parser.sps_store().len(), 1);
+        let (sps_id, sps) = parser.sps_store().iter().next().unwrap();
+        let format = sps.sequence_format();
+        assert_eq!(format.coded_resolution.width, u32::from(sps.width()));
+        assert_eq!(format.bit_depth_luma, 8);
+        assert_eq!(format.chroma_format, ChromaFormat::Yuv420);
+
+        let change = parser.activate_sps(sps_id).unwrap().unwrap();
+        assert_eq!(change.previous, None);
+        assert_eq!(change.current, format);
+        assert_eq!(parser.activate_sps(sps_id).unwrap(), None);
+        assert!(parser.activate_sps(sps_id + 1).is_err());
+    }
+
     #[test]
     fn parse_syntax_from_nals() {
         let mut cursor = Cursor::new(STREAM_BBB);
@@ -4598,8 +5076,12 @@ mod tests {
         assert_eq!(pps.num_tile_rows_minus1, 0);
         assert_eq!(pps.num_tile_columns_minus1, 0);
         assert!(pps.uniform_spacing_flag);
-        assert_eq!(pps.column_width_minus1, [0; 19]);
-        assert_eq!(pps.row_height_minus1, [0; 21]);
+        assert_eq!(pps.column_width_minus1[1..], [0; 19]);
+        assert_eq!(pps.row_height_minus1[1..], [0; 21]);
+        assert_eq!(pps.num_tiles(), 1);
+        // 320x240 with 64x64 CTBs.
+        assert_eq!(pps.column_boundaries(), [0, 5]);
+        assert_eq!(pps.row_boundaries(), [0, 4]);
         assert!(pps.loop_filter_across_slices_enabled_flag);
         assert!(pps.loop_filter_across_tiles_enabled_flag);
         assert!(!pps.deblocking_filter_control_present_flag);
@@ -4738,4 +5220,326 @@ mod tests {
         // Subtract 2 bytes to account for the header size.
         assert_eq!(hdr.header_bit_size - 16, 80);
     }
+
+    #[test]
+    fn scaling_list_data_round_trip() {
+        let mut sl = ScalingLists::default_lists();
+        sl.scaling_list_4x4[1] = std::array::from_fn(|i| (i * 16) as u8 + 4);
+        sl.scaling_list_4x4[2] = sl.scaling_list_4x4[1];
+        sl.scaling_list_8x8[5] = [255; 64];
+        sl.scaling_list_16x16[3] = std::array::from_fn(|i| if i % 2 == 0 { 1 } else { 250 });
+        sl.scaling_list_dc_coef_minus8_16x16[3] = -7;
+        sl.scaling_list_16x16[4] = sl.scaling_list_16x16[3];
+        sl.scaling_list_dc_coef_minus8_16x16[4] = 247;
+        sl.scaling_list_32x32[3] = sl.scaling_list_32x32[0];
+        // Not signaled.
+        for matrix_id in [1, 2, 4, 5] {
+            sl.scaling_list_32x32[matrix_id] = [0; 64];
+            sl.scaling_list_dc_coef_minus8_32x32[matrix_id] = 0;
+        }
+        assert_eq!(sl.kind(), ScalingMatrixKind::Custom);
+
+        let mut buf = Vec::<u8>::new();
+        {
+            let mut writer = BitWriter::new(&mut buf, false);
+            synthesize_scaling_list_data(&sl, &mut writer).unwrap();
+        }
+
+        let mut parsed = ScalingLists::default();
+        let mut r = BitReader::new(&buf, true);
+        Parser::parse_scaling_list_data(&mut parsed, &mut r).unwrap();
+
+        assert_eq!(parsed, sl);
+    }
+
+    #[test]
+    fn scaling_lists_kind() {
+        assert_eq!(ScalingLists::flat().kind(), ScalingMatrixKind::Flat);
+        assert_eq!(
+            ScalingLists::default_lists().kind(),
+            ScalingMatrixKind::Default
+        );
+
+        // Default lists compress to a single bit and a zero delta per list.
+        let mut buf = Vec::<u8>::new();
+        {
+            let mut writer = BitWriter::new(&mut buf, false);
+            synthesize_scaling_list_data(&ScalingLists::default_lists(), &mut writer).unwrap();
+        }
+        assert_eq!(buf.len(), (20 * 2usize).div_ceil(8));
+    }
+
+    #[test]
+    fn scaling_lists_lookup() {
+        let sl = ScalingLists::default_lists();
+        assert_eq!(sl.list(0, 0), Some(&[16; 16][..]));
+        assert_eq!(sl.dc_coef_minus8(0, 0), None);
+        assert_eq!(sl.dc_coef_minus8(3, 5), Some(8));
+
+        // Out of range sizes and matrices.
+        assert_eq!(sl.list(4, 0), None);
+        assert_eq!(sl.list(1, 6), None);
+        assert_eq!(sl.dc_coef_minus8(2, 6), None);
+    }
+
+    /// Writes a NALU of type `type_` with the payload produced by `f`.
+    fn write_nalu(type_: NaluType, f: impl FnOnce(&mut BitWriter<Vec<u8>>)) -> Vec<u8> {
+        let mut buf = vec![0x00, 0x00, 0x00, 0x01, (type_ as u8) << 1, 0x01];
+        {
+            let mut writer = BitWriter::new(&mut buf, true);
+            f(&mut writer);
+            // rbsp_trailing_bits() or byte_alignment()
+            writer.write_f(1, true).unwrap();
+            while !writer.aligned() {
+                writer.write_f(1, false).unwrap();
+            }
+        }
+        buf
+    }
+
+    #[test]
+    fn tiles_and_dependent_slices() {
+        let mut parser = Parser::default();
+
+        let sps_nalu = find_nalu_by_type(STREAM_TEST25FPS, NaluType::SpsNut, 0).unwrap();
+        let sps = parser.parse_sps(&sps_nalu).unwrap();
+        let sps_id = sps.seq_parameter_set_id;
+        // 320x240 with 64x64 CTBs, i.e. 5x4 CTBs.
+        assert_eq!(sps.pic_size_in_ctbs_y, 20);
+        assert!(sps.sample_adaptive_offset_enabled_flag);
+        assert_eq!(sps.chroma_array_type, 1);
+
+        let pps = write_nalu(NaluType::PpsNut, |w| {
+            w.write_ue(0u32).unwrap(); // pps_pic_parameter_set_id
+            w.write_ue(sps_id).unwrap();
+            w.write_f(1, true).unwrap(); // dependent_slice_segments_enabled_flag
+            w.write_f(6, 0u32).unwrap(); // output_flag_present_flag to cabac_init_present_flag
+            w.write_ue(0u32).unwrap(); // num_ref_idx_l0_default_active_minus1
+            w.write_ue(0u32).unwrap(); // num_ref_idx_l1_default_active_minus1
+            w.write_se(0).unwrap(); // init_qp_minus26
+            w.write_f(3, 0u32).unwrap(); // constrained_intra_pred_flag to cu_qp_delta_enabled_flag
+            w.write_se(0).unwrap(); // pps_cb_qp_offset
+            w.write_se(0).unwrap(); // pps_cr_qp_offset
+            w.write_f(4, 0u32).unwrap(); // pps_slice_chroma_qp_offsets_present_flag to transquant_bypass_enabled_flag
+            w.write_f(1, true).unwrap(); // tiles_enabled_flag
+            w.write_f(1, false).unwrap(); // entropy_coding_sync_enabled_flag
+            w.write_ue(1u32).unwrap(); // num_tile_columns_minus1
+            w.write_ue(1u32).unwrap(); // num_tile_rows_minus1
+            w.write_f(1, false).unwrap(); // uniform_spacing_flag
+            w.write_ue(1u32).unwrap(); // column_width_minus1[0]
+            w.write_ue(0u32).unwrap(); // row_height_minus1[0]
+            w.write_f(1, true).unwrap(); // loop_filter_across_tiles_enabled_flag
+            w.write_f(1, true).unwrap(); // pps_loop_filter_across_slices_enabled_flag
+            w.write_f(3, 0u32).unwrap(); // deblocking_filter_control_present_flag to lists_modification_present_flag
+            w.write_ue(0u32).unwrap(); // log2_parallel_merge_level_minus2
+            w.write_f(2, 0u32).unwrap(); // slice_segment_header_extension_present_flag, pps_extension_present_flag
+        });
+        let pps = Nalu::<NaluHeader>::next(&mut Cursor::new(pps.as_ref())).unwrap();
+        let pps = parser.parse_pps(&pps).unwrap();
+
+        assert_eq!(pps.num_tiles(), 4);
+        assert_eq!(pps.column_boundaries(), [0, 2, 5]);
+        assert_eq!(pps.row_boundaries(), [0, 1, 4]);
+
+        let independent = write_nalu(NaluType::IdrWRadl, |w| {
+            w.write_f(1, true).unwrap(); // first_slice_segment_in_pic_flag
+            w.write_f(1, false).unwrap(); // no_output_of_prior_pics_flag
+            w.write_ue(0u32).unwrap(); // slice_pic_parameter_set_id
+            w.write_ue(SliceType::I as u32).unwrap();
+            w.write_f(2, 0b11u32).unwrap(); // slice_sao_luma_flag, slice_sao_chroma_flag
+            w.write_se(-3).unwrap(); // slice_qp_delta
+            w.write_f(1, true).unwrap(); // slice_loop_filter_across_slices_enabled_flag
+            w.write_ue(3u32).unwrap(); // num_entry_point_offsets
+            w.write_ue(7u32).unwrap(); // offset_len_minus1
+            for offset_minus1 in [9u32, 19, 29] {
+                w.write_f(8, offset_minus1).unwrap();
+            }
+        });
+        let independent = Nalu::<NaluHeader>::next(&mut Cursor::new(independent.as_ref())).unwrap();
+        let independent = parser.parse_slice_header(independent).unwrap();
+
+        assert!(!independent.header.dependent_slice_segment_flag);
+        assert_eq!(independent.header.qp_delta, -3);
+        assert_eq!(independent.header.entry_point_offset_minus1, [9, 19, 29]);
+        assert_eq!(independent.header.entry_points(), [0, 10, 30, 60]);
+
+        let dependent = write_nalu(NaluType::IdrWRadl, |w| {
+            w.write_f(1, false).unwrap(); // first_slice_segment_in_pic_flag
+            w.write_f(1, false).unwrap(); // no_output_of_prior_pics_flag
+            w.write_ue(0u32).unwrap(); // slice_pic_parameter_set_id
+            w.write_f(1, true).unwrap(); // dependent_slice_segment_flag
+            w.write_f(5, 10u32).unwrap(); // slice_segment_address
+            w.write_ue(1u32).unwrap(); // num_entry_point_offsets
+            w.write_ue(31u32).unwrap(); // offset_len_minus1
+            w.write_f(32, 0xffff_fffeu32).unwrap();
+        });
+        let dependent = Nalu::<NaluHeader>::next(&mut Cursor::new(dependent.as_ref())).unwrap();
+        let dependent = parser.parse_slice_header(dependent).unwrap();
+
+        // Inferred from the independent slice segment.
+        assert_eq!(dependent.header.type_, SliceType::I);
+        assert_eq!(dependent.header.qp_delta, -3);
+        assert!(dependent.header.sao_luma_flag);
+        // Signaled in the dependent slice segment.
+        assert!(dependent.header.dependent_slice_segment_flag);
+        assert!(!dependent.header.first_slice_segment_in_pic_flag);
+        assert_eq!(dependent.header.segment_address, 10);
+        assert_eq!(dependent.header.num_entry_point_offsets, 1);
+        assert_eq!(dependent.header.entry_point_offset_minus1, [0xffff_fffe]);
+        assert_eq!(dependent.header.entry_points(), [0, 0xffff_ffff]);
+    }
+
+    /// 4:2:2 10-bit stream of the range extensions profile, with high precision weighted
+    /// prediction offsets and chroma QP offset lists.
+    #[test]
+    fn range_extensions() {
+        let mut parser = Parser::default();
+
+        let sps = write_nalu(NaluType::SpsNut, |w| {
+            w.write_f(4, 0u32).unwrap(); // sps_video_parameter_set_id
+            w.write_f(3, 0u32).unwrap(); // sps_max_sub_layers_minus1
+            w.write_f(1, true).unwrap(); // sps_temporal_id_nesting_flag
+            w.write_f(2, 0u32).unwrap(); // general_profile_space
+            w.write_f(1, false).unwrap(); // general_tier_flag
+            w.write_f(5, Profile::RangeExtensions as u32).unwrap();
+            // general_profile_compatibility_flag
+            w.write_f(32, 1u32 << (31 - Profile::RangeExtensions as u32))
+                .unwrap();
+            // From general_progressive_source_flag to general_frame_only_constraint_flag.
+            w.write_f(4, 0b1001u32).unwrap();
+            // From general_max_12bit_constraint_flag to general_lower_bit_rate_constraint_flag.
+            w.write_f(9, 0b110100001u32).unwrap();
+            w.write_f(32, 0u32).unwrap(); // general_reserved_zero_34bits
+            w.write_f(3, 0u32).unwrap(); // general_reserved_zero_34bits, general_inbld_flag
+            w.write_f(8, Level::L3_1 as u32).unwrap();
+            w.write_ue(0u32).unwrap(); // sps_seq_parameter_set_id
+            w.write_ue(2u32).unwrap(); // chroma_format_idc
+            w.write_ue(64u32).unwrap(); // pic_width_in_luma_samples
+            w.write_ue(64u32).unwrap(); // pic_height_in_luma_samples
+            w.write_f(1, false).unwrap(); // conformance_window_flag
+            w.write_ue(2u32).unwrap(); // bit_depth_luma_minus8
+            w.write_ue(2u32).unwrap(); // bit_depth_chroma_minus8
+            w.write_ue(4u32).unwrap(); // log2_max_pic_order_cnt_lsb_minus4
+            w.write_f(1, true).unwrap(); // sps_sub_layer_ordering_info_present_flag
+            w.write_ue(4u32).unwrap(); // sps_max_dec_pic_buffering_minus1
+            w.write_ue(0u32).unwrap(); // sps_max_num_reorder_pics
+            w.write_ue(0u32).unwrap(); // sps_max_latency_increase_plus1
+            w.write_ue(0u32).unwrap(); // log2_min_luma_coding_block_size_minus3
+            w.write_ue(3u32).unwrap(); // log2_diff_max_min_luma_coding_block_size
+            w.write_ue(0u32).unwrap(); // log2_min_luma_transform_block_size_minus2
+            w.write_ue(3u32).unwrap(); // log2_diff_max_min_luma_transform_block_size
+            w.write_ue(0u32).unwrap(); // max_transform_hierarchy_depth_inter
+            w.write_ue(0u32).unwrap(); // max_transform_hierarchy_depth_intra
+            w.write_f(4, 0u32).unwrap(); // scaling_list_enabled_flag to pcm_enabled_flag
+            w.write_ue(0u32).unwrap(); // num_short_term_ref_pic_sets
+
+            // From long_term_ref_pics_present_flag to vui_parameters_present_flag.
+            w.write_f(4, 0u32).unwrap();
+            w.write_f(1, true).unwrap(); // sps_extension_present_flag
+            w.write_f(1, true).unwrap(); // sps_range_extension_flag
+            w.write_f(3, 0u32).unwrap(); // sps_multilayer_extension_flag to sps_scc_extension_flag
+            w.write_f(4, 0u32).unwrap(); // sps_extension_4bits
+
+            // From transform_skip_rotation_enabled_flag to cabac_bypass_alignment_enabled_flag,
+            // with implicit_rdpcm_enabled_flag and high_precision_offsets_enabled_flag set.
+            w.write_f(9, 0b001000100u32).unwrap();
+        });
+        let sps = Nalu::<NaluHeader>::next(&mut Cursor::new(sps.as_ref())).unwrap();
+        let sps = parser.parse_sps(&sps).unwrap();
+
+        assert_eq!(sps.profile_tier_level.general_profile_idc, 4);
+        assert!(sps.profile_tier_level.general_max_422chroma_constraint_flag);
+        assert_eq!(sps.chroma_array_type, 2);
+        assert!(sps.range_extension.implicit_rdpcm_enabled_flag);
+        assert!(sps.range_extension.high_precision_offsets_enabled_flag);
+        assert_eq!(sps.wp_offset_half_range_y, 512);
+        assert_eq!(sps.wp_offset_half_range_c, 512);
+        let params = sps.sequence_format();
+        assert_eq!(params.chroma_format, ChromaFormat::Yuv422);
+        assert_eq!(params.bit_depth_luma, 10);
+
+        let pps = write_nalu(NaluType::PpsNut, |w| {
+            w.write_ue(0u32).unwrap(); // pps_pic_parameter_set_id
+            w.write_ue(0u32).unwrap(); // pps_seq_parameter_set_id
+
+            // From dependent_slice_segments_enabled_flag to cabac_init_present_flag.
+            w.write_f(7, 0u32).unwrap();
+            w.write_ue(0u32).unwrap(); // num_ref_idx_l0_default_active_minus1
+            w.write_ue(0u32).unwrap(); // num_ref_idx_l1_default_active_minus1
+            w.write_se(0).unwrap(); // init_qp_minus26
+            w.write_f(3, 0u32).unwrap(); // constrained_intra_pred_flag to cu_qp_delta_enabled_flag
+            w.write_se(0).unwrap(); // pps_cb_qp_offset
+            w.write_se(0).unwrap(); // pps_cr_qp_offset
+
+            // From pps_slice_chroma_qp_offsets_present_flag to transquant_bypass_enabled_flag, with
+            // weighted_pred_flag set.
+            w.write_f(4, 0b0100u32).unwrap();
+            w.write_f(2, 0u32).unwrap(); // tiles_enabled_flag, entropy_coding_sync_enabled_flag
+            w.write_f(1, true).unwrap(); // pps_loop_filter_across_slices_enabled_flag
+
+            // From deblocking_filter_control_present_flag to lists_modification_present_flag.
+            w.write_f(3, 0u32).unwrap();
+            w.write_ue(0u32).unwrap(); // log2_parallel_merge_level_minus2
+            w.write_f(1, false).unwrap(); // slice_segment_header_extension_present_flag
+            w.write_f(1, true).unwrap(); // pps_extension_present_flag
+            w.write_f(1, true).unwrap(); // pps_range_extension_flag
+            w.write_f(3, 0u32).unwrap(); // pps_multilayer_extension_flag to pps_scc_extension_flag
+            w.write_f(4, 0u32).unwrap(); // pps_extension_4bits
+            w.write_f(1, false).unwrap(); // cross_component_prediction_enabled_flag
+            w.write_f(1, true).unwrap(); // chroma_qp_offset_list_enabled_flag
+            w.write_ue(1u32).unwrap(); // diff_cu_chroma_qp_offset_depth
+            w.write_ue(1u32).unwrap(); // chroma_qp_offset_list_len_minus1
+            for (cb, cr) in [(2, -2), (4, -4)] {
+                w.write_se(cb).unwrap(); // cb_qp_offset_list
+                w.write_se(cr).unwrap(); // cr_qp_offset_list
+            }
+            w.write_ue(0u32).unwrap(); // log2_sao_offset_scale_luma
+            w.write_ue(0u32).unwrap(); // log2_sao_offset_scale_chroma
+        });
+        let pps = Nalu::<NaluHeader>::next(&mut Cursor::new(pps.as_ref())).unwrap();
+        let pps = parser.parse_pps(&pps).unwrap();
+
+        let rext = &pps.range_extension;
+        assert!(rext.chroma_qp_offset_list_enabled_flag);
+        assert_eq!(rext.diff_cu_chroma_qp_offset_depth, 1);
+        assert_eq!(rext.cb_qp_offset_list[..2], [2, 4]);
+        assert_eq!(rext.cr_qp_offset_list[..2], [-2, -4]);
+
+        let slice = write_nalu(NaluType::TrailR, |w| {
+            w.write_f(1, true).unwrap(); // first_slice_segment_in_pic_flag
+            w.write_ue(0u32).unwrap(); // slice_pic_parameter_set_id
+            w.write_ue(SliceType::P as u32).unwrap();
+            w.write_f(8, 1u32).unwrap(); // slice_pic_order_cnt_lsb
+            w.write_f(1, false).unwrap(); // short_term_ref_pic_set_sps_flag
+            w.write_ue(1u32).unwrap(); // num_negative_pics
+            w.write_ue(0u32).unwrap(); // num_positive_pics
+            w.write_ue(0u32).unwrap(); // delta_poc_s0_minus1
+            w.write_f(1, true).unwrap(); // used_by_curr_pic_s0_flag
+            w.write_f(1, false).unwrap(); // num_ref_idx_active_override_flag
+            w.write_ue(6u32).unwrap(); // luma_log2_weight_denom
+            w.write_se(0).unwrap(); // delta_chroma_log2_weight_denom
+            w.write_f(1, true).unwrap(); // luma_weight_l0_flag
+            w.write_f(1, true).unwrap(); // chroma_weight_l0_flag
+            w.write_se(0).unwrap(); // delta_luma_weight_l0
+
+            // luma_offset_l0, beyond 8 bits with high precision offsets.
+            w.write_se(-300).unwrap();
+            for delta_chroma_offset in [1000, -1000] {
+                w.write_se(0).unwrap(); // delta_chroma_weight_l0
+                w.write_se(delta_chroma_offset).unwrap(); // delta_chroma_offset_l0
+            }
+            w.write_ue(0u32).unwrap(); // five_minus_max_num_merge_cand
+            w.write_se(0).unwrap(); // slice_qp_delta
+            w.write_f(1, true).unwrap(); // cu_chroma_qp_offset_enabled_flag
+            w.write_f(1, true).unwrap(); // slice_loop_filter_across_slices_enabled_flag
+        });
+        let slice = Nalu::<NaluHeader>::next(&mut Cursor::new(slice.as_ref())).unwrap();
+        let slice = parser.parse_slice_header(slice).unwrap();
+
+        let pwt = &slice.header.pred_weight_table;
+        assert_eq!(pwt.luma_offset_l0[0], -300);
+        assert_eq!(pwt.delta_chroma_offset_l0[0], [1000, -1000]);
+        assert!(slice.header.cu_chroma_qp_offset_enabled_flag);
+        assert!(slice.header.loop_filter_across_slices_enabled_flag);
+    }
 }
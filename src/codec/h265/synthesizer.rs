@@ -0,0 +1,303 @@
+// Copyright 2024 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Synthesis of H.265 syntax structures.
+//!
+//! Full parameter set synthesis is not supported yet, only the syntax
+//! structures that can be embedded by callers writing their own parameter
+//! sets with [`BitWriter`], and SEI NALUs.
+
+use std::io::Write;
+
+use crate::codec::common::bit_writer::BitWriter;
+use crate::codec::common::bit_writer::BitWriterError;
+use crate::codec::common::bit_writer::BitWriterResult;
+use crate::codec::h265::parser::NaluType;
+use crate::codec::h265::parser::ScalingLists;
+use crate::codec::h265::parser::Sei;
+use crate::codec::h265::parser::SeiMessage;
+use crate::codec::h265::parser::TimeCode;
+use crate::codec::timecode::Timecode;
+
+/// Writes `sl` as a scaling_list_data() syntax structure (H.265 7.3.4).
+///
+/// Lists equal to the default ones of Table 7-5 and Table 7-6 are signaled as
+/// such, and lists equal to a previous list of the same size are predicted
+/// from it. The chroma 32x32 lists are not written, as per the syntax.
+pub fn synthesize_scaling_list_data<W: Write>(
+    sl: &ScalingLists,
+    writer: &mut BitWriter<W>,
+) -> BitWriterResult<()> {
+    let default_lists = ScalingLists::default_lists();
+
+    for size_id in 0..4 {
+        let step = if size_id == 3 { 3 } else { 1 };
+
+        for matrix_id in (0..6).step_by(step) {
+            let list = sl.list(size_id, matrix_id);
+            let dc = sl.dc_coef_minus8(size_id, matrix_id);
+
+            let same_as = |other: &ScalingLists, other_matrix_id: usize| {
+                list == other.list(size_id, other_matrix_id)
+                    && dc == other.dc_coef_minus8(size_id, other_matrix_id)
+            };
+
+            // scaling_list_pred_matrix_id_delta of 0 refers to the default
+            // list, otherwise to a previous list of the same size.
+            let pred_matrix_id_delta = if same_as(&default_lists, matrix_id) {
+                Some(0)
+            } else {
+                (0..matrix_id)
+                    .step_by(step)
+                    .rev()
+                    .find(|&ref_matrix_id| same_as(sl, ref_matrix_id))
+                    .map(|ref_matrix_id| (matrix_id - ref_matrix_id) / step)
+            };
+
+            writer.write_u(
+                1,
+                /* scaling_list_pred_mode_flag */ pred_matrix_id_delta.is_none(),
+            )?;
+
+            if let Some(delta) = pred_matrix_id_delta {
+                writer.write_ue(delta as u32)?;
+                continue;
+            }
+
+            let mut next_coef = 8i32;
+            if let Some(dc) = dc {
+                writer.write_se(dc)?;
+                next_coef = i32::from(dc) + 8;
+            }
+
+            for &coef in list.into_iter().flatten() {
+                // scaling_list_delta_coef shall be in the range of -128 to 127,
+                // the decoder computing the next coefficient modulo 256.
+                let delta = (i32::from(coef) - next_coef + 128).rem_euclid(256) - 128;
+                writer.write_se(delta)?;
+                next_coef = i32::from(coef);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Writes a SEI NALU of type `nalu_type`, which must be either
+/// [`NaluType::PrefixSeiNut`] or [`NaluType::SuffixSeiNut`], containing
+/// `sei`'s messages (H.265 7.3.2.4).
+pub fn synthesize_sei<W: Write>(
+    sei: &Sei,
+    nalu_type: NaluType,
+    temporal_id: u8,
+    writer: &mut W,
+    ep_enabled: bool,
+) -> BitWriterResult<()> {
+    if !matches!(nalu_type, NaluType::PrefixSeiNut | NaluType::SuffixSeiNut) || temporal_id > 6 {
+        return Err(BitWriterError::Io(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!(
+                "invalid SEI NALU type {:?} or temporal id {}",
+                nalu_type, temporal_id
+            ),
+        )));
+    }
+
+    // Start code and NALU header, with nuh_layer_id equal to 0.
+    writer.write_all(&[
+        0x00,
+        0x00,
+        0x00,
+        0x01,
+        (nalu_type as u8) << 1,
+        temporal_id + 1,
+    ])?;
+
+    let mut writer = BitWriter::new(writer, ep_enabled);
+
+    for message in &sei.messages {
+        // Write the payload without emulation prevention first, so its size
+        // is known before it is written into the NALU.
+        let mut payload = Vec::<u8>::new();
+        {
+            let mut payload_writer = BitWriter::new(&mut payload, false);
+            match message {
+                SeiMessage::DecodedPictureHash(hash) => {
+                    payload_writer.write_bytes(&hash.payload())?
+                }
+                SeiMessage::TimeCode(tc) => synthesize_time_code(tc, &mut payload_writer)?,
+                SeiMessage::Unknown { payload, .. } => payload_writer.write_bytes(payload)?,
+            }
+
+            // payload_bit_equal_to_one and payload_bit_equal_to_zero.
+            if !payload_writer.aligned() {
+                payload_writer.write_f(1, 1u32)?;
+                while !payload_writer.aligned() {
+                    payload_writer.write_f(1, 0u32)?;
+                }
+            }
+        }
+
+        write_sei_value(message.payload_type(), &mut writer)?;
+        write_sei_value(payload.len() as u32, &mut writer)?;
+        writer.write_bytes(&payload)?;
+    }
+
+    // rbsp_trailing_bits()
+    writer.write_f(1, 1u32)?;
+    while !writer.aligned() {
+        writer.write_f(1, 0u32)?;
+    }
+
+    Ok(())
+}
+
+/// Writes a `payloadType` or `payloadSize` value.
+fn write_sei_value<W: Write>(mut value: u32, writer: &mut BitWriter<W>) -> BitWriterResult<()> {
+    while value >= 0xff {
+        writer.write_u(8, 0xffu32)?;
+        value -= 0xff;
+    }
+
+    writer.write_u(8, value)?;
+    Ok(())
+}
+
+/// Writes a time code SEI message payload (H.265 D.2.27). The clock
+/// timestamps are always written in full.
+fn synthesize_time_code<W: Write>(tc: &TimeCode, writer: &mut BitWriter<W>) -> BitWriterResult<()> {
+    if tc.clock_timestamps.len() > TimeCode::MAX_NUM_CLOCK_TS {
+        return Err(BitWriterError::Overflow);
+    }
+
+    writer.write_u(2, tc.clock_timestamps.len() as u32)?;
+
+    for timecode in &tc.clock_timestamps {
+        writer.write_u(1, /* clock_timestamp_flag */ timecode.is_some())?;
+        let Some(timecode) = timecode else {
+            continue;
+        };
+
+        writer.write_u(1, timecode.field_based)?;
+        writer.write_u(5, timecode.counting_type)?;
+        writer.write_u(1, /* full_timestamp_flag */ true)?;
+        writer.write_u(1, timecode.discontinuity)?;
+        writer.write_u(1, timecode.cnt_dropped)?;
+        writer.write_u(9, timecode.frames)?;
+        writer.write_u(6, timecode.seconds)?;
+        writer.write_u(6, timecode.minutes)?;
+        writer.write_u(5, timecode.hours)?;
+
+        let time_offset_length = time_offset_length(timecode)?;
+        writer.write_u(5, time_offset_length as u32)?;
+        if time_offset_length > 0 {
+            let mask = u32::MAX >> (32 - time_offset_length);
+            writer.write_u(time_offset_length, timecode.time_offset as u32 & mask)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Returns the smallest `time_offset_length` able to hold the two's
+/// complement representation of `timecode.time_offset`.
+fn time_offset_length(timecode: &Timecode) -> BitWriterResult<usize> {
+    let offset = timecode.time_offset;
+    let length = match offset {
+        0 => 0,
+        1.. => 33 - offset.leading_zeros() as usize,
+        _ => 33 - (!offset).leading_zeros() as usize,
+    };
+
+    // time_offset_length is coded on 5 bits.
+    if length > 31 {
+        return Err(BitWriterError::Overflow);
+    }
+
+    Ok(length)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::codec::h265::parser::Nalu;
+    use crate::codec::h265::parser::Parser;
+    use crate::utils::picture_hash::PictureHash;
+
+    #[test]
+    fn synthesize_time_code_sei() {
+        let timecode = Timecode {
+            field_based: true,
+            discontinuity: true,
+            time_offset: -1000,
+            ..Timecode::from_frame_number(123_456, 60, true).unwrap()
+        };
+
+        let sei = Sei {
+            messages: vec![
+                SeiMessage::TimeCode(TimeCode {
+                    clock_timestamps: vec![
+                        None,
+                        Some(timecode),
+                        Some("05:06:07:08".parse().unwrap()),
+                    ],
+                }),
+                SeiMessage::Unknown {
+                    payload_type: 300,
+                    // Requires emulation prevention.
+                    payload: vec![0, 0, 0, 1],
+                },
+            ],
+        };
+
+        let mut buf = Vec::<u8>::new();
+        synthesize_sei(&sei, NaluType::PrefixSeiNut, 0, &mut buf, true).unwrap();
+        synthesize_sei(&sei, NaluType::SuffixSeiNut, 2, &mut buf, true).unwrap();
+
+        let mut cursor = Cursor::new(&buf[..]);
+        let mut parser = Parser::default();
+
+        let nalu = Nalu::next(&mut cursor).unwrap();
+        assert_eq!(nalu.header.type_, NaluType::PrefixSeiNut);
+        assert_eq!(parser.parse_sei(&nalu).unwrap(), sei);
+
+        let nalu = Nalu::next(&mut cursor).unwrap();
+        assert_eq!(nalu.header.type_, NaluType::SuffixSeiNut);
+        assert_eq!(nalu.header.nuh_temporal_id_plus1, 3);
+        assert_eq!(parser.parse_sei(&nalu).unwrap(), sei);
+
+        // A partial timestamp only carrying the seconds: clock_timestamp_flag
+        // 1, units_field_based_flag 0, counting_type 0, full_timestamp_flag 0,
+        // discontinuity_flag 0, cnt_dropped_flag 0, n_frames 7,
+        // seconds_flag 1, seconds_value 30, minutes_flag 0,
+        // time_offset_length 0.
+        let partial = [
+            0x00, 0x00, 0x00, 0x01, 0x4e, 0x01, 0x88, 0x05, 0x60, 0x00, 0x3d, 0xe0, 0x20, 0x80,
+        ];
+        let nalu = Nalu::next(&mut Cursor::new(&partial[..])).unwrap();
+        let sei = parser.parse_sei(&nalu).unwrap();
+        let SeiMessage::TimeCode(tc) = &sei.messages[0] else {
+            panic!("time code message expected");
+        };
+        // The minutes and hours are those of the last timestamp.
+        assert_eq!(tc.clock_timestamps[0].unwrap().to_string(), "05:06:30:07");
+    }
+
+    #[test]
+    fn synthesize_decoded_picture_hash_sei() {
+        // The MD5 hashes require emulation prevention.
+        let hash = PictureHash::Md5(vec![[0x5a; 16], [0; 16], [1; 16]]);
+        let sei = Sei {
+            messages: vec![SeiMessage::DecodedPictureHash(hash)],
+        };
+
+        let mut buf = Vec::<u8>::new();
+        synthesize_sei(&sei, NaluType::SuffixSeiNut, 0, &mut buf, true).unwrap();
+
+        let nalu = Nalu::next(&mut Cursor::new(&buf[..])).unwrap();
+        assert_eq!(Parser::default().parse_sei(&nalu).unwrap(), sei);
+    }
+}
@@ -0,0 +1,352 @@
+// Copyright 2024 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Tracking of parameter sets and of the sequence format they describe.
+//!
+//! [`ParamSetStore`] keeps the parameter sets of a stream (H.264/H.265 VPS, SPS and PPS) by id
+//! and reports whether a newly received set is new, a repetition of the stored one, or replaces
+//! it. Sequence-level headers of all codecs implement [`SequenceParams`], which allows the store
+//! to detect when activating a set changes the format of the decoded frames, e.g. their
+//! resolution, bit depth or chroma subsampling. The resulting [`SequenceChange`] tells the
+//! application whether its frame pools need to be reallocated or its formats renegotiated.
+
+use std::collections::BTreeMap;
+use std::rc::Rc;
+
+use anyhow::anyhow;
+
+use crate::Resolution;
+
+/// Chroma subsampling of a sequence.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum ChromaFormat {
+    Monochrome,
+    #[default]
+    Yuv420,
+    Yuv422,
+    Yuv444,
+}
+
+impl ChromaFormat {
+    /// Returns the chroma format corresponding to a H.264/H.265 `chroma_format_idc`.
+    pub fn from_chroma_format_idc(chroma_format_idc: u8) -> Option<Self> {
+        match chroma_format_idc {
+            0 => Some(ChromaFormat::Monochrome),
+            1 => Some(ChromaFormat::Yuv420),
+            2 => Some(ChromaFormat::Yuv422),
+            3 => Some(ChromaFormat::Yuv444),
+            _ => None,
+        }
+    }
+
+    /// Returns the chroma format corresponding to the AV1/VP9 subsampling flags.
+    pub fn from_subsampling(monochrome: bool, subsampling_x: bool, subsampling_y: bool) -> Self {
+        match (monochrome, subsampling_x, subsampling_y) {
+            (true, _, _) => ChromaFormat::Monochrome,
+            (false, true, true) => ChromaFormat::Yuv420,
+            (false, true, false) => ChromaFormat::Yuv422,
+            // 4:4:0 is not representable, treat it like 4:4:4 as far as allocation goes.
+            (false, false, _) => ChromaFormat::Yuv444,
+        }
+    }
+}
+
+/// Properties of a sequence that affect the frames it decodes into.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct SequenceFormat {
+    /// Minimum size of the frames to decode into.
+    pub coded_resolution: Resolution,
+    /// Part of the frames meant to be displayed.
+    pub display_resolution: Resolution,
+    /// Bit depth of the luma samples.
+    pub bit_depth_luma: u8,
+    /// Bit depth of the chroma samples.
+    pub bit_depth_chroma: u8,
+    /// Chroma subsampling.
+    pub chroma_format: ChromaFormat,
+}
+
+/// Trait for the sequence-level headers of a codec.
+pub trait SequenceParams {
+    /// Returns the format of the frames described by this header.
+    fn sequence_format(&self) -> SequenceFormat;
+}
+
+/// A change of sequence format, returned when a parameter set describing a different format than
+/// the previously active one is activated.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct SequenceChange {
+    /// Format of the previously active parameter set, or `None` if this is the first activation.
+    pub previous: Option<SequenceFormat>,
+    /// Format of the newly active parameter set.
+    pub current: SequenceFormat,
+}
+
+impl SequenceChange {
+    /// Returns `true` if the coded or display resolution changed.
+    pub fn resolution_changed(&self) -> bool {
+        self.previous.is_none_or(|p| {
+            p.coded_resolution != self.current.coded_resolution
+                || p.display_resolution != self.current.display_resolution
+        })
+    }
+
+    /// Returns `true` if the luma or chroma bit depth changed.
+    pub fn bit_depth_changed(&self) -> bool {
+        self.previous.is_none_or(|p| {
+            p.bit_depth_luma != self.current.bit_depth_luma
+                || p.bit_depth_chroma != self.current.bit_depth_chroma
+        })
+    }
+
+    /// Returns `true` if the chroma subsampling changed.
+    pub fn chroma_format_changed(&self) -> bool {
+        self.previous
+            .is_none_or(|p| p.chroma_format != self.current.chroma_format)
+    }
+
+    /// Returns `true` if frames allocated for the previous format cannot hold frames of the
+    /// current one, i.e. if the coded resolution grew or the pixel format changed.
+    pub fn needs_reallocation(&self) -> bool {
+        self.previous.is_none_or(|p| {
+            !p.coded_resolution
+                .can_contain(self.current.coded_resolution)
+                || self.bit_depth_changed()
+                || self.chroma_format_changed()
+        })
+    }
+}
+
+/// Outcome of inserting a parameter set into a [`ParamSetStore`].
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ParamSetUpdate {
+    /// No parameter set was stored with this id.
+    New,
+    /// The parameter set is identical to the stored one, e.g. because it is repeated before
+    /// every IDR.
+    Unchanged,
+    /// The parameter set replaces a different one with the same id.
+    Replaced,
+}
+
+/// Parameter sets of a given type, indexed by id.
+#[derive(Debug)]
+pub struct ParamSetStore<T> {
+    sets: BTreeMap<u8, Rc<T>>,
+    /// Number of ids allowed by the codec.
+    max_count: usize,
+    active_id: Option<u8>,
+    /// Format of the parameter set that was active when `activate` was last called. Kept
+    /// separately as the active set can be replaced under the same id.
+    active_format: Option<SequenceFormat>,
+}
+
+// Not derived, as the sets are shared and do not need to be `Clone`.
+impl<T> Clone for ParamSetStore<T> {
+    fn clone(&self) -> Self {
+        Self {
+            sets: self.sets.clone(),
+            max_count: self.max_count,
+            active_id: self.active_id,
+            active_format: self.active_format,
+        }
+    }
+}
+
+impl<T> Default for ParamSetStore<T> {
+    fn default() -> Self {
+        Self::new(usize::from(u8::MAX) + 1)
+    }
+}
+
+impl<T> ParamSetStore<T> {
+    /// Creates an empty store accepting ids from 0 to `max_count - 1`.
+    pub fn new(max_count: usize) -> Self {
+        Self {
+            sets: Default::default(),
+            max_count,
+            active_id: None,
+            active_format: None,
+        }
+    }
+
+    /// Returns the parameter set with id `id`, if any.
+    pub fn get(&self, id: u8) -> Option<&Rc<T>> {
+        self.sets.get(&id)
+    }
+
+    /// Returns the id of the active parameter set, if any.
+    pub fn active_id(&self) -> Option<u8> {
+        self.active_id
+    }
+
+    /// Returns the active parameter set, if any.
+    pub fn active(&self) -> Option<&Rc<T>> {
+        self.active_id.and_then(|id| self.get(id))
+    }
+
+    /// Returns the number of stored parameter sets.
+    pub fn len(&self) -> usize {
+        self.sets.len()
+    }
+
+    /// Returns `true` if no parameter set is stored.
+    pub fn is_empty(&self) -> bool {
+        self.sets.is_empty()
+    }
+
+    /// Iterates over the stored parameter sets in increasing id order.
+    pub fn iter(&self) -> impl Iterator<Item = (u8, &Rc<T>)> {
+        self.sets.iter().map(|(id, set)| (*id, set))
+    }
+
+    /// Removes the parameter set with id `id`, returning it.
+    pub fn remove(&mut self, id: u8) -> Option<Rc<T>> {
+        if self.active_id == Some(id) {
+            self.active_id = None;
+        }
+        self.sets.remove(&id)
+    }
+
+    /// Removes all parameter sets and forgets the active format.
+    pub fn clear(&mut self) {
+        self.sets.clear();
+        self.active_id = None;
+        self.active_format = None;
+    }
+}
+
+impl<T: PartialEq> ParamSetStore<T> {
+    /// Stores `set` under `id`, replacing any previous set with this id.
+    pub fn insert(&mut self, id: u8, set: T) -> anyhow::Result<ParamSetUpdate> {
+        if usize::from(id) >= self.max_count {
+            return Err(anyhow!(
+                "parameter set id {} out of range, maximum is {}",
+                id,
+                self.max_count - 1
+            ));
+        }
+
+        let update = match self.sets.get(&id) {
+            None => ParamSetUpdate::New,
+            Some(prev) if **prev == set => return Ok(ParamSetUpdate::Unchanged),
+            Some(_) => ParamSetUpdate::Replaced,
+        };
+
+        self.sets.insert(id, Rc::new(set));
+        Ok(update)
+    }
+}
+
+impl<T: SequenceParams> ParamSetStore<T> {
+    /// Makes the parameter set with id `id` the active one.
+    ///
+    /// Returns a [`SequenceChange`] if its format differs from the one of the previously
+    /// activated set, including when the active set has been replaced by a set with the same id
+    /// but a different format.
+    pub fn activate(&mut self, id: u8) -> anyhow::Result<Option<SequenceChange>> {
+        let set = self
+            .sets
+            .get(&id)
+            .ok_or_else(|| anyhow!("no parameter set with id {}", id))?;
+
+        let current = set.sequence_format();
+        let previous = self.active_format.replace(current);
+        self.active_id = Some(id);
+
+        if previous == Some(current) {
+            Ok(None)
+        } else {
+            Ok(Some(SequenceChange { previous, current }))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, PartialEq)]
+    struct TestSps {
+        width: u32,
+        height: u32,
+        bit_depth: u8,
+    }
+
+    impl SequenceParams for TestSps {
+        fn sequence_format(&self) -> SequenceFormat {
+            let resolution = Resolution::from((self.width, self.height));
+            SequenceFormat {
+                coded_resolution: resolution,
+                display_resolution: resolution,
+                bit_depth_luma: self.bit_depth,
+                bit_depth_chroma: self.bit_depth,
+                chroma_format: ChromaFormat::Yuv420,
+            }
+        }
+    }
+
+    fn sps(width: u32, height: u32, bit_depth: u8) -> TestSps {
+        TestSps {
+            width,
+            height,
+            bit_depth,
+        }
+    }
+
+    #[test]
+    fn insert_updates() {
+        let mut store = ParamSetStore::new(32);
+
+        assert_eq!(
+            store.insert(0, sps(320, 240, 8)).unwrap(),
+            ParamSetUpdate::New
+        );
+        assert_eq!(
+            store.insert(0, sps(320, 240, 8)).unwrap(),
+            ParamSetUpdate::Unchanged
+        );
+        assert_eq!(
+            store.insert(0, sps(640, 480, 8)).unwrap(),
+            ParamSetUpdate::Replaced
+        );
+        assert_eq!(
+            store.insert(1, sps(640, 480, 8)).unwrap(),
+            ParamSetUpdate::New
+        );
+        assert!(store.insert(32, sps(640, 480, 8)).is_err());
+        assert_eq!(store.len(), 2);
+        assert_eq!(store.get(0).unwrap().width, 640);
+    }
+
+    #[test]
+    fn activation_changes() {
+        let mut store = ParamSetStore::new(32);
+        store.insert(0, sps(320, 240, 8)).unwrap();
+        store.insert(1, sps(320, 240, 8)).unwrap();
+        store.insert(2, sps(320, 240, 10)).unwrap();
+
+        assert!(store.activate(3).is_err());
+
+        let change = store.activate(0).unwrap().unwrap();
+        assert_eq!(change.previous, None);
+        assert!(change.needs_reallocation());
+
+        // Same format under a different id.
+        assert_eq!(store.activate(1).unwrap(), None);
+        assert_eq!(store.active_id(), Some(1));
+
+        let change = store.activate(2).unwrap().unwrap();
+        assert!(change.bit_depth_changed());
+        assert!(!change.resolution_changed());
+        assert!(!change.chroma_format_changed());
+
+        // The active set is replaced mid-stream with a smaller resolution.
+        store.insert(2, sps(160, 120, 10)).unwrap();
+        let change = store.activate(2).unwrap().unwrap();
+        assert!(change.resolution_changed());
+        assert!(!change.needs_reallocation());
+        assert_eq!(store.activate(2).unwrap(), None);
+    }
+}
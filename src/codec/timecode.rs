@@ -0,0 +1,258 @@
+// Copyright 2024 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Codec-independent representation of the timecodes carried by H.264 picture
+//! timing SEI messages, H.265 time code SEI messages and AV1 timecode metadata
+//! OBUs.
+//!
+//! All three codecs use the same clock timestamp syntax, derived from SMPTE ST
+//! 12-1, so a [`Timecode`] can be converted from one codec to another without
+//! loss, save for the ranges of `n_frames` and `time_offset`.
+
+use std::fmt;
+use std::str::FromStr;
+
+use anyhow::anyhow;
+
+/// `counting_type` value used by drop-frame timecodes, where frame numbers 0
+/// and 1 are skipped at the start of every minute that is not a multiple of
+/// 10. See Table D-3 of the H.264 specification.
+pub const COUNTING_TYPE_DROP_FRAME: u8 = 4;
+
+/// A timecode, as carried by a clock timestamp.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Timecode {
+    /// Hours, from 0 to 23.
+    pub hours: u8,
+    /// Minutes, from 0 to 59.
+    pub minutes: u8,
+    /// Seconds, from 0 to 59.
+    pub seconds: u8,
+    /// Number of the frame, or field if `field_based` is set, within the
+    /// second. Corresponds to `n_frames`.
+    pub frames: u16,
+    /// Method used to skip frame numbers, as per Table D-3 of H.264. See
+    /// [`COUNTING_TYPE_DROP_FRAME`].
+    pub counting_type: u8,
+    /// Whether `frames` counts fields instead of frames. Corresponds to
+    /// `nuit_field_based_flag` in H.264 and `units_field_based_flag` in H.265.
+    /// Not carried by AV1.
+    pub field_based: bool,
+    /// Whether the difference with the previous timecode cannot be
+    /// interpreted as the time between the two frames.
+    pub discontinuity: bool,
+    /// Whether frame numbers were skipped before this one, as per
+    /// `counting_type`.
+    pub cnt_dropped: bool,
+    /// Offset to apply to the timecode, in clock ticks.
+    pub time_offset: i32,
+}
+
+impl Timecode {
+    /// Returns `true` if this is a drop-frame timecode.
+    pub fn is_drop_frame(&self) -> bool {
+        self.counting_type == COUNTING_TYPE_DROP_FRAME
+    }
+
+    /// Number of frame numbers skipped at the start of each minute by
+    /// drop-frame timecodes running at `fps` frames per second.
+    fn dropped_frames(fps: u32) -> u32 {
+        fps / 15
+    }
+
+    /// Builds the timecode of the frame with index `frame_number` in a
+    /// stream of `fps` (rounded, e.g. 30 for 29.97) frames per second,
+    /// starting at 00:00:00:00. If `drop_frame` is set, the drop-frame
+    /// counting is used, which requires `fps` to be a multiple of 30.
+    ///
+    /// The hours wrap around after 24 hours.
+    pub fn from_frame_number(
+        frame_number: u64,
+        fps: u32,
+        drop_frame: bool,
+    ) -> anyhow::Result<Self> {
+        if fps == 0 || fps > u32::from(u16::MAX) {
+            return Err(anyhow!("Invalid timecode frame rate {}", fps));
+        }
+
+        let remainder = fps % 30;
+        if drop_frame && remainder != 0 {
+            return Err(anyhow!(
+                "Drop-frame timecodes require a multiple of 30 fps, got {}",
+                fps
+            ));
+        }
+
+        let fps = u64::from(fps);
+        let mut frame_number = frame_number;
+
+        if drop_frame {
+            let drop = u64::from(Self::dropped_frames(fps as u32));
+            let frames_per_minute = fps * 60 - drop;
+            let frames_per_10_minutes = fps * 600 - drop * 9;
+
+            let tens = frame_number / frames_per_10_minutes;
+            let rem = frame_number % frames_per_10_minutes;
+
+            // Add back the frame numbers skipped so far.
+            frame_number += drop * 9 * tens;
+            if rem > drop {
+                frame_number += drop * ((rem - drop) / frames_per_minute);
+            }
+        }
+
+        let seconds = frame_number / fps;
+
+        Ok(Self {
+            hours: (seconds / 3600 % 24) as u8,
+            minutes: (seconds / 60 % 60) as u8,
+            seconds: (seconds % 60) as u8,
+            frames: (frame_number % fps) as u16,
+            counting_type: if drop_frame {
+                COUNTING_TYPE_DROP_FRAME
+            } else {
+                0
+            },
+            ..Default::default()
+        })
+    }
+
+    /// Returns the index of the frame of this timecode in a stream of `fps`
+    /// (rounded) frames per second starting at 00:00:00:00. This is the
+    /// reverse of [`Timecode::from_frame_number`].
+    pub fn frame_number(&self, fps: u32) -> u64 {
+        let fps = u64::from(fps);
+        let total_minutes = 60 * u64::from(self.hours) + u64::from(self.minutes);
+        let mut frame_number =
+            (60 * total_minutes + u64::from(self.seconds)) * fps + u64::from(self.frames);
+
+        if self.is_drop_frame() {
+            let drop = u64::from(Self::dropped_frames(fps as u32));
+            frame_number = frame_number.saturating_sub(drop * (total_minutes - total_minutes / 10));
+        }
+
+        frame_number
+    }
+}
+
+impl fmt::Display for Timecode {
+    /// Formats the timecode as `HH:MM:SS:FF`, or `HH:MM:SS;FF` for drop-frame
+    /// timecodes.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let separator = if self.is_drop_frame() { ';' } else { ':' };
+        write!(
+            f,
+            "{:02}:{:02}:{:02}{}{:02}",
+            self.hours, self.minutes, self.seconds, separator, self.frames
+        )
+    }
+}
+
+impl FromStr for Timecode {
+    type Err = anyhow::Error;
+
+    /// Parses a timecode in the `HH:MM:SS:FF` format. A `;` or `.` before the
+    /// frames denotes a drop-frame timecode.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || anyhow!("Invalid timecode {:?}, expected HH:MM:SS:FF", s);
+
+        let frames_pos = s.rfind([':', ';', '.']).ok_or_else(invalid)?;
+        let drop_frame = !s[frames_pos..].starts_with(':');
+
+        let mut fields = s[..frames_pos].split(':');
+        let mut next_field = |max: u8| -> anyhow::Result<u8> {
+            let value = fields
+                .next()
+                .and_then(|field| field.parse::<u8>().ok())
+                .ok_or_else(invalid)?;
+            if value > max {
+                return Err(invalid());
+            }
+            Ok(value)
+        };
+
+        let timecode = Timecode {
+            hours: next_field(23)?,
+            minutes: next_field(59)?,
+            seconds: next_field(59)?,
+            frames: s[frames_pos + 1..].parse().map_err(|_| invalid())?,
+            counting_type: if drop_frame {
+                COUNTING_TYPE_DROP_FRAME
+            } else {
+                0
+            },
+            ..Default::default()
+        };
+
+        if fields.next().is_some() {
+            return Err(invalid());
+        }
+
+        Ok(timecode)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_and_format() {
+        let tc: Timecode = "01:02:03:04".parse().unwrap();
+        assert_eq!(
+            tc,
+            Timecode {
+                hours: 1,
+                minutes: 2,
+                seconds: 3,
+                frames: 4,
+                ..Default::default()
+            }
+        );
+        assert_eq!(tc.to_string(), "01:02:03:04");
+
+        let tc: Timecode = "10:00:00;02".parse().unwrap();
+        assert!(tc.is_drop_frame());
+        assert_eq!(tc.to_string(), "10:00:00;02");
+
+        for invalid in [
+            "",
+            "01:02:03",
+            "24:00:00:00",
+            "00:60:00:00",
+            "a:b:c:d",
+            "00:00:00:00:00",
+        ] {
+            assert!(invalid.parse::<Timecode>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn frame_numbers() {
+        let tc = Timecode::from_frame_number(25 * 3661 + 7, 25, false).unwrap();
+        assert_eq!(tc.to_string(), "01:01:01:07");
+        assert_eq!(tc.frame_number(25), 25 * 3661 + 7);
+
+        // The first minute of drop-frame timecode has 1800 frames.
+        let tc = Timecode::from_frame_number(1799, 30, true).unwrap();
+        assert_eq!(tc.to_string(), "00:00:59;29");
+        let tc = Timecode::from_frame_number(1800, 30, true).unwrap();
+        assert_eq!(tc.to_string(), "00:01:00;02");
+        // Every tenth minute keeps its first frame numbers.
+        let tc = Timecode::from_frame_number(17982, 30, true).unwrap();
+        assert_eq!(tc.to_string(), "00:10:00;00");
+        let tc = Timecode::from_frame_number(107892, 30, true).unwrap();
+        assert_eq!(tc.to_string(), "01:00:00;00");
+
+        for frame_number in (0..200_000).step_by(997) {
+            for (fps, drop_frame) in [(30, true), (60, true), (24, false), (50, false)] {
+                let tc = Timecode::from_frame_number(frame_number, fps, drop_frame).unwrap();
+                assert_eq!(tc.frame_number(fps), frame_number);
+            }
+        }
+
+        assert!(Timecode::from_frame_number(0, 25, true).is_err());
+        assert!(Timecode::from_frame_number(0, 0, false).is_err());
+    }
+}
@@ -10,6 +10,9 @@ use bytes::Buf;
 use log::debug;
 use thiserror::Error;
 
+use crate::codec::param_sets::ChromaFormat;
+use crate::codec::param_sets::SequenceFormat;
+use crate::codec::param_sets::SequenceParams;
 use crate::codec::vp8::bool_decoder::BoolDecoder;
 use crate::codec::vp8::bool_decoder::BoolDecoderResult;
 use crate::codec::vp8::bool_decoder::BoolDecoderState;
@@ -21,6 +24,7 @@ use crate::codec::vp8::probs::MV_DEFAULT_PROBS;
 use crate::codec::vp8::probs::MV_UPDATE_PROBS;
 use crate::codec::vp8::probs::NK_UV_MODE_PROBS;
 use crate::codec::vp8::probs::NK_Y_MODE_PROBS;
+use crate::Resolution;
 
 /// Dequantization indices as parsed from the quant_indices() syntax.
 #[derive(Clone, Debug, Default, PartialEq, Eq)]
@@ -198,6 +202,26 @@ pub struct Header {
 enum ParseUncompressedChunkError {
     #[error("invalid start code {0}")]
     InvalidStartCode(u32),
+    #[error("uncompressed data chunk is truncated")]
+    Truncated,
+}
+
+impl SequenceParams for Header {
+    fn sequence_format(&self) -> SequenceFormat {
+        let resolution = Resolution {
+            width: u32::from(self.width),
+            height: u32::from(self.height),
+        };
+
+        // VP8 only supports 8-bit 4:2:0.
+        SequenceFormat {
+            coded_resolution: resolution,
+            display_resolution: resolution,
+            bit_depth_luma: 8,
+            bit_depth_chroma: 8,
+            chroma_format: ChromaFormat::Yuv420,
+        }
+    }
 }
 
 impl Header {
@@ -235,6 +259,10 @@ impl Header {
 
         let mut reader = Cursor::new(bitstream);
 
+        if reader.remaining() < 3 {
+            return Err(ParseUncompressedChunkError::Truncated);
+        }
+
         let frame_tag = reader.get_uint_le(3) as u32;
 
         let mut header = Header {
@@ -246,6 +274,10 @@ impl Header {
         };
 
         if header.key_frame {
+            if reader.remaining() < 7 {
+                return Err(ParseUncompressedChunkError::Truncated);
+            }
+
             let start_code = reader.get_uint(3) as u32;
 
             if start_code != 0x9d012a {
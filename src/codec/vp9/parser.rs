@@ -4,15 +4,20 @@
 
 use anyhow::anyhow;
 use anyhow::Context;
-use bitreader::BitReader;
 use enumn::N;
 
+use crate::codec::common::bit_reader::BitReader;
+use crate::codec::param_sets::ChromaFormat;
+use crate::codec::param_sets::SequenceFormat;
+use crate::codec::param_sets::SequenceParams;
 use crate::codec::vp9::lookups::AC_QLOOKUP;
 use crate::codec::vp9::lookups::AC_QLOOKUP_10;
 use crate::codec::vp9::lookups::AC_QLOOKUP_12;
 use crate::codec::vp9::lookups::DC_QLOOKUP;
 use crate::codec::vp9::lookups::DC_QLOOKUP_10;
 use crate::codec::vp9::lookups::DC_QLOOKUP_12;
+use crate::codec::vp9::superframe;
+use crate::Resolution;
 
 pub const REFS_PER_FRAME: usize = 3;
 
@@ -51,6 +56,32 @@ pub const MAX_TILE_WIDTH_B64: u32 = 64;
 /// The number of pictures in the DPB
 pub const NUM_REF_FRAMES: usize = 8;
 
+/// Precision of the scale factors of the reference frames, see 8.5.2.3.
+pub const REF_SCALE_SHIFT: u32 = 14;
+
+/// Returns the horizontal and vertical scale factors, in units of `1 / (1 << REF_SCALE_SHIFT)`,
+/// with which a frame of size `frame` predicts from a reference frame of size `reference`, as per
+/// 8.5.2.3. Returns `None` if the frame cannot use the reference, i.e. if the reference is more
+/// than twice as large or more than 16 times smaller in either dimension (7.2).
+///
+/// Scaled references let the resolution change on inter frames, e.g. for the dynamic resize of
+/// real-time encoders, which adapts faster than waiting for the next keyframe.
+pub fn reference_scale(frame: Resolution, reference: Resolution) -> Option<(u32, u32)> {
+    let scale = |size: u32, ref_size: u32| {
+        let (size, ref_size) = (u64::from(size), u64::from(ref_size));
+        if size == 0 || 2 * size < ref_size || size > 16 * ref_size {
+            return None;
+        }
+
+        u32::try_from((ref_size << REF_SCALE_SHIFT) / size).ok()
+    };
+
+    Some((
+        scale(frame.width, reference.width)?,
+        scale(frame.height, reference.height)?,
+    ))
+}
+
 /// A clamp such that min <= x <= max
 fn clamp<U: PartialOrd>(x: U, low: U, high: U) -> U {
     if x > high {
@@ -439,6 +470,28 @@ pub struct Header {
     pub uncompressed_header_size_in_bytes: u16,
 }
 
+impl SequenceParams for Header {
+    fn sequence_format(&self) -> SequenceFormat {
+        SequenceFormat {
+            coded_resolution: Resolution {
+                width: self.width,
+                height: self.height,
+            },
+            display_resolution: Resolution {
+                width: self.render_width,
+                height: self.render_height,
+            },
+            bit_depth_luma: self.bit_depth as u8,
+            bit_depth_chroma: self.bit_depth as u8,
+            chroma_format: ChromaFormat::from_subsampling(
+                false,
+                self.subsampling_x,
+                self.subsampling_y,
+            ),
+        }
+    }
+}
+
 impl Header {
     /// An implementation of seg_feature_active as per "6.4.9 Segmentation feature active syntax"
     pub fn seg_feature_active(&self, segment_id: u8, feature: u8) -> bool {
@@ -493,16 +546,6 @@ impl Header {
     }
 }
 
-/// The VP9 superframe header as per Annex B, B.2.1, B.2.2
-struct SuperframeHeader {
-    /// Indicates the number of frames within this superframe. NOTE - It is
-    /// legal for a superframe to contain just a single frame and have NumFrames
-    /// equal to 1.
-    frames_in_superframe: u32,
-    /// Specifies the size in bytes of frame number i (zero indexed) within this
-    /// superframe.
-    frame_sizes: Vec<usize>,
-}
 /// A VP9 bitstream parser.
 #[derive(Clone, Debug, Default, PartialEq, Eq)]
 pub struct Parser {
@@ -524,77 +567,10 @@ pub struct Parser {
 }
 
 impl Parser {
-    fn parse_superframe_hdr(resource: impl AsRef<[u8]>) -> anyhow::Result<SuperframeHeader> {
-        let bitstream = resource.as_ref();
-
-        // Skip to the end of the chunk.
-        let mut reader = BitReader::new(&bitstream[bitstream.len() - 1..]);
-
-        // Try reading a superframe marker.
-        let marker = reader.read_u32(3)?;
-
-        if marker != SUPERFRAME_MARKER {
-            // Not a superframe
-            return Ok(SuperframeHeader {
-                frames_in_superframe: 1,
-                frame_sizes: vec![bitstream.len()],
-            });
-        }
-
-        let bytes_per_framesize = reader.read_u32(2)? + 1;
-        let frames_in_superframe = reader.read_u32(3)? + 1;
-
-        if frames_in_superframe > MAX_FRAMES_IN_SUPERFRAME as u32 {
-            return Err(anyhow!(
-                "Broken stream: too many frames in superframe, expected a maximum of {:?}, found {:?}",
-                MAX_FRAMES_IN_SUPERFRAME,
-                frames_in_superframe
-            ));
-        }
-
-        let sz_index = 2 + frames_in_superframe * bytes_per_framesize;
-
-        let data = resource.as_ref();
-        let index_offset = data.len() - sz_index as usize;
-        let first_byte = data[index_offset];
-        let last_byte = *data
-            .last()
-            .ok_or_else(|| anyhow!("superframe header is empty"))?;
-
-        if first_byte != last_byte {
-            // Also not a superframe, we must pass both tests as per the specification.
-            return Ok(SuperframeHeader {
-                frames_in_superframe: 1,
-                frame_sizes: vec![bitstream.len()],
-            });
-        }
-
-        let mut frame_sizes = vec![];
-        let mut reader = BitReader::new(&bitstream[index_offset..]);
-
-        // Skip the superframe header.
-        let _ = reader.read_u32(8)?;
-
-        for _ in 0..frames_in_superframe {
-            let mut frame_size = 0;
-
-            for j in 0..bytes_per_framesize {
-                frame_size |= reader.read_u32(8)? << (j * 8);
-            }
-
-            frame_sizes.push(frame_size as usize);
-        }
-
-        Ok(SuperframeHeader {
-            frames_in_superframe,
-            frame_sizes,
-        })
-    }
-
     fn read_signed_8(r: &mut BitReader, nbits: u8) -> anyhow::Result<i8> {
-        let value = r.read_u8(nbits)?;
+        let value = r.read_bits::<u8>(usize::from(nbits))?;
 
-        let negative = r.read_bool()?;
+        let negative = r.read_bit()?;
 
         if negative {
             Ok(-(value as i8))
@@ -604,7 +580,7 @@ impl Parser {
     }
 
     fn parse_frame_marker(r: &mut BitReader) -> anyhow::Result<()> {
-        let marker = r.read_u32(2)?;
+        let marker = r.read_bits::<u32>(2)?;
 
         if marker != FRAME_MARKER {
             return Err(anyhow!(
@@ -617,14 +593,14 @@ impl Parser {
     }
 
     fn parse_profile(r: &mut BitReader, hdr: &mut Header) -> anyhow::Result<()> {
-        let low = r.read_u32(1)?;
-        let high = r.read_u32(1)?;
+        let low = r.read_bits::<u32>(1)?;
+        let high = r.read_bits::<u32>(1)?;
 
         let profile = (high << 1) | low;
 
         if profile == 3 {
             // Skip the reserved bit
-            let _ = r.read_bool()?;
+            let _ = r.read_bit()?;
         }
 
         hdr.profile = Profile::n(profile)
@@ -634,7 +610,7 @@ impl Parser {
     }
 
     fn parse_frame_sync_code(r: &mut BitReader) -> anyhow::Result<()> {
-        let sync_code = r.read_u32(24)?;
+        let sync_code = r.read_bits::<u32>(24)?;
 
         if sync_code != SYNC_CODE {
             return Err(anyhow!(
@@ -649,7 +625,7 @@ impl Parser {
 
     fn parse_color_config(&mut self, r: &mut BitReader, hdr: &mut Header) -> anyhow::Result<()> {
         if matches!(hdr.profile, Profile::Profile2 | Profile::Profile3) {
-            let ten_or_twelve_bit = r.read_bool()?;
+            let ten_or_twelve_bit = r.read_bit()?;
             if ten_or_twelve_bit {
                 hdr.bit_depth = BitDepth::Depth12;
             } else {
@@ -659,23 +635,23 @@ impl Parser {
             hdr.bit_depth = BitDepth::Depth8;
         }
 
-        let color_space = r.read_u32(3)?;
+        let color_space = r.read_bits::<u32>(3)?;
         hdr.color_space = ColorSpace::n(color_space)
             .with_context(|| format!("Broken stream: invalid color space: {:?}", color_space))?;
 
         if !matches!(hdr.color_space, ColorSpace::CsSrgb) {
-            let color_range = r.read_u32(1)?;
+            let color_range = r.read_bits::<u32>(1)?;
 
             hdr.color_range = ColorRange::n(color_range).with_context(|| {
                 format!("Broken stream: invalid color range: {:?}", color_range)
             })?;
 
             if matches!(hdr.profile, Profile::Profile1 | Profile::Profile3) {
-                hdr.subsampling_x = r.read_bool()?;
-                hdr.subsampling_y = r.read_bool()?;
+                hdr.subsampling_x = r.read_bit()?;
+                hdr.subsampling_y = r.read_bit()?;
 
                 // Skip the reserved bit
-                let _ = r.read_bool()?;
+                let _ = r.read_bit()?;
             } else {
                 hdr.subsampling_x = true;
                 hdr.subsampling_y = true;
@@ -687,7 +663,7 @@ impl Parser {
                 hdr.subsampling_y = false;
 
                 // Skip the reserved bit
-                let _ = r.read_bool()?;
+                let _ = r.read_bit()?;
             }
         }
 
@@ -708,17 +684,17 @@ impl Parser {
     }
 
     fn parse_frame_size(&mut self, r: &mut BitReader, hdr: &mut Header) -> anyhow::Result<()> {
-        hdr.width = r.read_u32(16)? + 1;
-        hdr.height = r.read_u32(16)? + 1;
+        hdr.width = r.read_bits::<u32>(16)? + 1;
+        hdr.height = r.read_bits::<u32>(16)? + 1;
         self.compute_image_size(hdr.width, hdr.height);
         Ok(())
     }
 
     fn parse_render_size(r: &mut BitReader, hdr: &mut Header) -> anyhow::Result<()> {
-        hdr.render_and_frame_size_different = r.read_bool()?;
+        hdr.render_and_frame_size_different = r.read_bit()?;
         if hdr.render_and_frame_size_different {
-            hdr.render_width = r.read_u32(16)? + 1;
-            hdr.render_height = r.read_u32(16)? + 1;
+            hdr.render_width = r.read_bits::<u32>(16)? + 1;
+            hdr.render_height = r.read_bits::<u32>(16)? + 1;
         } else {
             hdr.render_width = hdr.width;
             hdr.render_height = hdr.height;
@@ -735,7 +711,7 @@ impl Parser {
         let mut found_ref = false;
 
         for i in 0..REFS_PER_FRAME {
-            found_ref = r.read_bool()?;
+            found_ref = r.read_bit()?;
 
             if found_ref {
                 let idx = hdr.ref_frame_idx[i] as usize;
@@ -762,12 +738,12 @@ impl Parser {
             InterpolationFilter::Bilinear,
         ];
 
-        let is_filter_switchable = r.read_bool()?;
+        let is_filter_switchable = r.read_bit()?;
 
         if is_filter_switchable {
             hdr.interpolation_filter = InterpolationFilter::Switchable;
         } else {
-            let raw_interpolation_filter = r.read_u32(2)?;
+            let raw_interpolation_filter = r.read_bits::<u32>(2)?;
             hdr.interpolation_filter = LITERAL_TO_TYPE[raw_interpolation_filter as usize];
         }
 
@@ -793,22 +769,22 @@ impl Parser {
         r: &mut BitReader,
         lf: &mut LoopFilterParams,
     ) -> anyhow::Result<()> {
-        lf.level = r.read_u8(6)?;
-        lf.sharpness = r.read_u8(3)?;
-        lf.delta_enabled = r.read_bool()?;
+        lf.level = r.read_bits::<u8>(6)?;
+        lf.sharpness = r.read_bits::<u8>(3)?;
+        lf.delta_enabled = r.read_bit()?;
 
         if lf.delta_enabled {
-            lf.delta_update = r.read_bool()?;
+            lf.delta_update = r.read_bit()?;
             if lf.delta_update {
                 for i in 0..MAX_REF_LF_DELTAS {
-                    lf.update_ref_delta[i] = r.read_bool()?;
+                    lf.update_ref_delta[i] = r.read_bit()?;
                     if lf.update_ref_delta[i] {
                         lf.ref_deltas[i] = Self::read_signed_8(r, 6)?;
                     }
                 }
 
                 for i in 0..MAX_MODE_LF_DELTAS {
-                    lf.update_mode_delta[i] = r.read_bool()?;
+                    lf.update_mode_delta[i] = r.read_bit()?;
                     if lf.update_mode_delta[i] {
                         lf.mode_deltas[i] = Self::read_signed_8(r, 6)?;
                     }
@@ -820,7 +796,7 @@ impl Parser {
     }
 
     fn read_delta_q(r: &mut BitReader, value: &mut i8) -> anyhow::Result<()> {
-        let delta_coded = r.read_bool()?;
+        let delta_coded = r.read_bit()?;
 
         if delta_coded {
             *value = Self::read_signed_8(r, 4)?;
@@ -834,7 +810,7 @@ impl Parser {
     fn parse_quantization_params(r: &mut BitReader, hdr: &mut Header) -> anyhow::Result<()> {
         let quant = &mut hdr.quant;
 
-        quant.base_q_idx = r.read_u8(8)?;
+        quant.base_q_idx = r.read_bits::<u8>(8)?;
 
         Self::read_delta_q(r, &mut quant.delta_q_y_dc)?;
         Self::read_delta_q(r, &mut quant.delta_q_uv_dc)?;
@@ -849,9 +825,13 @@ impl Parser {
     }
 
     fn read_prob(r: &mut BitReader) -> anyhow::Result<u8> {
-        let prob_coded = r.read_bool()?;
+        let prob_coded = r.read_bit()?;
 
-        let prob = if prob_coded { r.read_u8(8)? } else { 255 };
+        let prob = if prob_coded {
+            r.read_bits::<u8>(8)?
+        } else {
+            255
+        };
 
         Ok(prob)
     }
@@ -866,20 +846,20 @@ impl Parser {
         seg.update_map = false;
         seg.update_data = false;
 
-        seg.enabled = r.read_bool()?;
+        seg.enabled = r.read_bit()?;
 
         if !seg.enabled {
             return Ok(());
         }
 
-        seg.update_map = r.read_bool()?;
+        seg.update_map = r.read_bit()?;
 
         if seg.update_map {
             for i in 0..SEG_TREE_PROBS {
                 seg.tree_probs[i] = Self::read_prob(r)?;
             }
 
-            seg.temporal_update = r.read_bool()?;
+            seg.temporal_update = r.read_bit()?;
 
             for i in 0..PREDICTION_PROBS {
                 seg.pred_probs[i] = if seg.temporal_update {
@@ -890,19 +870,19 @@ impl Parser {
             }
         }
 
-        seg.update_data = r.read_bool()?;
+        seg.update_data = r.read_bit()?;
 
         if seg.update_data {
-            seg.abs_or_delta_update = r.read_bool()?;
+            seg.abs_or_delta_update = r.read_bit()?;
             for i in 0..MAX_SEGMENTS {
                 for j in 0..SEG_LVL_MAX {
-                    seg.feature_enabled[i][j] = r.read_bool()?;
+                    seg.feature_enabled[i][j] = r.read_bit()?;
                     if seg.feature_enabled[i][j] {
                         let bits_to_read = SEGMENTATION_FEATURE_BITS[j];
-                        let mut feature_value = r.read_i16(bits_to_read)?;
+                        let mut feature_value = r.read_bits::<i16>(usize::from(bits_to_read))?;
 
                         if SEGMENTATION_FEATURE_SIGNED[j] {
-                            let feature_sign = r.read_bool()?;
+                            let feature_sign = r.read_bit()?;
 
                             if feature_sign {
                                 feature_value = -feature_value;
@@ -944,7 +924,7 @@ impl Parser {
         hdr.tile_cols_log2 = Self::calc_min_log2_tile_cols(self.sb64_cols);
 
         while hdr.tile_cols_log2 < max_log2_tile_cols {
-            let increment_tile_cols_log2 = r.read_bool()?;
+            let increment_tile_cols_log2 = r.read_bit()?;
 
             if increment_tile_cols_log2 {
                 hdr.tile_cols_log2 += 1;
@@ -953,10 +933,10 @@ impl Parser {
             }
         }
 
-        hdr.tile_rows_log2 = r.read_u8(1)?;
+        hdr.tile_rows_log2 = r.read_bits::<u8>(1)?;
 
         if hdr.tile_rows_log2 > 0 {
-            let increment_tile_rows_log2 = r.read_bool()?;
+            let increment_tile_rows_log2 = r.read_bit()?;
             hdr.tile_rows_log2 += increment_tile_rows_log2 as u8;
         }
 
@@ -969,24 +949,24 @@ impl Parser {
         offset: usize,
     ) -> anyhow::Result<Header> {
         let data = &resource.as_ref()[offset..];
-        let mut r = BitReader::new(data);
+        let mut r = BitReader::new(data, false);
         let mut hdr = Header::default();
 
         Self::parse_frame_marker(&mut r)?;
         Self::parse_profile(&mut r, &mut hdr)?;
 
-        hdr.show_existing_frame = r.read_bool()?;
+        hdr.show_existing_frame = r.read_bit()?;
 
         if hdr.show_existing_frame {
-            hdr.frame_to_show_map_idx = r.read_u8(3)?;
+            hdr.frame_to_show_map_idx = r.read_bits::<u8>(3)?;
             return Ok(hdr);
         }
 
-        hdr.frame_type =
-            FrameType::n(r.read_u8(1)?).ok_or(anyhow!("Broken data: invalid frame type"))?;
+        hdr.frame_type = FrameType::n(r.read_bits::<u8>(1)?)
+            .ok_or(anyhow!("Broken data: invalid frame type"))?;
 
-        hdr.show_frame = r.read_bool()?;
-        hdr.error_resilient_mode = r.read_bool()?;
+        hdr.show_frame = r.read_bit()?;
+        hdr.error_resilient_mode = r.read_bit()?;
 
         let frame_is_intra;
 
@@ -999,13 +979,13 @@ impl Parser {
             frame_is_intra = true;
         } else {
             if !hdr.show_frame {
-                hdr.intra_only = r.read_bool()?;
+                hdr.intra_only = r.read_bit()?;
             }
 
             frame_is_intra = hdr.intra_only;
 
             if !hdr.error_resilient_mode {
-                hdr.reset_frame_context = r.read_u8(2)?;
+                hdr.reset_frame_context = r.read_bits::<u8>(2)?;
             } else {
                 hdr.reset_frame_context = 0;
             }
@@ -1027,7 +1007,7 @@ impl Parser {
                     self.bit_depth = hdr.bit_depth;
                 }
 
-                hdr.refresh_frame_flags = r.read_u8(8)?;
+                hdr.refresh_frame_flags = r.read_bits::<u8>(8)?;
                 self.parse_frame_size(&mut r, &mut hdr)?;
                 Self::parse_render_size(&mut r, &mut hdr)?;
             } else {
@@ -1038,29 +1018,29 @@ impl Parser {
                 hdr.subsampling_y = self.subsampling_y;
                 hdr.bit_depth = self.bit_depth;
 
-                hdr.refresh_frame_flags = r.read_u8(8)?;
+                hdr.refresh_frame_flags = r.read_bits::<u8>(8)?;
 
                 for i in 0..REFS_PER_FRAME {
-                    hdr.ref_frame_idx[i] = r.read_u8(3)?;
+                    hdr.ref_frame_idx[i] = r.read_bits::<u8>(3)?;
                     hdr.ref_frame_sign_bias[ReferenceFrameType::Last as usize + i] =
-                        r.read_u8(1)?;
+                        r.read_bits::<u8>(1)?;
                 }
 
                 self.parse_frame_size_with_refs(&mut r, &mut hdr)?;
-                hdr.allow_high_precision_mv = r.read_bool()?;
+                hdr.allow_high_precision_mv = r.read_bit()?;
                 Self::read_interpolation_filter(&mut r, &mut hdr)?;
             }
         }
 
         if !hdr.error_resilient_mode {
-            hdr.refresh_frame_context = r.read_bool()?;
-            hdr.frame_parallel_decoding_mode = r.read_bool()?;
+            hdr.refresh_frame_context = r.read_bit()?;
+            hdr.frame_parallel_decoding_mode = r.read_bit()?;
         } else {
             hdr.refresh_frame_context = false;
             hdr.frame_parallel_decoding_mode = true;
         }
 
-        hdr.frame_context_idx = r.read_u8(2)?;
+        hdr.frame_context_idx = r.read_bits::<u8>(2)?;
 
         if frame_is_intra || hdr.error_resilient_mode {
             self.setup_past_independence(&mut hdr);
@@ -1071,7 +1051,7 @@ impl Parser {
         Self::parse_segmentation_params(&mut r, &mut self.seg)?;
         self.parse_tile_info(&mut r, &mut hdr)?;
 
-        hdr.header_size_in_bytes = r.read_u16(16)?;
+        hdr.header_size_in_bytes = r.read_bits::<u16>(16)?;
 
         hdr.lf = self.lf.clone();
         hdr.seg = self.seg.clone();
@@ -1109,13 +1089,11 @@ impl Parser {
     /// Parses VP9 frames from the data in `resource`. This can result in more than one frame if the
     /// data passed in contains a VP9 superframe.
     pub fn parse_chunk<'a>(&mut self, resource: &'a [u8]) -> anyhow::Result<Vec<Frame<'a>>> {
-        let superframe_hdr = Parser::parse_superframe_hdr(resource)?;
         let mut offset = 0;
 
         let mut frames = vec![];
 
-        for i in 0..superframe_hdr.frames_in_superframe {
-            let frame_sz = superframe_hdr.frame_sizes[i as usize];
+        for frame_sz in superframe::frame_sizes(resource)? {
             let frame = self.parse_frame(resource, offset, frame_sz)?;
             offset += frame_sz;
             frames.push(frame);
@@ -1127,6 +1105,7 @@ impl Parser {
 
 #[cfg(test)]
 mod tests {
+    use crate::codec::vp9::parser::reference_scale;
     use crate::codec::vp9::parser::BitDepth;
     use crate::codec::vp9::parser::ColorSpace;
     use crate::codec::vp9::parser::FrameType;
@@ -1134,8 +1113,10 @@ mod tests {
     use crate::codec::vp9::parser::Parser;
     use crate::codec::vp9::parser::Profile;
     use crate::codec::vp9::parser::MAX_SEGMENTS;
+    use crate::codec::vp9::parser::REF_SCALE_SHIFT;
     use crate::codec::vp9::parser::SEG_LVL_MAX;
     use crate::utils::IvfIterator;
+    use crate::Resolution;
 
     #[test]
     fn test_parse_superframe() {
@@ -1154,6 +1135,27 @@ mod tests {
         assert_eq!(frames[1].size, 214);
     }
 
+    #[test]
+    fn test_reference_scaling() {
+        let unscaled = 1 << REF_SCALE_SHIFT;
+        let frame = Resolution::from((320, 240));
+
+        assert_eq!(reference_scale(frame, frame), Some((unscaled, unscaled)));
+        assert_eq!(
+            reference_scale(frame, Resolution::from((640, 120))),
+            Some((2 * unscaled, unscaled / 2))
+        );
+        assert_eq!(
+            reference_scale(frame, Resolution::from((20, 15))),
+            Some((unscaled / 16, unscaled / 16))
+        );
+
+        // More than twice as large, or more than 16 times smaller.
+        assert_eq!(reference_scale(frame, Resolution::from((641, 240))), None);
+        assert_eq!(reference_scale(frame, Resolution::from((320, 14))), None);
+        assert_eq!(reference_scale(Resolution::from((0, 240)), frame), None);
+    }
+
     #[test]
     fn test_parse_test25fps() {
         // Muxed as IVF
@@ -0,0 +1,245 @@
+// Copyright 2024 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Splitting and building of VP9 superframes, as per Annex B.
+//!
+//! A superframe packs several frames into a single chunk of data, followed by
+//! an index giving the size of each of them. Encoders use them to deliver
+//! frames that are not shown, e.g. alternate reference frames or the lower
+//! spatial layers of a SVC stream, along with the next shown frame so every
+//! chunk of data results in exactly one displayed frame.
+
+use anyhow::anyhow;
+
+use crate::codec::vp9::parser::MAX_FRAMES_IN_SUPERFRAME;
+use crate::codec::vp9::parser::SUPERFRAME_MARKER;
+
+/// Maximum number of bytes used to code a frame size in the index.
+const MAX_BYTES_PER_FRAMESIZE: usize = 4;
+
+/// Returns the size of each frame of `data`. If `data` is not a superframe,
+/// it is made of a single frame spanning all of it.
+pub fn frame_sizes(data: &[u8]) -> anyhow::Result<Vec<usize>> {
+    let last_byte = *data.last().ok_or_else(|| anyhow!("empty VP9 chunk"))?;
+
+    // superframe_marker, bytes_per_framesize_minus_1, frames_in_superframe_minus_1
+    if u32::from(last_byte >> 5) != SUPERFRAME_MARKER {
+        return Ok(vec![data.len()]);
+    }
+
+    let bytes_per_framesize = usize::from((last_byte >> 3) & 0x3) + 1;
+    let frames_in_superframe = usize::from(last_byte & 0x7) + 1;
+    let index_size = 2 + frames_in_superframe * bytes_per_framesize;
+
+    // The index starts and ends with the same byte, otherwise the marker was
+    // just the end of the frame data.
+    let index_offset = match data.len().checked_sub(index_size) {
+        Some(offset) if data[offset] == last_byte => offset,
+        _ => return Ok(vec![data.len()]),
+    };
+
+    let index = &data[index_offset + 1..data.len() - 1];
+    let frame_sizes: Vec<usize> = index
+        .chunks(bytes_per_framesize)
+        .map(|size| {
+            size.iter()
+                .rev()
+                .fold(0, |acc, byte| (acc << 8) | usize::from(*byte))
+        })
+        .collect();
+
+    if frame_sizes.iter().sum::<usize>() > index_offset {
+        return Err(anyhow!(
+            "Broken stream: superframe frames take more than the {} bytes available",
+            index_offset
+        ));
+    }
+
+    Ok(frame_sizes)
+}
+
+/// Splits `data` into the frames it contains. If `data` is not a superframe,
+/// it is returned as a single frame.
+pub fn split_superframe(data: &[u8]) -> anyhow::Result<Vec<&[u8]>> {
+    let mut frames = vec![];
+    let mut offset = 0;
+
+    for size in frame_sizes(data)? {
+        frames.push(&data[offset..offset + size]);
+        offset += size;
+    }
+
+    Ok(frames)
+}
+
+/// Builds a superframe out of `frames`, which must contain between 1 and
+/// [`MAX_FRAMES_IN_SUPERFRAME`] frames.
+pub fn build_superframe<F: AsRef<[u8]>>(frames: &[F]) -> anyhow::Result<Vec<u8>> {
+    if frames.is_empty() || frames.len() > MAX_FRAMES_IN_SUPERFRAME {
+        return Err(anyhow!(
+            "a superframe must contain between 1 and {} frames, got {}",
+            MAX_FRAMES_IN_SUPERFRAME,
+            frames.len()
+        ));
+    }
+
+    let max_size = frames
+        .iter()
+        .map(|frame| frame.as_ref().len())
+        .max()
+        .unwrap_or(0);
+    let bytes_per_framesize = (1..=MAX_BYTES_PER_FRAMESIZE)
+        .find(|bytes| max_size >> (8 * bytes) == 0)
+        .ok_or_else(|| anyhow!("frame too large for a superframe: {} bytes", max_size))?;
+
+    let marker = (SUPERFRAME_MARKER as u8) << 5
+        | ((bytes_per_framesize - 1) as u8) << 3
+        | (frames.len() - 1) as u8;
+
+    let data_size = frames.iter().map(|f| f.as_ref().len()).sum::<usize>();
+    let index_size = 2 + frames.len() * bytes_per_framesize;
+    let mut out = Vec::with_capacity(data_size + index_size);
+
+    for frame in frames {
+        out.extend_from_slice(frame.as_ref());
+    }
+
+    out.push(marker);
+    for frame in frames {
+        let size = frame.as_ref().len();
+        out.extend((0..bytes_per_framesize).map(|i| (size >> (8 * i)) as u8));
+    }
+    out.push(marker);
+
+    Ok(out)
+}
+
+/// Groups the frames produced by an encoder into chunks that each contain one
+/// shown frame, packing the frames that are not shown into a superframe along
+/// with the next shown one.
+#[derive(Debug, Default)]
+pub struct SuperframeBuilder {
+    /// Frames that are not shown, waiting for the next shown frame.
+    pending: Vec<Vec<u8>>,
+}
+
+impl SuperframeBuilder {
+    /// Adds the coded `frame`, which is displayed if `show_frame` is set.
+    ///
+    /// Returns the chunk to output if `frame` is shown, i.e. `frame` itself or
+    /// a superframe if frames that are not shown precede it.
+    pub fn push(&mut self, frame: Vec<u8>, show_frame: bool) -> anyhow::Result<Option<Vec<u8>>> {
+        // Keep room for the shown frame that must end the superframe.
+        if !show_frame && self.pending.len() + 1 >= MAX_FRAMES_IN_SUPERFRAME {
+            return Err(anyhow!(
+                "too many frames not shown in a row, a superframe can contain at most {}",
+                MAX_FRAMES_IN_SUPERFRAME
+            ));
+        }
+
+        self.pending.push(frame);
+        if !show_frame {
+            return Ok(None);
+        }
+
+        let frames = std::mem::take(&mut self.pending);
+        if frames.len() == 1 {
+            // No need for a superframe.
+            Ok(frames.into_iter().next())
+        } else {
+            build_superframe(&frames).map(Some)
+        }
+    }
+
+    /// Returns the frames that are not followed by a shown frame, packed in a
+    /// superframe if there are several of them. Used when the encoder is
+    /// drained.
+    pub fn flush(&mut self) -> anyhow::Result<Option<Vec<u8>>> {
+        let frames = std::mem::take(&mut self.pending);
+        match frames.len() {
+            0 => Ok(None),
+            1 => Ok(frames.into_iter().next()),
+            _ => build_superframe(&frames).map(Some),
+        }
+    }
+
+    /// Returns the number of frames waiting for a shown frame.
+    pub fn num_pending(&self) -> usize {
+        self.pending.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const VP9_TEST_SUPERFRAME: &[u8] = include_bytes!("test_data/vp9-superframe.bin");
+
+    #[test]
+    fn split_and_build() {
+        let frames = split_superframe(VP9_TEST_SUPERFRAME).unwrap();
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[0].len(), 1333);
+        assert_eq!(frames[1].len(), 214);
+
+        // The index of the test superframe uses the smallest possible frame size field.
+        assert_eq!(build_superframe(&frames).unwrap(), VP9_TEST_SUPERFRAME);
+
+        // A single frame is not a superframe.
+        assert_eq!(split_superframe(frames[1]).unwrap(), vec![frames[1]]);
+        assert!(split_superframe(&[]).is_err());
+
+        let large = vec![0u8; 70000];
+        let superframe = build_superframe(&[&large[..], frames[1]]).unwrap();
+        assert_eq!(superframe.len(), 70000 + 214 + 2 + 2 * 3);
+        assert_eq!(
+            split_superframe(&superframe).unwrap(),
+            vec![&large[..], frames[1]]
+        );
+
+        assert!(build_superframe::<&[u8]>(&[]).is_err());
+        assert!(build_superframe(&[frames[1]; MAX_FRAMES_IN_SUPERFRAME + 1]).is_err());
+    }
+
+    #[test]
+    fn corrupted_index() {
+        let mut superframe = VP9_TEST_SUPERFRAME.to_vec();
+        // Make the first frame larger than the data.
+        let index_offset = superframe.len() - (2 + 2 * 2);
+        superframe[index_offset + 2] = 0xff;
+        assert!(split_superframe(&superframe).is_err());
+    }
+
+    #[test]
+    fn builder() {
+        let frames = split_superframe(VP9_TEST_SUPERFRAME).unwrap();
+        let mut builder = SuperframeBuilder::default();
+
+        // Alternate reference frame followed by a shown frame.
+        assert_eq!(builder.push(frames[0].to_vec(), false).unwrap(), None);
+        assert_eq!(builder.num_pending(), 1);
+        assert_eq!(
+            builder.push(frames[1].to_vec(), true).unwrap().unwrap(),
+            VP9_TEST_SUPERFRAME
+        );
+        assert_eq!(builder.num_pending(), 0);
+
+        // Shown frames are output as is.
+        assert_eq!(
+            builder.push(frames[1].to_vec(), true).unwrap().unwrap(),
+            frames[1]
+        );
+
+        for _ in 0..MAX_FRAMES_IN_SUPERFRAME - 1 {
+            builder.push(frames[1].to_vec(), false).unwrap();
+        }
+        assert!(builder.push(frames[1].to_vec(), false).is_err());
+        let superframe = builder.flush().unwrap().unwrap();
+        assert_eq!(
+            split_superframe(&superframe).unwrap().len(),
+            MAX_FRAMES_IN_SUPERFRAME - 1
+        );
+        assert_eq!(builder.flush().unwrap(), None);
+    }
+}
@@ -15,8 +15,14 @@ use std::collections::VecDeque;
 
 pub use crate::BlockingMode;
 
+use byteorder::ByteOrder;
+use byteorder::LittleEndian;
+
 use crate::decoder::stateless::PoolLayer;
 use crate::DecodedFormat;
+use crate::Fourcc;
+use crate::FrameLayout;
+use crate::PlaneSize;
 use crate::Resolution;
 
 /// Trait for a pool of frames in a particular format.
@@ -46,6 +52,39 @@ pub trait FramePool {
     fn clear(&mut self);
 }
 
+/// Trait for clients providing the memory backing the output frames of a decoder.
+///
+/// Decoders with a provider set request frames as they need them, instead of waiting for the
+/// client to add them to their [`FramePool`]. This allows clients to decode directly into buffers
+/// they already manage, e.g. DMABUFs allocated by a display server or compositor.
+///
+/// It is implemented for closures with a matching signature.
+pub trait FrameProvider<D> {
+    /// Returns up to `nb_frames` new frames able to hold the stream described by `stream_info`,
+    /// whose `coded_resolution` is the one of the pool the frames are added to.
+    ///
+    /// Returning fewer frames is allowed, in which case the decoder waits for its frames to be
+    /// returned as if there was no provider.
+    fn provide_frames(
+        &mut self,
+        stream_info: &StreamInfo,
+        nb_frames: usize,
+    ) -> anyhow::Result<Vec<D>>;
+}
+
+impl<D, F> FrameProvider<D> for F
+where
+    F: FnMut(&StreamInfo, usize) -> anyhow::Result<Vec<D>>,
+{
+    fn provide_frames(
+        &mut self,
+        stream_info: &StreamInfo,
+        nb_frames: usize,
+    ) -> anyhow::Result<Vec<D>> {
+        self(stream_info, nb_frames)
+    }
+}
+
 /// Information about the current stream.
 ///
 /// This is static information obtained from the stream itself about its requirements. It does not
@@ -109,6 +148,157 @@ pub trait MappableHandle {
 
     /// Returns the size of the `buffer` argument required to call `read` on this handle.
     fn image_size(&mut self) -> usize;
+
+    /// Maps the frame and returns accessors to its planes, without copying or converting them.
+    ///
+    /// Contrary to `read`, the planes keep the layout and pixel format of the backend, which are
+    /// described by the returned [`MappedFrame`].
+    fn map(&mut self) -> anyhow::Result<MappedFrame<'_>>;
+}
+
+/// A plane of a [`MappedFrame`].
+#[derive(Debug, Clone)]
+pub struct MappedPlane<'a> {
+    /// Memory of the plane, starting at its first row.
+    data: &'a [u8],
+    /// Distance in bytes between two rows of the plane.
+    stride: usize,
+    /// Size of the visible part of the plane.
+    size: PlaneSize,
+}
+
+impl<'a> MappedPlane<'a> {
+    /// Returns the distance in bytes between two rows of the plane.
+    pub fn stride(&self) -> usize {
+        self.stride
+    }
+
+    /// Returns the size of the visible part of the plane.
+    pub fn size(&self) -> PlaneSize {
+        self.size
+    }
+
+    /// Returns row `index` of the plane without its padding, or `None` if the plane does not have
+    /// that many rows.
+    pub fn row(&self, index: usize) -> Option<&'a [u8]> {
+        if index >= self.size.rows {
+            return None;
+        }
+
+        let start = index * self.stride;
+        Some(&self.data[start..start + self.size.row_size])
+    }
+
+    /// Returns an iterator over the rows of the plane, without their padding.
+    pub fn rows(&self) -> impl Iterator<Item = &'a [u8]> + '_ {
+        (0..self.size.rows).filter_map(|index| self.row(index))
+    }
+
+    /// Returns an iterator over the 16 bits little-endian words of row `index`, which is how
+    /// formats with more than 8 bits per sample store them.
+    ///
+    /// Samples are returned as stored in memory: formats like `P010` keep them in the most
+    /// significant bits of each word.
+    pub fn row_u16(&self, index: usize) -> Option<impl Iterator<Item = u16> + 'a> {
+        self.row(index)
+            .map(|row| row.chunks_exact(2).map(LittleEndian::read_u16))
+    }
+}
+
+/// A decoded frame mapped into the client's address space, as returned by
+/// [`MappableHandle::map`].
+#[derive(Debug, Clone)]
+pub struct MappedFrame<'a> {
+    /// Pixel format of the frame.
+    format: Fourcc,
+    /// Visible size of the frame in pixels.
+    resolution: Resolution,
+    /// Planes of the frame, in the order of `format`.
+    planes: Vec<MappedPlane<'a>>,
+    /// Whether `planes` point to the memory of the frame rather than to a copy of it.
+    direct: bool,
+}
+
+impl<'a> MappedFrame<'a> {
+    /// Creates the mapping of a frame stored in `data` following `layout`, whose planes must all
+    /// be linear and part of the same buffer.
+    ///
+    /// `direct` tells whether `data` is the memory the frame has been decoded into, or an
+    /// intermediate copy made by the backend.
+    pub fn new(data: &'a [u8], layout: &FrameLayout, direct: bool) -> anyhow::Result<Self> {
+        anyhow::ensure!(
+            layout.format.1 == 0,
+            "cannot map frames with modifier 0x{:x}",
+            layout.format.1
+        );
+        layout.validate(&[data.len()])?;
+
+        let sizes = layout.format.0.plane_sizes(layout.size).unwrap_or_default();
+        let planes = layout
+            .planes
+            .iter()
+            .zip(sizes)
+            .map(|(plane, size)| MappedPlane {
+                data: &data[plane.offset..],
+                stride: plane.stride,
+                size,
+            })
+            .collect();
+
+        Ok(Self {
+            format: layout.format.0,
+            resolution: layout.size,
+            planes,
+            direct,
+        })
+    }
+
+    /// Returns the pixel format of the frame.
+    pub fn format(&self) -> Fourcc {
+        self.format
+    }
+
+    /// Returns the number of significant bits of each sample of the frame.
+    pub fn bit_depth(&self) -> u32 {
+        // `new` only accepts supported formats.
+        self.format.bit_depth().unwrap_or(8)
+    }
+
+    /// Returns the visible size of the frame in pixels.
+    pub fn resolution(&self) -> Resolution {
+        self.resolution
+    }
+
+    /// Returns the planes of the frame.
+    pub fn planes(&self) -> &[MappedPlane<'a>] {
+        &self.planes
+    }
+
+    /// Returns `true` if the planes point directly to the memory of the decoded frame, `false` if
+    /// the backend had to copy it first.
+    ///
+    /// Direct mappings can be uncached and slow to read randomly from.
+    pub fn is_direct(&self) -> bool {
+        self.direct
+    }
+}
+
+/// View of a stereoscopic stream a decoded frame belongs to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StereoView {
+    /// Left view, which is the base view of H.264 MVC streams.
+    Left,
+    /// Right view, which is the first non-base view of H.264 MVC streams.
+    Right,
+}
+
+/// Order of the fields of a frame decoded from interlaced content.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FieldOrder {
+    /// The top field is displayed first.
+    TopFieldFirst,
+    /// The bottom field is displayed first.
+    BottomFieldFirst,
 }
 
 /// The handle type used by the decoder backend. The only requirement from implementors is that
@@ -137,6 +327,36 @@ pub trait DecodedHandle {
     /// Wait until this handle has been completely rendered.
     fn sync(&self) -> anyhow::Result<()>;
 
+    /// Returns `true` if this handle has been decoded from partially received input, meaning that
+    /// parts of the frame are likely to contain garbage.
+    ///
+    /// This can only happen if the decoder has been set to
+    /// [`ResilienceMode::Tolerant`](stateless::ResilienceMode::Tolerant).
+    fn is_corrupted(&self) -> bool {
+        false
+    }
+
+    /// Flags this handle as having been decoded from partially received input. Handles which
+    /// do not keep track of corruption ignore it.
+    fn set_corrupted(&self) {}
+
+    /// Returns the view this handle belongs to if it has been decoded from a stereoscopic
+    /// stream with separately coded views, like H.264 MVC streams.
+    fn view(&self) -> Option<StereoView> {
+        None
+    }
+
+    /// Tags this handle as belonging to `view` of a stereoscopic stream. Handles which do not
+    /// keep track of views ignore it.
+    fn set_view(&self, _view: StereoView) {}
+
+    /// Returns the order of the fields of this handle if it has been decoded from interlaced
+    /// content, or `None` for progressive frames.
+    fn field_order(&self) -> Option<FieldOrder>;
+
+    /// Tags this handle as an interlaced frame with fields in `order`.
+    fn set_field_order(&self, order: FieldOrder);
+
     fn resource(&self) -> std::cell::Ref<Self::Descriptor>;
 }
 
@@ -178,3 +398,57 @@ impl<'a, T> Iterator for &'a mut ReadyFramesQueue<T> {
         self.queue.pop_front()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::MappedFrame;
+    use crate::Fourcc;
+    use crate::FrameLayout;
+    use crate::PlaneLayout;
+    use crate::Resolution;
+
+    #[test]
+    fn mapped_frame_planes() {
+        // 2x2 P010 frame with rows padded to 8 bytes.
+        let mut data = vec![0u8; 24];
+        for (i, sample) in data.chunks_mut(2).enumerate() {
+            sample.copy_from_slice(&((i as u16) << 6).to_le_bytes());
+        }
+        let plane = |offset| PlaneLayout {
+            buffer_index: 0,
+            offset,
+            stride: 8,
+        };
+        let mut layout = FrameLayout {
+            format: (Fourcc::from(b"P010"), 0),
+            size: Resolution::from((2, 2)),
+            planes: vec![plane(0), plane(16)],
+        };
+
+        let frame = MappedFrame::new(&data, &layout, false).unwrap();
+        assert_eq!(frame.bit_depth(), 10);
+        assert!(!frame.is_direct());
+        let planes = frame.planes();
+        assert_eq!(planes.len(), 2);
+        assert_eq!(planes[0].stride(), 8);
+        assert_eq!(planes[0].rows().count(), 2);
+        assert_eq!(planes[0].row(1).unwrap(), &data[8..12]);
+        assert_eq!(
+            planes[0].row_u16(1).unwrap().collect::<Vec<_>>(),
+            vec![4 << 6, 5 << 6]
+        );
+        assert_eq!(
+            planes[1].row_u16(0).unwrap().collect::<Vec<_>>(),
+            vec![8 << 6, 9 << 6]
+        );
+        // The chroma plane only has one row.
+        assert!(planes[1].row(1).is_none());
+
+        // The chroma plane does not fit in the buffer.
+        assert!(MappedFrame::new(&data[..18], &layout, false).is_err());
+
+        // Tiled frames cannot be accessed by rows.
+        layout.format.1 = 1;
+        assert!(MappedFrame::new(&data, &layout, false).is_err());
+    }
+}
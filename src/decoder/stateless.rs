@@ -14,9 +14,12 @@
 pub mod av1;
 pub mod h264;
 pub mod h265;
+pub mod session;
 pub mod vp8;
 pub mod vp9;
 
+use std::collections::VecDeque;
+
 use thiserror::Error;
 
 use crate::decoder::BlockingMode;
@@ -24,8 +27,10 @@ use crate::decoder::DecodedHandle;
 use crate::decoder::DecoderEvent;
 use crate::decoder::DecoderFormatNegotiator;
 use crate::decoder::FramePool;
+use crate::decoder::FrameProvider;
 use crate::decoder::ReadyFramesQueue;
 use crate::decoder::StreamInfo;
+use crate::instrument::SessionId;
 use crate::DecodedFormat;
 use crate::Resolution;
 
@@ -59,6 +64,71 @@ enum DecodingState<F> {
     Reset,
 }
 
+/// Controls how the decoder reacts to input units that have been truncated, e.g. because of packet
+/// loss on an unreliable transport.
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResilienceMode {
+    /// Input units that cannot be parsed in their entirety are rejected with an error.
+    #[default]
+    Strict,
+    /// Truncated slices and OBUs are decoded up to the point where data is missing, and the
+    /// resulting frames are flagged using [`DecodedHandle::set_corrupted`]. Slices whose header
+    /// cannot be parsed are skipped, and flag the picture they belong to.
+    ///
+    /// H.264 and H.265 slices carry no size, so a slice truncated with a valid header is only
+    /// detected when it ends before its first macroblock, or before one of its entry points for
+    /// H.265.
+    Tolerant,
+}
+
+/// Controls what the decoder does when it needs an output frame while all the frames of its pool
+/// are in use, usually because the client holds on to decoded frames for too long.
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameReusePolicy {
+    /// [`StatelessVideoDecoder::decode`] returns [`DecodeError::NotEnoughOutputBuffers`] until
+    /// the client returns frames to the pool.
+    #[default]
+    Block,
+    /// Pictures that no other picture refers to are skipped without being decoded. Other pictures
+    /// block as with [`FrameReusePolicy::Block`].
+    ///
+    /// VP9 and H.264 MVC pictures are only skipped if the whole input unit can be, and AV1 ones
+    /// always block as their references are not known before decoding.
+    Drop,
+    /// [`DecodeError::NotEnoughOutputBuffers`] is returned as with [`FrameReusePolicy::Block`],
+    /// but the client is expected to add the missing frames to the pool rather than wait for
+    /// frames to be returned, as long as [`FrameBudget::max_num_frames`] is not exceeded.
+    ///
+    /// Decoders with a [`FrameProvider`] request these frames from it directly.
+    AllocateExtra,
+}
+
+/// Bounds the number of output frames used by a decoder, see
+/// [`StatelessDecoder::set_frame_budget`].
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameBudget {
+    /// Maximum number of output frames per layer that can be allocated for the decoder, or
+    /// `None` for no limit. Streams requiring more than this number of frames, as per
+    /// [`StreamInfo::min_num_frames`], fail to negotiate their format.
+    pub max_num_frames: Option<usize>,
+    /// What to do when all the output frames are in use.
+    pub reuse_policy: FrameReusePolicy,
+}
+
+impl FrameBudget {
+    /// Returns the number of frames that can be added to a pool managing `num_managed_frames`
+    /// frames to provide `needed` more, as allowed by the budget and reuse policy.
+    pub fn extra_frames(&self, num_managed_frames: usize, needed: usize) -> usize {
+        match (self.reuse_policy, self.max_num_frames) {
+            (FrameReusePolicy::AllocateExtra, None) => needed,
+            (FrameReusePolicy::AllocateExtra, Some(max_num_frames)) => {
+                std::cmp::min(needed, max_num_frames.saturating_sub(num_managed_frames))
+            }
+            (FrameReusePolicy::Block | FrameReusePolicy::Drop, _) => 0,
+        }
+    }
+}
+
 /// Error returned by the [`StatelessVideoDecoder::decode`] method.
 #[derive(Debug, Error)]
 pub enum DecodeError {
@@ -121,6 +191,47 @@ pub trait StatelessDecoderBackend {
 
     /// Returns the frame pool currently in use by the backend for `layer`.
     fn frame_pool(&mut self, layer: PoolLayer) -> Vec<&mut Self::FramePool>;
+
+    /// Sets the resolution decoded frames are scaled down to before being output, or `None` to
+    /// output them at their decoded size. See [`StatelessDecoder::set_output_resolution`].
+    fn set_output_resolution(&mut self, resolution: Option<Resolution>) -> anyhow::Result<()>;
+
+    /// Sets how frames decoded from interlaced content are deinterlaced before being output. See
+    /// [`StatelessDecoder::set_deinterlacing`].
+    fn set_deinterlacing(&mut self, deinterlacing: Deinterlacing) -> anyhow::Result<()>;
+}
+
+/// Deinterlacing applied to output frames decoded from interlaced content, i.e. frames with a
+/// [`DecodedHandle::field_order`].
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Deinterlacing {
+    /// Frames are output with the lines of both their fields interleaved.
+    #[default]
+    Off,
+    /// The first field of each frame is interpolated to the full frame height.
+    Bob,
+    /// The lines missing from the first field of each frame are taken from the second field
+    /// where no motion is detected, and interpolated elsewhere.
+    MotionAdaptive,
+}
+
+/// Returns the resolution frames with a visible area of `display_resolution` are output at when
+/// `requested` has been set with [`StatelessDecoder::set_output_resolution`], or `None` if they
+/// are output at their decoded size.
+///
+/// Frames are only ever scaled down, so they keep their size if `requested` is larger in any
+/// dimension.
+#[cfg(any(test, feature = "vaapi"))]
+pub(crate) fn scaled_output_resolution(
+    display_resolution: Resolution,
+    requested: Option<Resolution>,
+) -> Option<Resolution> {
+    requested.filter(|&requested| {
+        requested != display_resolution
+            && requested.width > 0
+            && requested.height > 0
+            && display_resolution.can_contain(requested)
+    })
 }
 
 /// Helper to implement [`DecoderFormatNegotiator`] for stateless decoders.
@@ -171,6 +282,19 @@ where
     /// Try to apply `format` to output frames. If successful, all frames emitted after the
     /// call will be in the new format.
     fn try_format(&mut self, format: DecodedFormat) -> anyhow::Result<()> {
+        if let (Some(max_num_frames), Some(stream_info)) = (
+            self.decoder.frame_budget().max_num_frames,
+            self.decoder.stream_info(),
+        ) {
+            if stream_info.min_num_frames > max_num_frames {
+                anyhow::bail!(
+                    "stream requires {} output frames, but the frame budget is {}",
+                    stream_info.min_num_frames,
+                    max_num_frames
+                );
+            }
+        }
+
         self.decoder.try_format(format)
     }
 
@@ -254,10 +378,206 @@ pub trait StatelessVideoDecoder<B: StatelessDecoderBackend> {
 
     fn stream_info(&self) -> Option<&StreamInfo>;
 
+    /// Returns the bounds on the number of output frames of the decoder, which the client should
+    /// follow when allocating frames.
+    fn frame_budget(&self) -> FrameBudget;
+
     /// Returns the next event, if there is any pending.
     fn next_event(&mut self) -> Option<DecoderEvent<B::Handle, B::FramePool>>;
 }
 
+/// Iterator decoding the chunks of its input and returning the decoded frames in display order.
+///
+/// Input chunks can be any slices the decoder accepts, e.g. NAL units of an Annex B stream as
+/// returned by [`NalIterator`](crate::utils::NalIterator), or frames of an IVF file as returned
+/// by [`IvfIterator`](crate::utils::IvfIterator). Each chunk is decoded with its index as
+/// timestamp, and the decoder is flushed once the input is exhausted.
+///
+/// Format changes are accepted by setting the output format given to [`DecodedFrames::new`],
+/// and the frames required by the stream are requested from its frame provider.
+///
+/// Frames must be dropped for decoding to proceed: an error is returned if the decoder runs out
+/// of output frames while none are pending.
+pub struct DecodedFrames<'a, B, D, I>
+where
+    B: StatelessDecoderBackend,
+    D: StatelessVideoDecoder<B> + ?Sized,
+    I: Iterator,
+    I::Item: AsRef<[u8]>,
+{
+    decoder: &'a mut D,
+    input: I,
+    /// Chunk being decoded along with its timestamp and the number of its bytes already
+    /// processed.
+    current: Option<(u64, I::Item, usize)>,
+    /// Timestamp of the next input chunk.
+    next_timestamp: u64,
+    /// Output format to negotiate on format changes.
+    format: DecodedFormat,
+    frame_provider: &'a mut dyn FrameProvider<<B::Handle as DecodedHandle>::Descriptor>,
+    /// Decoded frames waiting to be returned.
+    ready: VecDeque<B::Handle>,
+    /// Whether the decoder has been flushed, or has returned an error.
+    done: bool,
+}
+
+impl<'a, B, D, I> DecodedFrames<'a, B, D, I>
+where
+    B: StatelessDecoderBackend,
+    D: StatelessVideoDecoder<B> + ?Sized,
+    I: Iterator,
+    I::Item: AsRef<[u8]>,
+{
+    /// Creates an iterator decoding `input` using `decoder`. Its output frames will be in
+    /// `format`, and use memory from `frame_provider`.
+    pub fn new(
+        decoder: &'a mut D,
+        input: I,
+        format: DecodedFormat,
+        frame_provider: &'a mut dyn FrameProvider<<B::Handle as DecodedHandle>::Descriptor>,
+    ) -> Self {
+        Self {
+            decoder,
+            input,
+            current: None,
+            next_timestamp: 0,
+            format,
+            frame_provider,
+            ready: Default::default(),
+            done: false,
+        }
+    }
+
+    /// Processes all the pending events of the decoder, and returns whether there were any.
+    fn process_events(&mut self) -> anyhow::Result<bool> {
+        let mut has_events = false;
+
+        while let Some(event) = self.decoder.next_event() {
+            has_events = true;
+            match event {
+                DecoderEvent::FrameReady(handle) => self.ready.push_back(handle),
+                DecoderEvent::FormatChanged(mut negotiator) => {
+                    negotiator.try_format(self.format)?;
+                    let stream_info = negotiator.stream_info().clone();
+                    let pools = negotiator.frame_pool(PoolLayer::All);
+                    let min_num_frames = stream_info.min_num_frames / std::cmp::max(pools.len(), 1);
+                    for pool in pools {
+                        let missing = min_num_frames.saturating_sub(pool.num_managed_frames());
+                        if missing == 0 {
+                            continue;
+                        }
+
+                        let pool_info = StreamInfo {
+                            coded_resolution: pool.coded_resolution(),
+                            ..stream_info.clone()
+                        };
+                        let frames = self.frame_provider.provide_frames(&pool_info, missing)?;
+                        pool.add_frames(frames)?;
+                    }
+                }
+            }
+        }
+
+        Ok(has_events)
+    }
+
+    /// Submits the next input to the decoder, or flushes it if there is none left.
+    fn decode_next(&mut self) -> anyhow::Result<()> {
+        if self.current.is_none() {
+            match self.input.next() {
+                Some(chunk) => {
+                    self.current = Some((self.next_timestamp, chunk, 0));
+                    self.next_timestamp += 1;
+                }
+                None => {
+                    self.decoder.flush()?;
+                    self.process_events()?;
+                    self.done = true;
+                    return Ok(());
+                }
+            }
+        }
+
+        let Some((timestamp, chunk, processed)) = self.current.as_mut() else {
+            return Ok(());
+        };
+        match self
+            .decoder
+            .decode(*timestamp, &chunk.as_ref()[*processed..])
+        {
+            Ok(bytes) => {
+                *processed += bytes;
+                if *processed >= chunk.as_ref().len() {
+                    self.current = None;
+                }
+                self.process_events()?;
+            }
+            Err(DecodeError::CheckEvents) => {
+                self.process_events()?;
+            }
+            Err(DecodeError::NotEnoughOutputBuffers(needed)) => {
+                if !self.process_events()? && self.ready.is_empty() {
+                    anyhow::bail!(
+                        "decoder needs {} more output frames, but none are pending",
+                        needed
+                    );
+                }
+            }
+            Err(e) => return Err(e.into()),
+        }
+
+        Ok(())
+    }
+}
+
+impl<'a, B, D, I> Iterator for DecodedFrames<'a, B, D, I>
+where
+    B: StatelessDecoderBackend,
+    D: StatelessVideoDecoder<B> + ?Sized,
+    I: Iterator,
+    I::Item: AsRef<[u8]>,
+{
+    type Item = anyhow::Result<B::Handle>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(handle) = self.ready.pop_front() {
+                return Some(Ok(handle));
+            }
+            if self.done {
+                return None;
+            }
+
+            if let Err(e) = self.decode_next() {
+                self.done = true;
+                return Some(Err(e));
+            }
+        }
+    }
+}
+
+/// Decodes all of `input` using `decoder`, and passes the decoded frames to `on_new_frame` in
+/// display order. See [`DecodedFrames`] for the expected input and how it is decoded.
+pub fn simple_decode_loop<B, D, I>(
+    decoder: &mut D,
+    input: I,
+    format: DecodedFormat,
+    frame_provider: &mut dyn FrameProvider<<B::Handle as DecodedHandle>::Descriptor>,
+    mut on_new_frame: impl FnMut(B::Handle),
+) -> anyhow::Result<()>
+where
+    B: StatelessDecoderBackend,
+    D: StatelessVideoDecoder<B> + ?Sized,
+    I: Iterator,
+    I::Item: AsRef<[u8]>,
+{
+    for handle in DecodedFrames::new(decoder, input, format, frame_provider) {
+        on_new_frame(handle?);
+    }
+
+    Ok(())
+}
+
 pub trait StatelessCodec {
     /// Type providing current format information for the codec: resolution, color format, etc.
     ///
@@ -297,6 +617,15 @@ where
     /// Whether the decoder should block on decode operations.
     blocking_mode: BlockingMode,
 
+    /// How the decoder should handle truncated input.
+    resilience_mode: ResilienceMode,
+
+    /// Bounds on the number of output frames.
+    frame_budget: FrameBudget,
+
+    /// Client callback providing output frames on demand, if any.
+    frame_provider: Option<Box<dyn FrameProvider<<B::Handle as DecodedHandle>::Descriptor>>>,
+
     ready_queue: ReadyFramesQueue<B::Handle>,
 
     decoding_state: DecodingState<C::FormatInfo>,
@@ -306,6 +635,9 @@ where
 
     /// Codec-specific state.
     codec: C::DecoderState<B::Handle, B::Picture>,
+
+    /// Identifier of this decoder in traces.
+    session: SessionId,
 }
 
 impl<C, B> StatelessDecoder<C, B>
@@ -318,10 +650,137 @@ where
         Self {
             backend,
             blocking_mode,
+            resilience_mode: Default::default(),
+            frame_budget: Default::default(),
+            frame_provider: None,
             coded_resolution: Default::default(),
             decoding_state: Default::default(),
             ready_queue: Default::default(),
             codec: Default::default(),
+            session: SessionId::new(),
+        }
+    }
+}
+
+impl<C, B> StatelessDecoder<C, B>
+where
+    C: StatelessCodec,
+    B: StatelessDecoderBackend + StatelessDecoderBackendPicture<C>,
+{
+    /// Sets how the decoder should react to truncated input. See [`ResilienceMode`].
+    pub fn set_resilience_mode(&mut self, mode: ResilienceMode) {
+        self.resilience_mode = mode;
+    }
+
+    /// Returns the identifier of this decoder in traces.
+    pub fn session_id(&self) -> SessionId {
+        self.session
+    }
+
+    /// Returns the resilience mode currently in use.
+    pub fn resilience_mode(&self) -> ResilienceMode {
+        self.resilience_mode
+    }
+
+    /// Sets the bounds on the number of output frames of the decoder. See [`FrameBudget`].
+    pub fn set_frame_budget(&mut self, budget: FrameBudget) {
+        self.frame_budget = budget;
+    }
+
+    /// Sets the callback used to request output frames from the client. See [`FrameProvider`].
+    ///
+    /// The provider is asked for the frames the stream requires after each change of format, and
+    /// for extra frames when they are all in use and the frame reuse policy is
+    /// [`FrameReusePolicy::AllocateExtra`]. Clients can still add frames to the pools
+    /// themselves.
+    pub fn set_frame_provider(
+        &mut self,
+        provider: Box<dyn FrameProvider<<B::Handle as DecodedHandle>::Descriptor>>,
+    ) {
+        self.frame_provider = Some(provider);
+    }
+
+    /// Makes the decoder output frames scaled down to `resolution`, e.g. to produce thumbnails
+    /// without a separate scaling pass, or at their decoded size if `resolution` is `None`.
+    ///
+    /// Scaling is done by the backend after decoding, so reference frames are not affected. The
+    /// [`DecodedHandle::display_resolution`] and CPU mappings of output frames are those of the
+    /// scaled frame, while their [`DecodedHandle::resource`] keeps the decoded size. Streams
+    /// smaller than `resolution` in any dimension are output unscaled.
+    ///
+    /// Returns an error if the backend cannot scale frames.
+    pub fn set_output_resolution(&mut self, resolution: Option<Resolution>) -> anyhow::Result<()> {
+        self.backend.set_output_resolution(resolution)
+    }
+
+    /// Makes the decoder deinterlace the frames it decodes from interlaced content, which
+    /// currently means H.264 pictures coded as fields or with MBAFF. One progressive frame is
+    /// output per decoded frame, keeping the timestamp of the latter.
+    ///
+    /// Like scaling, deinterlacing is done by the backend after decoding and only applies to
+    /// the CPU mappings of output frames.
+    ///
+    /// Returns an error if the backend cannot deinterlace frames.
+    pub fn set_deinterlacing(&mut self, deinterlacing: Deinterlacing) -> anyhow::Result<()> {
+        self.backend.set_deinterlacing(deinterlacing)
+    }
+
+    /// Requests the frames missing from the pools of `layer` from the frame provider, if any, so
+    /// they have at least `needed` free frames.
+    fn provide_frames(&mut self, layer: PoolLayer, needed: usize) -> anyhow::Result<()> {
+        let Some(provider) = self.frame_provider.as_mut() else {
+            return Ok(());
+        };
+        let Some(stream_info) = self.backend.stream_info().cloned() else {
+            return Ok(());
+        };
+
+        let pools = self.backend.frame_pool(layer);
+        let min_num_frames = stream_info.min_num_frames / std::cmp::max(pools.len(), 1);
+        for pool in pools {
+            let num_managed_frames = pool.num_managed_frames();
+            let missing = min_num_frames.saturating_sub(num_managed_frames);
+            let missing = match self.frame_budget.max_num_frames {
+                Some(max_num_frames) => {
+                    std::cmp::min(missing, max_num_frames.saturating_sub(num_managed_frames))
+                }
+                None => missing,
+            };
+            let shortage = needed.saturating_sub(pool.num_free_frames() + missing);
+            let nb_frames = missing
+                + self
+                    .frame_budget
+                    .extra_frames(num_managed_frames + missing, shortage);
+            if nb_frames == 0 {
+                continue;
+            }
+
+            let pool_info = StreamInfo {
+                coded_resolution: pool.coded_resolution(),
+                ..stream_info.clone()
+            };
+            let frames = provider.provide_frames(&pool_info, nb_frames)?;
+            if !frames.is_empty() {
+                pool.add_frames(frames)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Reacts to `needed` more free output frames being required to decode the next picture,
+    /// according to the frame reuse policy. `droppable` tells whether that picture can be skipped
+    /// without affecting the decoding of the following ones.
+    ///
+    /// Returns `Ok(())` if the picture is to be skipped, or the error to return from `decode`
+    /// otherwise.
+    fn handle_frame_shortage(&self, needed: usize, droppable: bool) -> Result<(), DecodeError> {
+        match self.frame_budget.reuse_policy {
+            FrameReusePolicy::Drop if droppable => {
+                log::debug!("no free output frame, dropping picture");
+                Ok(())
+            }
+            _ => Err(DecodeError::NotEnoughOutputBuffers(needed)),
         }
     }
 }
@@ -343,9 +802,11 @@ where
 
 #[cfg(test)]
 pub(crate) mod tests {
+    use crate::decoder::stateless::scaled_output_resolution;
     use crate::decoder::stateless::StatelessDecoderBackend;
     use crate::decoder::stateless::StatelessVideoDecoder;
     use crate::decoder::DecodedHandle;
+    use crate::Resolution;
 
     /// Stream that can be used in tests, along with the CRC32 of all of its frames.
     pub struct TestStream {
@@ -404,4 +865,25 @@ pub(crate) mod tests {
 
         assert_eq!(crcs.next(), None, "decoded less frames than expected");
     }
+
+    #[test]
+    fn output_resolution() {
+        let display = Resolution::from((1920, 1080));
+
+        assert_eq!(scaled_output_resolution(display, None), None);
+        assert_eq!(
+            scaled_output_resolution(display, Some(Resolution::from((480, 270)))),
+            Some(Resolution::from((480, 270)))
+        );
+        // Frames are never scaled up.
+        assert_eq!(
+            scaled_output_resolution(display, Some(Resolution::from((480, 1440)))),
+            None
+        );
+        assert_eq!(scaled_output_resolution(display, Some(display)), None);
+        assert_eq!(
+            scaled_output_resolution(display, Some(Resolution::from((0, 270)))),
+            None
+        );
+    }
 }
@@ -10,6 +10,7 @@ use anyhow::Context;
 use crate::codec::av1::parser::FrameHeaderObu;
 use crate::codec::av1::parser::FrameObu;
 use crate::codec::av1::parser::FrameType;
+use crate::codec::av1::parser::ObuFormat;
 use crate::codec::av1::parser::ObuType;
 use crate::codec::av1::parser::ParsedObu;
 use crate::codec::av1::parser::Parser;
@@ -21,6 +22,8 @@ use crate::codec::av1::parser::TileGroupObu;
 use crate::decoder::stateless::DecodeError;
 use crate::decoder::stateless::DecoderEvent;
 use crate::decoder::stateless::DecodingState;
+use crate::decoder::stateless::FrameBudget;
+use crate::decoder::stateless::ResilienceMode;
 use crate::decoder::stateless::StatelessBackendResult;
 use crate::decoder::stateless::StatelessCodec;
 use crate::decoder::stateless::StatelessDecoder;
@@ -33,8 +36,10 @@ use crate::decoder::BlockingMode;
 use crate::decoder::DecodedHandle;
 use crate::decoder::FramePool;
 use crate::decoder::PoolLayer;
+use crate::instrument::enter_span;
+use crate::instrument::time_backend_call;
 
-#[cfg(test)]
+#[cfg(any(test, feature = "dummy"))]
 mod dummy;
 #[cfg(feature = "vaapi")]
 mod vaapi;
@@ -88,6 +93,8 @@ enum CurrentPicState<H: DecodedHandle, P> {
         header: FrameHeaderObu,
         /// Backend-specific data for that picture.
         backend_picture: P,
+        /// Whether some of the tile data for this picture is missing.
+        corrupted: bool,
     },
 
     /// A frame that has 'show_existing_frame' set.
@@ -157,9 +164,38 @@ where
     B: StatelessAV1DecoderBackend,
     B::Handle: Clone,
 {
+    /// Selects the operating point to decode for scalable streams.
+    ///
+    /// OBUs belonging to temporal or spatial layers that are not part of the operating point are
+    /// dropped before being submitted to the backend, which allows a scalable stream to be played
+    /// at a reduced frame rate or resolution for less work. Operating point 0, the default,
+    /// usually includes all the layers. If the stream has fewer operating points, the last one is
+    /// used.
+    ///
+    /// The selection takes effect at the next Sequence Header OBU, and triggers a format change
+    /// if it changes the highest spatial layer to output.
+    pub fn set_operating_point(&mut self, operating_point: u32) {
+        self.codec.parser.set_operating_point(operating_point);
+    }
+
+    /// Returns the operating point currently being decoded.
+    pub fn operating_point(&self) -> u32 {
+        self.codec.parser.operating_point()
+    }
+
+    /// Sets the format of the input, or `None` to detect it from the first submitted data, which
+    /// is the default. Detection requires the first data to contain a sequence header and a
+    /// frame, so streams starting otherwise should have their format set explicitly.
+    pub fn set_obu_format(&mut self, format: Option<ObuFormat>) {
+        self.codec.parser.set_format(format);
+    }
+
     fn count_frames(&mut self, bitstream: &[u8]) -> usize {
         let mut nframes = 0;
         let mut consumed = 0;
+        // Parsing the Annex B length fields updates the state of the parser, and the OBUs will be
+        // parsed again when decoding.
+        let stream_state = self.codec.parser.stream_state();
 
         while let Ok(obu) = self.codec.parser.parse_obu(&bitstream[consumed..]) {
             let obu = match obu {
@@ -177,6 +213,7 @@ where
             consumed += obu.data.len();
         }
 
+        self.codec.parser.restore_stream_state(stream_state);
         nframes
     }
 
@@ -213,6 +250,7 @@ where
             self.codec.current_pic = Some(CurrentPicState::RegularFrame {
                 header: frame_header.clone(),
                 backend_picture,
+                corrupted: false,
             });
         } else {
             log::warn!("invalid stream: frame header received while no valid sequence ongoing");
@@ -224,8 +262,15 @@ where
     fn decode_tile_group(&mut self, tile_group: TileGroupObu) -> anyhow::Result<()> {
         let picture = match self.codec.current_pic.as_mut() {
             Some(CurrentPicState::RegularFrame {
-                backend_picture, ..
-            }) => backend_picture,
+                backend_picture,
+                corrupted,
+                ..
+            }) => {
+                if tile_group.obu.truncated {
+                    *corrupted = true;
+                }
+                backend_picture
+            }
             Some(CurrentPicState::ShowExistingFrame { .. }) => {
                 return Err(anyhow!("Broken stream: cannot decode a tile group for a frame with show_existing_frame set"));
             }
@@ -260,12 +305,18 @@ where
             Some(CurrentPicState::RegularFrame {
                 header,
                 backend_picture,
+                corrupted,
             }) => {
-                let handle = self.backend.submit_picture(backend_picture)?;
+                let handle = time_backend_call("submit_picture", || {
+                    self.backend.submit_picture(backend_picture)
+                })?;
 
                 if self.blocking_mode == BlockingMode::Blocking {
                     handle.sync()?;
                 }
+                if corrupted {
+                    handle.set_corrupted();
+                }
                 (handle, header)
             }
             Some(CurrentPicState::ShowExistingFrame { header, handle }) => (handle, header),
@@ -318,20 +369,23 @@ where
         self.codec.frame_count += 1;
         Ok(())
     }
-}
 
-impl<B> StatelessVideoDecoder<B> for StatelessDecoder<Av1, B>
-where
-    B: StatelessAV1DecoderBackend + TryFormat<Av1>,
-    B::Handle: Clone + 'static,
-{
-    fn decode(&mut self, timestamp: u64, bitstream: &[u8]) -> Result<usize, super::DecodeError> {
+    /// Decodes the OBUs of `bitstream`. The caller is responsible for restoring the state of the
+    /// parser if this fails, as the same data will be submitted again.
+    fn decode_obus(&mut self, timestamp: u64, bitstream: &[u8]) -> Result<usize, DecodeError> {
         let mut consumed = 0;
 
+        self.codec
+            .parser
+            .set_tolerate_truncation(self.resilience_mode == ResilienceMode::Tolerant);
+
         let nframes = self.count_frames(bitstream);
         /* we do not know the resolution at this point, as we haven't parsed the
          * frames yet. Be conservative and check whether we have enough frames
          * across all layers */
+        if matches!(self.decoding_state, DecodingState::Decoding) {
+            self.provide_frames(PoolLayer::All, nframes)?;
+        }
         let num_free_frames = self
             .backend
             .frame_pool(PoolLayer::All)
@@ -410,8 +464,13 @@ where
                         None => true,
                     };
 
+                    // A different operating point may have been selected by the client.
+                    let layers_differ = self.codec.parser.highest_operating_point()
+                        != self.codec.highest_spatial_layer;
+
                     if matches!(self.decoding_state, DecodingState::AwaitingStreamInfo)
                         || sequence_differs
+                        || layers_differ
                     {
                         if self.codec.current_pic.is_some() {
                             return Err(DecodeError::DecoderError(anyhow!(
@@ -493,6 +552,23 @@ where
 
         Ok(consumed)
     }
+}
+
+impl<B> StatelessVideoDecoder<B> for StatelessDecoder<Av1, B>
+where
+    B: StatelessAV1DecoderBackend + TryFormat<Av1>,
+    B::Handle: Clone + 'static,
+{
+    fn decode(&mut self, timestamp: u64, bitstream: &[u8]) -> Result<usize, super::DecodeError> {
+        let _span = enter_span("decoder", self.session, "decode", Some(timestamp));
+        let stream_state = self.codec.parser.stream_state();
+        let res = self.decode_obus(timestamp, bitstream);
+        if res.is_err() {
+            self.codec.parser.restore_stream_state(stream_state);
+        }
+
+        res
+    }
 
     fn flush(&mut self) -> Result<(), super::DecodeError> {
         // Note: all the submitted frames are already in the ready queue.
@@ -510,6 +586,10 @@ where
         self.backend.stream_info()
     }
 
+    fn frame_budget(&self) -> FrameBudget {
+        self.frame_budget
+    }
+
     fn next_event(&mut self) -> Option<crate::decoder::DecoderEvent<B::Handle, B::FramePool>> {
         // The next event is either the next frame, or, if we are awaiting negotiation, the format
         // change event that will allow us to keep going.
@@ -537,6 +617,8 @@ where
 
 #[cfg(test)]
 pub mod tests {
+    use crate::codec::av1::annexb::low_overhead_to_annexb;
+    use crate::codec::av1::parser::ObuFormat;
     use crate::decoder::stateless::av1::Av1;
     use crate::decoder::stateless::tests::test_decode_stream;
     use crate::decoder::stateless::tests::TestStream;
@@ -584,4 +666,24 @@ pub mod tests {
     fn test_25fps_nonblock() {
         test_decoder_dummy(&DECODE_TEST_25FPS, BlockingMode::NonBlocking);
     }
+
+    #[test]
+    fn test_25fps_annexb() {
+        let mut decoder = StatelessDecoder::<Av1, _>::new_dummy(BlockingMode::Blocking);
+        decoder.set_obu_format(Some(ObuFormat::AnnexB));
+        let mut num_frames = 0;
+
+        simple_playback_loop(
+            &mut decoder,
+            IvfIterator::new(DECODE_TEST_25FPS.stream)
+                .map(|temporal_unit| low_overhead_to_annexb(temporal_unit).unwrap()),
+            &mut |_| num_frames += 1,
+            &mut simple_playback_loop_owned_frames,
+            DecodedFormat::NV12,
+            BlockingMode::Blocking,
+        )
+        .unwrap();
+
+        assert_eq!(num_frames, DECODE_TEST_25FPS.crcs.lines().count());
+    }
 }
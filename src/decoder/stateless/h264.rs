@@ -2,30 +2,39 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file.
 
-#[cfg(test)]
+#[cfg(any(test, feature = "dummy"))]
 mod dummy;
 #[cfg(feature = "vaapi")]
 mod vaapi;
 
 use std::cell::RefCell;
 use std::collections::btree_map::Entry;
+use std::collections::BTreeMap;
+use std::collections::VecDeque;
 use std::io::Cursor;
 use std::rc::Rc;
+use std::time::SystemTime;
 
 use anyhow::anyhow;
 use anyhow::Context;
+use bytes::BufMut;
 use log::debug;
 
+use crate::codec::common::bit_reader::BitReader;
 use crate::codec::h264::dpb::Dpb;
 use crate::codec::h264::dpb::DpbEntry;
 use crate::codec::h264::dpb::DpbPicRefList;
 use crate::codec::h264::dpb::ReferencePicLists;
+use crate::codec::h264::nalu::Header;
+use crate::codec::h264::parser::FramePackingArrangement;
 use crate::codec::h264::parser::MaxLongTermFrameIdx;
 use crate::codec::h264::parser::Nalu;
+use crate::codec::h264::parser::NaluHeader;
 use crate::codec::h264::parser::NaluType;
 use crate::codec::h264::parser::Parser;
 use crate::codec::h264::parser::Pps;
 use crate::codec::h264::parser::RefPicListModification;
+use crate::codec::h264::parser::SeiMessage;
 use crate::codec::h264::parser::Slice;
 use crate::codec::h264::parser::SliceHeader;
 use crate::codec::h264::parser::SliceType;
@@ -36,7 +45,9 @@ use crate::codec::h264::picture::PictureData;
 use crate::codec::h264::picture::Reference;
 use crate::decoder::stateless::DecodeError;
 use crate::decoder::stateless::DecodingState;
+use crate::decoder::stateless::FrameBudget;
 use crate::decoder::stateless::PoolLayer;
+use crate::decoder::stateless::ResilienceMode;
 use crate::decoder::stateless::StatelessBackendResult;
 use crate::decoder::stateless::StatelessCodec;
 use crate::decoder::stateless::StatelessDecoder;
@@ -47,12 +58,28 @@ use crate::decoder::stateless::TryFormat;
 use crate::decoder::BlockingMode;
 use crate::decoder::DecodedHandle;
 use crate::decoder::DecoderEvent;
+use crate::decoder::FieldOrder;
 use crate::decoder::FramePool;
+use crate::decoder::StereoView;
 use crate::decoder::StreamInfo;
+use crate::instrument::enter_span;
+use crate::instrument::time_backend_call;
+use crate::instrument::trace_event;
+use crate::utils::latency;
+use crate::utils::picture_hash;
+use crate::utils::picture_hash::PictureHash;
+use crate::utils::snapshot;
 use crate::Resolution;
 
 use super::StatelessDecoderBackendPicture;
 
+/// Maximum number of decoded picture hashes kept until taken by the client.
+pub const MAX_PICTURE_HASHES: usize = 32;
+
+/// Maximum number of capture times kept until taken by the client.
+pub const MAX_CAPTURE_TIMES: usize = 32;
+
+#[cfg(feature = "vaapi")]
 fn get_raster_from_zigzag_8x8(src: [u8; 64], dst: &mut [u8; 64]) {
     const ZIGZAG_8X8: [usize; 64] = [
         0, 1, 8, 16, 9, 2, 3, 10, 17, 24, 32, 25, 18, 11, 4, 5, 12, 19, 26, 33, 40, 48, 41, 34, 27,
@@ -65,6 +92,7 @@ fn get_raster_from_zigzag_8x8(src: [u8; 64], dst: &mut [u8; 64]) {
     }
 }
 
+#[cfg(feature = "vaapi")]
 fn get_raster_from_zigzag_4x4(src: [u8; 16], dst: &mut [u8; 16]) {
     const ZIGZAG_4X4: [usize; 16] = [0, 1, 4, 8, 5, 2, 3, 6, 9, 12, 13, 10, 7, 11, 14, 15];
 
@@ -99,6 +127,10 @@ pub trait StatelessH264DecoderBackend:
     ) -> StatelessBackendResult<Self::Picture>;
 
     /// Called by the decoder when starting a new frame or field.
+    ///
+    /// `inter_view_refs` are the pictures of other views of MVC streams that the picture may
+    /// refer to on top of those of `dpb`.
+    #[allow(clippy::too_many_arguments)]
     fn start_picture(
         &mut self,
         picture: &mut Self::Picture,
@@ -106,6 +138,7 @@ pub trait StatelessH264DecoderBackend:
         sps: &Sps,
         pps: &Pps,
         dpb: &Dpb<Self::Handle>,
+        inter_view_refs: &[DpbEntry<Self::Handle>],
         hdr: &SliceHeader,
     ) -> StatelessBackendResult<()>;
 
@@ -242,12 +275,39 @@ struct CurrentPicState<P> {
     pic: PictureData,
     /// PPS at the time of the current picture.
     pps: Rc<Pps>,
+    /// SPS of the current picture, which is the subset SPS referred to by `pps` for the non-base
+    /// views of MVC streams.
+    sps: Rc<Sps>,
     /// Backend-specific data for that picture.
     backend_pic: P,
     /// List of reference pictures, used once per slice.
     ref_pic_lists: ReferencePicLists,
     /// The current macroblock we are processing
     current_macroblock: CurrentMacroblockTracking,
+    /// Whether some slices of this picture have been lost or truncated.
+    corrupted: bool,
+}
+
+/// Reference state of the view of a MVC stream that is not being decoded at the moment, see
+/// [`H264DecoderState::switch_view`].
+struct ViewState<H> {
+    dpb: Dpb<H>,
+    prev_ref_pic_info: PrevReferencePicInfo,
+    prev_pic_info: PrevPicInfo,
+    max_long_term_frame_idx: MaxLongTermFrameIdx,
+    last_field: Option<(Rc<RefCell<PictureData>>, H)>,
+}
+
+impl<H> Default for ViewState<H> {
+    fn default() -> Self {
+        ViewState {
+            dpb: Default::default(),
+            prev_ref_pic_info: Default::default(),
+            prev_pic_info: Default::default(),
+            max_long_term_frame_idx: Default::default(),
+            last_field: Default::default(),
+        }
+    }
 }
 
 /// State of the H.264 decoder.
@@ -281,6 +341,37 @@ pub struct H264DecoderState<H: DecodedHandle, P> {
     /// The picture currently being decoded. We need to preserve it between calls to `decode`
     /// because multiple slices will be processed in different calls to `decode`.
     current_pic: Option<CurrentPicState<P>>,
+    /// Whether slices have been lost in [`ResilienceMode::Tolerant`] before the next picture
+    /// started, possibly including its first ones.
+    next_pic_corrupted: bool,
+
+    /// Size of the NAL unit length prefix if the input is length-prefixed
+    /// rather than in Annex B format.
+    nal_length_size: Option<usize>,
+
+    /// Last frame packing arrangement signaled by the stream, if it has not been cancelled.
+    frame_packing: Option<FramePackingArrangement>,
+
+    /// Decoded picture hashes signaled by the stream and not taken by the client yet, along with
+    /// the timestamp of their picture.
+    picture_hashes: VecDeque<(u64, PictureHash)>,
+
+    /// Capture times signaled by the stream and not taken by the client yet, along with the
+    /// timestamp of their picture.
+    capture_times: VecDeque<(u64, SystemTime)>,
+
+    /// Last parameter set NAL units received for each identifier, for [`DecoderSnapshot`]
+    parameter_sets: BTreeMap<(u8, u8), Vec<u8>>,
+
+    /// Subset SPS of MVC streams, whose base and first non-base views are decoded.
+    mvc_sps: Option<Rc<Sps>>,
+    /// View whose reference state is in the fields above.
+    view: StereoView,
+    /// Reference state of the other view.
+    other_view: ViewState<H>,
+    /// Base view picture of the current access unit of MVC streams, which the pictures of the
+    /// non-base view may refer to.
+    inter_view_refs: Vec<DpbEntry<H>>,
 }
 
 impl<H, P> Default for H264DecoderState<H, P>
@@ -297,7 +388,115 @@ where
             max_long_term_frame_idx: Default::default(),
             last_field: Default::default(),
             current_pic: None,
+            next_pic_corrupted: false,
+            nal_length_size: None,
+            frame_packing: None,
+            picture_hashes: Default::default(),
+            capture_times: Default::default(),
+            parameter_sets: Default::default(),
+            mvc_sps: None,
+            view: StereoView::Left,
+            other_view: Default::default(),
+            inter_view_refs: Default::default(),
+        }
+    }
+}
+
+impl<H, P> H264DecoderState<H, P>
+where
+    H: DecodedHandle,
+{
+    /// Keeps the parameter set `nalu` of identifier `id` for [`DecoderSnapshot`].
+    fn store_parameter_set(&mut self, nalu: &Nalu, id: u8) {
+        let data = &nalu.data[nalu.offset..nalu.offset + nalu.size];
+        self.parameter_sets
+            .insert((nalu.header.type_ as u8, id), data.to_vec());
+    }
+
+    /// Makes `view` the view whose reference state is used for decoding.
+    fn switch_view(&mut self, view: StereoView) {
+        if view == self.view {
+            return;
+        }
+
+        let other = &mut self.other_view;
+        std::mem::swap(&mut self.dpb, &mut other.dpb);
+        std::mem::swap(&mut self.prev_ref_pic_info, &mut other.prev_ref_pic_info);
+        std::mem::swap(&mut self.prev_pic_info, &mut other.prev_pic_info);
+        std::mem::swap(
+            &mut self.max_long_term_frame_idx,
+            &mut other.max_long_term_frame_idx,
+        );
+        std::mem::swap(&mut self.last_field, &mut other.last_field);
+        self.view = view;
+    }
+
+    /// Returns the view `slice` belongs to, or `None` if it belongs to a non-base view of a MVC
+    /// stream other than the first one, which are not decoded.
+    fn slice_view(&self, slice: &Slice) -> Option<StereoView> {
+        let view_id = match &slice.nalu.header.mvc_extension {
+            Some(mvc_extension) if slice.nalu.header.type_ == NaluType::SliceExt => {
+                mvc_extension.view_id
+            }
+            _ => return Some(StereoView::Left),
+        };
+
+        let mvc_extension = self.mvc_sps.as_ref()?.mvc_extension.as_ref()?;
+        match mvc_extension.view_order_idx(view_id)? {
+            1 => Some(StereoView::Right),
+            _ => None,
+        }
+    }
+
+    /// Returns the SPS to negotiate the format with for pictures using `sps`, which is the subset
+    /// SPS of MVC streams.
+    fn negotiation_sps<'a>(&'a self, sps: &'a Rc<Sps>) -> &'a Rc<Sps> {
+        self.mvc_sps.as_ref().unwrap_or(sps)
+    }
+}
+
+/// Codec state of a H.264 decoder, allowing another decoder to resume the decoding of its stream
+/// at the next IDR, e.g. on another device after a live migration. It holds the parameter sets
+/// received so far, which may not be repeated before that IDR, but no frame.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DecoderSnapshot {
+    /// SPS, PPS and subset SPS NAL units, without start code, the SPS first
+    pub parameter_sets: Vec<Vec<u8>>,
+    /// See [`StatelessDecoder::set_nal_length_size`]
+    pub nal_length_size: Option<usize>,
+}
+
+impl DecoderSnapshot {
+    const TAG: &'static [u8; 4] = b"H4DS";
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        snapshot::put_header(&mut out, Self::TAG);
+        out.put_u8(self.nal_length_size.unwrap_or(0) as u8);
+        out.put_u32(self.parameter_sets.len() as u32);
+        for nalu in &self.parameter_sets {
+            snapshot::put_bytes(&mut out, nalu);
         }
+
+        out
+    }
+
+    pub fn from_bytes(mut data: &[u8]) -> anyhow::Result<Self> {
+        let input = &mut data;
+        snapshot::get_header(input, Self::TAG)?;
+        let nal_length_size = match snapshot::get_u8(input)? {
+            0 => None,
+            size => Some(usize::from(size)),
+        };
+        let parameter_sets = (0..snapshot::get_u32(input)?)
+            .map(|_| snapshot::get_bytes(input))
+            .collect::<anyhow::Result<_>>()?;
+        snapshot::check_end(input)?;
+
+        Ok(Self {
+            parameter_sets,
+            nal_length_size,
+        })
     }
 }
 
@@ -310,6 +509,9 @@ where
 /// This makes it possible to call [`Decode`](StatelessDecoder::decode) repeatedly on some unsplit
 /// Annex B stream and shrinking it by the number of bytes processed after each call, until the
 /// stream ends up being empty.
+///
+/// Length-prefixed input, as found in MP4 files, is also accepted after calling
+/// [`StatelessDecoder::set_nal_length_size`].
 pub struct H264;
 
 impl StatelessCodec for H264 {
@@ -346,8 +548,7 @@ where
 
                 let max_pic_order_cnt_lsb = 1 << (sps.log2_max_pic_order_cnt_lsb_minus4 + 4);
 
-                pic.pic_order_cnt_msb = if (pic.pic_order_cnt_lsb
-                    < self.prev_ref_pic_info.pic_order_cnt_lsb)
+                pic.pic_order_cnt_msb = if (pic.pic_order_cnt_lsb < prev_pic_order_cnt_lsb)
                     && (prev_pic_order_cnt_lsb - pic.pic_order_cnt_lsb >= max_pic_order_cnt_lsb / 2)
                 {
                     prev_pic_order_cnt_msb + max_pic_order_cnt_lsb
@@ -500,81 +701,115 @@ where
 
     /// Returns an iterator of the handles of all the frames still present in the DPB.
     fn drain(&mut self) -> impl Iterator<Item = H> {
+        // A field still waiting for its second field will not get it anymore, output it on its
+        // own.
+        let last_field = self.last_field.take().map(|(_, handle)| handle);
+        if let Some((pic, _)) = self.unpaired_field() {
+            pic.borrow_mut().non_paired = true;
+        }
+
         let pics = self.dpb.drain();
 
         self.dpb.clear();
-        self.last_field = None;
 
-        pics.into_iter().flatten()
+        last_field.into_iter().chain(pics.into_iter().flatten())
     }
 
-    /// Find the first field for the picture started by `slice`, if any.
+    /// Find the first field for the picture started by the slice of header `hdr` in the NAL unit
+    /// of header `nalu_hdr`, if any.
+    ///
+    /// As per 3.30 and 3.31, two fields of opposite parity and the same frame_num form a
+    /// complementary field pair if they are both reference or both non-reference fields, and the
+    /// second one neither is an IDR picture nor contains a memory_management_control_operation
+    /// equal to 5. A previous field that cannot be paired with the current picture is a non-paired
+    /// field as per 3.92 and 3.94: it is left as-is and `None` is returned.
     #[allow(clippy::type_complexity)]
     fn find_first_field(
         &self,
         hdr: &SliceHeader,
-    ) -> anyhow::Result<Option<(Rc<RefCell<PictureData>>, H)>> {
-        let mut prev_field = None;
-
-        if self.dpb.interlaced() {
-            if let Some(last_field) = &self.last_field {
-                prev_field = Some((&last_field.0, &last_field.1));
-            } else if let Some(last_dpb_entry) = self.dpb.entries().last() {
-                // Use the last entry in the DPB
-                let last_pic = last_dpb_entry.pic.borrow();
-
-                if !matches!(last_pic.field, Field::Frame) && last_pic.other_field().is_none() {
-                    if let Some(handle) = &last_dpb_entry.handle {
-                        // Still waiting for the second field
-                        prev_field = Some((&last_dpb_entry.pic, &handle));
-                    }
-                }
-            }
-        }
+        nalu_hdr: &NaluHeader,
+    ) -> Option<(Rc<RefCell<PictureData>>, H)> {
+        let (prev_field, prev_field_handle) = self.unpaired_field()?;
+        let prev_field_pic = prev_field.borrow();
 
         if !hdr.field_pic_flag {
-            if let Some(prev_field) = prev_field {
-                let field = prev_field.0.borrow().field;
-                return Err(anyhow!(
-                    "Expecting complementary field {:?}, got {:?}",
-                    field.opposite(),
-                    field
-                ));
-            }
+            debug!(
+                "Non-paired {:?} field followed by a frame",
+                prev_field_pic.field
+            );
+            return None;
         }
 
-        match prev_field {
-            None => Ok(None),
-            Some(prev_field) => {
-                let prev_field_pic = prev_field.0.borrow();
+        if prev_field_pic.frame_num != u32::from(hdr.frame_num) {
+            debug!(
+                "Non-paired {:?} field: frame_num {} differs from the current field's {}",
+                prev_field_pic.field, prev_field_pic.frame_num, hdr.frame_num
+            );
+            return None;
+        }
 
-                if prev_field_pic.frame_num != u32::from(hdr.frame_num) {
-                    return Err(anyhow!(
-                "The previous field differs in frame_num value wrt. the current field. {:?} vs {:?}",
-                prev_field_pic.frame_num,
-                hdr.frame_num
-            ));
-                } else {
-                    let cur_field = if hdr.bottom_field_flag {
-                        Field::Bottom
-                    } else {
-                        Field::Top
-                    };
-
-                    if cur_field == prev_field_pic.field {
-                        let field = prev_field_pic.field;
-                        return Err(anyhow!(
-                            "Expecting complementary field {:?}, got {:?}",
-                            field.opposite(),
-                            field
-                        ));
-                    }
-                }
+        let cur_field = if hdr.bottom_field_flag {
+            Field::Bottom
+        } else {
+            Field::Top
+        };
 
-                drop(prev_field_pic);
-                Ok(Some((prev_field.0.clone(), prev_field.1.clone())))
-            }
+        if cur_field == prev_field_pic.field {
+            debug!(
+                "Non-paired {:?} field followed by a field of the same parity",
+                prev_field_pic.field
+            );
+            return None;
         }
+
+        if prev_field_pic.is_ref() != (nalu_hdr.ref_idc != 0) {
+            debug!(
+                "Non-paired {:?} field: only one of the fields is a reference",
+                prev_field_pic.field
+            );
+            return None;
+        }
+
+        let has_mmco_5 = hdr
+            .dec_ref_pic_marking
+            .inner
+            .iter()
+            .any(|marking| marking.memory_management_control_operation == 5);
+        if nalu_hdr.idr_pic_flag || has_mmco_5 {
+            debug!(
+                "Non-paired {:?} field: the current field resets the reference pictures",
+                prev_field_pic.field
+            );
+            return None;
+        }
+
+        drop(prev_field_pic);
+        Some((Rc::clone(prev_field), prev_field_handle.clone()))
+    }
+
+    /// Returns the last decoded field if it is still waiting for its second field.
+    fn unpaired_field(&self) -> Option<(&Rc<RefCell<PictureData>>, &H)> {
+        if !self.dpb.interlaced() {
+            return None;
+        }
+
+        if let Some((pic, handle)) = &self.last_field {
+            return Some((pic, handle));
+        }
+
+        // Use the last entry in the DPB
+        let last_dpb_entry = self.dpb.entries().last()?;
+        let last_pic = last_dpb_entry.pic.borrow();
+        if matches!(last_pic.field, Field::Frame)
+            || last_pic.is_second_field()
+            || last_pic.other_field().is_some()
+            || last_pic.non_paired
+        {
+            return None;
+        }
+
+        // Still waiting for the second field
+        Some((&last_dpb_entry.pic, last_dpb_entry.handle.as_ref()?))
     }
 
     fn pic_num_f(pic: &PictureData, max_pic_num: i32) -> i32 {
@@ -651,9 +886,12 @@ where
                 break;
             }
 
-            let target = &ref_pic_list_x[cidx].pic;
+            let target = ref_pic_list_x[cidx].pic.borrow();
 
-            if Self::pic_num_f(&target.borrow(), max_pic_num) != pic_num_lx {
+            // Inter-view references never match.
+            if target.view_id != cur_pic.view_id
+                || Self::pic_num_f(&target, max_pic_num) != pic_num_lx
+            {
                 ref_pic_list_x[nidx] = ref_pic_list_x[cidx];
                 nidx += 1;
             }
@@ -667,6 +905,7 @@ where
     }
 
     fn long_term_pic_list_modification<'a>(
+        cur_pic: &PictureData,
         dpb: &'a Dpb<H>,
         ref_pic_list_x: &mut DpbPicRefList<'a, H>,
         num_ref_idx_lx_active_minus1: u8,
@@ -695,9 +934,9 @@ where
                 break;
             }
 
-            let target = &ref_pic_list_x[cidx].pic;
-            if Self::long_term_pic_num_f(&target.borrow(), max_long_term_frame_idx)
-                != long_term_pic_num
+            let target = ref_pic_list_x[cidx].pic.borrow();
+            if target.view_id != cur_pic.view_id
+                || Self::long_term_pic_num_f(&target, max_long_term_frame_idx) != long_term_pic_num
             {
                 ref_pic_list_x[nidx] = ref_pic_list_x[cidx];
                 nidx += 1;
@@ -711,13 +950,104 @@ where
         Ok(())
     }
 
+    // H.8.2.2.3 Modification process for inter-view reference components
+    #[allow(clippy::too_many_arguments)]
+    fn inter_view_pic_list_modification<'a>(
+        &'a self,
+        ref_pic_list_x: &mut DpbPicRefList<'a, H>,
+        num_ref_idx_lx_active_minus1: u8,
+        inter_view_ref_ids: &[u16],
+        rplm: &RefPicListModification,
+        pic_view_idx_lx_pred: &mut i32,
+        ref_idx_lx: &mut usize,
+    ) -> anyhow::Result<()> {
+        let num_inter_view_refs = inter_view_ref_ids.len() as i32;
+        let abs_diff_view_idx = rplm.abs_diff_view_idx_minus1 as i32 + 1;
+
+        let mut pic_view_idx_lx = if rplm.modification_of_pic_nums_idc == 4 {
+            *pic_view_idx_lx_pred - abs_diff_view_idx
+        } else {
+            *pic_view_idx_lx_pred + abs_diff_view_idx
+        };
+
+        if pic_view_idx_lx < 0 {
+            pic_view_idx_lx += num_inter_view_refs;
+        } else if pic_view_idx_lx >= num_inter_view_refs {
+            pic_view_idx_lx -= num_inter_view_refs;
+        }
+
+        let target_view_id = usize::try_from(pic_view_idx_lx)
+            .ok()
+            .and_then(|idx| inter_view_ref_ids.get(idx))
+            .with_context(|| format!("Invalid abs_diff_view_idx_minus1 {}", abs_diff_view_idx))?;
+
+        *pic_view_idx_lx_pred = pic_view_idx_lx;
+
+        let entry = self.inter_view_ref(*target_view_id).with_context(|| {
+            format!("No inter-view reference found for view {}", target_view_id)
+        })?;
+
+        ref_pic_list_x.insert(*ref_idx_lx, entry);
+        *ref_idx_lx += 1;
+
+        let mut nidx = *ref_idx_lx;
+
+        for cidx in *ref_idx_lx..=usize::from(num_ref_idx_lx_active_minus1) + 1 {
+            if cidx == ref_pic_list_x.len() {
+                break;
+            }
+
+            if !Rc::ptr_eq(&ref_pic_list_x[cidx].pic, &entry.pic) {
+                ref_pic_list_x[nidx] = ref_pic_list_x[cidx];
+                nidx += 1;
+            }
+        }
+
+        while ref_pic_list_x.len() > (usize::from(num_ref_idx_lx_active_minus1) + 1) {
+            ref_pic_list_x.pop();
+        }
+
+        Ok(())
+    }
+
+    /// Returns the inter-view reference of view `view_id` for the current access unit.
+    fn inter_view_ref(&self, view_id: u16) -> Option<&DpbEntry<H>> {
+        self.inter_view_refs
+            .iter()
+            .find(|entry| entry.pic.borrow().view_id == view_id)
+    }
+
+    /// Returns the views the inter-view references of list `ref_pic_list_type` of `cur_pic` belong
+    /// to, in order, as signaled by the subset SPS of MVC streams.
+    fn inter_view_ref_ids(
+        &self,
+        cur_pic: &PictureData,
+        anchor_pic: bool,
+        ref_pic_list_type: RefPicList,
+    ) -> &[u16] {
+        let view = self
+            .mvc_sps
+            .as_ref()
+            .and_then(|sps| sps.mvc_extension.as_ref())
+            .and_then(|ext| Some(&ext.views[ext.view_order_idx(cur_pic.view_id)?]));
+
+        match (view, ref_pic_list_type, anchor_pic) {
+            (None, _, _) => &[],
+            (Some(view), RefPicList::RefPicList0, true) => &view.anchor_refs_l0,
+            (Some(view), RefPicList::RefPicList1, true) => &view.anchor_refs_l1,
+            (Some(view), RefPicList::RefPicList0, false) => &view.non_anchor_refs_l0,
+            (Some(view), RefPicList::RefPicList1, false) => &view.non_anchor_refs_l1,
+        }
+    }
+
     fn modify_ref_pic_list(
         &self,
         cur_pic: &PictureData,
         hdr: &SliceHeader,
+        anchor_pic: bool,
         ref_pic_list_type: RefPicList,
         ref_pic_list_indices: &[usize],
-    ) -> anyhow::Result<DpbPicRefList<H>> {
+    ) -> anyhow::Result<DpbPicRefList<'_, H>> {
         let (ref_pic_list_modification_flag_lx, num_ref_idx_lx_active_minus1, rplm) =
             match ref_pic_list_type {
                 RefPicList::RefPicList0 => (
@@ -732,9 +1062,16 @@ where
                 ),
             };
 
+        // H.8.2.1: the inter-view references follow the references of the same view.
+        let inter_view_ref_ids = self.inter_view_ref_ids(cur_pic, anchor_pic, ref_pic_list_type);
         let mut ref_pic_list: Vec<_> = ref_pic_list_indices
             .iter()
             .map(|&i| &self.dpb.entries()[i])
+            .chain(
+                inter_view_ref_ids
+                    .iter()
+                    .filter_map(|&view_id| self.inter_view_ref(view_id)),
+            )
             .take(usize::from(num_ref_idx_lx_active_minus1) + 1)
             .collect();
 
@@ -743,6 +1080,7 @@ where
         }
 
         let mut pic_num_lx_pred = cur_pic.pic_num;
+        let mut pic_view_idx_lx_pred = -1;
         let mut ref_idx_lx = 0;
 
         for modification in rplm {
@@ -762,6 +1100,7 @@ where
                     )?;
                 }
                 2 => Self::long_term_pic_list_modification(
+                    cur_pic,
                     &self.dpb,
                     &mut ref_pic_list,
                     num_ref_idx_lx_active_minus1,
@@ -770,6 +1109,14 @@ where
                     &mut ref_idx_lx,
                 )?,
                 3 => break,
+                4 | 5 => self.inter_view_pic_list_modification(
+                    &mut ref_pic_list,
+                    num_ref_idx_lx_active_minus1,
+                    inter_view_ref_ids,
+                    modification,
+                    &mut pic_view_idx_lx_pred,
+                    &mut ref_idx_lx,
+                )?,
                 _ => anyhow::bail!("unexpected modification_of_pic_nums_idc {:?}", idc),
             }
         }
@@ -782,19 +1129,28 @@ where
     fn create_ref_pic_lists(
         &mut self,
         cur_pic: &PictureData,
-        hdr: &SliceHeader,
+        slice: &Slice,
         ref_pic_lists: &ReferencePicLists,
-    ) -> anyhow::Result<RefPicLists<H>> {
+    ) -> anyhow::Result<RefPicLists<'_, H>> {
+        let hdr = &slice.header;
+        let anchor_pic = slice
+            .nalu
+            .header
+            .mvc_extension
+            .is_some_and(|ext| ext.anchor_pic_flag);
+
         let ref_pic_list0 = match hdr.slice_type {
             SliceType::P | SliceType::Sp => self.modify_ref_pic_list(
                 cur_pic,
                 hdr,
+                anchor_pic,
                 RefPicList::RefPicList0,
                 &ref_pic_lists.ref_pic_list_p0,
             )?,
             SliceType::B => self.modify_ref_pic_list(
                 cur_pic,
                 hdr,
+                anchor_pic,
                 RefPicList::RefPicList0,
                 &ref_pic_lists.ref_pic_list_b0,
             )?,
@@ -805,6 +1161,7 @@ where
             SliceType::B => self.modify_ref_pic_list(
                 cur_pic,
                 hdr,
+                anchor_pic,
                 RefPicList::RefPicList1,
                 &ref_pic_lists.ref_pic_list_b1,
             )?,
@@ -877,6 +1234,10 @@ where
 
         self.dpb.set_limits(max_dpb_frames, max_num_reorder_frames);
         self.dpb.set_interlaced(interlaced);
+        self.other_view
+            .dpb
+            .set_limits(max_dpb_frames, max_num_reorder_frames);
+        self.other_view.dpb.set_interlaced(interlaced);
     }
 }
 
@@ -885,6 +1246,99 @@ where
     B: StatelessH264DecoderBackend,
     B::Handle: Clone,
 {
+    /// Sets the size in bytes of the NAL unit length prefix if the input is length-prefixed, as
+    /// in MP4 files, or `None` if the input is in Annex B format, which is the default.
+    ///
+    /// The size is given by the decoder configuration record of the stream, whose parameter sets
+    /// must be submitted before the first slice, e.g. as length-prefixed NAL units.
+    pub fn set_nal_length_size(&mut self, nal_length_size: Option<usize>) {
+        self.codec.nal_length_size = nal_length_size;
+    }
+
+    /// Returns the frame packing arrangement of stereoscopic streams, as signaled by the last
+    /// frame packing arrangement SEI message of the stream.
+    pub fn frame_packing(&self) -> Option<&FramePackingArrangement> {
+        self.codec.frame_packing.as_ref()
+    }
+
+    /// Takes the decoded picture hash signaled for the picture of `timestamp`, as embedded by
+    /// [`crate::encoder::stateless::h264::EncoderConfig::picture_hash`]. The decoded frame is
+    /// bit-exact with the reconstructed picture of the encoder if [`PictureHash::verify`] passes.
+    ///
+    /// Only the hashes of the last [`MAX_PICTURE_HASHES`] pictures are kept.
+    pub fn take_picture_hash(&mut self, timestamp: u64) -> Option<PictureHash> {
+        let index = self
+            .codec
+            .picture_hashes
+            .iter()
+            .position(|(ts, _)| *ts == timestamp)?;
+
+        self.codec
+            .picture_hashes
+            .remove(index)
+            .map(|(_, hash)| hash)
+    }
+
+    /// Takes the capture time signaled for the picture of `timestamp`, as embedded by
+    /// [`crate::encoder::stateless::h264::EncoderConfig::capture_time`]. The end-to-end latency
+    /// of the frame is given by [`latency::latency`] once it is displayed.
+    ///
+    /// Only the capture times of the last [`MAX_CAPTURE_TIMES`] pictures are kept.
+    pub fn take_capture_time(&mut self, timestamp: u64) -> Option<SystemTime> {
+        let index = self
+            .codec
+            .capture_times
+            .iter()
+            .position(|(ts, _)| *ts == timestamp)?;
+
+        self.codec
+            .capture_times
+            .remove(index)
+            .map(|(_, capture_time)| capture_time)
+    }
+
+    /// Returns the codec state needed to resume the decoding of the stream at its next IDR with
+    /// another decoder, see [`StatelessDecoder::restore`].
+    pub fn snapshot(&self) -> DecoderSnapshot {
+        DecoderSnapshot {
+            parameter_sets: self.codec.parameter_sets.values().cloned().collect(),
+            nal_length_size: self.codec.nal_length_size,
+        }
+    }
+
+    /// Restores the codec state of `snapshot` into a newly created decoder, which then skips its
+    /// input until the next IDR, where it negotiates the format as for the start of a stream.
+    pub fn restore(&mut self, snapshot: &DecoderSnapshot) -> Result<(), DecodeError> {
+        self.codec.nal_length_size = snapshot.nal_length_size;
+
+        for data in &snapshot.parameter_sets {
+            let annexb = [&[0, 0, 1], &data[..]].concat();
+            let nalu = Nalu::next(&mut Cursor::new(&annexb[..]))?;
+            let id = match nalu.header.type_ {
+                NaluType::Sps => self.codec.parser.parse_sps(&nalu)?.seq_parameter_set_id,
+                NaluType::Pps => self.codec.parser.parse_pps(&nalu)?.pic_parameter_set_id,
+                NaluType::SubsetSps => {
+                    let sps = self.codec.parser.parse_subset_sps(&nalu)?.clone();
+                    self.codec.mvc_sps = Some(sps.clone());
+                    sps.seq_parameter_set_id
+                }
+                type_ => return Err(anyhow!("unexpected {:?} NAL unit in snapshot", type_).into()),
+            };
+            self.codec.store_parameter_set(&nalu, id);
+        }
+        self.decoding_state = DecodingState::Reset;
+
+        Ok(())
+    }
+
+    /// Reads the next NAL unit of the input, according to its format.
+    fn next_nalu<'a>(&self, cursor: &mut Cursor<&'a [u8]>) -> anyhow::Result<Nalu<'a>> {
+        match self.codec.nal_length_size {
+            Some(length_size) => Nalu::next_length_prefixed(cursor, length_size),
+            None => Nalu::next(cursor),
+        }
+    }
+
     fn negotiation_possible(sps: &Sps, old_negotiation_info: &NegotiationInfo) -> bool {
         let negotiation_info = NegotiationInfo::from(sps);
         *old_negotiation_info != negotiation_info
@@ -893,7 +1347,7 @@ where
     fn renegotiate_if_needed(&mut self, sps: &Rc<Sps>) -> anyhow::Result<()> {
         if Self::negotiation_possible(sps, &self.codec.negotiation_info) {
             // Make sure all the frames we decoded so far are in the ready queue.
-            self.drain()?;
+            self.drain_views()?;
             self.backend.new_sequence(sps)?;
             self.decoding_state = DecodingState::AwaitingFormat(sps.clone());
         }
@@ -922,6 +1376,24 @@ where
         Ok(())
     }
 
+    /// Same as [`Self::drain`], but for both views of MVC streams. [`Self::drain`] only drains the
+    /// view being decoded, as IDR pictures of one view do not affect the other.
+    fn drain_views(&mut self) -> anyhow::Result<()> {
+        self.drain()?;
+
+        let view = self.codec.view;
+        let other_view = match view {
+            StereoView::Left => StereoView::Right,
+            StereoView::Right => StereoView::Left,
+        };
+        self.codec.switch_view(other_view);
+        self.ready_queue.extend(self.codec.drain());
+        self.codec.switch_view(view);
+        self.codec.inter_view_refs.clear();
+
+        Ok(())
+    }
+
     /// Adds picture to the ready queue if it could not be added to the DPB.
     fn add_to_ready_queue(&mut self, pic: PictureData, handle: B::Handle) {
         if matches!(pic.field, Field::Frame) {
@@ -930,9 +1402,10 @@ where
             self.ready_queue.push(handle);
         } else {
             match self.codec.last_field.take() {
+                // The first field is in the DPB and shares its handle with this field, so the
+                // frame will be output from there.
+                None if pic.is_second_field() => (),
                 None => {
-                    assert!(!pic.is_second_field());
-
                     // Cache the field, wait for its pair.
                     self.codec.last_field = Some((Rc::new(RefCell::new(pic)), handle));
                 }
@@ -948,8 +1421,14 @@ where
                         .set_second_field_to(&Rc::new(RefCell::new(pic)));
                     self.ready_queue.push(field_handle);
                 }
-                // Somehow, the last field is not paired with the current field.
-                _ => log::warn!("unmatched field dropped"),
+                // The cached field is a non-paired field, output it on its own.
+                Some((_, field_handle)) => {
+                    self.ready_queue.push(field_handle);
+
+                    if !pic.is_second_field() {
+                        self.codec.last_field = Some((Rc::new(RefCell::new(pic)), handle));
+                    }
+                }
             }
         }
     }
@@ -959,11 +1438,29 @@ where
 
         // Submit the picture to the backend.
         let handle = self.submit_picture(pic.backend_pic)?;
-        let pps = pic.pps;
+        if pic.corrupted {
+            handle.set_corrupted();
+        }
+        let sps = pic.sps;
         let mut pic = pic.pic;
 
+        if let Some(field_order) = Self::field_order(&pic, &sps) {
+            handle.set_field_order(field_order);
+        }
+
+        if self.codec.mvc_sps.is_some() {
+            handle.set_view(self.codec.view);
+
+            if self.codec.view == StereoView::Left {
+                self.codec.inter_view_refs = vec![DpbEntry {
+                    pic: Rc::new(RefCell::new(pic.clone())),
+                    handle: Some(handle.clone()),
+                }];
+            }
+        }
+
         if matches!(pic.reference(), Reference::ShortTerm | Reference::LongTerm) {
-            self.codec.reference_pic_marking(&mut pic, &pps.sps)?;
+            self.codec.reference_pic_marking(&mut pic, &sps)?;
             self.codec.prev_ref_pic_info.fill(&pic);
         }
 
@@ -1029,6 +1526,23 @@ where
         Ok(())
     }
 
+    /// Returns the order of the fields of `pic` if it is interlaced, i.e. coded as a field or with
+    /// macroblock-adaptive frame/field decoding.
+    fn field_order(pic: &PictureData, sps: &Sps) -> Option<FieldOrder> {
+        let first_field = match pic.field {
+            Field::Frame if !sps.mb_adaptive_frame_field_flag => return None,
+            Field::Frame if pic.bottom_field_order_cnt < pic.top_field_order_cnt => Field::Bottom,
+            Field::Frame => Field::Top,
+            field if pic.is_second_field() => field.opposite(),
+            field => field,
+        };
+
+        match first_field {
+            Field::Bottom => Some(FieldOrder::BottomFieldFirst),
+            _ => Some(FieldOrder::TopFieldFirst),
+        }
+    }
+
     fn handle_frame_num_gap(
         &mut self,
         sps: &Sps,
@@ -1093,20 +1607,25 @@ where
     fn init_current_pic(
         &mut self,
         slice: &Slice,
+        sps: &Sps,
         first_field: Option<&Rc<RefCell<PictureData>>>,
         timestamp: u64,
     ) -> anyhow::Result<PictureData> {
-        let pps = self
-            .codec
-            .parser
-            .get_pps(slice.header.pic_parameter_set_id)
-            .context("Invalid SPS in init_current_pic")?;
-
-        let sps = Rc::clone(&pps.sps);
         let max_frame_num = sps.max_frame_num();
 
-        let mut pic = PictureData::new_from_slice(slice, &sps, timestamp);
-        self.codec.compute_pic_order_count(&mut pic, &sps)?;
+        let mut pic = PictureData::new_from_slice(slice, sps, timestamp);
+        // The view_id of the base view of MVC streams is signaled by their subset SPS.
+        if let Some(mvc_extension) = self
+            .codec
+            .mvc_sps
+            .as_ref()
+            .and_then(|sps| sps.mvc_extension.as_ref())
+        {
+            if slice.nalu.header.type_ != NaluType::SliceExt {
+                pic.view_id = mvc_extension.views[0].view_id;
+            }
+        }
+        self.codec.compute_pic_order_count(&mut pic, sps)?;
         if let Some(first_field) = first_field {
             pic.set_first_field_to(first_field);
         }
@@ -1137,13 +1656,30 @@ where
         Ok(pic)
     }
 
-    /// Called once per picture to start it.
+    /// Returns the SPS used by `slice` of `pps`, which is a subset SPS for the slices of the
+    /// non-base views of MVC streams.
+    fn slice_sps(&self, slice: &Slice, pps: &Pps) -> anyhow::Result<Rc<Sps>> {
+        if slice.nalu.header.type_ != NaluType::SliceExt {
+            return Ok(Rc::clone(&pps.sps));
+        }
+
+        self.codec
+            .parser
+            .get_subset_sps(pps.seq_parameter_set_id)
+            .cloned()
+            .context("Invalid subset SPS")
+    }
+
+    /// Called once per picture to start it. Returns `None` if the picture is dropped.
     fn begin_picture(
         &mut self,
         timestamp: u64,
         slice: &Slice,
-    ) -> Result<CurrentPicState<B::Picture>, DecodeError> {
+    ) -> Result<Option<CurrentPicState<B::Picture>>, DecodeError> {
         let nalu_hdr = &slice.nalu.header;
+        // Slices lost before this one belong to this picture if it does not start with it.
+        let corrupted = std::mem::take(&mut self.codec.next_pic_corrupted)
+            && slice.header.first_mb_in_slice != 0;
 
         if nalu_hdr.idr_pic_flag {
             self.codec.prev_ref_pic_info.frame_num = 0;
@@ -1158,13 +1694,16 @@ where
                 .get_pps(hdr.pic_parameter_set_id)
                 .context("Invalid PPS in handle_picture")?,
         );
+        let sps = self.slice_sps(slice, &pps)?;
 
         // A picture's SPS may require negociation.
-        self.renegotiate_if_needed(&pps.sps)?;
+        let negotiation_sps = Rc::clone(self.codec.negotiation_sps(&sps));
+        self.renegotiate_if_needed(&negotiation_sps)?;
         if let DecodingState::AwaitingFormat(_) = &self.decoding_state {
             return Err(DecodeError::CheckEvents);
         }
 
+        self.provide_frames(PoolLayer::Highest, 1)?;
         if self
             .backend
             .frame_pool(PoolLayer::Highest)
@@ -1173,23 +1712,38 @@ where
             .num_free_frames()
             == 0
         {
-            return Err(DecodeError::NotEnoughOutputBuffers(1));
+            // Pictures of MVC streams may be inter-view references even if nal_ref_idc is 0.
+            let droppable = nalu_hdr.ref_idc == 0 && self.codec.mvc_sps.is_none();
+            self.handle_frame_shortage(1, droppable)?;
+            return Ok(None);
         }
 
-        let current_macroblock = match pps.sps.separate_colour_plane_flag {
+        let current_macroblock = match sps.separate_colour_plane_flag {
             true => CurrentMacroblockTracking::SeparateColorPlane(Default::default()),
             false => CurrentMacroblockTracking::NonSeparateColorPlane(0),
         };
 
         if frame_num != self.codec.prev_ref_pic_info.frame_num
-            && frame_num != (self.codec.prev_ref_pic_info.frame_num + 1) % pps.sps.max_frame_num()
+            && frame_num != (self.codec.prev_ref_pic_info.frame_num + 1) % sps.max_frame_num()
         {
-            self.handle_frame_num_gap(&pps.sps, frame_num, timestamp)?;
+            self.handle_frame_num_gap(&sps, frame_num, timestamp)?;
         }
 
-        let first_field = self.codec.find_first_field(&slice.header)?;
+        let first_field = self
+            .codec
+            .find_first_field(&slice.header, &slice.nalu.header);
+        if first_field.is_none() {
+            // A field that could not be paired with this picture will never be: output it on its
+            // own.
+            if let Some((_, handle)) = self.codec.last_field.take() {
+                self.ready_queue.push(handle);
+            } else if let Some((pic, _)) = self.codec.unpaired_field() {
+                pic.borrow_mut().non_paired = true;
+            }
+        }
 
-        let pic = self.init_current_pic(slice, first_field.as_ref().map(|f| &f.0), timestamp)?;
+        let pic =
+            self.init_current_pic(slice, &sps, first_field.as_ref().map(|f| &f.0), timestamp)?;
         let ref_pic_lists = self.codec.dpb.build_ref_pic_lists(&pic);
 
         debug!("Decode picture POC {:?}", pic.pic_order_cnt);
@@ -1204,19 +1758,22 @@ where
         self.backend.start_picture(
             &mut backend_pic,
             &pic,
-            pps.sps.as_ref(),
+            sps.as_ref(),
             pps.as_ref(),
             &self.codec.dpb,
+            &self.codec.inter_view_refs,
             &slice.header,
         )?;
 
-        Ok(CurrentPicState {
+        Ok(Some(CurrentPicState {
             pic,
             pps,
+            sps,
             backend_pic,
             ref_pic_lists,
             current_macroblock,
-        })
+            corrupted,
+        }))
     }
 
     // Check whether first_mb_in_slice increases monotonically for the current
@@ -1235,7 +1792,7 @@ where
                     Entry::Occupied(mut current_macroblock) => {
                         let current_macroblock = current_macroblock.get_mut();
                         if slice.header.first_mb_in_slice >= *current_macroblock {
-                            log::trace!("first_mb_in_slice does not increase monotically, expect corrupted output");
+                            trace_event!("first_mb_in_slice does not increase monotically, expect corrupted output");
                         }
                         *current_macroblock = slice.header.first_mb_in_slice;
                     }
@@ -1243,7 +1800,7 @@ where
             }
             CurrentMacroblockTracking::NonSeparateColorPlane(current_macroblock) => {
                 if slice.header.first_mb_in_slice >= *current_macroblock {
-                    log::trace!(
+                    trace_event!(
                         "first_mb_in_slice does not increase monotically, expect corrupted output"
                     );
                 }
@@ -1267,10 +1824,14 @@ where
             .get_pps(slice.header.pic_parameter_set_id)
             .context("Invalid PPS")?;
         cur_pic.pps = Rc::clone(pps);
+        cur_pic.sps = self.slice_sps(slice, &cur_pic.pps)?;
 
         // Make sure that no negotiation is possible mid-picture. How could it?
         // We'd lose the context with the previous slices on it.
-        if Self::negotiation_possible(&cur_pic.pps.sps, &self.codec.negotiation_info) {
+        if Self::negotiation_possible(
+            self.codec.negotiation_sps(&cur_pic.sps),
+            &self.codec.negotiation_info,
+        ) {
             anyhow::bail!("invalid stream: inter-frame renegotiation requested");
         }
 
@@ -1279,12 +1840,12 @@ where
             ref_pic_list1,
         } = self
             .codec
-            .create_ref_pic_lists(&cur_pic.pic, &slice.header, &cur_pic.ref_pic_lists)?;
+            .create_ref_pic_lists(&cur_pic.pic, slice, &cur_pic.ref_pic_lists)?;
 
         self.backend.decode_slice(
             &mut cur_pic.backend_pic,
             slice,
-            cur_pic.pps.sps.as_ref(),
+            cur_pic.sps.as_ref(),
             cur_pic.pps.as_ref(),
             &ref_pic_list0,
             &ref_pic_list1,
@@ -1295,7 +1856,9 @@ where
 
     /// Submits the picture to the accelerator.
     fn submit_picture(&mut self, backend_pic: B::Picture) -> Result<B::Handle, DecodeError> {
-        let handle = self.backend.submit_picture(backend_pic)?;
+        let handle = time_backend_call("submit_picture", || {
+            self.backend.submit_picture(backend_pic)
+        })?;
 
         if self.blocking_mode == BlockingMode::Blocking {
             handle.sync()?;
@@ -1304,22 +1867,166 @@ where
         Ok(handle)
     }
 
+    /// Records the decoded picture hash `payload` of the picture of `timestamp`.
+    fn add_picture_hash(&mut self, timestamp: u64, payload: &[u8]) {
+        match PictureHash::from_payload(payload) {
+            Ok(hash) => {
+                if self.codec.picture_hashes.len() == MAX_PICTURE_HASHES {
+                    self.codec.picture_hashes.pop_front();
+                }
+                self.codec.picture_hashes.push_back((timestamp, hash));
+            }
+            Err(e) => log::debug!("ignoring invalid picture hash: {:#}", e),
+        }
+    }
+
+    /// Flags the picture a slice whose header could not be parsed belongs to as corrupted.
+    ///
+    /// Without the header, that picture is guessed from the NAL unit header of the slice and from
+    /// its `first_mb_in_slice` if it can still be read. A lost slice starting a new picture
+    /// finishes the current one, which is intact, and the next picture is flagged unless it starts
+    /// with a slice received afterwards.
+    fn handle_lost_slice(
+        &mut self,
+        slice_data: &[u8],
+        ref_idc: u8,
+        idr_pic_flag: bool,
+    ) -> anyhow::Result<()> {
+        let Some(mut cur_pic) = self.codec.current_pic.take() else {
+            self.codec.next_pic_corrupted = true;
+            return Ok(());
+        };
+
+        let first_mb_in_slice = BitReader::new(slice_data, true).read_ue::<u32>().ok();
+        let starts_picture = first_mb_in_slice == Some(0)
+            || idr_pic_flag != matches!(cur_pic.pic.is_idr, IsIdr::Yes { .. })
+            || (ref_idc == 0) != (cur_pic.pic.nal_ref_idc == 0);
+
+        if starts_picture {
+            self.finish_picture(cur_pic)?;
+            self.codec.next_pic_corrupted = true;
+        } else {
+            cur_pic.corrupted = true;
+            self.codec.current_pic = Some(cur_pic);
+        }
+
+        Ok(())
+    }
+
+    /// Returns `true` if the NAL unit of `slice` ends before its first macroblock. This is the
+    /// only kind of truncation that can be detected without parsing the slice data.
+    fn slice_data_missing(slice: &Slice, pps: &Pps) -> bool {
+        let hdr = &slice.header;
+        let data_bits =
+            (slice.nalu.size - hdr.n_emulation_prevention_bytes) * 8 - hdr.header_bit_size;
+
+        if pps.entropy_coding_mode_flag {
+            // CABAC slice data starts byte-aligned with cabac_alignment_one_bit.
+            data_bits <= (8 - hdr.header_bit_size % 8) % 8
+        } else {
+            // Nothing past the rbsp_stop_one_bit.
+            data_bits <= 1
+        }
+    }
+
+    /// Records the capture time `payload` of the picture of `timestamp`.
+    fn add_capture_time(&mut self, timestamp: u64, payload: &[u8]) {
+        match latency::from_payload(payload) {
+            Ok(capture_time) => {
+                if self.codec.capture_times.len() == MAX_CAPTURE_TIMES {
+                    self.codec.capture_times.pop_front();
+                }
+                self.codec
+                    .capture_times
+                    .push_back((timestamp, capture_time));
+            }
+            Err(e) => log::debug!("ignoring invalid capture time: {:#}", e),
+        }
+    }
+
     fn process_nalu(&mut self, timestamp: u64, nalu: Nalu) -> Result<(), DecodeError> {
         match nalu.header.type_ {
             NaluType::Sps => {
                 self.codec.parser.parse_sps(&nalu)?;
             }
             NaluType::Pps => {
-                self.codec.parser.parse_pps(&nalu)?;
+                let id = self.codec.parser.parse_pps(&nalu)?.pic_parameter_set_id;
+                self.codec.store_parameter_set(&nalu, id);
+            }
+            NaluType::SubsetSps => {
+                let sps = self.codec.parser.parse_subset_sps(&nalu)?.clone();
+                self.codec
+                    .store_parameter_set(&nalu, sps.seq_parameter_set_id);
+                self.codec.mvc_sps = Some(sps);
             }
+            NaluType::Sei => match self.codec.parser.parse_sei(&nalu) {
+                Ok(sei) => {
+                    for message in sei.messages {
+                        match message {
+                            SeiMessage::FramePackingArrangement(fpa) => {
+                                self.codec.frame_packing = Some(fpa)
+                                    .filter(|fpa| !fpa.frame_packing_arrangement_cancel_flag);
+                            }
+                            SeiMessage::UserDataUnregistered(user_data)
+                                if user_data.uuid_iso_iec_11578
+                                    == picture_hash::H264_USER_DATA_UUID =>
+                            {
+                                self.add_picture_hash(timestamp, &user_data.payload)
+                            }
+                            SeiMessage::UserDataUnregistered(user_data)
+                                if user_data.uuid_iso_iec_11578 == latency::H264_USER_DATA_UUID =>
+                            {
+                                self.add_capture_time(timestamp, &user_data.payload)
+                            }
+                            _ => (),
+                        }
+                    }
+                }
+                // SEI messages are not needed for decoding.
+                Err(e) => log::debug!("ignoring invalid SEI: {:#}", e),
+            },
             NaluType::Slice
             | NaluType::SliceDpa
             | NaluType::SliceDpb
             | NaluType::SliceDpc
             | NaluType::SliceIdr
             | NaluType::SliceExt => {
-                let slice = self.codec.parser.parse_slice_header(nalu)?;
-                let mut cur_pic = match self.codec.current_pic.take() {
+                // Without a subset SPS, only the base view of MVC streams can be decoded.
+                if nalu.header.type_ == NaluType::SliceExt && self.codec.mvc_sps.is_none() {
+                    debug!("Skipping slice of a non-base view without subset SPS");
+                    return Ok(());
+                }
+
+                // Kept to find out which picture the slice belongs to if its header is invalid.
+                let slice_data =
+                    &nalu.data[nalu.offset + nalu.header.len()..][..nalu.size - nalu.header.len()];
+                let (ref_idc, idr_pic_flag) = (nalu.header.ref_idc, nalu.header.idr_pic_flag);
+                let slice = match self.codec.parser.parse_slice_header(nalu) {
+                    Ok(slice) => slice,
+                    Err(e) if self.resilience_mode == ResilienceMode::Tolerant => {
+                        log::warn!("skipping slice with invalid header: {:#}", e);
+                        self.handle_lost_slice(slice_data, ref_idc, idr_pic_flag)?;
+                        return Ok(());
+                    }
+                    Err(e) => return Err(e.into()),
+                };
+
+                let view = match self.codec.slice_view(&slice) {
+                    Some(view) => view,
+                    None => {
+                        debug!("Skipping slice of a view that is not decoded");
+                        return Ok(());
+                    }
+                };
+                // The base view picture of an access unit precedes its non-base view one.
+                if view != self.codec.view {
+                    if let Some(cur_pic) = self.codec.current_pic.take() {
+                        self.finish_picture(cur_pic)?;
+                    }
+                    self.codec.switch_view(view);
+                }
+
+                let cur_pic = match self.codec.current_pic.take() {
                     // No current picture, start a new one.
                     None => self.begin_picture(timestamp, &slice)?,
                     // We have a current picture but are starting a new field, or first_mb_in_slice
@@ -1336,11 +2043,20 @@ where
                         self.begin_picture(timestamp, &slice)?
                     }
                     // This slice is part of the current picture.
-                    Some(cur_pic) => cur_pic,
+                    Some(cur_pic) => Some(cur_pic),
                 };
 
-                self.handle_slice(&mut cur_pic, &slice)?;
-                self.codec.current_pic = Some(cur_pic);
+                // The picture may have been dropped during begin_picture().
+                if let Some(mut cur_pic) = cur_pic {
+                    self.handle_slice(&mut cur_pic, &slice)?;
+                    if self.resilience_mode == ResilienceMode::Tolerant
+                        && Self::slice_data_missing(&slice, &cur_pic.pps)
+                    {
+                        log::warn!("slice truncated right after its header");
+                        cur_pic.corrupted = true;
+                    }
+                    self.codec.current_pic = Some(cur_pic);
+                }
             }
             other => {
                 debug!("Unsupported NAL unit type {:?}", other,);
@@ -1357,11 +2073,14 @@ where
     B::Handle: Clone + 'static,
 {
     fn decode(&mut self, timestamp: u64, bitstream: &[u8]) -> Result<usize, DecodeError> {
+        let _span = enter_span("decoder", self.session, "decode", Some(timestamp));
         let mut cursor = Cursor::new(bitstream);
-        let nalu = Nalu::next(&mut cursor)?;
+        let nalu = self.next_nalu(&mut cursor)?;
 
         if nalu.header.type_ == NaluType::Sps {
             let sps = self.codec.parser.parse_sps(&nalu)?.clone();
+            self.codec
+                .store_parameter_set(&nalu, sps.seq_parameter_set_id);
             if matches!(self.decoding_state, DecodingState::AwaitingStreamInfo) {
                 // If more SPS come along we will renegotiate in begin_picture().
                 self.renegotiate_if_needed(&sps)?;
@@ -1369,10 +2088,23 @@ where
                 // We can resume decoding since the decoding parameters have not changed.
                 self.decoding_state = DecodingState::Decoding;
             }
+        } else if nalu.header.type_ == NaluType::SubsetSps {
+            // Negotiate the format of both views at once if the subset SPS follows the SPS that
+            // started the stream.
+            if let DecodingState::AwaitingFormat(pending_sps) = &self.decoding_state {
+                if pending_sps.mvc_extension.is_none() {
+                    let sps = self.codec.parser.parse_subset_sps(&nalu)?.clone();
+                    if sps.seq_parameter_set_id == pending_sps.seq_parameter_set_id {
+                        self.codec.mvc_sps = Some(sps.clone());
+                        self.backend.new_sequence(&sps)?;
+                        self.decoding_state = DecodingState::AwaitingFormat(sps);
+                    }
+                }
+            }
         } else if matches!(self.decoding_state, DecodingState::Reset) {
             let mut cursor = Cursor::new(bitstream);
 
-            while let Ok(nalu) = Nalu::next(&mut cursor) {
+            while let Ok(nalu) = self.next_nalu(&mut cursor) {
                 // In the Reset state we can resume decoding from any key frame.
                 if matches!(nalu.header.type_, NaluType::SliceIdr) {
                     self.decoding_state = DecodingState::Decoding;
@@ -1387,7 +2119,10 @@ where
             // Process parameter sets, but skip input until we get information
             // from the stream.
             DecodingState::AwaitingStreamInfo | DecodingState::Reset => {
-                if matches!(nalu.header.type_, NaluType::Pps) {
+                if matches!(
+                    nalu.header.type_,
+                    NaluType::Pps | NaluType::Sei | NaluType::SubsetSps
+                ) {
                     self.process_nalu(timestamp, nalu)?;
                 }
             }
@@ -1402,7 +2137,7 @@ where
     }
 
     fn flush(&mut self) -> Result<(), DecodeError> {
-        self.drain()?;
+        self.drain_views()?;
         self.decoding_state = DecodingState::Reset;
 
         Ok(())
@@ -1437,16 +2172,64 @@ where
     fn stream_info(&self) -> Option<&StreamInfo> {
         self.backend.stream_info()
     }
+
+    fn frame_budget(&self) -> FrameBudget {
+        self.frame_budget
+    }
 }
 
 #[cfg(test)]
 pub mod tests {
+    use std::cell::RefCell;
+    use std::io::Cursor;
+    use std::rc::Rc;
+    use std::time::Duration;
+    use std::time::SystemTime;
+
+    use crate::backend::dummy::decoder::Handle;
+    use crate::codec::h264::dpb::Dpb;
+    use crate::codec::h264::nalu::annexb_to_length_prefixed;
+    use crate::codec::h264::parser::FramePackingArrangement;
+    use crate::codec::h264::parser::MvcLevelValue;
+    use crate::codec::h264::parser::MvcOperationPoint;
+    use crate::codec::h264::parser::MvcView;
     use crate::codec::h264::parser::Nalu;
+    use crate::codec::h264::parser::NaluHeader;
+    use crate::codec::h264::parser::NaluType;
+    use crate::codec::h264::parser::Parser;
+    use crate::codec::h264::parser::RefPicMarking;
+    use crate::codec::h264::parser::RefPicMarkingInner;
+    use crate::codec::h264::parser::Sei;
+    use crate::codec::h264::parser::SeiMessage;
+    use crate::codec::h264::parser::SliceHeader;
+    use crate::codec::h264::parser::Sps;
+    use crate::codec::h264::parser::SpsMvcExtension;
+    use crate::codec::h264::parser::UserDataUnregistered;
+    use crate::codec::h264::picture::Field;
+    use crate::codec::h264::picture::PictureData;
+    use crate::codec::h264::picture::Reference;
+    use crate::codec::h264::synthesizer::Synthesizer;
+    use crate::decoder::stateless::h264::DecoderSnapshot;
+    use crate::decoder::stateless::h264::H264DecoderState;
     use crate::decoder::stateless::h264::H264;
+    use crate::decoder::stateless::simple_decode_loop;
     use crate::decoder::stateless::tests::test_decode_stream;
     use crate::decoder::stateless::tests::TestStream;
+    use crate::decoder::stateless::DecodeError;
+    use crate::decoder::stateless::DecodedFrames;
+    use crate::decoder::stateless::FrameBudget;
+    use crate::decoder::stateless::FrameReusePolicy;
+    use crate::decoder::stateless::ResilienceMode;
     use crate::decoder::stateless::StatelessDecoder;
+    use crate::decoder::stateless::StatelessVideoDecoder;
     use crate::decoder::BlockingMode;
+    use crate::decoder::DecodedHandle;
+    use crate::decoder::FieldOrder;
+    use crate::decoder::StereoView;
+    use crate::decoder::StreamInfo;
+    use crate::utils::latency;
+    use crate::utils::picture_hash;
+    use crate::utils::picture_hash::PictureHash;
     use crate::utils::simple_playback_loop;
     use crate::utils::simple_playback_loop_owned_frames;
     use crate::utils::NalIterator;
@@ -1513,6 +2296,48 @@ pub mod tests {
         test_decoder_dummy(&DECODE_64X64_PROGRESSIVE_I_P, BlockingMode::NonBlocking);
     }
 
+    /// Decodes `nalus` in `resilience_mode`, returning whether each output frame is flagged as
+    /// corrupted in output order.
+    fn decode_truncated(
+        nalus: &[Vec<u8>],
+        resilience_mode: ResilienceMode,
+    ) -> anyhow::Result<Vec<bool>> {
+        let mut decoder = StatelessDecoder::<H264, _>::new_dummy(BlockingMode::Blocking);
+        decoder.set_resilience_mode(resilience_mode);
+        let mut frames = vec![];
+
+        simple_playback_loop(
+            &mut decoder,
+            nalus.iter(),
+            &mut |handle| frames.push(handle.is_corrupted()),
+            &mut simple_playback_loop_owned_frames,
+            DecodedFormat::NV12,
+            BlockingMode::Blocking,
+        )?;
+
+        Ok(frames)
+    }
+
+    /// Check that a slice whose header cannot be parsed is only accepted in tolerant mode, and
+    /// does not flag the previous picture.
+    #[test]
+    fn test_64x64_progressive_i_p_truncated_slice() {
+        // Cut the P slice right after its NAL header, so its slice header cannot be parsed.
+        let mut nalus = NalIterator::<Nalu>::new(DECODE_64X64_PROGRESSIVE_I_P.stream)
+            .map(|nalu| nalu.to_vec())
+            .collect::<Vec<_>>();
+        let p_slice = nalus.last_mut().unwrap();
+        p_slice.truncate(p_slice.iter().position(|&b| b == 1).unwrap() + 2);
+
+        assert!(decode_truncated(&nalus, ResilienceMode::Strict).is_err());
+
+        // The P picture is lost entirely, and the I picture is intact.
+        assert_eq!(
+            decode_truncated(&nalus, ResilienceMode::Tolerant).unwrap(),
+            [false]
+        );
+    }
+
     /// A 64x64 progressive byte-stream encoded I-P-B-P sequence to make it
     /// easier to it easier to spot errors on the libva trace.
     /// Encoded with the following GStreamer pipeline:
@@ -1559,6 +2384,50 @@ pub mod tests {
         );
     }
 
+    /// Check that a slice truncated right after its header flags its own picture only.
+    #[test]
+    fn test_64x64_progressive_i_p_b_p_high_truncated_slice_data() {
+        let mut nalus = NalIterator::<Nalu>::new(DECODE_64X64_PROGRESSIVE_I_P_B_P_HIGH.stream)
+            .map(|nalu| nalu.to_vec())
+            .collect::<Vec<_>>();
+
+        // Keep the B slice up to the end of its header, without any macroblock.
+        let mut parser = Parser::default();
+        let mut b_slice = None;
+        for (i, data) in nalus.iter().enumerate() {
+            let nalu = Nalu::next(&mut Cursor::new(data.as_slice())).unwrap();
+            match nalu.header.type_ {
+                NaluType::Sps => {
+                    parser.parse_sps(&nalu).unwrap();
+                }
+                NaluType::Pps => {
+                    parser.parse_pps(&nalu).unwrap();
+                }
+                NaluType::Slice => {
+                    let offset = nalu.offset;
+                    let hdr = parser.parse_slice_header(nalu).unwrap().header;
+                    if hdr.slice_type.is_b() {
+                        b_slice = Some((i, offset, hdr));
+                    }
+                }
+                _ => (),
+            }
+        }
+        let (i, offset, hdr) = b_slice.unwrap();
+        nalus[i]
+            .truncate(offset + hdr.header_bit_size.div_ceil(8) + hdr.n_emulation_prevention_bytes);
+
+        // Only the B picture, output between the I and P ones, is flagged.
+        assert_eq!(
+            decode_truncated(&nalus, ResilienceMode::Tolerant).unwrap(),
+            [false, true, false]
+        );
+        assert_eq!(
+            decode_truncated(&nalus, ResilienceMode::Strict).unwrap(),
+            [false, false, false]
+        );
+    }
+
     /// Same as Chromium's test-25fps.h264
     pub const DECODE_TEST_25FPS: TestStream = TestStream {
         stream: include_bytes!("../../codec/h264/test_data/test-25fps.h264"),
@@ -1575,6 +2444,291 @@ pub mod tests {
         test_decoder_dummy(&DECODE_TEST_25FPS, BlockingMode::NonBlocking);
     }
 
+    /// Check that only non-reference pictures are dropped when the client holds all the frames.
+    #[test]
+    fn test_25fps_frame_drop() {
+        let nalus = NalIterator::<Nalu>::new(DECODE_TEST_25FPS.stream).collect::<Vec<_>>();
+        let nalu_header = |nalu: &[u8]| Nalu::next(&mut Cursor::new(nalu)).unwrap().header;
+        let num_frames = DECODE_TEST_25FPS.crcs.lines().count();
+        let mut results = vec![];
+
+        for reuse_policy in [FrameReusePolicy::Block, FrameReusePolicy::Drop] {
+            let mut decoder = StatelessDecoder::<H264, _>::new_dummy(BlockingMode::Blocking);
+            decoder.set_frame_budget(FrameBudget {
+                max_num_frames: None,
+                reuse_policy,
+            });
+            let mut num_blocked = 0;
+
+            for nalu in &nalus {
+                let header = nalu_header(nalu);
+                let non_ref_slice = header.type_ == NaluType::Slice && header.ref_idc == 0;
+                decoder.backend.num_held_frames = if non_ref_slice { 4 } else { 0 };
+
+                loop {
+                    match decoder.decode(0, nalu) {
+                        Ok(_) => break,
+                        Err(DecodeError::CheckEvents) => while decoder.next_event().is_some() {},
+                        // Skip the input, as if it had been dropped.
+                        Err(DecodeError::NotEnoughOutputBuffers(1)) => {
+                            num_blocked += 1;
+                            break;
+                        }
+                        Err(e) => panic!("{}", e),
+                    }
+                }
+            }
+            decoder.flush().unwrap();
+            let mut num_decoded = 0;
+            while decoder.next_event().is_some() {
+                num_decoded += 1;
+            }
+            results.push((num_blocked, num_decoded));
+
+            // Reference pictures are never dropped.
+            let idr = nalus
+                .iter()
+                .find(|nalu| nalu_header(nalu).type_ == NaluType::SliceIdr)
+                .unwrap();
+            decoder.backend.num_held_frames = 4;
+            assert!(matches!(
+                decoder.decode(0, idr),
+                Err(DecodeError::NotEnoughOutputBuffers(1))
+            ));
+        }
+
+        let (num_blocked, num_decoded) = results[0];
+        assert!(num_blocked > 0);
+        assert!(num_decoded < num_frames);
+        // The same pictures are dropped by the decoder rather than refused.
+        assert_eq!(results[1], (0, num_decoded));
+    }
+
+    /// Check that the frame provider is asked for the frames required by the stream, and for extra
+    /// ones within the frame budget.
+    #[test]
+    fn test_25fps_frame_provider() {
+        let mut decoder = StatelessDecoder::<H264, _>::new_dummy(BlockingMode::Blocking);
+        let requests = Rc::new(RefCell::new(vec![]));
+        let provider_requests = Rc::clone(&requests);
+        decoder.set_frame_provider(Box::new(
+            move |_: &StreamInfo, nb_frames| -> anyhow::Result<Vec<()>> {
+                provider_requests.borrow_mut().push(nb_frames);
+                Ok(vec![(); nb_frames])
+            },
+        ));
+        decoder.set_frame_budget(FrameBudget {
+            max_num_frames: Some(6),
+            reuse_policy: FrameReusePolicy::AllocateExtra,
+        });
+        // The client did not allocate any frame.
+        decoder.backend.num_managed_frames = 0;
+
+        let mut nalus = NalIterator::<Nalu>::new(DECODE_TEST_25FPS.stream);
+        let decode = |decoder: &mut StatelessDecoder<H264, _>, nalu: &[u8]| loop {
+            match decoder.decode(0, nalu) {
+                Err(DecodeError::CheckEvents) => while decoder.next_event().is_some() {},
+                res => break res,
+            }
+        };
+        // Decode up to the second picture.
+        for nalu in nalus.by_ref().take(5) {
+            decode(&mut decoder, nalu).unwrap();
+        }
+        assert_eq!(*requests.borrow(), vec![4]);
+        assert_eq!(decoder.backend.num_managed_frames, 4);
+
+        // The client holds all the frames, so extra ones are requested up to the budget.
+        let res = nalus.find_map(|nalu| {
+            decoder.backend.num_held_frames = decoder.backend.num_managed_frames;
+            decode(&mut decoder, nalu).err()
+        });
+        assert!(matches!(res, Some(DecodeError::NotEnoughOutputBuffers(1))));
+        assert_eq!(*requests.borrow(), vec![4, 1, 1]);
+        assert_eq!(decoder.backend.num_managed_frames, 6);
+    }
+
+    #[test]
+    fn test_25fps_decoded_frames() {
+        let mut decoder = StatelessDecoder::<H264, _>::new_dummy(BlockingMode::Blocking);
+        let mut provider =
+            |_: &StreamInfo, nb_frames| -> anyhow::Result<Vec<()>> { Ok(vec![(); nb_frames]) };
+
+        let frames = DecodedFrames::new(
+            &mut decoder,
+            NalIterator::<Nalu>::new(DECODE_TEST_25FPS.stream),
+            DecodedFormat::NV12,
+            &mut provider,
+        )
+        .collect::<anyhow::Result<Vec<_>>>()
+        .unwrap();
+        assert_eq!(frames.len(), DECODE_TEST_25FPS.crcs.lines().count());
+
+        let mut num_frames = 0;
+        simple_decode_loop(
+            &mut decoder,
+            NalIterator::<Nalu>::new(DECODE_TEST_25FPS.stream),
+            DecodedFormat::NV12,
+            &mut provider,
+            |_| num_frames += 1,
+        )
+        .unwrap();
+        assert_eq!(num_frames, DECODE_TEST_25FPS.crcs.lines().count());
+    }
+
+    #[test]
+    fn test_25fps_length_prefixed() {
+        let stream = annexb_to_length_prefixed(DECODE_TEST_25FPS.stream, 4).unwrap();
+        let mut decoder = StatelessDecoder::<H264, _>::new_dummy(BlockingMode::Blocking);
+        decoder.set_nal_length_size(Some(4));
+        let mut num_frames = 0;
+
+        simple_playback_loop(
+            &mut decoder,
+            std::iter::once(&stream),
+            &mut |_| num_frames += 1,
+            &mut simple_playback_loop_owned_frames,
+            DecodedFormat::NV12,
+            BlockingMode::Blocking,
+        )
+        .unwrap();
+
+        assert_eq!(num_frames, DECODE_TEST_25FPS.crcs.lines().count());
+    }
+
+    #[test]
+    fn test_25fps_snapshot() {
+        let nalus = NalIterator::<Nalu>::new(DECODE_TEST_25FPS.stream)
+            .map(|nalu| nalu.to_vec())
+            .collect::<Vec<_>>();
+        let nalu_type = |nalu: &[u8]| {
+            let nalu = Nalu::next(&mut Cursor::new(nalu)).unwrap();
+            nalu.header.type_
+        };
+        let sps_positions = nalus
+            .iter()
+            .enumerate()
+            .filter(|(_, nalu)| nalu_type(nalu) == NaluType::Sps)
+            .map(|(i, _)| i)
+            .collect::<Vec<_>>();
+        let decode = |decoder: &mut StatelessDecoder<H264, _>, nalus: &[Vec<u8>]| {
+            let mut num_frames = 0;
+            simple_playback_loop(
+                decoder,
+                nalus.iter(),
+                &mut |_| num_frames += 1,
+                &mut simple_playback_loop_owned_frames,
+                DecodedFormat::NV12,
+                BlockingMode::Blocking,
+            )
+            .unwrap();
+            num_frames
+        };
+
+        // Migrate in the middle of the first sequence.
+        let migration = (sps_positions[0] + sps_positions[1]) / 2;
+        let mut decoder = StatelessDecoder::<H264, _>::new_dummy(BlockingMode::Blocking);
+        decode(&mut decoder, &nalus[..migration]);
+        let snapshot = DecoderSnapshot::from_bytes(&decoder.snapshot().to_bytes()).unwrap();
+        assert_eq!(snapshot, decoder.snapshot());
+        assert_eq!(snapshot.parameter_sets.len(), 2);
+
+        // The frames of the following sequences are decoded, even without their parameter sets.
+        let mut expected = StatelessDecoder::<H264, _>::new_dummy(BlockingMode::Blocking);
+        let expected_frames = decode(&mut expected, &nalus[sps_positions[1]..]);
+        let remaining = nalus[migration..]
+            .iter()
+            .filter(|nalu| !matches!(nalu_type(nalu), NaluType::Sps | NaluType::Pps))
+            .cloned()
+            .collect::<Vec<_>>();
+        let mut decoder = StatelessDecoder::<H264, _>::new_dummy(BlockingMode::Blocking);
+        decoder.restore(&snapshot).unwrap();
+        assert_eq!(decode(&mut decoder, &remaining), expected_frames);
+
+        let bytes = snapshot.to_bytes();
+        assert!(DecoderSnapshot::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+        assert!(DecoderSnapshot::from_bytes(&[bytes.as_slice(), &[0]].concat()).is_err());
+    }
+
+    #[test]
+    fn test_25fps_frame_packing() {
+        let frame_packing_sei = |cancel| {
+            let sei = Sei {
+                messages: vec![SeiMessage::FramePackingArrangement(
+                    FramePackingArrangement {
+                        frame_packing_arrangement_cancel_flag: cancel,
+                        frame_packing_arrangement_type: 3,
+                        ..Default::default()
+                    },
+                )],
+            };
+            let mut nalu = vec![];
+            Synthesizer::<Sei, _>::synthesize(0, &sei, &Sps::default(), &mut nalu, true).unwrap();
+            nalu
+        };
+        let mut decoder = StatelessDecoder::<H264, _>::new_dummy(BlockingMode::Blocking);
+        let stream = [frame_packing_sei(false), DECODE_TEST_25FPS.stream.to_vec()].concat();
+
+        simple_playback_loop(
+            &mut decoder,
+            NalIterator::<Nalu>::new(&stream),
+            &mut |_| (),
+            &mut simple_playback_loop_owned_frames,
+            DecodedFormat::NV12,
+            BlockingMode::Blocking,
+        )
+        .unwrap();
+        let frame_packing = decoder.frame_packing().unwrap();
+        assert_eq!(frame_packing.frame_packing_arrangement_type, 3);
+
+        decoder.decode(0, &frame_packing_sei(true)).unwrap();
+        assert!(decoder.frame_packing().is_none());
+    }
+
+    #[test]
+    fn test_25fps_picture_hash() {
+        let hash = PictureHash::Crc(vec![0x1234, 0x5678, 0x9abc]);
+        let sei = Sei {
+            messages: vec![SeiMessage::UserDataUnregistered(UserDataUnregistered {
+                uuid_iso_iec_11578: picture_hash::H264_USER_DATA_UUID,
+                payload: hash.payload(),
+            })],
+        };
+        let mut hash_sei = vec![];
+        Synthesizer::<Sei, _>::synthesize(0, &sei, &Sps::default(), &mut hash_sei, true).unwrap();
+
+        let mut decoder = StatelessDecoder::<H264, _>::new_dummy(BlockingMode::Blocking);
+        decoder.decode(0, &hash_sei).unwrap();
+        decoder.decode(1, &hash_sei[..hash_sei.len() - 2]).unwrap();
+
+        assert_eq!(decoder.take_picture_hash(0), Some(hash));
+        assert_eq!(decoder.take_picture_hash(0), None);
+        // The truncated hash was ignored.
+        assert_eq!(decoder.take_picture_hash(1), None);
+    }
+
+    #[test]
+    fn test_25fps_capture_time() {
+        let capture_time = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let sei = Sei {
+            messages: vec![SeiMessage::UserDataUnregistered(UserDataUnregistered {
+                uuid_iso_iec_11578: latency::H264_USER_DATA_UUID,
+                payload: latency::payload(capture_time),
+            })],
+        };
+        let mut capture_time_sei = vec![];
+        Synthesizer::<Sei, _>::synthesize(0, &sei, &Sps::default(), &mut capture_time_sei, true)
+            .unwrap();
+
+        let mut decoder = StatelessDecoder::<H264, _>::new_dummy(BlockingMode::Blocking);
+        decoder.decode(0, &capture_time_sei).unwrap();
+
+        // Capture times are not mistaken for picture hashes.
+        assert_eq!(decoder.take_picture_hash(0), None);
+        assert_eq!(decoder.take_capture_time(0), Some(capture_time));
+        assert_eq!(decoder.take_capture_time(0), None);
+    }
+
     // Adapted from Chromium's test-25fps.h264. Same file, but encoded as
     // interlaced instead using the following ffmpeg command:
     // ffmpeg -i
@@ -1598,4 +2752,271 @@ pub mod tests {
     fn test_25fps_interlaced_nonblock() {
         test_decoder_dummy(&DECODE_TEST_25FPS_INTERLACED, BlockingMode::NonBlocking);
     }
+
+    /// Check that frames are tagged with their field order only if they are interlaced.
+    #[test]
+    fn test_25fps_field_order() {
+        for (test, field_order) in [
+            (&DECODE_TEST_25FPS, None),
+            (
+                &DECODE_TEST_25FPS_INTERLACED,
+                Some(FieldOrder::BottomFieldFirst),
+            ),
+        ] {
+            let mut decoder = StatelessDecoder::<H264, _>::new_dummy(BlockingMode::Blocking);
+            let mut field_orders = vec![];
+            simple_playback_loop(
+                &mut decoder,
+                NalIterator::<Nalu>::new(test.stream),
+                &mut |handle| field_orders.push(handle.field_order()),
+                &mut simple_playback_loop_owned_frames,
+                DecodedFormat::NV12,
+                BlockingMode::Blocking,
+            )
+            .unwrap();
+
+            assert_eq!(field_orders.len(), test.crcs.lines().count());
+            assert!(field_orders.iter().all(|&order| order == field_order));
+        }
+    }
+
+    /// Turns `DECODE_64X64_PROGRESSIVE_I_P` into a stereo MVC stream whose non-base view is a copy
+    /// of its base view, predicted from the previous picture of the non-base view and from the
+    /// base view.
+    fn mvc_stream() -> Vec<u8> {
+        let mut stream = vec![];
+
+        for nalu in NalIterator::<Nalu>::new(DECODE_64X64_PROGRESSIVE_I_P.stream) {
+            let parsed = Nalu::next(&mut Cursor::new(nalu)).unwrap();
+            stream.extend_from_slice(nalu);
+
+            match parsed.header.type_ {
+                NaluType::Sps => {
+                    let mut parser = Parser::default();
+                    let sps = parser.parse_sps(&parsed).unwrap().clone();
+                    drop(parser);
+
+                    let mut sps = Rc::try_unwrap(sps).unwrap();
+                    // Stereo High
+                    sps.profile_idc = 128;
+                    sps.mvc_extension = Some(SpsMvcExtension {
+                        views: vec![
+                            MvcView {
+                                view_id: 0,
+                                ..Default::default()
+                            },
+                            MvcView {
+                                view_id: 1,
+                                anchor_refs_l0: vec![0],
+                                non_anchor_refs_l0: vec![0],
+                                ..Default::default()
+                            },
+                        ],
+                        level_values: vec![MvcLevelValue {
+                            level_idc: sps.level_idc as u8,
+                            operation_points: vec![MvcOperationPoint {
+                                temporal_id: 0,
+                                target_view_ids: vec![1],
+                                num_views_minus1: 1,
+                            }],
+                        }],
+                    });
+                    Synthesizer::<Sps, _>::synthesize(3, &sps, &mut stream, true).unwrap();
+                }
+                NaluType::Slice | NaluType::SliceIdr => {
+                    let idr = parsed.header.type_ == NaluType::SliceIdr;
+                    // nal_unit_header_mvc_extension() of view 1, with a reserved_one_bit.
+                    let mvc_extension: u32 =
+                        (u32::from(!idr) << 22) | (1 << 6) | (u32::from(idr) << 2) | 1;
+
+                    stream.extend_from_slice(&[0, 0, 0, 1, (parsed.header.ref_idc << 5) | 20]);
+                    stream.extend_from_slice(&mvc_extension.to_be_bytes()[1..]);
+                    stream.extend_from_slice(&parsed.as_ref()[1..]);
+                }
+                _ => (),
+            }
+        }
+
+        stream
+    }
+
+    #[test]
+    fn test_64x64_progressive_i_p_mvc() {
+        let stream = mvc_stream();
+        let mut decoder = StatelessDecoder::<H264, _>::new_dummy(BlockingMode::Blocking);
+        let mut views = vec![];
+
+        simple_playback_loop(
+            &mut decoder,
+            NalIterator::<Nalu>::new(&stream),
+            &mut |handle| views.push(handle.view()),
+            &mut simple_playback_loop_owned_frames,
+            DecodedFormat::NV12,
+            BlockingMode::Blocking,
+        )
+        .unwrap();
+
+        let num_views = |view| views.iter().filter(|&&v| v == Some(view)).count();
+        assert_eq!(views.len(), 4);
+        assert_eq!(num_views(StereoView::Left), 2);
+        assert_eq!(num_views(StereoView::Right), 2);
+
+        // Without the subset SPS, the non-base view is skipped.
+        let stream = NalIterator::<Nalu>::new(&stream)
+            .filter(|nalu| {
+                Nalu::next(&mut Cursor::new(*nalu)).unwrap().header.type_ != NaluType::SubsetSps
+            })
+            .map(|nalu| nalu.to_vec())
+            .collect::<Vec<_>>();
+        let mut decoder = StatelessDecoder::<H264, _>::new_dummy(BlockingMode::Blocking);
+        let mut views = vec![];
+
+        simple_playback_loop(
+            &mut decoder,
+            stream.iter(),
+            &mut |handle| views.push(handle.view()),
+            &mut simple_playback_loop_owned_frames,
+            DecodedFormat::NV12,
+            BlockingMode::Blocking,
+        )
+        .unwrap();
+        assert_eq!(views, vec![None, None]);
+    }
+
+    #[test]
+    fn non_paired_fields() {
+        let mut state = H264DecoderState::<Handle, ()>::default();
+        state.dpb.set_interlaced(true);
+
+        let mut top_field = PictureData::default();
+        top_field.field = Field::Top;
+        top_field.frame_num = 1;
+        top_field.set_reference(Reference::ShortTerm, false);
+        state.last_field = Some((
+            Rc::new(RefCell::new(top_field)),
+            Handle {
+                handle: Default::default(),
+            },
+        ));
+
+        let bottom_field = SliceHeader {
+            field_pic_flag: true,
+            bottom_field_flag: true,
+            frame_num: 1,
+            ..Default::default()
+        };
+        let reference = NaluHeader {
+            ref_idc: 1,
+            type_: NaluType::Slice,
+            idr_pic_flag: false,
+            mvc_extension: None,
+        };
+        assert!(state.find_first_field(&bottom_field, &reference).is_some());
+
+        // A frame, a field of another frame_num, or a field of the same parity cannot be paired.
+        let frame = SliceHeader {
+            frame_num: 1,
+            ..Default::default()
+        };
+        assert!(state.find_first_field(&frame, &reference).is_none());
+
+        let other_frame_num = SliceHeader {
+            frame_num: 2,
+            ..bottom_field.clone()
+        };
+        assert!(state
+            .find_first_field(&other_frame_num, &reference)
+            .is_none());
+
+        let same_parity = SliceHeader {
+            bottom_field_flag: false,
+            ..bottom_field.clone()
+        };
+        assert!(state.find_first_field(&same_parity, &reference).is_none());
+
+        // Neither can a non-reference field follow a reference one, nor a field resetting the
+        // reference pictures.
+        let non_reference = NaluHeader {
+            ref_idc: 0,
+            ..reference
+        };
+        assert!(state
+            .find_first_field(&bottom_field, &non_reference)
+            .is_none());
+
+        let idr = NaluHeader {
+            type_: NaluType::SliceIdr,
+            idr_pic_flag: true,
+            ..reference
+        };
+        assert!(state.find_first_field(&bottom_field, &idr).is_none());
+
+        let mmco_5 = SliceHeader {
+            dec_ref_pic_marking: RefPicMarking {
+                adaptive_ref_pic_marking_mode_flag: true,
+                inner: vec![RefPicMarkingInner {
+                    memory_management_control_operation: 5,
+                    ..Default::default()
+                }],
+                ..Default::default()
+            },
+            ..bottom_field
+        };
+        assert!(state.find_first_field(&mmco_5, &reference).is_none());
+    }
+
+    /// Check that a complementary field pair is output according to the lowest POC of its fields,
+    /// even when it is the one of its second field.
+    #[test]
+    fn field_pair_pic_order_cnt() {
+        let mut dpb = Dpb::<u32>::default();
+        dpb.set_interlaced(true);
+        dpb.set_limits(4, 0);
+        let mut last_field = None;
+
+        let mut frame = PictureData::default();
+        frame.pic_order_cnt = 4;
+        dpb.add_picture(Rc::new(RefCell::new(frame)), Some(0), &mut last_field)
+            .unwrap();
+
+        let mut top_field = PictureData::default();
+        top_field.field = Field::Top;
+        top_field.pic_order_cnt = 6;
+        let top_field = Rc::new(RefCell::new(top_field));
+        dpb.add_picture(Rc::clone(&top_field), Some(1), &mut last_field)
+            .unwrap();
+        let mut bottom_field = PictureData::default();
+        bottom_field.field = Field::Bottom;
+        bottom_field.pic_order_cnt = 2;
+        bottom_field.set_first_field_to(&top_field);
+        dpb.add_picture(
+            Rc::new(RefCell::new(bottom_field)),
+            Some(1),
+            &mut last_field,
+        )
+        .unwrap();
+
+        assert_eq!(top_field.borrow().frame_pic_order_cnt(), 2);
+        assert_eq!(dpb.drain(), [Some(1), Some(0)]);
+    }
+
+    /// Check that a field whose second field is missing is only output on its own once it is
+    /// known to be non-paired.
+    #[test]
+    fn non_paired_field_output() {
+        for non_paired in [false, true] {
+            let mut dpb = Dpb::<u32>::default();
+            dpb.set_interlaced(true);
+            dpb.set_limits(4, 0);
+
+            let mut top_field = PictureData::default();
+            top_field.field = Field::Top;
+            top_field.non_paired = non_paired;
+            dpb.add_picture(Rc::new(RefCell::new(top_field)), Some(0), &mut None)
+                .unwrap();
+
+            let expected = if non_paired { vec![Some(0)] } else { vec![] };
+            assert_eq!(dpb.drain(), expected);
+        }
+    }
 }
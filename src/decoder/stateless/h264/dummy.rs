@@ -35,6 +35,7 @@ impl StatelessH264DecoderBackend for Backend {
         _: &Sps,
         _: &Pps,
         _: &Dpb<Self::Handle>,
+        _: &[DpbEntry<Self::Handle>],
         _: &SliceHeader,
     ) -> StatelessBackendResult<()> {
         Ok(())
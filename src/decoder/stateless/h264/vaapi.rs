@@ -44,6 +44,13 @@ use crate::decoder::BlockingMode;
 impl VaStreamInfo for &Rc<Sps> {
     fn va_profile(&self) -> anyhow::Result<i32> {
         let profile_idc = self.profile_idc;
+        // The MVC profiles are only signaled by subset SPSes.
+        match profile_idc {
+            118 => return Ok(libva::VAProfile::VAProfileH264MultiviewHigh),
+            128 => return Ok(libva::VAProfile::VAProfileH264StereoHigh),
+            _ => (),
+        }
+
         let profile = Profile::n(profile_idc)
             .with_context(|| format!("Invalid profile_idc {:?}", profile_idc))?;
 
@@ -96,7 +103,13 @@ impl VaStreamInfo for &Rc<Sps> {
     }
 
     fn min_num_surfaces(&self) -> usize {
-        self.max_dpb_frames() + 4
+        // Both views of stereo MVC streams are decoded, each with its own DPB.
+        let num_views = match &self.mvc_extension {
+            Some(mvc_extension) => std::cmp::min(mvc_extension.views.len(), 2),
+            None => 1,
+        };
+
+        self.max_dpb_frames() * num_views + 4
     }
 
     fn coded_size(&self) -> (u32, u32) {
@@ -208,6 +221,7 @@ fn build_pic_param<M: SurfaceMemoryDescriptor>(
     current_picture: &PictureData,
     current_surface_id: libva::VASurfaceID,
     dpb: &Dpb<VADecodedHandle<M>>,
+    inter_view_refs: &[DpbEntry<VADecodedHandle<M>>],
     sps: &Sps,
     pps: &Pps,
 ) -> anyhow::Result<BufferType> {
@@ -249,6 +263,15 @@ fn build_pic_param<M: SurfaceMemoryDescriptor>(
         va_refs.push(pic);
     }
 
+    // ReferenceFrames only has room for 16 pictures.
+    let num_inter_view_refs = 16usize.saturating_sub(va_refs.len());
+    for handle in inter_view_refs.iter().take(num_inter_view_refs) {
+        let surface_id = va_surface_id(&handle.handle);
+        let ref_pic = handle.pic.borrow();
+        let pic = fill_va_h264_pic(&ref_pic, surface_id, true);
+        va_refs.push(pic);
+    }
+
     for _ in va_refs.len()..16 {
         va_refs.push(build_invalid_va_h264_pic());
     }
@@ -478,6 +501,7 @@ impl<M: SurfaceMemoryDescriptor + 'static> StatelessH264DecoderBackend for Vaapi
         sps: &Sps,
         pps: &Pps,
         dpb: &Dpb<Self::Handle>,
+        inter_view_refs: &[DpbEntry<Self::Handle>],
         hdr: &SliceHeader,
     ) -> StatelessBackendResult<()> {
         let metadata = self.metadata_state.get_parsed()?;
@@ -485,7 +509,15 @@ impl<M: SurfaceMemoryDescriptor + 'static> StatelessH264DecoderBackend for Vaapi
 
         let surface_id = picture.surface().id();
 
-        let pic_param = build_pic_param(hdr, picture_data, surface_id, dpb, sps, pps)?;
+        let pic_param = build_pic_param(
+            hdr,
+            picture_data,
+            surface_id,
+            dpb,
+            inter_view_refs,
+            sps,
+            pps,
+        )?;
         let pic_param = context
             .create_buffer(pic_param)
             .context("while creating picture parameter buffer")?;
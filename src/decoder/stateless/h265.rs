@@ -2,7 +2,7 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file.
 
-#[cfg(test)]
+#[cfg(any(test, feature = "dummy"))]
 mod dummy;
 #[cfg(feature = "vaapi")]
 mod vaapi;
@@ -14,6 +14,8 @@ use std::rc::Rc;
 use anyhow::anyhow;
 use anyhow::Context;
 
+use crate::codec::common::bit_reader::BitReader;
+use crate::codec::h264::nalu::Header;
 use crate::codec::h265::dpb::Dpb;
 use crate::codec::h265::dpb::DpbEntry;
 use crate::codec::h265::parser::Nalu;
@@ -28,7 +30,9 @@ use crate::codec::h265::picture::PictureData;
 use crate::codec::h265::picture::Reference;
 use crate::decoder::stateless::DecodeError;
 use crate::decoder::stateless::DecodingState;
+use crate::decoder::stateless::FrameBudget;
 use crate::decoder::stateless::PoolLayer;
+use crate::decoder::stateless::ResilienceMode;
 use crate::decoder::stateless::StatelessBackendResult;
 use crate::decoder::stateless::StatelessCodec;
 use crate::decoder::stateless::StatelessDecoder;
@@ -41,6 +45,9 @@ use crate::decoder::DecodedHandle;
 use crate::decoder::DecoderEvent;
 use crate::decoder::FramePool;
 use crate::decoder::StreamInfo;
+use crate::instrument::enter_span;
+use crate::instrument::time_backend_call;
+use crate::instrument::trace_event;
 use crate::Resolution;
 
 use super::StatelessDecoderBackendPicture;
@@ -48,6 +55,7 @@ use super::StatelessDecoderBackendPicture;
 const MAX_DPB_SIZE: usize = 16;
 
 // Equation 5-8
+#[cfg(feature = "vaapi")]
 pub(crate) fn clip3(x: i32, y: i32, z: i32) -> i32 {
     if z < x {
         x
@@ -59,6 +67,7 @@ pub(crate) fn clip3(x: i32, y: i32, z: i32) -> i32 {
 }
 
 // See 6.5.3
+#[cfg(feature = "vaapi")]
 const fn up_right_diagonal<const N: usize, const ROWS: usize>() -> [usize; N] {
     // Generics can't be used in const operations for now, so [0; ROWS * ROWS]
     // is rejected by the compiler
@@ -89,15 +98,19 @@ const fn up_right_diagonal<const N: usize, const ROWS: usize>() -> [usize; N] {
     ret
 }
 
+#[cfg(feature = "vaapi")]
 const UP_RIGHT_DIAGONAL_4X4: [usize; 16] = up_right_diagonal::<16, 4>();
+#[cfg(feature = "vaapi")]
 const UP_RIGHT_DIAGONAL_8X8: [usize; 64] = up_right_diagonal::<64, 8>();
 
+#[cfg(feature = "vaapi")]
 fn get_raster_from_up_right_diagonal_8x8(src: [u8; 64], dst: &mut [u8; 64]) {
     for i in 0..64 {
         dst[UP_RIGHT_DIAGONAL_8X8[i]] = src[i];
     }
 }
 
+#[cfg(feature = "vaapi")]
 fn get_raster_from_up_right_diagonal_4x4(src: [u8; 16], dst: &mut [u8; 16]) {
     for i in 0..16 {
         dst[UP_RIGHT_DIAGONAL_4X4[i]] = src[i];
@@ -252,6 +265,8 @@ struct CurrentPicState<H: DecodedHandle, P> {
     backend_pic: P,
     /// List of reference pictures, used once per slice.
     ref_pic_lists: ReferencePicLists<H>,
+    /// Whether some slices of this picture have been lost or truncated.
+    corrupted: bool,
 }
 
 /// All the reference picture lists used to decode a stream.
@@ -306,16 +321,19 @@ pub struct H265DecoderState<H: DecodedHandle, P> {
     /// The value of NoRaslOutputFlag for the last IRAP picture.
     irap_no_rasl_output_flag: bool,
 
-    /// We keep track of the last independent header so we can copy that into
-    /// dependent slices.
-    last_independent_slice_header: Option<SliceHeader>,
-
     /// The picture currently being decoded. We need to preserve it between
     /// calls to `decode` because multiple slices will be processed in different
     /// calls to `decode`.
     current_pic: Option<CurrentPicState<H, P>>,
+    /// Whether slices have been lost in [`ResilienceMode::Tolerant`] before the next picture
+    /// started, possibly including its first ones.
+    next_pic_corrupted: bool,
 
     pending_pps: Vec<Vec<u8>>,
+
+    /// Size of the NAL unit length prefix if the input is length-prefixed
+    /// rather than in Annex B format.
+    nal_length_size: Option<usize>,
 }
 
 impl<H, P> Default for H265DecoderState<H, P>
@@ -335,9 +353,10 @@ where
             prev_tid_0_pic: Default::default(),
             max_pic_order_cnt_lsb: Default::default(),
             irap_no_rasl_output_flag: Default::default(),
-            last_independent_slice_header: Default::default(),
             current_pic: Default::default(),
+            next_pic_corrupted: false,
             pending_pps: Default::default(),
+            nal_length_size: None,
         }
     }
 }
@@ -351,6 +370,9 @@ where
 /// This makes it possible to call [`Decode`](StatelessDecoder::decode) repeatedly on some unsplit
 /// Annex B stream and shrinking it by the number of bytes processed after each call, until the
 /// stream ends up being empty.
+///
+/// Length-prefixed input, as found in MP4 files, is also accepted after calling
+/// [`StatelessDecoder::set_nal_length_size`].
 pub struct H265;
 
 impl StatelessCodec for H265 {
@@ -363,6 +385,23 @@ where
     B: StatelessH265DecoderBackend,
     B::Handle: Clone,
 {
+    /// Sets the size in bytes of the NAL unit length prefix if the input is length-prefixed, as
+    /// in MP4 files, or `None` if the input is in Annex B format, which is the default.
+    ///
+    /// The size is given by the decoder configuration record of the stream, whose parameter sets
+    /// must be submitted before the first slice, e.g. as length-prefixed NAL units.
+    pub fn set_nal_length_size(&mut self, nal_length_size: Option<usize>) {
+        self.codec.nal_length_size = nal_length_size;
+    }
+
+    /// Reads the next NAL unit of the input, according to its format.
+    fn next_nalu<'a>(&self, cursor: &mut Cursor<&'a [u8]>) -> anyhow::Result<Nalu<'a>> {
+        match self.codec.nal_length_size {
+            Some(length_size) => Nalu::next_length_prefixed(cursor, length_size),
+            None => Nalu::next(cursor),
+        }
+    }
+
     /// Whether the stream parameters have changed, indicating that a negotiation window has opened.
     fn negotiation_possible(
         sps: &Sps,
@@ -833,7 +872,7 @@ where
                 .collect::<Vec<_>>()
         );
 
-        log::trace!(
+        trace_event!(
             "{:#?}",
             pics.iter().map(|p| p.0.borrow()).collect::<Vec<_>>()
         );
@@ -908,7 +947,7 @@ where
                     .collect::<Vec<_>>()
             );
 
-            log::trace!(
+            trace_event!(
                 "{:#?}",
                 bumped.iter().map(|p| p.0.borrow()).collect::<Vec<_>>()
             );
@@ -927,17 +966,9 @@ where
         timestamp: u64,
         slice: &Slice,
     ) -> Result<Option<CurrentPicState<B::Handle, B::Picture>>, DecodeError> {
-        let layer = PoolLayer::Layer(self.coded_resolution);
-        if self
-            .backend
-            .frame_pool(layer)
-            .pop()
-            .ok_or(anyhow!("Pool not found"))?
-            .num_free_frames()
-            == 0
-        {
-            return Err(DecodeError::NotEnoughOutputBuffers(1));
-        }
+        // Slices lost before this one belong to this picture if it does not start with it.
+        let corrupted = std::mem::take(&mut self.codec.next_pic_corrupted)
+            && !slice.header.first_slice_segment_in_pic_flag;
 
         let pps = self
             .codec
@@ -954,6 +985,28 @@ where
             return Err(DecodeError::CheckEvents);
         }
 
+        let layer = PoolLayer::Layer(self.coded_resolution);
+        self.provide_frames(layer, 1)?;
+        if self
+            .backend
+            .frame_pool(layer)
+            .pop()
+            .ok_or(anyhow!("Pool not found"))?
+            .num_free_frames()
+            == 0
+        {
+            // Sub-layer non-reference pictures of the highest sub-layer are not referred to.
+            let sps = self
+                .codec
+                .parser
+                .get_sps(self.codec.cur_sps_id)
+                .context("Invalid SPS")?;
+            let droppable = slice.nalu.header.type_.is_slnr()
+                && slice.nalu.header.nuh_temporal_id_plus1 - 1 == sps.max_sub_layers_minus1;
+            self.handle_frame_shortage(1, droppable)?;
+            return Ok(None);
+        }
+
         let pic = PictureData::new_from_slice(
             slice,
             self.codec
@@ -1015,6 +1068,7 @@ where
             pic,
             backend_pic,
             ref_pic_lists: Default::default(),
+            corrupted,
         }))
     }
 
@@ -1078,6 +1132,9 @@ where
 
         // Submit the picture to the backend.
         let handle = self.submit_picture(pic.backend_pic)?;
+        if pic.corrupted {
+            handle.set_corrupted();
+        }
         let pic = pic.pic;
 
         // 8.3.1
@@ -1102,7 +1159,7 @@ where
                 .collect::<Vec<_>>()
         );
 
-        log::trace!(
+        trace_event!(
             "{:#?}",
             bumped.iter().map(|p| p.0.borrow()).collect::<Vec<_>>()
         );
@@ -1171,8 +1228,9 @@ where
 
             NaluType::PpsNut => {
                 if self.codec.parser.parse_pps(&nalu).is_err() {
-                    let data = &nalu.data[nalu.sc_offset..nalu.offset + nalu.size];
-                    self.codec.pending_pps.push(Vec::from(data))
+                    // Keep the PPS with a start code, regardless of the input format.
+                    let data = [&[0x00, 0x00, 0x01], nalu.as_ref()].concat();
+                    self.codec.pending_pps.push(data)
                 }
             }
 
@@ -1192,17 +1250,22 @@ where
             | NaluType::RaslN
             | NaluType::RaslR
             | NaluType::CraNut => {
-                let mut slice = self.codec.parser.parse_slice_header(nalu)?;
+                // Kept to find out which picture the slice belongs to if its header is invalid.
+                let slice_data =
+                    &nalu.data[nalu.offset + nalu.header.len()..][..nalu.size - nalu.header.len()];
+
+                let slice = match self.codec.parser.parse_slice_header(nalu) {
+                    Ok(slice) => slice,
+                    Err(e) if self.resilience_mode == ResilienceMode::Tolerant => {
+                        log::warn!("skipping slice with invalid header: {:#}", e);
+                        self.handle_lost_slice(slice_data)?;
+                        return Ok(());
+                    }
+                    Err(e) => return Err(e.into()),
+                };
 
                 let first_slice_segment_in_pic_flag = slice.header.first_slice_segment_in_pic_flag;
 
-                if slice.header.dependent_slice_segment_flag {
-                    let previous_independent_header = self.codec.last_independent_slice_header.as_ref().ok_or(anyhow!("Cannot process an dependent slice without first processing and independent one"))?.clone();
-                    slice.replace_header(previous_independent_header)?;
-                } else {
-                    self.codec.last_independent_slice_header = Some(slice.header.clone());
-                }
-
                 let cur_pic = match self.codec.current_pic.take() {
                     // No current picture, start a new one.
                     None => self.begin_picture(timestamp, &slice)?,
@@ -1216,6 +1279,12 @@ where
                 // Picture may have been dropped during begin_picture()
                 if let Some(mut cur_pic) = cur_pic {
                     self.handle_slice(&mut cur_pic, &slice)?;
+                    if self.resilience_mode == ResilienceMode::Tolerant
+                        && Self::slice_data_missing(&slice)
+                    {
+                        log::warn!("slice segment truncated before the end of its data");
+                        cur_pic.corrupted = true;
+                    }
                     self.codec.current_pic = Some(cur_pic);
                 }
             }
@@ -1236,9 +1305,48 @@ where
         Ok(())
     }
 
+    /// Flags the picture a slice whose header could not be parsed belongs to as corrupted.
+    ///
+    /// Unless the slice is empty, first_slice_segment_in_pic_flag can still be read to tell
+    /// whether it starts a new picture. If so, the current picture is intact and finished, and the
+    /// next one is flagged unless it starts with a slice received afterwards.
+    fn handle_lost_slice(&mut self, slice_data: &[u8]) -> anyhow::Result<()> {
+        let Some(mut cur_pic) = self.codec.current_pic.take() else {
+            self.codec.next_pic_corrupted = true;
+            return Ok(());
+        };
+
+        if BitReader::new(slice_data, true).read_bit().unwrap_or(false) {
+            self.finish_picture(cur_pic)?;
+            self.codec.next_pic_corrupted = true;
+        } else {
+            cur_pic.corrupted = true;
+            self.codec.current_pic = Some(cur_pic);
+        }
+
+        Ok(())
+    }
+
+    /// Returns `true` if the NAL unit of `slice` ends before its slice segment data, or before the
+    /// start of one of its substreams. This is the only kind of truncation that can be detected
+    /// without parsing the slice segment data.
+    fn slice_data_missing(slice: &Slice) -> bool {
+        let hdr = &slice.header;
+        // The header ends byte-aligned, and the entry points count emulation prevention bytes.
+        let header_size =
+            hdr.header_bit_size as usize / 8 + hdr.n_emulation_prevention_bytes as usize;
+        let data_size = slice.nalu.size.saturating_sub(header_size) as u64;
+
+        hdr.entry_points()
+            .last()
+            .is_some_and(|&entry_point| entry_point >= data_size)
+    }
+
     /// Submits the picture to the accelerator.
     fn submit_picture(&mut self, backend_pic: B::Picture) -> Result<B::Handle, DecodeError> {
-        let handle = self.backend.submit_picture(backend_pic)?;
+        let handle = time_backend_call("submit_picture", || {
+            self.backend.submit_picture(backend_pic)
+        })?;
 
         if self.blocking_mode == BlockingMode::Blocking {
             handle.sync()?;
@@ -1254,8 +1362,9 @@ where
     B::Handle: Clone + 'static,
 {
     fn decode(&mut self, timestamp: u64, bitstream: &[u8]) -> Result<usize, DecodeError> {
+        let _span = enter_span("decoder", self.session, "decode", Some(timestamp));
         let mut cursor = Cursor::new(bitstream);
-        let nalu = Nalu::next(&mut cursor)?;
+        let nalu = self.next_nalu(&mut cursor)?;
 
         if nalu.header.type_ == NaluType::SpsNut {
             let sps = self.codec.parser.parse_sps(&nalu)?.clone();
@@ -1269,7 +1378,7 @@ where
         } else if matches!(self.decoding_state, DecodingState::Reset) {
             let mut cursor = Cursor::new(bitstream);
 
-            while let Ok(nalu) = Nalu::next(&mut cursor) {
+            while let Ok(nalu) = self.next_nalu(&mut cursor) {
                 // In the Reset state we can resume decoding from any key frame.
                 if nalu.header.type_.is_idr() {
                     self.decoding_state = DecodingState::Decoding;
@@ -1338,11 +1447,16 @@ where
     fn stream_info(&self) -> Option<&StreamInfo> {
         self.backend.stream_info()
     }
+
+    fn frame_budget(&self) -> FrameBudget {
+        self.frame_budget
+    }
 }
 
 #[cfg(test)]
 pub mod tests {
 
+    use crate::codec::h264::nalu::annexb_to_length_prefixed;
     use crate::codec::h265::parser::Nalu;
     use crate::decoder::stateless::h265::H265;
     use crate::decoder::stateless::tests::test_decode_stream;
@@ -1450,6 +1564,26 @@ pub mod tests {
         test_decoder_dummy(&DECODE_TEST_25FPS, BlockingMode::NonBlocking);
     }
 
+    #[test]
+    fn test_25fps_length_prefixed() {
+        let stream = annexb_to_length_prefixed(DECODE_TEST_25FPS.stream, 4).unwrap();
+        let mut decoder = StatelessDecoder::<H265, _>::new_dummy(BlockingMode::Blocking);
+        decoder.set_nal_length_size(Some(4));
+        let mut num_frames = 0;
+
+        simple_playback_loop(
+            &mut decoder,
+            std::iter::once(&stream),
+            &mut |_| num_frames += 1,
+            &mut simple_playback_loop_owned_frames,
+            DecodedFormat::NV12,
+            BlockingMode::Blocking,
+        )
+        .unwrap();
+
+        assert_eq!(num_frames, DECODE_TEST_25FPS.crcs.lines().count());
+    }
+
     /// Same as Chromium's bear.h265
     pub const DECODE_BEAR: TestStream = TestStream {
         stream: include_bytes!("../../codec/h265/test_data/bear.h265"),
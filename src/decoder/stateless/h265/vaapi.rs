@@ -83,16 +83,28 @@ impl VaStreamInfo for &Sps {
                 }
             }
 
+            // See table A.2. The smallest profile supporting the bit depth and chroma format is
+            // picked, e.g. the 4:2:2 profiles for 4:2:2 8-bit streams.
+            Profile::RangeExtensions => match (bit_depth, chroma_format_idc) {
+                (8..=12, 0 | 1) => Ok(libva::VAProfile::VAProfileHEVCMain12),
+                (8..=10, 2) => Ok(libva::VAProfile::VAProfileHEVCMain422_10),
+                (11..=12, 2) => Ok(libva::VAProfile::VAProfileHEVCMain422_12),
+                (8, 3) => Ok(libva::VAProfile::VAProfileHEVCMain444),
+                (9..=10, 3) => Ok(libva::VAProfile::VAProfileHEVCMain444_10),
+                (11..=12, 3) => Ok(libva::VAProfile::VAProfileHEVCMain444_12),
+                _ => err,
+            },
+
             // See table A.4.
-            Profile::ScalableMain => match (bit_depth, chroma_format_idc) {
-                (8, 1) => Ok(libva::VAProfile::VAProfileHEVCSccMain),
+            Profile::ScreenContentCoding => match (bit_depth, chroma_format_idc) {
+                (8, 0 | 1) => Ok(libva::VAProfile::VAProfileHEVCSccMain),
                 (8, 3) => Ok(libva::VAProfile::VAProfileHEVCSccMain444),
-                (10, 1) => Ok(libva::VAProfile::VAProfileHEVCSccMain10),
-                (10, 3) => Ok(libva::VAProfile::VAProfileHEVCSccMain444_10),
+                (9..=10, 0 | 1) => Ok(libva::VAProfile::VAProfileHEVCSccMain10),
+                (9..=10, 3) => Ok(libva::VAProfile::VAProfileHEVCSccMain444_10),
                 _ => err,
             },
 
-            _ => unimplemented!("Adding more profile support based on A.3. is still TODO"),
+            _ => Err(anyhow!("unsupported profile {:?}", profile)),
         }
     }
 
@@ -243,7 +255,7 @@ fn is_scc_ext_profile(va_profile: libva::VAProfile::Type) -> bool {
         libva::VAProfile::VAProfileHEVCSccMain
             | libva::VAProfile::VAProfileHEVCSccMain10
             | libva::VAProfile::VAProfileHEVCSccMain444
-            | libva::VAProfile::VAProfileHEVCMain444_10,
+            | libva::VAProfile::VAProfileHEVCSccMain444_10,
     )
 }
 
@@ -444,8 +456,9 @@ fn build_pic_param<M: SurfaceMemoryDescriptor>(
         pps.log2_parallel_merge_level_minus2,
         pps.num_tile_columns_minus1,
         pps.num_tile_rows_minus1,
-        pps.column_width_minus1.map(|x| x as u16),
-        pps.row_height_minus1.map(|x| x as u16),
+        // VA-API does not take the size of the last tile column and row.
+        std::array::from_fn(|i| pps.column_width_minus1[i] as u16),
+        std::array::from_fn(|i| pps.row_height_minus1[i] as u16),
         &slice_parsing_fields,
         sps.log2_max_pic_order_cnt_lsb_minus4,
         sps.num_short_term_ref_pic_sets,
@@ -710,13 +723,15 @@ impl<M: SurfaceMemoryDescriptor + 'static> StatelessH265DecoderBackend for Vaapi
         let pwt = &hdr.pred_weight_table;
 
         let mut delta_luma_weight_l0: [i8; 15usize] = Default::default();
-        let mut luma_offset_l0: [i8; 15usize] = Default::default();
+        // The offsets exceed 8 bits with high precision offsets, they are passed in full in the
+        // range extension buffer.
+        let mut luma_offset_l0: [i16; 15usize] = Default::default();
         let mut delta_chroma_weight_l0: [[i8; 2usize]; 15usize] = Default::default();
-        let mut chroma_offset_l0: [[i8; 2usize]; 15usize] = Default::default();
+        let mut chroma_offset_l0: [[i16; 2usize]; 15usize] = Default::default();
         let mut delta_luma_weight_l1: [i8; 15usize] = Default::default();
-        let mut luma_offset_l1: [i8; 15usize] = Default::default();
+        let mut luma_offset_l1: [i16; 15usize] = Default::default();
         let mut delta_chroma_weight_l1: [[i8; 2usize]; 15usize] = Default::default();
-        let mut chroma_offset_l1: [[i8; 2usize]; 15usize] = Default::default();
+        let mut chroma_offset_l1: [[i16; 2usize]; 15usize] = Default::default();
 
         for i in 0..15 {
             delta_luma_weight_l0[i] = pwt.delta_luma_weight_l0[i];
@@ -783,13 +798,13 @@ impl<M: SurfaceMemoryDescriptor + 'static> StatelessH265DecoderBackend for Vaapi
             pwt.luma_log2_weight_denom,
             pwt.delta_chroma_log2_weight_denom,
             delta_luma_weight_l0,
-            luma_offset_l0,
+            luma_offset_l0.map(|offset| offset as i8),
             delta_chroma_weight_l0,
-            chroma_offset_l0,
+            chroma_offset_l0.map(|offsets| offsets.map(|offset| offset as i8)),
             delta_luma_weight_l1,
-            luma_offset_l1,
+            luma_offset_l1.map(|offset| offset as i8),
             delta_chroma_weight_l1,
-            chroma_offset_l1,
+            chroma_offset_l1.map(|offsets| offsets.map(|offset| offset as i8)),
             hdr.five_minus_max_num_merge_cand,
             hdr.num_entry_point_offsets as _,
             0,
@@ -806,10 +821,10 @@ impl<M: SurfaceMemoryDescriptor + 'static> StatelessH265DecoderBackend for Vaapi
                 );
 
                 let slice_param_ext = SliceParameterBufferHEVCRext::new(
-                    luma_offset_l0.map(i16::from),
-                    chroma_offset_l0.map(|outer| outer.map(i16::from)),
-                    luma_offset_l1.map(i16::from),
-                    chroma_offset_l1.map(|outer| outer.map(i16::from)),
+                    luma_offset_l0,
+                    chroma_offset_l0,
+                    luma_offset_l1,
+                    chroma_offset_l1,
                     &slice_ext_flags,
                     hdr.slice_act_y_qp_offset,
                     hdr.slice_act_cb_qp_offset,
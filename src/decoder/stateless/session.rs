@@ -0,0 +1,607 @@
+// Copyright 2024 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Scheduling of several stateless decoders sharing the same device.
+//!
+//! When many streams are decoded at the same time (e.g. a player showing a wall of camera feeds),
+//! feeding each decoder as fast as possible lets the busiest streams starve the others, and
+//! allocating output frames without limit can exhaust the resources of the driver.
+//!
+//! A [`DecoderSession`] owns a set of decoders along with their pending input. Each call to
+//! [`DecoderSession::run_round`] submits at most one input chunk per stream, starting with a
+//! different stream every round, and the number of output frames allocated across all the streams
+//! is kept under a global budget.
+//!
+//! Decoders using the same backend type can be added to a session regardless of their codec. A
+//! session created with a decoder factory, e.g. [`DecoderSession::new_vaapi`], creates the
+//! decoders of its streams itself with [`DecoderSession::add_codec_stream`], so that they all
+//! share the same device. With VAAPI, all the streams then use a single display.
+
+#[cfg(feature = "vaapi")]
+mod vaapi;
+
+use std::collections::VecDeque;
+
+use thiserror::Error;
+
+use crate::decoder::stateless::DecodeError;
+use crate::decoder::stateless::PoolLayer;
+use crate::decoder::stateless::StatelessDecoderBackend;
+use crate::decoder::stateless::StatelessVideoDecoder;
+use crate::decoder::DecodedHandle;
+use crate::decoder::DecoderEvent;
+use crate::decoder::FramePool;
+use crate::decoder::StreamInfo;
+use crate::utils::demux::TrackCodec;
+use crate::DecodedFormat;
+
+/// Identifier of a stream managed by a [`DecoderSession`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct StreamId(usize);
+
+/// Error returned by [`DecoderSession`] methods.
+#[derive(Debug, Error)]
+pub enum SessionError {
+    #[error("no stream with id {0:?}")]
+    UnknownStream(StreamId),
+    #[error(
+        "stream {stream:?} needs {requested} frames but only {available} are left in the budget"
+    )]
+    BudgetExceeded {
+        stream: StreamId,
+        requested: usize,
+        available: usize,
+    },
+    #[error("error while decoding stream {0:?}: {1}")]
+    DecodeError(StreamId, #[source] DecodeError),
+    #[error("the session has no decoder factory")]
+    NoDecoderFactory,
+    #[error("cannot create a {0:?} decoder: {1}")]
+    DecoderCreation(TrackCodec, #[source] anyhow::Error),
+}
+
+/// Callback used to allocate `nb_frames` new output frames for a stream.
+pub type FrameAllocator<'a, M> =
+    dyn FnMut(StreamId, &StreamInfo, usize) -> anyhow::Result<Vec<M>> + 'a;
+
+/// Callback used to create the decoder of a new stream of a given codec.
+pub type DecoderFactory<B> =
+    Box<dyn FnMut(TrackCodec) -> anyhow::Result<Box<dyn StatelessVideoDecoder<B>>>>;
+
+/// Returns the number of frames each of `nb_pools` pools needs so that together they hold at least
+/// `min_num_frames` frames. Every pool gets at least one frame.
+fn frames_per_pool(min_num_frames: usize, nb_pools: usize) -> usize {
+    if nb_pools == 0 {
+        return 0;
+    }
+
+    min_num_frames.div_ceil(nb_pools).max(1)
+}
+
+/// A stream managed by a [`DecoderSession`].
+struct Stream<B: StatelessDecoderBackend> {
+    decoder: Box<dyn StatelessVideoDecoder<B>>,
+    /// Output format to negotiate for this stream.
+    format: DecodedFormat,
+    /// Pending input along with its timestamp. The first chunk may have been partially processed.
+    input: VecDeque<(u64, Vec<u8>)>,
+    /// Whether the decoder must be flushed once all its pending input has been processed.
+    flush_requested: bool,
+}
+
+/// Schedules the decoding of several streams on decoders sharing the same backend type.
+pub struct DecoderSession<B: StatelessDecoderBackend> {
+    streams: Vec<Option<Stream<B>>>,
+    /// Index of the stream to serve first on the next round.
+    next_stream: usize,
+    /// Maximum number of output frames that can be allocated across all streams.
+    frame_budget: usize,
+    /// Creates the decoders of [`DecoderSession::add_codec_stream`], if any.
+    create_decoder: Option<DecoderFactory<B>>,
+}
+
+impl<B> DecoderSession<B>
+where
+    B: StatelessDecoderBackend,
+{
+    /// Creates a new session allowing at most `frame_budget` output frames to be allocated across
+    /// all its streams.
+    pub fn new(frame_budget: usize) -> Self {
+        Self {
+            streams: Default::default(),
+            next_stream: 0,
+            frame_budget,
+            create_decoder: None,
+        }
+    }
+
+    /// Same as [`DecoderSession::new`], with `create_decoder` creating the decoders of the streams
+    /// added with [`DecoderSession::add_codec_stream`].
+    pub fn with_decoder_factory(frame_budget: usize, create_decoder: DecoderFactory<B>) -> Self {
+        Self {
+            create_decoder: Some(create_decoder),
+            ..Self::new(frame_budget)
+        }
+    }
+
+    /// Returns the maximum number of output frames allowed across all streams.
+    pub fn frame_budget(&self) -> usize {
+        self.frame_budget
+    }
+
+    /// Adds `decoder` to the session. Its output frames will be negotiated into `format`.
+    pub fn add_stream(
+        &mut self,
+        decoder: Box<dyn StatelessVideoDecoder<B>>,
+        format: DecodedFormat,
+    ) -> StreamId {
+        let stream = Stream {
+            decoder,
+            format,
+            input: Default::default(),
+            flush_requested: false,
+        };
+
+        match self.streams.iter().position(Option::is_none) {
+            Some(idx) => {
+                self.streams[idx] = Some(stream);
+                StreamId(idx)
+            }
+            None => {
+                self.streams.push(Some(stream));
+                StreamId(self.streams.len() - 1)
+            }
+        }
+    }
+
+    /// Adds a stream of `codec` to the session, decoded by a decoder created with the factory of
+    /// the session. Its output frames will be negotiated into `format`.
+    pub fn add_codec_stream(
+        &mut self,
+        codec: TrackCodec,
+        format: DecodedFormat,
+    ) -> Result<StreamId, SessionError> {
+        let create_decoder = self
+            .create_decoder
+            .as_mut()
+            .ok_or(SessionError::NoDecoderFactory)?;
+        let decoder = create_decoder(codec).map_err(|e| SessionError::DecoderCreation(codec, e))?;
+
+        Ok(self.add_stream(decoder, format))
+    }
+
+    /// Removes stream `id` from the session and returns its decoder. Its pending input is
+    /// discarded, and its output frames no longer count against the budget.
+    pub fn remove_stream(
+        &mut self,
+        id: StreamId,
+    ) -> Result<Box<dyn StatelessVideoDecoder<B>>, SessionError> {
+        self.streams
+            .get_mut(id.0)
+            .and_then(Option::take)
+            .map(|stream| stream.decoder)
+            .ok_or(SessionError::UnknownStream(id))
+    }
+
+    fn stream_mut(&mut self, id: StreamId) -> Result<&mut Stream<B>, SessionError> {
+        self.streams
+            .get_mut(id.0)
+            .and_then(Option::as_mut)
+            .ok_or(SessionError::UnknownStream(id))
+    }
+
+    /// Queues `bitstream` to be decoded by stream `id`. The expectations on the input are those of
+    /// the codec used by the stream's decoder.
+    pub fn queue_input(
+        &mut self,
+        id: StreamId,
+        timestamp: u64,
+        bitstream: Vec<u8>,
+    ) -> Result<(), SessionError> {
+        self.stream_mut(id)?.input.push_back((timestamp, bitstream));
+        Ok(())
+    }
+
+    /// Requests stream `id` to be flushed once all its currently pending input is processed.
+    pub fn flush(&mut self, id: StreamId) -> Result<(), SessionError> {
+        self.stream_mut(id)?.flush_requested = true;
+        Ok(())
+    }
+
+    /// Whether any stream still has input to process or a flush to perform.
+    pub fn has_pending_work(&self) -> bool {
+        self.streams
+            .iter()
+            .flatten()
+            .any(|stream| !stream.input.is_empty() || stream.flush_requested)
+    }
+
+    /// Returns the number of output frames currently allocated across all streams.
+    pub fn frames_in_use(&mut self) -> usize {
+        self.streams
+            .iter_mut()
+            .flatten()
+            .map(Self::stream_frames)
+            .sum()
+    }
+
+    fn stream_frames(stream: &mut Stream<B>) -> usize {
+        stream
+            .decoder
+            .frame_pool(PoolLayer::All)
+            .iter()
+            .map(|pool| pool.num_managed_frames())
+            .sum()
+    }
+
+    /// Performs one scheduling round: each stream processes its pending events and at most one
+    /// chunk of its pending input. The first stream served changes every round so all streams get
+    /// the same opportunity to use free hardware resources.
+    ///
+    /// `allocate_frames` is called when a stream needs new output frames, and `on_new_frame` for
+    /// each decoded frame.
+    ///
+    /// An error only concerns the stream it reports, and the streams after it in the round are
+    /// not served. The failing stream can be removed and the round run again.
+    pub fn run_round(
+        &mut self,
+        allocate_frames: &mut FrameAllocator<<B::Handle as DecodedHandle>::Descriptor>,
+        on_new_frame: &mut dyn FnMut(StreamId, B::Handle),
+    ) -> Result<(), SessionError> {
+        let nb_streams = self.streams.len();
+        if nb_streams == 0 {
+            return Ok(());
+        }
+
+        let first_stream = self.next_stream % nb_streams;
+        self.next_stream = (first_stream + 1) % nb_streams;
+
+        for idx in (first_stream..nb_streams).chain(0..first_stream) {
+            if self.streams[idx].is_none() {
+                continue;
+            }
+
+            let frames_in_use = self.frames_in_use();
+            let stream = self.streams[idx].as_mut().unwrap();
+            let used_by_others = frames_in_use - Self::stream_frames(stream);
+            let available = self.frame_budget.saturating_sub(used_by_others);
+
+            Self::serve_stream(
+                StreamId(idx),
+                stream,
+                available,
+                allocate_frames,
+                on_new_frame,
+            )?;
+        }
+
+        Ok(())
+    }
+
+    /// Processes the pending events and the next input chunk of `stream`. `available` is the
+    /// number of frames the stream can use without exceeding the budget.
+    fn serve_stream(
+        id: StreamId,
+        stream: &mut Stream<B>,
+        available: usize,
+        allocate_frames: &mut FrameAllocator<<B::Handle as DecodedHandle>::Descriptor>,
+        on_new_frame: &mut dyn FnMut(StreamId, B::Handle),
+    ) -> Result<(), SessionError> {
+        Self::process_events(id, stream, available, allocate_frames, on_new_frame)?;
+
+        if let Some((timestamp, bitstream)) = stream.input.front_mut() {
+            match stream.decoder.decode(*timestamp, bitstream) {
+                Ok(processed) => {
+                    bitstream.drain(..processed);
+                    if bitstream.is_empty() {
+                        stream.input.pop_front();
+                    }
+                }
+                // The same input will be submitted again next round, once the events have been
+                // processed or output frames have been returned.
+                Err(DecodeError::CheckEvents) => (),
+                Err(DecodeError::NotEnoughOutputBuffers(needed)) => {
+                    Self::allocate_extra_frames(id, stream, needed, available, allocate_frames)?
+                }
+                Err(e) => return Err(SessionError::DecodeError(id, e)),
+            }
+        } else if stream.flush_requested {
+            stream
+                .decoder
+                .flush()
+                .map_err(|e| SessionError::DecodeError(id, e))?;
+            stream.flush_requested = false;
+        }
+
+        Self::process_events(id, stream, available, allocate_frames, on_new_frame)
+    }
+
+    /// Adds up to `needed` frames to the pool of `stream` if the frame budget of its decoder allows
+    /// allocating extra frames, without using more than `available` frames in total.
+    fn allocate_extra_frames(
+        id: StreamId,
+        stream: &mut Stream<B>,
+        needed: usize,
+        available: usize,
+        allocate_frames: &mut FrameAllocator<<B::Handle as DecodedHandle>::Descriptor>,
+    ) -> Result<(), SessionError> {
+        let decoder_error = |e| SessionError::DecodeError(id, DecodeError::DecoderError(e));
+
+        let stream_info = match stream.decoder.stream_info() {
+            Some(stream_info) => stream_info.clone(),
+            None => return Ok(()),
+        };
+        let budget = stream.decoder.frame_budget();
+        let remaining = available.saturating_sub(Self::stream_frames(stream));
+
+        if let Some(pool) = stream.decoder.frame_pool(PoolLayer::Highest).pop() {
+            let nb_frames = std::cmp::min(
+                budget.extra_frames(pool.num_managed_frames(), needed),
+                remaining,
+            );
+            if nb_frames > 0 {
+                let frames = allocate_frames(id, &stream_info, nb_frames).map_err(decoder_error)?;
+                pool.add_frames(frames).map_err(decoder_error)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn process_events(
+        id: StreamId,
+        stream: &mut Stream<B>,
+        available: usize,
+        allocate_frames: &mut FrameAllocator<<B::Handle as DecodedHandle>::Descriptor>,
+        on_new_frame: &mut dyn FnMut(StreamId, B::Handle),
+    ) -> Result<(), SessionError> {
+        let decoder_error = |e| SessionError::DecodeError(id, DecodeError::DecoderError(e));
+
+        while let Some(event) = stream.decoder.next_event() {
+            match event {
+                DecoderEvent::FrameReady(handle) => on_new_frame(id, handle),
+                DecoderEvent::FormatChanged(mut format_setter) => {
+                    format_setter
+                        .try_format(stream.format)
+                        .map_err(decoder_error)?;
+                    let stream_info = format_setter.stream_info().clone();
+                    let pools = format_setter.frame_pool(PoolLayer::All);
+                    let frames_per_pool = frames_per_pool(stream_info.min_num_frames, pools.len());
+
+                    let managed: usize = pools.iter().map(|p| p.num_managed_frames()).sum();
+                    let missing: usize = pools
+                        .iter()
+                        .map(|p| frames_per_pool.saturating_sub(p.num_managed_frames()))
+                        .sum();
+                    if managed + missing > available {
+                        return Err(SessionError::BudgetExceeded {
+                            stream: id,
+                            requested: managed + missing,
+                            available,
+                        });
+                    }
+
+                    for pool in pools {
+                        let pool_missing =
+                            frames_per_pool.saturating_sub(pool.num_managed_frames());
+                        if pool_missing > 0 {
+                            let frames = allocate_frames(id, &stream_info, pool_missing)
+                                .map_err(decoder_error)?;
+                            pool.add_frames(frames).map_err(decoder_error)?;
+                        }
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::dummy::decoder::Backend;
+    use crate::codec::h264::parser::Nalu;
+    use crate::decoder::stateless::h264::tests::DECODE_TEST_25FPS;
+    use crate::decoder::stateless::h264::H264;
+    use crate::decoder::stateless::FrameBudget;
+    use crate::decoder::stateless::FrameReusePolicy;
+    use crate::decoder::stateless::StatelessDecoder;
+    use crate::decoder::BlockingMode;
+    use crate::utils::NalIterator;
+
+    fn new_session(frame_budget: usize, nb_streams: usize) -> (DecoderSession<Backend>, usize) {
+        new_session_with(frame_budget, nb_streams, |_| ())
+    }
+
+    /// Same as [`new_session`], with `setup` called on each decoder before it is added.
+    fn new_session_with(
+        frame_budget: usize,
+        nb_streams: usize,
+        setup: impl Fn(&mut StatelessDecoder<H264, Backend>),
+    ) -> (DecoderSession<Backend>, usize) {
+        let mut session = DecoderSession::new(frame_budget);
+        let mut nb_chunks = 0;
+
+        for _ in 0..nb_streams {
+            let mut decoder = StatelessDecoder::<H264, _>::new_dummy(BlockingMode::Blocking);
+            setup(&mut decoder);
+            let id = session.add_stream(Box::new(decoder), DecodedFormat::NV12);
+
+            nb_chunks = 0;
+            for (timestamp, nalu) in NalIterator::<Nalu>::new(DECODE_TEST_25FPS.stream).enumerate()
+            {
+                session
+                    .queue_input(id, timestamp as u64, nalu.to_vec())
+                    .unwrap();
+                nb_chunks += 1;
+            }
+            session.flush(id).unwrap();
+        }
+
+        (session, nb_chunks)
+    }
+
+    #[test]
+    fn frames_per_pool_split() {
+        assert_eq!(frames_per_pool(8, 2), 4);
+        // An uneven split rounds up so that the pools hold at least the required frames.
+        assert_eq!(frames_per_pool(5, 2), 3);
+        assert_eq!(frames_per_pool(7, 3), 3);
+        // Each pool gets at least one frame.
+        assert_eq!(frames_per_pool(1, 3), 1);
+        assert_eq!(frames_per_pool(0, 2), 1);
+        assert_eq!(frames_per_pool(4, 0), 0);
+    }
+
+    #[test]
+    fn round_robin() {
+        let (mut session, nb_chunks) = new_session(12, 3);
+        let mut frames = Vec::new();
+
+        let mut nb_rounds = 0;
+        while session.has_pending_work() {
+            session
+                .run_round(
+                    &mut |_, _, nb_frames| Ok(vec![(); nb_frames]),
+                    &mut |id, _| frames.push(id),
+                )
+                .unwrap();
+            nb_rounds += 1;
+        }
+        // Each stream processes one chunk per round, plus one round for the format change and one
+        // for the flush.
+        assert_eq!(nb_rounds, nb_chunks + 2);
+
+        let nb_frames = DECODE_TEST_25FPS.crcs.lines().count();
+        assert_eq!(frames.len(), 3 * nb_frames);
+        for i in 0..3 {
+            assert_eq!(
+                frames.iter().filter(|&&id| id == StreamId(i)).count(),
+                nb_frames
+            );
+        }
+        // The streams are decoded in parallel, not one after the other.
+        assert_ne!(frames[0], frames[1]);
+    }
+
+    #[test]
+    fn frame_budget() {
+        // The dummy backend uses 4 frames per stream, so one of the streams cannot fit.
+        let (mut session, _) = new_session(8, 3);
+        let mut frames = Vec::new();
+        let mut failed = Vec::new();
+
+        while session.has_pending_work() {
+            match session.run_round(
+                &mut |_, _, nb_frames| Ok(vec![(); nb_frames]),
+                &mut |id, _| frames.push(id),
+            ) {
+                Ok(()) => (),
+                Err(SessionError::BudgetExceeded { stream, .. }) => {
+                    session.remove_stream(stream).unwrap();
+                    failed.push(stream);
+                }
+                Err(e) => panic!("{}", e),
+            }
+        }
+
+        assert_eq!(failed.len(), 1);
+        assert!(!frames.contains(&failed[0]));
+        assert_eq!(frames.len(), 2 * DECODE_TEST_25FPS.crcs.lines().count());
+        assert_eq!(session.frames_in_use(), 8);
+    }
+
+    #[test]
+    fn allocate_extra_frames() {
+        // The client holds all the frames the stream requires, so one extra frame is needed.
+        let (mut session, _) = new_session_with(16, 1, |decoder| {
+            decoder.backend.num_held_frames = 4;
+            decoder.set_frame_budget(FrameBudget {
+                max_num_frames: Some(6),
+                reuse_policy: FrameReusePolicy::AllocateExtra,
+            });
+        });
+        let mut nb_frames = 0;
+
+        while session.has_pending_work() {
+            session
+                .run_round(
+                    &mut |_, _, nb_frames| Ok(vec![(); nb_frames]),
+                    &mut |_, _| nb_frames += 1,
+                )
+                .unwrap();
+        }
+
+        assert_eq!(nb_frames, DECODE_TEST_25FPS.crcs.lines().count());
+        assert_eq!(session.frames_in_use(), 5);
+    }
+
+    #[test]
+    fn decoder_frame_budget() {
+        // The stream requires 4 frames, more than the decoder allows.
+        let (mut session, _) = new_session_with(16, 1, |decoder| {
+            decoder.set_frame_budget(FrameBudget {
+                max_num_frames: Some(2),
+                reuse_policy: FrameReusePolicy::Block,
+            });
+        });
+
+        let mut result = Ok(());
+        while result.is_ok() && session.has_pending_work() {
+            result = session.run_round(
+                &mut |_, _, nb_frames| Ok(vec![(); nb_frames]),
+                &mut |_, _| (),
+            );
+        }
+
+        assert!(matches!(result, Err(SessionError::DecodeError(..))));
+    }
+
+    #[test]
+    fn decoder_factory() {
+        let mut session = DecoderSession::<Backend>::new(8);
+        assert!(matches!(
+            session.add_codec_stream(TrackCodec::H264, DecodedFormat::NV12),
+            Err(SessionError::NoDecoderFactory)
+        ));
+
+        let mut session = DecoderSession::with_decoder_factory(
+            8,
+            Box::new(|codec| match codec {
+                TrackCodec::H264 => Ok(Box::new(StatelessDecoder::<H264, _>::new_dummy(
+                    BlockingMode::Blocking,
+                ))),
+                _ => Err(anyhow::anyhow!("unsupported codec")),
+            }),
+        );
+        assert!(matches!(
+            session.add_codec_stream(TrackCodec::Vp8, DecodedFormat::NV12),
+            Err(SessionError::DecoderCreation(TrackCodec::Vp8, _))
+        ));
+
+        let id = session
+            .add_codec_stream(TrackCodec::H264, DecodedFormat::NV12)
+            .unwrap();
+        for (timestamp, nalu) in NalIterator::<Nalu>::new(DECODE_TEST_25FPS.stream).enumerate() {
+            session
+                .queue_input(id, timestamp as u64, nalu.to_vec())
+                .unwrap();
+        }
+        session.flush(id).unwrap();
+
+        let mut nb_frames = 0;
+        while session.has_pending_work() {
+            session
+                .run_round(
+                    &mut |_, _, nb_frames| Ok(vec![(); nb_frames]),
+                    &mut |_, _| nb_frames += 1,
+                )
+                .unwrap();
+        }
+
+        assert_eq!(nb_frames, DECODE_TEST_25FPS.crcs.lines().count());
+    }
+}
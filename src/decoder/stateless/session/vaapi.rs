@@ -0,0 +1,63 @@
+// Copyright 2024 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+use std::rc::Rc;
+
+use libva::Display;
+use libva::SurfaceMemoryDescriptor;
+
+use crate::backend::vaapi::decoder::VaapiBackend;
+use crate::decoder::stateless::av1::Av1;
+use crate::decoder::stateless::h264::H264;
+use crate::decoder::stateless::h265::H265;
+use crate::decoder::stateless::session::DecoderSession;
+use crate::decoder::stateless::vp8::Vp8;
+use crate::decoder::stateless::vp9::Vp9;
+use crate::decoder::stateless::StatelessDecoder;
+use crate::decoder::stateless::StatelessVideoDecoder;
+use crate::decoder::BlockingMode;
+use crate::utils::demux::TrackCodec;
+
+impl<M: SurfaceMemoryDescriptor + 'static> DecoderSession<VaapiBackend<M>> {
+    /// Creates a new session allowing at most `frame_budget` output frames to be allocated across
+    /// all its streams, whose decoders are created on `display` by
+    /// [`DecoderSession::add_codec_stream`].
+    pub fn new_vaapi<S>(
+        display: Rc<Display>,
+        frame_budget: usize,
+        blocking_mode: BlockingMode,
+    ) -> Self
+    where
+        M: From<S>,
+        S: From<M> + 'static,
+    {
+        let create_decoder = move |codec| {
+            let display = Rc::clone(&display);
+            Ok(match codec {
+                TrackCodec::H264 => Box::new(StatelessDecoder::<H264, _>::new_vaapi::<S>(
+                    display,
+                    blocking_mode,
+                )) as Box<dyn StatelessVideoDecoder<_>>,
+                TrackCodec::H265 => Box::new(StatelessDecoder::<H265, _>::new_vaapi::<S>(
+                    display,
+                    blocking_mode,
+                )) as Box<dyn StatelessVideoDecoder<_>>,
+                TrackCodec::Vp8 => Box::new(StatelessDecoder::<Vp8, _>::new_vaapi::<S>(
+                    display,
+                    blocking_mode,
+                )) as Box<dyn StatelessVideoDecoder<_>>,
+                TrackCodec::Vp9 => Box::new(StatelessDecoder::<Vp9, _>::new_vaapi::<S>(
+                    display,
+                    blocking_mode,
+                )) as Box<dyn StatelessVideoDecoder<_>>,
+                TrackCodec::Av1 => Box::new(StatelessDecoder::<Av1, _>::new_vaapi::<S>(
+                    display,
+                    blocking_mode,
+                )) as Box<dyn StatelessVideoDecoder<_>>,
+            })
+        };
+
+        Self::with_decoder_factory(frame_budget, Box::new(create_decoder))
+    }
+}
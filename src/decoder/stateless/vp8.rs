@@ -2,7 +2,7 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file.
 
-#[cfg(test)]
+#[cfg(any(test, feature = "dummy"))]
 mod dummy;
 #[cfg(feature = "vaapi")]
 mod vaapi;
@@ -16,6 +16,7 @@ use crate::codec::vp8::parser::Parser;
 use crate::codec::vp8::parser::Segmentation;
 use crate::decoder::stateless::DecodeError;
 use crate::decoder::stateless::DecodingState;
+use crate::decoder::stateless::FrameBudget;
 use crate::decoder::stateless::PoolLayer;
 use crate::decoder::stateless::StatelessBackendResult;
 use crate::decoder::stateless::StatelessCodec;
@@ -29,6 +30,8 @@ use crate::decoder::DecodedHandle;
 use crate::decoder::DecoderEvent;
 use crate::decoder::FramePool;
 use crate::decoder::StreamInfo;
+use crate::instrument::enter_span;
+use crate::instrument::time_backend_call;
 use crate::Resolution;
 
 use super::StatelessDecoderBackendPicture;
@@ -176,6 +179,7 @@ where
 {
     /// Handle a single frame.
     fn handle_frame(&mut self, frame: Frame, timestamp: u64) -> Result<(), DecodeError> {
+        self.provide_frames(PoolLayer::Highest, 1)?;
         if self
             .backend
             .frame_pool(PoolLayer::Highest)
@@ -184,21 +188,29 @@ where
             .num_free_frames()
             == 0
         {
-            return Err(DecodeError::NotEnoughOutputBuffers(1));
+            let hdr = &frame.header;
+            let droppable = !hdr.refresh_last
+                && !hdr.refresh_golden_frame
+                && !hdr.refresh_alternate_frame
+                && hdr.copy_buffer_to_golden == 0
+                && hdr.copy_buffer_to_alternate == 0;
+            return self.handle_frame_shortage(1, droppable);
         }
 
         let show_frame = frame.header.show_frame;
 
-        let decoded_handle = self.backend.submit_picture(
-            &frame.header,
-            &self.codec.last_picture,
-            &self.codec.golden_ref_picture,
-            &self.codec.alt_ref_picture,
-            frame.as_ref(),
-            self.codec.parser.segmentation(),
-            self.codec.parser.mb_lf_adjust(),
-            timestamp,
-        )?;
+        let decoded_handle = time_backend_call("submit_picture", || {
+            self.backend.submit_picture(
+                &frame.header,
+                &self.codec.last_picture,
+                &self.codec.golden_ref_picture,
+                &self.codec.alt_ref_picture,
+                frame.as_ref(),
+                self.codec.parser.segmentation(),
+                self.codec.parser.mb_lf_adjust(),
+                timestamp,
+            )
+        })?;
 
         if self.blocking_mode == BlockingMode::Blocking {
             decoded_handle.sync()?;
@@ -231,6 +243,7 @@ where
     B::Handle: Clone + 'static,
 {
     fn decode(&mut self, timestamp: u64, bitstream: &[u8]) -> Result<usize, DecodeError> {
+        let _span = enter_span("decoder", self.session, "decode", Some(timestamp));
         let frame = self.codec.parser.parse_frame(bitstream)?;
 
         if frame.header.key_frame {
@@ -296,6 +309,10 @@ where
     fn stream_info(&self) -> Option<&StreamInfo> {
         self.backend.stream_info()
     }
+
+    fn frame_budget(&self) -> FrameBudget {
+        self.frame_budget
+    }
 }
 
 #[cfg(test)]
@@ -2,11 +2,13 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file.
 
-#[cfg(test)]
+#[cfg(any(test, feature = "dummy"))]
 mod dummy;
 #[cfg(feature = "vaapi")]
 mod vaapi;
 
+use std::collections::VecDeque;
+
 use anyhow::anyhow;
 use log::debug;
 
@@ -20,6 +22,7 @@ use crate::codec::vp9::parser::MAX_SEGMENTS;
 use crate::codec::vp9::parser::NUM_REF_FRAMES;
 use crate::decoder::stateless::DecodeError;
 use crate::decoder::stateless::DecodingState;
+use crate::decoder::stateless::FrameBudget;
 use crate::decoder::stateless::PoolLayer;
 use crate::decoder::stateless::StatelessBackendResult;
 use crate::decoder::stateless::StatelessCodec;
@@ -33,6 +36,8 @@ use crate::decoder::DecodedHandle;
 use crate::decoder::DecoderEvent;
 use crate::decoder::FramePool;
 use crate::decoder::StreamInfo;
+use crate::instrument::enter_span;
+use crate::instrument::time_backend_call;
 use crate::Resolution;
 
 use super::StatelessDecoderBackendPicture;
@@ -71,6 +76,18 @@ pub struct Vp9DecoderState<H: DecodedHandle> {
 
     /// Keeps track of the last values seen for negotiation purposes.
     negotiation_info: NegotiationInfo,
+
+    /// Output information about the frames in each reference slot.
+    reference_info: [Option<ReferenceInfo>; NUM_REF_FRAMES],
+
+    /// Number of frames decoded so far, used to identify the frames held in the reference slots.
+    frame_count: u64,
+
+    /// Information about the frames in the ready queue, in the same order.
+    output_info: VecDeque<Vp9FrameInfo>,
+
+    /// Information about the last frame returned by `next_event`.
+    last_output_info: Option<Vp9FrameInfo>,
 }
 
 impl<H> Default for Vp9DecoderState<H>
@@ -83,10 +100,45 @@ where
             reference_frames: Default::default(),
             segmentation: Default::default(),
             negotiation_info: Default::default(),
+            reference_info: Default::default(),
+            frame_count: 0,
+            output_info: Default::default(),
+            last_output_info: None,
         }
     }
 }
 
+/// Information about a frame output by the VP9 decoder.
+///
+/// VP9 has no super-resolution tool: frames are always decoded at their coded size, and the
+/// bitstream can only signal a different size at which they are meant to be rendered. Both sizes
+/// are reported so clients can perform the upscaling themselves.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Vp9FrameInfo {
+    /// Timestamp of the input chunk that caused the frame to be output.
+    pub timestamp: u64,
+    /// Size of the decoded frame, as coded in the bitstream.
+    pub frame_resolution: Resolution,
+    /// Size at which the frame is meant to be rendered.
+    pub render_resolution: Resolution,
+    /// Whether the frame has been output as the result of a `show_existing_frame` header.
+    pub show_existing_frame: bool,
+    /// Whether the frame has already been output before, i.e. it is a `show_existing_frame` of a
+    /// frame that was already shown. Clients will usually not want to present it again.
+    pub repeated: bool,
+}
+
+/// Information about the frame held in a reference slot.
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct ReferenceInfo {
+    /// Identifies the decoded frame, as several slots can hold the same one.
+    id: u64,
+    frame_resolution: Resolution,
+    render_resolution: Resolution,
+    /// Whether the frame has already been output.
+    shown: bool,
+}
+
 /// Keeps track of the last values seen for negotiation purposes.
 #[derive(Clone, Debug, Default, PartialEq, Eq)]
 struct NegotiationInfo {
@@ -150,7 +202,7 @@ where
 
     /// Handle a single frame.
     fn handle_frame(&mut self, frame: &Frame, timestamp: u64) -> Result<(), DecodeError> {
-        let decoded_handle = if frame.header.show_existing_frame {
+        let (decoded_handle, output_info) = if frame.header.show_existing_frame {
             // Frame to be shown. Because the spec mandates that frame_to_show_map_idx references a
             // valid entry in the DPB, an non-existing index means that the stream is invalid.
             let idx = usize::from(frame.header.frame_to_show_map_idx);
@@ -164,20 +216,50 @@ where
                     anyhow::anyhow!("empty reference frame referenced in frame header")
                 })?;
 
+            let output_info = match &self.codec.reference_info[idx] {
+                Some(info) => {
+                    let output_info = Vp9FrameInfo {
+                        timestamp,
+                        frame_resolution: info.frame_resolution,
+                        render_resolution: info.render_resolution,
+                        show_existing_frame: true,
+                        repeated: info.shown,
+                    };
+
+                    // The same frame may be held in several slots.
+                    let id = info.id;
+                    for info in self.codec.reference_info.iter_mut().flatten() {
+                        if info.id == id {
+                            info.shown = true;
+                        }
+                    }
+
+                    output_info
+                }
+                None => {
+                    return Err(DecodeError::DecoderError(anyhow!(
+                        "no information for reference frame {}",
+                        idx
+                    )))
+                }
+            };
+
             // We are done, no further processing needed.
-            ref_frame.clone()
+            (ref_frame.clone(), output_info)
         } else {
             // Otherwise, we must actually arrange to decode a frame
             let refresh_frame_flags = frame.header.refresh_frame_flags;
 
             Segmentation::update_segmentation(&mut self.codec.segmentation, &frame.header)?;
-            let decoded_handle = self.backend.submit_picture(
-                &frame.header,
-                &self.codec.reference_frames,
-                frame.as_ref(),
-                timestamp,
-                &self.codec.segmentation,
-            )?;
+            let decoded_handle = time_backend_call("submit_picture", || {
+                self.backend.submit_picture(
+                    &frame.header,
+                    &self.codec.reference_frames,
+                    frame.as_ref(),
+                    timestamp,
+                    &self.codec.segmentation,
+                )
+            })?;
 
             if self.blocking_mode == BlockingMode::Blocking {
                 decoded_handle.sync()?;
@@ -190,12 +272,38 @@ where
                 refresh_frame_flags,
             )?;
 
-            decoded_handle
+            let frame_resolution = Resolution::from((frame.header.width, frame.header.height));
+            let render_resolution =
+                Resolution::from((frame.header.render_width, frame.header.render_height));
+
+            self.codec.frame_count += 1;
+            let reference_info = ReferenceInfo {
+                id: self.codec.frame_count,
+                frame_resolution,
+                render_resolution,
+                shown: frame.header.show_frame,
+            };
+            for (i, info) in self.codec.reference_info.iter_mut().enumerate() {
+                if refresh_frame_flags & (1 << i) != 0 {
+                    *info = Some(reference_info.clone());
+                }
+            }
+
+            let output_info = Vp9FrameInfo {
+                timestamp,
+                frame_resolution,
+                render_resolution,
+                show_existing_frame: false,
+                repeated: false,
+            };
+
+            (decoded_handle, output_info)
         };
 
         let show_existing_frame = frame.header.show_existing_frame;
         if frame.header.show_frame || show_existing_frame {
             self.ready_queue.push(decoded_handle);
+            self.codec.output_info.push_back(output_info);
         }
 
         Ok(())
@@ -212,6 +320,15 @@ where
             *old_negotiation_info != negotiation_info
         }
     }
+
+    /// Returns information about the frame last returned by [`StatelessVideoDecoder::next_event`]
+    /// as part of a [`DecoderEvent::FrameReady`] event.
+    ///
+    /// This notably allows clients to detect frames that are repeated through
+    /// `show_existing_frame`, and to retrieve the size at which frames are meant to be rendered.
+    pub fn last_output_info(&self) -> Option<&Vp9FrameInfo> {
+        self.codec.last_output_info.as_ref()
+    }
 }
 
 impl<B> StatelessVideoDecoder<B> for StatelessDecoder<Vp9, B>
@@ -220,8 +337,12 @@ where
     B::Handle: Clone + 'static,
 {
     fn decode(&mut self, timestamp: u64, bitstream: &[u8]) -> Result<usize, DecodeError> {
+        let _span = enter_span("decoder", self.session, "decode", Some(timestamp));
         let frames = self.codec.parser.parse_chunk(bitstream)?;
 
+        if matches!(self.decoding_state, DecodingState::Decoding) {
+            self.provide_frames(PoolLayer::Highest, frames.len())?;
+        }
         let num_free_frames = self
             .backend
             .frame_pool(PoolLayer::Highest)
@@ -231,9 +352,11 @@ where
 
         if matches!(self.decoding_state, DecodingState::Decoding) && num_free_frames < frames.len()
         {
-            return Err(DecodeError::NotEnoughOutputBuffers(
-                frames.len() - num_free_frames,
-            ));
+            let droppable = frames
+                .iter()
+                .all(|frame| frame.header.refresh_frame_flags == 0);
+            self.handle_frame_shortage(frames.len() - num_free_frames, droppable)?;
+            return Ok(bitstream.len());
         }
 
         // With SVC, the first frame will usually be a key-frame, with
@@ -283,6 +406,7 @@ where
     fn flush(&mut self) -> Result<(), DecodeError> {
         // Note: all the submitted frames are already in the ready queue.
         self.codec.reference_frames = Default::default();
+        self.codec.reference_info = Default::default();
         self.decoding_state = DecodingState::Reset;
 
         Ok(())
@@ -293,7 +417,10 @@ where
         // change event that will allow us to keep going.
         (&mut self.ready_queue)
             .next()
-            .map(DecoderEvent::FrameReady)
+            .map(|handle| {
+                self.codec.last_output_info = self.codec.output_info.pop_front();
+                DecoderEvent::FrameReady(handle)
+            })
             .or_else(|| {
                 if let DecodingState::AwaitingFormat(hdr) = &self.decoding_state {
                     Some(DecoderEvent::FormatChanged(Box::new(
@@ -315,19 +442,30 @@ where
     fn stream_info(&self) -> Option<&StreamInfo> {
         self.backend.stream_info()
     }
+
+    fn frame_budget(&self) -> FrameBudget {
+        self.frame_budget
+    }
 }
 
 #[cfg(test)]
 pub mod tests {
+    use crate::backend::dummy::decoder::Backend;
     use crate::decoder::stateless::tests::test_decode_stream;
     use crate::decoder::stateless::tests::TestStream;
     use crate::decoder::stateless::vp9::Vp9;
+    use crate::decoder::stateless::DecodeError;
+    use crate::decoder::stateless::PoolLayer;
     use crate::decoder::stateless::StatelessDecoder;
+    use crate::decoder::stateless::StatelessVideoDecoder;
     use crate::decoder::BlockingMode;
+    use crate::decoder::DecoderEvent;
+    use crate::decoder::FramePool;
     use crate::utils::simple_playback_loop;
     use crate::utils::simple_playback_loop_owned_frames;
     use crate::utils::IvfIterator;
     use crate::DecodedFormat;
+    use crate::Resolution;
 
     /// Run `test` using the dummy decoder, in both blocking and non-blocking modes.
     fn test_decoder_dummy(test: &TestStream, blocking_mode: BlockingMode) {
@@ -390,6 +528,57 @@ pub mod tests {
         );
     }
 
+    #[test]
+    fn show_existing_frame_output_info() {
+        let mut decoder = StatelessDecoder::<Vp9, _>::new_dummy(BlockingMode::Blocking);
+        let mut infos = Vec::new();
+
+        let mut check_events = |decoder: &mut StatelessDecoder<Vp9, Backend>| loop {
+            match decoder.next_event() {
+                Some(DecoderEvent::FrameReady(_)) => (),
+                Some(DecoderEvent::FormatChanged(mut format_setter)) => {
+                    format_setter.try_format(DecodedFormat::NV12).unwrap();
+                    let min_num_frames = format_setter.stream_info().min_num_frames;
+                    for pool in format_setter.frame_pool(PoolLayer::All) {
+                        pool.add_frames(vec![(); min_num_frames]).unwrap();
+                    }
+                    continue;
+                }
+                None => break,
+            }
+            infos.push(decoder.last_output_info().cloned().unwrap());
+        };
+
+        for (frame_num, packet) in
+            IvfIterator::new(DECODE_TEST_25FPS_SHOW_EXISTING_FRAME.stream).enumerate()
+        {
+            loop {
+                match decoder.decode(frame_num as u64, packet) {
+                    Ok(_) => {
+                        check_events(&mut decoder);
+                        break;
+                    }
+                    Err(DecodeError::CheckEvents) => check_events(&mut decoder),
+                    Err(e) => panic!("{}", e),
+                }
+            }
+        }
+        decoder.flush().unwrap();
+        check_events(&mut decoder);
+
+        assert_eq!(
+            infos.len(),
+            DECODE_TEST_25FPS_SHOW_EXISTING_FRAME.crcs.lines().count()
+        );
+        // Repeated frames can only come from `show_existing_frame`.
+        assert!(infos.iter().all(|i| i.show_existing_frame || !i.repeated));
+        assert!(infos.iter().any(|i| i.repeated));
+        assert!(infos
+            .iter()
+            .all(|i| i.frame_resolution == Resolution::from((352, 288))
+                && i.render_resolution == i.frame_resolution));
+    }
+
     pub const DECODE_TEST_25FPS_SHOW_EXISTING_FRAME2: TestStream = TestStream {
         stream: include_bytes!("../../codec/vp9/test_data/vp90-2-10-show-existing-frame2.vp9.ivf"),
         crcs: include_str!("../../codec/vp9/test_data/vp90-2-10-show-existing-frame2.vp9.ivf.crc"),
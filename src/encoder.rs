@@ -2,24 +2,128 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file.
 
+pub mod alpha;
+pub mod aq;
+pub mod filter;
+pub mod preprocess;
 pub mod stateless;
+pub mod units;
 
+use bytes::Bytes;
+
+use crate::codec::h264::parser::Rect;
+use crate::encoder::preprocess::Preprocessing;
+use crate::Fourcc;
 use crate::FrameLayout;
+use crate::PlaneLayout;
 use crate::Resolution;
 
 #[derive(Clone)]
 pub enum Bitrate {
     Constant(u64),
+    /// Constant bitrate of each temporal layer alone, from the base layer up. The frames are
+    /// spread over as many temporal layers as there are bitrates, so that receivers can drop the
+    /// upper layers to lower the frame rate, e.g. as instructed by an SFU. The rate control
+    /// enforces the bitrate of each layer separately.
+    PerLayer(Vec<u64>),
 }
 
 impl Bitrate {
+    #[cfg(any(feature = "vaapi", all(windows, feature = "d3d12")))]
     pub(crate) fn target(&self) -> u64 {
         match self {
             Bitrate::Constant(target) => *target,
+            Bitrate::PerLayer(targets) => targets.iter().sum(),
+        }
+    }
+
+    /// Returns the number of temporal layers of the stream.
+    pub(crate) fn num_layers(&self) -> usize {
+        match self {
+            Bitrate::Constant(_) => 1,
+            Bitrate::PerLayer(targets) => targets.len().max(1),
+        }
+    }
+
+    /// Returns the bitrate of the stream made of the temporal layers up to `layer`, included.
+    #[cfg(feature = "vaapi")]
+    pub(crate) fn layer_target(&self, layer: usize) -> u64 {
+        match self {
+            Bitrate::Constant(target) => *target,
+            Bitrate::PerLayer(targets) => targets.iter().take(layer + 1).sum(),
         }
     }
 }
 
+/// Statistics of the coded frames of a temporal layer, see [`Bitrate::PerLayer`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct LayerStatistics {
+    /// Number of coded frames
+    pub frames: u64,
+    /// Total size of the coded frames, in bytes
+    pub bytes: u64,
+}
+
+/// Arrangement of the two views of stereoscopic frames, signaled in the stream for 3D and VR
+/// players.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FramePacking {
+    /// The left view is in the left half of the frames, and the right view in the right half.
+    SideBySide,
+    /// The left view is in the top half of the frames, and the right view in the bottom half.
+    TopBottom,
+}
+
+/// Tradeoff between the encoding speed and the quality of the stream, translated by the encoders
+/// and their backends into their own tuning parameters.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Preset {
+    /// Fastest encoding with the lowest latency, e.g. for video calls.
+    Realtime,
+    /// Compromise between speed and quality.
+    Balanced,
+    /// Best quality the backend offers, at the expense of speed.
+    Quality,
+}
+
+/// Handling of input timestamps which do not increase strictly, e.g. duplicated or going back
+/// after a capture source restarted, which confuse the muxers downstream.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TimestampPolicy {
+    /// Reject the frame with [`stateless::EncodeError::NonMonotonicTimestamp`].
+    Error,
+    /// Replace the timestamp of the frame with the one following the previous timestamp.
+    Clamp,
+    /// Shift the timestamps of the frame and all the following ones to continue after the
+    /// previous timestamp, keeping the durations of the frames.
+    Renumber,
+}
+
+/// Trellis quantization of the frames by their type. Trellis quantization chooses the
+/// quantized coefficients by rate-distortion optimization, which is slower but saves bits.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Trellis {
+    pub intra: bool,
+    pub predicted: bool,
+    pub bipredicted: bool,
+}
+
+/// Rounding offsets of the quantization of the coefficients, in 1/8th of the quantization step.
+/// Lower offsets round more coefficients down to zero, saving bits at the expense of details.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct QuantizationRounding {
+    pub intra: u8,
+    pub inter: u8,
+}
+
+/// Driver specific tuning of the quantization. Backends which do not support some of the
+/// controls ignore them and use their defaults.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct AdvancedConfig {
+    pub trellis: Option<Trellis>,
+    pub rounding: Option<QuantizationRounding>,
+}
+
 /// Encoder's input metadata
 #[derive(Clone)]
 pub struct FrameMetadata {
@@ -27,6 +131,11 @@ pub struct FrameMetadata {
     pub display_resolution: Resolution,
     pub layout: FrameLayout,
     pub force_keyframe: bool,
+    /// Regions of the frame which changed since the previous one, e.g. as reported by a
+    /// compositor, with exclusive maximum coordinates. `None` if unknown, in which case the
+    /// whole frame may have changed. It is ignored by encoders which cannot set the QP of each
+    /// macroblock, e.g. VA-API drivers without the macroblock level rate control in CBR mode.
+    pub damage: Option<Vec<Rect<u32>>>,
 }
 
 /// Encoder's coded output with contained frame.
@@ -34,21 +143,413 @@ pub struct CodedBitstreamBuffer {
     /// [`FrameMetadata`] of the frame that is compressed in [`Self::bitstream`]
     pub metadata: FrameMetadata,
 
-    /// Bitstream with compressed frame together with optionally other compressed control messages
-    pub bitstream: Vec<u8>,
+    /// Bitstream with compressed frame together with optionally other compressed control messages.
+    /// The storage is reference-counted, so packetizers and muxers can keep cheap sub-slices of it,
+    /// e.g. one per NAL unit or per packet, without copying the frame.
+    pub bitstream: Bytes,
+
+    /// True if the buffer is the first one after a segment point requested with
+    /// [`StatelessVideoEncoder::request_segment_point`]. Such buffer is a keyframe carrying new
+    /// parameter sets, so adaptive streaming packagers may start a new segment with it.
+    ///
+    /// [`StatelessVideoEncoder::request_segment_point`]: stateless::StatelessVideoEncoder::request_segment_point
+    pub segment_point: bool,
+
+    /// True if the frame is identical to the previous one and was not encoded, in which case the
+    /// bitstream is empty. The previous frame shall be displayed for the duration of this one.
+    pub skipped: bool,
+
+    /// Temporal layer of the frame, 0 for streams without temporal layers. The frames of a layer
+    /// only reference the frames of the same or lower layers, so the upper layers may be dropped.
+    pub temporal_layer: u8,
 }
 
 impl CodedBitstreamBuffer {
-    pub fn new(metadata: FrameMetadata, bitstream: Vec<u8>) -> Self {
+    pub fn new(metadata: FrameMetadata, bitstream: impl Into<Bytes>) -> Self {
         Self {
             metadata,
-            bitstream,
+            bitstream: bitstream.into(),
+            segment_point: false,
+            skipped: false,
+            temporal_layer: 0,
         }
     }
 }
 
 impl From<CodedBitstreamBuffer> for Vec<u8> {
     fn from(value: CodedBitstreamBuffer) -> Self {
-        value.bitstream
+        value.bitstream.into()
+    }
+}
+
+/// Returns the BT.601 limited range luma of an RGB pixel.
+fn bt601_luma(r: i32, g: i32, b: i32) -> u8 {
+    (((66 * r + 129 * g + 25 * b + 128) >> 8) + 16) as u8
+}
+
+/// Returns the BT.601 limited range `(U, V)` chroma of an RGB pixel.
+fn bt601_chroma(r: i32, g: i32, b: i32) -> (u8, u8) {
+    let u = ((-38 * r - 74 * g + 112 * b + 128) >> 8) + 128;
+    let v = ((112 * r - 94 * g - 18 * b + 128) >> 8) + 128;
+
+    (u as u8, v as u8)
+}
+
+/// A frame in CPU memory, used as encoder input handle when frames are not already in buffers
+/// of the backend. The frame is copied into a backend buffer when it is imported.
+///
+/// `T` can be anything that derefs to bytes, e.g. `Vec<u8>`, `&[u8]` or `memmap2::Mmap`. The planes
+/// of the frame can be spread over several buffers, as indicated by their `buffer_index`.
+pub struct MemFrame<T: AsRef<[u8]>> {
+    buffers: Vec<T>,
+    layout: FrameLayout,
+}
+
+impl<T: AsRef<[u8]>> MemFrame<T> {
+    /// Creates a frame from `data`, in which its planes are laid out according to `layout`.
+    ///
+    /// Fails if the format of `layout` is not supported, or if its planes do not fit in `data`.
+    pub fn new(data: T, layout: FrameLayout) -> anyhow::Result<Self> {
+        Self::from_buffers(vec![data], layout)
+    }
+
+    /// Creates a frame whose planes are in `buffers`, laid out according to `layout`.
+    ///
+    /// Fails if the format of `layout` is not supported, or if its planes do not fit in the
+    /// buffer they refer to.
+    pub fn from_buffers(buffers: Vec<T>, layout: FrameLayout) -> anyhow::Result<Self> {
+        let sizes = buffers.iter().map(|b| b.as_ref().len()).collect::<Vec<_>>();
+        layout.validate(&sizes)?;
+
+        Ok(Self { buffers, layout })
+    }
+
+    /// Creates a tightly packed NV12 frame of `size` from `data`.
+    pub fn nv12(data: T, size: Resolution) -> anyhow::Result<Self> {
+        let width = size.width as usize;
+        let height = size.height as usize;
+        let chroma_stride = width.next_multiple_of(2);
+        let layout = FrameLayout {
+            format: (Fourcc::from(b"NV12"), 0),
+            size,
+            planes: vec![
+                PlaneLayout {
+                    buffer_index: 0,
+                    offset: 0,
+                    stride: width,
+                },
+                PlaneLayout {
+                    buffer_index: 0,
+                    offset: width * height,
+                    stride: chroma_stride,
+                },
+            ],
+        };
+
+        Self::new(data, layout)
+    }
+
+    pub fn layout(&self) -> &FrameLayout {
+        &self.layout
+    }
+
+    pub fn buffers(&self) -> &[T] {
+        &self.buffers
+    }
+
+    /// Returns the rows of plane `index`, without their padding.
+    pub fn plane_rows(&self, index: usize) -> impl Iterator<Item = &[u8]> {
+        let (row_size, rows) = self
+            .layout
+            .format
+            .0
+            .plane_sizes(self.layout.size)
+            .and_then(|sizes| sizes.get(index).map(|size| (size.row_size, size.rows)))
+            .unwrap_or((0, 0));
+        let plane = self.layout.planes.get(index);
+        let (buffer, offset, stride) = plane.map_or((&[][..], 0, 1), |p| {
+            (self.buffers[p.buffer_index].as_ref(), p.offset, p.stride)
+        });
+
+        buffer[offset..]
+            .chunks(stride)
+            .take(rows)
+            .map(move |row| &row[..row_size])
+    }
+
+    /// Writes the frame as NV12 into `luma` and `chroma`, whose rows are `luma_stride` and
+    /// `chroma_stride` bytes apart, converting it from NV21, I420, YV12 or BGRA if needed.
+    /// BGRA frames are converted to BT.601 limited range YUV.
+    pub fn write_nv12(
+        &self,
+        luma: &mut [u8],
+        luma_stride: usize,
+        chroma: &mut [u8],
+        chroma_stride: usize,
+    ) -> anyhow::Result<()> {
+        let fourcc = self.layout.format.0;
+        let width = self.layout.size.width as usize;
+        let chroma_height = (self.layout.size.height as usize).div_ceil(2);
+        let luma_rows = luma.chunks_mut(luma_stride);
+        let chroma_rows = chroma.chunks_mut(chroma_stride).take(chroma_height);
+
+        let (u_plane, v_plane) = match &<[u8; 4]>::from(fourcc) {
+            b"NV12" | b"NV21" => (1, 1),
+            b"I420" => (1, 2),
+            b"YV12" => (2, 1),
+            b"BGRA" => {
+                self.write_nv12_from_bgra(luma_rows, chroma_rows);
+                return Ok(());
+            }
+            _ => anyhow::bail!("cannot convert {} frames to NV12", fourcc),
+        };
+
+        for (src, dst) in self.plane_rows(0).zip(luma_rows) {
+            dst[..width].copy_from_slice(src);
+        }
+
+        if u_plane != v_plane {
+            let src_rows = self.plane_rows(u_plane).zip(self.plane_rows(v_plane));
+            for ((u, v), dst) in src_rows.zip(chroma_rows) {
+                for ((u, v), uv) in u.iter().zip(v).zip(dst.chunks_mut(2)) {
+                    uv[0] = *u;
+                    uv[1] = *v;
+                }
+            }
+        } else if fourcc == Fourcc::from(b"NV21") {
+            for (src, dst) in self.plane_rows(1).zip(chroma_rows) {
+                for (vu, uv) in src.chunks(2).zip(dst.chunks_mut(2)) {
+                    uv[0] = vu[1];
+                    uv[1] = vu[0];
+                }
+            }
+        } else {
+            for (src, dst) in self.plane_rows(1).zip(chroma_rows) {
+                dst[..src.len()].copy_from_slice(src);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn write_nv12_from_bgra<'a>(
+        &self,
+        luma_rows: impl Iterator<Item = &'a mut [u8]>,
+        chroma_rows: impl Iterator<Item = &'a mut [u8]>,
+    ) {
+        let width = self.layout.size.width as usize;
+        let rows = self.plane_rows(0).collect::<Vec<_>>();
+        for (src, dst) in rows.iter().zip(luma_rows) {
+            for (bgra, y) in src.chunks(4).zip(dst.iter_mut()) {
+                *y = bt601_luma(bgra[2].into(), bgra[1].into(), bgra[0].into());
+            }
+        }
+
+        for (j, dst) in chroma_rows.enumerate() {
+            let top = rows[2 * j];
+            let bottom = rows.get(2 * j + 1).unwrap_or(&top);
+            for (i, uv) in dst.chunks_mut(2).take(width.div_ceil(2)).enumerate() {
+                // Average the 2x2 block of pixels sharing the chroma sample.
+                let x0 = 8 * i;
+                let x1 = if 2 * i + 1 < width { x0 + 4 } else { x0 };
+                let mut bgr = [0i32; 3];
+                for row in [top, bottom] {
+                    for x in [x0, x1] {
+                        for (c, sum) in bgr.iter_mut().enumerate() {
+                            *sum += i32::from(row[x + c]);
+                        }
+                    }
+                }
+                let [b, g, r] = bgr.map(|sum| (sum + 2) / 4);
+                (uv[0], uv[1]) = bt601_chroma(r, g, b);
+            }
+        }
+    }
+
+    /// Returns a copy of the frame mirrored and rotated according to `preprocessing`, with its
+    /// planes tightly packed in a single buffer.
+    pub fn transformed(&self, preprocessing: &Preprocessing) -> anyhow::Result<MemFrame<Vec<u8>>> {
+        let fourcc = self.layout.format.0;
+        let (_, bytes_per_sample, planes) = fourcc
+            .plane_info()
+            .ok_or_else(|| anyhow::anyhow!("cannot transform {} frames", fourcc))?;
+
+        let mut data = Vec::new();
+        let mut layout = FrameLayout {
+            format: self.layout.format,
+            size: preprocessing.output_size(self.layout.size),
+            planes: Vec::with_capacity(planes.len()),
+        };
+        for (i, &(_, _, samples)) in planes.iter().enumerate() {
+            let element_size = samples * bytes_per_sample;
+            let rows = self.plane_rows(i).collect::<Vec<_>>();
+            let width = rows.first().map_or(0, |row| row.len() / element_size);
+            let grid = Resolution::from((width as u32, rows.len() as u32));
+            layout.planes.push(PlaneLayout {
+                buffer_index: 0,
+                offset: data.len(),
+                stride: preprocessing.output_size(grid).width as usize * element_size,
+            });
+            data.extend(preprocessing.transform_plane(&rows, width, element_size));
+        }
+
+        MemFrame::new(data, layout)
+    }
+
+    /// Returns the buffers backing the frame.
+    pub fn into_buffers(self) -> Vec<T> {
+        self.buffers
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encoder::preprocess::Rotation;
+
+    #[test]
+    fn mem_frame_layout() {
+        let size = Resolution::from((5, 3));
+        let frame = MemFrame::nv12(vec![0u8; 5 * 3 + 6 * 2], size).unwrap();
+        assert_eq!(frame.plane_rows(0).count(), 3);
+        assert!(frame.plane_rows(1).all(|row| row.len() == 6));
+
+        // Too small buffer.
+        assert!(MemFrame::nv12(vec![0u8; 5 * 3 + 6], size).is_err());
+
+        // Padded I420 frame, whose last row does not need padding.
+        let planes = [(0, 8), (8 * 3, 4), (8 * 3 + 4 * 2, 4)]
+            .into_iter()
+            .map(|(offset, stride)| PlaneLayout {
+                buffer_index: 0,
+                offset,
+                stride,
+            })
+            .collect::<Vec<_>>();
+        let layout = FrameLayout {
+            format: (Fourcc::from(b"I420"), 0),
+            size,
+            planes: planes.clone(),
+        };
+        let frame = MemFrame::new(&[0u8; 8 * 3 + 4 * 2 + 4 + 3][..], layout.clone()).unwrap();
+        assert_eq!(
+            frame.plane_rows(2).collect::<Vec<_>>(),
+            vec![&[0u8; 3][..]; 2]
+        );
+
+        // Stride smaller than the rows.
+        let mut narrow = layout.clone();
+        narrow.planes[0].stride = 4;
+        assert!(MemFrame::new(vec![0u8; 64], narrow).is_err());
+
+        // Missing plane and unsupported format.
+        let mut missing = layout.clone();
+        missing.planes.pop();
+        assert!(MemFrame::new(vec![0u8; 64], missing).is_err());
+        let mut rgb = layout.clone();
+        rgb.format.0 = Fourcc::from(b"RG24");
+        assert!(MemFrame::new(vec![0u8; 64], rgb).is_err());
+
+        // Each plane in its own buffer.
+        let mut split = layout;
+        for (i, plane) in split.planes.iter_mut().enumerate() {
+            plane.buffer_index = i;
+            plane.offset = 0;
+        }
+        let buffers = vec![vec![1u8; 8 * 3], vec![2u8; 4 * 2], vec![3u8; 4 + 3]];
+        let frame = MemFrame::from_buffers(buffers.clone(), split.clone()).unwrap();
+        assert!(frame.plane_rows(1).all(|row| row == [2u8; 3]));
+        assert!(frame.plane_rows(2).all(|row| row == [3u8; 3]));
+        // Missing buffer.
+        assert!(MemFrame::from_buffers(buffers[..2].to_vec(), split).is_err());
+    }
+
+    /// Converts `frame` to a tightly packed NV12 buffer.
+    fn to_nv12<T: AsRef<[u8]>>(frame: &MemFrame<T>) -> Vec<u8> {
+        let size = frame.layout().size;
+        let width = size.width as usize;
+        let luma_size = width * size.height as usize;
+        let chroma_stride = width.next_multiple_of(2);
+        let mut nv12 = vec![0u8; luma_size + chroma_stride * (size.height as usize).div_ceil(2)];
+        let (luma, chroma) = nv12.split_at_mut(luma_size);
+        frame
+            .write_nv12(luma, width, chroma, chroma_stride)
+            .unwrap();
+
+        nv12
+    }
+
+    fn packed_layout(fourcc: &[u8; 4], size: Resolution, planes: &[(usize, usize)]) -> FrameLayout {
+        FrameLayout {
+            format: (Fourcc::from(fourcc), 0),
+            size,
+            planes: planes
+                .iter()
+                .map(|&(offset, stride)| PlaneLayout {
+                    buffer_index: 0,
+                    offset,
+                    stride,
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn nv12_conversion() {
+        let size = Resolution::from((4, 2));
+        // Luma, then the U and V samples of the two chroma blocks.
+        let nv12 = [0, 1, 2, 3, 4, 5, 6, 7, 10, 20, 11, 21];
+
+        let frame = MemFrame::nv12(&nv12[..], size).unwrap();
+        assert_eq!(to_nv12(&frame), nv12);
+
+        let nv21 = [0, 1, 2, 3, 4, 5, 6, 7, 20, 10, 21, 11];
+        let layout = packed_layout(b"NV21", size, &[(0, 4), (8, 4)]);
+        assert_eq!(to_nv12(&MemFrame::new(&nv21[..], layout).unwrap()), nv12);
+
+        let i420 = [0, 1, 2, 3, 4, 5, 6, 7, 10, 11, 20, 21];
+        let layout = packed_layout(b"I420", size, &[(0, 4), (8, 2), (10, 2)]);
+        assert_eq!(to_nv12(&MemFrame::new(&i420[..], layout).unwrap()), nv12);
+
+        let yv12 = [0, 1, 2, 3, 4, 5, 6, 7, 20, 21, 10, 11];
+        let layout = packed_layout(b"YV12", size, &[(0, 4), (8, 2), (10, 2)]);
+        assert_eq!(to_nv12(&MemFrame::new(&yv12[..], layout).unwrap()), nv12);
+    }
+
+    #[test]
+    fn transformed() {
+        // 4x2 I420 frame, rotated into a 2x4 one.
+        let i420 = [0, 1, 2, 3, 4, 5, 6, 7, 10, 11, 20, 21];
+        let layout = packed_layout(
+            b"I420",
+            Resolution::from((4, 2)),
+            &[(0, 4), (8, 2), (10, 2)],
+        );
+        let frame = MemFrame::new(&i420[..], layout).unwrap();
+        let preprocessing = Preprocessing {
+            rotation: Some(Rotation::Rotate90),
+            ..Default::default()
+        };
+
+        let rotated = frame.transformed(&preprocessing).unwrap();
+        assert_eq!(rotated.layout().size, Resolution::from((2, 4)));
+        assert_eq!(
+            rotated.plane_rows(0).collect::<Vec<_>>(),
+            [[4, 0], [5, 1], [6, 2], [7, 3]]
+        );
+        assert_eq!(rotated.plane_rows(1).collect::<Vec<_>>(), [[10], [11]]);
+        assert_eq!(rotated.plane_rows(2).collect::<Vec<_>>(), [[20], [21]]);
+    }
+
+    #[test]
+    fn bgra_conversion() {
+        // A 3x1 frame of white, black and pure red pixels.
+        let bgra = [255, 255, 255, 255, 0, 0, 0, 255, 0, 0, 255, 255];
+        let layout = packed_layout(b"BGRA", Resolution::from((3, 1)), &[(0, 12)]);
+        let nv12 = to_nv12(&MemFrame::new(&bgra[..], layout).unwrap());
+
+        assert_eq!(&nv12[..3], &[235, 16, 82]);
+        // The first chroma sample averages white and black to grey, the second one is red.
+        assert_eq!(&nv12[3..], &[128, 128, 90, 240]);
     }
 }
@@ -0,0 +1,247 @@
+// Copyright 2024 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Encoding of frames with transparency.
+//!
+//! None of the supported codecs carry an alpha channel, so the alpha plane of the frames is
+//! encoded as a secondary, auxiliary stream next to the color one, like VP9 alpha in WebM or the
+//! HEVC alpha auxiliary layer do. The alpha plane becomes the luma plane of a grey NV12 frame,
+//! which is encoded by a second encoder with the same configuration as the color one.
+
+use std::collections::VecDeque;
+use std::marker::PhantomData;
+
+use crate::encoder::stateless::EncodeError;
+use crate::encoder::stateless::EncodeResult;
+use crate::encoder::stateless::StatelessBackendError;
+use crate::encoder::stateless::StatelessVideoEncoder;
+use crate::encoder::CodedBitstreamBuffer;
+use crate::encoder::FrameMetadata;
+use crate::encoder::MemFrame;
+use crate::Fourcc;
+use crate::FrameLayout;
+use crate::PlaneLayout;
+
+/// Coded frame with transparency.
+pub struct AlphaCodedBuffer {
+    /// Coded color frame.
+    pub color: CodedBitstreamBuffer,
+    /// Coded alpha plane of the frame, to be muxed as its auxiliary picture.
+    pub alpha: CodedBitstreamBuffer,
+}
+
+/// Returns the alpha plane of the BGRA `frame`, tightly packed.
+pub fn bgra_alpha_plane<T: AsRef<[u8]>>(frame: &MemFrame<T>) -> anyhow::Result<Vec<u8>> {
+    let fourcc = frame.layout().format.0;
+    anyhow::ensure!(
+        fourcc == Fourcc::from(b"BGRA"),
+        "cannot extract the alpha plane of {} frames",
+        fourcc
+    );
+
+    Ok(frame
+        .plane_rows(0)
+        .flat_map(|row| row.chunks(4).map(|bgra| bgra[3]))
+        .collect())
+}
+
+/// Encoder of frames with transparency, made of a `C` encoder for the color frames of type `H`
+/// and a `A` encoder for their alpha plane.
+///
+/// Both encoders must be configured identically, so that each color frame and its alpha plane
+/// are coded with the same frame type and come out of the encoders at the same time.
+pub struct AlphaEncoder<H, C, A> {
+    color: C,
+    alpha: A,
+    color_output: VecDeque<CodedBitstreamBuffer>,
+    alpha_output: VecDeque<CodedBitstreamBuffer>,
+    _phantom: PhantomData<H>,
+}
+
+impl<H, C, A> AlphaEncoder<H, C, A>
+where
+    C: StatelessVideoEncoder<H>,
+    A: StatelessVideoEncoder<MemFrame<Vec<u8>>>,
+{
+    pub fn new(color: C, alpha: A) -> Self {
+        Self {
+            color,
+            alpha,
+            color_output: Default::default(),
+            alpha_output: Default::default(),
+            _phantom: Default::default(),
+        }
+    }
+
+    /// Enqueues the color frame `handle` and its tightly packed `alpha` plane for encoding.
+    pub fn encode(&mut self, meta: FrameMetadata, handle: H, alpha: Vec<u8>) -> EncodeResult<()> {
+        let size = meta.layout.size;
+        let width = size.width as usize;
+        let luma_size = width * size.height as usize;
+        if alpha.len() != luma_size {
+            return Err(StatelessBackendError::Other(anyhow::anyhow!(
+                "alpha plane of {} bytes for a frame of {:?}",
+                alpha.len(),
+                size
+            ))
+            .into());
+        }
+
+        let chroma_stride = width.next_multiple_of(2);
+        let mut data = alpha;
+        data.resize(
+            luma_size + chroma_stride * (size.height as usize).div_ceil(2),
+            128,
+        );
+        let layout = FrameLayout {
+            format: (Fourcc::from(b"NV12"), 0),
+            size,
+            planes: vec![
+                PlaneLayout {
+                    buffer_index: 0,
+                    offset: 0,
+                    stride: width,
+                },
+                PlaneLayout {
+                    buffer_index: 0,
+                    offset: luma_size,
+                    stride: chroma_stride,
+                },
+            ],
+        };
+        let alpha_frame =
+            MemFrame::new(data, layout.clone()).map_err(StatelessBackendError::Other)?;
+        let alpha_meta = FrameMetadata {
+            layout,
+            ..meta.clone()
+        };
+
+        self.color.encode(meta, handle)?;
+        self.alpha.encode(alpha_meta, alpha_frame)
+    }
+
+    /// Drains both encoders, see [`StatelessVideoEncoder::drain`].
+    pub fn drain(&mut self) -> EncodeResult<()> {
+        self.color.drain()?;
+        self.alpha.drain()
+    }
+
+    /// Polls for the next coded frame, returned once both its color and alpha are coded.
+    pub fn poll(&mut self) -> EncodeResult<Option<AlphaCodedBuffer>> {
+        while let Some(coded) = self.color.poll()? {
+            self.color_output.push_back(coded);
+        }
+        while let Some(coded) = self.alpha.poll()? {
+            self.alpha_output.push_back(coded);
+        }
+
+        if self.color_output.is_empty() || self.alpha_output.is_empty() {
+            return Ok(None);
+        }
+
+        let color = self.color_output.pop_front().unwrap();
+        let alpha = self.alpha_output.pop_front().unwrap();
+        if color.metadata.timestamp != alpha.metadata.timestamp {
+            log::error!(
+                "Color frame {} coded along with alpha frame {}",
+                color.metadata.timestamp,
+                alpha.metadata.timestamp
+            );
+            return Err(EncodeError::InvalidInternalState);
+        }
+
+        Ok(Some(AlphaCodedBuffer { color, alpha }))
+    }
+
+    /// Requests a segment point in both streams, see
+    /// [`StatelessVideoEncoder::request_segment_point`].
+    pub fn request_segment_point(&mut self) {
+        self.color.request_segment_point();
+        self.alpha.request_segment_point();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Resolution;
+
+    /// Encoder outputting the first byte of the frames as bitstream, one frame late.
+    #[derive(Default)]
+    struct DummyEncoder {
+        queue: VecDeque<(FrameMetadata, u8)>,
+        output: VecDeque<CodedBitstreamBuffer>,
+    }
+
+    impl<T: AsRef<[u8]>> StatelessVideoEncoder<MemFrame<T>> for DummyEncoder {
+        fn encode(&mut self, meta: FrameMetadata, handle: MemFrame<T>) -> EncodeResult<()> {
+            self.queue
+                .push_back((meta, handle.buffers()[0].as_ref()[0]));
+            if self.queue.len() > 1 {
+                let (meta, byte) = self.queue.pop_front().unwrap();
+                self.output
+                    .push_back(CodedBitstreamBuffer::new(meta, vec![byte]));
+            }
+            Ok(())
+        }
+
+        fn drain(&mut self) -> EncodeResult<()> {
+            for (meta, byte) in self.queue.drain(..) {
+                self.output
+                    .push_back(CodedBitstreamBuffer::new(meta, vec![byte]));
+            }
+            Ok(())
+        }
+
+        fn poll(&mut self) -> EncodeResult<Option<CodedBitstreamBuffer>> {
+            Ok(self.output.pop_front())
+        }
+
+        fn request_segment_point(&mut self) {}
+    }
+
+    #[test]
+    fn alpha_encoder() {
+        let size = Resolution::from((2, 1));
+        let mut encoder = AlphaEncoder::new(DummyEncoder::default(), DummyEncoder::default());
+        let mut coded = Vec::new();
+
+        for timestamp in 0..3u8 {
+            // Blue pixels of varying opacity.
+            let bgra = vec![255, 0, 0, timestamp, 255, 0, 0, 255];
+            let layout = FrameLayout {
+                format: (Fourcc::from(b"BGRA"), 0),
+                size,
+                planes: vec![PlaneLayout {
+                    buffer_index: 0,
+                    offset: 0,
+                    stride: 8,
+                }],
+            };
+            let frame = MemFrame::new(bgra, layout.clone()).unwrap();
+            let alpha = bgra_alpha_plane(&frame).unwrap();
+            assert_eq!(alpha, [timestamp, 255]);
+
+            let meta = FrameMetadata {
+                timestamp: timestamp.into(),
+                display_resolution: size,
+                layout,
+                force_keyframe: false,
+                damage: None,
+            };
+            encoder.encode(meta, frame, alpha).unwrap();
+            coded.extend(encoder.poll().unwrap());
+        }
+        encoder.drain().unwrap();
+        while let Some(frame) = encoder.poll().unwrap() {
+            coded.push(frame);
+        }
+
+        let coded = coded
+            .iter()
+            .map(|frame| (frame.color.bitstream[0], frame.alpha.bitstream[0]))
+            .collect::<Vec<_>>();
+        assert_eq!(coded, [(255, 0), (255, 1), (255, 2)]);
+    }
+}
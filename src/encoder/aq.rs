@@ -0,0 +1,241 @@
+// Copyright 2024 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Variance-based adaptive quantization.
+//!
+//! Quantization artifacts are much more visible in flat areas, e.g. as banding in a sky, than in
+//! detailed ones where texture masks them. Adaptive quantization measures the variance of the
+//! luma of each macroblock and lowers the QP of the flat macroblocks while raising the QP of the
+//! detailed ones, moving bits to where they matter without changing the average QP of frames.
+
+use crate::codec::h264::parser::Rect;
+use crate::Resolution;
+
+/// Size of the side of the square macroblocks the QP is adapted for.
+pub const MB_SIZE: usize = 16;
+
+/// QP offset of the macroblocks outside of the damaged regions of frames, see
+/// [`QpMap::bias_damage`].
+pub const UNDAMAGED_QP_DELTA: i8 = 6;
+
+/// Configuration of the adaptive quantization.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct AdaptiveQuantization {
+    /// QP offset applied per doubling of the variance of a macroblock relative to the average of
+    /// the frame.
+    pub strength: f32,
+    /// Maximum absolute QP offset of a macroblock.
+    pub max_delta: u8,
+}
+
+impl Default for AdaptiveQuantization {
+    fn default() -> Self {
+        Self {
+            strength: 1.0,
+            max_delta: 6,
+        }
+    }
+}
+
+/// QP offsets of the macroblocks of a frame, in raster order.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct QpMap {
+    pub width_in_mbs: usize,
+    pub height_in_mbs: usize,
+    pub deltas: Vec<i8>,
+}
+
+impl QpMap {
+    /// Returns a map of `width_in_mbs` by `height_in_mbs` macroblocks without QP offsets.
+    pub fn zero(width_in_mbs: usize, height_in_mbs: usize) -> Self {
+        Self {
+            width_in_mbs,
+            height_in_mbs,
+            deltas: vec![0; width_in_mbs * height_in_mbs],
+        }
+    }
+
+    /// Computes the QP offsets of the `width_in_mbs` by `height_in_mbs` macroblocks of the frame
+    /// of `size` with the `luma` plane of `stride`. Macroblocks outside of the frame, i.e. the
+    /// padding of frames which size is not a multiple of the macroblock size, are not offset.
+    pub fn from_luma(
+        aq: &AdaptiveQuantization,
+        size: Resolution,
+        luma: &[u8],
+        stride: usize,
+        width_in_mbs: usize,
+        height_in_mbs: usize,
+    ) -> Self {
+        let (width, height) = (size.width as usize, size.height as usize);
+
+        // log2 of the variance of each macroblock, if it has any sample in the frame.
+        let energies = (0..width_in_mbs * height_in_mbs)
+            .map(|mb| {
+                let (x0, y0) = ((mb % width_in_mbs) * MB_SIZE, (mb / width_in_mbs) * MB_SIZE);
+                let (x1, y1) = ((x0 + MB_SIZE).min(width), (y0 + MB_SIZE).min(height));
+                if x0 >= x1 || y0 >= y1 {
+                    return None;
+                }
+
+                let (mut sum, mut sum_sq) = (0u64, 0u64);
+                for row in luma[y0 * stride..].chunks(stride).take(y1 - y0) {
+                    for &sample in &row[x0..x1] {
+                        sum += u64::from(sample);
+                        sum_sq += u64::from(sample) * u64::from(sample);
+                    }
+                }
+
+                let count = ((x1 - x0) * (y1 - y0)) as f64;
+                let variance = sum_sq as f64 / count - (sum as f64 / count).powi(2);
+                Some((variance.max(0.0) + 1.0).log2())
+            })
+            .collect::<Vec<_>>();
+
+        let (total, count) = energies
+            .iter()
+            .flatten()
+            .fold((0.0, 0), |(total, count), energy| {
+                (total + energy, count + 1)
+            });
+        let mean = if count > 0 { total / count as f64 } else { 0.0 };
+
+        let max_delta = f64::from(aq.max_delta.min(i8::MAX as u8));
+        let deltas = energies
+            .into_iter()
+            .map(|energy| match energy {
+                Some(energy) => {
+                    let delta = f64::from(aq.strength) * (energy - mean);
+                    delta.round().clamp(-max_delta, max_delta) as i8
+                }
+                None => 0,
+            })
+            .collect();
+
+        Self {
+            width_in_mbs,
+            height_in_mbs,
+            deltas,
+        }
+    }
+
+    /// Raises the QP of the macroblocks outside of the `damage` regions by
+    /// [`UNDAMAGED_QP_DELTA`]. These macroblocks are identical to the ones of the previous frame,
+    /// so they are predicted perfectly and need no residual.
+    pub fn bias_damage(&mut self, damage: &[Rect<u32>]) {
+        let mb_size = MB_SIZE as u32;
+        for (mb, delta) in self.deltas.iter_mut().enumerate() {
+            let x = (mb % self.width_in_mbs) as u32 * mb_size;
+            let y = (mb / self.width_in_mbs) as u32 * mb_size;
+            let damaged = damage.iter().any(|rect| {
+                rect.min.x < x + mb_size
+                    && x < rect.max.x
+                    && rect.min.y < y + mb_size
+                    && y < rect.max.y
+            });
+
+            if !damaged {
+                *delta = delta.saturating_add(UNDAMAGED_QP_DELTA);
+            }
+        }
+    }
+
+    /// Returns the QP of each macroblock, in raster order, offset from the `slice_qp` and clamped
+    /// to the range of H.264.
+    pub fn qps(&self, slice_qp: i32) -> Vec<u8> {
+        self.deltas
+            .iter()
+            .map(|&delta| (slice_qp + i32::from(delta)).clamp(0, 51) as u8)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec::h264::parser::Point;
+
+    /// Returns a 32x16 luma plane, flat on the left and noisy on the right.
+    fn half_flat_luma() -> Vec<u8> {
+        (0..16)
+            .flat_map(|y| {
+                (0..32).map(move |x| {
+                    if x < 16 {
+                        128
+                    } else {
+                        ((x * 37 + y * 91) % 256) as u8
+                    }
+                })
+            })
+            .collect()
+    }
+
+    #[test]
+    fn flat_frame() {
+        let luma = vec![100; 32 * 32];
+        let map = QpMap::from_luma(
+            &Default::default(),
+            Resolution::from((32, 32)),
+            &luma,
+            32,
+            2,
+            2,
+        );
+
+        assert_eq!(map.deltas, [0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn flat_and_detailed() {
+        let luma = half_flat_luma();
+        let map = QpMap::from_luma(
+            &Default::default(),
+            Resolution::from((32, 16)),
+            &luma,
+            32,
+            2,
+            1,
+        );
+
+        // The flat macroblock gets the lower QP, both clamped to the maximum offset.
+        assert_eq!(map.deltas, [-6, 6]);
+        assert_eq!(map.qps(26), [20, 32]);
+
+        let aq = AdaptiveQuantization {
+            strength: 0.5,
+            max_delta: 2,
+        };
+        let map = QpMap::from_luma(&aq, Resolution::from((32, 16)), &luma, 32, 2, 1);
+        assert_eq!(map.deltas, [-2, 2]);
+    }
+
+    #[test]
+    fn padding_macroblocks() {
+        let luma = half_flat_luma();
+        // The frame is 32x16 but coded as 3x2 macroblocks.
+        let map = QpMap::from_luma(
+            &Default::default(),
+            Resolution::from((32, 16)),
+            &luma,
+            32,
+            3,
+            2,
+        );
+
+        assert_eq!(map.deltas, [-6, 6, 0, 0, 0, 0]);
+        assert_eq!(map.qps(3), [0, 9, 3, 3, 3, 3]);
+    }
+
+    #[test]
+    fn damage() {
+        let mut map = QpMap::zero(3, 2);
+        let damage = [Rect {
+            min: Point { x: 20, y: 4 },
+            max: Point { x: 33, y: 10 },
+        }];
+        map.bias_damage(&damage);
+
+        assert_eq!(map.deltas, [6, 0, 0, 6, 6, 6]);
+        assert_eq!(map.qps(48), [51, 48, 48, 51, 51, 51]);
+    }
+}
@@ -0,0 +1,166 @@
+// Copyright 2024 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Post-processing of the coded buffers before they are output by the encoder.
+//!
+//! A [`BitstreamFilter`] rewrites each [`CodedBitstreamBuffer`], e.g. to strip or insert NAL
+//! units for a transport, or to change its framing for a container. Filters are chained with
+//! [`StatelessEncoder::add_bitstream_filter`], and run in the order they were added on the thread
+//! polling the encoder.
+//!
+//! [`StatelessEncoder::add_bitstream_filter`]:
+//!     crate::encoder::stateless::h264::StatelessEncoder::add_bitstream_filter
+
+use crate::codec::h264::nalu::annexb_to_length_prefixed;
+use crate::codec::h264::parser::NaluType;
+use crate::encoder::units::BitstreamUnit;
+use crate::encoder::units::UnitType;
+use crate::encoder::CodedBitstreamBuffer;
+
+/// A post-processing step of the coded buffers. Skipped frames, whose bitstream is empty, go
+/// through the filters too.
+pub type BitstreamFilter = Box<dyn FnMut(&mut CodedBitstreamBuffer) -> anyhow::Result<()>>;
+
+/// Access unit delimiter NAL unit allowing any slice type, with its start code.
+const H264_ACCESS_UNIT_DELIMITER: [u8; 6] = [0x00, 0x00, 0x00, 0x01, 0x09, 0xf0];
+
+/// Returns a filter removing the H.264 NAL units of `types` from the Annex B bitstream, e.g. the
+/// SEI messages for receivers which do not want them.
+pub fn strip_h264_nal_units(types: Vec<NaluType>) -> BitstreamFilter {
+    Box::new(move |buffer| {
+        let stripped = |unit: &BitstreamUnit| matches!(unit.unit_type, Some(UnitType::H264(type_)) if types.contains(&type_));
+        if !buffer.h264_nal_units().any(|unit| stripped(&unit)) {
+            return Ok(());
+        }
+
+        let mut bitstream = Vec::with_capacity(buffer.bitstream.len());
+        for unit in buffer.h264_nal_units() {
+            if !stripped(&unit) {
+                bitstream.extend_from_slice(&[0x00, 0x00, 0x00, 0x01]);
+                bitstream.extend_from_slice(unit.data);
+            }
+        }
+        buffer.bitstream = bitstream.into();
+
+        Ok(())
+    })
+}
+
+/// Returns a filter starting each H.264 access unit with an access unit delimiter, as required by
+/// MPEG-TS and some hardware decoders. Access units already starting with one are left as-is.
+pub fn insert_h264_access_unit_delimiter() -> BitstreamFilter {
+    Box::new(|buffer| {
+        let Some(first) = buffer.h264_nal_units().next() else {
+            return Ok(());
+        };
+        if first.unit_type == Some(UnitType::H264(NaluType::AuDelimiter)) {
+            return Ok(());
+        }
+
+        let mut bitstream =
+            Vec::with_capacity(H264_ACCESS_UNIT_DELIMITER.len() + buffer.bitstream.len());
+        bitstream.extend_from_slice(&H264_ACCESS_UNIT_DELIMITER);
+        bitstream.extend_from_slice(&buffer.bitstream);
+        buffer.bitstream = bitstream.into();
+
+        Ok(())
+    })
+}
+
+/// Returns a filter converting the H.264 or H.265 Annex B bitstream into the length-prefixed
+/// format of the `avcC` and `hvcC` sample entries, with each NAL unit preceded by its size in
+/// `length_size` big-endian bytes.
+pub fn length_prefixed(length_size: usize) -> BitstreamFilter {
+    Box::new(move |buffer| {
+        buffer.bitstream = annexb_to_length_prefixed(&buffer.bitstream, length_size)?.into();
+
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec::h264::nalu::split_annexb;
+    use crate::encoder::FrameMetadata;
+    use crate::FrameLayout;
+    use crate::Resolution;
+
+    const ACCESS_UNIT: [u8; 19] = [
+        0x00, 0x00, 0x00, 0x01, 0x67, 0x42, 0x1e, // SPS
+        0x00, 0x00, 0x00, 0x01, 0x06, 0x05, 0x80, // SEI
+        0x00, 0x00, 0x01, 0x65, 0x88, // IDR slice
+    ];
+
+    fn coded_buffer(bitstream: &[u8]) -> CodedBitstreamBuffer {
+        let metadata = FrameMetadata {
+            timestamp: 0,
+            display_resolution: Resolution::default(),
+            layout: FrameLayout {
+                format: (b"NV12".into(), 0),
+                size: Resolution::default(),
+                planes: vec![],
+            },
+            force_keyframe: false,
+            damage: None,
+        };
+
+        CodedBitstreamBuffer::new(metadata, bitstream.to_vec())
+    }
+
+    #[test]
+    fn strip_nal_units() {
+        let mut filter = strip_h264_nal_units(vec![NaluType::Sei]);
+
+        let mut buffer = coded_buffer(&ACCESS_UNIT);
+        filter(&mut buffer).unwrap();
+        assert_eq!(
+            split_annexb(&buffer.bitstream),
+            [vec![0x67, 0x42, 0x1e], vec![0x65, 0x88]]
+        );
+
+        // Buffers without the NAL units are not rewritten.
+        let bitstream = buffer.bitstream.clone();
+        filter(&mut buffer).unwrap();
+        assert_eq!(buffer.bitstream.as_ptr(), bitstream.as_ptr());
+    }
+
+    #[test]
+    fn insert_access_unit_delimiter() {
+        let mut filter = insert_h264_access_unit_delimiter();
+
+        let mut buffer = coded_buffer(&ACCESS_UNIT);
+        filter(&mut buffer).unwrap();
+        assert_eq!(buffer.bitstream[..6], H264_ACCESS_UNIT_DELIMITER);
+        assert_eq!(buffer.bitstream[6..], ACCESS_UNIT);
+
+        // The delimiter is inserted once.
+        filter(&mut buffer).unwrap();
+        assert_eq!(buffer.bitstream.len(), ACCESS_UNIT.len() + 6);
+
+        // Skipped frames have no access unit.
+        let mut skipped = coded_buffer(&[]);
+        filter(&mut skipped).unwrap();
+        assert!(skipped.bitstream.is_empty());
+    }
+
+    #[test]
+    fn length_prefixed_framing() {
+        let mut filter = length_prefixed(4);
+
+        let mut buffer = coded_buffer(&ACCESS_UNIT);
+        filter(&mut buffer).unwrap();
+        assert_eq!(
+            buffer.bitstream,
+            [
+                vec![0x00, 0x00, 0x00, 0x03, 0x67, 0x42, 0x1e],
+                vec![0x00, 0x00, 0x00, 0x03, 0x06, 0x05, 0x80],
+                vec![0x00, 0x00, 0x00, 0x02, 0x65, 0x88],
+            ]
+            .concat()
+        );
+
+        assert!(length_prefixed(5)(&mut buffer).is_err());
+    }
+}
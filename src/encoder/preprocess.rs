@@ -0,0 +1,373 @@
+// Copyright 2024 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Filters applied to the input frames of an encoder before they are encoded.
+//!
+//! Noisy input, e.g. from cheap camera sensors, costs a lot of bits to encode. Smoothing it out
+//! before encoding materially improves the quality of low bitrate streams. Interlaced input can
+//! also be deinterlaced, as the encoders only produce progressive streams, and frames from mobile
+//! cameras rotated according to the orientation of the device. Finally, an image such as a logo
+//! or a timestamp can be overlaid on all frames.
+//!
+//! The deinterlacing, denoise, sharpen and color balance filters are applied by the video
+//! processor of the backend, and the overlay, mirroring and rotation on the CPU.
+
+use crate::encoder::bt601_chroma;
+use crate::encoder::bt601_luma;
+use crate::Resolution;
+
+/// Adjustment of the brightness, contrast and saturation of frames.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ColorBalance {
+    /// Offset added to the luma samples.
+    pub brightness: i16,
+    /// Factor applied to the luma samples around black.
+    pub contrast: f32,
+    /// Factor applied to the chroma samples around grey.
+    pub saturation: f32,
+}
+
+impl Default for ColorBalance {
+    fn default() -> Self {
+        Self {
+            brightness: 0,
+            contrast: 1.0,
+            saturation: 1.0,
+        }
+    }
+}
+
+/// Deinterlacing method, keeping the top field of the frames and rebuilding the bottom one.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Deinterlace {
+    /// Interpolate all the lines of the bottom field from the top field.
+    Bob,
+    /// Only interpolate the samples of the bottom field that moved, and keep the others as is.
+    MotionAdaptive,
+}
+
+/// Image blended over the frames, e.g. a logo or a timestamp.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Overlay {
+    pub size: Resolution,
+    /// Tightly packed pixels of the image in BGRA byte order, i.e. ARGB8888 little endian words,
+    /// with non-premultiplied alpha.
+    pub data: Vec<u8>,
+    /// Position of the top-left corner of the image in the frames. The parts of the image
+    /// outside of the frames are not blended.
+    pub position: (u32, u32),
+}
+
+/// Clockwise rotation of frames.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Rotation {
+    Rotate90,
+    Rotate180,
+    Rotate270,
+}
+
+/// Preprocessing stage of an encoder. All filters are disabled by default.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Preprocessing {
+    /// Deinterlacing of interlaced input, applied before the other filters.
+    pub deinterlace: Option<Deinterlace>,
+    /// Strength of the denoise filter, from 0 to 100 of the range of the video processor.
+    pub denoise: Option<u8>,
+    /// Strength of the sharpen filter, from 0 to 100 of the range of the video processor.
+    pub sharpen: Option<u8>,
+    pub color_balance: Option<ColorBalance>,
+    /// Image blended over the frames, after the other filters.
+    pub overlay: Option<Overlay>,
+    /// Horizontal flip of the frames, applied before their rotation.
+    pub mirror: bool,
+    /// Rotation of the frames. Rotations by 90 and 270 degrees swap the width and height of the
+    /// frames, so the encoder and the metadata of the frames must use the rotated size.
+    pub rotation: Option<Rotation>,
+}
+
+impl Preprocessing {
+    /// Returns whether any filter is enabled.
+    pub fn is_enabled(&self) -> bool {
+        *self != Default::default()
+    }
+
+    /// Returns whether any filter other than the mirroring and rotation is enabled.
+    pub fn has_filters(&self) -> bool {
+        Self {
+            mirror: false,
+            rotation: None,
+            ..self.clone()
+        }
+        .is_enabled()
+    }
+
+    /// Returns the filters applied by the video processor of the backend, i.e. all but the
+    /// overlay, mirroring and rotation.
+    pub fn video_processing(&self) -> Self {
+        Self {
+            overlay: None,
+            mirror: false,
+            rotation: None,
+            ..self.clone()
+        }
+    }
+
+    /// Returns whether any filter applied by the video processor of the backend is enabled.
+    pub fn has_video_processing(&self) -> bool {
+        self.video_processing().is_enabled()
+    }
+
+    /// Returns whether the frames are mirrored or rotated, which changes their layout unlike the
+    /// other filters.
+    pub fn has_transform(&self) -> bool {
+        self.mirror || self.rotation.is_some()
+    }
+
+    /// Returns the size of frames of `size` once rotated.
+    pub fn output_size(&self, size: Resolution) -> Resolution {
+        match self.rotation {
+            Some(Rotation::Rotate90) | Some(Rotation::Rotate270) => Resolution {
+                width: size.height,
+                height: size.width,
+            },
+            _ => size,
+        }
+    }
+
+    /// Mirrors and rotates the plane made of `rows` of `width` elements of `element_size` bytes,
+    /// and returns it tightly packed.
+    pub fn transform_plane(&self, rows: &[&[u8]], width: usize, element_size: usize) -> Vec<u8> {
+        let height = rows.len();
+        let output = self.output_size(Resolution::from((width as u32, height as u32)));
+        let mut transformed = Vec::with_capacity(width * height * element_size);
+
+        for out_y in 0..output.height as usize {
+            for out_x in 0..output.width as usize {
+                let (x, y) = match self.rotation {
+                    None => (out_x, out_y),
+                    Some(Rotation::Rotate90) => (out_y, height - 1 - out_x),
+                    Some(Rotation::Rotate180) => (width - 1 - out_x, height - 1 - out_y),
+                    Some(Rotation::Rotate270) => (width - 1 - out_y, out_x),
+                };
+                let x = if self.mirror { width - 1 - x } else { x };
+                let element = &rows[y][x * element_size..(x + 1) * element_size];
+                transformed.extend_from_slice(element);
+            }
+        }
+
+        transformed
+    }
+}
+
+impl Overlay {
+    /// Checks that the image is not empty and that its data holds exactly its pixels.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        let len = (self.size.width as usize)
+            .checked_mul(self.size.height as usize)
+            .and_then(|pixels| pixels.checked_mul(4));
+        anyhow::ensure!(
+            self.size.width > 0 && self.size.height > 0,
+            "empty {}x{} overlay",
+            self.size.width,
+            self.size.height
+        );
+        anyhow::ensure!(
+            len == Some(self.data.len()),
+            "overlay of {}x{} pixels has {} bytes of data",
+            self.size.width,
+            self.size.height,
+            self.data.len()
+        );
+
+        Ok(())
+    }
+
+    /// Blends the image over a NV12 frame of `size` whose planes are `luma` and `chroma`, with
+    /// rows `luma_stride` and `chroma_stride` bytes apart. The image must be valid, see
+    /// [`Overlay::validate`].
+    pub fn blend_nv12(
+        &self,
+        size: Resolution,
+        luma: &mut [u8],
+        luma_stride: usize,
+        chroma: &mut [u8],
+        chroma_stride: usize,
+    ) {
+        let (left, top) = (self.position.0 as usize, self.position.1 as usize);
+        let right = (left + self.size.width as usize).min(size.width as usize);
+        let bottom = (top + self.size.height as usize).min(size.height as usize);
+        if right <= left || bottom <= top {
+            return;
+        }
+        // Returns the BGRA pixel of the overlay at position `(x, y)` of the frame, if any.
+        let pixel = |x: usize, y: usize| {
+            let (x, y) = (x.checked_sub(left)?, y.checked_sub(top)?);
+            (x < right - left && y < bottom - top).then(|| {
+                let offset = (y * self.size.width as usize + x) * 4;
+                <[u8; 4]>::try_from(&self.data[offset..offset + 4])
+                    .unwrap()
+                    .map(i32::from)
+            })
+        };
+        let blend = |sample: &mut u8, value: i32, alpha: i32| {
+            *sample = ((value * alpha + i32::from(*sample) * (255 - alpha) + 127) / 255) as u8;
+        };
+
+        for y in top..bottom {
+            for x in left..right {
+                let bgra = pixel(x, y).unwrap();
+                let value = i32::from(bt601_luma(bgra[2], bgra[1], bgra[0]));
+                blend(&mut luma[y * luma_stride + x], value, bgra[3]);
+            }
+        }
+
+        // Each chroma sample is blended with the average of the overlay pixels of its 2x2 block,
+        // the pixels outside of the overlay being transparent.
+        for y in (top / 2)..bottom.div_ceil(2) {
+            for x in (left / 2)..right.div_ceil(2) {
+                let mut sum = [0i32; 4];
+                let mut count = 0;
+                for (px, py) in [(0, 0), (1, 0), (0, 1), (1, 1)] {
+                    if let Some(bgra) = pixel(2 * x + px, 2 * y + py) {
+                        for (sum, c) in sum.iter_mut().zip(bgra) {
+                            *sum += c;
+                        }
+                        count += 1;
+                    }
+                }
+                let [b, g, r, _] = sum.map(|c| c / count);
+                let (u, v) = bt601_chroma(r, g, b);
+                let uv = &mut chroma[y * chroma_stride + 2 * x..];
+                blend(&mut uv[0], i32::from(u), sum[3] / 4);
+                blend(&mut uv[1], i32::from(v), sum[3] / 4);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn video_processing() {
+        assert!(!Preprocessing::default().is_enabled());
+
+        let denoise = Preprocessing {
+            denoise: Some(100),
+            ..Default::default()
+        };
+        assert!(denoise.is_enabled() && denoise.has_filters() && !denoise.has_transform());
+        assert!(denoise.has_video_processing());
+        assert!(Preprocessing {
+            deinterlace: Some(Deinterlace::MotionAdaptive),
+            ..Default::default()
+        }
+        .has_video_processing());
+
+        // The overlay and the transforms are applied on the CPU.
+        let overlay = Preprocessing {
+            overlay: Some(Overlay {
+                size: Resolution::from((1, 1)),
+                data: vec![255; 4],
+                position: (0, 0),
+            }),
+            mirror: true,
+            rotation: Some(Rotation::Rotate90),
+            ..denoise.clone()
+        };
+        assert!(overlay.has_filters() && overlay.has_transform());
+        assert_eq!(overlay.video_processing(), denoise);
+        assert!(!Preprocessing {
+            denoise: None,
+            ..overlay
+        }
+        .has_video_processing());
+    }
+
+    #[test]
+    fn transform_plane() {
+        // 3x2 plane of 2 bytes elements.
+        let rows: [&[u8]; 2] = [&[1, 1, 2, 2, 3, 3], &[4, 4, 5, 5, 6, 6]];
+        let transform = |mirror, rotation| {
+            let preprocessing = Preprocessing {
+                mirror,
+                rotation,
+                ..Default::default()
+            };
+            assert!(!preprocessing.has_filters());
+            preprocessing
+                .transform_plane(&rows, 3, 2)
+                .chunks(2)
+                .map(|element| element[0])
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(transform(false, None), [1, 2, 3, 4, 5, 6]);
+        assert_eq!(transform(true, None), [3, 2, 1, 6, 5, 4]);
+        assert_eq!(
+            transform(false, Some(Rotation::Rotate90)),
+            [4, 1, 5, 2, 6, 3]
+        );
+        assert_eq!(
+            transform(false, Some(Rotation::Rotate180)),
+            [6, 5, 4, 3, 2, 1]
+        );
+        assert_eq!(
+            transform(false, Some(Rotation::Rotate270)),
+            [3, 6, 2, 5, 1, 4]
+        );
+        assert_eq!(
+            transform(true, Some(Rotation::Rotate90)),
+            [6, 3, 5, 2, 4, 1]
+        );
+    }
+
+    #[test]
+    fn overlay() {
+        // White opaque 2x2 image, whose right half is outside of the 2x2 frame.
+        let overlay = Overlay {
+            size: Resolution::from((2, 2)),
+            data: vec![255; 16],
+            position: (1, 0),
+        };
+        let (mut luma, mut chroma) = ([16; 4], [28, 228]);
+        overlay.blend_nv12(Resolution::from((2, 2)), &mut luma, 2, &mut chroma, 2);
+
+        assert_eq!(luma, [16, 235, 16, 235]);
+        // Half of the chroma block is covered by grey.
+        assert_eq!(chroma, [78, 178]);
+
+        // Images outside of the frame are not blended.
+        let outside = Overlay {
+            position: (3, 1),
+            ..overlay
+        };
+        outside.blend_nv12(Resolution::from((2, 2)), &mut luma, 2, &mut chroma, 2);
+        assert_eq!((luma, chroma), ([16, 235, 16, 235], [78, 178]));
+    }
+
+    #[test]
+    fn validate_overlay() {
+        let overlay = Overlay {
+            size: Resolution::from((2, 2)),
+            data: vec![255; 16],
+            position: (1, 0),
+        };
+        assert!(overlay.validate().is_ok());
+
+        let short = Overlay {
+            data: vec![255; 12],
+            ..overlay.clone()
+        };
+        assert!(short.validate().is_err());
+
+        let empty = Overlay {
+            size: Resolution::from((0, 2)),
+            data: vec![],
+            ..overlay
+        };
+        assert!(empty.validate().is_err());
+    }
+}
@@ -2,14 +2,19 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file.
 
+use std::cell::RefCell;
 use std::collections::VecDeque;
+use std::sync::mpsc;
+use std::thread::JoinHandle;
 
+use anyhow::anyhow;
 use thiserror::Error;
 
 use crate::codec::h264::synthesizer::SynthesizerError;
 use crate::encoder::CodedBitstreamBuffer;
 use crate::encoder::FrameMetadata;
 use crate::BlockingMode;
+use crate::FrameLayout;
 
 pub mod h264;
 
@@ -31,6 +36,24 @@ pub type StatelessBackendResult<T> = Result<T, StatelessBackendError>;
 pub enum EncodeError {
     #[error("invalid internal state. This is likely a bug.")]
     InvalidInternalState,
+    #[error("the encoder cannot accept more frames until its output is polled")]
+    WouldBlock,
+    #[error("the encoder is paused")]
+    Paused,
+    #[error("long-term reference {0} is not configured")]
+    InvalidLongTermReference(u8),
+    #[error("timestamp {timestamp} does not follow the previous timestamp {previous}")]
+    NonMonotonicTimestamp { timestamp: u64, previous: u64 },
+    #[error("the parameter sets do not match the configuration: {0}")]
+    InvalidParameterSets(String),
+    #[error("invalid resolution {width}x{height}")]
+    InvalidResolution { width: u32, height: u32 },
+    #[error("invalid prediction structure: {0}")]
+    InvalidPredictionStructure(String),
+    #[error("inconsistent predictor state: {0}")]
+    InvalidPredictorState(String),
+    #[error("the snapshot does not match the encoder: {0}")]
+    InvalidSnapshot(String),
     #[error(transparent)]
     BackendError(#[from] StatelessBackendError),
     #[error(transparent)]
@@ -39,6 +62,50 @@ pub enum EncodeError {
 
 pub type EncodeResult<T> = Result<T, EncodeError>;
 
+/// Error returned by [`StatelessVideoEncoder::try_encode`].
+#[derive(Error)]
+pub enum TryEncodeError<H> {
+    /// The encoder cannot accept more frames until its output is polled. The frame is handed
+    /// back, so that the caller can submit it again.
+    #[error("the encoder cannot accept more frames until its output is polled")]
+    WouldBlock(FrameMetadata, H),
+    #[error(transparent)]
+    Error(#[from] EncodeError),
+}
+
+impl<H> std::fmt::Debug for TryEncodeError<H> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TryEncodeError::WouldBlock(meta, _) => f
+                .debug_struct("WouldBlock")
+                .field("timestamp", &meta.timestamp)
+                .finish(),
+            TryEncodeError::Error(error) => f.debug_tuple("Error").field(error).finish(),
+        }
+    }
+}
+
+impl<H> From<TryEncodeError<H>> for EncodeError {
+    fn from(error: TryEncodeError<H>) -> Self {
+        match error {
+            TryEncodeError::WouldBlock(..) => EncodeError::WouldBlock,
+            TryEncodeError::Error(error) => error,
+        }
+    }
+}
+
+/// Error returned by [`StatelessVideoEncoder::encode_batch`].
+#[derive(Error, Debug)]
+#[error("{error} after accepting {accepted} frames of the batch")]
+pub struct BatchEncodeError {
+    /// Number of frames at the start of the batch taken by the encoder. The frames following them
+    /// are dropped.
+    pub accepted: usize,
+    /// Error that stopped the batch.
+    #[source]
+    pub error: EncodeError,
+}
+
 /// Trait for representing pending encoder output.
 pub trait BackendPromise {
     type Output;
@@ -70,6 +137,18 @@ impl<T> BackendPromise for ReadyPromise<T> {
     }
 }
 
+/// Entry of an [`OutputQueue`].
+enum Pending<O>
+where
+    O: BackendPromise,
+{
+    /// Promise still being processed
+    Promise(O),
+
+    /// Result of a promise synced ahead of the older ones
+    Completed(StatelessBackendResult<O::Output>),
+}
+
 /// Internal structure representing all current processing represented using promises and allowing
 /// polling for finished promises.
 pub(crate) struct OutputQueue<O>
@@ -79,8 +158,8 @@ where
     /// True if the every single polling call shall be blocking
     blocking: BlockingMode,
 
-    /// Queue of currently pending [`BackendPromise`]
-    promises: VecDeque<O>,
+    /// Queue of currently pending [`BackendPromise`], in submission order
+    promises: VecDeque<Pending<O>>,
 }
 
 impl<O> OutputQueue<O>
@@ -96,7 +175,30 @@ where
 
     /// Add new pending job to the queue. Which will be returned to client if it is done.
     pub(crate) fn add_promise(&mut self, pending: O) {
-        self.promises.push_back(pending);
+        self.promises.push_back(Pending::Promise(pending));
+    }
+
+    /// Syncs every [`BackendPromise`] done processing, so that one slow promise does not delay the
+    /// completion of the following ones. Their results are held until returned.
+    fn sync_ready(&mut self) {
+        for pending in self.promises.iter_mut() {
+            if matches!(pending, Pending::Promise(o) if o.is_ready()) {
+                // The placeholder is replaced by the result right away.
+                let placeholder = Pending::Completed(Err(StatelessBackendError::OutOfResources));
+                if let Pending::Promise(o) = std::mem::replace(pending, placeholder) {
+                    *pending = Pending::Completed(o.sync());
+                }
+            }
+        }
+    }
+
+    /// Returns the result of the [`BackendPromise`] at `index`, blocking till it is finished.
+    fn take(&mut self, index: usize) -> StatelessBackendResult<Option<O::Output>> {
+        match self.promises.remove(index) {
+            Some(Pending::Promise(o)) => Ok(Some(o.sync()?)),
+            Some(Pending::Completed(result)) => Ok(Some(result?)),
+            None => Ok(None),
+        }
     }
 
     /// Returns the result of an oldest [`BackendPromise`] if it is done processing. If `force_block`
@@ -105,22 +207,139 @@ where
     pub(crate) fn poll(&mut self, mode: BlockingMode) -> StatelessBackendResult<Option<O::Output>> {
         let block = self.blocking == BlockingMode::Blocking || mode == BlockingMode::Blocking;
 
-        match self.promises.pop_front() {
-            Some(o) if block || o.is_ready() => Ok(Some(o.sync()?)),
-            Some(o) => {
-                self.promises.push_front(o);
-                Ok(None)
-            }
-            None => Ok(None),
+        self.sync_ready();
+        match self.promises.front() {
+            Some(Pending::Promise(_)) if !block => Ok(None),
+            _ => self.take(0),
+        }
+    }
+
+    /// Returns the result of the oldest [`BackendPromise`] done processing, regardless of the
+    /// older ones, for the results which do not need to be returned in submission order. Blocks
+    /// as [`OutputQueue::poll`] if none is done.
+    pub(crate) fn poll_completed(
+        &mut self,
+        mode: BlockingMode,
+    ) -> StatelessBackendResult<Option<O::Output>> {
+        self.sync_ready();
+        match self
+            .promises
+            .iter()
+            .position(|pending| matches!(pending, Pending::Completed(_)))
+        {
+            Some(index) => self.take(index),
+            None => self.poll(mode),
         }
     }
 
+    /// Returns the number of pending [`BackendPromise`].
+    pub(crate) fn len(&self) -> usize {
+        self.promises.len()
+    }
+
     /// Returns true if queue is empty ie. no [`BackendPromise`] is pending.
     pub(crate) fn is_empty(&self) -> bool {
         self.promises.is_empty()
     }
 }
 
+/// Thread running the jobs submitted with [`WorkerPromise::new`] in submission order, to offload
+/// CPU work from the caller's thread.
+pub(crate) struct Worker {
+    jobs: Option<mpsc::Sender<Box<dyn FnOnce() + Send>>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Worker {
+    pub(crate) fn new(name: &str) -> std::io::Result<Self> {
+        let (jobs, receiver) = mpsc::channel::<Box<dyn FnOnce() + Send>>();
+        let thread = std::thread::Builder::new()
+            .name(name.into())
+            .spawn(move || receiver.into_iter().for_each(|job| job()))?;
+
+        Ok(Self {
+            jobs: Some(jobs),
+            thread: Some(thread),
+        })
+    }
+}
+
+impl Drop for Worker {
+    fn drop(&mut self) {
+        // Closing the channel stops the thread once the pending jobs are done.
+        self.jobs.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// [`BackendPromise`] of the result of a job run by a [`Worker`].
+pub(crate) struct WorkerPromise<T> {
+    receiver: mpsc::Receiver<T>,
+
+    /// Result received by [`BackendPromise::is_ready`]
+    result: RefCell<Option<T>>,
+}
+
+impl<T> WorkerPromise<T>
+where
+    T: Send + 'static,
+{
+    /// Runs `job` on `worker`, or on the current thread if `None`.
+    pub(crate) fn new<F>(worker: Option<&Worker>, job: F) -> Self
+    where
+        F: FnOnce() -> T + Send + 'static,
+    {
+        let (sender, receiver) = mpsc::sync_channel(1);
+        // The promise may be dropped before the job is done, e.g. when flushing.
+        let job = move || {
+            let _ = sender.send(job());
+        };
+
+        match worker.and_then(|worker| worker.jobs.as_ref()) {
+            // If the thread terminated, the job is dropped and the promise fails to sync.
+            Some(jobs) => {
+                let _ = jobs.send(Box::new(job));
+            }
+            None => job(),
+        }
+
+        Self {
+            receiver,
+            result: Default::default(),
+        }
+    }
+}
+
+impl<T> BackendPromise for WorkerPromise<T> {
+    type Output = T;
+
+    fn sync(self) -> StatelessBackendResult<Self::Output> {
+        match self.result.into_inner() {
+            Some(result) => Ok(result),
+            None => Ok(self
+                .receiver
+                .recv()
+                .map_err(|_| anyhow!("the worker thread terminated"))?),
+        }
+    }
+
+    fn is_ready(&self) -> bool {
+        let mut result = self.result.borrow_mut();
+        if result.is_none() {
+            match self.receiver.try_recv() {
+                Ok(received) => *result = Some(received),
+                Err(mpsc::TryRecvError::Empty) => return false,
+                // Syncing reports the error.
+                Err(mpsc::TryRecvError::Disconnected) => (),
+            }
+        }
+
+        true
+    }
+}
+
 /// Predictor is responsible for yielding stream parameter sets and creating requests to backend.
 /// It accepts the frames and reconstructed frames and returns [`Request`]s for execution. For
 /// example [`Predictor`] may hold frames from processing until enough is supplied to create a
@@ -137,12 +356,32 @@ pub(super) trait Predictor<Picture, Reference, Request> {
     ) -> EncodeResult<Vec<Request>>;
 
     /// This function is called by the encoder, with reconstructed frame when backend finished
-    /// processing the frame. the [`Predictor`] may choose to return [`Request`]s to submit to
-    /// backend, if reconstructed was required for creating that request.
+    /// processing the frame, or right away if the backend allocated it ahead of the submission.
+    /// the [`Predictor`] may choose to return [`Request`]s to submit to backend, if reconstructed
+    /// was required for creating that request.
     fn reconstructed(&mut self, recon: Reference) -> EncodeResult<Vec<Request>>;
 
     /// Force [`Predictor`] to pop at least one frame from internal queue and return a [`Request`]s
     fn drain(&mut self) -> EncodeResult<Vec<Request>>;
+
+    /// Aborts the encoding of the frames held by [`Predictor`] and returns them with their
+    /// metadata. The following frame starts a new sequence.
+    fn flush(&mut self) -> Vec<(Picture, FrameMetadata)>;
+
+    /// Removes the frame of `timestamp` from the frames held by [`Predictor`], if it is there.
+    fn cancel(&mut self, timestamp: u64) -> Option<(Picture, FrameMetadata)>;
+
+    /// Marks the frame of `timestamp`, passed next to [`Predictor::new_frame`], as the long-term
+    /// reference `index`.
+    fn mark_long_term(&mut self, timestamp: u64, index: u8);
+
+    /// Encodes the frame of `timestamp`, passed next to [`Predictor::new_frame`], referencing only
+    /// the long-term reference `index`.
+    fn recover(&mut self, timestamp: u64, index: u8);
+
+    /// Stops referencing the frame of `timestamp` and its descendants. Returns false if the frame
+    /// is not known, e.g. when it is not referenced by the current sequence.
+    fn invalidate(&mut self, timestamp: u64) -> bool;
 }
 
 /// Generic trait for stateless encoder backends
@@ -166,6 +405,23 @@ where
     /// Backend's specific [`BackendPromise`] for [`StatelessVideoEncoderBackend::Reconstructed`],
     /// a result of [`Request`] submission.
     type ReconPromise: BackendPromise<Output = Self::Reconstructed>;
+
+    /// Reads `picture` back into CPU memory, returning its data and layout. Used to score the
+    /// encoding quality. Backends unable to do so return `None`, which is the default.
+    fn read_picture(
+        &self,
+        _picture: &Self::Picture,
+    ) -> StatelessBackendResult<Option<(Vec<u8>, FrameLayout)>> {
+        Ok(None)
+    }
+
+    /// Same as [`StatelessVideoEncoderBackend::read_picture`], but for reconstructed pictures.
+    fn read_reconstructed(
+        &self,
+        _recon: &Self::Reconstructed,
+    ) -> StatelessBackendResult<Option<(Vec<u8>, FrameLayout)>> {
+        Ok(None)
+    }
 }
 
 pub trait StatelessEncoderBackendImport<Handle, Picture> {
@@ -177,6 +433,14 @@ pub trait StatelessEncoderBackendImport<Handle, Picture> {
         metadata: &FrameMetadata,
         handle: Handle,
     ) -> StatelessBackendResult<Picture>;
+
+    /// Returns false if the backend runs out of the resources needed to import the frame of
+    /// `metadata` until the previous ones complete, e.g. staging surfaces. The encoder checks it
+    /// before consuming the handle, so that it can hand it back to the caller. Backends which do
+    /// not hold resources for the imported frames keep the default, which is always true.
+    fn can_import(&self, _metadata: &FrameMetadata) -> bool {
+        true
+    }
 }
 
 pub trait StatelessCodec {}
@@ -188,8 +452,65 @@ pub trait StatelessVideoEncoder<H> {
     /// and yield output bitstream. It is allowed to hold frames until certain conditions are met
     /// eg. for specified prediction structures or referencing in order to further optimize
     /// the compression rate of the bitstream.
+    ///
+    /// The handle is consumed even if the frame is not accepted, e.g. with
+    /// [`EncodeError::WouldBlock`]: the frame is then dropped, and the caller moves on to the
+    /// next one. Callers which rather submit it again use [`try_encode`].
+    ///
+    /// [`try_encode`]: StatelessVideoEncoder::try_encode
     fn encode(&mut self, meta: FrameMetadata, handle: H) -> Result<(), EncodeError>;
 
+    /// Same as [`encode`], but hands the frame back with [`TryEncodeError::WouldBlock`] instead
+    /// of dropping it when the encoder cannot accept it yet. The default implementation checks
+    /// the [`capacity`] of the encoder before passing the frame to [`encode`], encoders which
+    /// can run out of resources otherwise override it.
+    ///
+    /// [`capacity`]: StatelessVideoEncoder::capacity
+    /// [`encode`]: StatelessVideoEncoder::encode
+    fn try_encode(&mut self, meta: FrameMetadata, handle: H) -> Result<(), TryEncodeError<H>> {
+        if self.capacity()? == Some(0) {
+            return Err(TryEncodeError::WouldBlock(meta, handle));
+        }
+
+        Ok(self.encode(meta, handle)?)
+    }
+
+    /// Returns the number of frames [`encode`] accepts before failing with
+    /// [`EncodeError::WouldBlock`], or `None` if the encoder does not limit it, which is the
+    /// default.
+    ///
+    /// [`encode`]: StatelessVideoEncoder::encode
+    fn capacity(&mut self) -> EncodeResult<Option<usize>> {
+        Ok(None)
+    }
+
+    /// Enqueues several frames for encoding at once, allowing the encoder to submit them to the
+    /// backend together, eg. to reduce the per-frame driver overhead of high frame rate
+    /// transcoding. The batch is rejected as a whole with [`EncodeError::WouldBlock`] if it
+    /// exceeds the [`capacity`] of the encoder. Otherwise it fails as [`encode`] does, in which
+    /// case the frames preceding the failing one are enqueued and the following ones are dropped.
+    /// The error tells how many frames were enqueued, so that the caller knows which ones to
+    /// produce again. The default implementation passes the frames to [`encode`] one by one,
+    /// encoders able to submit them together override it.
+    ///
+    /// [`capacity`]: StatelessVideoEncoder::capacity
+    /// [`encode`]: StatelessVideoEncoder::encode
+    fn encode_batch(&mut self, frames: Vec<(FrameMetadata, H)>) -> Result<(), BatchEncodeError> {
+        let rejected = |error| BatchEncodeError { accepted: 0, error };
+        if let Some(capacity) = self.capacity().map_err(rejected)? {
+            if frames.len() > capacity {
+                return Err(rejected(EncodeError::WouldBlock));
+            }
+        }
+
+        for (accepted, (meta, handle)) in frames.into_iter().enumerate() {
+            self.encode(meta, handle)
+                .map_err(|error| BatchEncodeError { accepted, error })?;
+        }
+
+        Ok(())
+    }
+
     /// Drains the encoder. This means that encoder is required to finish processing of all the
     /// frames in the internal queue and yield output bitstream by the end of the call. The output
     /// bitstream then can be polled using [`poll`] function.
@@ -209,6 +530,14 @@ pub trait StatelessVideoEncoder<H> {
     ///
     /// [`encode`]: StatelessVideoEncoder::encode
     fn poll(&mut self) -> EncodeResult<Option<CodedBitstreamBuffer>>;
+
+    /// Requests a segment point before the next frame passed to [`encode`]. The encoder
+    /// guarantees that this frame is encoded as a keyframe preceded by new parameter sets, and
+    /// flags its output with [`CodedBitstreamBuffer::segment_point`], so that adaptive streaming
+    /// packagers (eg. HLS or DASH) can cut segments deterministically.
+    ///
+    /// [`encode`]: StatelessVideoEncoder::encode
+    fn request_segment_point(&mut self);
 }
 
 pub fn simple_encode_loop<E, H, P>(
@@ -234,3 +563,73 @@ where
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    use super::*;
+
+    /// Promise of `value`, ready once `ready` is set.
+    struct TestPromise {
+        value: u32,
+        ready: Rc<Cell<bool>>,
+        synced: Rc<Cell<u32>>,
+    }
+
+    impl BackendPromise for TestPromise {
+        type Output = u32;
+
+        fn sync(self) -> StatelessBackendResult<u32> {
+            self.synced.set(self.synced.get() + 1);
+            Ok(self.value)
+        }
+
+        fn is_ready(&self) -> bool {
+            self.ready.get()
+        }
+    }
+
+    #[test]
+    fn output_queue_out_of_order_completion() {
+        let mut queue = OutputQueue::new(BlockingMode::NonBlocking);
+        let synced = Rc::new(Cell::new(0));
+        let ready: Vec<_> = (0..4).map(|_| Rc::new(Cell::new(false))).collect();
+        for (value, ready) in ready.iter().enumerate() {
+            queue.add_promise(TestPromise {
+                value: value as u32,
+                ready: ready.clone(),
+                synced: synced.clone(),
+            });
+        }
+
+        // The completed promises are synced even when an older one is still processing.
+        ready[1].set(true);
+        ready[2].set(true);
+        assert_eq!(queue.poll(BlockingMode::NonBlocking).unwrap(), None);
+        assert_eq!(synced.get(), 2);
+
+        assert_eq!(
+            queue.poll_completed(BlockingMode::NonBlocking).unwrap(),
+            Some(1)
+        );
+        assert_eq!(queue.len(), 3);
+
+        // The results are otherwise returned in submission order.
+        ready[0].set(true);
+        assert_eq!(queue.poll(BlockingMode::NonBlocking).unwrap(), Some(0));
+        assert_eq!(queue.poll(BlockingMode::NonBlocking).unwrap(), Some(2));
+        assert_eq!(queue.poll(BlockingMode::NonBlocking).unwrap(), None);
+        assert_eq!(
+            queue.poll_completed(BlockingMode::NonBlocking).unwrap(),
+            None
+        );
+        assert_eq!(
+            queue.poll_completed(BlockingMode::Blocking).unwrap(),
+            Some(3)
+        );
+        assert!(queue.is_empty());
+        assert_eq!(synced.get(), 4);
+    }
+}
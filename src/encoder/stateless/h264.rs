@@ -2,33 +2,78 @@
 // Use of this source code is governed by a BSD-style license that can be
 // found in the LICENSE file.
 
+use std::collections::BTreeMap;
 use std::collections::VecDeque;
+use std::io::Cursor;
 use std::rc::Rc;
+use std::time::SystemTime;
+
+use anyhow::anyhow;
+use bytes::BufMut;
 
 use crate::codec::h264::parser::Level;
+use crate::codec::h264::parser::Nalu;
+use crate::codec::h264::parser::NaluType;
+use crate::codec::h264::parser::Parser;
 use crate::codec::h264::parser::Pps;
 use crate::codec::h264::parser::Profile;
+use crate::codec::h264::parser::Sei;
+use crate::codec::h264::parser::SeiMessage;
 use crate::codec::h264::parser::SliceHeader;
 use crate::codec::h264::parser::Sps;
+use crate::codec::h264::parser::UserDataUnregistered;
+use crate::codec::h264::synthesizer::Synthesizer;
+use crate::encoder::aq::AdaptiveQuantization;
+use crate::encoder::filter::BitstreamFilter;
+use crate::encoder::preprocess::Preprocessing;
 use crate::encoder::stateless::h264::predictor::LowDelay;
-use crate::encoder::stateless::h264::predictor::PredictionStructure;
 use crate::encoder::stateless::BackendPromise;
+use crate::encoder::stateless::BatchEncodeError;
+use crate::encoder::stateless::EncodeError;
 use crate::encoder::stateless::EncodeResult;
 use crate::encoder::stateless::FrameMetadata;
 use crate::encoder::stateless::OutputQueue;
 use crate::encoder::stateless::Predictor;
+use crate::encoder::stateless::StatelessBackendError;
 use crate::encoder::stateless::StatelessBackendResult;
 use crate::encoder::stateless::StatelessCodec;
 use crate::encoder::stateless::StatelessEncoderBackendImport;
 use crate::encoder::stateless::StatelessVideoEncoder;
 use crate::encoder::stateless::StatelessVideoEncoderBackend;
+use crate::encoder::stateless::TryEncodeError;
+use crate::encoder::stateless::Worker;
+use crate::encoder::stateless::WorkerPromise;
+use crate::encoder::AdvancedConfig;
 use crate::encoder::Bitrate;
 use crate::encoder::CodedBitstreamBuffer;
+use crate::encoder::FramePacking;
+use crate::encoder::LayerStatistics;
+use crate::encoder::MemFrame;
+use crate::encoder::Preset;
+use crate::encoder::TimestampPolicy;
+use crate::instrument::enter_span;
+use crate::instrument::time_backend_call;
+use crate::instrument::trace_event;
+use crate::instrument::SessionId;
+use crate::utils::latency;
+use crate::utils::picture_hash;
+use crate::utils::picture_hash::PictureHash;
+use crate::utils::picture_hash::PictureHashType;
+use crate::utils::quality;
+use crate::utils::quality::FrameQuality;
+use crate::utils::snapshot;
 use crate::BlockingMode;
+use crate::FrameLayout;
 use crate::Resolution;
 
+#[cfg(test)]
+mod golden;
 mod predictor;
 
+pub use predictor::PredictionStructure;
+
+#[cfg(all(windows, feature = "d3d12"))]
+pub mod d3d12;
 #[cfg(feature = "vaapi")]
 pub mod vaapi;
 
@@ -41,6 +86,110 @@ pub struct EncoderConfig {
     pub level: Level,
     pub pred_structure: PredictionStructure,
     pub default_qp: u8,
+    /// Compute the PSNR and SSIM of each reconstructed frame against its input, see
+    /// [`StatelessEncoder::poll_quality`]. This requires reading both frames back and is slow.
+    pub score_quality: bool,
+    /// Read back every reconstructed frame, i.e. the frame as a decoder would output it, see
+    /// [`StatelessEncoder::poll_reconstructed`]. Useful to debug quality issues and to verify the
+    /// DPB consistency without a separate decode pass. This is slow.
+    pub tap_reconstructed: bool,
+    /// Filters applied to the input frames before they are encoded.
+    pub preprocessing: Preprocessing,
+    /// Stereoscopic arrangement of the frames, signaled with a frame packing arrangement SEI
+    /// message at each IDR frame.
+    pub frame_packing: Option<FramePacking>,
+    /// Adapt the QP of each macroblock to the variance of its luma, see
+    /// [`crate::encoder::aq`]. Backends which cannot set the QP of each macroblock reject it.
+    pub adaptive_quantization: Option<AdaptiveQuantization>,
+    /// Speed and quality tradeoff of the backend, see [`EncoderConfig::with_preset`].
+    pub preset: Option<Preset>,
+    /// Quality level of the backend, overriding the one of [`EncoderConfig::preset`]. Lower
+    /// levels give better quality and higher ones faster encoding, e.g. the target usage of
+    /// VA-API. It is clamped to the levels supported by the backend.
+    pub quality_level: Option<u32>,
+    /// Driver specific quantization controls.
+    pub advanced: AdvancedConfig,
+    /// Maximum number of frames passed to [`StatelessVideoEncoder::encode`] and not returned by
+    /// [`StatelessVideoEncoder::poll`] yet. Once reached, `encode` fails with
+    /// [`EncodeError::WouldBlock`] and drops the frame, so that real-time callers can move on to
+    /// the next one, while [`StatelessVideoEncoder::try_encode`] hands it back.
+    ///
+    /// This bounds the latency of the encoder in frames. It includes the frames held back by the
+    /// prediction structure, see [`PredictionStructure::structural_delay`], so the encoder fails
+    /// to be created if the structure alone reaches it.
+    pub max_in_flight: Option<usize>,
+    /// Start a new sequence with an IDR when the encoding resumes after
+    /// [`StatelessEncoder::pause`], instead of referencing the frames encoded before the pause.
+    pub force_idr_on_resume: bool,
+    /// Skip the frames identical to the previous one instead of encoding them, see
+    /// [`CodedBitstreamBuffer::skipped`]. Unless [`FrameMetadata::damage`] is set, each frame is
+    /// read back into CPU memory and compared to a copy of the previous one, which costs a
+    /// readback and a copy of the visible part of every frame. This is meant for mostly static
+    /// content like screen sharing.
+    pub skip_duplicate_frames: bool,
+    /// Number of units per second of the [`FrameMetadata::timestamp`]s, e.g. 90000 for MPEG-TS
+    /// or 1000000 for microseconds. When set, the rate control budgets each frame by the time
+    /// elapsed since the previous one instead of assuming [`EncoderConfig::framerate`], so that
+    /// variable frame rate sources like screen capture are properly paced.
+    pub timescale: Option<u32>,
+    /// Validation of the input timestamps, which must increase strictly. The output carries the
+    /// normalized timestamps. If `None`, the timestamps are passed through unchecked.
+    pub timestamp_policy: Option<TimestampPolicy>,
+    /// Number of long-term references, which are kept until replaced to recover from losses
+    /// reported by the receiver, see [`StatelessEncoder::mark_long_term_reference`].
+    pub long_term_references: u8,
+    /// Minimum number of frames between the keyframes forced with
+    /// [`FrameMetadata::force_keyframe`] or [`StatelessEncoder::request_keyframe`]. Keyframes
+    /// forced earlier are deferred until the interval elapsed, coalescing bursts of requests so
+    /// that they do not blow the bitrate. Segment points are not deferred.
+    pub min_keyframe_interval: Option<u32>,
+    /// Hash of the visible part of each reconstructed frame to embed in the bitstream, so that
+    /// decoders and conformance tools can check that they are bit-exact with the encoder. The
+    /// hash is carried in a user data unregistered SEI message preceding the slices, see
+    /// [`picture_hash::H264_USER_DATA_UUID`]. This requires reading the frames back and is slow.
+    pub picture_hash: Option<PictureHashType>,
+    /// Embed the wall clock time at which each frame was passed to
+    /// [`StatelessVideoEncoder::encode`] in a user data unregistered SEI message preceding the
+    /// slices, see [`latency::H264_USER_DATA_UUID`], so that receivers can measure the end-to-end
+    /// latency of the pipeline. This is a troubleshooting aid which costs a few bytes per frame.
+    pub capture_time: bool,
+    /// SPS and PPS NAL units in Annex B format, to use instead of generating them, e.g. to match
+    /// the parameter sets negotiated in an SDP. They are parsed when the encoder is created, must
+    /// match the other fields of the configuration, and bound the number of reference frames and
+    /// the length of the sequences.
+    pub parameter_sets: Option<Vec<u8>>,
+    /// Do not insert the SPS and PPS at the IDR frames, for containers and transports delivering
+    /// them out of band, e.g. in the `avcC` box of MP4 files. They are given by
+    /// [`StatelessEncoder::codec_config`] instead.
+    pub out_of_band_parameter_sets: bool,
+    /// Repeat the SPS and PPS every this number of frames of a sequence, in addition to its IDR
+    /// frame, so that receivers joining mid-stream can decode it before the next IDR, e.g. over
+    /// UDP or MPEG-TS. 1 repeats them at every frame. Ignored if
+    /// [`EncoderConfig::out_of_band_parameter_sets`] is set.
+    pub parameter_set_interval: Option<u32>,
+    /// Mark the frames of the top temporal layer of [`Bitrate::PerLayer`] as non-reference,
+    /// i.e. with a `nal_ref_idc` of 0, and do not reference them, so that selective forwarding
+    /// units can drop any of them. Without temporal layers, every frame is a reference.
+    pub disposable_frames: bool,
+    /// Require the Constrained Baseline or Constrained High profile, depending on
+    /// [`EncoderConfig::profile`], e.g. as negotiated in an SDP. The generated SPS always signals
+    /// them with its constraint flags, as the coding tools allow it, but the encoder fails to be
+    /// created for the other profiles or if [`EncoderConfig::parameter_sets`] does not signal
+    /// them.
+    pub constrained_profile: bool,
+    /// Hash the reconstructed pictures and insert the SEI messages of
+    /// [`EncoderConfig::picture_hash`] and [`EncoderConfig::capture_time`] on a separate thread.
+    /// Useful for high resolutions and frame rates, where hashing becomes a significant part of
+    /// the frame time. Only this work is moved: the parameter sets and slice headers are still
+    /// synthesized on the caller's thread, as the backends need them to encode the frames. No
+    /// thread is started if neither SEI message is enabled.
+    pub sei_hashing_thread: bool,
+    /// Return the coded frames from [`StatelessVideoEncoder::poll`] as soon as the backend
+    /// completes them, instead of in submission order, so that one slow frame does not hold back
+    /// the following ones. This is meant for callers which do not need the presentation order,
+    /// e.g. which mux the frames by their [`FrameMetadata::timestamp`]. Decoders still need the
+    /// frames in submission order.
+    pub out_of_order_output: bool,
 }
 
 impl Default for EncoderConfig {
@@ -60,6 +209,56 @@ impl Default for EncoderConfig {
                 limit: 2048,
             },
             default_qp: 26,
+            score_quality: false,
+            tap_reconstructed: false,
+            preprocessing: Default::default(),
+            frame_packing: None,
+            adaptive_quantization: None,
+            preset: None,
+            quality_level: None,
+            advanced: Default::default(),
+            max_in_flight: None,
+            force_idr_on_resume: false,
+            skip_duplicate_frames: false,
+            timescale: None,
+            timestamp_policy: None,
+            long_term_references: 0,
+            min_keyframe_interval: None,
+            picture_hash: None,
+            capture_time: false,
+            parameter_sets: None,
+            out_of_band_parameter_sets: false,
+            parameter_set_interval: None,
+            disposable_frames: false,
+            constrained_profile: false,
+            sei_hashing_thread: false,
+            out_of_order_output: false,
+        }
+    }
+}
+
+impl EncoderConfig {
+    /// Returns the configuration tuned for `preset`. The number of reference frames and the
+    /// adaptive quantization are set here, while the backend translates the preset into its own
+    /// parameters, e.g. the quality level of VA-API.
+    pub fn with_preset(self, preset: Preset) -> Self {
+        let (tail, adaptive_quantization) = match preset {
+            Preset::Realtime => (1, None),
+            Preset::Balanced => (2, None),
+            Preset::Quality => (4, Some(Default::default())),
+        };
+
+        let pred_structure = match self.pred_structure {
+            PredictionStructure::LowDelay { limit, .. } => {
+                PredictionStructure::LowDelay { tail, limit }
+            }
+        };
+
+        Self {
+            pred_structure,
+            adaptive_quantization,
+            preset: Some(preset),
+            ..self
         }
     }
 }
@@ -77,6 +276,8 @@ pub(crate) struct DpbEntryMeta {
     poc: u16,
     frame_num: u32,
     is_reference: IsReference,
+    /// Index of the long-term reference, if `is_reference` is [`IsReference::LongTerm`]
+    long_term_frame_idx: u32,
 }
 
 /// Frame structure used in the backend representing currently encoded frame or references used
@@ -88,7 +289,173 @@ pub(crate) struct DpbEntry<R> {
     meta: DpbEntryMeta,
 }
 
+/// Reconstructed frame read back from the backend, see [`EncoderConfig::tap_reconstructed`].
+pub struct ReconstructedFrame {
+    /// Timestamp of the input frame
+    pub timestamp: u64,
+    /// Picture order count
+    pub poc: u16,
+    pub frame_num: u32,
+    /// Whether the frame is used as reference for the following ones
+    pub is_reference: IsReference,
+    /// Frame data, described by [`ReconstructedFrame::layout`]
+    pub data: Vec<u8>,
+    pub layout: FrameLayout,
+}
+
+/// Codec state of a H.264 encoder, allowing another encoder to resume its stream with an IDR,
+/// e.g. on another device after a live migration. It holds the parameter sets, so that the stream
+/// keeps matching the codec configuration negotiated with the receivers, and the counters, but no
+/// frame.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct EncoderSnapshot {
+    /// See [`StatelessEncoder::codec_config`]. These are the parameter sets to pass as
+    /// [`EncoderConfig::parameter_sets`] to the encoder resuming the stream.
+    pub codec_config: Option<Vec<u8>>,
+    /// Timestamp of the last frame passed to `encode`
+    pub last_timestamp: Option<u64>,
+    /// Offset added to the input timestamps by [`TimestampPolicy::Renumber`]
+    pub timestamp_offset: u64,
+    /// Number of frames passed to `encode` since the last forced keyframe, see
+    /// [`EncoderConfig::min_keyframe_interval`]
+    pub frames_since_keyframe: Option<u32>,
+    /// See [`StatelessEncoder::layer_statistics`]
+    pub layer_statistics: Vec<LayerStatistics>,
+}
+
+impl EncoderSnapshot {
+    const TAG: &'static [u8; 4] = b"H4ES";
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        snapshot::put_header(&mut out, Self::TAG);
+        snapshot::put_option(&mut out, self.codec_config.as_deref(), snapshot::put_bytes);
+        snapshot::put_option(&mut out, self.last_timestamp, Vec::put_u64);
+        out.put_u64(self.timestamp_offset);
+        snapshot::put_option(&mut out, self.frames_since_keyframe, Vec::put_u32);
+        out.put_u32(self.layer_statistics.len() as u32);
+        for stats in &self.layer_statistics {
+            out.put_u64(stats.frames);
+            out.put_u64(stats.bytes);
+        }
+
+        out
+    }
+
+    pub fn from_bytes(mut data: &[u8]) -> anyhow::Result<Self> {
+        let input = &mut data;
+        snapshot::get_header(input, Self::TAG)?;
+        let codec_config = snapshot::get_option(input, snapshot::get_bytes)?;
+        let last_timestamp = snapshot::get_option(input, snapshot::get_u64)?;
+        let timestamp_offset = snapshot::get_u64(input)?;
+        let frames_since_keyframe = snapshot::get_option(input, snapshot::get_u32)?;
+        let layer_statistics = (0..snapshot::get_u32(input)?)
+            .map(|_| {
+                Ok(LayerStatistics {
+                    frames: snapshot::get_u64(input)?,
+                    bytes: snapshot::get_u64(input)?,
+                })
+            })
+            .collect::<anyhow::Result<_>>()?;
+        snapshot::check_end(input)?;
+
+        Ok(Self {
+            codec_config,
+            last_timestamp,
+            timestamp_offset,
+            frames_since_keyframe,
+            layer_statistics,
+        })
+    }
+}
+
+/// Parses the PPS of `parameter_sets`, and the SPS it refers to, see
+/// [`EncoderConfig::parameter_sets`].
+fn parse_parameter_sets(parameter_sets: &[u8]) -> anyhow::Result<Rc<Pps>> {
+    let mut cursor = Cursor::new(parameter_sets);
+    let mut parser = Parser::default();
+    let mut pps_id = None;
+    while let Ok(nalu) = Nalu::next(&mut cursor) {
+        match nalu.header.type_ {
+            NaluType::Sps => {
+                parser.parse_sps(&nalu)?;
+            }
+            NaluType::Pps => pps_id = Some(parser.parse_pps(&nalu)?.pic_parameter_set_id),
+            type_ => return Err(anyhow!("unexpected {:?} NAL unit in parameter sets", type_)),
+        }
+    }
+
+    let pps = pps_id
+        .and_then(|id| parser.get_pps(id))
+        .ok_or_else(|| anyhow!("no PPS in parameter sets"))?;
+    Ok(pps.clone())
+}
+
+/// Submitted frame whose reconstructed picture is to be read back.
+struct PendingRecon {
+    /// Identifier of the frame, see [`PendingFrame::id`]
+    frame_id: u64,
+    timestamp: u64,
+    display_resolution: Resolution,
+    /// Input frame read back for scoring, if any
+    input: Option<(Vec<u8>, FrameLayout)>,
+}
+
+/// Packages `coded` for output, embedding the hash of its reconstructed picture described by
+/// `recon` and its `capture_time`, if any.
+fn package(
+    mut coded: CodedBitstreamBuffer,
+    recon: Option<(PictureHashType, Vec<u8>, FrameLayout)>,
+    capture_time: Option<SystemTime>,
+) -> EncodeResult<CodedBitstreamBuffer> {
+    let mut messages = Vec::new();
+
+    if let Some((hash_type, data, layout)) = recon {
+        match PictureHash::from_frame(hash_type, &data, &layout) {
+            Ok(hash) => messages.push(SeiMessage::UserDataUnregistered(UserDataUnregistered {
+                uuid_iso_iec_11578: picture_hash::H264_USER_DATA_UUID,
+                payload: hash.payload(),
+            })),
+            Err(e) => log::warn!("failed to hash frame {}: {:#}", coded.metadata.timestamp, e),
+        }
+    }
+
+    if let Some(capture_time) = capture_time {
+        messages.push(SeiMessage::UserDataUnregistered(UserDataUnregistered {
+            uuid_iso_iec_11578: latency::H264_USER_DATA_UUID,
+            payload: latency::payload(capture_time),
+        }));
+    }
+
+    if !messages.is_empty() {
+        let mut bitstream = Vec::from(std::mem::take(&mut coded.bitstream));
+        insert_sei(&mut bitstream, messages)?;
+        coded.bitstream = bitstream.into();
+    }
+
+    Ok(coded)
+}
+
+/// Inserts an SEI NAL unit carrying `messages` before the first slice of `bitstream`.
+fn insert_sei(bitstream: &mut Vec<u8>, messages: Vec<SeiMessage>) -> EncodeResult<()> {
+    let mut cursor = std::io::Cursor::new(&bitstream[..]);
+    let position = std::iter::from_fn(|| Nalu::next(&mut cursor).ok())
+        .find(|nalu| matches!(nalu.header.type_, NaluType::Slice | NaluType::SliceIdr))
+        .map(|nalu| nalu.sc_offset)
+        .ok_or(EncodeError::InvalidInternalState)?;
+
+    let sei = Sei { messages };
+    // The SPS is only needed by the messages related to the HRD.
+    let mut nalu = Vec::new();
+    Synthesizer::<Sei, _>::synthesize(0, &sei, &Sps::default(), &mut nalu, true)?;
+    bitstream.splice(position..position, nalu);
+
+    Ok(())
+}
+
 /// Stateless H.264 encoder backend input.
+// Most fields are only read by the backends, none of which may be enabled.
+#[cfg_attr(not(feature = "vaapi"), allow(dead_code))]
 pub struct BackendRequest<P, R> {
     sps: Rc<Sps>,
     pps: Rc<Pps>,
@@ -103,6 +470,10 @@ pub struct BackendRequest<P, R> {
     /// DPB entry metadata
     dpb_meta: DpbEntryMeta,
 
+    /// Picture to reconstruct the frame into, if it was allocated ahead of the submission by
+    /// [`StatelessH264EncoderBackend::new_reconstructed`]. The DPB already holds it.
+    recon: Option<Rc<DpbEntry<R>>>,
+
     /// Reference lists
     ref_list_0: Vec<Rc<DpbEntry<R>>>,
     ref_list_1: Vec<Rc<DpbEntry<R>>>,
@@ -116,40 +487,106 @@ pub struct BackendRequest<P, R> {
     /// Current expected bitrate
     bitrate: Bitrate,
 
+    /// Adaptive quantization of the macroblocks, if enabled
+    adaptive_quantization: Option<AdaptiveQuantization>,
+
+    /// Instantaneous frame rate as a numerator and denominator, both fitting in 16 bits
+    framerate: (u32, u32),
+
+    /// Temporal layer of the frame, see [`Bitrate::PerLayer`]
+    temporal_layer: u8,
+
     /// Container for the request output. [`StatelessH264EncoderBackend`] impl shall move it and
     /// append the slice data to it. This prevents unnecessary copying of bitstream around.
     coded_output: Vec<u8>,
 }
 
+/// Frame held by the predictor.
+struct PendingFrame {
+    /// Identifier of the frame, unique even if the timestamps are not
+    id: u64,
+    timestamp: u64,
+    /// True if the frame was requested to start a new segment
+    segment_point: bool,
+    /// Metadata and identifiers of the duplicate frames skipped after this one, output once it
+    /// is submitted
+    skipped: Vec<(FrameMetadata, u64)>,
+}
+
+impl<P, R> BackendRequest<P, R> {
+    /// Returns the NAL unit header and slice `header` of the frame without the slice data, for
+    /// the backends appending it to the headers written by the encoder, as well as its size in
+    /// bits. The reference list modification and the marking of long-term references are only
+    /// carried by these headers.
+    #[cfg_attr(not(feature = "vaapi"), allow(dead_code))]
+    fn slice_header(&self, header: &SliceHeader) -> StatelessBackendResult<(Vec<u8>, usize)> {
+        let (ref_idc, nalu_type) = match (self.is_idr, self.dpb_meta.is_reference) {
+            (true, _) => (3, NaluType::SliceIdr),
+            (false, IsReference::No) => (0, NaluType::Slice),
+            (false, _) => (2, NaluType::Slice),
+        };
+
+        let mut nalu = Vec::new();
+        let bits = Synthesizer::<SliceHeader, _>::synthesize(
+            ref_idc, nalu_type, header, &self.pps, &mut nalu, true,
+        )
+        .map_err(|e| StatelessBackendError::Other(e.into()))?;
+
+        Ok((nalu, bits))
+    }
+}
+
 /// Wrapper type for [`BackendPromise<Output = Vec<u8>>`], with additional
 /// metadata.
 struct SlicePromise<P>
 where
     P: BackendPromise<Output = Vec<u8>>,
 {
-    /// Slice data and reconstructed surface promise
-    bitstream: P,
+    /// Slice data and reconstructed surface promise, `None` if the frame is skipped
+    bitstream: Option<P>,
 
     /// Input frame metadata, for [`CodedBitstreamBuffer`]
     meta: FrameMetadata,
+
+    /// Identifier of the frame, see [`PendingFrame::id`]
+    frame_id: u64,
+
+    /// True if the frame was requested to start a new segment
+    segment_point: bool,
+
+    /// Temporal layer of the frame
+    temporal_layer: u8,
 }
 
 impl<P> BackendPromise for SlicePromise<P>
 where
     P: BackendPromise<Output = Vec<u8>>,
 {
-    type Output = CodedBitstreamBuffer;
+    /// Identifier of the frame, see [`PendingFrame::id`], and its coded output
+    type Output = (u64, CodedBitstreamBuffer);
 
     fn is_ready(&self) -> bool {
-        self.bitstream.is_ready()
+        match &self.bitstream {
+            Some(bitstream) => bitstream.is_ready(),
+            None => true,
+        }
     }
 
     fn sync(self) -> StatelessBackendResult<Self::Output> {
-        let coded_data = self.bitstream.sync()?;
+        let Some(bitstream) = self.bitstream else {
+            let mut coded = CodedBitstreamBuffer::new(self.meta, vec![]);
+            coded.skipped = true;
+            return Ok((self.frame_id, coded));
+        };
+        let coded_data = bitstream.sync()?;
 
-        log::trace!("synced bitstream size={}", coded_data.len());
+        trace_event!("synced bitstream size={}", coded_data.len());
 
-        Ok(CodedBitstreamBuffer::new(self.meta, coded_data))
+        let mut coded = CodedBitstreamBuffer::new(self.meta, coded_data);
+        coded.segment_point = self.segment_point;
+        coded.temporal_layer = self.temporal_layer;
+
+        Ok((self.frame_id, coded))
     }
 }
 
@@ -164,13 +601,18 @@ where
 
     /// [`DpbEntryMeta`] of reconstructed surface
     dpb_meta: DpbEntryMeta,
+
+    /// True if the request was handed its reconstructed picture ahead of the submission, which
+    /// the predictor already holds
+    chained: bool,
 }
 
 impl<P> BackendPromise for ReferencePromise<P>
 where
     P: BackendPromise,
 {
-    type Output = DpbEntry<P::Output>;
+    /// Reconstructed picture and whether the predictor already holds it
+    type Output = (DpbEntry<P::Output>, bool);
 
     fn is_ready(&self) -> bool {
         self.recon.is_ready()
@@ -179,12 +621,14 @@ where
     fn sync(self) -> StatelessBackendResult<Self::Output> {
         let recon_pic = self.recon.sync()?;
 
-        log::trace!("synced recon picture frame_num={}", self.dpb_meta.frame_num);
+        trace_event!("synced recon picture frame_num={}", self.dpb_meta.frame_num);
 
-        Ok(DpbEntry {
+        let recon = DpbEntry {
             recon_pic,
             meta: self.dpb_meta,
-        })
+        };
+
+        Ok((recon, self.chained))
     }
 }
 
@@ -195,11 +639,37 @@ impl StatelessCodec for H264 {}
 /// Trait for stateless encoder backend for H.264
 pub trait StatelessH264EncoderBackend: StatelessVideoEncoderBackend<H264> {
     /// Submit a [`BackendRequest`] to the backend. This operation returns both a
-    /// [`Self::CodedPromise`] and a [`Self::ReconPromise`] with resulting slice data.
+    /// [`Self::CodedPromise`] and a [`Self::ReconPromise`] with resulting slice data. The frame
+    /// is reconstructed into [`BackendRequest::recon`] if set, the [`Self::ReconPromise`] then
+    /// only signaling when the reconstructed picture can be read back.
     fn encode_slice(
         &mut self,
         request: BackendRequest<Self::Picture, Self::Reconstructed>,
     ) -> StatelessBackendResult<(Self::ReconPromise, Self::CodedPromise)>;
+
+    /// Submits several [`BackendRequest`]s at once, in order, returning the promises of each.
+    /// The requests may reference the frames reconstructed by the preceding ones. Backends able
+    /// to queue the work of several frames in the driver may override it to reduce the per-frame
+    /// overhead. The default implementation calls [`Self::encode_slice`] for each request.
+    #[allow(clippy::type_complexity)]
+    fn encode_slices(
+        &mut self,
+        requests: Vec<BackendRequest<Self::Picture, Self::Reconstructed>>,
+    ) -> StatelessBackendResult<Vec<(Self::ReconPromise, Self::CodedPromise)>> {
+        requests
+            .into_iter()
+            .map(|request| self.encode_slice(request))
+            .collect()
+    }
+
+    /// Allocates the picture the next request is reconstructed into, ahead of its submission, so
+    /// that the requests submitted after it by the same [`Self::encode_slices`] call can
+    /// reference it. Returns `None` if the backend only allocates it on submission, in which
+    /// case the requests of a batch wait for the reconstruction of their references. The default
+    /// implementation returns `None`.
+    fn new_reconstructed(&mut self) -> StatelessBackendResult<Option<Self::Reconstructed>> {
+        Ok(None)
+    }
 }
 
 pub struct StatelessEncoder<H, B>
@@ -214,24 +684,142 @@ where
     /// Pending reconstructed pictures promise queue
     recon_queue: OutputQueue<ReferencePromise<B::ReconPromise>>,
 
+    /// Number of the reconstructed pictures of `recon_queue` the predictor does not hold yet. The
+    /// requests of a batch are only chained when there is none, as the predictor takes the
+    /// reconstructed pictures in order.
+    unchained_recons: usize,
+
     /// [`Predictor`] instance responsible for the encoder decision making
     predictor: Box<
         dyn Predictor<
             B::Picture,
-            DpbEntry<B::Reconstructed>,
+            Rc<DpbEntry<B::Reconstructed>>,
             BackendRequest<B::Picture, B::Reconstructed>,
         >,
     >,
 
+    /// Coded frames waiting for the hash of their reconstructed picture to be packaged, with
+    /// their identifier, see [`PendingFrame::id`]
+    unpackaged_queue: VecDeque<(u64, CodedBitstreamBuffer)>,
+
+    /// Pending packaging of the coded frames, see [`EncoderConfig::sei_hashing_thread`]
+    packaging_queue: OutputQueue<WorkerPromise<EncodeResult<CodedBitstreamBuffer>>>,
+
+    /// Thread hashing the reconstructed pictures and inserting the SEI messages, if enabled
+    sei_hashing_thread: Option<Worker>,
+
     /// Pending [`CodedBitstreamBuffer`]s to be polled by the user
     coded_queue: VecDeque<CodedBitstreamBuffer>,
 
     /// Number of the currently held frames by the predictor
     predictor_frame_count: usize,
 
+    /// True if the next frame passed to [`StatelessVideoEncoder::encode`] shall start a segment
+    segment_point_requested: bool,
+
+    /// True if the next frame passed to [`StatelessVideoEncoder::encode`] shall be a keyframe
+    keyframe_requested: bool,
+
+    /// See [`EncoderConfig::min_keyframe_interval`]
+    min_keyframe_interval: Option<u32>,
+
+    /// Number of frames passed to `encode` since the last forced keyframe, `None` if the next
+    /// keyframe shall not be deferred
+    frames_since_keyframe: Option<u32>,
+
+    /// True if the encoding is paused, see [`StatelessEncoder::pause`]
+    paused: bool,
+
+    /// See [`EncoderConfig::long_term_references`]
+    long_term_references: u8,
+
+    /// Long-term reference index the next frame passed to `encode` shall be marked as
+    long_term_requested: Option<u8>,
+
+    /// Long-term reference index the next frame passed to `encode` shall be recovered from
+    recovery_requested: Option<u8>,
+
+    /// See [`EncoderConfig::force_idr_on_resume`]
+    force_idr_on_resume: bool,
+
+    /// See [`EncoderConfig::skip_duplicate_frames`]
+    skip_duplicate_frames: bool,
+
+    /// Visible part of the last frame passed to `encode`, to detect duplicate frames
+    last_frame: Option<Vec<u8>>,
+
+    /// True if a frame was passed to `encode` since the encoder was created or flushed, and
+    /// was not cancelled
+    has_previous_frame: bool,
+
+    /// See [`EncoderConfig::timestamp_policy`]
+    timestamp_policy: Option<TimestampPolicy>,
+
+    /// Timestamp of the last frame passed to `encode`
+    last_timestamp: Option<u64>,
+
+    /// Offset added to the input timestamps by [`TimestampPolicy::Renumber`]
+    timestamp_offset: u64,
+
+    /// Frames held by the predictor, in the order they were passed to it
+    pending_frames: VecDeque<PendingFrame>,
+
+    /// Identifier of the next frame passed to `encode`, see [`PendingFrame::id`]
+    next_frame_id: u64,
+
+    /// True if reconstructed frames shall be scored against their input
+    score_quality: bool,
+
+    /// True if reconstructed frames shall be handed to the user
+    tap_reconstructed: bool,
+
+    /// See [`EncoderConfig::picture_hash`]
+    picture_hash: Option<PictureHashType>,
+
+    /// Submitted frames whose reconstructed picture is to be read back, in submission order
+    pending_recon: VecDeque<PendingRecon>,
+
+    /// Reconstructed frames to hash and embed in the coded frames of `unpackaged_queue`, by frame
+    /// identifier, `None` if the backend could not read the frame back
+    picture_hashes: BTreeMap<u64, Option<(Vec<u8>, FrameLayout)>>,
+
+    /// See [`EncoderConfig::capture_time`]
+    capture_time: bool,
+
+    /// Wall clock time at which the frames not packaged yet were passed to `encode`, by frame
+    /// identifier
+    capture_times: BTreeMap<u64, SystemTime>,
+
+    /// Maximum number of frames in flight, see [`EncoderConfig::max_in_flight`]
+    max_in_flight: Option<usize>,
+
+    /// See [`EncoderConfig::out_of_order_output`]
+    out_of_order_output: bool,
+
+    /// Quality scores to be polled by the user
+    quality_scores: VecDeque<(u64, FrameQuality)>,
+
+    /// Reconstructed frames to be polled by the user
+    reconstructed_queue: VecDeque<ReconstructedFrame>,
+
+    /// Statistics of the coded frames of each temporal layer
+    layer_statistics: Vec<LayerStatistics>,
+
     /// [`StatelessH264EncoderBackend`] instance to delegate [`BackendRequest`] to
     backend: B,
 
+    /// Identifier of this encoder in traces
+    session: SessionId,
+
+    /// See [`PredictionStructure::structural_delay`]
+    structural_delay: u32,
+
+    /// SPS and PPS of the current sequence, see [`StatelessEncoder::codec_config`]
+    codec_config: Option<Vec<u8>>,
+
+    /// See [`StatelessEncoder::add_bitstream_filter`]
+    bitstream_filters: Vec<BitstreamFilter>,
+
     _phantom: std::marker::PhantomData<H>,
 }
 
@@ -241,126 +829,2484 @@ where
     B::Picture: 'static,
     B::Reconstructed: 'static,
 {
-    fn new(backend: B, config: EncoderConfig, mode: BlockingMode) -> EncodeResult<Self> {
+    /// Creates a new encoder encoding the frames with `backend`, which can be any backend
+    /// implementing [`StatelessH264EncoderBackend`].
+    pub fn new(backend: B, config: EncoderConfig, mode: BlockingMode) -> EncodeResult<Self> {
+        let score_quality = config.score_quality;
+        let tap_reconstructed = config.tap_reconstructed;
+        let structural_delay = config.pred_structure.structural_delay();
+        let max_in_flight = config.max_in_flight;
+        // The prediction structure needs one more frame than it holds back to output any.
+        if max_in_flight.is_some_and(|max_in_flight| structural_delay as usize >= max_in_flight) {
+            return Err(EncodeError::InvalidPredictionStructure(format!(
+                "structural delay of {} frames reaches the limit of frames in flight",
+                structural_delay
+            )));
+        }
+        let out_of_order_output = config.out_of_order_output;
+        let force_idr_on_resume = config.force_idr_on_resume;
+        let skip_duplicate_frames = config.skip_duplicate_frames;
+        let timestamp_policy = config.timestamp_policy;
+        let long_term_references = config.long_term_references;
+        let min_keyframe_interval = config.min_keyframe_interval;
+        let picture_hash = config.picture_hash;
+        let capture_time = config.capture_time;
+        let sei_hashing_thread =
+            if config.sei_hashing_thread && (picture_hash.is_some() || capture_time) {
+                let worker = Worker::new("h264-sei-hashing")
+                    .map_err(|e| StatelessBackendError::Other(e.into()))?;
+                Some(worker)
+            } else {
+                None
+            };
+        let layer_statistics = vec![Default::default(); config.bitrate.num_layers()];
         let predictor: Box<dyn Predictor<_, _, _>> = match config.pred_structure {
-            PredictionStructure::LowDelay { .. } => Box::new(LowDelay::new(config)),
+            PredictionStructure::LowDelay { .. } => Box::new(LowDelay::new(config)?),
         };
 
         Ok(Self {
             backend,
             predictor,
             predictor_frame_count: 0,
+            segment_point_requested: false,
+            keyframe_requested: false,
+            min_keyframe_interval,
+            frames_since_keyframe: None,
+            paused: false,
+            long_term_references,
+            long_term_requested: None,
+            recovery_requested: None,
+            force_idr_on_resume,
+            skip_duplicate_frames,
+            last_frame: None,
+            has_previous_frame: false,
+            timestamp_policy,
+            last_timestamp: None,
+            timestamp_offset: 0,
+            pending_frames: Default::default(),
+            next_frame_id: 0,
+            score_quality,
+            tap_reconstructed,
+            max_in_flight,
+            out_of_order_output,
+            picture_hash,
+            pending_recon: Default::default(),
+            picture_hashes: Default::default(),
+            capture_time,
+            capture_times: Default::default(),
+            quality_scores: Default::default(),
+            reconstructed_queue: Default::default(),
+            layer_statistics,
+            session: SessionId::new(),
+            structural_delay,
+            codec_config: None,
+            bitstream_filters: Vec::new(),
+            unpackaged_queue: Default::default(),
+            packaging_queue: OutputQueue::new(mode),
+            sei_hashing_thread,
             coded_queue: Default::default(),
             output_queue: OutputQueue::new(mode),
             recon_queue: OutputQueue::new(mode),
+            unchained_recons: 0,
             _phantom: Default::default(),
         })
     }
 
+    /// Returns `timestamp` normalized by `policy` and the offset of the following timestamps.
+    fn normalize_timestamp(
+        &self,
+        policy: TimestampPolicy,
+        timestamp: u64,
+    ) -> EncodeResult<(u64, u64)> {
+        let timestamp = timestamp.saturating_add(self.timestamp_offset);
+        let previous = match self.last_timestamp {
+            Some(previous) if timestamp <= previous => previous,
+            _ => return Ok((timestamp, self.timestamp_offset)),
+        };
+
+        let next = previous.saturating_add(1);
+        match policy {
+            TimestampPolicy::Error => Err(EncodeError::NonMonotonicTimestamp {
+                timestamp,
+                previous,
+            }),
+            TimestampPolicy::Clamp => Ok((next, self.timestamp_offset)),
+            TimestampPolicy::Renumber => Ok((next, self.timestamp_offset + (next - timestamp))),
+        }
+    }
+
     fn execute(
         &mut self,
-        request: BackendRequest<B::Picture, B::Reconstructed>,
+        requests: Vec<BackendRequest<B::Picture, B::Reconstructed>>,
     ) -> EncodeResult<()> {
-        let meta = request.input_meta.clone();
-        let dpb_meta = request.dpb_meta.clone();
+        if requests.is_empty() {
+            return Ok(());
+        }
 
-        // The [`BackendRequest`] has a frame from predictor. Decreasing internal counter.
-        self.predictor_frame_count -= 1;
+        let mut submitted = Vec::with_capacity(requests.len());
+        for request in &requests {
+            let meta = request.input_meta.clone();
+
+            // The [`BackendRequest`] has a frame from predictor. Decreasing internal counter.
+            self.predictor_frame_count -= 1;
+
+            // The predictor yields the frames in the order they were passed to it.
+            let frame = self
+                .pending_frames
+                .pop_front()
+                .filter(|frame| frame.timestamp == meta.timestamp)
+                .ok_or(EncodeError::InvalidInternalState)?;
+            let segment_point = request.is_idr && frame.segment_point;
+
+            if request.is_idr {
+                let mut codec_config = Vec::new();
+                Synthesizer::<Sps, _>::synthesize(3, &request.sps, &mut codec_config, true)?;
+                Synthesizer::<Pps, _>::synthesize(3, &request.pps, &mut codec_config, true)?;
+                self.codec_config = Some(codec_config);
+            }
 
-        log::trace!("submitting new request");
-        let (recon, bitstream) = self.backend.encode_slice(request)?;
+            if self.reads_reconstructed() {
+                let mut input = None;
+                if self.score_quality {
+                    input = self
+                        .backend
+                        .read_picture(&request.input)?
+                        .map(|(data, mut layout)| {
+                            // Do not score the padding of the coded size
+                            layout.size = Resolution {
+                                width: std::cmp::min(
+                                    layout.size.width,
+                                    meta.display_resolution.width,
+                                ),
+                                height: std::cmp::min(
+                                    layout.size.height,
+                                    meta.display_resolution.height,
+                                ),
+                            };
+                            (data, layout)
+                        });
+                }
+
+                self.pending_recon.push_back(PendingRecon {
+                    frame_id: frame.id,
+                    timestamp: meta.timestamp,
+                    display_resolution: meta.display_resolution,
+                    input,
+                });
+            }
+
+            if request.recon.is_none() {
+                self.unchained_recons += 1;
+            }
+
+            submitted.push((
+                meta,
+                frame.id,
+                request.dpb_meta.clone(),
+                request.recon.is_some(),
+                segment_point,
+                request.temporal_layer,
+                frame.skipped,
+            ));
+        }
 
-        // Wrap promise from backend with headers and metadata
-        let slice_promise = SlicePromise { bitstream, meta };
+        trace_event!("submitting {} requests", requests.len());
+        let promises = time_backend_call("encode_slices", || self.backend.encode_slices(requests))?;
+        if promises.len() != submitted.len() {
+            return Err(EncodeError::InvalidInternalState);
+        }
 
-        self.output_queue.add_promise(slice_promise);
+        for (
+            (meta, frame_id, dpb_meta, chained, segment_point, temporal_layer, skipped),
+            (recon, bitstream),
+        ) in submitted.into_iter().zip(promises)
+        {
+            // Wrap promise from backend with headers and metadata
+            let slice_promise = SlicePromise {
+                bitstream: Some(bitstream),
+                meta,
+                frame_id,
+                segment_point,
+                temporal_layer,
+            };
+
+            self.output_queue.add_promise(slice_promise);
+            for (meta, frame_id) in skipped {
+                self.skip_frame(meta, frame_id);
+            }
 
-        let ref_promise = ReferencePromise { recon, dpb_meta };
+            let ref_promise = ReferencePromise {
+                recon,
+                dpb_meta,
+                chained,
+            };
 
-        self.recon_queue.add_promise(ref_promise);
+            self.recon_queue.add_promise(ref_promise);
+        }
 
         Ok(())
     }
 
-    fn poll_pending(&mut self, mode: BlockingMode) -> EncodeResult<()> {
-        // Poll the output queue once and then continue polling while new promise is submitted
-        while let Some(coded) = self.output_queue.poll(mode)? {
-            self.coded_queue.push_back(coded);
+    /// Outputs the frame of `meta` as skipped, see [`CodedBitstreamBuffer::skipped`].
+    fn skip_frame(&mut self, meta: FrameMetadata, frame_id: u64) {
+        self.output_queue.add_promise(SlicePromise {
+            bitstream: None,
+            meta,
+            frame_id,
+            segment_point: false,
+            temporal_layer: 0,
+        });
+    }
+
+    /// Appends `requests` to the `batch` to submit, along with the requests the predictor yields
+    /// once handed their reconstructed pictures, if the backend allocates them ahead of the
+    /// submission. See [`StatelessH264EncoderBackend::new_reconstructed`].
+    fn chain(
+        &mut self,
+        requests: Vec<BackendRequest<B::Picture, B::Reconstructed>>,
+        batch: &mut Vec<BackendRequest<B::Picture, B::Reconstructed>>,
+    ) -> EncodeResult<()> {
+        let mut requests = VecDeque::from(requests);
+        while let Some(mut request) = requests.pop_front() {
+            let chainable = self.unchained_recons == 0 && batch.iter().all(|r| r.recon.is_some());
+            let recon_pic = match chainable {
+                true => self.backend.new_reconstructed(),
+                false => Ok(None),
+            };
+            // The request is submitted anyway, as the predictor released it.
+            let recon_pic = match recon_pic {
+                Ok(Some(recon_pic)) => recon_pic,
+                Ok(None) => {
+                    batch.push(request);
+                    continue;
+                }
+                Err(e) => {
+                    batch.push(request);
+                    batch.extend(requests);
+                    return Err(e.into());
+                }
+            };
+
+            let recon = Rc::new(DpbEntry {
+                recon_pic,
+                meta: request.dpb_meta.clone(),
+            });
+            request.recon = Some(Rc::clone(&recon));
+            batch.push(request);
+            match self.predictor.reconstructed(recon) {
+                Ok(next) => requests.extend(next),
+                Err(e) => {
+                    batch.extend(requests);
+                    return Err(e);
+                }
+            }
         }
 
-        while let Some(recon) = self.recon_queue.poll(mode)? {
-            let requests = self.predictor.reconstructed(recon)?;
-            if requests.is_empty() {
-                // No promise was submitted, therefore break
-                break;
+        Ok(())
+    }
+
+    /// Returns true if the reconstructed pictures shall be read back.
+    fn reads_reconstructed(&self) -> bool {
+        self.score_quality || self.tap_reconstructed || self.picture_hash.is_some()
+    }
+
+    /// Reads the reconstructed picture `recon` back, then scores it against its input, hashes it
+    /// and hands it to the user as requested.
+    fn read_reconstructed(&mut self, recon: &DpbEntry<B::Reconstructed>) -> EncodeResult<()> {
+        // Reconstructed pictures are yielded in submission order, matching the pending queue.
+        let Some(PendingRecon {
+            frame_id,
+            timestamp,
+            display_resolution,
+            input,
+        }) = self.pending_recon.pop_front()
+        else {
+            return Err(EncodeError::InvalidInternalState);
+        };
+
+        let Some((mut data, layout)) = self.backend.read_reconstructed(&recon.recon_pic)? else {
+            if self.picture_hash.is_some() {
+                self.picture_hashes.insert(frame_id, None);
             }
+            return Ok(());
+        };
 
-            for request in requests {
-                self.execute(request)?;
+        // Decoders crop the padding of the coded size
+        let hash_layout = self.picture_hash.map(|_| FrameLayout {
+            size: Resolution {
+                width: std::cmp::min(layout.size.width, display_resolution.width),
+                height: std::cmp::min(layout.size.height, display_resolution.height),
+            },
+            ..layout.clone()
+        });
+
+        if let Some((input, input_layout)) = input {
+            match quality::compare(&input, &input_layout, &data, &layout) {
+                Ok(score) => {
+                    log::debug!(
+                        "frame {} quality: psnr={:.2}dB ssim={:.4}",
+                        timestamp,
+                        score.psnr_frame,
+                        score.ssim_frame
+                    );
+                    self.quality_scores.push_back((timestamp, score));
+                }
+                Err(e) => log::warn!("failed to score frame {}: {:#}", timestamp, e),
             }
         }
 
+        // The frame is hashed when packaged, it is only copied if it is also handed to the user.
+        if let Some(hash_layout) = hash_layout {
+            let data = if self.tap_reconstructed {
+                data.clone()
+            } else {
+                std::mem::take(&mut data)
+            };
+            self.picture_hashes
+                .insert(frame_id, Some((data, hash_layout)));
+        }
+
+        if self.tap_reconstructed {
+            self.reconstructed_queue.push_back(ReconstructedFrame {
+                timestamp,
+                poc: recon.meta.poc,
+                frame_num: recon.meta.frame_num,
+                is_reference: recon.meta.is_reference,
+                data,
+                layout,
+            });
+        }
+
         Ok(())
     }
-}
 
-impl<H, B> StatelessVideoEncoder<H> for StatelessEncoder<H, B>
-where
-    B: StatelessH264EncoderBackend,
-    B: StatelessEncoderBackendImport<H, B::Picture>,
-{
-    fn encode(&mut self, metadata: FrameMetadata, handle: H) -> EncodeResult<()> {
-        log::trace!(
-            "encode: timestamp={} layout={:?}",
-            metadata.timestamp,
-            metadata.layout
-        );
+    /// Aborts the encoding of the frames not submitted to the backend yet and returns them, so
+    /// that their buffers can be reclaimed. The frames being processed by the backend are waited
+    /// for, and their output is discarded along with the coded output not polled yet. The next
+    /// frame starts a new sequence with an IDR.
+    ///
+    /// The frames are returned as imported by the backend, which is the input handle itself for
+    /// backends encoding the handles in place, like VA-API surfaces.
+    pub fn flush(&mut self) -> EncodeResult<Vec<(FrameMetadata, B::Picture)>> {
+        let _span = enter_span("encoder", self.session, "flush", None);
+        let frames: Vec<_> = self
+            .predictor
+            .flush()
+            .into_iter()
+            .map(|(picture, meta)| (meta, picture))
+            .collect();
+        trace_event!("flushed {} frames", frames.len());
+        self.predictor_frame_count = 0;
+
+        // The backend cannot abort its work, wait for it to complete. The output is discarded, so
+        // it does not need to be collected in order.
+        while self
+            .output_queue
+            .poll_completed(BlockingMode::Blocking)?
+            .is_some()
+        {}
+        while self
+            .recon_queue
+            .poll_completed(BlockingMode::Blocking)?
+            .is_some()
+        {}
+        self.unchained_recons = 0;
+        while self
+            .packaging_queue
+            .poll_completed(BlockingMode::Blocking)?
+            .is_some()
+        {}
+        self.unpackaged_queue.clear();
+        self.coded_queue.clear();
+        self.pending_recon.clear();
+        self.picture_hashes.clear();
+        self.capture_times.clear();
+        self.last_frame = None;
+        self.has_previous_frame = false;
+        self.frames_since_keyframe = None;
+        // Callers may seek back after a flush.
+        self.last_timestamp = None;
+        self.timestamp_offset = 0;
+
+        // The segment points requested for the flushed frames move to the next frame, which is an
+        // IDR anyway.
+        if self.pending_frames.iter().any(|frame| frame.segment_point) {
+            self.segment_point_requested = true;
+        }
+        self.pending_frames.clear();
 
-        // Import `handle` to backends representation
-        let backend_pic = self.backend.import_picture(&metadata, handle)?;
+        Ok(frames)
+    }
 
-        // Increase the number of frames that predictor holds, before handing one to it
-        self.predictor_frame_count += 1;
+    /// Cancels the encoding of the frame of `timestamp` and returns it, if it was not submitted to
+    /// the backend yet. Useful to drop stale frames, e.g. when the network stalls. A keyframe or
+    /// a segment point requested for the frame moves to the next frame passed to `encode`.
+    pub fn cancel(&mut self, timestamp: u64) -> Option<(FrameMetadata, B::Picture)> {
+        let (picture, meta) = self.predictor.cancel(timestamp)?;
+        trace_event!("cancelled frame {}", timestamp);
+        self.predictor_frame_count -= 1;
+        // The next frame is compared to a frame which will not be displayed
+        self.last_frame = None;
+        self.has_previous_frame = false;
+
+        // The predictor cancels the first frame of `timestamp` it holds, which is the first one
+        // pending.
+        let mut segment_point = false;
+        if let Some(index) = self
+            .pending_frames
+            .iter()
+            .position(|frame| frame.timestamp == timestamp)
+        {
+            if let Some(frame) = self.pending_frames.remove(index) {
+                self.capture_times.remove(&frame.id);
+                segment_point = frame.segment_point;
+
+                // The frames skipped after the cancelled one follow the frame preceding it, which
+                // was already submitted if there is no pending one.
+                match index.checked_sub(1) {
+                    Some(previous) => self.pending_frames[previous].skipped.extend(frame.skipped),
+                    None => {
+                        for (meta, frame_id) in frame.skipped {
+                            self.skip_frame(meta, frame_id);
+                        }
+                    }
+                }
+            }
+        }
 
-        // Ask predictor to decide on the next move and execute it
-        let requests = self.predictor.new_frame(backend_pic, metadata)?;
-        for request in requests {
-            self.execute(request)?;
+        if segment_point {
+            self.segment_point_requested = true;
+        } else if meta.force_keyframe {
+            self.keyframe_requested = true;
+            self.frames_since_keyframe = None;
         }
 
+        Some((meta, picture))
+    }
+
+    /// Pauses the encoding, e.g. while a captured screen does not change. The frames passed to
+    /// `encode` so far are encoded and `encode` fails with [`EncodeError::Paused`] until
+    /// [`StatelessEncoder::resume`] is called. The reference frames are kept, so the encoding can
+    /// resume without an IDR.
+    pub fn pause(&mut self) -> EncodeResult<()>
+    where
+        B: StatelessEncoderBackendImport<H, B::Picture>,
+    {
+        let _span = enter_span("encoder", self.session, "pause", None);
+        self.drain()?;
+        self.paused = true;
+
         Ok(())
     }
 
-    fn drain(&mut self) -> EncodeResult<()> {
-        log::trace!("currently predictor holds {}", self.predictor_frame_count);
+    /// Requests the next frame passed to `encode` to be a keyframe, e.g. when a receiver reports a
+    /// picture loss. See [`EncoderConfig::min_keyframe_interval`] to limit the rate of keyframes.
+    pub fn request_keyframe(&mut self) {
+        self.keyframe_requested = true;
+    }
 
-        // Drain the predictor
-        while self.predictor_frame_count > 0 || !self.recon_queue.is_empty() {
-            if self.output_queue.is_empty() && self.recon_queue.is_empty() {
-                // The OutputQueue is empty and predictor holds frames, force it to yield a request
-                // to empty it's internal queue.
-                let requests = self.predictor.drain()?;
-                self.predictor_frame_count -= requests.len();
+    /// Resumes the encoding paused with [`StatelessEncoder::pause`].
+    pub fn resume(&mut self) {
+        if self.paused && self.force_idr_on_resume {
+            self.keyframe_requested = true;
+            self.frames_since_keyframe = None;
+        }
+        self.paused = false;
+    }
 
-                for request in requests {
-                    self.execute(request)?;
-                }
-            }
+    /// Returns the number of frames the prediction structure delays the output by, see
+    /// [`PredictionStructure::structural_delay`].
+    pub fn structural_delay(&self) -> u32 {
+        self.structural_delay
+    }
 
-            self.poll_pending(BlockingMode::Blocking)?;
+    /// Returns the identifier of this encoder in traces.
+    pub fn session_id(&self) -> SessionId {
+        self.session
+    }
+
+    /// Returns the quality score of the next reconstructed frame along with its timestamp, if
+    /// [`EncoderConfig::score_quality`] is set and the backend supports reading frames back.
+    pub fn poll_quality(&mut self) -> Option<(u64, FrameQuality)> {
+        self.quality_scores.pop_front()
+    }
+
+    /// Returns the next reconstructed frame in submission order, if
+    /// [`EncoderConfig::tap_reconstructed`] is set and the backend supports reading frames back.
+    pub fn poll_reconstructed(&mut self) -> Option<ReconstructedFrame> {
+        self.reconstructed_queue.pop_front()
+    }
+
+    /// Marks the next frame passed to `encode` as the long-term reference `index`, replacing the
+    /// previous one. Once the receiver acknowledges the frame, it can recover from losses with
+    /// [`StatelessEncoder::encode_recovery_frame`]. The long-term references are dropped at IDR
+    /// frames, which can only be marked as the long-term reference 0.
+    pub fn mark_long_term_reference(&mut self, index: u8) -> EncodeResult<()> {
+        if index >= self.long_term_references {
+            return Err(EncodeError::InvalidLongTermReference(index));
         }
 
-        // There are still some requests being processed. Continue on polling them.
-        while !self.output_queue.is_empty() {
-            self.poll_pending(BlockingMode::Blocking)?;
+        self.long_term_requested = Some(index);
+        Ok(())
+    }
+
+    /// Encodes the next frame passed to `encode` referencing only the long-term reference
+    /// `index`, which the receiver holds, so that it recovers from losses without an IDR. The
+    /// following frames do not reference the frames encoded after the long-term reference. The
+    /// frame is an IDR if the reference was dropped.
+    pub fn encode_recovery_frame(&mut self, index: u8) -> EncodeResult<()> {
+        if index >= self.long_term_references {
+            return Err(EncodeError::InvalidLongTermReference(index));
         }
 
+        self.recovery_requested = Some(index);
         Ok(())
     }
 
-    fn poll(&mut self) -> EncodeResult<Option<CodedBitstreamBuffer>> {
-        // Poll on output queue without blocking and try to dueue from coded queue
-        self.poll_pending(BlockingMode::NonBlocking)?;
-        Ok(self.coded_queue.pop_front())
+    /// Reports the frame of `timestamp` as lost by the receiver, e.g. on a NACK. The following
+    /// frames reference neither it nor the frames referencing it, directly or not, to prevent the
+    /// propagation of the error, and are IDR if no other reference is left. Returns false if the
+    /// frame is unknown, e.g. encoded before the last IDR, and thus not referenced anymore.
+    pub fn invalidate_reference(&mut self, timestamp: u64) -> bool {
+        trace_event!("invalidating frame {}", timestamp);
+        self.predictor.invalidate(timestamp)
+    }
+
+    /// Returns the SPS and PPS of the current sequence as Annex B NAL units, once its first frame
+    /// was submitted to the backend. They are needed to decode the stream if
+    /// [`EncoderConfig::out_of_band_parameter_sets`] is set, and can be turned into an `avcC`
+    /// record with [`AvcDecoderConfigurationRecord::from_annexb`].
+    ///
+    /// [`AvcDecoderConfigurationRecord::from_annexb`]:
+    ///     crate::codec::h264::avcc::AvcDecoderConfigurationRecord::from_annexb
+    pub fn codec_config(&self) -> Option<&[u8]> {
+        self.codec_config.as_deref()
+    }
+
+    /// Appends `filter` to the chain of [`BitstreamFilter`]s applied to the coded buffers before
+    /// they are returned by [`StatelessVideoEncoder::poll`], e.g. one of the built-in filters of
+    /// [`crate::encoder::filter`]. The [`StatelessEncoder::codec_config`] and the
+    /// [`StatelessEncoder::layer_statistics`] are not affected by the filters.
+    pub fn add_bitstream_filter(&mut self, filter: BitstreamFilter) {
+        self.bitstream_filters.push(filter);
+    }
+
+    /// Returns the statistics of the frames coded so far in each temporal layer, see
+    /// [`Bitrate::PerLayer`].
+    pub fn layer_statistics(&self) -> &[LayerStatistics] {
+        &self.layer_statistics
+    }
+
+    /// Returns the codec state needed to resume the stream with another encoder, see
+    /// [`StatelessEncoder::restore`]. It is meant to be taken once the encoder is drained and its
+    /// output polled, as the frames still in flight are not accounted for.
+    pub fn snapshot(&self) -> EncoderSnapshot {
+        EncoderSnapshot {
+            codec_config: self.codec_config.clone(),
+            last_timestamp: self.last_timestamp,
+            timestamp_offset: self.timestamp_offset,
+            frames_since_keyframe: self.frames_since_keyframe,
+            layer_statistics: self.layer_statistics.clone(),
+        }
+    }
+
+    /// Restores the codec state of `snapshot` into a newly created encoder, whose first frame
+    /// starts a new sequence with an IDR. The encoder should be created with the parameter sets of
+    /// [`EncoderSnapshot::codec_config`] for the stream to keep the same codec configuration.
+    pub fn restore(&mut self, snapshot: &EncoderSnapshot) -> EncodeResult<()> {
+        if self.codec_config.is_some() || self.predictor_frame_count > 0 {
+            return Err(EncodeError::InvalidSnapshot(
+                "the encoder already encoded frames".into(),
+            ));
+        }
+        if snapshot.layer_statistics.len() != self.layer_statistics.len() {
+            return Err(EncodeError::InvalidSnapshot(format!(
+                "{} temporal layers, expected {}",
+                snapshot.layer_statistics.len(),
+                self.layer_statistics.len()
+            )));
+        }
+
+        self.last_timestamp = snapshot.last_timestamp;
+        self.timestamp_offset = snapshot.timestamp_offset;
+        self.frames_since_keyframe = snapshot.frames_since_keyframe;
+        self.layer_statistics = snapshot.layer_statistics.clone();
+
+        Ok(())
+    }
+
+    /// Returns the rows of the visible part of `picture` packed together, if the backend can read
+    /// it back.
+    fn visible_frame(
+        &self,
+        picture: &B::Picture,
+        meta: &FrameMetadata,
+    ) -> EncodeResult<Option<Vec<u8>>> {
+        let Some((data, mut layout)) = self.backend.read_picture(picture)? else {
+            return Ok(None);
+        };
+
+        // The padding of the coded size may differ between identical frames
+        layout.size = Resolution {
+            width: std::cmp::min(layout.size.width, meta.display_resolution.width),
+            height: std::cmp::min(layout.size.height, meta.display_resolution.height),
+        };
+        let num_planes = layout.planes.len();
+        let frame = MemFrame::new(&data[..], layout).map_err(StatelessBackendError::Other)?;
+
+        let mut visible = Vec::new();
+        for plane in 0..num_planes {
+            for row in frame.plane_rows(plane) {
+                visible.extend_from_slice(row);
+            }
+        }
+
+        Ok(Some(visible))
+    }
+
+    /// Hands the frame to the predictor, after applying the requests and policies of the encoder,
+    /// and returns the requests it yields without submitting them. The frame is handed back if
+    /// the encoder cannot accept it yet.
+    #[allow(clippy::type_complexity)]
+    fn accept(
+        &mut self,
+        mut metadata: FrameMetadata,
+        handle: H,
+    ) -> Result<Vec<BackendRequest<B::Picture, B::Reconstructed>>, TryEncodeError<H>>
+    where
+        B: StatelessEncoderBackendImport<H, B::Picture>,
+    {
+        if self.paused {
+            return Err(EncodeError::Paused.into());
+        }
+
+        if let Some(max_in_flight) = self.max_in_flight {
+            if self.frames_in_flight() >= max_in_flight {
+                self.poll_pending(BlockingMode::NonBlocking)?;
+            }
+            if self.frames_in_flight() >= max_in_flight {
+                trace_event!("encode: {} frames in flight", self.frames_in_flight());
+                return Err(TryEncodeError::WouldBlock(metadata, handle));
+            }
+        }
+
+        // The backend runs out of resources when too many frames are in flight, which the caller
+        // shall handle like reaching the limit.
+        if !self.backend.can_import(&metadata) {
+            trace_event!("encode: backend cannot import more frames");
+            return Err(TryEncodeError::WouldBlock(metadata, handle));
+        }
+
+        // The timestamp is only committed once the frame is accepted, like the requests.
+        let timestamp_offset = match self.timestamp_policy {
+            Some(policy) => {
+                let (timestamp, offset) = self.normalize_timestamp(policy, metadata.timestamp)?;
+                if timestamp != metadata.timestamp {
+                    trace_event!(
+                        "encode: timestamp {} normalized to {}",
+                        metadata.timestamp,
+                        timestamp
+                    );
+                    metadata.timestamp = timestamp;
+                }
+                offset
+            }
+            None => self.timestamp_offset,
+        };
+
+        // A segment starts with an IDR, which comes with new SPS and PPS
+        let segment_point = self.segment_point_requested;
+        if segment_point || self.keyframe_requested {
+            metadata.force_keyframe = true;
+        }
+
+        // Keyframes forced too soon after the previous one are deferred, which also coalesces
+        // bursts of requests, e.g. picture loss indications of many receivers.
+        let mut keyframe_deferred = false;
+        if let (Some(min_interval), Some(frames)) =
+            (self.min_keyframe_interval, self.frames_since_keyframe)
+        {
+            if metadata.force_keyframe && !segment_point && frames + 1 < min_interval {
+                trace_event!("encode: deferring keyframe");
+                metadata.force_keyframe = false;
+                keyframe_deferred = true;
+            }
+        }
+
+        // Import `handle` to backends representation.
+        let backend_pic = self
+            .backend
+            .import_picture(&metadata, handle)
+            .map_err(EncodeError::from)?;
+
+        // The frame is read back before the requests are consumed, so that they are kept for the
+        // next frame if it fails.
+        let visible = match &metadata.damage {
+            None if self.skip_duplicate_frames => self.visible_frame(&backend_pic, &metadata)?,
+            _ => None,
+        };
+
+        // The requests are only consumed once the frame is accepted, the frames dropped with
+        // `WouldBlock` leave them to the next one.
+        let frame_id = self.next_frame_id;
+        self.next_frame_id += 1;
+        self.last_timestamp = Some(metadata.timestamp);
+        self.timestamp_offset = timestamp_offset;
+        self.segment_point_requested = false;
+        self.keyframe_requested = keyframe_deferred;
+        if self.capture_time {
+            self.capture_times.insert(frame_id, SystemTime::now());
+        }
+        self.frames_since_keyframe = match self.frames_since_keyframe {
+            Some(frames) if !metadata.force_keyframe => Some(frames + 1),
+            _ => Some(0),
+        };
+
+        if self.skip_duplicate_frames {
+            let duplicate = match &metadata.damage {
+                // The frame is known to be unchanged, there is no need to read it back.
+                Some(damage) if damage.is_empty() => self.has_previous_frame,
+                Some(_) => {
+                    self.last_frame = None;
+                    self.has_previous_frame = false;
+                    false
+                }
+                None => {
+                    let duplicate = visible.is_some() && visible == self.last_frame;
+                    self.last_frame = visible;
+                    duplicate
+                }
+            };
+            self.has_previous_frame = true;
+
+            // The frames held by the predictor must be output before the skipped one, which then
+            // waits for the last of them to be submitted.
+            let marked = self.long_term_requested.is_some() || self.recovery_requested.is_some();
+            if duplicate && !metadata.force_keyframe && !marked {
+                trace_event!("encode: skipping duplicate frame");
+                match self.pending_frames.back_mut() {
+                    Some(frame) => frame.skipped.push((metadata, frame_id)),
+                    None => self.skip_frame(metadata, frame_id),
+                }
+                return Ok(Vec::new());
+            }
+        }
+
+        if let Some(index) = self.long_term_requested.take() {
+            self.predictor.mark_long_term(metadata.timestamp, index);
+        }
+        if let Some(index) = self.recovery_requested.take() {
+            self.predictor.recover(metadata.timestamp, index);
+        }
+
+        // Increase the number of frames that predictor holds, before handing one to it
+        self.predictor_frame_count += 1;
+        self.pending_frames.push_back(PendingFrame {
+            id: frame_id,
+            timestamp: metadata.timestamp,
+            segment_point,
+            skipped: Vec::new(),
+        });
+
+        // Ask predictor to decide on the next move
+        Ok(self.predictor.new_frame(backend_pic, metadata)?)
+    }
+
+    /// Returns the number of frames passed to `encode` and not returned by `poll` yet.
+    fn frames_in_flight(&self) -> usize {
+        self.predictor_frame_count
+            + self
+                .pending_frames
+                .iter()
+                .map(|frame| frame.skipped.len())
+                .sum::<usize>()
+            + self.output_queue.len()
+            + self.unpackaged_queue.len()
+            + self.packaging_queue.len()
+            + self.coded_queue.len()
+    }
+
+    fn poll_pending(&mut self, mode: BlockingMode) -> EncodeResult<()> {
+        let poll_output = if self.out_of_order_output {
+            OutputQueue::poll_completed
+        } else {
+            OutputQueue::poll
+        };
+
+        // Poll the output queue once and then continue polling while new promise is submitted
+        while let Some((frame_id, coded)) = poll_output(&mut self.output_queue, mode)? {
+            if !coded.skipped {
+                let stats = &mut self.layer_statistics[coded.temporal_layer as usize];
+                stats.frames += 1;
+                stats.bytes += coded.bitstream.len() as u64;
+            }
+            self.unpackaged_queue.push_back((frame_id, coded));
+        }
+
+        while let Some((recon, chained)) = self.recon_queue.poll(mode)? {
+            if self.reads_reconstructed() {
+                self.read_reconstructed(&recon)?;
+            }
+
+            // The predictor was handed the chained reconstructed pictures on submission.
+            if chained {
+                continue;
+            }
+            self.unchained_recons -= 1;
+
+            let requests = self.predictor.reconstructed(Rc::new(recon))?;
+            if requests.is_empty() {
+                // No promise was submitted, therefore break
+                break;
+            }
+
+            self.execute(requests)?;
+        }
+
+        self.package()?;
+        while let Some(coded) = self.packaging_queue.poll(mode)? {
+            let mut coded = coded?;
+            for filter in &mut self.bitstream_filters {
+                filter(&mut coded).map_err(StatelessBackendError::Other)?;
+            }
+            self.coded_queue.push_back(coded);
+        }
+
+        Ok(())
+    }
+
+    /// Submits the packaging of the coded frames whose reconstructed picture was hashed, if
+    /// requested, in order.
+    fn package(&mut self) -> EncodeResult<()> {
+        while let Some((frame_id, coded)) = self.unpackaged_queue.front() {
+            let mut recon = None;
+            if let (Some(hash_type), false) = (self.picture_hash, coded.skipped) {
+                let Some(hash_input) = self.picture_hashes.remove(frame_id) else {
+                    break;
+                };
+                recon = hash_input.map(|(data, layout)| (hash_type, data, layout));
+            }
+            // Skipped frames have no slice to precede.
+            let capture_time = self
+                .capture_times
+                .remove(frame_id)
+                .filter(|_| !coded.skipped);
+
+            let (_, coded) = self
+                .unpackaged_queue
+                .pop_front()
+                .ok_or(EncodeError::InvalidInternalState)?;
+            let promise = WorkerPromise::new(self.sei_hashing_thread.as_ref(), move || {
+                package(coded, recon, capture_time)
+            });
+            self.packaging_queue.add_promise(promise);
+        }
+
+        Ok(())
+    }
+}
+
+impl<H, B> StatelessVideoEncoder<H> for StatelessEncoder<H, B>
+where
+    B: StatelessH264EncoderBackend,
+    B: StatelessEncoderBackendImport<H, B::Picture>,
+{
+    fn encode(&mut self, metadata: FrameMetadata, handle: H) -> EncodeResult<()> {
+        Ok(self.try_encode(metadata, handle)?)
+    }
+
+    fn try_encode(&mut self, metadata: FrameMetadata, handle: H) -> Result<(), TryEncodeError<H>> {
+        let _span = enter_span("encoder", self.session, "encode", Some(metadata.timestamp));
+        trace_event!(
+            "encode: timestamp={} layout={:?}",
+            metadata.timestamp,
+            metadata.layout
+        );
+
+        let requests = self.accept(metadata, handle)?;
+        Ok(self.execute(requests)?)
+    }
+
+    fn capacity(&mut self) -> EncodeResult<Option<usize>> {
+        let Some(max_in_flight) = self.max_in_flight else {
+            return Ok(None);
+        };
+
+        self.poll_pending(BlockingMode::NonBlocking)?;
+        Ok(Some(max_in_flight.saturating_sub(self.frames_in_flight())))
+    }
+
+    fn encode_batch(&mut self, frames: Vec<(FrameMetadata, H)>) -> Result<(), BatchEncodeError> {
+        let _span = enter_span("encoder", self.session, "encode_batch", None);
+        trace_event!("encode_batch: {} frames", frames.len());
+
+        // The batch is rejected as a whole if it does not fit.
+        let rejected = |error| BatchEncodeError { accepted: 0, error };
+        if let Some(capacity) = self.capacity().map_err(rejected)? {
+            if frames.len() > capacity {
+                trace_event!("encode_batch: {} frames in flight", self.frames_in_flight());
+                return Err(rejected(EncodeError::WouldBlock));
+            }
+        }
+
+        // The frames accepted before a failure are still submitted, as the predictor released
+        // them. Chaining the requests lets the predictor release the frames referencing the
+        // preceding ones of the batch, rather than once they are reconstructed.
+        let mut requests = Vec::new();
+        let mut accepted = 0;
+        let mut result = Ok(());
+        for (metadata, handle) in frames {
+            let error = match self.accept(metadata, handle) {
+                Ok(frame_requests) => {
+                    accepted += 1;
+                    match self.chain(frame_requests, &mut requests) {
+                        Ok(()) => continue,
+                        Err(error) => error,
+                    }
+                }
+                Err(error) => error.into(),
+            };
+            result = Err(BatchEncodeError { accepted, error });
+            break;
+        }
+
+        self.execute(requests)
+            .map_err(|error| BatchEncodeError { accepted, error })?;
+        result
+    }
+
+    fn drain(&mut self) -> EncodeResult<()> {
+        let _span = enter_span("encoder", self.session, "drain", None);
+        trace_event!("currently predictor holds {}", self.predictor_frame_count);
+
+        // Drain the predictor
+        while self.predictor_frame_count > 0 || !self.recon_queue.is_empty() {
+            if self.output_queue.is_empty() && self.recon_queue.is_empty() {
+                // The OutputQueue is empty and predictor holds frames, force it to yield a request
+                // to empty it's internal queue.
+                let requests = self.predictor.drain()?;
+                if requests.is_empty() {
+                    // Nothing is pending that could unblock the predictor, it would never yield
+                    // the frames it holds.
+                    log::error!(
+                        "Predictor holds {} frames it cannot drain",
+                        self.predictor_frame_count
+                    );
+                    return Err(EncodeError::InvalidInternalState);
+                }
+
+                self.execute(requests)?;
+            }
+
+            self.poll_pending(BlockingMode::Blocking)?;
+        }
+
+        // There are still some requests being processed. Continue on polling them, along with
+        // their reconstructed pictures which may be needed to complete the output, and their
+        // packaging.
+        while !self.output_queue.is_empty()
+            || !self.recon_queue.is_empty()
+            || !self.packaging_queue.is_empty()
+        {
+            self.poll_pending(BlockingMode::Blocking)?;
+        }
+
+        Ok(())
+    }
+
+    fn poll(&mut self) -> EncodeResult<Option<CodedBitstreamBuffer>> {
+        let _span = enter_span("encoder", self.session, "poll", None);
+        // Poll on output queue without blocking and try to dueue from coded queue
+        self.poll_pending(BlockingMode::NonBlocking)?;
+
+        Ok(self.coded_queue.pop_front())
+    }
+
+    fn request_segment_point(&mut self) {
+        self.segment_point_requested = true;
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::codec::h264::avcc::AvcDecoderConfigurationRecord;
+    use crate::codec::h264::parser::Nalu;
+    use crate::codec::h264::parser::NaluType;
+    use crate::codec::h264::parser::Parser;
+    use crate::codec::h264::parser::Point;
+    use crate::codec::h264::parser::PpsBuilder;
+    use crate::codec::h264::parser::Rect;
+    use crate::codec::h264::parser::SeiMessage;
+    use crate::codec::h264::parser::SpsBuilder;
+    use crate::decoder::stateless::StatelessDecoder;
+    use crate::encoder::filter;
+    use crate::encoder::units::UnitType;
+    use crate::utils::simple_playback_loop;
+    use crate::utils::simple_playback_loop_owned_frames;
+    use crate::utils::NalIterator;
+    use crate::DecodedFormat;
+    use crate::Fourcc;
+    use crate::FrameLayout;
+
+    /// Promise becoming ready only after being polled a number of times, like the work of a slow
+    /// backend.
+    pub(crate) struct DummyPromise<T> {
+        value: T,
+        polls: std::cell::Cell<u32>,
+    }
+
+    impl<T> BackendPromise for DummyPromise<T> {
+        type Output = T;
+
+        fn sync(self) -> StatelessBackendResult<T> {
+            Ok(self.value)
+        }
+
+        fn is_ready(&self) -> bool {
+            let polls = self.polls.get();
+            self.polls.set(polls.saturating_sub(1));
+            polls == 0
+        }
+    }
+
+    /// Backend returning the synthesized headers followed by an empty slice NAL unit, allowing to
+    /// test the encoder logic without hardware. Its flags make it behave like the backends the
+    /// encoder has to cope with.
+    #[derive(Default)]
+    pub(crate) struct DummyBackend {
+        /// Promises need a varying number of polls to be ready, different for the reconstruction
+        /// and the bitstream.
+        slow: bool,
+        /// Timestamps of the frames whose import runs out of resources.
+        exhausted: Vec<u64>,
+        /// The frames and their reconstructions cannot be read back.
+        unreadable: bool,
+        /// Number of the next reads back failing.
+        read_failures: std::cell::Cell<u32>,
+        /// The slices carry their headers, so that the stream can be parsed by a decoder.
+        slice_headers: bool,
+        /// The reconstructed pictures are allocated ahead of the submission, so that the requests
+        /// of a batch are chained.
+        chained: bool,
+        submitted: u32,
+        /// Number of requests of each [`StatelessH264EncoderBackend::encode_slices`] call.
+        batches: Vec<usize>,
+    }
+
+    impl DummyBackend {
+        fn read_back(&self, value: u8) -> StatelessBackendResult<Option<(Vec<u8>, FrameLayout)>> {
+            match self.read_failures.get() {
+                0 if self.unreadable => Ok(None),
+                0 => Ok(Some(gray_frame(value))),
+                failures => {
+                    self.read_failures.set(failures - 1);
+                    Err(StatelessBackendError::Other(anyhow::anyhow!(
+                        "cannot map the frame"
+                    )))
+                }
+            }
+        }
+    }
+
+    impl StatelessVideoEncoderBackend<H264> for DummyBackend {
+        type Picture = ();
+        type Reconstructed = ();
+        type CodedPromise = DummyPromise<Vec<u8>>;
+        type ReconPromise = DummyPromise<()>;
+
+        fn read_picture(
+            &self,
+            _picture: &(),
+        ) -> StatelessBackendResult<Option<(Vec<u8>, FrameLayout)>> {
+            self.read_back(128)
+        }
+
+        fn read_reconstructed(
+            &self,
+            _recon: &(),
+        ) -> StatelessBackendResult<Option<(Vec<u8>, FrameLayout)>> {
+            self.read_back(130)
+        }
+    }
+
+    /// Returns a small NV12 frame with all samples set to `value`.
+    fn gray_frame(value: u8) -> (Vec<u8>, FrameLayout) {
+        let size = Resolution {
+            width: 16,
+            height: 16,
+        };
+        let layout = quality::packed_layout(Fourcc::from(b"NV12"), size).unwrap();
+
+        (vec![value; 16 * 16 * 3 / 2], layout)
+    }
+
+    impl StatelessEncoderBackendImport<(), ()> for DummyBackend {
+        fn import_picture(
+            &mut self,
+            _metadata: &FrameMetadata,
+            _handle: (),
+        ) -> StatelessBackendResult<()> {
+            Ok(())
+        }
+
+        fn can_import(&self, metadata: &FrameMetadata) -> bool {
+            !self.exhausted.contains(&metadata.timestamp)
+        }
+    }
+
+    impl StatelessH264EncoderBackend for DummyBackend {
+        fn encode_slice(
+            &mut self,
+            request: BackendRequest<(), ()>,
+        ) -> StatelessBackendResult<(Self::ReconPromise, Self::CodedPromise)> {
+            let mut coded = request.coded_output.clone();
+            if self.slice_headers {
+                coded.extend(request.slice_header(&request.header)?.0);
+            } else {
+                // IDR, reference or non-reference non-IDR slice NAL unit header
+                let header = match (request.is_idr, request.dpb_meta.is_reference) {
+                    (true, _) => 0x65,
+                    (false, IsReference::No) => 0x01,
+                    (false, _) => 0x41,
+                };
+                coded.extend_from_slice(&[0x00, 0x00, 0x00, 0x01, header]);
+            }
+
+            self.submitted += 1;
+            let delay = |seed: u32| match self.slow {
+                true => std::cell::Cell::new(seed * 7 % 5),
+                false => std::cell::Cell::new(0),
+            };
+
+            Ok((
+                DummyPromise {
+                    value: (),
+                    polls: delay(self.submitted),
+                },
+                DummyPromise {
+                    value: coded,
+                    polls: delay(self.submitted + 3),
+                },
+            ))
+        }
+
+        fn encode_slices(
+            &mut self,
+            requests: Vec<BackendRequest<(), ()>>,
+        ) -> StatelessBackendResult<Vec<(Self::ReconPromise, Self::CodedPromise)>> {
+            self.batches.push(requests.len());
+            requests
+                .into_iter()
+                .map(|request| self.encode_slice(request))
+                .collect()
+        }
+
+        fn new_reconstructed(&mut self) -> StatelessBackendResult<Option<()>> {
+            Ok(self.chained.then_some(()))
+        }
+    }
+
+    /// Returns the bitstreams left in `encoder`, once drained.
+    pub(crate) fn collect_coded(
+        encoder: &mut impl StatelessVideoEncoder<()>,
+    ) -> Vec<CodedBitstreamBuffer> {
+        encoder.drain().unwrap();
+        std::iter::from_fn(|| encoder.poll().unwrap()).collect()
+    }
+
+    /// Encodes the frames of `frames`, polling `encoder` after each of them, and returns all the
+    /// bitstreams produced.
+    pub(crate) fn encode_all(
+        encoder: &mut impl StatelessVideoEncoder<()>,
+        frames: impl IntoIterator<Item = FrameMetadata>,
+    ) -> Vec<CodedBitstreamBuffer> {
+        let mut coded = vec![];
+        for meta in frames {
+            encoder.encode(meta, ()).unwrap();
+            coded.extend(encoder.poll().unwrap());
+        }
+        coded.extend(collect_coded(encoder));
+        coded
+    }
+
+    pub(crate) fn frame_metadata(timestamp: u64) -> FrameMetadata {
+        let resolution = EncoderConfig::default().resolution;
+
+        FrameMetadata {
+            timestamp,
+            display_resolution: resolution,
+            layout: FrameLayout {
+                format: (Fourcc::from(b"NV12"), 0),
+                size: resolution,
+                planes: vec![],
+            },
+            force_keyframe: false,
+            damage: None,
+        }
+    }
+
+    /// Returns true if `bitstream` ends with an IDR slice and starts with an SPS.
+    fn is_idr_with_sps(bitstream: &[u8]) -> bool {
+        bitstream.last() == Some(&0x65) && bitstream[4] & 0x1f == 7
+    }
+
+    #[test]
+    fn segment_point() {
+        let mut encoder = StatelessEncoder::<(), _>::new(
+            DummyBackend::default(),
+            EncoderConfig::default(),
+            BlockingMode::Blocking,
+        )
+        .unwrap();
+
+        let mut coded = vec![];
+        for timestamp in 0..10 {
+            if timestamp == 4 || timestamp == 7 {
+                encoder.request_segment_point();
+            }
+            encoder.encode(frame_metadata(timestamp), ()).unwrap();
+            while let Some(buffer) = encoder.poll().unwrap() {
+                coded.push(buffer);
+            }
+        }
+        coded.extend(collect_coded(&mut encoder));
+
+        assert_eq!(coded.len(), 10);
+        for (timestamp, buffer) in coded.iter().enumerate() {
+            let segment_point = timestamp == 4 || timestamp == 7;
+            assert_eq!(buffer.metadata.timestamp, timestamp as u64);
+            assert_eq!(buffer.segment_point, segment_point);
+            assert_eq!(
+                is_idr_with_sps(&buffer.bitstream),
+                timestamp == 0 || segment_point
+            );
+        }
+    }
+
+    #[test]
+    fn frame_packing() {
+        let config = EncoderConfig {
+            frame_packing: Some(FramePacking::TopBottom),
+            ..Default::default()
+        };
+        let mut encoder =
+            StatelessEncoder::<(), _>::new(DummyBackend::default(), config, BlockingMode::Blocking)
+                .unwrap();
+        let coded = encode_all(&mut encoder, [frame_metadata(0)]).remove(0);
+
+        let mut cursor = std::io::Cursor::new(&coded.bitstream[..]);
+        let mut parser = Parser::default();
+        let sei = std::iter::from_fn(|| Nalu::next(&mut cursor).ok())
+            .find(|nalu| nalu.header.type_ == NaluType::Sei)
+            .map(|nalu| parser.parse_sei(&nalu).unwrap())
+            .unwrap();
+        let SeiMessage::FramePackingArrangement(fpa) = &sei.messages[0] else {
+            panic!("unexpected SEI message {:?}", sei.messages[0]);
+        };
+        assert_eq!(fpa.frame_packing_arrangement_type, 4);
+    }
+
+    #[test]
+    fn preset() {
+        let config = EncoderConfig::default().with_preset(Preset::Quality);
+        assert!(config.adaptive_quantization.is_some());
+
+        let mut encoder =
+            StatelessEncoder::<(), _>::new(DummyBackend::default(), config, BlockingMode::Blocking)
+                .unwrap();
+        let coded = encode_all(&mut encoder, [frame_metadata(0)]).remove(0);
+
+        let mut cursor = std::io::Cursor::new(&coded.bitstream[..]);
+        let mut parser = Parser::default();
+        let nalu = Nalu::next(&mut cursor).unwrap();
+        let sps = parser.parse_sps(&nalu).unwrap();
+        assert_eq!(sps.max_num_ref_frames, 5);
+    }
+
+    #[test]
+    fn dpb_size_limit() {
+        // 300 macroblocks frames, of which level 2 allows 7 in the DPB.
+        let config = EncoderConfig {
+            level: Level::L2_0,
+            pred_structure: PredictionStructure::LowDelay {
+                tail: 8,
+                limit: 2048,
+            },
+            ..Default::default()
+        };
+        let mut encoder =
+            StatelessEncoder::<(), _>::new(DummyBackend::default(), config, BlockingMode::Blocking)
+                .unwrap();
+        let coded = encode_all(&mut encoder, [frame_metadata(0)]).remove(0);
+
+        let mut cursor = std::io::Cursor::new(&coded.bitstream[..]);
+        let mut parser = Parser::default();
+        let nalu = Nalu::next(&mut cursor).unwrap();
+        let sps = parser.parse_sps(&nalu).unwrap();
+        assert_eq!(sps.max_num_ref_frames, 7);
+    }
+
+    #[test]
+    fn invalid_resolution() {
+        let config = EncoderConfig {
+            resolution: Resolution {
+                width: 0,
+                height: 240,
+            },
+            ..Default::default()
+        };
+        assert!(matches!(
+            StatelessEncoder::<(), _>::new(DummyBackend::default(), config, BlockingMode::Blocking),
+            Err(EncodeError::InvalidResolution { .. })
+        ));
+    }
+
+    #[test]
+    fn score_quality() {
+        let config = EncoderConfig {
+            score_quality: true,
+            ..Default::default()
+        };
+        let mut encoder =
+            StatelessEncoder::<(), _>::new(DummyBackend::default(), config, BlockingMode::Blocking)
+                .unwrap();
+
+        encode_all(&mut encoder, (0..4).map(frame_metadata));
+
+        // Every sample is off by 2, for a MSE of 4
+        let expected_psnr = 10.0 * (255.0f64 * 255.0 / 4.0).log10();
+        for timestamp in 0..4 {
+            let (score_timestamp, score) = encoder.poll_quality().unwrap();
+            assert_eq!(score_timestamp, timestamp);
+            assert!((score.psnr_frame - expected_psnr).abs() < 1e-9);
+            // Flat frames only differ in luminance
+            assert!(score.ssim_frame < 1.0);
+        }
+        assert!(encoder.poll_quality().is_none());
+    }
+
+    #[test]
+    fn tap_reconstructed() {
+        let config = EncoderConfig {
+            tap_reconstructed: true,
+            ..Default::default()
+        };
+        let mut encoder =
+            StatelessEncoder::<(), _>::new(DummyBackend::default(), config, BlockingMode::Blocking)
+                .unwrap();
+
+        encode_all(&mut encoder, (0..4).map(frame_metadata));
+
+        for timestamp in 0..4 {
+            let recon = encoder.poll_reconstructed().unwrap();
+            assert_eq!(recon.timestamp, timestamp);
+            assert_eq!(recon.frame_num, timestamp as u32);
+            assert_eq!(recon.data, gray_frame(130).0);
+        }
+        assert!(encoder.poll_reconstructed().is_none());
+        // Scoring was not requested
+        assert!(encoder.poll_quality().is_none());
+    }
+
+    #[test]
+    fn picture_hash() {
+        let config = EncoderConfig {
+            picture_hash: Some(PictureHashType::Md5),
+            ..Default::default()
+        };
+        let mut encoder =
+            StatelessEncoder::<(), _>::new(DummyBackend::default(), config, BlockingMode::Blocking)
+                .unwrap();
+
+        let coded = encode_all(&mut encoder, (0..3).map(frame_metadata));
+        assert_eq!(coded.len(), 3);
+
+        let (recon, layout) = gray_frame(130);
+        let expected = PictureHash::from_frame(PictureHashType::Md5, &recon, &layout).unwrap();
+        let mut parser = Parser::default();
+        for coded in coded {
+            let mut cursor = std::io::Cursor::new(&coded.bitstream[..]);
+            let nalus: Vec<_> = std::iter::from_fn(|| Nalu::next(&mut cursor).ok()).collect();
+
+            // The hash immediately precedes the slice.
+            let [.., sei, slice] = &nalus[..] else {
+                panic!("SEI and slice NAL units expected");
+            };
+            assert!(matches!(
+                slice.header.type_,
+                NaluType::Slice | NaluType::SliceIdr
+            ));
+            let sei = parser.parse_sei(sei).unwrap();
+            let SeiMessage::UserDataUnregistered(user_data) = &sei.messages[0] else {
+                panic!("user data unregistered SEI message expected");
+            };
+            assert_eq!(
+                user_data.uuid_iso_iec_11578,
+                picture_hash::H264_USER_DATA_UUID
+            );
+            assert_eq!(
+                PictureHash::from_payload(&user_data.payload).unwrap(),
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn capture_time() {
+        let config = EncoderConfig {
+            picture_hash: Some(PictureHashType::Crc),
+            capture_time: true,
+            ..Default::default()
+        };
+        let mut encoder =
+            StatelessEncoder::<(), _>::new(DummyBackend::default(), config, BlockingMode::Blocking)
+                .unwrap();
+
+        // The capture times are carried with a microsecond precision.
+        let before = SystemTime::now() - std::time::Duration::from_micros(1);
+        let coded = encode_all(&mut encoder, (0..3).map(frame_metadata));
+        let after = SystemTime::now();
+
+        assert_eq!(coded.len(), 3);
+        for coded in coded {
+            let capture_time = latency::capture_time(&coded.bitstream).unwrap();
+            assert!(before <= capture_time && capture_time <= after);
+
+            // The picture hash and the capture time share the SEI NAL unit preceding the slice.
+            let mut cursor = std::io::Cursor::new(&coded.bitstream[..]);
+            let nalus: Vec<_> = std::iter::from_fn(|| Nalu::next(&mut cursor).ok()).collect();
+            let [.., sei, _] = &nalus[..] else {
+                panic!("SEI and slice NAL units expected");
+            };
+            let sei = Parser::default().parse_sei(sei).unwrap();
+            assert_eq!(sei.messages.len(), 2);
+        }
+    }
+
+    #[test]
+    fn bitstream_filters() {
+        let config = EncoderConfig {
+            picture_hash: Some(PictureHashType::Crc),
+            ..Default::default()
+        };
+        let mut encoder =
+            StatelessEncoder::<(), _>::new(DummyBackend::default(), config, BlockingMode::Blocking)
+                .unwrap();
+        encoder.add_bitstream_filter(filter::strip_h264_nal_units(vec![NaluType::Sei]));
+        encoder.add_bitstream_filter(filter::insert_h264_access_unit_delimiter());
+
+        let coded = encode_all(&mut encoder, (0..2).map(frame_metadata));
+        assert_eq!(coded.len(), 2);
+        for coded in coded {
+            let types = coded
+                .h264_nal_units()
+                .map(|unit| unit.unit_type)
+                .collect::<Vec<_>>();
+            assert_eq!(types[0], Some(UnitType::H264(NaluType::AuDelimiter)));
+            assert!(!types.contains(&Some(UnitType::H264(NaluType::Sei))));
+        }
+
+        // Failing filters fail the polling.
+        let mut encoder = StatelessEncoder::<(), _>::new(
+            DummyBackend::default(),
+            Default::default(),
+            BlockingMode::Blocking,
+        )
+        .unwrap();
+        encoder.add_bitstream_filter(Box::new(|_| Err(anyhow!("filter failed"))));
+        encoder.encode(frame_metadata(0), ()).unwrap();
+        assert!(encoder.drain().is_err());
+    }
+
+    #[test]
+    fn sei_hashing_thread() {
+        let encode = |sei_hashing_thread, mode| {
+            let config = EncoderConfig {
+                picture_hash: Some(PictureHashType::Crc),
+                tap_reconstructed: true,
+                sei_hashing_thread,
+                ..Default::default()
+            };
+            let mut encoder =
+                StatelessEncoder::<(), _>::new(DummyBackend::default(), config, mode).unwrap();
+            let coded = encode_all(&mut encoder, (0..4).map(frame_metadata));
+
+            // The reconstructed frames are still handed to the user.
+            assert_eq!(
+                std::iter::from_fn(|| encoder.poll_reconstructed()).count(),
+                4
+            );
+            coded
+                .into_iter()
+                .map(|coded| (coded.metadata.timestamp, coded.bitstream))
+                .collect::<Vec<_>>()
+        };
+
+        let expected = encode(false, BlockingMode::Blocking);
+        assert_eq!(expected.len(), 4);
+        for mode in [BlockingMode::Blocking, BlockingMode::NonBlocking] {
+            assert_eq!(encode(true, mode), expected);
+        }
+
+        // There is nothing to package without the SEI messages.
+        let config = EncoderConfig {
+            sei_hashing_thread: true,
+            ..Default::default()
+        };
+        let encoder =
+            StatelessEncoder::<(), _>::new(DummyBackend::default(), config, BlockingMode::Blocking)
+                .unwrap();
+        assert!(encoder.sei_hashing_thread.is_none());
+    }
+
+    /// Returns parameter sets matching the default configuration, with short sequences.
+    fn parameter_sets(width: u32) -> Vec<u8> {
+        let sps = SpsBuilder::new()
+            .seq_parameter_set_id(3)
+            .profile_idc(Profile::Baseline)
+            .chroma_format_idc(1)
+            .level_idc(Level::L4)
+            .max_frame_num(16)
+            .pic_order_cnt_type(0)
+            .max_pic_order_cnt_lsb(64)
+            .max_num_ref_frames(2)
+            .frame_mbs_only_flag(true)
+            .resolution(width, 240)
+            .bit_depth_luma(8)
+            .bit_depth_chroma(8)
+            .build();
+
+        let pps = PpsBuilder::new(sps).pic_parameter_set_id(7).build();
+
+        let mut headers = vec![];
+        Synthesizer::<Sps, _>::synthesize(3, &pps.sps, &mut headers, true).unwrap();
+        Synthesizer::<Pps, _>::synthesize(3, &pps, &mut headers, true).unwrap();
+        headers
+    }
+
+    #[test]
+    fn external_parameter_sets() {
+        let headers = parameter_sets(320);
+        let config = EncoderConfig {
+            parameter_sets: Some(headers.clone()),
+            ..Default::default()
+        };
+        let mut encoder =
+            StatelessEncoder::<(), _>::new(DummyBackend::default(), config, BlockingMode::Blocking)
+                .unwrap();
+
+        let mut keyframes = vec![];
+        for coded in encode_all(&mut encoder, (0..20).map(frame_metadata)) {
+            if is_idr_with_sps(&coded.bitstream) {
+                // The provided parameter sets are passed through as is.
+                assert!(coded.bitstream.starts_with(&headers));
+                keyframes.push(coded.metadata.timestamp);
+            }
+        }
+        // The sequences are bounded by the frame numbers of the SPS.
+        assert_eq!(keyframes, [0, 16]);
+
+        let config = EncoderConfig {
+            parameter_sets: Some(parameter_sets(640)),
+            ..Default::default()
+        };
+        assert!(matches!(
+            StatelessEncoder::<(), _>::new(DummyBackend::default(), config, BlockingMode::Blocking),
+            Err(EncodeError::InvalidParameterSets(_))
+        ));
+
+        // The SPS does not signal the Constrained Baseline profile.
+        let config = EncoderConfig {
+            parameter_sets: Some(headers.clone()),
+            constrained_profile: true,
+            ..Default::default()
+        };
+        assert!(matches!(
+            StatelessEncoder::<(), _>::new(DummyBackend::default(), config, BlockingMode::Blocking),
+            Err(EncodeError::InvalidParameterSets(_))
+        ));
+
+        // The SPS has no room for the long-term reference.
+        let config = EncoderConfig {
+            parameter_sets: Some(headers),
+            long_term_references: 1,
+            ..Default::default()
+        };
+        assert!(matches!(
+            StatelessEncoder::<(), _>::new(DummyBackend::default(), config, BlockingMode::Blocking),
+            Err(EncodeError::InvalidParameterSets(_))
+        ));
+
+        // There is no PPS to parse.
+        let config = EncoderConfig {
+            parameter_sets: Some(vec![]),
+            ..Default::default()
+        };
+        assert!(matches!(
+            StatelessEncoder::<(), _>::new(DummyBackend::default(), config, BlockingMode::Blocking),
+            Err(EncodeError::InvalidParameterSets(_))
+        ));
+    }
+
+    #[test]
+    fn config_is_send() {
+        // Configurations can be prepared on another thread than the encoder's.
+        fn is_send<T: Send>(_: T) {}
+        is_send(EncoderConfig {
+            parameter_sets: Some(parameter_sets(320)),
+            ..Default::default()
+        });
+    }
+
+    #[test]
+    fn out_of_band_parameter_sets() {
+        let config = EncoderConfig {
+            out_of_band_parameter_sets: true,
+            ..Default::default()
+        };
+        let mut encoder =
+            StatelessEncoder::<(), _>::new(DummyBackend::default(), config, BlockingMode::Blocking)
+                .unwrap();
+        assert!(encoder.codec_config().is_none());
+
+        // Only the slices are inlined.
+        let coded = encode_all(&mut encoder, (0..2).map(frame_metadata));
+        assert_eq!(coded[0].bitstream[..], [0x00, 0x00, 0x00, 0x01, 0x65]);
+        assert_eq!(coded[1].bitstream[..], [0x00, 0x00, 0x00, 0x01, 0x41]);
+
+        let record =
+            AvcDecoderConfigurationRecord::from_annexb(encoder.codec_config().unwrap(), 4).unwrap();
+        assert_eq!(record.sps.len(), 1);
+        assert_eq!(record.pps.len(), 1);
+        assert_eq!(record.avc_profile_indication, Profile::Baseline as u8);
+    }
+
+    #[test]
+    fn parameter_set_interval() {
+        let config = EncoderConfig {
+            parameter_set_interval: Some(3),
+            ..Default::default()
+        };
+        let mut encoder =
+            StatelessEncoder::<(), _>::new(DummyBackend::default(), config, BlockingMode::Blocking)
+                .unwrap();
+
+        let mut with_parameter_sets = vec![];
+        for coded in encode_all(&mut encoder, (0..8).map(frame_metadata)) {
+            if coded.bitstream[4] & 0x1f == 7 {
+                with_parameter_sets.push(coded.metadata.timestamp);
+                // The parameter sets precede the slice of the frame.
+                let slice = if coded.metadata.timestamp == 0 {
+                    0x65
+                } else {
+                    0x41
+                };
+                assert_eq!(coded.bitstream.last(), Some(&slice));
+            }
+        }
+        assert_eq!(with_parameter_sets, [0, 3, 6]);
+    }
+
+    #[test]
+    fn drain_slow_backend() {
+        for tail in 1..4 {
+            let config = EncoderConfig {
+                pred_structure: PredictionStructure::LowDelay { tail, limit: 16 },
+                ..Default::default()
+            };
+            let mut encoder = StatelessEncoder::<(), _>::new(
+                DummyBackend {
+                    slow: true,
+                    ..Default::default()
+                },
+                config,
+                BlockingMode::NonBlocking,
+            )
+            .unwrap();
+
+            let mut coded = vec![];
+            for timestamp in 0..40 {
+                encoder.encode(frame_metadata(timestamp), ()).unwrap();
+                coded.extend(encoder.poll().unwrap());
+                if timestamp % 13 == 12 {
+                    encoder.drain().unwrap();
+                }
+            }
+            coded.extend(collect_coded(&mut encoder));
+
+            assert_eq!(coded.len(), 40);
+            for (timestamp, buffer) in coded.iter().enumerate() {
+                assert_eq!(buffer.metadata.timestamp, timestamp as u64);
+                assert_eq!(is_idr_with_sps(&buffer.bitstream), timestamp % 16 == 0);
+            }
+        }
+    }
+
+    #[test]
+    fn out_of_order_output() {
+        let config = EncoderConfig {
+            out_of_order_output: true,
+            ..Default::default()
+        };
+        let mut encoder = StatelessEncoder::<(), _>::new(
+            DummyBackend {
+                slow: true,
+                ..Default::default()
+            },
+            config,
+            BlockingMode::NonBlocking,
+        )
+        .unwrap();
+
+        let mut timestamps = encode_all(&mut encoder, (0..40).map(frame_metadata))
+            .iter()
+            .map(|coded| coded.metadata.timestamp)
+            .collect::<Vec<_>>();
+
+        // The frames completed early are not held back by the older ones.
+        assert!(timestamps.windows(2).any(|pair| pair[0] > pair[1]));
+        timestamps.sort();
+        assert_eq!(timestamps, (0..40).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn flush() {
+        let mut encoder = StatelessEncoder::<(), _>::new(
+            DummyBackend {
+                slow: true,
+                ..Default::default()
+            },
+            EncoderConfig::default(),
+            BlockingMode::NonBlocking,
+        )
+        .unwrap();
+
+        // The first frame is submitted, the others wait for its reconstruction.
+        for timestamp in 0..5 {
+            encoder.encode(frame_metadata(timestamp), ()).unwrap();
+        }
+        let flushed = encoder
+            .flush()
+            .unwrap()
+            .iter()
+            .map(|(meta, ())| meta.timestamp)
+            .collect::<Vec<_>>();
+        assert_eq!(flushed, [1, 2, 3, 4]);
+        assert!(encoder.poll().unwrap().is_none());
+
+        let coded = encode_all(&mut encoder, [frame_metadata(5)]);
+        assert_eq!(coded.len(), 1);
+        assert_eq!(coded[0].metadata.timestamp, 5);
+        assert!(is_idr_with_sps(&coded[0].bitstream));
+    }
+
+    #[test]
+    fn max_in_flight() {
+        let config = EncoderConfig {
+            max_in_flight: Some(2),
+            ..Default::default()
+        };
+        let mut encoder = StatelessEncoder::<(), _>::new(
+            DummyBackend {
+                slow: true,
+                ..Default::default()
+            },
+            config,
+            BlockingMode::NonBlocking,
+        )
+        .unwrap();
+
+        let mut coded = vec![];
+        let mut dropped = vec![];
+        for timestamp in 0..20 {
+            match encoder.encode(frame_metadata(timestamp), ()) {
+                Ok(()) => (),
+                Err(EncodeError::WouldBlock) => dropped.push(timestamp),
+                Err(e) => panic!("{}", e),
+            }
+            assert!(encoder.frames_in_flight() <= 2);
+            coded.extend(encoder.poll().unwrap());
+        }
+        coded.extend(collect_coded(&mut encoder));
+
+        assert!(!dropped.is_empty());
+        assert_eq!(coded.len() + dropped.len(), 20);
+        assert!(coded
+            .iter()
+            .all(|buffer| !dropped.contains(&buffer.metadata.timestamp)));
+    }
+
+    #[test]
+    fn try_encode() {
+        let config = EncoderConfig {
+            max_in_flight: Some(2),
+            ..Default::default()
+        };
+        let backend = DummyBackend {
+            slow: true,
+            ..Default::default()
+        };
+        let mut encoder =
+            StatelessEncoder::<(), _>::new(backend, config, BlockingMode::NonBlocking).unwrap();
+
+        // The frames handed back are submitted again once some output is polled.
+        let mut coded = vec![];
+        let mut retries = 0;
+        for timestamp in 0..20 {
+            let mut frame = frame_metadata(timestamp);
+            while let Err(error) = encoder.try_encode(frame, ()) {
+                let TryEncodeError::WouldBlock(meta, ()) = error else {
+                    panic!("{}", error);
+                };
+                assert_eq!(meta.timestamp, timestamp);
+                frame = meta;
+                retries += 1;
+                coded.extend(encoder.poll().unwrap());
+            }
+            assert!(encoder.frames_in_flight() <= 2);
+        }
+        coded.extend(collect_coded(&mut encoder));
+
+        assert!(retries > 0);
+        let timestamps = coded
+            .iter()
+            .map(|buffer| buffer.metadata.timestamp)
+            .collect::<Vec<_>>();
+        assert_eq!(timestamps, (0..20).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn structural_delay() {
+        let encoder = StatelessEncoder::<(), _>::new(
+            DummyBackend::default(),
+            EncoderConfig::default(),
+            BlockingMode::Blocking,
+        )
+        .unwrap();
+        assert_eq!(encoder.structural_delay(), 0);
+
+        // The encoder could never output a frame.
+        let config = EncoderConfig {
+            max_in_flight: Some(0),
+            ..Default::default()
+        };
+        assert!(matches!(
+            StatelessEncoder::<(), _>::new(DummyBackend::default(), config, BlockingMode::Blocking),
+            Err(EncodeError::InvalidPredictionStructure(_))
+        ));
+    }
+
+    #[test]
+    fn long_term_references_bitstream() {
+        let config = EncoderConfig {
+            long_term_references: 2,
+            ..Default::default()
+        };
+        let backend = DummyBackend {
+            slice_headers: true,
+            ..Default::default()
+        };
+        let mut encoder =
+            StatelessEncoder::<(), _>::new(backend, config, BlockingMode::Blocking).unwrap();
+
+        for timestamp in 0..12 {
+            match timestamp {
+                1 => encoder.mark_long_term_reference(0).unwrap(),
+                4 => encoder.mark_long_term_reference(1).unwrap(),
+                7 => encoder.encode_recovery_frame(1).unwrap(),
+                9 => encoder.encode_recovery_frame(0).unwrap(),
+                _ => (),
+            }
+            encoder.encode(frame_metadata(timestamp), ()).unwrap();
+        }
+        let stream = collect_coded(&mut encoder)
+            .into_iter()
+            .flat_map(|buffer| buffer.bitstream)
+            .collect::<Vec<_>>();
+
+        // The decoder follows the marking and finds the references of each frame.
+        let mut decoder = StatelessDecoder::<crate::decoder::stateless::h264::H264, _>::new_dummy(
+            BlockingMode::Blocking,
+        );
+        let mut frames = 0;
+        simple_playback_loop(
+            &mut decoder,
+            NalIterator::<Nalu>::new(&stream),
+            &mut |_| frames += 1,
+            &mut simple_playback_loop_owned_frames,
+            DecodedFormat::NV12,
+            BlockingMode::Blocking,
+        )
+        .unwrap();
+        assert_eq!(frames, 12);
+
+        let mut parser = Parser::default();
+        let mut slices = vec![];
+        let mut cursor = std::io::Cursor::new(&stream[..]);
+        while let Ok(nalu) = Nalu::next(&mut cursor) {
+            match nalu.header.type_ {
+                NaluType::Sps => drop(parser.parse_sps(&nalu).unwrap()),
+                NaluType::Pps => drop(parser.parse_pps(&nalu).unwrap()),
+                _ => slices.push(parser.parse_slice_header(nalu).unwrap().header),
+            }
+        }
+
+        let long_term_frame_idx = |header: &SliceHeader| {
+            header
+                .dec_ref_pic_marking
+                .inner
+                .iter()
+                .find(|marking| marking.memory_management_control_operation == 6)
+                .map(|marking| marking.long_term_frame_idx)
+        };
+        let long_term_references = |header: &SliceHeader| {
+            header
+                .ref_pic_list_modification_l0
+                .iter()
+                .filter(|modification| modification.modification_of_pic_nums_idc == 2)
+                .map(|modification| modification.long_term_pic_num)
+                .collect::<Vec<_>>()
+        };
+        let marked = slices.iter().map(long_term_frame_idx).collect::<Vec<_>>();
+        let referenced = slices.iter().map(long_term_references).collect::<Vec<_>>();
+        assert_eq!(
+            marked,
+            [
+                None,
+                Some(0),
+                None,
+                None,
+                Some(1),
+                None,
+                None,
+                None,
+                None,
+                None,
+                None,
+                None
+            ]
+        );
+        // The frames following the long-term references and the recovery frames reference them.
+        assert_eq!(
+            referenced,
+            [
+                vec![],
+                vec![],
+                vec![0],
+                vec![],
+                vec![],
+                vec![1],
+                vec![],
+                vec![1],
+                vec![],
+                vec![0],
+                vec![],
+                vec![],
+            ]
+        );
+    }
+
+    #[test]
+    fn out_of_resources_keeps_requests() {
+        let backend = DummyBackend {
+            exhausted: vec![2, 5],
+            ..Default::default()
+        };
+        let mut encoder = StatelessEncoder::<(), _>::new(
+            backend,
+            EncoderConfig::default(),
+            BlockingMode::Blocking,
+        )
+        .unwrap();
+
+        let mut coded = vec![];
+        for timestamp in 0..8 {
+            match timestamp {
+                2 => encoder.request_segment_point(),
+                5 => encoder.request_keyframe(),
+                _ => (),
+            }
+            match encoder.try_encode(frame_metadata(timestamp), ()) {
+                Ok(()) => (),
+                Err(TryEncodeError::WouldBlock(meta, ())) => {
+                    assert_eq!(meta.timestamp, timestamp);
+                    assert!(!meta.force_keyframe);
+                    continue;
+                }
+                Err(e) => panic!("{}", e),
+            }
+            while let Some(buffer) = encoder.poll().unwrap() {
+                coded.push(buffer);
+            }
+        }
+        coded.extend(collect_coded(&mut encoder));
+
+        // The requests made for the dropped frames apply to the next accepted ones.
+        let coded = coded
+            .iter()
+            .map(|buffer| {
+                (
+                    buffer.metadata.timestamp,
+                    buffer.segment_point,
+                    is_idr_with_sps(&buffer.bitstream),
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            coded,
+            [
+                (0, false, true),
+                (1, false, false),
+                (3, true, true),
+                (4, false, false),
+                (6, false, true),
+                (7, false, false),
+            ]
+        );
+    }
+
+    #[test]
+    fn repeated_timestamps() {
+        let config = EncoderConfig {
+            picture_hash: Some(PictureHashType::Crc),
+            capture_time: true,
+            ..Default::default()
+        };
+        let mut encoder =
+            StatelessEncoder::<(), _>::new(DummyBackend::default(), config, BlockingMode::Blocking)
+                .unwrap();
+
+        // Without a timestamp policy, the timestamps are passed through unchecked.
+        for frame in 0..4 {
+            if frame == 2 {
+                encoder.request_segment_point();
+            }
+            encoder.encode(frame_metadata(7), ()).unwrap();
+        }
+
+        let coded = collect_coded(&mut encoder);
+        assert_eq!(coded.len(), 4);
+        for (frame, coded) in coded.into_iter().enumerate() {
+            assert_eq!(coded.segment_point, frame == 2);
+            assert!(latency::capture_time(&coded.bitstream).is_some());
+
+            // Each frame carries its own picture hash next to its capture time.
+            let mut cursor = std::io::Cursor::new(&coded.bitstream[..]);
+            let nalus: Vec<_> = std::iter::from_fn(|| Nalu::next(&mut cursor).ok()).collect();
+            let [.., sei, _] = &nalus[..] else {
+                panic!("SEI and slice NAL units expected");
+            };
+            let sei = Parser::default().parse_sei(sei).unwrap();
+            assert_eq!(sei.messages.len(), 2);
+        }
+    }
+
+    #[test]
+    fn encode_batch() {
+        let config = EncoderConfig {
+            max_in_flight: Some(3),
+            timestamp_policy: Some(TimestampPolicy::Error),
+            ..Default::default()
+        };
+        let backend = DummyBackend {
+            chained: true,
+            ..Default::default()
+        };
+        let mut encoder =
+            StatelessEncoder::<(), _>::new(backend, config, BlockingMode::Blocking).unwrap();
+        let batch = |timestamps: &[u64]| {
+            timestamps
+                .iter()
+                .map(|&timestamp| (frame_metadata(timestamp), ()))
+                .collect()
+        };
+        let poll_timestamps = |encoder: &mut StatelessEncoder<(), DummyBackend>| {
+            collect_coded(encoder)
+                .iter()
+                .map(|coded| coded.metadata.timestamp)
+                .collect::<Vec<_>>()
+        };
+
+        // The batch does not fit as a whole.
+        assert_eq!(encoder.capacity().unwrap(), Some(3));
+        assert!(matches!(
+            encoder.encode_batch(batch(&[0, 1, 2, 3])),
+            Err(BatchEncodeError {
+                accepted: 0,
+                error: EncodeError::WouldBlock
+            })
+        ));
+        assert_eq!(encoder.frames_in_flight(), 0);
+
+        // The interframes reference the preceding frames of the batch, submitted along with them.
+        encoder.encode_batch(batch(&[0, 1, 2])).unwrap();
+        assert_eq!(encoder.backend.batches, [3]);
+        assert_eq!(poll_timestamps(&mut encoder), [0, 1, 2]);
+
+        // The frames preceding the failing one are encoded.
+        assert!(matches!(
+            encoder.encode_batch(batch(&[5, 4, 6])),
+            Err(BatchEncodeError {
+                accepted: 1,
+                error: EncodeError::NonMonotonicTimestamp { .. }
+            })
+        ));
+        assert_eq!(poll_timestamps(&mut encoder), [5]);
+        assert_eq!(encoder.backend.batches, [3, 1]);
+
+        // Without reconstructed pictures allocated ahead, the frames wait for their references.
+        let mut encoder = StatelessEncoder::<(), _>::new(
+            DummyBackend::default(),
+            EncoderConfig::default(),
+            BlockingMode::Blocking,
+        )
+        .unwrap();
+        encoder.encode_batch(batch(&[0, 1, 2])).unwrap();
+        assert_eq!(poll_timestamps(&mut encoder), [0, 1, 2]);
+        assert_eq!(encoder.backend.batches, [1, 1, 1]);
+    }
+
+    #[test]
+    fn cancel() {
+        let mut encoder = StatelessEncoder::<(), _>::new(
+            DummyBackend {
+                slow: true,
+                ..Default::default()
+            },
+            EncoderConfig::default(),
+            BlockingMode::NonBlocking,
+        )
+        .unwrap();
+
+        // The first frame is submitted, the others wait for its reconstruction.
+        for timestamp in 0..4 {
+            if timestamp == 2 {
+                encoder.request_segment_point();
+            }
+            encoder.encode(frame_metadata(timestamp), ()).unwrap();
+        }
+        assert!(encoder.cancel(0).is_none());
+        let (meta, ()) = encoder.cancel(2).unwrap();
+        assert_eq!(meta.timestamp, 2);
+        assert!(encoder.cancel(2).is_none());
+
+        // The segment point moves to the next frame.
+        let coded = encode_all(&mut encoder, [frame_metadata(4)])
+            .iter()
+            .map(|buffer| (buffer.metadata.timestamp, buffer.segment_point))
+            .collect::<Vec<_>>();
+        assert_eq!(coded, [(0, false), (1, false), (3, false), (4, true)]);
+    }
+
+    #[test]
+    fn pause() {
+        for force_idr_on_resume in [false, true] {
+            let config = EncoderConfig {
+                force_idr_on_resume,
+                ..Default::default()
+            };
+            let mut encoder = StatelessEncoder::<(), _>::new(
+                DummyBackend::default(),
+                config,
+                BlockingMode::Blocking,
+            )
+            .unwrap();
+
+            encoder.encode(frame_metadata(0), ()).unwrap();
+            encoder.encode(frame_metadata(1), ()).unwrap();
+            encoder.pause().unwrap();
+            assert!(matches!(
+                encoder.encode(frame_metadata(2), ()),
+                Err(EncodeError::Paused)
+            ));
+            encoder.resume();
+            let coded = encode_all(&mut encoder, [frame_metadata(3)])
+                .iter()
+                .map(|buffer| {
+                    (
+                        buffer.metadata.timestamp,
+                        is_idr_with_sps(&buffer.bitstream),
+                    )
+                })
+                .collect::<Vec<_>>();
+            assert_eq!(coded, [(0, true), (1, false), (3, force_idr_on_resume)]);
+        }
+    }
+
+    #[test]
+    fn skip_duplicate_frames() {
+        let config = EncoderConfig {
+            skip_duplicate_frames: true,
+            ..Default::default()
+        };
+        let mut encoder =
+            StatelessEncoder::<(), _>::new(DummyBackend::default(), config, BlockingMode::Blocking)
+                .unwrap();
+
+        // All the frames of the dummy backend are identical.
+        let frames = (0..4).map(|timestamp| FrameMetadata {
+            force_keyframe: timestamp == 2,
+            ..frame_metadata(timestamp)
+        });
+        let mut coded = vec![];
+        for buffer in encode_all(&mut encoder, frames) {
+            assert_eq!(buffer.skipped, buffer.bitstream.is_empty());
+            coded.push((buffer.metadata.timestamp, buffer.skipped));
+        }
+        assert_eq!(coded, [(0, false), (1, true), (2, false), (3, true)]);
+    }
+
+    #[test]
+    fn skip_duplicate_frames_readback_failure() {
+        let config = EncoderConfig {
+            skip_duplicate_frames: true,
+            ..Default::default()
+        };
+        let backend = DummyBackend {
+            unreadable: true,
+            read_failures: std::cell::Cell::new(1),
+            ..Default::default()
+        };
+        let mut encoder =
+            StatelessEncoder::<(), _>::new(backend, config, BlockingMode::Blocking).unwrap();
+
+        // The frame which failed to be read back is not committed, and the segment point moves
+        // to the next frame.
+        encoder.request_segment_point();
+        encoder.encode(frame_metadata(0), ()).unwrap_err();
+        let coded = encode_all(&mut encoder, [frame_metadata(1)]);
+        assert_eq!(coded.len(), 1);
+        assert_eq!(coded[0].metadata.timestamp, 1);
+        assert!(coded[0].segment_point);
+        assert!(!coded[0].skipped);
+    }
+
+    #[test]
+    fn timestamp_policy() {
+        let timestamps = [0, 10, 10, 5, 20];
+        let encode = |policy| {
+            let config = EncoderConfig {
+                timestamp_policy: Some(policy),
+                ..Default::default()
+            };
+            let mut encoder = StatelessEncoder::<(), _>::new(
+                DummyBackend::default(),
+                config,
+                BlockingMode::Blocking,
+            )
+            .unwrap();
+
+            let mut results = vec![];
+            for timestamp in timestamps {
+                results.push(encoder.encode(frame_metadata(timestamp), ()).map_err(|e| {
+                    assert!(matches!(e, EncodeError::NonMonotonicTimestamp { .. }));
+                }));
+            }
+            let coded = collect_coded(&mut encoder)
+                .iter()
+                .map(|buffer| buffer.metadata.timestamp)
+                .collect::<Vec<_>>();
+            (results, coded)
+        };
+
+        let (results, coded) = encode(TimestampPolicy::Error);
+        assert_eq!(results, [Ok(()), Ok(()), Err(()), Err(()), Ok(())]);
+        assert_eq!(coded, [0, 10, 20]);
+
+        let (_, coded) = encode(TimestampPolicy::Clamp);
+        assert_eq!(coded, [0, 10, 11, 12, 20]);
+
+        let (_, coded) = encode(TimestampPolicy::Renumber);
+        assert_eq!(coded, [0, 10, 11, 12, 27]);
+    }
+
+    #[test]
+    fn layer_statistics() {
+        let config = EncoderConfig {
+            bitrate: Bitrate::PerLayer(vec![1_000_000, 1_000_000]),
+            ..Default::default()
+        };
+        let mut encoder =
+            StatelessEncoder::<(), _>::new(DummyBackend::default(), config, BlockingMode::Blocking)
+                .unwrap();
+
+        let mut layers = vec![];
+        let mut bytes = [0; 2];
+        for buffer in encode_all(&mut encoder, (0..5).map(frame_metadata)) {
+            layers.push(buffer.temporal_layer);
+            bytes[buffer.temporal_layer as usize] += buffer.bitstream.len() as u64;
+        }
+        assert_eq!(layers, [0, 1, 0, 1, 0]);
+
+        let stats = encoder.layer_statistics();
+        assert_eq!(stats[0].frames, 3);
+        assert_eq!(stats[1].frames, 2);
+        assert_eq!(stats[0].bytes, bytes[0]);
+        assert_eq!(stats[1].bytes, bytes[1]);
+    }
+
+    #[test]
+    fn snapshot() {
+        let config = EncoderConfig {
+            timestamp_policy: Some(TimestampPolicy::Error),
+            out_of_band_parameter_sets: true,
+            ..Default::default()
+        };
+        let mut encoder = StatelessEncoder::<(), _>::new(
+            DummyBackend::default(),
+            config.clone(),
+            BlockingMode::Blocking,
+        )
+        .unwrap();
+        encode_all(&mut encoder, (0..5).map(frame_metadata));
+
+        let snapshot = EncoderSnapshot::from_bytes(&encoder.snapshot().to_bytes()).unwrap();
+        assert_eq!(snapshot, encoder.snapshot());
+        let bytes = snapshot.to_bytes();
+        assert!(EncoderSnapshot::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+        assert!(matches!(
+            encoder.restore(&snapshot),
+            Err(EncodeError::InvalidSnapshot(_))
+        ));
+
+        let config = EncoderConfig {
+            parameter_sets: snapshot.codec_config.clone(),
+            ..config
+        };
+        let mut encoder =
+            StatelessEncoder::<(), _>::new(DummyBackend::default(), config, BlockingMode::Blocking)
+                .unwrap();
+        encoder.restore(&snapshot).unwrap();
+
+        // The timestamps must follow the ones of the previous encoder.
+        assert!(matches!(
+            encoder.encode(frame_metadata(4), ()),
+            Err(EncodeError::NonMonotonicTimestamp { .. })
+        ));
+        let coded = encode_all(&mut encoder, (5..8).map(frame_metadata));
+
+        // The stream resumes with an IDR of the same codec configuration.
+        assert_eq!(coded[0].bitstream.last(), Some(&0x65));
+        assert_eq!(encoder.codec_config(), snapshot.codec_config.as_deref());
+        assert_eq!(encoder.layer_statistics()[0].frames, 8);
+    }
+
+    #[test]
+    fn min_keyframe_interval() {
+        let config = EncoderConfig {
+            min_keyframe_interval: Some(4),
+            ..Default::default()
+        };
+        let mut encoder =
+            StatelessEncoder::<(), _>::new(DummyBackend::default(), config, BlockingMode::Blocking)
+                .unwrap();
+
+        for timestamp in 0..10 {
+            match timestamp {
+                // A burst of requests is coalesced in a single keyframe.
+                1 | 2 => encoder.request_keyframe(),
+                // Segment points are not deferred.
+                7 => encoder.request_segment_point(),
+                _ => (),
+            }
+            let mut meta = frame_metadata(timestamp);
+            meta.force_keyframe = timestamp == 6;
+            encoder.encode(meta, ()).unwrap();
+        }
+
+        let mut keyframes = vec![];
+        for buffer in collect_coded(&mut encoder) {
+            if is_idr_with_sps(&buffer.bitstream) {
+                keyframes.push(buffer.metadata.timestamp);
+            }
+        }
+        assert_eq!(keyframes, [0, 4, 7]);
+    }
+
+    #[test]
+    fn skip_undamaged_frames() {
+        let damage = |rects: usize| {
+            Some(vec![
+                Rect {
+                    min: Point { x: 0, y: 0 },
+                    max: Point { x: 16, y: 16 },
+                };
+                rects
+            ])
+        };
+        let damages = [damage(0), damage(1), damage(0), damage(0), None, damage(0)];
+
+        for mode in [BlockingMode::Blocking, BlockingMode::NonBlocking] {
+            let config = EncoderConfig {
+                skip_duplicate_frames: true,
+                ..Default::default()
+            };
+            // The frames cannot be read back, only the damage tells duplicates apart.
+            let backend = DummyBackend {
+                slow: true,
+                unreadable: true,
+                ..Default::default()
+            };
+            let mut encoder = StatelessEncoder::<(), _>::new(backend, config, mode).unwrap();
+
+            // The duplicates arrive while the predictor holds the frames preceding them, and are
+            // output after them.
+            for (timestamp, damage) in damages.iter().cloned().enumerate() {
+                let meta = FrameMetadata {
+                    damage,
+                    ..frame_metadata(timestamp as u64)
+                };
+                encoder.encode(meta, ()).unwrap();
+            }
+            let coded = collect_coded(&mut encoder)
+                .iter()
+                .map(|buffer| (buffer.metadata.timestamp, buffer.skipped))
+                .collect::<Vec<_>>();
+            assert_eq!(
+                coded,
+                [
+                    (0, false),
+                    (1, false),
+                    (2, true),
+                    (3, true),
+                    (4, false),
+                    (5, true)
+                ]
+            );
+        }
+    }
+
+    #[test]
+    fn cancel_before_skipped_frames() {
+        let config = EncoderConfig {
+            skip_duplicate_frames: true,
+            ..Default::default()
+        };
+        let backend = DummyBackend {
+            slow: true,
+            unreadable: true,
+            ..Default::default()
+        };
+        let mut encoder =
+            StatelessEncoder::<(), _>::new(backend, config, BlockingMode::NonBlocking).unwrap();
+
+        // The first frame is submitted, the second waits for its reconstruction and the third is
+        // skipped after the second.
+        for (timestamp, rects) in [1, 1, 0].into_iter().enumerate() {
+            let meta = FrameMetadata {
+                damage: Some(vec![Rect::default(); rects]),
+                ..frame_metadata(timestamp as u64)
+            };
+            encoder.encode(meta, ()).unwrap();
+        }
+        encoder.cancel(1).unwrap();
+
+        // The skipped frame is still output, after the frame preceding the cancelled one.
+        let coded = collect_coded(&mut encoder)
+            .iter()
+            .map(|buffer| (buffer.metadata.timestamp, buffer.skipped))
+            .collect::<Vec<_>>();
+        assert_eq!(coded, [(0, false), (2, true)]);
     }
 }
@@ -0,0 +1,997 @@
+// Copyright 2024 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+use std::any::Any;
+use std::borrow::Borrow;
+use std::mem::size_of;
+use std::mem::ManuallyDrop;
+use std::rc::Rc;
+
+use windows::Win32::Graphics::Direct3D12::ID3D12Device;
+use windows::Win32::Graphics::Direct3D12::ID3D12Resource;
+use windows::Win32::Graphics::Direct3D12::D3D12_RESOURCE_FLAG_DENY_SHADER_RESOURCE;
+use windows::Win32::Graphics::Direct3D12::D3D12_RESOURCE_FLAG_NONE;
+use windows::Win32::Graphics::Direct3D12::D3D12_RESOURCE_FLAG_VIDEO_ENCODE_REFERENCE_ONLY;
+use windows::Win32::Graphics::Direct3D12::D3D12_RESOURCE_STATE_COMMON;
+use windows::Win32::Graphics::Direct3D12::D3D12_RESOURCE_STATE_VIDEO_ENCODE_READ;
+use windows::Win32::Graphics::Direct3D12::D3D12_RESOURCE_STATE_VIDEO_ENCODE_WRITE;
+use windows::Win32::Graphics::Dxgi::Common::DXGI_FORMAT_NV12;
+use windows::Win32::Graphics::Dxgi::Common::DXGI_RATIONAL;
+use windows::Win32::Media::MediaFoundation::D3D12_VIDEO_ENCODER_CODEC_CONFIGURATION_H264_FLAG_USE_ADAPTIVE_8x8_TRANSFORM;
+use windows::Win32::Media::MediaFoundation::D3D12_VIDEO_ENCODER_LEVELS_H264_1b;
+use windows::Win32::Media::MediaFoundation::ID3D12VideoEncoder;
+use windows::Win32::Media::MediaFoundation::ID3D12VideoEncoderHeap;
+use windows::Win32::Media::MediaFoundation::D3D12_FEATURE_DATA_VIDEO_ENCODER_RESOLUTION_SUPPORT_LIMITS;
+use windows::Win32::Media::MediaFoundation::D3D12_FEATURE_DATA_VIDEO_ENCODER_RESOURCE_REQUIREMENTS;
+use windows::Win32::Media::MediaFoundation::D3D12_FEATURE_DATA_VIDEO_ENCODER_SUPPORT;
+use windows::Win32::Media::MediaFoundation::D3D12_FEATURE_VIDEO_ENCODER_RESOURCE_REQUIREMENTS;
+use windows::Win32::Media::MediaFoundation::D3D12_FEATURE_VIDEO_ENCODER_SUPPORT;
+use windows::Win32::Media::MediaFoundation::D3D12_VIDEO_ENCODER_CODEC_CONFIGURATION;
+use windows::Win32::Media::MediaFoundation::D3D12_VIDEO_ENCODER_CODEC_CONFIGURATION_0;
+use windows::Win32::Media::MediaFoundation::D3D12_VIDEO_ENCODER_CODEC_CONFIGURATION_H264;
+use windows::Win32::Media::MediaFoundation::D3D12_VIDEO_ENCODER_CODEC_CONFIGURATION_H264_DIRECT_MODES_DISABLED;
+use windows::Win32::Media::MediaFoundation::D3D12_VIDEO_ENCODER_CODEC_CONFIGURATION_H264_FLAG_ENABLE_CABAC_ENCODING;
+use windows::Win32::Media::MediaFoundation::D3D12_VIDEO_ENCODER_CODEC_CONFIGURATION_H264_FLAG_NONE;
+use windows::Win32::Media::MediaFoundation::D3D12_VIDEO_ENCODER_CODEC_CONFIGURATION_H264_FLAG_USE_CONSTRAINED_INTRAPREDICTION;
+use windows::Win32::Media::MediaFoundation::D3D12_VIDEO_ENCODER_CODEC_CONFIGURATION_H264_SLICES_DEBLOCKING_MODE_0_ALL_LUMA_CHROMA_SLICE_BLOCK_EDGES_ALWAYS_FILTERED;
+use windows::Win32::Media::MediaFoundation::D3D12_VIDEO_ENCODER_CODEC_CONFIGURATION_H264_SLICES_DEBLOCKING_MODE_1_DISABLE_ALL_SLICE_BLOCK_EDGES;
+use windows::Win32::Media::MediaFoundation::D3D12_VIDEO_ENCODER_CODEC_CONFIGURATION_H264_SLICES_DEBLOCKING_MODE_2_DISABLE_SLICE_BOUNDARIES_BLOCKS;
+use windows::Win32::Media::MediaFoundation::D3D12_VIDEO_ENCODER_CODEC_H264;
+use windows::Win32::Media::MediaFoundation::D3D12_VIDEO_ENCODER_COMPRESSED_BITSTREAM;
+use windows::Win32::Media::MediaFoundation::D3D12_VIDEO_ENCODER_DESC;
+use windows::Win32::Media::MediaFoundation::D3D12_VIDEO_ENCODER_ENCODEFRAME_INPUT_ARGUMENTS;
+use windows::Win32::Media::MediaFoundation::D3D12_VIDEO_ENCODER_ENCODEFRAME_OUTPUT_ARGUMENTS;
+use windows::Win32::Media::MediaFoundation::D3D12_VIDEO_ENCODER_ENCODE_OPERATION_METADATA_BUFFER;
+use windows::Win32::Media::MediaFoundation::D3D12_VIDEO_ENCODER_FLAG_NONE;
+use windows::Win32::Media::MediaFoundation::D3D12_VIDEO_ENCODER_FRAME_SUBREGION_LAYOUT_MODE_FULL_FRAME;
+use windows::Win32::Media::MediaFoundation::D3D12_VIDEO_ENCODER_FRAME_SUBREGION_METADATA;
+use windows::Win32::Media::MediaFoundation::D3D12_VIDEO_ENCODER_FRAME_TYPE_H264_B_FRAME;
+use windows::Win32::Media::MediaFoundation::D3D12_VIDEO_ENCODER_FRAME_TYPE_H264_IDR_FRAME;
+use windows::Win32::Media::MediaFoundation::D3D12_VIDEO_ENCODER_FRAME_TYPE_H264_I_FRAME;
+use windows::Win32::Media::MediaFoundation::D3D12_VIDEO_ENCODER_FRAME_TYPE_H264_P_FRAME;
+use windows::Win32::Media::MediaFoundation::D3D12_VIDEO_ENCODER_HEAP_DESC;
+use windows::Win32::Media::MediaFoundation::D3D12_VIDEO_ENCODER_HEAP_FLAG_NONE;
+use windows::Win32::Media::MediaFoundation::D3D12_VIDEO_ENCODER_INTRA_REFRESH;
+use windows::Win32::Media::MediaFoundation::D3D12_VIDEO_ENCODER_INTRA_REFRESH_MODE_NONE;
+use windows::Win32::Media::MediaFoundation::D3D12_VIDEO_ENCODER_LEVELS_H264;
+use windows::Win32::Media::MediaFoundation::D3D12_VIDEO_ENCODER_LEVELS_H264_1;
+use windows::Win32::Media::MediaFoundation::D3D12_VIDEO_ENCODER_LEVELS_H264_11;
+use windows::Win32::Media::MediaFoundation::D3D12_VIDEO_ENCODER_LEVELS_H264_12;
+use windows::Win32::Media::MediaFoundation::D3D12_VIDEO_ENCODER_LEVELS_H264_13;
+use windows::Win32::Media::MediaFoundation::D3D12_VIDEO_ENCODER_LEVELS_H264_2;
+use windows::Win32::Media::MediaFoundation::D3D12_VIDEO_ENCODER_LEVELS_H264_21;
+use windows::Win32::Media::MediaFoundation::D3D12_VIDEO_ENCODER_LEVELS_H264_22;
+use windows::Win32::Media::MediaFoundation::D3D12_VIDEO_ENCODER_LEVELS_H264_3;
+use windows::Win32::Media::MediaFoundation::D3D12_VIDEO_ENCODER_LEVELS_H264_31;
+use windows::Win32::Media::MediaFoundation::D3D12_VIDEO_ENCODER_LEVELS_H264_32;
+use windows::Win32::Media::MediaFoundation::D3D12_VIDEO_ENCODER_LEVELS_H264_4;
+use windows::Win32::Media::MediaFoundation::D3D12_VIDEO_ENCODER_LEVELS_H264_41;
+use windows::Win32::Media::MediaFoundation::D3D12_VIDEO_ENCODER_LEVELS_H264_42;
+use windows::Win32::Media::MediaFoundation::D3D12_VIDEO_ENCODER_LEVELS_H264_5;
+use windows::Win32::Media::MediaFoundation::D3D12_VIDEO_ENCODER_LEVELS_H264_51;
+use windows::Win32::Media::MediaFoundation::D3D12_VIDEO_ENCODER_LEVELS_H264_52;
+use windows::Win32::Media::MediaFoundation::D3D12_VIDEO_ENCODER_LEVELS_H264_6;
+use windows::Win32::Media::MediaFoundation::D3D12_VIDEO_ENCODER_LEVELS_H264_61;
+use windows::Win32::Media::MediaFoundation::D3D12_VIDEO_ENCODER_LEVELS_H264_62;
+use windows::Win32::Media::MediaFoundation::D3D12_VIDEO_ENCODER_LEVEL_SETTING;
+use windows::Win32::Media::MediaFoundation::D3D12_VIDEO_ENCODER_LEVEL_SETTING_0;
+use windows::Win32::Media::MediaFoundation::D3D12_VIDEO_ENCODER_MOTION_ESTIMATION_PRECISION_MODE_MAXIMUM;
+use windows::Win32::Media::MediaFoundation::D3D12_VIDEO_ENCODER_OUTPUT_METADATA;
+use windows::Win32::Media::MediaFoundation::D3D12_VIDEO_ENCODER_PICTURE_CONTROL_CODEC_DATA;
+use windows::Win32::Media::MediaFoundation::D3D12_VIDEO_ENCODER_PICTURE_CONTROL_CODEC_DATA_0;
+use windows::Win32::Media::MediaFoundation::D3D12_VIDEO_ENCODER_PICTURE_CONTROL_CODEC_DATA_H264;
+use windows::Win32::Media::MediaFoundation::D3D12_VIDEO_ENCODER_PICTURE_CONTROL_CODEC_DATA_H264_FLAG_NONE;
+use windows::Win32::Media::MediaFoundation::D3D12_VIDEO_ENCODER_PICTURE_CONTROL_CODEC_DATA_H264_FLAG_REQUEST_NUM_REF_IDX_ACTIVE_OVERRIDE_FLAG_SLICE;
+use windows::Win32::Media::MediaFoundation::D3D12_VIDEO_ENCODER_PICTURE_CONTROL_CODEC_DATA_H264_REFERENCE_PICTURE_LIST_MODIFICATION_OPERATION;
+use windows::Win32::Media::MediaFoundation::D3D12_VIDEO_ENCODER_PICTURE_CONTROL_CODEC_DATA_H264_REFERENCE_PICTURE_MARKING_OPERATION;
+use windows::Win32::Media::MediaFoundation::D3D12_VIDEO_ENCODER_PICTURE_CONTROL_DESC;
+use windows::Win32::Media::MediaFoundation::D3D12_VIDEO_ENCODER_PICTURE_CONTROL_FLAG_NONE;
+use windows::Win32::Media::MediaFoundation::D3D12_VIDEO_ENCODER_PICTURE_CONTROL_FLAG_USED_AS_REFERENCE_PICTURE;
+use windows::Win32::Media::MediaFoundation::D3D12_VIDEO_ENCODER_PICTURE_CONTROL_SUBREGIONS_LAYOUT_DATA;
+use windows::Win32::Media::MediaFoundation::D3D12_VIDEO_ENCODER_PICTURE_RESOLUTION_DESC;
+use windows::Win32::Media::MediaFoundation::D3D12_VIDEO_ENCODER_PROFILE_DESC;
+use windows::Win32::Media::MediaFoundation::D3D12_VIDEO_ENCODER_PROFILE_DESC_0;
+use windows::Win32::Media::MediaFoundation::D3D12_VIDEO_ENCODER_PROFILE_H264;
+use windows::Win32::Media::MediaFoundation::D3D12_VIDEO_ENCODER_PROFILE_H264_HIGH;
+use windows::Win32::Media::MediaFoundation::D3D12_VIDEO_ENCODER_PROFILE_H264_MAIN;
+use windows::Win32::Media::MediaFoundation::D3D12_VIDEO_ENCODER_RATE_CONTROL;
+use windows::Win32::Media::MediaFoundation::D3D12_VIDEO_ENCODER_RATE_CONTROL_CBR;
+use windows::Win32::Media::MediaFoundation::D3D12_VIDEO_ENCODER_RATE_CONTROL_CONFIGURATION_PARAMS;
+use windows::Win32::Media::MediaFoundation::D3D12_VIDEO_ENCODER_RATE_CONTROL_CONFIGURATION_PARAMS_0;
+use windows::Win32::Media::MediaFoundation::D3D12_VIDEO_ENCODER_RATE_CONTROL_FLAG_NONE;
+use windows::Win32::Media::MediaFoundation::D3D12_VIDEO_ENCODER_RATE_CONTROL_MODE_CBR;
+use windows::Win32::Media::MediaFoundation::D3D12_VIDEO_ENCODER_RECONSTRUCTED_PICTURE;
+use windows::Win32::Media::MediaFoundation::D3D12_VIDEO_ENCODER_REFERENCE_PICTURE_DESCRIPTOR_H264;
+use windows::Win32::Media::MediaFoundation::D3D12_VIDEO_ENCODER_RESOLVE_METADATA_INPUT_ARGUMENTS;
+use windows::Win32::Media::MediaFoundation::D3D12_VIDEO_ENCODER_RESOLVE_METADATA_OUTPUT_ARGUMENTS;
+use windows::Win32::Media::MediaFoundation::D3D12_VIDEO_ENCODER_SEQUENCE_CONTROL_DESC;
+use windows::Win32::Media::MediaFoundation::D3D12_VIDEO_ENCODER_SEQUENCE_CONTROL_FLAG_NONE;
+use windows::Win32::Media::MediaFoundation::D3D12_VIDEO_ENCODER_SEQUENCE_CONTROL_FLAG_RATE_CONTROL_CHANGE;
+use windows::Win32::Media::MediaFoundation::D3D12_VIDEO_ENCODER_SEQUENCE_GOP_STRUCTURE;
+use windows::Win32::Media::MediaFoundation::D3D12_VIDEO_ENCODER_SEQUENCE_GOP_STRUCTURE_0;
+use windows::Win32::Media::MediaFoundation::D3D12_VIDEO_ENCODER_SEQUENCE_GOP_STRUCTURE_H264;
+use windows::Win32::Media::MediaFoundation::D3D12_VIDEO_ENCODER_SUPPORT_FLAG_GENERAL_SUPPORT_OK;
+use windows::Win32::Media::MediaFoundation::D3D12_VIDEO_ENCODER_SUPPORT_FLAG_READABLE_RECONSTRUCTED_PICTURE_LAYOUT_AVAILABLE;
+use windows::Win32::Media::MediaFoundation::D3D12_VIDEO_ENCODER_SUPPORT_FLAG_RECONSTRUCTED_FRAMES_REQUIRE_TEXTURE_ARRAYS;
+use windows::Win32::Media::MediaFoundation::D3D12_VIDEO_ENCODE_REFERENCE_FRAMES;
+
+use crate::backend::d3d12::encoder::resource_barriers;
+use crate::backend::d3d12::encoder::transition;
+use crate::backend::d3d12::encoder::CodedOutputPromise;
+use crate::backend::d3d12::encoder::D3d12Backend;
+use crate::backend::d3d12::encoder::Reconstructed;
+use crate::backend::d3d12::encoder::Session;
+use crate::codec::h264::parser::Level;
+use crate::codec::h264::parser::Profile;
+use crate::codec::h264::parser::RefPicListModification;
+use crate::codec::h264::parser::Sps;
+use crate::encoder::stateless::h264::BackendRequest;
+use crate::encoder::stateless::h264::Bitrate;
+use crate::encoder::stateless::h264::DpbEntry;
+use crate::encoder::stateless::h264::EncoderConfig;
+use crate::encoder::stateless::h264::IsReference;
+use crate::encoder::stateless::h264::StatelessEncoder;
+use crate::encoder::stateless::h264::StatelessH264EncoderBackend;
+use crate::encoder::stateless::h264::H264;
+use crate::encoder::stateless::EncodeResult;
+use crate::encoder::stateless::ReadyPromise;
+use crate::encoder::stateless::StatelessBackendError;
+use crate::encoder::stateless::StatelessBackendResult;
+use crate::encoder::stateless::StatelessVideoEncoderBackend;
+use crate::BlockingMode;
+use crate::Resolution;
+
+type Request<H> = BackendRequest<H, Reconstructed>;
+
+/// Parameters the encoder and its heap are created with, derived from the SPS and PPS.
+#[derive(Clone, Copy, PartialEq)]
+struct SessionParams {
+    profile: D3D12_VIDEO_ENCODER_PROFILE_H264,
+    level: D3D12_VIDEO_ENCODER_LEVELS_H264,
+    config: D3D12_VIDEO_ENCODER_CODEC_CONFIGURATION_H264,
+    gop: D3D12_VIDEO_ENCODER_SEQUENCE_GOP_STRUCTURE_H264,
+    max_num_ref_frames: u32,
+}
+
+impl SessionParams {
+    fn new<H>(request: &Request<H>) -> StatelessBackendResult<Self> {
+        let sps = &request.sps;
+        let pps = &request.pps;
+
+        let mut flags = D3D12_VIDEO_ENCODER_CODEC_CONFIGURATION_H264_FLAG_NONE;
+        if pps.entropy_coding_mode_flag {
+            flags |= D3D12_VIDEO_ENCODER_CODEC_CONFIGURATION_H264_FLAG_ENABLE_CABAC_ENCODING;
+        }
+        if pps.transform_8x8_mode_flag {
+            flags |= D3D12_VIDEO_ENCODER_CODEC_CONFIGURATION_H264_FLAG_USE_ADAPTIVE_8x8_TRANSFORM;
+        }
+        if pps.constrained_intra_pred_flag {
+            flags |=
+                D3D12_VIDEO_ENCODER_CODEC_CONFIGURATION_H264_FLAG_USE_CONSTRAINED_INTRAPREDICTION;
+        }
+        let deblocking = match request.header.disable_deblocking_filter_idc {
+            0 => D3D12_VIDEO_ENCODER_CODEC_CONFIGURATION_H264_SLICES_DEBLOCKING_MODE_0_ALL_LUMA_CHROMA_SLICE_BLOCK_EDGES_ALWAYS_FILTERED,
+            1 => D3D12_VIDEO_ENCODER_CODEC_CONFIGURATION_H264_SLICES_DEBLOCKING_MODE_1_DISABLE_ALL_SLICE_BLOCK_EDGES,
+            _ => D3D12_VIDEO_ENCODER_CODEC_CONFIGURATION_H264_SLICES_DEBLOCKING_MODE_2_DISABLE_SLICE_BOUNDARIES_BLOCKS,
+        };
+
+        Ok(Self {
+            profile: d3d12_profile(sps)?,
+            level: d3d12_level(sps.level_idc),
+            config: D3D12_VIDEO_ENCODER_CODEC_CONFIGURATION_H264 {
+                ConfigurationFlags: flags,
+                DirectModeConfig:
+                    D3D12_VIDEO_ENCODER_CODEC_CONFIGURATION_H264_DIRECT_MODES_DISABLED,
+                DisableDeblockingFilterConfig: deblocking,
+            },
+            // The prediction structure is driven frame by frame, so the GOP is left open with
+            // only P frames, while its syntax elements match the SPS.
+            gop: D3D12_VIDEO_ENCODER_SEQUENCE_GOP_STRUCTURE_H264 {
+                GOPLength: 0,
+                PPicturePeriod: 1,
+                pic_order_cnt_type: sps.pic_order_cnt_type,
+                log2_max_frame_num_minus4: sps.log2_max_frame_num_minus4,
+                log2_max_pic_order_cnt_lsb_minus4: sps.log2_max_pic_order_cnt_lsb_minus4,
+            },
+            max_num_ref_frames: sps.max_num_ref_frames,
+        })
+    }
+
+    fn profile_desc(&mut self) -> D3D12_VIDEO_ENCODER_PROFILE_DESC {
+        D3D12_VIDEO_ENCODER_PROFILE_DESC {
+            DataSize: size_of::<D3D12_VIDEO_ENCODER_PROFILE_H264>() as u32,
+            Anonymous: D3D12_VIDEO_ENCODER_PROFILE_DESC_0 {
+                pH264Profile: &mut self.profile,
+            },
+        }
+    }
+
+    fn level_setting(&mut self) -> D3D12_VIDEO_ENCODER_LEVEL_SETTING {
+        D3D12_VIDEO_ENCODER_LEVEL_SETTING {
+            DataSize: size_of::<D3D12_VIDEO_ENCODER_LEVELS_H264>() as u32,
+            Anonymous: D3D12_VIDEO_ENCODER_LEVEL_SETTING_0 {
+                pH264LevelSetting: &mut self.level,
+            },
+        }
+    }
+
+    fn codec_configuration(&mut self) -> D3D12_VIDEO_ENCODER_CODEC_CONFIGURATION {
+        D3D12_VIDEO_ENCODER_CODEC_CONFIGURATION {
+            DataSize: size_of::<D3D12_VIDEO_ENCODER_CODEC_CONFIGURATION_H264>() as u32,
+            Anonymous: D3D12_VIDEO_ENCODER_CODEC_CONFIGURATION_0 {
+                pH264Config: &mut self.config,
+            },
+        }
+    }
+
+    fn gop_structure(&mut self) -> D3D12_VIDEO_ENCODER_SEQUENCE_GOP_STRUCTURE {
+        D3D12_VIDEO_ENCODER_SEQUENCE_GOP_STRUCTURE {
+            DataSize: size_of::<D3D12_VIDEO_ENCODER_SEQUENCE_GOP_STRUCTURE_H264>() as u32,
+            Anonymous: D3D12_VIDEO_ENCODER_SEQUENCE_GOP_STRUCTURE_0 {
+                pH264GroupOfPictures: &mut self.gop,
+            },
+        }
+    }
+}
+
+fn d3d12_profile(sps: &Sps) -> StatelessBackendResult<D3D12_VIDEO_ENCODER_PROFILE_H264> {
+    // D3D12 has no Baseline profile, but the constrained baseline streams produced by the
+    // encoder use a subset of the Main profile.
+    match Profile::n(sps.profile_idc) {
+        Some(Profile::Baseline) | Some(Profile::Main) => Ok(D3D12_VIDEO_ENCODER_PROFILE_H264_MAIN),
+        Some(Profile::High) => Ok(D3D12_VIDEO_ENCODER_PROFILE_H264_HIGH),
+        _ => Err(StatelessBackendError::UnsupportedProfile),
+    }
+}
+
+fn d3d12_level(level: Level) -> D3D12_VIDEO_ENCODER_LEVELS_H264 {
+    match level {
+        Level::L1 => D3D12_VIDEO_ENCODER_LEVELS_H264_1,
+        Level::L1B => D3D12_VIDEO_ENCODER_LEVELS_H264_1b,
+        Level::L1_1 => D3D12_VIDEO_ENCODER_LEVELS_H264_11,
+        Level::L1_2 => D3D12_VIDEO_ENCODER_LEVELS_H264_12,
+        Level::L1_3 => D3D12_VIDEO_ENCODER_LEVELS_H264_13,
+        Level::L2_0 => D3D12_VIDEO_ENCODER_LEVELS_H264_2,
+        Level::L2_1 => D3D12_VIDEO_ENCODER_LEVELS_H264_21,
+        Level::L2_2 => D3D12_VIDEO_ENCODER_LEVELS_H264_22,
+        Level::L3 => D3D12_VIDEO_ENCODER_LEVELS_H264_3,
+        Level::L3_1 => D3D12_VIDEO_ENCODER_LEVELS_H264_31,
+        Level::L3_2 => D3D12_VIDEO_ENCODER_LEVELS_H264_32,
+        Level::L4 => D3D12_VIDEO_ENCODER_LEVELS_H264_4,
+        Level::L4_1 => D3D12_VIDEO_ENCODER_LEVELS_H264_41,
+        Level::L4_2 => D3D12_VIDEO_ENCODER_LEVELS_H264_42,
+        Level::L5 => D3D12_VIDEO_ENCODER_LEVELS_H264_5,
+        Level::L5_1 => D3D12_VIDEO_ENCODER_LEVELS_H264_51,
+        Level::L5_2 => D3D12_VIDEO_ENCODER_LEVELS_H264_52,
+        Level::L6 => D3D12_VIDEO_ENCODER_LEVELS_H264_6,
+        Level::L6_1 => D3D12_VIDEO_ENCODER_LEVELS_H264_61,
+        Level::L6_2 => D3D12_VIDEO_ENCODER_LEVELS_H264_62,
+    }
+}
+
+/// Returns a pointer to the data of `v`, or null if it is empty.
+fn ptr_or_null<T>(v: &mut [T]) -> *mut T {
+    if v.is_empty() {
+        std::ptr::null_mut()
+    } else {
+        v.as_mut_ptr()
+    }
+}
+
+fn align(size: u64, alignment: u64) -> u64 {
+    size.div_ceil(alignment.max(1)) * alignment.max(1)
+}
+
+impl<H> StatelessVideoEncoderBackend<H264> for D3d12Backend<H>
+where
+    H: Borrow<ID3D12Resource>,
+{
+    type Picture = H;
+    type Reconstructed = Reconstructed;
+    type CodedPromise = CodedOutputPromise<H>;
+    // The queue encodes the frames in order, so a reconstructed picture is always written before
+    // the frames referencing it are encoded.
+    type ReconPromise = ReadyPromise<Self::Reconstructed>;
+}
+
+impl<H> D3d12Backend<H>
+where
+    H: Borrow<ID3D12Resource>,
+{
+    /// Creates the encoder and heap for `params`, after checking the device supports them.
+    fn open_session(&mut self, mut params: SessionParams) -> StatelessBackendResult<()> {
+        let coded_size = self.coded_size();
+        let resolution = D3D12_VIDEO_ENCODER_PICTURE_RESOLUTION_DESC {
+            Width: coded_size.width,
+            Height: coded_size.height,
+        };
+
+        // Only the mode of the rate control matters to the query.
+        let cbr = D3D12_VIDEO_ENCODER_RATE_CONTROL_CBR::default();
+        let mut limits = D3D12_FEATURE_DATA_VIDEO_ENCODER_RESOLUTION_SUPPORT_LIMITS::default();
+        let mut support = D3D12_FEATURE_DATA_VIDEO_ENCODER_SUPPORT {
+            NodeIndex: 0,
+            Codec: D3D12_VIDEO_ENCODER_CODEC_H264,
+            InputFormat: DXGI_FORMAT_NV12,
+            CodecConfiguration: params.codec_configuration(),
+            CodecGopSequence: params.gop_structure(),
+            RateControl: D3D12_VIDEO_ENCODER_RATE_CONTROL {
+                Mode: D3D12_VIDEO_ENCODER_RATE_CONTROL_MODE_CBR,
+                Flags: D3D12_VIDEO_ENCODER_RATE_CONTROL_FLAG_NONE,
+                ConfigParams: D3D12_VIDEO_ENCODER_RATE_CONTROL_CONFIGURATION_PARAMS {
+                    DataSize: size_of::<D3D12_VIDEO_ENCODER_RATE_CONTROL_CBR>() as u32,
+                    Anonymous: D3D12_VIDEO_ENCODER_RATE_CONTROL_CONFIGURATION_PARAMS_0 {
+                        pConfiguration_CBR: &cbr,
+                    },
+                },
+                TargetFrameRate: DXGI_RATIONAL {
+                    Numerator: 30,
+                    Denominator: 1,
+                },
+            },
+            IntraRefresh: D3D12_VIDEO_ENCODER_INTRA_REFRESH_MODE_NONE,
+            SubregionFrameEncoding: D3D12_VIDEO_ENCODER_FRAME_SUBREGION_LAYOUT_MODE_FULL_FRAME,
+            ResolutionsListCount: 1,
+            pResolutionList: &resolution,
+            SuggestedProfile: params.profile_desc(),
+            SuggestedLevel: params.level_setting(),
+            pResolutionDependentSupport: &mut limits,
+            ..Default::default()
+        };
+        unsafe {
+            self.video_device().CheckFeatureSupport(
+                D3D12_FEATURE_VIDEO_ENCODER_SUPPORT,
+                &mut support as *mut _ as *mut _,
+                size_of::<D3D12_FEATURE_DATA_VIDEO_ENCODER_SUPPORT>() as u32,
+            )?
+        };
+        if !support
+            .SupportFlags
+            .contains(D3D12_VIDEO_ENCODER_SUPPORT_FLAG_GENERAL_SUPPORT_OK)
+        {
+            return Err(StatelessBackendError::Other(anyhow::anyhow!(
+                "unsupported encoder configuration, validation flags {:#x}",
+                support.ValidationFlags.0
+            )));
+        }
+        if support
+            .SupportFlags
+            .contains(D3D12_VIDEO_ENCODER_SUPPORT_FLAG_RECONSTRUCTED_FRAMES_REQUIRE_TEXTURE_ARRAYS)
+        {
+            return Err(StatelessBackendError::Other(anyhow::anyhow!(
+                "reconstructed frames in texture arrays are not supported"
+            )));
+        }
+        if params.max_num_ref_frames > support.MaxReferenceFramesInDPB {
+            return Err(StatelessBackendError::Other(anyhow::anyhow!(
+                "{} references requested, the encoder supports {}",
+                params.max_num_ref_frames,
+                support.MaxReferenceFramesInDPB
+            )));
+        }
+        let recon_flags = if support.SupportFlags.contains(
+            D3D12_VIDEO_ENCODER_SUPPORT_FLAG_READABLE_RECONSTRUCTED_PICTURE_LAYOUT_AVAILABLE,
+        ) {
+            D3D12_RESOURCE_FLAG_NONE
+        } else {
+            D3D12_RESOURCE_FLAG_VIDEO_ENCODE_REFERENCE_ONLY
+                | D3D12_RESOURCE_FLAG_DENY_SHADER_RESOURCE
+        };
+
+        let mut requirements = D3D12_FEATURE_DATA_VIDEO_ENCODER_RESOURCE_REQUIREMENTS {
+            NodeIndex: 0,
+            Codec: D3D12_VIDEO_ENCODER_CODEC_H264,
+            Profile: params.profile_desc(),
+            InputFormat: DXGI_FORMAT_NV12,
+            PictureTargetResolution: resolution,
+            ..Default::default()
+        };
+        unsafe {
+            self.video_device().CheckFeatureSupport(
+                D3D12_FEATURE_VIDEO_ENCODER_RESOURCE_REQUIREMENTS,
+                &mut requirements as *mut _ as *mut _,
+                size_of::<D3D12_FEATURE_DATA_VIDEO_ENCODER_RESOURCE_REQUIREMENTS>() as u32,
+            )?
+        };
+        if !requirements.IsSupported.as_bool() {
+            return Err(StatelessBackendError::Other(anyhow::anyhow!(
+                "no resource requirements for {:?}",
+                coded_size
+            )));
+        }
+
+        let encoder: ID3D12VideoEncoder = unsafe {
+            self.video_device()
+                .CreateVideoEncoder(&D3D12_VIDEO_ENCODER_DESC {
+                    NodeMask: 0,
+                    Flags: D3D12_VIDEO_ENCODER_FLAG_NONE,
+                    EncodeCodec: D3D12_VIDEO_ENCODER_CODEC_H264,
+                    EncodeProfile: params.profile_desc(),
+                    InputFormat: DXGI_FORMAT_NV12,
+                    CodecConfiguration: params.codec_configuration(),
+                    MaxMotionEstimationPrecision:
+                        D3D12_VIDEO_ENCODER_MOTION_ESTIMATION_PRECISION_MODE_MAXIMUM,
+                })?
+        };
+        let heap: ID3D12VideoEncoderHeap = unsafe {
+            self.video_device()
+                .CreateVideoEncoderHeap(&D3D12_VIDEO_ENCODER_HEAP_DESC {
+                    NodeMask: 0,
+                    Flags: D3D12_VIDEO_ENCODER_HEAP_FLAG_NONE,
+                    EncodeCodec: D3D12_VIDEO_ENCODER_CODEC_H264,
+                    EncodeProfile: params.profile_desc(),
+                    EncodeLevel: params.level_setting(),
+                    ResolutionsListCount: 1,
+                    pResolutionList: &resolution,
+                })?
+        };
+
+        let metadata_alignment = requirements.EncoderMetadataBufferAccessAlignment as u64;
+        self.set_session(
+            Session {
+                params: Box::new(params),
+                encoder,
+                heap,
+                metadata_size: align(
+                    requirements.MaxEncoderOutputMetadataBufferSize as u64,
+                    metadata_alignment,
+                ),
+                bitstream_alignment: requirements.CompressedBitstreamBufferAccessAlignment as u64,
+                rate_control: None,
+            },
+            recon_flags,
+        );
+
+        Ok(())
+    }
+}
+
+impl<H> StatelessH264EncoderBackend for D3d12Backend<H>
+where
+    H: Borrow<ID3D12Resource>,
+{
+    fn encode_slice(
+        &mut self,
+        request: Request<H>,
+    ) -> StatelessBackendResult<(Self::ReconPromise, Self::CodedPromise)> {
+        let mut params = SessionParams::new(&request)?;
+        let reopen = match self.session() {
+            Some(session) => session.params.downcast_ref::<SessionParams>() != Some(&params),
+            None => true,
+        };
+        if reopen {
+            self.open_session(params)?;
+        }
+
+        let target = request.bitrate.target();
+        let rate_control_flags = {
+            let session = self.session_mut().unwrap();
+            let previous = session.rate_control.replace((target, request.framerate));
+            match previous {
+                Some(previous) if previous != (target, request.framerate) => {
+                    D3D12_VIDEO_ENCODER_SEQUENCE_CONTROL_FLAG_RATE_CONTROL_CHANGE
+                }
+                _ => D3D12_VIDEO_ENCODER_SEQUENCE_CONTROL_FLAG_NONE,
+            }
+        };
+        let session = self.session().unwrap();
+        let encoder = session.encoder.clone();
+        let heap = session.heap.clone();
+        let metadata_size = session.metadata_size;
+        let bitstream_alignment = session.bitstream_alignment;
+
+        let coded_size = self.coded_size();
+        let resolution = D3D12_VIDEO_ENCODER_PICTURE_RESOLUTION_DESC {
+            Width: coded_size.width,
+            Height: coded_size.height,
+        };
+
+        // Uncompressed 4:2:0 macroblocks take 384 bytes, which the coded ones should not exceed.
+        let bitstream_size = align(
+            request.num_macroblocks as u64 * 384 + 4096,
+            bitstream_alignment,
+        );
+        let bitstream = self.create_buffer(bitstream_size, true)?;
+        let opaque_metadata = self.create_buffer(metadata_size, false)?;
+        let metadata = self.create_buffer(
+            (size_of::<D3D12_VIDEO_ENCODER_OUTPUT_METADATA>()
+                + size_of::<D3D12_VIDEO_ENCODER_FRAME_SUBREGION_METADATA>()) as u64,
+            true,
+        )?;
+        let recon = self.new_reconstructed()?;
+        let input: ID3D12Resource = request.input.borrow().clone();
+
+        // Each reference is described once, and the lists index the descriptions.
+        let header = &request.header;
+        let mut references: Vec<&Rc<DpbEntry<Reconstructed>>> = Vec::new();
+        let mut index_lists = [Vec::new(), Vec::new()];
+        for (list, indices) in [&request.ref_list_0, &request.ref_list_1]
+            .into_iter()
+            .zip(index_lists.iter_mut())
+        {
+            for entry in list {
+                let index = match references.iter().position(|r| Rc::ptr_eq(r, entry)) {
+                    Some(index) => index,
+                    None => {
+                        references.push(entry);
+                        references.len() - 1
+                    }
+                };
+                indices.push(index as u32);
+            }
+        }
+        let [mut list0, mut list1] = index_lists;
+        let mut textures: Vec<Option<ID3D12Resource>> = references
+            .iter()
+            .map(|entry| Some(entry.recon_pic.texture().clone()))
+            .collect();
+        let mut subresources = vec![0u32; references.len()];
+        let mut descriptors: Vec<_> = references
+            .iter()
+            .enumerate()
+            .map(
+                |(index, entry)| D3D12_VIDEO_ENCODER_REFERENCE_PICTURE_DESCRIPTOR_H264 {
+                    ReconstructedPictureResourceIndex: index as u32,
+                    IsLongTermReference: (entry.meta.is_reference == IsReference::LongTerm).into(),
+                    LongTermPictureIdx: entry.meta.long_term_frame_idx,
+                    PictureOrderCountNumber: entry.meta.poc as u32,
+                    FrameDecodingOrderNumber: entry.meta.frame_num,
+                    TemporalLayerIndex: 0,
+                },
+            )
+            .collect();
+        let modification = |m: &RefPicListModification| {
+            D3D12_VIDEO_ENCODER_PICTURE_CONTROL_CODEC_DATA_H264_REFERENCE_PICTURE_LIST_MODIFICATION_OPERATION {
+                modification_of_pic_nums_idc: m.modification_of_pic_nums_idc,
+                abs_diff_pic_num_minus1: m.abs_diff_pic_num_minus1,
+                long_term_pic_num: m.long_term_pic_num,
+            }
+        };
+        let mut modifications0: Vec<_> = header
+            .ref_pic_list_modification_l0
+            .iter()
+            .map(modification)
+            .collect();
+        let mut modifications1: Vec<_> = header
+            .ref_pic_list_modification_l1
+            .iter()
+            .map(modification)
+            .collect();
+        let marking = &header.dec_ref_pic_marking;
+        let mut marking_ops: Vec<_> = marking
+            .inner
+            .iter()
+            .map(
+                |op| D3D12_VIDEO_ENCODER_PICTURE_CONTROL_CODEC_DATA_H264_REFERENCE_PICTURE_MARKING_OPERATION {
+                    memory_management_control_operation: op.memory_management_control_operation,
+                    difference_of_pic_nums_minus1: op.difference_of_pic_nums_minus1,
+                    long_term_pic_num: op.long_term_pic_num,
+                    long_term_frame_idx: op.long_term_frame_idx,
+                    max_long_term_frame_idx_plus1: op.max_long_term_frame_idx.to_value_plus1(),
+                },
+            )
+            .collect();
+
+        let frame_type = if request.is_idr {
+            D3D12_VIDEO_ENCODER_FRAME_TYPE_H264_IDR_FRAME
+        } else if header.slice_type.is_i() {
+            D3D12_VIDEO_ENCODER_FRAME_TYPE_H264_I_FRAME
+        } else if header.slice_type.is_p() {
+            D3D12_VIDEO_ENCODER_FRAME_TYPE_H264_P_FRAME
+        } else {
+            D3D12_VIDEO_ENCODER_FRAME_TYPE_H264_B_FRAME
+        };
+        let mut pic_data = D3D12_VIDEO_ENCODER_PICTURE_CONTROL_CODEC_DATA_H264 {
+            Flags: if header.num_ref_idx_active_override_flag {
+                D3D12_VIDEO_ENCODER_PICTURE_CONTROL_CODEC_DATA_H264_FLAG_REQUEST_NUM_REF_IDX_ACTIVE_OVERRIDE_FLAG_SLICE
+            } else {
+                D3D12_VIDEO_ENCODER_PICTURE_CONTROL_CODEC_DATA_H264_FLAG_NONE
+            },
+            FrameType: frame_type,
+            pic_parameter_set_id: header.pic_parameter_set_id as u32,
+            idr_pic_id: header.idr_pic_id as u32,
+            PictureOrderCountNumber: request.dpb_meta.poc as u32,
+            FrameDecodingOrderNumber: request.dpb_meta.frame_num,
+            TemporalLayerIndex: request.temporal_layer as u32,
+            List0ReferenceFramesCount: list0.len() as u32,
+            pList0ReferenceFrames: ptr_or_null(&mut list0),
+            List1ReferenceFramesCount: list1.len() as u32,
+            pList1ReferenceFrames: ptr_or_null(&mut list1),
+            ReferenceFramesReconPictureDescriptorsCount: descriptors.len() as u32,
+            pReferenceFramesReconPictureDescriptors: ptr_or_null(&mut descriptors),
+            adaptive_ref_pic_marking_mode_flag: marking.adaptive_ref_pic_marking_mode_flag as u8,
+            RefPicMarkingOperationsCommandsCount: marking_ops.len() as u32,
+            pRefPicMarkingOperationsCommands: ptr_or_null(&mut marking_ops),
+            List0RefPicModificationsCount: modifications0.len() as u32,
+            pList0RefPicModifications: ptr_or_null(&mut modifications0),
+            List1RefPicModificationsCount: modifications1.len() as u32,
+            pList1RefPicModifications: ptr_or_null(&mut modifications1),
+            QPMapValuesCount: 0,
+            pRateControlQPMap: std::ptr::null_mut(),
+        };
+
+        let cbr = D3D12_VIDEO_ENCODER_RATE_CONTROL_CBR {
+            TargetBitRate: target,
+            ..Default::default()
+        };
+        let input_args = D3D12_VIDEO_ENCODER_ENCODEFRAME_INPUT_ARGUMENTS {
+            SequenceControlDesc: D3D12_VIDEO_ENCODER_SEQUENCE_CONTROL_DESC {
+                Flags: rate_control_flags,
+                IntraRefreshConfig: D3D12_VIDEO_ENCODER_INTRA_REFRESH {
+                    Mode: D3D12_VIDEO_ENCODER_INTRA_REFRESH_MODE_NONE,
+                    IntraRefreshDuration: 0,
+                },
+                RateControl: D3D12_VIDEO_ENCODER_RATE_CONTROL {
+                    Mode: D3D12_VIDEO_ENCODER_RATE_CONTROL_MODE_CBR,
+                    Flags: D3D12_VIDEO_ENCODER_RATE_CONTROL_FLAG_NONE,
+                    ConfigParams: D3D12_VIDEO_ENCODER_RATE_CONTROL_CONFIGURATION_PARAMS {
+                        DataSize: size_of::<D3D12_VIDEO_ENCODER_RATE_CONTROL_CBR>() as u32,
+                        Anonymous: D3D12_VIDEO_ENCODER_RATE_CONTROL_CONFIGURATION_PARAMS_0 {
+                            pConfiguration_CBR: &cbr,
+                        },
+                    },
+                    TargetFrameRate: DXGI_RATIONAL {
+                        Numerator: request.framerate.0,
+                        Denominator: request.framerate.1,
+                    },
+                },
+                PictureTargetResolution: resolution,
+                SelectedLayoutMode: D3D12_VIDEO_ENCODER_FRAME_SUBREGION_LAYOUT_MODE_FULL_FRAME,
+                FrameSubregionsLayoutData:
+                    D3D12_VIDEO_ENCODER_PICTURE_CONTROL_SUBREGIONS_LAYOUT_DATA::default(),
+                CodecGopSequence: params.gop_structure(),
+            },
+            PictureControlDesc: D3D12_VIDEO_ENCODER_PICTURE_CONTROL_DESC {
+                IntraRefreshFrameIndex: 0,
+                Flags: match request.dpb_meta.is_reference {
+                    IsReference::No => D3D12_VIDEO_ENCODER_PICTURE_CONTROL_FLAG_NONE,
+                    _ => D3D12_VIDEO_ENCODER_PICTURE_CONTROL_FLAG_USED_AS_REFERENCE_PICTURE,
+                },
+                PictureControlCodecData: D3D12_VIDEO_ENCODER_PICTURE_CONTROL_CODEC_DATA {
+                    DataSize: size_of::<D3D12_VIDEO_ENCODER_PICTURE_CONTROL_CODEC_DATA_H264>()
+                        as u32,
+                    Anonymous: D3D12_VIDEO_ENCODER_PICTURE_CONTROL_CODEC_DATA_0 {
+                        pH264PicData: &mut pic_data,
+                    },
+                },
+                ReferenceFrames: D3D12_VIDEO_ENCODE_REFERENCE_FRAMES {
+                    NumTexture2Ds: textures.len() as u32,
+                    ppTexture2Ds: ptr_or_null(&mut textures),
+                    pSubresources: ptr_or_null(&mut subresources),
+                },
+            },
+            pInputFrame: ManuallyDrop::new(Some(input.clone())),
+            InputFrameSubresource: 0,
+            // The SPS, PPS and SEI messages written before the slices count towards the bitrate.
+            CurrentFrameBitstreamMetadataSize: request.coded_output.len() as u32,
+        };
+        let output_args = D3D12_VIDEO_ENCODER_ENCODEFRAME_OUTPUT_ARGUMENTS {
+            Bitstream: D3D12_VIDEO_ENCODER_COMPRESSED_BITSTREAM {
+                pBuffer: ManuallyDrop::new(Some(bitstream.clone())),
+                FrameStartOffset: 0,
+            },
+            ReconstructedPicture: D3D12_VIDEO_ENCODER_RECONSTRUCTED_PICTURE {
+                pReconstructedPicture: ManuallyDrop::new(Some(recon.texture().clone())),
+                ReconstructedPictureSubresource: 0,
+            },
+            EncoderOutputMetadata: D3D12_VIDEO_ENCODER_ENCODE_OPERATION_METADATA_BUFFER {
+                pBuffer: ManuallyDrop::new(Some(opaque_metadata.clone())),
+                Offset: 0,
+            },
+        };
+        let resolve_input = D3D12_VIDEO_ENCODER_RESOLVE_METADATA_INPUT_ARGUMENTS {
+            EncoderCodec: D3D12_VIDEO_ENCODER_CODEC_H264,
+            EncoderProfile: params.profile_desc(),
+            EncoderInputFormat: DXGI_FORMAT_NV12,
+            EncodedPictureEffectiveResolution: resolution,
+            HWLayoutMetadata: D3D12_VIDEO_ENCODER_ENCODE_OPERATION_METADATA_BUFFER {
+                pBuffer: ManuallyDrop::new(Some(opaque_metadata.clone())),
+                Offset: 0,
+            },
+        };
+        let resolve_output = D3D12_VIDEO_ENCODER_RESOLVE_METADATA_OUTPUT_ARGUMENTS {
+            ResolvedLayoutMetadata: D3D12_VIDEO_ENCODER_ENCODE_OPERATION_METADATA_BUFFER {
+                pBuffer: ManuallyDrop::new(Some(metadata.clone())),
+                Offset: 0,
+            },
+        };
+
+        let (allocator, list) = self.new_command_list()?;
+        let common = D3D12_RESOURCE_STATE_COMMON;
+        let read = D3D12_RESOURCE_STATE_VIDEO_ENCODE_READ;
+        let write = D3D12_RESOURCE_STATE_VIDEO_ENCODE_WRITE;
+        let mut barriers = vec![
+            transition(&input, common, read),
+            transition(recon.texture(), common, write),
+            transition(&bitstream, common, write),
+            transition(&opaque_metadata, common, write),
+        ];
+        barriers.extend(
+            references
+                .iter()
+                .map(|entry| transition(entry.recon_pic.texture(), common, read)),
+        );
+        resource_barriers(&list, barriers);
+        unsafe { list.EncodeFrame(&encoder, &heap, &input_args, &output_args) };
+        resource_barriers(
+            &list,
+            vec![
+                transition(&opaque_metadata, write, read),
+                transition(&metadata, common, write),
+            ],
+        );
+        unsafe { list.ResolveEncoderOutputMetadata(&resolve_input, &resolve_output) };
+        let mut barriers = vec![
+            transition(&input, read, common),
+            transition(recon.texture(), write, common),
+            transition(&bitstream, write, common),
+            transition(&opaque_metadata, read, common),
+            transition(&metadata, write, common),
+        ];
+        barriers.extend(
+            references
+                .iter()
+                .map(|entry| transition(entry.recon_pic.texture(), read, common)),
+        );
+        resource_barriers(&list, barriers);
+
+        // Release the resources referenced by the arguments, now that they are recorded.
+        drop(ManuallyDrop::into_inner(input_args.pInputFrame));
+        drop(ManuallyDrop::into_inner(output_args.Bitstream.pBuffer));
+        drop(ManuallyDrop::into_inner(
+            output_args.ReconstructedPicture.pReconstructedPicture,
+        ));
+        drop(ManuallyDrop::into_inner(
+            output_args.EncoderOutputMetadata.pBuffer,
+        ));
+        drop(ManuallyDrop::into_inner(
+            resolve_input.HWLayoutMetadata.pBuffer,
+        ));
+        drop(ManuallyDrop::into_inner(
+            resolve_output.ResolvedLayoutMetadata.pBuffer,
+        ));
+
+        let fence_value = self.submit(&list)?;
+
+        let references: Vec<Rc<dyn Any>> = references
+            .into_iter()
+            .map(|entry| Rc::clone(entry) as Rc<dyn Any>)
+            .collect();
+        let keepalive: Vec<Box<dyn Any>> = vec![
+            Box::new(allocator),
+            Box::new(list),
+            Box::new(opaque_metadata),
+        ];
+        let coded = CodedOutputPromise::new(
+            self.fence().clone(),
+            fence_value,
+            bitstream,
+            metadata,
+            request.coded_output,
+            request.input,
+            keepalive,
+            references,
+        );
+
+        Ok((ReadyPromise::from(recon), coded))
+    }
+}
+
+fn new_backend<H>(
+    device: ID3D12Device,
+    config: &EncoderConfig,
+    coded_size: Resolution,
+) -> EncodeResult<D3d12Backend<H>>
+where
+    H: Borrow<ID3D12Resource>,
+{
+    match config.profile {
+        Profile::Baseline | Profile::Main | Profile::High => (),
+        _ => return Err(StatelessBackendError::UnsupportedProfile.into()),
+    }
+    // The encoder only gets the marking operations of the slices, not the flag marking an IDR
+    // frame as a long-term reference.
+    if config.long_term_references > 0 {
+        return Err(StatelessBackendError::Other(anyhow::anyhow!(
+            "long-term references are not supported by D3D12 encoders"
+        ))
+        .into());
+    }
+    if config.adaptive_quantization.is_some() {
+        return Err(StatelessBackendError::Other(anyhow::anyhow!(
+            "adaptive quantization needs the QP of each macroblock to be settable"
+        ))
+        .into());
+    }
+    if let Bitrate::PerLayer(_) = config.bitrate {
+        return Err(StatelessBackendError::Other(anyhow::anyhow!(
+            "the bitrate of each temporal layer cannot be enforced by D3D12 encoders"
+        ))
+        .into());
+    }
+    // The slice headers written by the driver assume the QP the PPS starts from.
+    if config.default_qp != 26 {
+        return Err(StatelessBackendError::Other(anyhow::anyhow!(
+            "D3D12 encoders need a default QP of 26"
+        ))
+        .into());
+    }
+    if config.preprocessing.is_enabled() {
+        return Err(StatelessBackendError::Other(anyhow::anyhow!(
+            "frames cannot be preprocessed by D3D12 encoders"
+        ))
+        .into());
+    }
+
+    Ok(D3d12Backend::new(device, coded_size)?)
+}
+
+impl<H> StatelessEncoder<H, D3d12Backend<H>>
+where
+    H: Borrow<ID3D12Resource>,
+{
+    /// Creates an encoder of the NV12 textures of `device`, which must be in the common state
+    /// when passed to [`StatelessVideoEncoder::encode`].
+    ///
+    /// [`StatelessVideoEncoder::encode`]: crate::encoder::stateless::StatelessVideoEncoder::encode
+    pub fn new_d3d12(
+        device: ID3D12Device,
+        config: EncoderConfig,
+        coded_size: Resolution,
+        blocking_mode: BlockingMode,
+    ) -> EncodeResult<Self> {
+        let backend = new_backend(device, &config, coded_size)?;
+        Self::new(backend, config, blocking_mode)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use windows::Win32::Graphics::Direct3D::D3D_FEATURE_LEVEL_11_0;
+    use windows::Win32::Graphics::Direct3D12::D3D12CreateDevice;
+    use windows::Win32::Graphics::Direct3D12::D3D12_HEAP_FLAG_NONE;
+    use windows::Win32::Graphics::Direct3D12::D3D12_HEAP_PROPERTIES;
+    use windows::Win32::Graphics::Direct3D12::D3D12_HEAP_TYPE_DEFAULT;
+    use windows::Win32::Graphics::Direct3D12::D3D12_RESOURCE_DESC;
+    use windows::Win32::Graphics::Direct3D12::D3D12_RESOURCE_DIMENSION_TEXTURE2D;
+    use windows::Win32::Graphics::Direct3D12::D3D12_TEXTURE_LAYOUT_UNKNOWN;
+    use windows::Win32::Graphics::Dxgi::Common::DXGI_SAMPLE_DESC;
+
+    use super::*;
+    use crate::codec::h264::parser::Nalu;
+    use crate::codec::h264::parser::NaluType;
+    use crate::encoder::stateless::simple_encode_loop;
+    use crate::encoder::FrameMetadata;
+    use crate::Fourcc;
+    use crate::FrameLayout;
+    use crate::PlaneLayout;
+
+    /// Creates a NV12 texture of `device`, whose content is zeroed on creation.
+    fn new_nv12_texture(device: &ID3D12Device, size: Resolution) -> ID3D12Resource {
+        let heap = D3D12_HEAP_PROPERTIES {
+            Type: D3D12_HEAP_TYPE_DEFAULT,
+            ..Default::default()
+        };
+        let desc = D3D12_RESOURCE_DESC {
+            Dimension: D3D12_RESOURCE_DIMENSION_TEXTURE2D,
+            Width: size.width as u64,
+            Height: size.height,
+            DepthOrArraySize: 1,
+            MipLevels: 1,
+            Format: DXGI_FORMAT_NV12,
+            SampleDesc: DXGI_SAMPLE_DESC {
+                Count: 1,
+                Quality: 0,
+            },
+            Layout: D3D12_TEXTURE_LAYOUT_UNKNOWN,
+            ..Default::default()
+        };
+
+        let mut texture: Option<ID3D12Resource> = None;
+        unsafe {
+            device
+                .CreateCommittedResource(
+                    &heap,
+                    D3D12_HEAP_FLAG_NONE,
+                    &desc,
+                    D3D12_RESOURCE_STATE_COMMON,
+                    None,
+                    &mut texture,
+                )
+                .unwrap()
+        };
+
+        texture.unwrap()
+    }
+
+    #[test]
+    // Ignore this test by default as it requires D3D12 video encoding hardware.
+    #[ignore]
+    fn test_d3d12_encoder() {
+        const NUM_FRAMES: u64 = 30;
+
+        let resolution = Resolution {
+            width: 256,
+            height: 256,
+        };
+
+        let mut device: Option<ID3D12Device> = None;
+        unsafe { D3D12CreateDevice(None, D3D_FEATURE_LEVEL_11_0, &mut device).unwrap() };
+        let device = device.unwrap();
+
+        let config = EncoderConfig {
+            bitrate: Bitrate::Constant(1_000_000),
+            profile: Profile::Main,
+            framerate: 30,
+            resolution,
+            ..Default::default()
+        };
+        let mut encoder = StatelessEncoder::<ID3D12Resource, _>::new_d3d12(
+            device.clone(),
+            config,
+            resolution,
+            BlockingMode::Blocking,
+        )
+        .unwrap();
+
+        let width = resolution.width as usize;
+        let height = resolution.height as usize;
+        let layout = FrameLayout {
+            format: (Fourcc::from(b"NV12"), 0),
+            size: resolution,
+            planes: vec![
+                PlaneLayout {
+                    buffer_index: 0,
+                    offset: 0,
+                    stride: width,
+                },
+                PlaneLayout {
+                    buffer_index: 0,
+                    offset: width * height,
+                    stride: width,
+                },
+            ],
+        };
+        let mut frames = (0..NUM_FRAMES).map(|timestamp| {
+            let meta = FrameMetadata {
+                timestamp,
+                display_resolution: resolution,
+                layout: layout.clone(),
+                force_keyframe: false,
+                damage: None,
+            };
+            (meta, new_nv12_texture(&device, resolution))
+        });
+
+        let mut bitstream = Vec::new();
+        let mut num_coded = 0;
+        simple_encode_loop(&mut encoder, &mut frames, |coded| {
+            num_coded += 1;
+            bitstream.extend(coded.bitstream);
+        })
+        .unwrap();
+
+        // The stream starts with the parameter sets and an IDR frame, each frame being one slice.
+        let mut cursor = Cursor::new(bitstream.as_slice());
+        let mut nalus = Vec::new();
+        while let Ok(nalu) = Nalu::next(&mut cursor) {
+            nalus.push(nalu.header.type_);
+        }
+        assert_eq!(num_coded, NUM_FRAMES);
+        assert_eq!(nalus.first(), Some(&NaluType::Sps));
+        assert_eq!(
+            nalus
+                .iter()
+                .find(|type_| matches!(type_, NaluType::Slice | NaluType::SliceIdr)),
+            Some(&NaluType::SliceIdr)
+        );
+        let num_slices = nalus
+            .iter()
+            .filter(|type_| matches!(type_, NaluType::Slice | NaluType::SliceIdr))
+            .count();
+        assert_eq!(num_slices as u64, NUM_FRAMES);
+    }
+}
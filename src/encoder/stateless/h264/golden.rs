@@ -0,0 +1,416 @@
+// Copyright 2024 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Golden tests of the structure of the streams produced by the encoder.
+//!
+//! Each test encodes deterministic synthetic content with [`GoldenBackend`] and compares a digest
+//! of the resulting stream against a golden file of the `test_data` directory. The digest lists
+//! the NAL unit types of each coded buffer, the slice header fields and references chosen by the
+//! predictor, the values of the parameter sets and a CRC32 of the whole stream, so that
+//! refactors cannot silently change the structure of the streams.
+//!
+//! After an intended change, the golden files are regenerated by running the tests with the
+//! `CROS_CODECS_UPDATE_GOLDEN` environment variable set, and their diff should be reviewed.
+
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::path::PathBuf;
+
+use super::*;
+use crate::codec::h264::parser::Nalu;
+use crate::codec::h264::parser::NaluType;
+use crate::codec::h264::parser::Parser;
+use crate::encoder::stateless::ReadyPromise;
+use crate::Fourcc;
+use crate::FrameLayout;
+
+/// Environment variable regenerating the golden files instead of checking them.
+const UPDATE_GOLDEN_VAR: &str = "CROS_CODECS_UPDATE_GOLDEN";
+
+/// Backend recording the slice parameters chosen by the encoder, and returning an empty slice NAL
+/// unit for each frame. Its pictures are synthetic frames generated from a seed, so that tests
+/// control which frames are identical.
+#[derive(Default)]
+struct GoldenBackend {
+    /// Digest of the slice of each frame, by timestamp.
+    slices: Rc<RefCell<BTreeMap<u64, String>>>,
+}
+
+/// Returns a small NV12 frame with a gradient offset by `seed`.
+fn synthetic_frame(seed: u8) -> (Vec<u8>, FrameLayout) {
+    let size = Resolution {
+        width: 16,
+        height: 16,
+    };
+    let layout = quality::packed_layout(Fourcc::from(b"NV12"), size).unwrap();
+    let data = (0..16 * 16 * 3 / 2)
+        .map(|i| ((i % 16) * 8 + (i / 16) * 4 + usize::from(seed) * 16) as u8)
+        .collect();
+
+    (data, layout)
+}
+
+impl StatelessVideoEncoderBackend<H264> for GoldenBackend {
+    type Picture = u8;
+    type Reconstructed = ();
+    type CodedPromise = ReadyPromise<Vec<u8>>;
+    type ReconPromise = ReadyPromise<()>;
+
+    fn read_picture(&self, seed: &u8) -> StatelessBackendResult<Option<(Vec<u8>, FrameLayout)>> {
+        Ok(Some(synthetic_frame(*seed)))
+    }
+
+    fn read_reconstructed(
+        &self,
+        _recon: &(),
+    ) -> StatelessBackendResult<Option<(Vec<u8>, FrameLayout)>> {
+        Ok(None)
+    }
+}
+
+impl StatelessEncoderBackendImport<u8, u8> for GoldenBackend {
+    fn import_picture(
+        &mut self,
+        _metadata: &FrameMetadata,
+        seed: u8,
+    ) -> StatelessBackendResult<u8> {
+        Ok(seed)
+    }
+}
+
+impl StatelessH264EncoderBackend for GoldenBackend {
+    fn encode_slice(
+        &mut self,
+        request: BackendRequest<u8, ()>,
+    ) -> StatelessBackendResult<(Self::ReconPromise, Self::CodedPromise)> {
+        self.slices
+            .borrow_mut()
+            .insert(request.input_meta.timestamp, slice_digest(&request));
+
+        let mut coded = request.coded_output;
+        // IDR, reference or non-reference non-IDR slice NAL unit header
+        let header = match (request.is_idr, request.dpb_meta.is_reference) {
+            (true, _) => 0x65,
+            (false, IsReference::No) => 0x01,
+            (false, _) => 0x41,
+        };
+        coded.extend_from_slice(&[0x00, 0x00, 0x00, 0x01, header]);
+
+        Ok((ReadyPromise::from(()), ReadyPromise::from(coded)))
+    }
+}
+
+/// Returns the fields of `request` that define the structure of the stream.
+fn slice_digest(request: &BackendRequest<u8, ()>) -> String {
+    let header = &request.header;
+    let meta = &request.dpb_meta;
+    // The backends take frame_num from the DPB entry rather than from the slice header.
+    let mut digest = format!(
+        "{:?} frame_num={} poc_lsb={}",
+        header.slice_type, meta.frame_num, header.pic_order_cnt_lsb
+    );
+
+    if request.is_idr {
+        write!(digest, " idr_pic_id={}", header.idr_pic_id).unwrap();
+    }
+    match meta.is_reference {
+        IsReference::No => digest.push_str(" nonref"),
+        IsReference::ShortTerm => digest.push_str(" short"),
+        IsReference::LongTerm => write!(digest, " long={}", meta.long_term_frame_idx).unwrap(),
+    }
+    if request.temporal_layer != 0 {
+        write!(digest, " layer={}", request.temporal_layer).unwrap();
+    }
+
+    for (name, list) in [("l0", &request.ref_list_0), ("l1", &request.ref_list_1)] {
+        if list.is_empty() {
+            continue;
+        }
+        let refs = list
+            .iter()
+            .map(|entry| match entry.meta.is_reference {
+                IsReference::LongTerm => format!("lt{}", entry.meta.long_term_frame_idx),
+                _ => format!("{}", entry.meta.frame_num),
+            })
+            .collect::<Vec<_>>();
+        write!(digest, " {}=[{}]", name, refs.join(",")).unwrap();
+    }
+    if header.num_ref_idx_active_override_flag {
+        write!(
+            digest,
+            " num_ref_idx_l0={}",
+            header.num_ref_idx_l0_active_minus1 + 1
+        )
+        .unwrap();
+    }
+    if header.ref_pic_list_modification_flag_l0 {
+        let modifications = header
+            .ref_pic_list_modification_l0
+            .iter()
+            .map(|m| {
+                format!(
+                    "{}:{}",
+                    m.modification_of_pic_nums_idc,
+                    match m.modification_of_pic_nums_idc {
+                        2 => m.long_term_pic_num,
+                        _ => m.abs_diff_pic_num_minus1,
+                    }
+                )
+            })
+            .collect::<Vec<_>>();
+        write!(digest, " rplm=[{}]", modifications.join(",")).unwrap();
+    }
+
+    let marking = &header.dec_ref_pic_marking;
+    if marking.long_term_reference_flag {
+        digest.push_str(" idr_long_term");
+    }
+    if marking.adaptive_ref_pic_marking_mode_flag {
+        let operations = marking
+            .inner
+            .iter()
+            .map(|op| match op.memory_management_control_operation {
+                1 => format!("1:{}", op.difference_of_pic_nums_minus1),
+                2 => format!("2:{}", op.long_term_pic_num),
+                3 => format!(
+                    "3:{}:{}",
+                    op.difference_of_pic_nums_minus1, op.long_term_frame_idx
+                ),
+                4 => format!("4:{}", op.max_long_term_frame_idx.to_value_plus1()),
+                6 => format!("6:{}", op.long_term_frame_idx),
+                mmco => format!("{}", mmco),
+            })
+            .collect::<Vec<_>>();
+        write!(digest, " mmco=[{}]", operations.join(",")).unwrap();
+    }
+
+    digest
+}
+
+/// Encodes frames of seed `seeds`, calling `before_frame` with the encoder before each of them,
+/// and returns the digest of the resulting stream.
+fn encode_digest(
+    config: EncoderConfig,
+    seeds: &[u8],
+    mut before_frame: impl FnMut(&mut StatelessEncoder<u8, GoldenBackend>, u64),
+) -> String {
+    let backend = GoldenBackend::default();
+    let slices = Rc::clone(&backend.slices);
+    let mut encoder =
+        StatelessEncoder::<u8, _>::new(backend, config, BlockingMode::Blocking).unwrap();
+
+    let mut coded = vec![];
+    for (timestamp, &seed) in seeds.iter().enumerate() {
+        let timestamp = timestamp as u64;
+        before_frame(&mut encoder, timestamp);
+        encoder
+            .encode(tests::frame_metadata(timestamp), seed)
+            .unwrap();
+        while let Some(buffer) = encoder.poll().unwrap() {
+            coded.push(buffer);
+        }
+    }
+    encoder.drain().unwrap();
+    while let Some(buffer) = encoder.poll().unwrap() {
+        coded.push(buffer);
+    }
+
+    let mut parser = Parser::default();
+    let mut crc = crc32fast::Hasher::new();
+    let mut digest = String::new();
+    for buffer in coded {
+        let timestamp = buffer.metadata.timestamp;
+        crc.update(&buffer.bitstream);
+        write!(digest, "{}", timestamp).unwrap();
+        if buffer.skipped {
+            digest.push_str(" skipped\n");
+            continue;
+        }
+        if buffer.segment_point {
+            digest.push_str(" segment_point");
+        }
+
+        let mut cursor = std::io::Cursor::new(&buffer.bitstream[..]);
+        let nalus = std::iter::from_fn(|| Nalu::next(&mut cursor).ok()).collect::<Vec<_>>();
+        let types = nalus
+            .iter()
+            .map(|nalu| format!("{:?}", nalu.header.type_))
+            .collect::<Vec<_>>();
+        writeln!(
+            digest,
+            " [{}] {}",
+            types.join(" "),
+            slices.borrow().get(&timestamp).map_or("", String::as_str)
+        )
+        .unwrap();
+
+        for nalu in &nalus {
+            match nalu.header.type_ {
+                NaluType::Sps => {
+                    let sps = parser.parse_sps(nalu).unwrap();
+                    writeln!(
+                        digest,
+                        "  sps profile_idc={} level={:?} mbs={}x{} max_num_ref_frames={} \
+                         log2_max_frame_num={} poc_type={} log2_max_poc_lsb={}",
+                        sps.profile_idc,
+                        sps.level_idc,
+                        sps.pic_width_in_mbs_minus1 + 1,
+                        sps.pic_height_in_map_units_minus1 + 1,
+                        sps.max_num_ref_frames,
+                        sps.log2_max_frame_num_minus4 + 4,
+                        sps.pic_order_cnt_type,
+                        sps.log2_max_pic_order_cnt_lsb_minus4 + 4,
+                    )
+                    .unwrap();
+                }
+                NaluType::Pps => {
+                    let pps = parser.parse_pps(nalu).unwrap();
+                    writeln!(
+                        digest,
+                        "  pps cabac={} num_ref_idx_l0_default={} init_qp={} transform_8x8={} \
+                         deblocking_control={}",
+                        pps.entropy_coding_mode_flag,
+                        pps.num_ref_idx_l0_default_active_minus1 + 1,
+                        i32::from(pps.pic_init_qp_minus26) + 26,
+                        pps.transform_8x8_mode_flag,
+                        pps.deblocking_filter_control_present_flag,
+                    )
+                    .unwrap();
+                }
+                _ => (),
+            }
+        }
+    }
+    writeln!(digest, "crc32 {:08x}", crc.finalize()).unwrap();
+
+    digest
+}
+
+/// Returns the path of the golden file `name`.
+fn golden_path(name: &str) -> PathBuf {
+    [
+        env!("CARGO_MANIFEST_DIR"),
+        "src",
+        "encoder",
+        "stateless",
+        "h264",
+        "test_data",
+        &format!("{}.golden", name),
+    ]
+    .iter()
+    .collect()
+}
+
+/// Checks `digest` against the golden file `name`, or replaces the golden file with it if
+/// [`UPDATE_GOLDEN_VAR`] is set.
+fn check_golden(name: &str, digest: &str) {
+    let path = golden_path(name);
+    if std::env::var_os(UPDATE_GOLDEN_VAR).is_some() {
+        std::fs::write(&path, digest).unwrap();
+        return;
+    }
+
+    let golden = std::fs::read_to_string(&path).unwrap_or_else(|e| {
+        panic!(
+            "cannot read {}: {}, set {} to create it",
+            path.display(),
+            e,
+            UPDATE_GOLDEN_VAR
+        )
+    });
+    if let Some(line) = first_difference(&golden, digest) {
+        panic!(
+            "stream structure differs from {} at line {}, set {} to update it if intended\n\
+             expected: {}\n   found: {}",
+            path.display(),
+            line + 1,
+            UPDATE_GOLDEN_VAR,
+            golden.lines().nth(line).unwrap_or("<end>"),
+            digest.lines().nth(line).unwrap_or("<end>"),
+        );
+    }
+}
+
+/// Returns the index of the first line that differs between `golden` and `digest`, if any.
+fn first_difference(golden: &str, digest: &str) -> Option<usize> {
+    let (mut golden, mut digest) = (golden.lines(), digest.lines());
+
+    (0..).find_map(|line| match (golden.next(), digest.next()) {
+        (None, None) => Some(None),
+        (expected, found) if expected != found => Some(Some(line)),
+        _ => None,
+    })?
+}
+
+#[test]
+fn golden_low_delay() {
+    let digest = encode_digest(EncoderConfig::default(), &[0; 12], |_, _| ());
+    check_golden("low_delay", &digest);
+}
+
+#[test]
+fn golden_keyframes() {
+    let config = EncoderConfig {
+        pred_structure: PredictionStructure::LowDelay { tail: 2, limit: 16 },
+        ..Default::default()
+    };
+    // Keyframes are requested at 4, due at 20 after the limit, and a segment starts at 23.
+    let digest = encode_digest(config, &[0; 26], |encoder, timestamp| match timestamp {
+        4 => encoder.request_keyframe(),
+        23 => encoder.request_segment_point(),
+        _ => (),
+    });
+    check_golden("keyframes", &digest);
+}
+
+#[test]
+fn golden_temporal_layers() {
+    let config = EncoderConfig {
+        bitrate: Bitrate::PerLayer(vec![500_000, 250_000, 250_000]),
+        ..Default::default()
+    };
+    let digest = encode_digest(config, &[0; 12], |_, _| ());
+    check_golden("temporal_layers", &digest);
+}
+
+#[test]
+fn golden_long_term_references() {
+    let config = EncoderConfig {
+        long_term_references: 2,
+        ..Default::default()
+    };
+    let digest = encode_digest(config, &[0; 12], |encoder, timestamp| match timestamp {
+        1 => encoder.mark_long_term_reference(0).unwrap(),
+        4 => encoder.mark_long_term_reference(1).unwrap(),
+        7 => encoder.encode_recovery_frame(0).unwrap(),
+        9 => assert!(encoder.invalidate_reference(8)),
+        _ => (),
+    });
+    check_golden("long_term_references", &digest);
+}
+
+#[test]
+fn golden_skip_duplicate_frames() {
+    let config = EncoderConfig {
+        skip_duplicate_frames: true,
+        ..Default::default()
+    };
+    let digest = encode_digest(config, &[0, 0, 1, 2, 2, 2, 3, 4, 4, 5], |_, _| ());
+    check_golden("skip_duplicate_frames", &digest);
+}
+
+#[test]
+fn golden_differences() {
+    let digest = encode_digest(EncoderConfig::default(), &[0; 12], |_, _| ());
+    assert_eq!(first_difference(&digest, &digest), None);
+
+    let changed = digest.replacen("poc_lsb=2", "poc_lsb=4", 1);
+    let line = digest.lines().position(|line| line.contains("poc_lsb=2"));
+    assert!(line.is_some());
+    assert_eq!(first_difference(&digest, &changed), line);
+
+    let truncated = digest.lines().take(3).collect::<Vec<_>>().join("\n");
+    assert_eq!(first_difference(&digest, &truncated), Some(3));
+}
@@ -6,16 +6,25 @@ use std::collections::VecDeque;
 use std::rc::Rc;
 
 use log::trace;
+use log::warn;
 
+use crate::codec::h264::parser::FramePackingArrangement;
 use crate::codec::h264::parser::Level;
+use crate::codec::h264::parser::MaxLongTermFrameIdx;
 use crate::codec::h264::parser::Pps;
 use crate::codec::h264::parser::PpsBuilder;
 use crate::codec::h264::parser::Profile;
+use crate::codec::h264::parser::RefPicListModification;
+use crate::codec::h264::parser::RefPicMarking;
+use crate::codec::h264::parser::RefPicMarkingInner;
+use crate::codec::h264::parser::Sei;
+use crate::codec::h264::parser::SeiMessage;
 use crate::codec::h264::parser::SliceHeaderBuilder;
 use crate::codec::h264::parser::SliceType;
 use crate::codec::h264::parser::Sps;
 use crate::codec::h264::parser::SpsBuilder;
 use crate::codec::h264::synthesizer::Synthesizer;
+use crate::encoder::stateless::h264::parse_parameter_sets;
 use crate::encoder::stateless::h264::BackendRequest;
 use crate::encoder::stateless::h264::DpbEntry;
 use crate::encoder::stateless::h264::DpbEntryMeta;
@@ -25,6 +34,8 @@ use crate::encoder::stateless::EncodeError;
 use crate::encoder::stateless::EncodeResult;
 use crate::encoder::stateless::FrameMetadata;
 use crate::encoder::stateless::Predictor;
+use crate::encoder::Bitrate;
+use crate::encoder::FramePacking;
 
 /// Available predictors and initialization parameters
 #[derive(Clone)]
@@ -36,6 +47,343 @@ pub enum PredictionStructure {
     LowDelay { tail: u16, limit: u16 },
 }
 
+impl PredictionStructure {
+    /// Returns the number of frames the structure holds back to encode them out of order, e.g.
+    /// B-frames waiting for their future references, which adds to the end-to-end latency. It does
+    /// not account for the frames in flight in the backend.
+    pub fn structural_delay(&self) -> u32 {
+        match self {
+            // Frames are encoded in display order.
+            PredictionStructure::LowDelay { .. } => 0,
+        }
+    }
+}
+
+/// Returns the frame packing arrangement SEI message signaling `frame_packing`, which persists
+/// until the end of the coded video sequence.
+fn frame_packing_arrangement(frame_packing: FramePacking) -> FramePackingArrangement {
+    FramePackingArrangement {
+        // Table D-8.
+        frame_packing_arrangement_type: match frame_packing {
+            FramePacking::SideBySide => 3,
+            FramePacking::TopBottom => 4,
+        },
+        // Table D-9: constituent frame 0 is the left view.
+        content_interpretation_type: 1,
+        frame_packing_arrangement_repetition_period: 1,
+        ..Default::default()
+    }
+}
+
+/// Maximum number of temporal layers of [`LowDelay`].
+const MAX_TEMPORAL_LAYERS: usize = 3;
+
+/// Returns the temporal layer of the frame `frame_num` of a sequence with `num_layers` temporal
+/// layers. The layers follow the dyadic pattern, e.g. 0, 2, 1, 2 for three layers, repeating every
+/// `2^(num_layers - 1)` frames.
+pub(super) fn temporal_layer(frame_num: u32, num_layers: usize) -> u8 {
+    let period = 1 << (num_layers - 1);
+    match frame_num % period {
+        0 => 0,
+        n => (num_layers - 1 - n.trailing_zeros() as usize) as u8,
+    }
+}
+
+/// Writes `pps` and the SPS it refers to into `headers`.
+fn synthesize_parameter_sets(pps: &Pps, headers: &mut Vec<u8>) -> EncodeResult<()> {
+    Synthesizer::<Sps, Vec<u8>>::synthesize(3, &pps.sps, headers, true)?;
+    Synthesizer::<Pps, Vec<u8>>::synthesize(3, pps, headers, true)?;
+    Ok(())
+}
+
+/// Removes the entry of `timestamp` from `requests` and returns its index, if any.
+fn take_index(requests: &mut Vec<(u64, u8)>, timestamp: u64) -> Option<u8> {
+    let position = requests.iter().position(|&(t, _)| t == timestamp)?;
+    Some(requests.remove(position).1)
+}
+
+/// Returns the frame rate of frames lasting `duration` units of `timescale` per second, as a
+/// numerator and denominator reduced to fit in 16 bits each.
+fn frame_rate(timescale: u32, duration: u64) -> (u32, u32) {
+    fn gcd(a: u64, b: u64) -> u64 {
+        if b == 0 {
+            a
+        } else {
+            gcd(b, a % b)
+        }
+    }
+
+    let divisor = gcd(u64::from(timescale), duration).max(1);
+    let (mut num, mut den) = (u64::from(timescale) / divisor, duration / divisor);
+    while num > u64::from(u16::MAX) || den > u64::from(u16::MAX) {
+        num >>= 1;
+        den >>= 1;
+    }
+
+    (num.max(1) as u32, den.max(1) as u32)
+}
+
+/// Returns the `constraint_set0_flag` to `constraint_set5_flag` of the SPS, along with its
+/// `level_idc`, signaling the profiles the stream conforms to given the coding tools used by the
+/// predictor (H.264 A.2).
+fn constraint_flags(config: &EncoderConfig) -> ([bool; 6], Level) {
+    let profile = config.profile;
+    // Frames of 4:2:0 8-bit samples coded with CAVLC, without FMO, ASO nor redundant slices, are
+    // also conforming to the Baseline, Main and Extended profiles. The latter requires
+    // direct_8x8_inference_flag.
+    let baseline_family = matches!(
+        profile,
+        Profile::Baseline | Profile::Main | Profile::Extended
+    );
+    // Level 1b is signaled with level_idc 11 and constraint_set3_flag in these profiles.
+    let level_1b = baseline_family && config.level == Level::L1B;
+    // Only frames are coded and there are no B slices, i.e. the Progressive and Constrained High
+    // profiles.
+    let progressive = matches!(profile, Profile::Main | Profile::Extended | Profile::High);
+
+    let flags = [
+        baseline_family,
+        baseline_family,
+        baseline_family && config.level >= Level::L3,
+        level_1b,
+        progressive,
+        progressive,
+    ];
+    let level = if level_1b { Level::L1_1 } else { config.level };
+
+    (flags, level)
+}
+
+/// Returns the number of temporal layers of `config` the predictor supports.
+fn num_layers(config: &EncoderConfig) -> usize {
+    config.bitrate.num_layers().min(MAX_TEMPORAL_LAYERS)
+}
+
+/// Checks that the parameter sets, either provided with [`EncoderConfig::parameter_sets`] and
+/// parsed into `pps`, or generated, are usable by the predictor and match the other fields of
+/// `config`.
+fn check_parameter_sets(config: &EncoderConfig, pps: Option<&Rc<Pps>>) -> EncodeResult<()> {
+    let mismatch = |reason: String| Err(EncodeError::InvalidParameterSets(reason));
+    if config.constrained_profile && !matches!(config.profile, Profile::Baseline | Profile::High) {
+        return mismatch(format!(
+            "profile_idc {} has no constrained variant",
+            config.profile as u8
+        ));
+    }
+
+    // The generated parameter sets always signal the constrained profiles.
+    let Some(pps) = pps else {
+        return Ok(());
+    };
+    let sps = &pps.sps;
+
+    let constrained = match config.profile {
+        Profile::Baseline => sps.constraint_set1_flag,
+        Profile::High => sps.constraint_set4_flag && sps.constraint_set5_flag,
+        _ => false,
+    };
+    if config.constrained_profile && !constrained {
+        return mismatch("the SPS does not signal a constrained profile".into());
+    }
+
+    if sps.profile_idc != config.profile as u8 {
+        return mismatch(format!("profile_idc is {}", sps.profile_idc));
+    }
+    if sps.level_idc != constraint_flags(config).1 {
+        return mismatch(format!("level_idc is {:?}", sps.level_idc));
+    }
+    // H.264 Table 6-1
+    let chroma_format_idc = match config.profile {
+        Profile::High422P => 2,
+        _ => 1,
+    };
+    if sps.chroma_format_idc != chroma_format_idc {
+        return mismatch(format!("chroma_format_idc is {}", sps.chroma_format_idc));
+    }
+    if sps.bit_depth_luma_minus8 != 0 || sps.bit_depth_chroma_minus8 != 0 {
+        return mismatch("only 8-bit samples are supported".into());
+    }
+
+    // The slices are coded as frames with explicit picture order counts.
+    if !sps.frame_mbs_only_flag || sps.pic_order_cnt_type != 0 {
+        return mismatch("only frames with pic_order_cnt_type 0 are supported".into());
+    }
+
+    // H.264 7.4.2.1.1, with the crop units of 4:2:0 and 4:2:2 frames
+    let (left, right, top, bottom) = if sps.frame_cropping_flag {
+        (
+            sps.frame_crop_left_offset,
+            sps.frame_crop_right_offset,
+            sps.frame_crop_top_offset,
+            sps.frame_crop_bottom_offset,
+        )
+    } else {
+        (0, 0, 0, 0)
+    };
+    let crop_unit_y = if chroma_format_idc == 1 { 2 } else { 1 };
+    let width = ((sps.pic_width_in_mbs_minus1 + 1) * 16).saturating_sub(2 * (left + right));
+    let height = ((sps.pic_height_in_map_units_minus1 + 1) * 16)
+        .saturating_sub(crop_unit_y * (top + bottom));
+    if (width, height) != (config.resolution.width, config.resolution.height) {
+        return mismatch(format!("resolution is {}x{}", width, height));
+    }
+
+    let num_layers = num_layers(config);
+    if num_layers > 1 && !sps.gaps_in_frame_num_value_allowed_flag {
+        return mismatch("temporal layers require gaps_in_frame_num_value_allowed_flag".into());
+    }
+    // At least one reference frame of each layer, the long-term references and the current frame.
+    let min_ref_frames = (1 << (num_layers - 1)) + config.long_term_references as u32 + 1;
+    if sps.max_num_ref_frames < min_ref_frames {
+        return mismatch(format!(
+            "max_num_ref_frames is {}, at least {} are needed",
+            sps.max_num_ref_frames, min_ref_frames
+        ));
+    }
+
+    Ok(())
+}
+
+/// Timestamp and references of a frame of the sequence, to find the frames descending from a
+/// lost one.
+struct Ancestry {
+    timestamp: u64,
+    /// Frame numbers of the references
+    references: Vec<u32>,
+    /// True if the frame was lost by the receiver or references a lost frame
+    invalid: bool,
+}
+
+/// Reference frames held by the decoders, following the marking process of H.264 8.2.5. The
+/// decoders may hold more short-term references than the predictor, as well as the ones it
+/// dropped, which the memory management operations marking long-term references account for.
+#[derive(Default)]
+struct DecoderReferences {
+    /// Frame numbers of the short-term references, from the oldest
+    short_term: VecDeque<u32>,
+    /// Whether each long-term frame index is used
+    long_term: Vec<bool>,
+    /// Frame number of the previous reference frame
+    prev_ref_frame_num: u32,
+}
+
+impl DecoderReferences {
+    /// Marks the IDR frame, which drops all the references.
+    fn idr(&mut self, is_reference: IsReference, long_term_references: usize) {
+        self.short_term.clear();
+        self.long_term = vec![false; long_term_references];
+        self.prev_ref_frame_num = 0;
+
+        match is_reference {
+            IsReference::LongTerm => self.long_term[0] = true,
+            _ => self.short_term.push_back(0),
+        }
+    }
+
+    /// Marks the interframe `frame_num` as `is_reference`, returning the operations needed by
+    /// long-term references.
+    fn interframe(
+        &mut self,
+        frame_num: u32,
+        is_reference: IsReference,
+        long_term_frame_idx: u32,
+        max_num_ref_frames: usize,
+    ) -> RefPicMarking {
+        // The frames missing from frame_num, e.g. the dropped disposable frames, are inferred by
+        // the decoders.
+        for missing in self.prev_ref_frame_num + 1..frame_num {
+            self.sliding_window(max_num_ref_frames);
+            self.short_term.push_back(missing);
+        }
+
+        match is_reference {
+            IsReference::No => return Default::default(),
+            IsReference::ShortTerm => {
+                self.sliding_window(max_num_ref_frames);
+                self.short_term.push_back(frame_num);
+                self.prev_ref_frame_num = frame_num;
+                return Default::default();
+            }
+            IsReference::LongTerm => (),
+        }
+
+        let operation = |mmco| RefPicMarkingInner {
+            memory_management_control_operation: mmco,
+            ..Default::default()
+        };
+        let mut operations = vec![RefPicMarkingInner {
+            max_long_term_frame_idx: MaxLongTermFrameIdx::Idx(self.long_term.len() as u32 - 1),
+            ..operation(4)
+        }];
+
+        // The sliding window does not apply to the frames marked explicitly, so the oldest
+        // short-term reference makes room for a new long-term one.
+        let index = long_term_frame_idx as usize;
+        let num_long_term = self.long_term.iter().filter(|&&used| used).count();
+        if !self.long_term[index] && self.short_term.len() + num_long_term >= max_num_ref_frames {
+            if let Some(oldest) = self.short_term.pop_front() {
+                operations.push(RefPicMarkingInner {
+                    difference_of_pic_nums_minus1: frame_num - oldest - 1,
+                    ..operation(1)
+                });
+            }
+        }
+
+        operations.push(RefPicMarkingInner {
+            long_term_frame_idx,
+            ..operation(6)
+        });
+        self.long_term[index] = true;
+        self.prev_ref_frame_num = frame_num;
+
+        RefPicMarking {
+            adaptive_ref_pic_marking_mode_flag: true,
+            inner: operations,
+            ..Default::default()
+        }
+    }
+
+    /// Unmarks the oldest short-term reference if the current frame would exceed
+    /// `max_num_ref_frames`, see H.264 8.2.5.3.
+    fn sliding_window(&mut self, max_num_ref_frames: usize) {
+        let num_long_term = self.long_term.iter().filter(|&&used| used).count();
+        if self.short_term.len() + num_long_term >= max_num_ref_frames {
+            self.short_term.pop_front();
+        }
+    }
+}
+
+/// Returns the modification of the reference list of the frame `frame_num`, placing each of
+/// `references` in order whatever the references held by the decoders, see H.264 8.2.4.3.
+fn ref_pic_list_modification<R>(
+    frame_num: u32,
+    references: &[Rc<DpbEntry<R>>],
+) -> Vec<RefPicListModification> {
+    let mut pic_num_pred = frame_num;
+
+    references
+        .iter()
+        .map(|entry| match entry.meta.is_reference {
+            IsReference::LongTerm => RefPicListModification {
+                modification_of_pic_nums_idc: 2,
+                long_term_pic_num: entry.meta.long_term_frame_idx,
+                ..Default::default()
+            },
+            // The frame numbers do not wrap within a sequence.
+            _ => {
+                let pic_num = entry.meta.frame_num;
+                let modification = RefPicListModification {
+                    modification_of_pic_nums_idc: if pic_num < pic_num_pred { 0 } else { 1 },
+                    abs_diff_pic_num_minus1: pic_num.abs_diff(pic_num_pred) - 1,
+                    ..Default::default()
+                };
+                pic_num_pred = pic_num;
+                modification
+            }
+        })
+        .collect()
+}
+
 /// Implementation of [`LowDelay`] prediction structure. See [`LowDelay`] for details.
 ///
 /// [`LowDelay`]: PredictionStructure::LowDelay
@@ -46,47 +394,368 @@ pub(super) struct LowDelay<P, R> {
     limit: u16,
     /// Target number of reference frames that an interframe should have
     tail: u16,
+    /// Number of temporal layers, see [`Bitrate::PerLayer`]
+    ///
+    /// [`Bitrate::PerLayer`]: crate::encoder::Bitrate::PerLayer
+    num_layers: usize,
 
     /// Queue of pending frames to be encoded
     queue: VecDeque<(P, FrameMetadata)>,
 
     /// The currently held frames in POC increasing order.
     dpb: VecDeque<Rc<DpbEntry<R>>>,
+    /// Long-term references by their index, see [`EncoderConfig::long_term_references`]
+    long_term: Vec<Option<Rc<DpbEntry<R>>>>,
+    /// Frame number of the last reconstructed frame
+    last_reconstructed: Option<u32>,
+    /// Number of the requested frames not reconstructed yet
+    pending: usize,
+    /// Number of the pending frames belonging to the previous sequence, whose reconstructions
+    /// must not be referenced
+    stale: usize,
+
+    /// Timestamps of the frames to be marked as long-term reference, with its index
+    long_term_marks: Vec<(u64, u8)>,
+    /// Timestamps of the frames to be recovered from a long-term reference, with its index
+    recoveries: Vec<(u64, u8)>,
+    /// Ancestry of the frames of the sequence, by their frame number
+    ancestry: Vec<Ancestry>,
+    /// References held by the decoders
+    decoder_references: DecoderReferences,
 
     /// Current sequence SPS
     sps: Option<Rc<Sps>>,
     /// Current sequence PPS
     pps: Option<Rc<Pps>>,
+    /// Parsed [`EncoderConfig::parameter_sets`]
+    parameter_sets: Option<Rc<Pps>>,
+
+    /// Timestamp of the previously requested frame, to derive the frame rate of variable frame
+    /// rate streams
+    last_timestamp: Option<u64>,
 
     /// Encoder config
     config: EncoderConfig,
 }
 
 impl<P, R> LowDelay<P, R> {
-    pub(super) fn new(config: EncoderConfig) -> Self {
-        let (tail, limit) = match config.pred_structure {
+    /// Creates the predictor of `config`, failing if its prediction structure or parameter sets
+    /// are not usable.
+    pub(super) fn new(mut config: EncoderConfig) -> EncodeResult<Self> {
+        let (mut tail, mut limit) = match config.pred_structure {
             PredictionStructure::LowDelay { tail, limit } => (tail, limit),
         };
+        if limit == 0 {
+            return Err(EncodeError::InvalidPredictionStructure(
+                "sequences must contain at least one frame".into(),
+            ));
+        }
+        if tail == 0 {
+            return Err(EncodeError::InvalidPredictionStructure(
+                "interframes must have at least one reference frame".into(),
+            ));
+        }
+        if config.resolution.width == 0 || config.resolution.height == 0 {
+            return Err(EncodeError::InvalidResolution {
+                width: config.resolution.width,
+                height: config.resolution.height,
+            });
+        }
+        let parameter_sets = config
+            .parameter_sets
+            .as_deref()
+            .map(parse_parameter_sets)
+            .transpose()
+            .map_err(|e| EncodeError::InvalidParameterSets(format!("{:#}", e)))?;
+        check_parameter_sets(&config, parameter_sets.as_ref())?;
+
+        let mut num_layers = config.bitrate.num_layers();
+        if num_layers > MAX_TEMPORAL_LAYERS {
+            warn!(
+                "{} temporal layers are not supported, reducing to {}",
+                num_layers, MAX_TEMPORAL_LAYERS
+            );
+            num_layers = MAX_TEMPORAL_LAYERS;
 
-        Self {
+            // The top layer carries the bitrate of the dropped ones.
+            if let Bitrate::PerLayer(targets) = &mut config.bitrate {
+                let dropped: u64 = targets.drain(num_layers..).sum();
+                targets[num_layers - 1] += dropped;
+            }
+        }
+
+        // The SPS declares one more reference frame than the frames held to find `tail`
+        // references in each layer, which must fit in the DPB of the configured level.
+        let period = 1 << (num_layers - 1);
+        let max_dpb_frames = config.level.max_dpb_frames(
+            config.resolution.width.div_ceil(16),
+            config.resolution.height.div_ceil(16),
+        );
+        let long_term_references = config.long_term_references as usize;
+        let max_tail = (max_dpb_frames.saturating_sub(1 + long_term_references) / period) as u16;
+        if max_tail == 0 {
+            return Err(EncodeError::InvalidPredictionStructure(format!(
+                "{} temporal layers and {} long-term references exceed the DPB of level {:?}",
+                num_layers, long_term_references, config.level
+            )));
+        }
+        if tail > max_tail {
+            warn!(
+                "{} reference frames exceed the DPB of level {:?}, reducing to {}",
+                tail, config.level, max_tail
+            );
+            tail = max_tail;
+        }
+
+        // The provided SPS bounds the frame numbers, picture order counts and references.
+        if let Some(pps) = &parameter_sets {
+            let sps = &pps.sps;
+            let max_poc_lsb = 1u32 << (sps.log2_max_pic_order_cnt_lsb_minus4 + 4);
+            let max_limit = std::cmp::min(sps.max_frame_num(), max_poc_lsb / 2);
+            if u32::from(limit) > max_limit {
+                warn!(
+                    "sequences of {} frames exceed the SPS, reducing to {}",
+                    limit, max_limit
+                );
+                limit = max_limit as u16;
+            }
+
+            // Not zero, the SPS holds at least one reference frame of each layer.
+            let max_tail =
+                (sps.max_num_ref_frames as usize).saturating_sub(1 + long_term_references) / period;
+            let max_tail = max_tail as u16;
+            if tail > max_tail {
+                warn!(
+                    "{} reference frames exceed the SPS, reducing to {}",
+                    tail, max_tail
+                );
+                tail = max_tail;
+            }
+        }
+
+        Ok(Self {
             counter: 0,
             limit,
             tail,
+            num_layers,
             queue: Default::default(),
             dpb: Default::default(),
+            long_term: vec![None; long_term_references],
+            last_reconstructed: None,
+            pending: 0,
+            stale: 0,
+            long_term_marks: Default::default(),
+            recoveries: Default::default(),
+            ancestry: Default::default(),
+            decoder_references: Default::default(),
             sps: None,
             pps: None,
+            parameter_sets,
+            last_timestamp: None,
             config,
-        }
+        })
     }
 }
 
 impl<Picture, Reference> LowDelay<Picture, Reference> {
+    /// Returns the number of the most recent frames held in the DPB, which contain `tail` frames
+    /// of the base layer.
+    fn window(&self) -> usize {
+        self.tail as usize * (1 << (self.num_layers - 1))
+    }
+
+    /// Returns `requests`, after checking them along with the predictor state if the
+    /// `strict-checks` feature is enabled, as well as in tests.
+    fn checked(
+        &self,
+        requests: EncodeResult<Vec<BackendRequest<Picture, Reference>>>,
+    ) -> EncodeResult<Vec<BackendRequest<Picture, Reference>>> {
+        #[cfg(any(test, feature = "strict-checks"))]
+        if let Ok(requests) = &requests {
+            self.check_invariants(requests)?;
+        }
+
+        requests
+    }
+
+    /// Checks the invariants of the DPB, and the frame numbers, picture order counts and reference
+    /// lists of the `requests` just yielded, describing the state on violation.
+    #[cfg(any(test, feature = "strict-checks"))]
+    fn check_invariants(
+        &self,
+        requests: &[BackendRequest<Picture, Reference>],
+    ) -> EncodeResult<()> {
+        let violation = |reason: String| {
+            let dpb: Vec<_> = self.dpb.iter().map(|entry| entry.meta.frame_num).collect();
+            let long_term: Vec<_> = self
+                .long_term
+                .iter()
+                .map(|entry| entry.as_ref().map(|entry| entry.meta.frame_num))
+                .collect();
+            let state = format!(
+                "{} (counter={} dpb={:?} long_term={:?})",
+                reason, self.counter, dpb, long_term
+            );
+            log::error!("predictor invariant violated: {}", state);
+            Err(EncodeError::InvalidPredictorState(state))
+        };
+
+        if self.dpb.len() > self.window() {
+            return violation(format!(
+                "the DPB holds {} frames, above the window of {}",
+                self.dpb.len(),
+                self.window()
+            ));
+        }
+        for (entry, next) in self.dpb.iter().zip(self.dpb.iter().skip(1)) {
+            if entry.meta.frame_num >= next.meta.frame_num {
+                return violation(format!(
+                    "frame {} precedes frame {} in the DPB",
+                    entry.meta.frame_num, next.meta.frame_num
+                ));
+            }
+        }
+        if let Some(entry) = self
+            .dpb
+            .iter()
+            .find(|entry| entry.meta.is_reference == IsReference::LongTerm)
+        {
+            return violation(format!(
+                "long-term reference {} is in the DPB",
+                entry.meta.frame_num
+            ));
+        }
+        for (index, entry) in self.long_term.iter().enumerate() {
+            let Some(entry) = entry else {
+                continue;
+            };
+            if entry.meta.is_reference != IsReference::LongTerm
+                || entry.meta.long_term_frame_idx as usize != index
+            {
+                return violation(format!(
+                    "frame {} is not long-term reference {}",
+                    entry.meta.frame_num, index
+                ));
+            }
+        }
+
+        let mut next_frame_num = None;
+        for request in requests {
+            let frame_num = request.dpb_meta.frame_num;
+            let poc = u32::from(request.dpb_meta.poc);
+            let sps = &request.sps;
+
+            if !Rc::ptr_eq(sps, &request.pps.sps) {
+                return violation(format!(
+                    "frame {}: the PPS refers to another SPS",
+                    frame_num
+                ));
+            }
+            if frame_num >= sps.max_frame_num() {
+                return violation(format!(
+                    "frame_num {} exceeds the maximum of {}",
+                    frame_num,
+                    sps.max_frame_num()
+                ));
+            }
+            if poc != frame_num * 2 || poc >= 1 << (sps.log2_max_pic_order_cnt_lsb_minus4 + 4) {
+                return violation(format!(
+                    "frame {}: invalid picture order count {}",
+                    frame_num, poc
+                ));
+            }
+            if request.is_idr != (frame_num == 0) {
+                return violation(format!(
+                    "frame {}: only the first frame of a sequence is an IDR",
+                    frame_num
+                ));
+            }
+            if !request.is_idr && next_frame_num.is_some_and(|next| next != frame_num) {
+                return violation(format!(
+                    "frame_num {} does not follow the previous request",
+                    frame_num
+                ));
+            }
+            next_frame_num = Some(frame_num + 1);
+
+            let references: Vec<_> = request
+                .ref_list_0
+                .iter()
+                .map(|entry| entry.meta.frame_num)
+                .collect();
+            if request.is_idr && !(references.is_empty() && request.ref_list_1.is_empty()) {
+                return violation(format!("IDR frame references {:?}", references));
+            }
+            if !request.is_idr && references.is_empty() {
+                return violation(format!("frame {} has no reference", frame_num));
+            }
+            if !request.ref_list_1.is_empty() {
+                return violation(format!("frame {} has future references", frame_num));
+            }
+            if references.len() > sps.max_num_ref_frames as usize {
+                return violation(format!(
+                    "frame {} has {} references, above the maximum of {}",
+                    frame_num,
+                    references.len(),
+                    sps.max_num_ref_frames
+                ));
+            }
+
+            for (index, reference) in request.ref_list_0.iter().enumerate() {
+                let reference_num = reference.meta.frame_num;
+                let reason = if reference_num >= frame_num {
+                    "does not precede it"
+                } else if references[..index].contains(&reference_num) {
+                    "is listed twice"
+                } else if reference.meta.is_reference == IsReference::No {
+                    "is not a reference"
+                } else if self.is_invalid(reference_num) {
+                    "is invalidated"
+                } else if reference.meta.is_reference == IsReference::ShortTerm
+                    && temporal_layer(reference_num, self.num_layers) > request.temporal_layer
+                {
+                    "is of an upper temporal layer"
+                } else {
+                    continue;
+                };
+
+                return violation(format!(
+                    "frame {} references frame {}, which {}",
+                    frame_num, reference_num, reason
+                ));
+            }
+        }
+
+        if let Some(next_frame_num) = next_frame_num {
+            if u32::from(self.counter) != next_frame_num {
+                return violation(format!(
+                    "the next frame is numbered {} instead of {}",
+                    self.counter, next_frame_num
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
     fn new_sequence(&mut self) {
         trace!("beginning new sequence");
+        self.stale = self.pending;
+        self.dpb.clear();
+        self.long_term.fill(None);
+        self.ancestry.clear();
+
+        if let Some(pps) = &self.parameter_sets {
+            self.sps = Some(Rc::clone(&pps.sps));
+            self.pps = Some(Rc::clone(pps));
+            return;
+        }
+
+        let (constraint_flags, level) = constraint_flags(&self.config);
         let mut sps = SpsBuilder::new()
             .seq_parameter_set_id(0)
-            .profile_idc(self.config.profile);
+            .profile_idc(self.config.profile)
+            .constraint_set_flags(constraint_flags);
 
         // H.264 Table 6-1
         sps = match self.config.profile {
@@ -97,11 +766,13 @@ impl<Picture, Reference> LowDelay<Picture, Reference> {
         };
 
         let sps = sps
-            .level_idc(self.config.level)
+            .level_idc(level)
             .max_frame_num(self.limit as u32)
             .pic_order_cnt_type(0)
             .max_pic_order_cnt_lsb(self.limit as u32 * 2)
-            .max_num_ref_frames(self.tail as u32 + 1)
+            .max_num_ref_frames(self.window() as u32 + 1 + self.long_term.len() as u32)
+            // Receivers dropping the upper temporal layers see gaps in frame_num
+            .gaps_in_frame_num_value_allowed_flag(self.num_layers > 1)
             .frame_mbs_only_flag(true)
             // H264 spec Table A-4
             .direct_8x8_inference_flag(self.config.level >= Level::L3)
@@ -121,11 +792,40 @@ impl<Picture, Reference> LowDelay<Picture, Reference> {
             .num_ref_idx_l1_default_active_minus1(0)
             .build();
 
-        self.dpb.clear();
         self.sps = Some(sps);
         self.pps = Some(pps);
     }
 
+    /// Returns true if the frame `frame_num` of the sequence must not be referenced, see
+    /// [`Predictor::invalidate`].
+    fn is_invalid(&self, frame_num: u32) -> bool {
+        self.ancestry
+            .get(frame_num as usize)
+            .is_some_and(|ancestry| ancestry.invalid)
+    }
+
+    /// Returns the reference marking of the frame of `timestamp`, see
+    /// [`Predictor::mark_long_term`].
+    fn take_reference_marking(&mut self, timestamp: u64) -> (IsReference, u32) {
+        match take_index(&mut self.long_term_marks, timestamp) {
+            Some(index) => (IsReference::LongTerm, u32::from(index)),
+            None => (IsReference::ShortTerm, 0),
+        }
+    }
+
+    /// Returns the frame rate of the frame of `input_meta`, derived from the time elapsed since
+    /// the previous frame if the timestamps have a [`EncoderConfig::timescale`].
+    fn frame_rate(&mut self, input_meta: &FrameMetadata) -> (u32, u32) {
+        let last_timestamp = self.last_timestamp.replace(input_meta.timestamp);
+
+        match (self.config.timescale, last_timestamp) {
+            (Some(timescale), Some(last)) if input_meta.timestamp > last => {
+                frame_rate(timescale, input_meta.timestamp - last)
+            }
+            _ => (self.config.framerate, 1),
+        }
+    }
+
     fn request_idr(
         &mut self,
         input: Picture,
@@ -135,30 +835,64 @@ impl<Picture, Reference> LowDelay<Picture, Reference> {
         self.counter = 0;
         self.new_sequence();
 
-        // SAFETY: SPS and PPS were initialized by [`Self::new_sequence()`]
-        let sps = self.sps.clone().unwrap();
-        let pps = self.pps.clone().unwrap();
+        // SPS and PPS are initialized by [`Self::new_sequence()`]
+        let sps = self.sps.clone().ok_or(EncodeError::InvalidInternalState)?;
+        let pps = self.pps.clone().ok_or(EncodeError::InvalidInternalState)?;
+
+        let (mut is_reference, mut long_term_frame_idx) =
+            self.take_reference_marking(input_meta.timestamp);
+        // IDR frames can only be marked as the long-term reference 0, see H.264 7.4.3.3.
+        if is_reference == IsReference::LongTerm && long_term_frame_idx != 0 {
+            warn!(
+                "IDR frames cannot be marked as long-term reference {}",
+                long_term_frame_idx
+            );
+            (is_reference, long_term_frame_idx) = (IsReference::ShortTerm, 0);
+        }
 
         let dpb_meta = DpbEntryMeta {
             poc: self.counter * 2,
             frame_num: self.counter as u32,
-            is_reference: IsReference::ShortTerm,
+            is_reference,
+            long_term_frame_idx,
         };
+        self.decoder_references
+            .idr(is_reference, self.long_term.len());
+
+        self.ancestry.push(Ancestry {
+            timestamp: input_meta.timestamp,
+            references: vec![],
+            invalid: false,
+        });
 
         let header = SliceHeaderBuilder::new(&pps)
             .slice_type(SliceType::I)
             .first_mb_in_slice(0)
             .pic_order_cnt_lsb(dpb_meta.poc)
+            .dec_ref_pic_marking(RefPicMarking {
+                long_term_reference_flag: is_reference == IsReference::LongTerm,
+                ..Default::default()
+            })
             .build();
 
         self.counter += 1;
 
         let mut headers = vec![];
-        Synthesizer::<Sps, Vec<u8>>::synthesize(3, &sps, &mut headers, true)?;
-        Synthesizer::<Pps, Vec<u8>>::synthesize(3, &pps, &mut headers, true)?;
+        if !self.config.out_of_band_parameter_sets {
+            synthesize_parameter_sets(&pps, &mut headers)?;
+        }
+        if let Some(frame_packing) = self.config.frame_packing {
+            let sei = Sei {
+                messages: vec![SeiMessage::FramePackingArrangement(
+                    frame_packing_arrangement(frame_packing),
+                )],
+            };
+            Synthesizer::<Sei, Vec<u8>>::synthesize(0, &sei, &sps, &mut headers, true)?;
+        }
 
         let num_macroblocks =
             ((sps.pic_width_in_mbs_minus1 + 1) * (sps.pic_height_in_map_units_minus1 + 1)) as usize;
+        let framerate = self.frame_rate(&input_meta);
 
         let request = BackendRequest {
             sps,
@@ -175,10 +909,16 @@ impl<Picture, Reference> LowDelay<Picture, Reference> {
 
             is_idr: true,
             bitrate: self.config.bitrate.clone(),
+            adaptive_quantization: self.config.adaptive_quantization,
+            framerate,
+            temporal_layer: 0,
 
+            recon: None,
             coded_output: headers,
         };
 
+        self.pending += 1;
+
         Ok(vec![request])
     }
 
@@ -186,32 +926,133 @@ impl<Picture, Reference> LowDelay<Picture, Reference> {
         &mut self,
         input: Picture,
         input_meta: FrameMetadata,
-    ) -> Vec<BackendRequest<Picture, Reference>> {
-        let mut ref_list_0 = vec![];
+    ) -> EncodeResult<Vec<BackendRequest<Picture, Reference>>> {
+        let layer = temporal_layer(self.counter as u32, self.num_layers);
+
+        let ref_list_0: Vec<_> = match take_index(&mut self.recoveries, input_meta.timestamp) {
+            Some(index) => {
+                let reference = self.long_term[index as usize]
+                    .clone()
+                    .filter(|entry| !self.is_invalid(entry.meta.frame_num));
+                let Some(reference) = reference else {
+                    warn!(
+                        "long-term reference {} is gone, recovering with an IDR",
+                        index
+                    );
+                    return self.request_idr(input, input_meta);
+                };
 
-        // Use all avaiable reference frames in DPB. Their number is limited by the parameter
-        for reference in self.dpb.iter().rev() {
-            ref_list_0.push(Rc::clone(reference));
+                // The receiver may have lost any frame following the long-term reference, which
+                // must not be referenced anymore.
+                let frame_num = reference.meta.frame_num;
+                self.dpb.clear();
+                for entry in &mut self.long_term {
+                    if entry
+                        .as_ref()
+                        .is_some_and(|entry| entry.meta.frame_num > frame_num)
+                    {
+                        *entry = None;
+                    }
+                }
+
+                vec![reference]
+            }
+            None => {
+                let mut references: Vec<_> = self
+                    .dpb
+                    .iter()
+                    .chain(self.long_term.iter().flatten())
+                    .cloned()
+                    .collect();
+                references.sort_by_key(|entry| std::cmp::Reverse(entry.meta.frame_num));
+
+                // Use the most recent valid reference frames of the same or lower temporal
+                // layers, so that the upper layers can be dropped. Their number is limited by the
+                // parameter
+                references
+                    .into_iter()
+                    .filter(|entry| entry.meta.is_reference != IsReference::No)
+                    .filter(|entry| !self.is_invalid(entry.meta.frame_num))
+                    .filter(|entry| temporal_layer(entry.meta.frame_num, self.num_layers) <= layer)
+                    .take(self.tail as usize)
+                    .collect()
+            }
+        };
+
+        if ref_list_0.is_empty() {
+            warn!("no valid reference frame is left, encoding an IDR");
+            return self.request_idr(input, input_meta);
         }
 
-        // SAFETY: SPS and PPS were initialized during IDR request
-        let sps = self.sps.clone().unwrap();
-        let pps = self.pps.clone().unwrap();
+        self.ancestry.push(Ancestry {
+            timestamp: input_meta.timestamp,
+            references: ref_list_0
+                .iter()
+                .map(|entry| entry.meta.frame_num)
+                .collect(),
+            invalid: false,
+        });
+
+        // SPS and PPS are initialized during IDR request
+        let sps = self.sps.clone().ok_or(EncodeError::InvalidInternalState)?;
+        let pps = self.pps.clone().ok_or(EncodeError::InvalidInternalState)?;
+
+        let (mut is_reference, long_term_frame_idx) =
+            self.take_reference_marking(input_meta.timestamp);
+        // The frames of the top layer are only referenced by the following frames of that layer,
+        // which are made not to so that selective forwarding units can drop any of them. They
+        // still take a slot of the sliding window, like the frames inferred by the receivers
+        // from the gaps in frame_num.
+        let disposable = self.config.disposable_frames
+            && self.num_layers > 1
+            && usize::from(layer) == self.num_layers - 1;
+        if disposable && is_reference == IsReference::ShortTerm {
+            is_reference = IsReference::No;
+        }
 
         let dpb_meta = DpbEntryMeta {
             poc: self.counter * 2,
             frame_num: self.counter as u32,
-            is_reference: IsReference::ShortTerm,
+            is_reference,
+            long_term_frame_idx,
         };
 
-        let header = SliceHeaderBuilder::new(&pps)
+        // The reference list and the marking of long-term references are explicit, as the
+        // decoders may hold other references than the predictor.
+        let dec_ref_pic_marking = self.decoder_references.interframe(
+            dpb_meta.frame_num,
+            is_reference,
+            long_term_frame_idx,
+            sps.max_num_ref_frames as usize,
+        );
+        let mut header = SliceHeaderBuilder::new(&pps)
             .slice_type(SliceType::P)
             .first_mb_in_slice(0)
+            .frame_num(dpb_meta.frame_num as u16)
             .pic_order_cnt_lsb(dpb_meta.poc)
-            .build();
+            .ref_pic_list_modification_l0(ref_pic_list_modification(
+                dpb_meta.frame_num,
+                &ref_list_0,
+            ))
+            .dec_ref_pic_marking(dec_ref_pic_marking);
+        if ref_list_0.len() != usize::from(pps.num_ref_idx_l0_default_active_minus1) + 1 {
+            header = header.num_ref_idx_l0_active(ref_list_0.len() as u8);
+        }
+        let header = header.build();
+
+        // Receivers joining mid-stream need the parameter sets before the next IDR
+        let mut headers = vec![];
+        let repeat_parameter_sets = self
+            .config
+            .parameter_set_interval
+            .is_some_and(|interval| dpb_meta.frame_num.is_multiple_of(interval.max(1)));
+        if repeat_parameter_sets && !self.config.out_of_band_parameter_sets {
+            synthesize_parameter_sets(&pps, &mut headers)?;
+        }
 
         let num_macroblocks =
             ((sps.pic_width_in_mbs_minus1 + 1) * (sps.pic_height_in_map_units_minus1 + 1)) as usize;
+        let framerate = self.frame_rate(&input_meta);
 
         let request = BackendRequest {
             sps,
@@ -227,18 +1068,24 @@ impl<Picture, Reference> LowDelay<Picture, Reference> {
 
             is_idr: false,
             bitrate: self.config.bitrate.clone(),
+            adaptive_quantization: self.config.adaptive_quantization,
+            framerate,
+            temporal_layer: layer,
 
-            coded_output: vec![],
+            recon: None,
+            coded_output: headers,
         };
 
         self.counter += 1;
 
         // Remove obselete reference frames
-        while self.dpb.len() > self.tail as usize - 1 {
+        while self.dpb.len() > self.window() - 1 {
             self.dpb.pop_front();
         }
 
-        vec![request]
+        self.pending += 1;
+
+        Ok(vec![request])
     }
 
     fn next_request(&mut self) -> EncodeResult<Vec<BackendRequest<Picture, Reference>>> {
@@ -253,25 +1100,43 @@ impl<Picture, Reference> LowDelay<Picture, Reference> {
                 Ok(self.request_idr(input, meta)?)
             }
 
-            // There is no enough frames in the DPB
+            // The previous frame, which is referenced, is not reconstructed yet. Reconstructions
+            // arrive in order, so the DPB holds all the other references once it is.
             Some((input, meta))
-                if self.dpb.is_empty()
-                    || self.dpb.len() < (self.counter.min(self.tail) as usize) =>
+                if self.last_reconstructed.map(|frame_num| frame_num + 1)
+                    != Some(self.counter as u32) =>
             {
                 self.queue.push_front((input, meta));
                 Ok(Vec::new())
             }
 
-            Some((input, meta)) => {
-                // Make sure that reference frames in DPB is consistent
-                assert!(self.dpb.back().unwrap().meta.frame_num == self.counter as u32 - 1);
-                Ok(self.request_interframe(input, meta))
+            Some((input, meta)) => self.request_interframe(input, meta),
+        }
+    }
+
+    /// Yields the request of the oldest queued frame even if its references are not all
+    /// reconstructed, see [`Predictor::drain`].
+    fn drain_next(&mut self) -> EncodeResult<Vec<BackendRequest<Picture, Reference>>> {
+        // Frames only wait in the queue for the reconstructions of their references. Once none is
+        // pending, encode the next frame with the references at hand, or as IDR if there is none.
+        self.counter %= self.limit;
+
+        match self.queue.pop_front() {
+            None => Ok(Vec::new()),
+            Some((input, meta))
+                if self.counter == 0
+                    || meta.force_keyframe
+                    || (self.dpb.is_empty() && self.long_term.iter().all(Option::is_none)) =>
+            {
+                self.request_idr(input, meta)
             }
+            Some((input, meta)) => self.request_interframe(input, meta),
         }
     }
 }
 
-impl<Picture, Reference> Predictor<Picture, DpbEntry<Reference>, BackendRequest<Picture, Reference>>
+impl<Picture, Reference>
+    Predictor<Picture, Rc<DpbEntry<Reference>>, BackendRequest<Picture, Reference>>
     for LowDelay<Picture, Reference>
 {
     fn new_frame(
@@ -281,20 +1146,499 @@ impl<Picture, Reference> Predictor<Picture, DpbEntry<Reference>, BackendRequest<
     ) -> EncodeResult<Vec<BackendRequest<Picture, Reference>>> {
         // Add new frame in the request queue and request new encoding if possible
         self.queue.push_back((input, frame_metadata));
-        self.next_request()
+        let requests = self.next_request();
+        self.checked(requests)
     }
 
     fn reconstructed(
         &mut self,
-        recon: DpbEntry<Reference>,
+        recon: Rc<DpbEntry<Reference>>,
     ) -> EncodeResult<Vec<BackendRequest<Picture, Reference>>> {
+        // Reconstructions arrive in order, the ones of the previous sequence first.
+        self.pending = self.pending.saturating_sub(1);
+        if self.stale > 0 {
+            trace!(
+                "dropping reconstructed frame {} of the previous sequence",
+                recon.meta.frame_num
+            );
+            self.stale -= 1;
+            let requests = self.next_request();
+            return self.checked(requests);
+        }
+
         // Add new reconstructed surface and request next encoding if possible
-        self.dpb.push_back(Rc::new(recon));
-        self.next_request()
+        self.last_reconstructed = Some(recon.meta.frame_num);
+        if recon.meta.is_reference == IsReference::LongTerm {
+            let index = recon.meta.long_term_frame_idx as usize;
+            self.long_term[index] = Some(recon);
+        } else {
+            self.dpb.push_back(recon);
+        }
+        let requests = self.next_request();
+        self.checked(requests)
     }
 
     fn drain(&mut self) -> EncodeResult<Vec<BackendRequest<Picture, Reference>>> {
-        // [`LowDelay`] will not hold any frames, therefore the drain function shall never be called.
-        Err(EncodeError::InvalidInternalState)
+        let requests = self.drain_next();
+        self.checked(requests)
+    }
+
+    fn flush(&mut self) -> Vec<(Picture, FrameMetadata)> {
+        self.counter = 0;
+        self.last_timestamp = None;
+        self.last_reconstructed = None;
+        self.pending = 0;
+        self.stale = 0;
+        self.dpb.clear();
+        self.long_term.fill(None);
+        self.long_term_marks.clear();
+        self.recoveries.clear();
+        self.ancestry.clear();
+        self.queue.drain(..).collect()
+    }
+
+    fn cancel(&mut self, timestamp: u64) -> Option<(Picture, FrameMetadata)> {
+        // Queued frames are not numbered yet, so any of them can be removed.
+        let index = self
+            .queue
+            .iter()
+            .position(|(_, meta)| meta.timestamp == timestamp)?;
+        take_index(&mut self.long_term_marks, timestamp);
+        take_index(&mut self.recoveries, timestamp);
+        self.queue.remove(index)
+    }
+
+    fn mark_long_term(&mut self, timestamp: u64, index: u8) {
+        self.long_term_marks.push((timestamp, index));
+    }
+
+    fn recover(&mut self, timestamp: u64, index: u8) {
+        self.recoveries.push((timestamp, index));
+    }
+
+    fn invalidate(&mut self, timestamp: u64) -> bool {
+        let Some(lost) = self
+            .ancestry
+            .iter()
+            .position(|ancestry| ancestry.timestamp == timestamp)
+        else {
+            return false;
+        };
+
+        // Frames only reference the preceding ones, so a single pass finds all the descendants.
+        self.ancestry[lost].invalid = true;
+        for frame in lost + 1..self.ancestry.len() {
+            let invalid = self.ancestry[frame]
+                .references
+                .iter()
+                .any(|&reference| self.is_invalid(reference));
+            self.ancestry[frame].invalid |= invalid;
+        }
+
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encoder::stateless::h264::tests::frame_metadata;
+    use crate::Resolution;
+
+    #[test]
+    fn invalid_prediction_structure() {
+        for (tail, limit) in [(1, 0), (0, 16)] {
+            let config = EncoderConfig {
+                pred_structure: PredictionStructure::LowDelay { tail, limit },
+                ..Default::default()
+            };
+            assert!(matches!(
+                LowDelay::<(), ()>::new(config),
+                Err(EncodeError::InvalidPredictionStructure(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn invalid_resolution() {
+        let config = EncoderConfig {
+            resolution: Resolution {
+                width: 0,
+                height: 240,
+            },
+            ..Default::default()
+        };
+        assert!(matches!(
+            LowDelay::<(), ()>::new(config),
+            Err(EncodeError::InvalidResolution { .. })
+        ));
+    }
+
+    #[test]
+    fn layers_exceeding_level() {
+        // 1080p frames at level 4 fit in a DPB of 4 frames, too few for 3 temporal layers.
+        let config = EncoderConfig {
+            resolution: Resolution {
+                width: 1920,
+                height: 1080,
+            },
+            level: Level::L4,
+            bitrate: Bitrate::PerLayer(vec![1_000_000, 500_000, 250_000]),
+            ..Default::default()
+        };
+        assert!(matches!(
+            LowDelay::<(), ()>::new(config.clone()),
+            Err(EncodeError::InvalidPredictionStructure(_))
+        ));
+
+        let config = EncoderConfig {
+            bitrate: Bitrate::PerLayer(vec![1_000_000, 500_000]),
+            ..config
+        };
+        let mut predictor = LowDelay::<(), ()>::new(config).unwrap();
+        predictor.new_frame((), frame_metadata(0)).unwrap();
+        assert_eq!(predictor.sps.as_ref().unwrap().max_num_ref_frames, 3);
+    }
+
+    #[test]
+    fn invariants() {
+        let mut predictor = LowDelay::<(), ()>::new(EncoderConfig::default()).unwrap();
+        let mut requests = predictor.new_frame((), frame_metadata(0)).unwrap();
+        assert!(predictor.check_invariants(&requests).is_ok());
+
+        // An IDR has no reference.
+        let meta = requests[0].dpb_meta.clone();
+        requests[0].ref_list_0.push(Rc::new(DpbEntry {
+            recon_pic: (),
+            meta: meta.clone(),
+        }));
+        assert!(matches!(
+            predictor.check_invariants(&requests),
+            Err(EncodeError::InvalidPredictorState(_))
+        ));
+
+        // The frames are reconstructed in order.
+        for frame_num in [1, 0] {
+            predictor.dpb.push_back(Rc::new(DpbEntry {
+                recon_pic: (),
+                meta: DpbEntryMeta {
+                    frame_num,
+                    ..meta.clone()
+                },
+            }));
+        }
+        assert!(matches!(
+            predictor.check_invariants(&[]),
+            Err(EncodeError::InvalidPredictorState(_))
+        ));
+    }
+
+    #[test]
+    fn drain_without_references() {
+        let mut predictor = LowDelay::<(), ()>::new(EncoderConfig::default()).unwrap();
+
+        let requests = predictor.new_frame((), frame_metadata(0)).unwrap();
+        assert!(requests[0].is_idr);
+
+        // The frame waits for the reconstruction of the IDR, which never comes.
+        assert!(predictor
+            .new_frame((), frame_metadata(1))
+            .unwrap()
+            .is_empty());
+
+        let requests = predictor.drain().unwrap();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].input_meta.timestamp, 1);
+        assert!(requests[0].is_idr);
+        assert!(predictor.drain().unwrap().is_empty());
+    }
+
+    #[test]
+    fn variable_frame_rate() {
+        assert_eq!(frame_rate(90_000, 3_000), (30, 1));
+        assert_eq!(frame_rate(90_000, 3_003), (30_000, 1_001));
+        assert_eq!(frame_rate(1_000_000, 40_000), (25, 1));
+        // Irreducible fractions are approximated to fit.
+        assert_eq!(frame_rate(90_000, 3_001), (45_000, 1_500));
+        assert_eq!(frame_rate(90_000, 900_000), (1, 10));
+
+        let config = EncoderConfig {
+            timescale: Some(90_000),
+            ..Default::default()
+        };
+        let mut predictor = LowDelay::<(), ()>::new(config).unwrap();
+
+        let mut framerates = vec![];
+        for timestamp in [0, 3_000, 4_500, 13_500, 13_500] {
+            let mut requests = predictor.new_frame((), frame_metadata(timestamp)).unwrap();
+            requests.extend(predictor.drain().unwrap());
+            framerates.extend(requests.iter().map(|request| request.framerate));
+        }
+
+        // The first frame and the one without elapsed time fall back to the nominal frame rate.
+        assert_eq!(framerates, [(30, 1), (30, 1), (60, 1), (10, 1), (30, 1)]);
+    }
+
+    #[test]
+    fn temporal_layers() {
+        let config = EncoderConfig {
+            bitrate: Bitrate::PerLayer(vec![1_000_000, 500_000, 500_000]),
+            ..Default::default()
+        };
+        let mut predictor = LowDelay::<(), ()>::new(config).unwrap();
+
+        let mut frames = vec![];
+        for timestamp in 0..9 {
+            let mut requests = predictor.new_frame((), frame_metadata(timestamp)).unwrap();
+            while let Some(request) = requests.pop() {
+                let references: Vec<_> = request
+                    .ref_list_0
+                    .iter()
+                    .map(|entry| entry.meta.frame_num)
+                    .collect();
+                frames.push((request.temporal_layer, references));
+
+                requests.extend(
+                    predictor
+                        .reconstructed(Rc::new(DpbEntry {
+                            recon_pic: (),
+                            meta: request.dpb_meta,
+                        }))
+                        .unwrap(),
+                );
+            }
+        }
+
+        // Each frame references the most recent frame of the same or lower layers.
+        assert_eq!(
+            frames,
+            [
+                (0, vec![]),
+                (2, vec![0]),
+                (1, vec![0]),
+                (2, vec![2]),
+                (0, vec![0]),
+                (2, vec![4]),
+                (1, vec![4]),
+                (2, vec![6]),
+                (0, vec![4]),
+            ]
+        );
+
+        let sps = predictor.sps.as_ref().unwrap();
+        assert_eq!(sps.max_num_ref_frames, 5);
+        assert!(sps.gaps_in_frame_num_value_allowed_flag);
+    }
+
+    #[test]
+    fn disposable_frames() {
+        let config = EncoderConfig {
+            bitrate: Bitrate::PerLayer(vec![1_000_000, 500_000]),
+            pred_structure: PredictionStructure::LowDelay {
+                tail: 2,
+                limit: 2048,
+            },
+            disposable_frames: true,
+            ..Default::default()
+        };
+        let mut predictor = LowDelay::<(), ()>::new(config).unwrap();
+
+        let mut frames = vec![];
+        for timestamp in 0..6 {
+            let mut requests = predictor.new_frame((), frame_metadata(timestamp)).unwrap();
+            while let Some(request) = requests.pop() {
+                let references: Vec<_> = request
+                    .ref_list_0
+                    .iter()
+                    .map(|entry| entry.meta.frame_num)
+                    .collect();
+                frames.push((request.dpb_meta.is_reference, references));
+
+                requests.extend(
+                    predictor
+                        .reconstructed(Rc::new(DpbEntry {
+                            recon_pic: (),
+                            meta: request.dpb_meta,
+                        }))
+                        .unwrap(),
+                );
+            }
+        }
+
+        // The frames of the top layer are neither marked as nor used as reference.
+        assert_eq!(
+            frames,
+            [
+                (IsReference::ShortTerm, vec![]),
+                (IsReference::No, vec![0]),
+                (IsReference::ShortTerm, vec![0]),
+                (IsReference::No, vec![2, 0]),
+                (IsReference::ShortTerm, vec![2, 0]),
+                (IsReference::No, vec![4, 2]),
+            ]
+        );
+    }
+
+    #[test]
+    fn constraint_flags() {
+        let sps = |profile, level| {
+            // QCIF frames, for the DPB of level 1b to hold a reference frame.
+            let config = EncoderConfig {
+                profile,
+                level,
+                resolution: Resolution {
+                    width: 176,
+                    height: 144,
+                },
+                ..Default::default()
+            };
+            let mut predictor = LowDelay::<(), ()>::new(config).unwrap();
+            predictor.new_sequence();
+            predictor.sps.unwrap()
+        };
+        let flags = |sps: &Sps| {
+            [
+                sps.constraint_set0_flag,
+                sps.constraint_set1_flag,
+                sps.constraint_set2_flag,
+                sps.constraint_set3_flag,
+                sps.constraint_set4_flag,
+                sps.constraint_set5_flag,
+            ]
+        };
+
+        // Constrained Baseline, also conforming to Main and Extended.
+        let baseline = sps(Profile::Baseline, Level::L4);
+        assert_eq!(flags(&baseline), [true, true, true, false, false, false]);
+
+        // Level 1b of the Main profile, which is not conforming to Extended.
+        let main = sps(Profile::Main, Level::L1B);
+        assert_eq!(flags(&main), [true, true, false, true, true, true]);
+        assert_eq!(main.level_idc, Level::L1_1);
+
+        // Constrained High
+        let high = sps(Profile::High, Level::L1B);
+        assert_eq!(flags(&high), [false, false, false, false, true, true]);
+        assert_eq!(high.level_idc, Level::L1B);
+
+        let high_422 = sps(Profile::High422P, Level::L4);
+        assert_eq!(flags(&high_422), [false; 6]);
+    }
+
+    #[test]
+    fn long_term_recovery() {
+        let config = EncoderConfig {
+            long_term_references: 1,
+            ..Default::default()
+        };
+        let mut predictor = LowDelay::<(), ()>::new(config).unwrap();
+
+        let mut frames = vec![];
+        for timestamp in 0..9 {
+            match timestamp {
+                2 => predictor.mark_long_term(timestamp, 0),
+                5 | 8 => predictor.recover(timestamp, 0),
+                _ => (),
+            }
+            let mut meta = frame_metadata(timestamp);
+            meta.force_keyframe = timestamp == 7;
+
+            let mut requests = predictor.new_frame((), meta).unwrap();
+            while let Some(request) = requests.pop() {
+                let references: Vec<_> = request
+                    .ref_list_0
+                    .iter()
+                    .map(|entry| entry.meta.frame_num)
+                    .collect();
+                frames.push((request.is_idr, request.dpb_meta.is_reference, references));
+
+                requests.extend(
+                    predictor
+                        .reconstructed(Rc::new(DpbEntry {
+                            recon_pic: (),
+                            meta: request.dpb_meta,
+                        }))
+                        .unwrap(),
+                );
+            }
+        }
+
+        assert_eq!(
+            frames,
+            [
+                (true, IsReference::ShortTerm, vec![]),
+                (false, IsReference::ShortTerm, vec![0]),
+                (false, IsReference::LongTerm, vec![1]),
+                (false, IsReference::ShortTerm, vec![2]),
+                (false, IsReference::ShortTerm, vec![3]),
+                // The recovery frame references the long-term one, and the following frames
+                // the recovery frame.
+                (false, IsReference::ShortTerm, vec![2]),
+                (false, IsReference::ShortTerm, vec![5]),
+                // The IDR drops the long-term reference, the recovery falls back to an IDR.
+                (true, IsReference::ShortTerm, vec![]),
+                (true, IsReference::ShortTerm, vec![]),
+            ]
+        );
+        assert_eq!(predictor.sps.as_ref().unwrap().max_num_ref_frames, 3);
+    }
+
+    #[test]
+    fn invalidate_reference() {
+        let config = EncoderConfig {
+            pred_structure: PredictionStructure::LowDelay {
+                tail: 3,
+                limit: 2048,
+            },
+            ..Default::default()
+        };
+        let mut predictor = LowDelay::<(), ()>::new(config).unwrap();
+
+        let mut frames = vec![];
+        for timestamp in 0..9 {
+            match timestamp {
+                6 => assert!(predictor.invalidate(4)),
+                // All the frames descend from the IDR.
+                8 => assert!(predictor.invalidate(0)),
+                _ => (),
+            }
+
+            let mut requests = predictor.new_frame((), frame_metadata(timestamp)).unwrap();
+            while let Some(request) = requests.pop() {
+                let references: Vec<_> = request
+                    .ref_list_0
+                    .iter()
+                    .map(|entry| entry.meta.frame_num)
+                    .collect();
+                frames.push((request.is_idr, references));
+
+                requests.extend(
+                    predictor
+                        .reconstructed(Rc::new(DpbEntry {
+                            recon_pic: (),
+                            meta: request.dpb_meta,
+                        }))
+                        .unwrap(),
+                );
+            }
+        }
+
+        assert_eq!(
+            frames,
+            [
+                (true, vec![]),
+                (false, vec![0]),
+                (false, vec![1, 0]),
+                (false, vec![2, 1, 0]),
+                (false, vec![3, 2, 1]),
+                (false, vec![4, 3, 2]),
+                // Neither the lost frame 4 nor the frame 5 referencing it are referenced.
+                (false, vec![3]),
+                // The frame 3 left the DPB.
+                (false, vec![6]),
+                (true, vec![]),
+            ]
+        );
+        assert!(!predictor.invalidate(4));
     }
 }
@@ -13,8 +13,17 @@ use libva::constants::VA_PICTURE_H264_SHORT_TERM_REFERENCE;
 use libva::BufferType;
 use libva::Display;
 use libva::EncCodedBuffer;
+use libva::EncMiscParameter;
+use libva::EncMiscParameterBufferQualityLevel;
+use libva::EncMiscParameterFrameRate;
+use libva::EncMiscParameterQuantization;
+use libva::EncMiscParameterRateControl;
+use libva::EncMiscParameterTemporalLayerStructure;
+use libva::EncPackedHeaderData;
+use libva::EncPackedHeaderParameter;
 use libva::EncPictureParameter;
 use libva::EncPictureParameterBufferH264;
+use libva::EncQpBufferH264;
 use libva::EncSequenceParameter;
 use libva::EncSequenceParameterBufferH264;
 use libva::EncSliceParameter;
@@ -24,18 +33,26 @@ use libva::H264EncPicFields;
 use libva::H264EncSeqFields;
 use libva::H264VuiFields;
 use libva::Picture;
+use libva::PictureEnd;
 use libva::PictureH264;
+use libva::PictureNew;
+use libva::RcFlags;
 use libva::Surface;
 use libva::SurfaceMemoryDescriptor;
+use libva::VAEncPackedHeaderType;
 use libva::VAProfile;
 
 use crate::backend::vaapi::encoder::CodedOutputPromise;
 use crate::backend::vaapi::encoder::Reconstructed;
 use crate::backend::vaapi::encoder::VaapiBackend;
+use crate::backend::vaapi::surface_pool::PooledVaSurface;
 use crate::codec::h264::parser::Pps;
 use crate::codec::h264::parser::Profile;
 use crate::codec::h264::parser::SliceHeader;
 use crate::codec::h264::parser::Sps;
+use crate::encoder::aq::QpMap;
+use crate::encoder::preprocess::Preprocessing;
+use crate::encoder::stateless::h264::predictor::temporal_layer;
 use crate::encoder::stateless::h264::BackendRequest;
 use crate::encoder::stateless::h264::Bitrate;
 use crate::encoder::stateless::h264::DpbEntry;
@@ -50,12 +67,24 @@ use crate::encoder::stateless::ReadyPromise;
 use crate::encoder::stateless::StatelessBackendError;
 use crate::encoder::stateless::StatelessBackendResult;
 use crate::encoder::stateless::StatelessVideoEncoderBackend;
+use crate::encoder::MemFrame;
 use crate::BlockingMode;
 use crate::Fourcc;
+use crate::FrameLayout;
 use crate::Resolution;
 
 type Request<'l, H> = BackendRequest<H, Reconstructed>;
 
+/// Picture of a request with all its buffers, ready to be submitted.
+struct PreparedSlice<H> {
+    picture: Picture<PictureNew, H>,
+    recon: Reconstructed,
+    /// Reference frames, held while the picture is processed
+    references: Vec<Rc<dyn Any>>,
+    coded_buf: EncCodedBuffer,
+    coded_output: Vec<u8>,
+}
+
 impl<M, H> StatelessVideoEncoderBackend<H264> for VaapiBackend<M, H>
 where
     M: SurfaceMemoryDescriptor,
@@ -65,6 +94,17 @@ where
     type Reconstructed = Reconstructed;
     type CodedPromise = CodedOutputPromise<M, H>;
     type ReconPromise = ReadyPromise<Self::Reconstructed>;
+
+    fn read_picture(&self, picture: &H) -> StatelessBackendResult<Option<(Vec<u8>, FrameLayout)>> {
+        self.read_surface(picture.borrow()).map(Some)
+    }
+
+    fn read_reconstructed(
+        &self,
+        recon: &Reconstructed,
+    ) -> StatelessBackendResult<Option<(Vec<u8>, FrameLayout)>> {
+        self.read_surface(recon.surface()).map(Some)
+    }
 }
 
 impl<M, H> VaapiBackend<M, H>
@@ -86,15 +126,18 @@ where
 
     /// Builds [`libva::PictureH264`] from `frame`
     fn build_h264_pic(surface: &Reconstructed, meta: &DpbEntryMeta) -> PictureH264 {
-        let flags = match meta.is_reference {
-            IsReference::No => 0,
-            IsReference::LongTerm => VA_PICTURE_H264_LONG_TERM_REFERENCE,
-            IsReference::ShortTerm => VA_PICTURE_H264_SHORT_TERM_REFERENCE,
+        let (flags, frame_idx) = match meta.is_reference {
+            IsReference::No => (0, meta.frame_num),
+            IsReference::LongTerm => (
+                VA_PICTURE_H264_LONG_TERM_REFERENCE,
+                meta.long_term_frame_idx,
+            ),
+            IsReference::ShortTerm => (VA_PICTURE_H264_SHORT_TERM_REFERENCE, meta.frame_num),
         };
 
         PictureH264::new(
             surface.surface_id(),
-            meta.frame_num,
+            frame_idx,
             flags,
             meta.poc as i32,
             meta.poc as i32,
@@ -355,17 +398,13 @@ where
             header.slice_beta_offset_div2,
         )))
     }
-}
 
-impl<M, H> StatelessH264EncoderBackend for VaapiBackend<M, H>
-where
-    M: SurfaceMemoryDescriptor,
-    H: Borrow<Surface<M>>,
-{
-    fn encode_slice(
+    /// Creates the picture encoding `request` with all its buffers, to be submitted with
+    /// [`Self::submit_slice`].
+    fn prepare_slice(
         &mut self,
         request: Request<'_, H>,
-    ) -> StatelessBackendResult<(Self::ReconPromise, Self::CodedPromise)> {
+    ) -> StatelessBackendResult<PreparedSlice<H>> {
         // Coded buffer size multiplier. It's inteded to give head room for the encoder.
         const CODED_SIZE_MUL: usize = 2;
 
@@ -373,17 +412,71 @@ where
             .context()
             .create_enc_coded(CODED_SIZE_MUL * request.bitrate.target() as usize)?;
 
-        let recon = self.new_scratch_picture()?;
+        // The picture allocated ahead of the submission is already held by the DPB.
+        let recon = match &request.recon {
+            Some(entry) => entry.recon_pic.clone(),
+            None => self.new_scratch_picture()?,
+        };
 
         let seq_param = Self::build_enc_seq_param(&request.sps, request.bitrate.target() as u32);
         let pic_param = Self::build_enc_pic_param(&request, &coded_buf, &recon);
-        let slice_param = Self::build_enc_slice_param(
+        let width_in_mbs = (request.sps.pic_width_in_mbs_minus1 + 1) as usize;
+        let height_in_mbs = (request.sps.pic_height_in_map_units_minus1 + 1) as usize;
+
+        // The damage only describes the changes since the previous frame, which IDR frames do
+        // not reference. It is only a hint, so it is ignored by encoders which cannot set the QP
+        // of each macroblock in their rate control mode, while the adaptive quantization is
+        // rejected when they are created.
+        let damage = match request.input_meta.damage.as_deref() {
+            Some(_) if !self.supports_qp_map() => {
+                log::debug!("Damage ignored, the encoder cannot set the QP of each macroblock");
+                None
+            }
+            _ if request.is_idr => None,
+            damage => damage,
+        };
+        let qp_map = match (&request.adaptive_quantization, damage) {
+            (None, None) => None,
+            (aq, damage) => {
+                let mut qp_map = match aq {
+                    Some(aq) => self.qp_map(
+                        aq,
+                        request.input.borrow(),
+                        request.input_meta.layout.size,
+                        width_in_mbs,
+                        height_in_mbs,
+                    )?,
+                    None => QpMap::zero(width_in_mbs, height_in_mbs),
+                };
+                if let Some(damage) = damage {
+                    qp_map.bias_damage(damage);
+                }
+                Some(qp_map)
+            }
+        };
+
+        let mut slice_buffers = vec![];
+        // The slice parameter buffer follows the header of the slice when the encoder writes it.
+        if self.packed_slice_headers() {
+            let (data, bits) = request.slice_header(&request.header)?;
+            slice_buffers.push(BufferType::EncPackedHeaderParameter(
+                EncPackedHeaderParameter::new(
+                    VAEncPackedHeaderType::VAEncPackedHeaderSlice,
+                    bits as u32,
+                    true,
+                ),
+            ));
+            slice_buffers.push(BufferType::EncPackedHeaderData(EncPackedHeaderData::new(
+                data,
+            )));
+        }
+        slice_buffers.push(Self::build_enc_slice_param(
             &request.pps,
             &request.header,
             &request.ref_list_0,
             &request.ref_list_1,
             request.num_macroblocks as u32,
-        );
+        ));
 
         // Clone reference frames
         let references: Vec<Rc<dyn Any>> = request
@@ -405,22 +498,226 @@ where
 
         picture.add_buffer(self.context().create_buffer(seq_param)?);
         picture.add_buffer(self.context().create_buffer(pic_param)?);
-        picture.add_buffer(self.context().create_buffer(slice_param)?);
+        for slice_buffer in slice_buffers {
+            picture.add_buffer(self.context().create_buffer(slice_buffer)?);
+        }
+        if let Some(qp_map) = qp_map {
+            let slice_qp = 26
+                + i32::from(request.pps.pic_init_qp_minus26)
+                + i32::from(request.header.slice_qp_delta);
+            picture.add_buffer(self.context().create_buffer(BufferType::EncQp(
+                EncQpBufferH264::new(qp_map.qps(slice_qp)),
+            ))?);
+        }
+
+        // The rate control budgets the frame by its duration, which varies with the timestamps of
+        // variable frame rate streams. Each temporal layer has its own budget, the layers up to
+        // a given one having a fraction of the frame rate and the sum of their bitrates.
+        let num_layers = request.bitrate.num_layers();
+        if num_layers > 1 {
+            let period = 1 << (num_layers - 1);
+            let mut layer_id = [0; 32];
+            for (frame, layer_id) in layer_id.iter_mut().take(period).enumerate() {
+                *layer_id = u32::from(temporal_layer(frame as u32, num_layers));
+            }
+
+            picture.add_buffer(self.context().create_buffer(BufferType::EncMiscParameter(
+                EncMiscParameter::TemporalLayerStructure(
+                    EncMiscParameterTemporalLayerStructure::new(
+                        num_layers as u32,
+                        period as u32,
+                        layer_id,
+                    ),
+                ),
+            ))?);
+        }
+
+        for layer in 0..num_layers {
+            if num_layers > 1 {
+                let rc_flags = RcFlags::new(0, 0, 0, 0, layer as u32, 0, 0, 0, 0);
+                picture.add_buffer(self.context().create_buffer(BufferType::EncMiscParameter(
+                    EncMiscParameter::RateControl(EncMiscParameterRateControl::new(
+                        request.bitrate.layer_target(layer) as u32,
+                        100,
+                        1000,
+                        0,
+                        0,
+                        0,
+                        rc_flags,
+                        0,
+                        0,
+                        0,
+                        0,
+                    )),
+                ))?);
+            }
 
-        // Start processing the picture encoding
+            // The denominator is packed in the upper 16 bits.
+            let (num, den) = request.framerate;
+            let den = (den << (num_layers - 1 - layer)).min(u32::from(u16::MAX));
+            let framerate = if den == 1 { num } else { (den << 16) | num };
+            picture.add_buffer(self.context().create_buffer(BufferType::EncMiscParameter(
+                EncMiscParameter::FrameRate(EncMiscParameterFrameRate::new(
+                    framerate,
+                    layer as u32,
+                )),
+            ))?);
+        }
+
+        if let Some(quality_level) = self.quality_level() {
+            picture.add_buffer(self.context().create_buffer(BufferType::EncMiscParameter(
+                EncMiscParameter::QualityLevel(EncMiscParameterBufferQualityLevel::new(
+                    quality_level,
+                )),
+            ))?);
+        }
+
+        if let Some(trellis) = self.trellis() {
+            let disabled = !(trellis.intra || trellis.predicted || trellis.bipredicted);
+            picture.add_buffer(self.context().create_buffer(BufferType::EncMiscParameter(
+                EncMiscParameter::Quantization(EncMiscParameterQuantization::new(
+                    disabled as u32,
+                    trellis.intra as u32,
+                    trellis.bipredicted as u32,
+                    trellis.predicted as u32,
+                )),
+            ))?);
+        }
+
+        Ok(PreparedSlice {
+            picture,
+            recon,
+            references,
+            coded_buf,
+            coded_output: request.coded_output,
+        })
+    }
+
+    /// Submits `picture` to the driver.
+    fn submit_slice(
+        picture: Picture<PictureNew, H>,
+    ) -> StatelessBackendResult<Picture<PictureEnd, H>> {
         let picture = picture.begin().context("picture begin")?;
         let picture = picture.render().context("picture render")?;
         let picture = picture.end().context("picture end")?;
 
+        Ok(picture)
+    }
+}
+
+impl<M, H> StatelessH264EncoderBackend for VaapiBackend<M, H>
+where
+    M: SurfaceMemoryDescriptor,
+    H: Borrow<Surface<M>>,
+{
+    fn encode_slice(
+        &mut self,
+        request: Request<'_, H>,
+    ) -> StatelessBackendResult<(Self::ReconPromise, Self::CodedPromise)> {
+        let slice = self.prepare_slice(request)?;
+        let picture = Self::submit_slice(slice.picture)?;
+
         // libva will handle the synchronization of reconstructed surface with implicit fences.
         // Therefore return the reconstructed frame immediately.
-        let reference_promise = ReadyPromise::from(recon);
+        let reference_promise = ReadyPromise::from(slice.recon);
 
-        let bitstream_promise =
-            CodedOutputPromise::new(picture, references, coded_buf, request.coded_output);
+        let bitstream_promise = CodedOutputPromise::new(
+            picture,
+            slice.references,
+            slice.coded_buf,
+            slice.coded_output,
+        );
 
         Ok((reference_promise, bitstream_promise))
     }
+
+    fn encode_slices(
+        &mut self,
+        requests: Vec<Request<'_, H>>,
+    ) -> StatelessBackendResult<Vec<(Self::ReconPromise, Self::CodedPromise)>> {
+        // The buffers of all the pictures are created first, so that the pictures are submitted
+        // back to back, then synced at once.
+        let slices = requests
+            .into_iter()
+            .map(|request| self.prepare_slice(request))
+            .collect::<StatelessBackendResult<Vec<_>>>()?;
+
+        let mut reference_promises = Vec::with_capacity(slices.len());
+        let mut submitted = Vec::with_capacity(slices.len());
+        for slice in slices {
+            let picture = Self::submit_slice(slice.picture)?;
+            reference_promises.push(ReadyPromise::from(slice.recon));
+            submitted.push((
+                picture,
+                slice.references,
+                slice.coded_buf,
+                slice.coded_output,
+            ));
+        }
+
+        Ok(reference_promises
+            .into_iter()
+            .zip(CodedOutputPromise::batch(submitted))
+            .collect())
+    }
+
+    fn new_reconstructed(&mut self) -> StatelessBackendResult<Option<Self::Reconstructed>> {
+        self.new_scratch_picture().map(Some)
+    }
+}
+
+/// Creates the VAAPI backend of an H.264 encoder for `config`.
+fn new_backend<M, H>(
+    display: Rc<Display>,
+    config: &EncoderConfig,
+    fourcc: Fourcc,
+    coded_size: Resolution,
+    low_power: bool,
+) -> EncodeResult<VaapiBackend<M, H>>
+where
+    M: SurfaceMemoryDescriptor,
+    H: Borrow<libva::Surface<M>>,
+{
+    let va_profile = match config.profile {
+        Profile::Baseline => VAProfile::VAProfileH264ConstrainedBaseline,
+        Profile::Main => VAProfile::VAProfileH264Main,
+        Profile::High => VAProfile::VAProfileH264High,
+        _ => return Err(StatelessBackendError::UnsupportedProfile.into()),
+    };
+
+    let bitrate_control = match config.bitrate {
+        Bitrate::Constant(_) | Bitrate::PerLayer(_) => libva::constants::VA_RC_CBR,
+    };
+
+    let mut backend = VaapiBackend::new(
+        display,
+        va_profile,
+        fourcc,
+        coded_size,
+        bitrate_control,
+        low_power,
+    )?;
+    // Drivers writing the slice headers cannot mark long-term references.
+    if config.long_term_references > 0 && !backend.packed_slice_headers() {
+        return Err(StatelessBackendError::Other(anyhow::anyhow!(
+            "long-term references need packed slice headers"
+        ))
+        .into());
+    }
+    if config.adaptive_quantization.is_some() && !backend.supports_qp_map() {
+        return Err(StatelessBackendError::Other(anyhow::anyhow!(
+            "adaptive quantization needs the QP of each macroblock to be settable"
+        ))
+        .into());
+    }
+    backend.set_preprocessing(config.preprocessing.clone())?;
+    backend.set_preset(config.preset);
+    if let Some(quality_level) = config.quality_level {
+        backend.set_quality_level(quality_level);
+    }
+    backend.set_advanced(&config.advanced);
+
+    Ok(backend)
 }
 
 impl<M, H> StatelessEncoder<H, VaapiBackend<M, H>>
@@ -436,29 +733,43 @@ where
         low_power: bool,
         blocking_mode: BlockingMode,
     ) -> EncodeResult<Self> {
-        let va_profile = match config.profile {
-            Profile::Baseline => VAProfile::VAProfileH264ConstrainedBaseline,
-            Profile::Main => VAProfile::VAProfileH264Main,
-            Profile::High => VAProfile::VAProfileH264High,
-            _ => return Err(StatelessBackendError::UnsupportedProfile.into()),
-        };
-
-        let bitrate_control = match config.bitrate {
-            Bitrate::Constant(_) => libva::constants::VA_RC_CBR,
-        };
+        let backend = new_backend(display, &config, fourcc, coded_size, low_power)?;
+        Self::new(backend, config, blocking_mode)
+    }
+}
 
-        let backend = VaapiBackend::new(
-            display,
-            va_profile,
-            fourcc,
-            coded_size,
-            bitrate_control,
-            low_power,
-        )?;
+impl<T: AsRef<[u8]>> StatelessEncoder<MemFrame<T>, VaapiBackend<(), PooledVaSurface<()>>> {
+    /// Creates an encoder taking frames from CPU memory, which are copied into VA surfaces before
+    /// being encoded. The frames must be in the `fourcc` format, except for NV12 encoders which
+    /// also accept NV21, I420, YV12 and BGRA frames, converted as they are copied. The frames are
+    /// also mirrored and rotated there if requested by [`EncoderConfig::preprocessing`].
+    pub fn new_vaapi_mem(
+        display: Rc<Display>,
+        config: EncoderConfig,
+        fourcc: Fourcc,
+        coded_size: Resolution,
+        low_power: bool,
+        blocking_mode: BlockingMode,
+    ) -> EncodeResult<Self> {
+        let backend = new_backend(display, &config, fourcc, coded_size, low_power)?;
         Self::new(backend, config, blocking_mode)
     }
 }
 
+impl<Handle, M, H> StatelessEncoder<Handle, VaapiBackend<M, H>>
+where
+    M: SurfaceMemoryDescriptor,
+    H: Borrow<libva::Surface<M>>,
+{
+    /// Replaces the preprocessing of the frames passed to [`StatelessVideoEncoder::encode`] from
+    /// now on, e.g. to update the overlay with the current time.
+    ///
+    /// [`StatelessVideoEncoder::encode`]: crate::encoder::stateless::StatelessVideoEncoder::encode
+    pub fn set_preprocessing(&mut self, preprocessing: Preprocessing) -> EncodeResult<()> {
+        Ok(self.backend.set_preprocessing(preprocessing)?)
+    }
+}
+
 #[cfg(test)]
 pub(super) mod tests {
     use libva::constants::VA_RT_FORMAT_YUV420;
@@ -468,6 +779,7 @@ pub(super) mod tests {
     use libva::VAProfile::VAProfileH264Main;
 
     use super::*;
+    use crate::backend::vaapi::encoder::tests::upload_nv12_img;
     use crate::backend::vaapi::encoder::tests::upload_test_frame;
     use crate::backend::vaapi::encoder::tests::TestFrameGenerator;
     use crate::backend::vaapi::surface_pool::PooledVaSurface;
@@ -478,6 +790,8 @@ pub(super) mod tests {
     use crate::codec::h264::parser::SliceHeaderBuilder;
     use crate::codec::h264::parser::SliceType;
     use crate::codec::h264::parser::SpsBuilder;
+    use crate::decoder::stateless::h264::H264 as H264Decoder;
+    use crate::decoder::stateless::StatelessDecoder;
     use crate::decoder::FramePool;
     use crate::encoder::stateless::h264::BackendRequest;
     use crate::encoder::stateless::h264::EncoderConfig;
@@ -486,6 +800,10 @@ pub(super) mod tests {
     use crate::encoder::stateless::BackendPromise;
     use crate::encoder::stateless::StatelessEncoderBackendImport;
     use crate::encoder::FrameMetadata;
+    use crate::roundtrip::round_trip;
+    use crate::roundtrip::RoundTripParams;
+    use crate::roundtrip::TestPattern;
+    use crate::utils::simple_playback_loop_owned_frames;
     use crate::FrameLayout;
     use crate::PlaneLayout;
     use crate::Resolution;
@@ -559,6 +877,7 @@ pub(super) mod tests {
             },
             layout: frame_layout,
             force_keyframe: false,
+            damage: None,
             timestamp: 0,
         };
 
@@ -598,6 +917,7 @@ pub(super) mod tests {
             poc: 0,
             frame_num: 0,
             is_reference: IsReference::ShortTerm,
+            long_term_frame_idx: 0,
         };
 
         let request = BackendRequest {
@@ -612,6 +932,10 @@ pub(super) mod tests {
             num_macroblocks: (WIDTH * HEIGHT) as usize / (16 * 16),
             is_idr: true,
             bitrate: Bitrate::Constant(30_000),
+            adaptive_quantization: None,
+            framerate: (30, 1),
+            temporal_layer: 0,
+            recon: None,
             coded_output: vec![],
         };
 
@@ -715,7 +1039,7 @@ pub(super) mod tests {
         let mut bitstream = Vec::new();
 
         simple_encode_loop(&mut encoder, &mut frame_producer, |coded| {
-            bitstream.extend(coded.bitstream)
+            bitstream.extend_from_slice(&coded.bitstream)
         })
         .unwrap();
 
@@ -727,4 +1051,79 @@ pub(super) mod tests {
             out.flush().unwrap();
         }
     }
+
+    #[test]
+    // Ignore this test by default as it requires libva-compatible hardware.
+    #[ignore]
+    fn test_vaapi_round_trip() {
+        type VaapiH264Encoder<'l> =
+            StatelessEncoder<PooledVaSurface<()>, VaapiBackend<(), PooledVaSurface<()>>>;
+
+        let _ = env_logger::try_init();
+
+        let resolution = Resolution {
+            width: 320,
+            height: 240,
+        };
+
+        let display = libva::Display::open().unwrap();
+        let entrypoints = display.query_config_entrypoints(VAProfileH264Main).unwrap();
+        let low_power = entrypoints.contains(&VAEntrypointEncSliceLP);
+
+        let config = EncoderConfig {
+            bitrate: Bitrate::Constant(2_000_000),
+            profile: Profile::Main,
+            resolution,
+            ..Default::default()
+        };
+
+        for pattern in [TestPattern::ColorBars, TestPattern::MovingBox] {
+            let mut encoder = VaapiH264Encoder::new_vaapi(
+                Rc::clone(&display),
+                config.clone(),
+                b"NV12".into(),
+                resolution,
+                low_power,
+                BlockingMode::Blocking,
+            )
+            .unwrap();
+
+            let mut pool = VaSurfacePool::new(
+                Rc::clone(&display),
+                VA_RT_FORMAT_YUV420,
+                Some(UsageHint::USAGE_HINT_ENCODER),
+                resolution,
+            );
+            pool.add_frames(vec![(); 16]).unwrap();
+
+            let mut decoder = StatelessDecoder::<H264Decoder, _>::new_vaapi::<()>(
+                Rc::clone(&display),
+                BlockingMode::Blocking,
+            );
+
+            let params = RoundTripParams {
+                pattern,
+                resolution,
+                num_frames: 30,
+                min_psnr: 30.0,
+            };
+
+            round_trip(
+                &params,
+                &mut encoder,
+                &mut |meta, frame| {
+                    let surface = pool
+                        .get_surface()
+                        .ok_or_else(|| anyhow::anyhow!("no free surface"))?;
+                    let size = meta.layout.size;
+                    upload_nv12_img(&display, surface.borrow(), size.width, size.height, frame);
+                    Ok(surface)
+                },
+                &mut decoder,
+                &mut simple_playback_loop_owned_frames,
+                BlockingMode::Blocking,
+            )
+            .unwrap();
+        }
+    }
 }
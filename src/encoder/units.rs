@@ -0,0 +1,285 @@
+// Copyright 2024 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Zero-copy iteration over the NAL units and OBUs of the coded buffers.
+//!
+//! Packetizers and muxers handle the coded frames unit by unit, e.g. to drop the access unit
+//! delimiters or to move the parameter sets out of band. [`BitstreamUnits`] lets them do so
+//! without copying the units or scanning the buffer for start codes themselves.
+
+use bytes::Bytes;
+
+use crate::codec::av1::annexb::RawObu;
+use crate::codec::av1::parser::ObuType;
+use crate::codec::h264::parser::NaluType as H264NaluType;
+use crate::codec::h265::parser::NaluType as H265NaluType;
+use crate::encoder::CodedBitstreamBuffer;
+
+/// Type of a [`BitstreamUnit`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UnitType {
+    H264(H264NaluType),
+    H265(H265NaluType),
+    Av1(ObuType),
+}
+
+/// A NAL unit or an OBU of a coded buffer.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BitstreamUnit<'a> {
+    /// Type of the unit, `None` if it is reserved or unspecified.
+    pub unit_type: Option<UnitType>,
+    /// Offset of the unit in the bitstream. For NAL units, this is past their start code.
+    pub offset: usize,
+    /// The unit, from its header and without the start code of NAL units, as a slice of the
+    /// bitstream.
+    pub data: &'a [u8],
+}
+
+#[derive(Clone, Copy, Debug)]
+enum UnitFormat {
+    H264,
+    H265,
+    Av1,
+}
+
+/// Iterator over the [`BitstreamUnit`]s of a coded buffer, see
+/// [`CodedBitstreamBuffer::h264_nal_units`], [`CodedBitstreamBuffer::h265_nal_units`] and
+/// [`CodedBitstreamBuffer::obus`].
+#[derive(Clone)]
+pub struct BitstreamUnits<'a> {
+    data: &'a [u8],
+    pos: usize,
+    format: UnitFormat,
+}
+
+impl<'a> BitstreamUnits<'a> {
+    /// Returns the position of the next `00 00 01` start code of the data, from `from`.
+    fn find_start_code(&self, from: usize) -> Option<usize> {
+        self.data
+            .get(from..)?
+            .windows(3)
+            .position(|window| window == [0x00, 0x00, 0x01])
+            .map(|pos| from + pos)
+    }
+
+    fn next_nal_unit(&mut self) -> Option<(usize, &'a [u8])> {
+        loop {
+            let start = self.find_start_code(self.pos)? + 3;
+            let next = self.find_start_code(start).unwrap_or(self.data.len());
+            self.pos = next;
+
+            // NAL units never end with a zero byte, so the trailing ones are the leading zero
+            // of the next start code or trailing_zero_8bits.
+            let mut end = next;
+            while end > start && self.data[end - 1] == 0 {
+                end -= 1;
+            }
+
+            if end > start {
+                return Some((start, &self.data[start..end]));
+            }
+        }
+    }
+
+    fn next_obu(&mut self) -> Option<(usize, &'a [u8])> {
+        let start = self.pos;
+        // The encoders only produce well formed OBUs with their size field set, so the iteration
+        // just stops at anything else.
+        let (_, len) =
+            RawObu::split(self.data.get(start..).filter(|d| !d.is_empty())?, None).ok()?;
+        self.pos = start + len;
+
+        Some((start, &self.data[start..self.pos]))
+    }
+}
+
+impl<'a> Iterator for BitstreamUnits<'a> {
+    type Item = BitstreamUnit<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (offset, data) = match self.format {
+            UnitFormat::H264 | UnitFormat::H265 => self.next_nal_unit()?,
+            UnitFormat::Av1 => self.next_obu()?,
+        };
+
+        let unit_type = match self.format {
+            UnitFormat::H264 => H264NaluType::n(data[0] & 0x1f).map(UnitType::H264),
+            UnitFormat::H265 => H265NaluType::n((data[0] >> 1) & 0x3f).map(UnitType::H265),
+            UnitFormat::Av1 => ObuType::n((data[0] >> 3) & 0xf).map(UnitType::Av1),
+        };
+
+        Some(BitstreamUnit {
+            unit_type,
+            offset,
+            data,
+        })
+    }
+}
+
+impl CodedBitstreamBuffer {
+    fn units(&self, format: UnitFormat) -> BitstreamUnits<'_> {
+        BitstreamUnits {
+            data: &self.bitstream,
+            pos: 0,
+            format,
+        }
+    }
+
+    /// Returns the NAL units of the H.264 Annex B bitstream.
+    pub fn h264_nal_units(&self) -> BitstreamUnits<'_> {
+        self.units(UnitFormat::H264)
+    }
+
+    /// Returns the NAL units of the H.265 Annex B bitstream.
+    pub fn h265_nal_units(&self) -> BitstreamUnits<'_> {
+        self.units(UnitFormat::H265)
+    }
+
+    /// Returns the OBUs of the AV1 low-overhead bitstream.
+    pub fn obus(&self) -> BitstreamUnits<'_> {
+        self.units(UnitFormat::Av1)
+    }
+
+    /// Returns `unit`, which must come from this buffer, as an owned slice sharing the storage of
+    /// the bitstream.
+    pub fn unit_bytes(&self, unit: &BitstreamUnit<'_>) -> Bytes {
+        self.bitstream.slice_ref(unit.data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec::h264::nalu::split_annexb;
+    use crate::encoder::FrameMetadata;
+    use crate::FrameLayout;
+    use crate::Resolution;
+
+    fn coded_buffer(bitstream: &[u8]) -> CodedBitstreamBuffer {
+        let metadata = FrameMetadata {
+            timestamp: 0,
+            display_resolution: Resolution::default(),
+            layout: FrameLayout {
+                format: (b"NV12".into(), 0),
+                size: Resolution::default(),
+                planes: vec![],
+            },
+            force_keyframe: false,
+            damage: None,
+        };
+
+        CodedBitstreamBuffer::new(metadata, bitstream.to_vec())
+    }
+
+    #[test]
+    fn h264_nal_units() {
+        let stream = [
+            0x00, 0x00, 0x00, 0x01, 0x09, 0x10, // AUD
+            0x00, 0x00, 0x01, 0x67, 0x42, 0x1e, // SPS
+            0x00, 0x00, 0x01, // empty
+            0x00, 0x00, 0x01, 0x65, 0x88, 0x00, 0x00, 0x03, 0x01, 0x00, 0x00, // IDR slice
+            0x00, 0x00, 0x01, 0x71, 0x80, // nal_unit_type 17, reserved
+        ];
+        let buffer = coded_buffer(&stream);
+
+        let units = buffer.h264_nal_units().collect::<Vec<_>>();
+        let types = units.iter().map(|unit| unit.unit_type).collect::<Vec<_>>();
+        assert_eq!(
+            types,
+            [
+                Some(UnitType::H264(H264NaluType::AuDelimiter)),
+                Some(UnitType::H264(H264NaluType::Sps)),
+                Some(UnitType::H264(H264NaluType::SliceIdr)),
+                None,
+            ]
+        );
+
+        let offsets = units.iter().map(|unit| unit.offset).collect::<Vec<_>>();
+        assert_eq!(offsets, [4, 9, 18, 29]);
+        for unit in &units {
+            assert_eq!(
+                unit.data,
+                &stream[unit.offset..unit.offset + unit.data.len()]
+            );
+        }
+
+        let data = units
+            .iter()
+            .map(|unit| unit.data.to_vec())
+            .collect::<Vec<_>>();
+        let expected = split_annexb(&stream)
+            .into_iter()
+            .filter(|nalu| !nalu.is_empty())
+            .collect::<Vec<_>>();
+        assert_eq!(data, expected);
+
+        // The owned unit shares the storage of the bitstream.
+        let sps = buffer.unit_bytes(&units[1]);
+        assert_eq!(sps, &stream[9..12]);
+        assert_eq!(sps.as_ptr(), buffer.bitstream[9..].as_ptr());
+    }
+
+    #[test]
+    fn h265_nal_units() {
+        let stream = [
+            0x00, 0x00, 0x00, 0x01, 0x40, 0x01, 0x0c, // VPS
+            0x00, 0x00, 0x01, 0x26, 0x01, 0xaf, // IDR_W_RADL
+        ];
+        let buffer = coded_buffer(&stream);
+
+        let units = buffer
+            .h265_nal_units()
+            .map(|unit| (unit.unit_type, unit.offset, unit.data))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            units,
+            [
+                (Some(UnitType::H265(H265NaluType::VpsNut)), 4, &stream[4..7]),
+                (
+                    Some(UnitType::H265(H265NaluType::IdrWRadl)),
+                    10,
+                    &stream[10..]
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn obus() {
+        let stream = [
+            0x12, 0x00, // temporal delimiter
+            0x0a, 0x02, 0xaa, 0xbb, // sequence header
+            0x36, 0x08, 0x01, 0xcc, // frame with extension
+            0x32, 0x05, 0xdd, // truncated frame
+        ];
+        let buffer = coded_buffer(&stream);
+
+        let units = buffer
+            .obus()
+            .map(|unit| (unit.unit_type, unit.offset, unit.data))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            units,
+            [
+                (
+                    Some(UnitType::Av1(ObuType::TemporalDelimiter)),
+                    0,
+                    &stream[0..2]
+                ),
+                (
+                    Some(UnitType::Av1(ObuType::SequenceHeader)),
+                    2,
+                    &stream[2..6]
+                ),
+                (Some(UnitType::Av1(ObuType::Frame)), 6, &stream[6..10]),
+            ]
+        );
+
+        assert_eq!(coded_buffer(&[]).obus().count(), 0);
+        assert_eq!(
+            coded_buffer(&[0x00, 0x00, 0x00]).h264_nal_units().count(),
+            0
+        );
+    }
+}
@@ -0,0 +1,596 @@
+// Copyright 2024 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Helpers to embed the decoders and encoders of this crate into GStreamer elements, e.g. written
+//! with gst-plugins-rs.
+//!
+//! This module does not depend on GStreamer. Frame layouts are built from the values an element
+//! gets from its `VideoInfo` or `VideoMeta`, and caps are produced as strings to be parsed with
+//! `gst::Caps::from_str`. Timestamps are opaque to the decoders and encoders: elements typically
+//! use the system frame number of the `VideoCodecFrame` to match the output with its input.
+//!
+//! [`ElementDecoder`] and [`ElementEncoder`] wrap a decoder or an encoder with the calls of the
+//! `handle_frame`, `finish` and `flush` virtual methods of the GStreamer video decoder and encoder
+//! base classes, and keep track of the latency to report.
+
+use std::marker::PhantomData;
+
+use crate::codec::h264::parser::Level;
+use crate::codec::h264::parser::Profile;
+use crate::decoder::stateless::DecodeError;
+use crate::decoder::stateless::PoolLayer;
+use crate::decoder::stateless::StatelessDecoderBackend;
+use crate::decoder::stateless::StatelessVideoDecoder;
+use crate::decoder::DecodedHandle;
+use crate::decoder::DecoderEvent;
+use crate::decoder::FramePool;
+use crate::decoder::StreamInfo;
+use crate::encoder::stateless::EncodeResult;
+use crate::encoder::stateless::StatelessVideoEncoder;
+use crate::encoder::CodedBitstreamBuffer;
+use crate::encoder::FrameMetadata;
+use crate::DecodedFormat;
+use crate::Fourcc;
+use crate::FrameLayout;
+use crate::PlaneLayout;
+use crate::Resolution;
+
+/// Caps of the sink pad of a decoder element, for all the codecs supported by the decoders.
+///
+/// H.264 and H.265 streams must be passed one access unit at a time.
+pub const DECODER_SINK_CAPS: &str = "video/x-h264, stream-format=(string)byte-stream, \
+     alignment=(string)au; \
+     video/x-h265, stream-format=(string)byte-stream, alignment=(string)au; \
+     video/x-vp8; video/x-vp9; video/x-av1";
+
+/// Returns the fourcc and number of planes of the GStreamer video format `format`, e.g. `"NV12"`.
+fn gst_format_info(format: &str) -> Option<(Fourcc, usize)> {
+    let (fourcc, num_planes) = match format {
+        "NV12" => (b"NV12", 2),
+        "I420" => (b"I420", 3),
+        "YV12" => (b"YV12", 3),
+        "P010_10LE" => (b"P010", 2),
+        "BGRA" => (b"BGRA", 1),
+        _ => return None,
+    };
+
+    Some((Fourcc::from(fourcc), num_planes))
+}
+
+/// Returns the fourcc matching the GStreamer video format `format`, e.g. `"NV12"`, if it can be
+/// used as encoder input.
+pub fn fourcc_from_gst_format(format: &str) -> Option<Fourcc> {
+    gst_format_info(format).map(|(fourcc, _)| fourcc)
+}
+
+/// Returns the name of the GStreamer video format matching `format`.
+pub fn gst_format(format: DecodedFormat) -> &'static str {
+    match format {
+        DecodedFormat::I420 => "I420",
+        DecodedFormat::NV12 => "NV12",
+        DecodedFormat::I422 => "Y42B",
+        DecodedFormat::I444 => "Y444",
+        DecodedFormat::I010 => "I420_10LE",
+        DecodedFormat::I012 => "I420_12LE",
+        DecodedFormat::I210 => "I422_10LE",
+        DecodedFormat::I212 => "I422_12LE",
+        DecodedFormat::I410 => "Y444_10LE",
+        DecodedFormat::I412 => "Y444_12LE",
+    }
+}
+
+/// Builds the layout of a frame of GStreamer video format `format` from the plane `offsets` and
+/// `strides` of its `VideoMeta` or `VideoInfo`.
+///
+/// These offsets are relative to the start of the `gst::Buffer`, so all the planes are considered
+/// to belong to its first memory buffer. Returns `None` if the format is not supported or the
+/// number of offsets and strides does not match it.
+pub fn frame_layout(
+    format: &str,
+    size: Resolution,
+    offsets: &[usize],
+    strides: &[usize],
+) -> Option<FrameLayout> {
+    let (fourcc, num_planes) = gst_format_info(format)?;
+    if offsets.len() < num_planes || strides.len() < num_planes {
+        return None;
+    }
+
+    Some(FrameLayout {
+        format: (fourcc, 0),
+        size,
+        planes: offsets
+            .iter()
+            .zip(strides)
+            .take(num_planes)
+            .map(|(&offset, &stride)| PlaneLayout {
+                buffer_index: 0,
+                offset,
+                stride,
+            })
+            .collect(),
+    })
+}
+
+/// Formats a framerate as a caps fraction. `None` means a variable framerate.
+fn caps_framerate(framerate: Option<(u32, u32)>) -> String {
+    let (num, den) = framerate.unwrap_or((0, 1));
+    format!("{}/{}", num, den)
+}
+
+/// Returns the caps of raw frames of `format` and `resolution`.
+pub fn raw_caps(
+    format: DecodedFormat,
+    resolution: Resolution,
+    framerate: Option<(u32, u32)>,
+) -> String {
+    format!(
+        "video/x-raw, format=(string){}, width=(int){}, height=(int){}, framerate=(fraction){}",
+        gst_format(format),
+        resolution.width,
+        resolution.height,
+        caps_framerate(framerate)
+    )
+}
+
+/// Returns the caps of the H.264 stream produced by an encoder.
+pub fn h264_caps(
+    profile: Profile,
+    level: Level,
+    resolution: Resolution,
+    framerate: Option<(u32, u32)>,
+) -> String {
+    let profile = match profile {
+        Profile::Baseline => "constrained-baseline",
+        Profile::Main => "main",
+        Profile::Extended => "extended",
+        Profile::High => "high",
+        Profile::High10 => "high-10",
+        Profile::High422P => "high-4:2:2",
+    };
+    let level = match level as u32 {
+        9 => String::from("1b"),
+        level if level % 10 == 0 => format!("{}", level / 10),
+        level => format!("{}.{}", level / 10, level % 10),
+    };
+
+    format!(
+        "video/x-h264, stream-format=(string)byte-stream, alignment=(string)au, \
+         profile=(string){}, level=(string){}, width=(int){}, height=(int){}, \
+         framerate=(fraction){}",
+        profile,
+        level,
+        resolution.width,
+        resolution.height,
+        caps_framerate(framerate)
+    )
+}
+
+/// Latency to report in answer to latency queries, in nanoseconds.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Latency {
+    pub min: u64,
+    pub max: u64,
+}
+
+/// Tracks the number of frames held by a decoder or an encoder to compute its latency.
+#[derive(Debug)]
+struct LatencyTracker {
+    /// Duration of a frame in nanoseconds, or 0 if the framerate is unknown.
+    frame_duration: u64,
+    in_flight: usize,
+    max_in_flight: usize,
+    changed: bool,
+}
+
+impl LatencyTracker {
+    fn new(framerate: Option<(u32, u32)>) -> Self {
+        let frame_duration = match framerate {
+            Some((num, den)) if num != 0 => 1_000_000_000 * den as u64 / num as u64,
+            _ => 0,
+        };
+
+        Self {
+            frame_duration,
+            in_flight: 0,
+            max_in_flight: 0,
+            changed: false,
+        }
+    }
+
+    fn input(&mut self) {
+        self.in_flight += 1;
+    }
+
+    fn output(&mut self) {
+        // The frame is still in flight when its output is produced, so account for it before
+        // releasing it.
+        if self.in_flight > self.max_in_flight {
+            self.max_in_flight = self.in_flight;
+            self.changed = true;
+        }
+        self.in_flight = self.in_flight.saturating_sub(1);
+    }
+
+    fn reset(&mut self) {
+        self.in_flight = 0;
+    }
+
+    fn latency(&self) -> Latency {
+        let latency = self.max_in_flight.saturating_sub(1) as u64 * self.frame_duration;
+
+        Latency {
+            min: latency,
+            max: latency,
+        }
+    }
+}
+
+/// Output of an [`ElementDecoder`].
+pub enum DecoderOutput<H> {
+    /// The stream format changed. The element must negotiate the new caps, see [`raw_caps`],
+    /// before pushing the following frames.
+    FormatChanged(StreamInfo),
+    /// A frame is decoded. Frames are output in display order.
+    Frame(H),
+}
+
+/// A decoder with the semantics of a GStreamer video decoder element.
+pub struct ElementDecoder<B, D>
+where
+    B: StatelessDecoderBackend,
+    D: StatelessVideoDecoder<B> + ?Sized,
+{
+    decoder: Box<D>,
+    output_format: DecodedFormat,
+    #[allow(clippy::type_complexity)]
+    allocate_new_frames: Box<
+        dyn FnMut(
+            &StreamInfo,
+            usize,
+        ) -> anyhow::Result<Vec<<B::Handle as DecodedHandle>::Descriptor>>,
+    >,
+    latency: LatencyTracker,
+}
+
+impl<B, D> ElementDecoder<B, D>
+where
+    B: StatelessDecoderBackend,
+    D: StatelessVideoDecoder<B> + ?Sized,
+{
+    /// Wraps `decoder`, which outputs frames of `output_format` allocated with
+    /// `allocate_new_frames`.
+    ///
+    /// `framerate` is the framerate of the input caps if known, used to express the latency in
+    /// time.
+    #[allow(clippy::type_complexity)]
+    pub fn new(
+        decoder: Box<D>,
+        output_format: DecodedFormat,
+        allocate_new_frames: Box<
+            dyn FnMut(
+                &StreamInfo,
+                usize,
+            ) -> anyhow::Result<Vec<<B::Handle as DecodedHandle>::Descriptor>>,
+        >,
+        framerate: Option<(u32, u32)>,
+    ) -> Self {
+        Self {
+            decoder,
+            output_format,
+            allocate_new_frames,
+            latency: LatencyTracker::new(framerate),
+        }
+    }
+
+    /// Returns the wrapped decoder.
+    pub fn decoder(&mut self) -> &mut D {
+        &mut self.decoder
+    }
+
+    /// Processes the pending events of the decoder.
+    fn process_events(
+        &mut self,
+        on_output: &mut dyn FnMut(DecoderOutput<B::Handle>),
+    ) -> anyhow::Result<()> {
+        while let Some(event) = self.decoder.next_event() {
+            match event {
+                DecoderEvent::FrameReady(handle) => {
+                    self.latency.output();
+                    on_output(DecoderOutput::Frame(handle));
+                }
+                DecoderEvent::FormatChanged(mut format_setter) => {
+                    format_setter.try_format(self.output_format)?;
+                    let stream_info = format_setter.stream_info().clone();
+                    let min_num_frames = stream_info.min_num_frames;
+                    let pools = format_setter.frame_pool(PoolLayer::All);
+                    let nb_pools = pools.len();
+                    for pool in pools {
+                        let pool_num_frames = pool.num_managed_frames();
+                        if pool_num_frames < (min_num_frames / nb_pools) {
+                            let frames = (self.allocate_new_frames)(
+                                &stream_info,
+                                min_num_frames - pool_num_frames,
+                            )?;
+                            pool.add_frames(frames)?;
+                        }
+                    }
+
+                    on_output(DecoderOutput::FormatChanged(stream_info));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Decodes `bitstream`, i.e. the content of an input `gst::Buffer`. Frames decoded from it
+    /// carry `timestamp`.
+    ///
+    /// `on_output` is called for every format change and decoded frame.
+    pub fn handle_frame(
+        &mut self,
+        timestamp: u64,
+        mut bitstream: &[u8],
+        on_output: &mut dyn FnMut(DecoderOutput<B::Handle>),
+    ) -> anyhow::Result<()> {
+        self.latency.input();
+
+        while !bitstream.is_empty() {
+            match self.decoder.decode(timestamp, bitstream) {
+                Ok(processed) => {
+                    bitstream = &bitstream[processed..];
+                    self.process_events(on_output)?;
+                }
+                Err(DecodeError::CheckEvents) | Err(DecodeError::NotEnoughOutputBuffers(_)) => {
+                    self.process_events(on_output)?
+                }
+                Err(e) => anyhow::bail!(e),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Outputs all the pending frames, e.g. on EOS.
+    pub fn finish(
+        &mut self,
+        on_output: &mut dyn FnMut(DecoderOutput<B::Handle>),
+    ) -> anyhow::Result<()> {
+        self.decoder.flush()?;
+        self.process_events(on_output)?;
+        self.latency.reset();
+
+        Ok(())
+    }
+
+    /// Drops all the pending frames, e.g. on a seek. A keyframe is expected next.
+    pub fn flush(&mut self) -> anyhow::Result<()> {
+        self.finish(&mut |_| ())
+    }
+
+    /// Returns the latency to report.
+    pub fn latency(&self) -> Latency {
+        self.latency.latency()
+    }
+
+    /// Returns whether the latency changed since the last call, in which case the element should
+    /// post a latency message.
+    pub fn latency_changed(&mut self) -> bool {
+        std::mem::take(&mut self.latency.changed)
+    }
+}
+
+/// An encoder with the semantics of a GStreamer video encoder element.
+pub struct ElementEncoder<H, E>
+where
+    E: StatelessVideoEncoder<H>,
+{
+    encoder: E,
+    /// Whether the next frame must be a keyframe, after a flush.
+    force_keyframe: bool,
+    latency: LatencyTracker,
+    _phantom: PhantomData<H>,
+}
+
+impl<H, E> ElementEncoder<H, E>
+where
+    E: StatelessVideoEncoder<H>,
+{
+    /// Wraps `encoder`, encoding frames at `framerate` if known.
+    pub fn new(encoder: E, framerate: Option<(u32, u32)>) -> Self {
+        Self {
+            encoder,
+            force_keyframe: false,
+            latency: LatencyTracker::new(framerate),
+            _phantom: PhantomData,
+        }
+    }
+
+    /// Returns the wrapped encoder.
+    pub fn encoder(&mut self) -> &mut E {
+        &mut self.encoder
+    }
+
+    /// Polls all the available output of the encoder.
+    fn poll(&mut self) -> EncodeResult<Vec<CodedBitstreamBuffer>> {
+        let mut coded = Vec::new();
+        while let Some(buffer) = self.encoder.poll()? {
+            self.latency.output();
+            coded.push(buffer);
+        }
+
+        Ok(coded)
+    }
+
+    /// Encodes the frame `handle` of `layout` with `timestamp`, and returns the encoded output
+    /// available, in decoding order.
+    pub fn handle_frame(
+        &mut self,
+        timestamp: u64,
+        layout: FrameLayout,
+        handle: H,
+        force_keyframe: bool,
+    ) -> EncodeResult<Vec<CodedBitstreamBuffer>> {
+        let meta = FrameMetadata {
+            timestamp,
+            display_resolution: layout.size,
+            layout,
+            force_keyframe: force_keyframe || std::mem::take(&mut self.force_keyframe),
+            damage: None,
+        };
+
+        self.latency.input();
+        self.encoder.encode(meta, handle)?;
+        self.poll()
+    }
+
+    /// Encodes all the pending frames and returns their output, e.g. on EOS.
+    pub fn finish(&mut self) -> EncodeResult<Vec<CodedBitstreamBuffer>> {
+        self.encoder.drain()?;
+        let coded = self.poll()?;
+        self.latency.reset();
+
+        Ok(coded)
+    }
+
+    /// Drops all the pending frames, e.g. on a seek. The next frame is encoded as a keyframe so
+    /// the output can be decoded from it.
+    pub fn flush(&mut self) -> EncodeResult<()> {
+        self.finish()?;
+        self.force_keyframe = true;
+
+        Ok(())
+    }
+
+    /// Returns the latency to report.
+    pub fn latency(&self) -> Latency {
+        self.latency.latency()
+    }
+
+    /// Returns whether the latency changed since the last call, in which case the element should
+    /// post a latency message.
+    pub fn latency_changed(&mut self) -> bool {
+        std::mem::take(&mut self.latency.changed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+
+    use super::*;
+
+    /// Encoder holding `delay` frames before outputting them.
+    struct DelayEncoder {
+        delay: usize,
+        pending: VecDeque<FrameMetadata>,
+        output: VecDeque<CodedBitstreamBuffer>,
+    }
+
+    impl StatelessVideoEncoder<()> for DelayEncoder {
+        fn encode(&mut self, meta: FrameMetadata, _: ()) -> EncodeResult<()> {
+            self.pending.push_back(meta);
+            while self.pending.len() > self.delay {
+                let meta = self.pending.pop_front().unwrap();
+                self.output
+                    .push_back(CodedBitstreamBuffer::new(meta, vec![0]));
+            }
+
+            Ok(())
+        }
+
+        fn drain(&mut self) -> EncodeResult<()> {
+            for meta in self.pending.drain(..) {
+                self.output
+                    .push_back(CodedBitstreamBuffer::new(meta, vec![0]));
+            }
+
+            Ok(())
+        }
+
+        fn poll(&mut self) -> EncodeResult<Option<CodedBitstreamBuffer>> {
+            Ok(self.output.pop_front())
+        }
+
+        fn request_segment_point(&mut self) {}
+    }
+
+    fn nv12_layout(size: Resolution) -> FrameLayout {
+        let width = size.width as usize;
+        let height = size.height as usize;
+        frame_layout("NV12", size, &[0, width * height], &[width, width]).unwrap()
+    }
+
+    #[test]
+    fn layouts() {
+        let size = Resolution::from((64, 48));
+        let layout = nv12_layout(size);
+        assert_eq!(layout.format, (Fourcc::from(b"NV12"), 0));
+        assert_eq!(layout.planes.len(), 2);
+        assert_eq!(layout.planes[1].offset, 64 * 48);
+
+        // Missing planes and unsupported formats.
+        assert!(frame_layout("I420", size, &[0, 3072], &[64, 32]).is_none());
+        assert!(frame_layout("RGB16", size, &[0], &[128]).is_none());
+    }
+
+    #[test]
+    fn caps() {
+        let size = Resolution::from((1280, 720));
+        assert_eq!(
+            raw_caps(DecodedFormat::I010, size, Some((30, 1))),
+            "video/x-raw, format=(string)I420_10LE, width=(int)1280, height=(int)720, \
+             framerate=(fraction)30/1"
+        );
+
+        let caps = h264_caps(Profile::Main, Level::L3_1, size, None);
+        assert!(caps.contains("profile=(string)main"));
+        assert!(caps.contains("level=(string)3.1"));
+        assert!(caps.contains("framerate=(fraction)0/1"));
+        assert!(h264_caps(Profile::High, Level::L4, size, None).contains("level=(string)4,"));
+    }
+
+    #[test]
+    fn encoder_latency_and_flush() {
+        let size = Resolution::from((64, 48));
+        let mut encoder = ElementEncoder::new(
+            DelayEncoder {
+                delay: 2,
+                pending: Default::default(),
+                output: Default::default(),
+            },
+            Some((25, 1)),
+        );
+
+        assert!(encoder
+            .handle_frame(0, nv12_layout(size), (), false)
+            .unwrap()
+            .is_empty());
+        assert!(encoder
+            .handle_frame(1, nv12_layout(size), (), false)
+            .unwrap()
+            .is_empty());
+        assert_eq!(encoder.latency(), Latency::default());
+
+        let coded = encoder
+            .handle_frame(2, nv12_layout(size), (), false)
+            .unwrap();
+        assert_eq!(coded.len(), 1);
+        assert_eq!(coded[0].metadata.timestamp, 0);
+        assert!(encoder.latency_changed());
+        assert!(!encoder.latency_changed());
+        // Two frames were held before the first output.
+        assert_eq!(encoder.latency().min, 80_000_000);
+
+        encoder.flush().unwrap();
+        let coded = encoder
+            .handle_frame(3, nv12_layout(size), (), false)
+            .unwrap();
+        assert!(coded.is_empty());
+        let coded = encoder.finish().unwrap();
+        assert_eq!(coded.len(), 1);
+        assert!(coded[0].metadata.force_keyframe);
+    }
+}
@@ -0,0 +1,114 @@
+// Copyright 2024 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Instrumentation of the decoders and encoders.
+//!
+//! Every decoder and encoder instance is a session with a unique [`SessionId`]. With the
+//! `tracing` feature, each call into a session is recorded as a [`tracing`] span carrying the
+//! session ID and the timestamp of the frame being processed, so that services handling several
+//! streams can attribute events to them. Calls into the backends are timed and recorded as events
+//! of the `cros_codecs::backend` target.
+//!
+//! Without the feature, spans and timings are no-ops and trace events are forwarded to [`log`].
+//!
+//! [`tracing`]: https://docs.rs/tracing
+
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+
+/// Source of the session IDs.
+static NEXT_SESSION_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Unique identifier of a decoding or encoding session.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct SessionId(u64);
+
+impl SessionId {
+    /// Allocates a new session ID, unique within the process.
+    pub fn new() -> Self {
+        Self(NEXT_SESSION_ID.fetch_add(1, Ordering::Relaxed))
+    }
+
+    pub fn value(&self) -> u64 {
+        self.0
+    }
+}
+
+impl Default for SessionId {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl std::fmt::Display for SessionId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// Guard of a session span, which is exited when the guard is dropped.
+pub(crate) struct SessionSpan {
+    #[cfg(feature = "tracing")]
+    _entered: tracing::span::EnteredSpan,
+}
+
+/// Enters the span of `operation` (e.g. `"decode"`) on the `kind` (`"decoder"` or `"encoder"`)
+/// session `session`, processing the frame with `timestamp`.
+#[allow(unused_variables)]
+pub(crate) fn enter_span(
+    kind: &'static str,
+    session: SessionId,
+    operation: &'static str,
+    timestamp: Option<u64>,
+) -> SessionSpan {
+    SessionSpan {
+        #[cfg(feature = "tracing")]
+        _entered: tracing::debug_span!("session", kind, session = session.0, operation, timestamp)
+            .entered(),
+    }
+}
+
+/// Runs `f`, which calls `call` into the backend, and records how long it took.
+#[allow(unused_variables)]
+pub(crate) fn time_backend_call<T>(call: &'static str, f: impl FnOnce() -> T) -> T {
+    #[cfg(feature = "tracing")]
+    {
+        let start = std::time::Instant::now();
+        let res = f();
+        tracing::trace!(
+            target: "cros_codecs::backend",
+            call,
+            elapsed_us = start.elapsed().as_micros() as u64
+        );
+        res
+    }
+
+    #[cfg(not(feature = "tracing"))]
+    f()
+}
+
+/// Emits a trace event, through `tracing` with the `tracing` feature and `log` otherwise.
+macro_rules! trace_event {
+    ($($arg:tt)+) => {{
+        #[cfg(feature = "tracing")]
+        tracing::trace!($($arg)+);
+        #[cfg(not(feature = "tracing"))]
+        log::trace!($($arg)+);
+    }};
+}
+
+pub(crate) use trace_event;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unique_session_ids() {
+        let a = SessionId::new();
+        let b = SessionId::new();
+        assert_ne!(a, b);
+        assert!(b.value() > a.value());
+    }
+}
@@ -17,6 +17,17 @@
 //! The [encoder] module contains encoder that can turn a picture sequence into a compressed
 //! sequence of decodable encoded packets using the hardware acceleration available on the host.
 //!
+//! The [transcode] module connects decoders to encoders in order to convert a stream from one
+//! codec or configuration to another.
+//!
+//! The [gst] module helps embedding the decoders and encoders into GStreamer elements.
+//!
+//! The [roundtrip] module encodes synthetic frames and decodes them back, checking the result, in
+//! order to test encoder and decoder implementations.
+//!
+//! The `webcodecs` module, enabled by the `webcodecs` feature, provides decoders and encoders
+//! following the semantics of the WebCodecs API.
+//!
 //! The [utils] module contains some useful code that is shared between different parts of this
 //! crate and didn't fit any of the modules above.
 
@@ -24,11 +35,21 @@ pub mod backend;
 pub mod codec;
 pub mod decoder;
 pub mod encoder;
+pub mod gst;
+pub mod instrument;
+pub mod roundtrip;
+pub mod transcode;
 pub mod utils;
+#[cfg(feature = "virtio-video")]
+pub mod virtio_video;
+#[cfg(feature = "webcodecs")]
+pub mod webcodecs;
 
 use std::str::FromStr;
 
+#[cfg(feature = "vaapi")]
 use byteorder::ByteOrder;
+#[cfg(feature = "vaapi")]
 use byteorder::LittleEndian;
 #[cfg(feature = "vaapi")]
 pub use libva;
@@ -138,6 +159,80 @@ impl std::fmt::Debug for Fourcc {
     }
 }
 
+/// Horizontal and vertical subsampling of a plane, and its number of interleaved samples.
+type PlaneInfo = (bool, bool, usize);
+
+impl Fourcc {
+    /// Returns the number of bits per sample and the bytes of memory storing each sample, and for
+    /// each plane its horizontal and vertical subsampling and number of interleaved samples, or
+    /// `None` if the format is not supported.
+    fn plane_info(&self) -> Option<(u32, usize, &'static [PlaneInfo])> {
+        const YUV420: &[PlaneInfo] = &[(false, false, 1), (true, true, 1), (true, true, 1)];
+        const YUV420_SP: &[PlaneInfo] = &[(false, false, 1), (true, true, 2)];
+        const YUV422: &[PlaneInfo] = &[(false, false, 1), (true, false, 1), (true, false, 1)];
+        const YUV444: &[PlaneInfo] = &[(false, false, 1); 3];
+        const YUV422_PACKED: &[PlaneInfo] = &[(true, false, 4)];
+        const YUV444_PACKED: &[PlaneInfo] = &[(false, false, 1)];
+        const PACKED: &[PlaneInfo] = &[(false, false, 4)];
+
+        let info = match &<[u8; 4]>::from(*self) {
+            b"NV12" | b"NV21" => (8, 1, YUV420_SP),
+            b"I420" | b"YV12" => (8, 1, YUV420),
+            b"422H" => (8, 1, YUV422),
+            b"444P" => (8, 1, YUV444),
+            // 10 and 12 bits samples stored in the most significant bits of 16 bits words.
+            b"P010" => (10, 2, YUV420_SP),
+            b"P012" => (12, 2, YUV420_SP),
+            b"Y210" => (10, 2, YUV422_PACKED),
+            b"Y212" => (12, 2, YUV422_PACKED),
+            b"Y412" => (12, 2, PACKED),
+            // 10 bits samples stored in the least significant bits of 16 bits words.
+            b"I010" => (10, 2, YUV420),
+            // Three 10 bits samples and a 2 bits alpha packed in each 32 bits word.
+            b"Y410" => (10, 4, YUV444_PACKED),
+            b"BGRA" => (8, 1, PACKED),
+            _ => return None,
+        };
+
+        Some(info)
+    }
+
+    /// Returns the number of significant bits of each sample of the format, or `None` if the
+    /// format is not supported.
+    pub fn bit_depth(&self) -> Option<u32> {
+        self.plane_info().map(|(bit_depth, _, _)| bit_depth)
+    }
+
+    /// Returns the size of each plane of a frame of `size` in this format, or `None` if the format
+    /// is not supported.
+    pub fn plane_sizes(&self, size: Resolution) -> Option<Vec<PlaneSize>> {
+        let (_, bytes_per_sample, planes) = self.plane_info()?;
+        let width = size.width as usize;
+        let height = size.height as usize;
+
+        let sizes = planes
+            .iter()
+            .map(|&(sub_h, sub_v, samples)| PlaneSize {
+                row_size: if sub_h { width.div_ceil(2) } else { width }
+                    * samples
+                    * bytes_per_sample,
+                rows: if sub_v { height.div_ceil(2) } else { height },
+            })
+            .collect();
+
+        Some(sizes)
+    }
+}
+
+/// Size of a plane of a frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PlaneSize {
+    /// Size in bytes of a row of the plane, without padding.
+    pub row_size: usize,
+    /// Number of rows of the plane.
+    pub rows: usize,
+}
+
 /// Formats that buffers can be mapped into for the CPU to read.
 ///
 /// The conventions here largely follow these of libyuv.
@@ -215,6 +310,80 @@ pub struct FrameLayout {
     pub planes: Vec<PlaneLayout>,
 }
 
+impl FrameLayout {
+    /// Checks that the layout is consistent with its format: number of planes, stride large
+    /// enough for a row of each plane, and alignment of planes with 16 bits samples to 2 bytes.
+    ///
+    /// Tiled layouts, i.e. with a non-zero modifier, only have their number of planes checked.
+    pub fn validate_planes(&self) -> anyhow::Result<()> {
+        self.checked_plane_sizes().map(|_| ())
+    }
+
+    /// Checks that the layout is consistent with its format like [`FrameLayout::validate_planes`],
+    /// and that its planes fit within buffers of `buffer_sizes` bytes.
+    pub fn validate(&self, buffer_sizes: &[usize]) -> anyhow::Result<()> {
+        let sizes = self.checked_plane_sizes()?;
+        if self.format.1 != 0 {
+            return Ok(());
+        }
+
+        for (i, (plane, size)) in self.planes.iter().zip(&sizes).enumerate() {
+            let buffer_size = *buffer_sizes.get(plane.buffer_index).ok_or_else(|| {
+                anyhow::anyhow!("plane {} is in missing buffer {}", i, plane.buffer_index)
+            })?;
+            let end = plane.offset + plane.stride * size.rows.saturating_sub(1) + size.row_size;
+            anyhow::ensure!(
+                end <= buffer_size,
+                "plane {} ends at byte {}, past the {} bytes of its buffer",
+                i,
+                end,
+                buffer_size
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Validates the planes of the layout and returns their sizes.
+    fn checked_plane_sizes(&self) -> anyhow::Result<Vec<PlaneSize>> {
+        let fourcc = self.format.0;
+        let (_, bytes_per_sample, _) = fourcc
+            .plane_info()
+            .ok_or_else(|| anyhow::anyhow!("unsupported frame format {}", fourcc))?;
+        let sizes = fourcc.plane_sizes(self.size).unwrap_or_default();
+        anyhow::ensure!(
+            self.planes.len() == sizes.len(),
+            "{} frames have {} planes, got {}",
+            fourcc,
+            sizes.len(),
+            self.planes.len()
+        );
+        if self.format.1 != 0 {
+            return Ok(sizes);
+        }
+
+        for (i, (plane, size)) in self.planes.iter().zip(&sizes).enumerate() {
+            anyhow::ensure!(
+                plane.stride >= size.row_size,
+                "stride {} of plane {} is smaller than its rows of {} bytes",
+                plane.stride,
+                i,
+                size.row_size
+            );
+            anyhow::ensure!(
+                plane.offset % bytes_per_sample == 0 && plane.stride % bytes_per_sample == 0,
+                "offset {} and stride {} of plane {} are not aligned to its {} bytes samples",
+                plane.offset,
+                plane.stride,
+                i,
+                bytes_per_sample
+            );
+        }
+
+        Ok(sizes)
+    }
+}
+
 /// Build a frame memory descriptor enum that supports multiple descriptor types.
 ///
 /// This is useful for the case where the frames' memory backing is not decided at compile-time.
@@ -224,6 +393,7 @@ pub struct FrameLayout {
 /// # Example
 ///
 /// ```
+/// # #[cfg(unix)] {
 /// use cros_codecs::multiple_desc_type;
 /// use cros_codecs::utils::DmabufFrame;
 ///
@@ -234,6 +404,7 @@ pub struct FrameLayout {
 ///         Dmabuf(DmabufFrame),
 ///     }
 /// }
+/// # }
 /// ```
 #[macro_export]
 macro_rules! multiple_desc_type {
@@ -382,6 +553,7 @@ pub fn decoded_frame_size(format: DecodedFormat, width: usize, height: usize) ->
 
 /// Copies `src` into `dst` as I410, removing all padding and changing the layout from packed to
 /// triplanar. Also drops the alpha channel.
+#[cfg(feature = "vaapi")]
 fn y410_to_i410(
     src: &[u8],
     dst: &mut [u8],
@@ -433,6 +605,10 @@ pub enum BlockingMode {
 #[cfg(test)]
 mod tests {
     use super::Fourcc;
+    use super::FrameLayout;
+    use super::PlaneLayout;
+    use super::PlaneSize;
+    use super::Resolution;
 
     const NV12_FOURCC: u32 = 0x3231564E;
 
@@ -461,4 +637,87 @@ mod tests {
         let fourcc = Fourcc::from(NV12_FOURCC);
         assert_eq!(format!("{:?}", fourcc), "0x3231564e (NV12)");
     }
+
+    #[test]
+    fn plane_sizes() {
+        let size = Resolution::from((5, 3));
+        let nv12 = Fourcc::from(b"NV12");
+        assert_eq!(nv12.bit_depth(), Some(8));
+        assert_eq!(
+            nv12.plane_sizes(size).unwrap(),
+            vec![
+                PlaneSize {
+                    row_size: 5,
+                    rows: 3
+                },
+                PlaneSize {
+                    row_size: 6,
+                    rows: 2
+                }
+            ]
+        );
+
+        let p010 = Fourcc::from(b"P010");
+        assert_eq!(p010.bit_depth(), Some(10));
+        assert_eq!(p010.plane_sizes(size).unwrap()[1].row_size, 12);
+
+        let i010 = Fourcc::from(b"I010");
+        let sizes = i010.plane_sizes(size).unwrap();
+        assert_eq!(sizes.len(), 3);
+        assert_eq!(sizes[0].row_size, 10);
+        assert_eq!(sizes[2].row_size, 6);
+
+        let y210 = Fourcc::from(b"Y210");
+        assert_eq!(
+            y210.plane_sizes(size).unwrap(),
+            vec![PlaneSize {
+                row_size: 24,
+                rows: 3
+            }]
+        );
+        assert_eq!(
+            Fourcc::from(b"Y410").plane_sizes(size).unwrap()[0].row_size,
+            20
+        );
+
+        assert!(Fourcc::from(b"RG24").plane_sizes(size).is_none());
+    }
+
+    #[test]
+    fn validate_layout() {
+        let plane = |buffer_index, offset, stride| PlaneLayout {
+            buffer_index,
+            offset,
+            stride,
+        };
+        let mut layout = FrameLayout {
+            format: (Fourcc::from(b"P010"), 0),
+            size: Resolution::from((4, 2)),
+            planes: vec![plane(0, 0, 8), plane(0, 16, 8)],
+        };
+        layout.validate(&[24]).unwrap();
+        // The chroma plane does not fit in the buffer.
+        assert!(layout.validate(&[23]).is_err());
+
+        // Misaligned 16 bits samples.
+        layout.planes[1] = plane(0, 17, 8);
+        assert!(layout.validate(&[32]).is_err());
+
+        // Planes in separate buffers.
+        layout.planes[1] = plane(1, 0, 8);
+        layout.validate(&[16, 8]).unwrap();
+        assert!(layout.validate(&[16]).is_err());
+        // Buffer sizes are not known to `validate_planes`.
+        layout.validate_planes().unwrap();
+
+        // Strides too small for 16 bits samples.
+        layout.planes[0].stride = 4;
+        assert!(layout.validate(&[16, 8]).is_err());
+        assert!(layout.validate_planes().is_err());
+
+        // Missing plane, which is also checked for tiled layouts.
+        layout.planes.pop();
+        layout.format.1 = 1;
+        assert!(layout.validate(&[16]).is_err());
+    }
 }
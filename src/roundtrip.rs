@@ -0,0 +1,322 @@
+// Copyright 2024 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Round-trip testing of encoders: synthetic frames are encoded, the resulting bitstream is
+//! decoded with the matching decoder, and the decoded frames are checked against the originals.
+//!
+//! [`round_trip`] is generic over the encoder and decoder traits, so it can be used to test the
+//! backends of this crate as well as downstream implementations of these traits.
+
+use anyhow::anyhow;
+use anyhow::ensure;
+
+use crate::decoder::stateless::StatelessDecoderBackend;
+use crate::decoder::stateless::StatelessVideoDecoder;
+use crate::decoder::BlockingMode;
+use crate::decoder::DecodedHandle;
+use crate::decoder::StreamInfo;
+use crate::encoder::stateless::StatelessVideoEncoder;
+use crate::encoder::CodedBitstreamBuffer;
+use crate::encoder::FrameMetadata;
+use crate::utils::quality;
+use crate::utils::quality::FrameQuality;
+use crate::utils::simple_playback_loop;
+use crate::DecodedFormat;
+use crate::Fourcc;
+use crate::FrameLayout;
+use crate::Resolution;
+
+/// 75% color bars as `(Y, U, V)` BT.601 limited range values, from left to right.
+const COLOR_BARS: [(u8, u8, u8); 8] = [
+    (180, 128, 128), // White
+    (162, 44, 142),  // Yellow
+    (131, 156, 44),  // Cyan
+    (112, 72, 58),   // Green
+    (84, 184, 198),  // Magenta
+    (65, 100, 212),  // Red
+    (35, 212, 114),  // Blue
+    (16, 128, 128),  // Black
+];
+
+/// Synthetic content to feed the encoder with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TestPattern {
+    /// Static vertical color bars.
+    ColorBars,
+    /// A white box moving diagonally over a dark background, exercising inter prediction.
+    MovingBox,
+    /// Uniform random noise, different for every frame. Expect a low PSNR.
+    Noise,
+}
+
+impl TestPattern {
+    /// Generates frame `index` of the pattern as a tightly packed NV12 frame of `resolution`.
+    pub fn generate(&self, resolution: Resolution, index: u64) -> Vec<u8> {
+        let width = resolution.width as usize;
+        let height = resolution.height as usize;
+        let chroma_width = width.div_ceil(2);
+        let chroma_height = height.div_ceil(2);
+
+        let mut frame = vec![0u8; width * height + chroma_width * 2 * chroma_height];
+        let (luma, chroma) = frame.split_at_mut(width * height);
+
+        match self {
+            TestPattern::ColorBars => {
+                let bar = |x: usize| COLOR_BARS[x * COLOR_BARS.len() / width];
+
+                for line in luma.chunks_mut(width) {
+                    for (x, y) in line.iter_mut().enumerate() {
+                        *y = bar(x).0;
+                    }
+                }
+                for line in chroma.chunks_mut(chroma_width * 2) {
+                    for (x, uv) in line.chunks_mut(2).enumerate() {
+                        let (_, u, v) = bar(x * 2);
+                        uv.copy_from_slice(&[u, v]);
+                    }
+                }
+            }
+            TestPattern::MovingBox => {
+                let box_width = std::cmp::max(width / 4, 1);
+                let box_height = std::cmp::max(height / 4, 1);
+                let box_x = (index as usize * 4) % (width - box_width + 1);
+                let box_y = (index as usize * 4) % (height - box_height + 1);
+                let inside = |x: usize, y: usize| {
+                    (box_x..box_x + box_width).contains(&x)
+                        && (box_y..box_y + box_height).contains(&y)
+                };
+
+                for (y, line) in luma.chunks_mut(width).enumerate() {
+                    for (x, sample) in line.iter_mut().enumerate() {
+                        *sample = if inside(x, y) { 235 } else { 48 };
+                    }
+                }
+                chroma.fill(128);
+            }
+            TestPattern::Noise => {
+                // xorshift64, seeded so that every frame is different but reproducible.
+                let mut state =
+                    0x9e37_79b9_7f4a_7c15u64 ^ index.wrapping_mul(0x2545_f491_4f6c_dd1d);
+                for sample in luma.iter_mut().chain(chroma.iter_mut()) {
+                    state ^= state << 13;
+                    state ^= state >> 7;
+                    state ^= state << 17;
+                    *sample = state as u8;
+                }
+            }
+        }
+
+        frame
+    }
+}
+
+/// Parameters of a round-trip test.
+#[derive(Clone, Debug)]
+pub struct RoundTripParams {
+    /// Content of the frames.
+    pub pattern: TestPattern,
+    /// Resolution of the frames.
+    pub resolution: Resolution,
+    /// Number of frames to encode.
+    pub num_frames: u64,
+    /// Minimum PSNR of every decoded frame, in dB.
+    pub min_psnr: f64,
+}
+
+/// Reads the decoded frame `handle` back into CPU memory.
+fn read_decoded_frame<H: DecodedHandle>(handle: &H) -> anyhow::Result<Vec<u8>> {
+    handle.sync()?;
+
+    let picture = handle.dyn_picture();
+    let mut mappable = picture.dyn_mappable_handle()?;
+    let mut frame = vec![0u8; mappable.image_size()];
+    mappable.read(&mut frame)?;
+
+    Ok(frame)
+}
+
+/// Encodes `params.num_frames` frames of `params.pattern` with `encoder`, decodes the resulting
+/// bitstream with `decoder` and checks that the decoded frames match the originals.
+///
+/// `import_frame` turns a tightly packed NV12 frame and its metadata into an input handle for
+/// `encoder`. `allocate_new_frames` and `blocking_mode` are used for decoding as with
+/// [`simple_playback_loop`]. The decoder is expected to take one unit of encoded bitstream at a
+/// time, as they are produced by the encoder.
+///
+/// Returns an error if the number of encoded or decoded frames, their ordering or resolution do
+/// not match, or if the PSNR of any decoded frame is below `params.min_psnr`. Otherwise, the
+/// quality of every decoded frame is returned in display order.
+#[allow(clippy::type_complexity)]
+pub fn round_trip<E, H, D, B>(
+    params: &RoundTripParams,
+    encoder: &mut E,
+    import_frame: &mut dyn FnMut(&FrameMetadata, &[u8]) -> anyhow::Result<H>,
+    decoder: &mut D,
+    allocate_new_frames: &mut dyn FnMut(
+        &StreamInfo,
+        usize,
+    ) -> anyhow::Result<
+        Vec<<B::Handle as DecodedHandle>::Descriptor>,
+    >,
+    blocking_mode: BlockingMode,
+) -> anyhow::Result<Vec<FrameQuality>>
+where
+    E: StatelessVideoEncoder<H> + ?Sized,
+    B: StatelessDecoderBackend,
+    D: StatelessVideoDecoder<B> + ?Sized,
+{
+    let nv12_layout = |resolution| {
+        quality::packed_layout(Fourcc::from(b"NV12"), resolution)
+            .ok_or_else(|| anyhow!("NV12 is not supported by quality metrics"))
+    };
+    let layout: FrameLayout = nv12_layout(params.resolution)?;
+
+    let frames = (0..params.num_frames)
+        .map(|index| params.pattern.generate(params.resolution, index))
+        .collect::<Vec<_>>();
+
+    // Encode all the frames.
+    let mut coded: Vec<CodedBitstreamBuffer> = Vec::new();
+    for (timestamp, frame) in frames.iter().enumerate() {
+        let meta = FrameMetadata {
+            timestamp: timestamp as u64,
+            display_resolution: params.resolution,
+            layout: layout.clone(),
+            force_keyframe: false,
+            damage: None,
+        };
+
+        let handle = import_frame(&meta, frame)?;
+        encoder.encode(meta, handle)?;
+        while let Some(buffer) = encoder.poll()? {
+            coded.push(buffer);
+        }
+    }
+
+    encoder.drain()?;
+    while let Some(buffer) = encoder.poll()? {
+        coded.push(buffer);
+    }
+
+    ensure!(
+        coded.len() == frames.len(),
+        "encoder produced {} units for {} frames",
+        coded.len(),
+        frames.len()
+    );
+    for (index, buffer) in coded.iter().enumerate() {
+        ensure!(
+            buffer.metadata.timestamp == index as u64,
+            "encoded unit {} has timestamp {}",
+            index,
+            buffer.metadata.timestamp
+        );
+    }
+
+    // Decode them back. `simple_playback_loop` timestamps the frames with the index of the unit
+    // they have been decoded from, which matches the encoder's timestamps checked above.
+    let mut decoded = Vec::new();
+    let mut read_error = None;
+    simple_playback_loop(
+        decoder,
+        coded.iter().map(|buffer| &buffer.bitstream),
+        &mut |handle| {
+            if read_error.is_some() {
+                return;
+            }
+
+            match read_decoded_frame(&handle) {
+                Ok(frame) => decoded.push((handle.timestamp(), handle.display_resolution(), frame)),
+                Err(e) => read_error = Some(e),
+            }
+        },
+        allocate_new_frames,
+        DecodedFormat::NV12,
+        blocking_mode,
+    )?;
+
+    if let Some(e) = read_error {
+        return Err(e);
+    }
+
+    ensure!(
+        decoded.len() == frames.len(),
+        "decoder produced {} frames for {} encoded frames",
+        decoded.len(),
+        frames.len()
+    );
+
+    let mut scores = Vec::with_capacity(decoded.len());
+    for (index, (timestamp, resolution, frame)) in decoded.iter().enumerate() {
+        ensure!(
+            *timestamp == index as u64,
+            "decoded frame {} has timestamp {}",
+            index,
+            timestamp
+        );
+        ensure!(
+            *resolution == params.resolution,
+            "decoded frame {} has resolution {:?}, expected {:?}",
+            index,
+            resolution,
+            params.resolution
+        );
+
+        let score = quality::compare(&frames[index], &layout, frame, &nv12_layout(*resolution)?)?;
+        ensure!(
+            score.psnr_frame >= params.min_psnr,
+            "decoded frame {} has a PSNR of {:.2}dB, expected at least {:.2}dB",
+            index,
+            score.psnr_frame,
+            params.min_psnr
+        );
+
+        scores.push(score);
+    }
+
+    Ok(scores)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RESOLUTION: Resolution = Resolution {
+        width: 64,
+        height: 48,
+    };
+
+    #[test]
+    fn pattern_sizes() {
+        for pattern in [
+            TestPattern::ColorBars,
+            TestPattern::MovingBox,
+            TestPattern::Noise,
+        ] {
+            for resolution in [RESOLUTION, Resolution::from((17, 9))] {
+                let (width, height) = (resolution.width, resolution.height);
+                let expected = width * height + width.div_ceil(2) * 2 * height.div_ceil(2);
+                assert_eq!(pattern.generate(resolution, 0).len(), expected as usize);
+            }
+        }
+    }
+
+    #[test]
+    fn pattern_motion() {
+        let bars = TestPattern::ColorBars;
+        assert_eq!(bars.generate(RESOLUTION, 0), bars.generate(RESOLUTION, 1));
+
+        for pattern in [TestPattern::MovingBox, TestPattern::Noise] {
+            // Reproducible, but different from one frame to the next.
+            assert_eq!(
+                pattern.generate(RESOLUTION, 3),
+                pattern.generate(RESOLUTION, 3)
+            );
+            assert_ne!(
+                pattern.generate(RESOLUTION, 3),
+                pattern.generate(RESOLUTION, 4)
+            );
+        }
+    }
+}
@@ -0,0 +1,217 @@
+// Copyright 2024 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Transcoding, i.e. connecting the output of a decoder to the input of an encoder.
+//!
+//! Decoded frames are handed to the encoder through a [`FrameConverter`], which turns a decoded
+//! handle into an input handle the encoder can import, along with its [`FrameMetadata`]. When the
+//! decoder and encoder work on the same device, the converter only needs to wrap the decoded
+//! handle or to schedule a conversion on the device if the formats or resolutions differ, so the
+//! frames are never mapped into CPU memory.
+
+#[cfg(feature = "vaapi")]
+pub mod vaapi;
+
+use crate::decoder::stateless::StatelessDecoderBackend;
+use crate::decoder::stateless::StatelessVideoDecoder;
+use crate::decoder::BlockingMode;
+use crate::decoder::DecodedHandle;
+use crate::decoder::StreamInfo;
+use crate::encoder::stateless::StatelessVideoEncoder;
+use crate::encoder::CodedBitstreamBuffer;
+use crate::encoder::FrameMetadata;
+use crate::utils::simple_playback_loop;
+use crate::DecodedFormat;
+
+/// Turns the decoded frames of type `H` into input frames for an encoder.
+///
+/// Any `FnMut(H) -> anyhow::Result<(FrameMetadata, O)>` closure can be used as a converter.
+pub trait FrameConverter<H: DecodedHandle> {
+    /// Input handle type of the encoder.
+    type Output;
+
+    /// Converts `handle` into an input frame for the encoder, along with the metadata to encode
+    /// it with.
+    fn convert(&mut self, handle: H) -> anyhow::Result<(FrameMetadata, Self::Output)>;
+}
+
+impl<H, O, F> FrameConverter<H> for F
+where
+    H: DecodedHandle,
+    F: FnMut(H) -> anyhow::Result<(FrameMetadata, O)>,
+{
+    type Output = O;
+
+    fn convert(&mut self, handle: H) -> anyhow::Result<(FrameMetadata, O)> {
+        self(handle)
+    }
+}
+
+/// Simple transcoding loop: decodes all the units of `stream_iter` with `decoder`, and encodes
+/// the decoded frames with `encoder` after converting them with `converter`.
+///
+/// `allocate_new_frames` and `output_format` are used to negotiate the decoder's output as with
+/// [`simple_playback_loop`]. Decoded frames are synced before being passed to `converter`, and
+/// `coded_consumer` is called for every unit of encoded bitstream. Both the decoder and the
+/// encoder are drained before returning.
+#[allow(clippy::too_many_arguments, clippy::type_complexity)]
+pub fn simple_transcode_loop<D, B, R, I, E, C>(
+    decoder: &mut D,
+    stream_iter: I,
+    allocate_new_frames: &mut dyn FnMut(
+        &StreamInfo,
+        usize,
+    ) -> anyhow::Result<
+        Vec<<B::Handle as DecodedHandle>::Descriptor>,
+    >,
+    output_format: DecodedFormat,
+    blocking_mode: BlockingMode,
+    encoder: &mut E,
+    converter: &mut C,
+    mut coded_consumer: impl FnMut(CodedBitstreamBuffer),
+) -> anyhow::Result<()>
+where
+    B: StatelessDecoderBackend,
+    D: StatelessVideoDecoder<B> + ?Sized,
+    R: AsRef<[u8]>,
+    I: Iterator<Item = R>,
+    E: StatelessVideoEncoder<C::Output> + ?Sized,
+    C: FrameConverter<B::Handle>,
+{
+    // `simple_playback_loop` does not let the frame callback fail, so keep the first error to
+    // report it once decoding is done.
+    let mut transcode_error = None;
+
+    simple_playback_loop(
+        decoder,
+        stream_iter,
+        &mut |handle| {
+            if transcode_error.is_some() {
+                return;
+            }
+
+            let res = handle
+                .sync()
+                .and_then(|()| converter.convert(handle))
+                .and_then(|(meta, frame)| Ok(encoder.encode(meta, frame)?))
+                .and_then(|()| {
+                    while let Some(coded) = encoder.poll()? {
+                        coded_consumer(coded);
+                    }
+                    Ok(())
+                });
+
+            if let Err(e) = res {
+                transcode_error = Some(e);
+            }
+        },
+        allocate_new_frames,
+        output_format,
+        blocking_mode,
+    )?;
+
+    if let Some(e) = transcode_error {
+        return Err(e);
+    }
+
+    encoder.drain()?;
+    while let Some(coded) = encoder.poll()? {
+        coded_consumer(coded);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+
+    use super::*;
+    use crate::backend::dummy::decoder::Handle;
+    use crate::codec::h264::parser::Nalu;
+    use crate::decoder::stateless::h264::tests::DECODE_TEST_25FPS;
+    use crate::decoder::stateless::h264::H264;
+    use crate::decoder::stateless::StatelessDecoder;
+    use crate::encoder::stateless::EncodeResult;
+    use crate::utils::simple_playback_loop_owned_frames;
+    use crate::utils::NalIterator;
+    use crate::Fourcc;
+    use crate::FrameLayout;
+    use crate::Resolution;
+
+    /// Encoder that holds up to two frames and outputs their timestamp as bitstream.
+    #[derive(Default)]
+    struct DummyEncoder {
+        queue: VecDeque<FrameMetadata>,
+        output: VecDeque<CodedBitstreamBuffer>,
+    }
+
+    impl StatelessVideoEncoder<Handle> for DummyEncoder {
+        fn encode(&mut self, meta: FrameMetadata, _handle: Handle) -> EncodeResult<()> {
+            self.queue.push_back(meta);
+            if self.queue.len() > 2 {
+                self.drain()?;
+            }
+            Ok(())
+        }
+
+        fn drain(&mut self) -> EncodeResult<()> {
+            for meta in self.queue.drain(..) {
+                let bitstream = meta.timestamp.to_le_bytes().to_vec();
+                self.output
+                    .push_back(CodedBitstreamBuffer::new(meta, bitstream));
+            }
+            Ok(())
+        }
+
+        fn poll(&mut self) -> EncodeResult<Option<CodedBitstreamBuffer>> {
+            Ok(self.output.pop_front())
+        }
+
+        fn request_segment_point(&mut self) {
+            // Every frame is independently coded.
+        }
+    }
+
+    #[test]
+    fn transcode_h264() {
+        let mut decoder = StatelessDecoder::<H264, _>::new_dummy(BlockingMode::Blocking);
+        let mut encoder = DummyEncoder::default();
+        let mut frame_num = 0;
+        let mut coded = Vec::new();
+
+        simple_transcode_loop(
+            &mut decoder,
+            NalIterator::<Nalu>::new(DECODE_TEST_25FPS.stream),
+            &mut simple_playback_loop_owned_frames,
+            DecodedFormat::NV12,
+            BlockingMode::Blocking,
+            &mut encoder,
+            &mut |handle: Handle| {
+                let meta = FrameMetadata {
+                    timestamp: frame_num,
+                    display_resolution: handle.display_resolution(),
+                    layout: FrameLayout {
+                        format: (Fourcc::from(b"NV12"), 0),
+                        size: Resolution::from((320, 240)),
+                        planes: vec![],
+                    },
+                    force_keyframe: false,
+                    damage: None,
+                };
+                frame_num += 1;
+
+                Ok((meta, handle))
+            },
+            |buffer| coded.push(buffer),
+        )
+        .unwrap();
+
+        assert_eq!(coded.len(), DECODE_TEST_25FPS.crcs.lines().count());
+        for (i, buffer) in coded.iter().enumerate() {
+            assert_eq!(buffer.metadata.timestamp, i as u64);
+            assert_eq!(buffer.bitstream[..], (i as u64).to_le_bytes());
+        }
+    }
+}
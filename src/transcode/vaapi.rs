@@ -0,0 +1,221 @@
+// Copyright 2024 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Transcoding between a VAAPI decoder and a VAAPI encoder created on the same display.
+
+use std::rc::Rc;
+
+use libva::Display;
+use libva::Picture;
+use libva::PictureSync;
+use libva::Surface;
+
+use crate::backend::vaapi::decoder::DecodedHandle;
+use crate::backend::vaapi::vpp::Vpp;
+use crate::decoder::DecodedHandle as DecodedHandleTrait;
+use crate::encoder::FrameMetadata;
+use crate::transcode::FrameConverter;
+use crate::Fourcc;
+use crate::FrameLayout;
+use crate::PlaneLayout;
+use crate::Resolution;
+
+/// Input frame of a VAAPI encoder produced by [`VaapiFrameConverter`].
+pub struct VaapiTranscodeFrame(Picture<PictureSync, Surface<()>>);
+
+impl std::borrow::Borrow<Surface<()>> for VaapiTranscodeFrame {
+    fn borrow(&self) -> &Surface<()> {
+        self.0.surface()
+    }
+}
+
+/// Converts the frames of a VAAPI decoder into input frames of a VAAPI encoder, without mapping
+/// them into CPU memory.
+///
+/// Each decoded frame is copied with VPP into a new surface, scaled and converted to the format
+/// expected by the encoder if needed. The decoder keeps the surface of a decoded frame as long as
+/// its handle is alive, so the copy completes before the decoder can reuse it, and the encoder
+/// never reads a surface the decoder may be rewriting.
+pub struct VaapiFrameConverter {
+    display: Rc<Display>,
+    /// Format of the frames expected by the encoder.
+    fourcc: Fourcc,
+    /// Resolution of the frames expected by the encoder.
+    resolution: Resolution,
+    /// Post-processor copying the decoded frames, along with the format of the decoded surfaces
+    /// it processes.
+    vpp: Option<(Fourcc, Vpp)>,
+}
+
+impl VaapiFrameConverter {
+    /// Creates a new converter producing NV12 frames of `resolution` for an encoder. `display`
+    /// must be the display both the decoder and the encoder have been created on.
+    pub fn new(display: Rc<Display>, resolution: Resolution) -> Self {
+        Self {
+            display,
+            fourcc: Fourcc::from(b"NV12"),
+            resolution,
+            vpp: None,
+        }
+    }
+
+    /// Returns the post-processor converting surfaces of `fourcc`, creating it if needed.
+    fn vpp(&mut self, fourcc: Fourcc) -> anyhow::Result<&Vpp> {
+        let vpp = match self.vpp.take() {
+            Some((vpp_fourcc, vpp)) if vpp_fourcc == fourcc => vpp,
+            _ => Vpp::new_converter(&self.display, fourcc, self.fourcc, self.resolution)?,
+        };
+
+        Ok(&self.vpp.insert((fourcc, vpp)).1)
+    }
+
+    /// Returns the layout of an NV12 surface of `size`.
+    ///
+    /// The surfaces are not mapped, so their planes are described as if they were packed.
+    fn layout(size: Resolution) -> FrameLayout {
+        let width = size.width as usize;
+        let height = size.height as usize;
+
+        FrameLayout {
+            format: (Fourcc::from(b"NV12"), 0),
+            size,
+            planes: vec![
+                PlaneLayout {
+                    buffer_index: 0,
+                    offset: 0,
+                    stride: width,
+                },
+                PlaneLayout {
+                    buffer_index: 0,
+                    offset: width * height,
+                    stride: width,
+                },
+            ],
+        }
+    }
+}
+
+impl FrameConverter<DecodedHandle<()>> for VaapiFrameConverter {
+    type Output = VaapiTranscodeFrame;
+
+    fn convert(
+        &mut self,
+        handle: DecodedHandle<()>,
+    ) -> anyhow::Result<(FrameMetadata, VaapiTranscodeFrame)> {
+        let timestamp = handle.timestamp();
+        // Keep the decoded frame borrowed until the copy has completed.
+        let decoded = handle.borrow();
+        let fourcc = decoded.map_fourcc();
+        let visible_resolution = decoded.decoded_resolution();
+
+        let processed =
+            self.vpp(fourcc)?
+                .process(decoded.surface(), visible_resolution, None, timestamp)?;
+        drop(decoded);
+
+        let meta = FrameMetadata {
+            timestamp,
+            display_resolution: self.resolution,
+            layout: Self::layout(Resolution::from(processed.surface().size())),
+            force_keyframe: false,
+            damage: None,
+        };
+
+        Ok((meta, VaapiTranscodeFrame(processed)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use libva::Display;
+    use libva::VAEntrypoint::VAEntrypointEncSliceLP;
+    use libva::VAProfile::VAProfileH264Main;
+
+    use super::*;
+    use crate::backend::vaapi::encoder::VaapiBackend;
+    use crate::codec::h264::parser::Nalu;
+    use crate::decoder::stateless::h264::tests::DECODE_TEST_25FPS;
+    use crate::decoder::stateless::h264::H264;
+    use crate::decoder::stateless::StatelessDecoder;
+    use crate::decoder::BlockingMode;
+    use crate::encoder::stateless::h264::EncoderConfig;
+    use crate::encoder::stateless::h264::StatelessEncoder;
+    use crate::encoder::Bitrate;
+    use crate::transcode::simple_transcode_loop;
+    use crate::utils::simple_playback_loop;
+    use crate::utils::simple_playback_loop_owned_frames;
+    use crate::utils::NalIterator;
+    use crate::DecodedFormat;
+
+    #[test]
+    // Ignore this test by default as it requires libva-compatible hardware.
+    #[ignore]
+    fn test_vaapi_transcode_round_trip() {
+        type VaapiH264Encoder =
+            StatelessEncoder<VaapiTranscodeFrame, VaapiBackend<(), VaapiTranscodeFrame>>;
+
+        // Resolution of DECODE_TEST_25FPS.
+        let resolution = Resolution {
+            width: 320,
+            height: 240,
+        };
+
+        let display = Display::open().unwrap();
+        let entrypoints = display.query_config_entrypoints(VAProfileH264Main).unwrap();
+        let low_power = entrypoints.contains(&VAEntrypointEncSliceLP);
+
+        let mut decoder = StatelessDecoder::<H264, _>::new_vaapi::<()>(
+            Rc::clone(&display),
+            BlockingMode::Blocking,
+        );
+        let mut encoder = VaapiH264Encoder::new_vaapi(
+            Rc::clone(&display),
+            EncoderConfig {
+                bitrate: Bitrate::Constant(1_000_000),
+                resolution,
+                ..Default::default()
+            },
+            Fourcc::from(b"NV12"),
+            resolution,
+            low_power,
+            BlockingMode::Blocking,
+        )
+        .unwrap();
+        let mut converter = VaapiFrameConverter::new(Rc::clone(&display), resolution);
+
+        let mut num_decoded = 0;
+        let mut bitstream = Vec::new();
+        simple_transcode_loop(
+            &mut decoder,
+            NalIterator::<Nalu>::new(DECODE_TEST_25FPS.stream),
+            &mut simple_playback_loop_owned_frames,
+            DecodedFormat::NV12,
+            BlockingMode::Blocking,
+            &mut encoder,
+            &mut |handle: DecodedHandle<()>| {
+                num_decoded += 1;
+                converter.convert(handle)
+            },
+            |coded| bitstream.extend(coded.bitstream),
+        )
+        .unwrap();
+
+        // Decoding the transcoded stream gives back as many frames as the original one.
+        let mut decoder =
+            StatelessDecoder::<H264, _>::new_vaapi::<()>(display, BlockingMode::Blocking);
+        let mut num_transcoded = 0;
+        simple_playback_loop(
+            &mut decoder,
+            NalIterator::<Nalu>::new(&bitstream),
+            &mut |_| num_transcoded += 1,
+            &mut simple_playback_loop_owned_frames,
+            DecodedFormat::NV12,
+            BlockingMode::Blocking,
+        )
+        .unwrap();
+
+        assert!(num_decoded > 0);
+        assert_eq!(num_transcoded, num_decoded);
+    }
+}
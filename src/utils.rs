@@ -8,12 +8,10 @@
 //! new code here unless it really doesn't belong anywhere else.
 
 use std::io::Cursor;
-use std::io::Seek;
 use std::marker::PhantomData;
+#[cfg(unix)]
 use std::os::fd::OwnedFd;
 
-use bytes::Buf;
-
 use crate::codec::h264::parser::Nalu as H264Nalu;
 use crate::codec::h265::parser::Nalu as H265Nalu;
 use crate::decoder::stateless::DecodeError;
@@ -31,128 +29,26 @@ use crate::FrameLayout;
 use crate::PlaneLayout;
 use crate::Resolution;
 
-/// Iterator over IVF packets.
-pub struct IvfIterator<'a> {
-    cursor: Cursor<&'a [u8]>,
-}
-
-impl<'a> IvfIterator<'a> {
-    pub fn new(data: &'a [u8]) -> Self {
-        let mut cursor = Cursor::new(data);
-
-        // Skip the IVH header entirely.
-        cursor.seek(std::io::SeekFrom::Start(32)).unwrap();
-
-        Self { cursor }
-    }
-}
-
-impl<'a> Iterator for IvfIterator<'a> {
-    type Item = &'a [u8];
-
-    fn next(&mut self) -> Option<Self::Item> {
-        // Make sure we have a header.
-        if self.cursor.remaining() < 6 {
-            return None;
-        }
-
-        let len = self.cursor.get_u32_le() as usize;
-        // Skip PTS.
-        let _ = self.cursor.get_u64_le();
-
-        if self.cursor.remaining() < len {
-            return None;
-        }
-
-        let start = self.cursor.position() as usize;
-        let _ = self.cursor.seek(std::io::SeekFrom::Current(len as i64));
-        let end = self.cursor.position() as usize;
-
-        Some(&self.cursor.get_ref()[start..end])
-    }
-}
-
-/// Helper struct for synthesizing IVF file header
-pub struct IvfFileHeader {
-    pub magic: [u8; 4],
-    pub version: u16,
-    pub header_size: u16,
-    pub codec: [u8; 4],
-    pub width: u16,
-    pub height: u16,
-    pub framerate: u32,
-    pub timescale: u32,
-    pub frame_count: u32,
-    pub unused: u32,
-}
-
-impl Default for IvfFileHeader {
-    fn default() -> Self {
-        Self {
-            magic: Self::MAGIC,
-            version: 0,
-            header_size: 32,
-            codec: Self::CODEC_VP9,
-            width: 320,
-            height: 240,
-            framerate: 1,
-            timescale: 1000,
-            frame_count: 1,
-            unused: Default::default(),
-        }
-    }
-}
-
-impl IvfFileHeader {
-    pub const MAGIC: [u8; 4] = *b"DKIF";
-    pub const CODEC_VP9: [u8; 4] = *b"VP90";
-
-    pub fn new(codec: [u8; 4], width: u16, height: u16, framerate: u32, frame_count: u32) -> Self {
-        let default = Self::default();
-
-        Self {
-            codec,
-            width,
-            height,
-            framerate: framerate * default.timescale,
-            frame_count,
-            ..default
-        }
-    }
-}
-
-impl IvfFileHeader {
-    /// Writes header into writer
-    pub fn writo_into(&self, writer: &mut impl std::io::Write) -> std::io::Result<()> {
-        writer.write_all(&self.magic)?;
-        writer.write_all(&self.version.to_le_bytes())?;
-        writer.write_all(&self.header_size.to_le_bytes())?;
-        writer.write_all(&self.codec)?;
-        writer.write_all(&self.width.to_le_bytes())?;
-        writer.write_all(&self.height.to_le_bytes())?;
-        writer.write_all(&self.framerate.to_le_bytes())?;
-        writer.write_all(&self.timescale.to_le_bytes())?;
-        writer.write_all(&self.frame_count.to_le_bytes())?;
-        writer.write_all(&self.unused.to_le_bytes())?;
-
-        Ok(())
-    }
-}
-
-/// Helper struct for synthesizing IVF frame header
-pub struct IvfFrameHeader {
-    pub frame_size: u32,
-    pub timestamp: u64,
-}
-
-impl IvfFrameHeader {
-    /// Writes header into writer
-    pub fn writo_into(&self, writer: &mut impl std::io::Write) -> std::io::Result<()> {
-        writer.write_all(&self.frame_size.to_le_bytes())?;
-        writer.write_all(&self.timestamp.to_le_bytes())?;
-        Ok(())
-    }
-}
+pub mod corpus;
+pub mod demux;
+#[cfg(test)]
+pub(crate) mod differential;
+pub mod ivf;
+pub mod latency;
+pub mod mkv;
+pub mod mp4;
+pub mod mpegts;
+pub mod picture_hash;
+pub mod probe;
+pub mod quality;
+pub mod raw;
+pub mod rtp;
+pub(crate) mod snapshot;
+pub mod y4m;
+
+pub use ivf::IvfFileHeader;
+pub use ivf::IvfFrameHeader;
+pub use ivf::IvfIterator;
 
 /// Iterator NALUs in a bitstream.
 pub struct NalIterator<'a, Nalu>(Cursor<&'a [u8]>, PhantomData<Nalu>);
@@ -354,11 +250,44 @@ impl UserPtrFrame {
     }
 }
 
+/// Frame memory backed by DMA-BUF file descriptors, only available on Unix platforms.
+///
+/// Planes can live in separate buffers, as is often the case for frames coming from cameras: the
+/// `buffer_index` of each plane of `layout` is then the index of its file descriptor in `fds`.
+#[cfg(unix)]
 pub struct DmabufFrame {
     pub fds: Vec<OwnedFd>,
     pub layout: FrameLayout,
 }
 
+#[cfg(unix)]
+impl DmabufFrame {
+    /// Maximum number of buffers a frame can be made of.
+    pub const MAX_BUFFERS: usize = 4;
+
+    /// Creates a frame from the buffers `fds`, checking that each plane of `layout` refers to one
+    /// of them.
+    pub fn new(fds: Vec<OwnedFd>, layout: FrameLayout) -> anyhow::Result<Self> {
+        anyhow::ensure!(
+            !fds.is_empty() && fds.len() <= Self::MAX_BUFFERS,
+            "frames must have between 1 and {} buffers, got {}",
+            Self::MAX_BUFFERS,
+            fds.len()
+        );
+        for (i, plane) in layout.planes.iter().enumerate() {
+            anyhow::ensure!(
+                plane.buffer_index < fds.len(),
+                "plane {} is in buffer {}, but the frame only has {} buffers",
+                i,
+                plane.buffer_index,
+                fds.len()
+            );
+        }
+
+        Ok(Self { fds, layout })
+    }
+}
+
 impl Drop for UserPtrFrame {
     fn drop(&mut self) {
         for buffer in std::mem::take(&mut self.buffers).into_iter() {
@@ -0,0 +1,133 @@
+// Copyright 2024 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Access to the bitstreams the tests of this crate are run on, so benchmarks and downstream
+//! test suites can use them as representative corpora.
+//!
+//! The streams are read at runtime from the `test_data` directories of the source tree, so they
+//! are only available when the sources of the crate are.
+
+use std::path::PathBuf;
+
+use anyhow::Context;
+
+use crate::codec::fuzz::FuzzTarget;
+use crate::codec::h264::parser::Nalu as H264Nalu;
+use crate::codec::h265::parser::Nalu as H265Nalu;
+use crate::utils::IvfIterator;
+use crate::utils::NalIterator;
+
+/// A bitstream of the corpus.
+#[derive(Clone, Debug)]
+pub struct CorpusStream {
+    /// File name of the stream.
+    pub name: String,
+    /// The codec the stream is encoded with.
+    pub target: FuzzTarget,
+    /// Content of the file: an Annex B stream for H.264 and H.265, an IVF file otherwise.
+    pub data: Vec<u8>,
+}
+
+impl CorpusStream {
+    /// Splits the stream into the units a parser consumes: NAL units for H.264 and H.265, frames
+    /// otherwise.
+    pub fn units(&self) -> Vec<&[u8]> {
+        match self.target {
+            FuzzTarget::H264 => NalIterator::<H264Nalu>::new(&self.data).collect(),
+            FuzzTarget::H265 => NalIterator::<H265Nalu>::new(&self.data).collect(),
+            FuzzTarget::Vp8 | FuzzTarget::Vp9 | FuzzTarget::Av1 => {
+                IvfIterator::new(&self.data).collect()
+            }
+        }
+    }
+}
+
+/// Returns the directory holding the test data of `target`.
+pub fn test_data_dir(target: FuzzTarget) -> PathBuf {
+    let codec = match target {
+        FuzzTarget::H264 => "h264",
+        FuzzTarget::H265 => "h265",
+        FuzzTarget::Vp8 => "vp8",
+        FuzzTarget::Vp9 => "vp9",
+        FuzzTarget::Av1 => "av1",
+    };
+
+    [
+        env!("CARGO_MANIFEST_DIR"),
+        "src",
+        "codec",
+        codec,
+        "test_data",
+    ]
+    .iter()
+    .collect()
+}
+
+/// Loads the complete streams of the test data of `target`, sorted by name.
+///
+/// Fragments such as single slices or frames, used by the unit tests of the parsers, are left
+/// out.
+pub fn load_corpus(target: FuzzTarget) -> anyhow::Result<Vec<CorpusStream>> {
+    let dir = test_data_dir(target);
+    let entries = std::fs::read_dir(&dir)
+        .with_context(|| format!("cannot read test data directory {}", dir.display()))?;
+
+    let mut streams = Vec::new();
+    for entry in entries {
+        let path = entry?.path();
+        if !path.is_file() {
+            continue;
+        }
+
+        let data = std::fs::read(&path)
+            .with_context(|| format!("cannot read test data {}", path.display()))?;
+
+        let is_stream = match target {
+            FuzzTarget::H264 | FuzzTarget::H265 => {
+                data.starts_with(&[0, 0, 1]) || data.starts_with(&[0, 0, 0, 1])
+            }
+            FuzzTarget::Vp8 | FuzzTarget::Vp9 | FuzzTarget::Av1 => data.starts_with(b"DKIF"),
+        };
+        if !is_stream {
+            continue;
+        }
+
+        streams.push(CorpusStream {
+            name: path
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_default(),
+            target,
+            data,
+        });
+    }
+
+    streams.sort_by(|a, b| a.name.cmp(&b.name));
+
+    Ok(streams)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn load_all_corpora() {
+        for target in FuzzTarget::ALL {
+            let streams = load_corpus(target).unwrap();
+            assert!(!streams.is_empty(), "no stream for {:?}", target);
+
+            for stream in streams {
+                assert!(!stream.units().is_empty(), "{} has no units", stream.name);
+            }
+        }
+    }
+
+    #[test]
+    fn fragments_are_left_out() {
+        let streams = load_corpus(FuzzTarget::H264).unwrap();
+        assert!(streams.iter().any(|s| s.name == "test-25fps.h264"));
+        assert!(streams.iter().all(|s| !s.name.ends_with(".bin")));
+    }
+}
@@ -0,0 +1,437 @@
+// Copyright 2024 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Thin demuxing of the video track of MP4 and Matroska files.
+//!
+//! [`Demuxer`] extracts just enough of a file to feed its first video track
+//! to a decoder: the codec, its configuration and the samples. H.264 and
+//! H.265 samples are converted to Annex B, with the parameter sets of the
+//! decoder configuration record prepended to the first sample, while VP8,
+//! VP9 and AV1 samples are returned as stored.
+//!
+//! Edit lists, composition time offsets, laced Matroska blocks and encrypted
+//! or compressed tracks are not supported.
+
+use anyhow::anyhow;
+use anyhow::Context;
+
+use crate::codec::h264::avcc::AvcDecoderConfigurationRecord;
+use crate::codec::h264::nalu::length_prefixed_to_annexb;
+use crate::codec::h265::hvcc::HevcDecoderConfigurationRecord;
+use crate::utils::mkv;
+use crate::utils::mp4;
+use crate::Resolution;
+
+/// Container formats supported by [`Demuxer`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Container {
+    Mp4,
+    Matroska,
+}
+
+impl Container {
+    /// Detects the container of the file starting with `data`.
+    pub fn detect(data: &[u8]) -> Option<Self> {
+        if data.starts_with(&[0x1a, 0x45, 0xdf, 0xa3]) {
+            return Some(Container::Matroska);
+        }
+
+        match data.get(4..8)? {
+            b"ftyp" | b"styp" | b"moov" => Some(Container::Mp4),
+            _ => None,
+        }
+    }
+}
+
+/// Codec of a demuxed video track.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TrackCodec {
+    H264,
+    H265,
+    Vp8,
+    Vp9,
+    Av1,
+}
+
+/// Position and timing of a sample in the file.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(super) struct SampleRef {
+    pub(super) offset: usize,
+    pub(super) size: usize,
+    pub(super) timestamp: u64,
+    pub(super) keyframe: bool,
+}
+
+/// Video track found by the container parsers.
+pub(super) struct Track {
+    pub(super) codec: TrackCodec,
+    pub(super) resolution: Resolution,
+    /// Number of timestamp units per second.
+    pub(super) timescale: u64,
+    /// `avcC` or `hvcC` record of H.264 and H.265 tracks.
+    pub(super) config: Option<Vec<u8>>,
+    pub(super) samples: Vec<SampleRef>,
+}
+
+/// Bounds-checked reader of big-endian values.
+pub(super) struct ByteReader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> ByteReader<'a> {
+    pub(super) fn new(data: &'a [u8]) -> Self {
+        Self { data }
+    }
+
+    /// Returns the data that has not been read yet.
+    pub(super) fn remaining(&self) -> &'a [u8] {
+        self.data
+    }
+
+    pub(super) fn bytes(&mut self, len: usize) -> anyhow::Result<&'a [u8]> {
+        if self.data.len() < len {
+            return Err(anyhow!(
+                "Reading {} bytes past the {} remaining ones",
+                len,
+                self.data.len()
+            ));
+        }
+
+        let (bytes, rest) = self.data.split_at(len);
+        self.data = rest;
+
+        Ok(bytes)
+    }
+
+    pub(super) fn skip(&mut self, len: usize) -> anyhow::Result<()> {
+        self.bytes(len).map(drop)
+    }
+
+    /// Reads an unsigned integer of `len` bytes, at most 8.
+    pub(super) fn uint(&mut self, len: usize) -> anyhow::Result<u64> {
+        Ok(self
+            .bytes(len)?
+            .iter()
+            .fold(0, |value, &byte| value << 8 | u64::from(byte)))
+    }
+
+    pub(super) fn u8(&mut self) -> anyhow::Result<u8> {
+        Ok(self.uint(1)? as u8)
+    }
+
+    pub(super) fn u16(&mut self) -> anyhow::Result<u16> {
+        Ok(self.uint(2)? as u16)
+    }
+
+    pub(super) fn u32(&mut self) -> anyhow::Result<u32> {
+        Ok(self.uint(4)? as u32)
+    }
+
+    pub(super) fn u64(&mut self) -> anyhow::Result<u64> {
+        self.uint(8)
+    }
+}
+
+/// A sample of the demuxed track, ready to be decoded.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Sample {
+    pub data: Vec<u8>,
+    /// Timestamp of the sample, in units of [`Demuxer::timescale`].
+    pub timestamp: u64,
+    pub keyframe: bool,
+}
+
+/// Demuxer of the first video track of an MP4 or Matroska file.
+///
+/// The whole file is indexed when the demuxer is created, and the samples
+/// are then returned in file order by the [`Iterator`] implementation.
+pub struct Demuxer<'a> {
+    data: &'a [u8],
+    container: Container,
+    codec: TrackCodec,
+    resolution: Resolution,
+    timescale: u64,
+    /// Size of the NAL unit length prefixes of H.264 and H.265 samples.
+    nal_length_size: usize,
+    /// Annex B parameter sets to prepend to the next sample.
+    parameter_sets: Vec<u8>,
+    samples: std::vec::IntoIter<SampleRef>,
+}
+
+impl<'a> Demuxer<'a> {
+    /// Creates a demuxer of the file `data`, whose container is detected
+    /// from its first bytes.
+    pub fn new(data: &'a [u8]) -> anyhow::Result<Self> {
+        let container = Container::detect(data).ok_or(anyhow!("Unrecognized container format"))?;
+
+        Self::with_container(data, container)
+    }
+
+    /// Creates a demuxer of the file `data`, stored in `container`.
+    pub fn with_container(data: &'a [u8], container: Container) -> anyhow::Result<Self> {
+        let track = match container {
+            Container::Mp4 => mp4::demux(data).context("Invalid MP4 file")?,
+            Container::Matroska => mkv::demux(data).context("Invalid Matroska file")?,
+        };
+
+        let (nal_length_size, parameter_sets) = match (track.codec, &track.config) {
+            (TrackCodec::H264, Some(config)) => {
+                let avcc = AvcDecoderConfigurationRecord::parse(config)?;
+                (avcc.nal_length_size(), avcc.to_annexb())
+            }
+            (TrackCodec::H265, Some(config)) => {
+                let hvcc = HevcDecoderConfigurationRecord::parse(config)?;
+                (hvcc.nal_length_size(), hvcc.to_annexb())
+            }
+            (TrackCodec::H264 | TrackCodec::H265, None) => {
+                return Err(anyhow!(
+                    "{:?} track without decoder configuration record",
+                    track.codec
+                ))
+            }
+            _ => (0, vec![]),
+        };
+
+        Ok(Self {
+            data,
+            container,
+            codec: track.codec,
+            resolution: track.resolution,
+            timescale: track.timescale,
+            nal_length_size,
+            parameter_sets,
+            samples: track.samples.into_iter(),
+        })
+    }
+
+    pub fn container(&self) -> Container {
+        self.container
+    }
+
+    pub fn codec(&self) -> TrackCodec {
+        self.codec
+    }
+
+    /// Returns the resolution declared by the container, which may differ
+    /// from the one of the stream.
+    pub fn resolution(&self) -> Resolution {
+        self.resolution
+    }
+
+    /// Returns the number of timestamp units per second.
+    pub fn timescale(&self) -> u64 {
+        self.timescale
+    }
+
+    fn read_sample(&mut self, sample: SampleRef) -> anyhow::Result<Sample> {
+        let data = sample
+            .offset
+            .checked_add(sample.size)
+            .and_then(|end| self.data.get(sample.offset..end))
+            .ok_or(anyhow!(
+                "Sample of {} bytes at offset {} is out of the file",
+                sample.size,
+                sample.offset
+            ))?;
+
+        let data = match self.codec {
+            TrackCodec::H264 | TrackCodec::H265 => {
+                let mut annexb = std::mem::take(&mut self.parameter_sets);
+                annexb.extend(length_prefixed_to_annexb(data, self.nal_length_size)?);
+                annexb
+            }
+            TrackCodec::Vp8 | TrackCodec::Vp9 | TrackCodec::Av1 => data.to_vec(),
+        };
+
+        Ok(Sample {
+            data,
+            timestamp: sample.timestamp,
+            keyframe: sample.keyframe,
+        })
+    }
+}
+
+impl<'a> Iterator for Demuxer<'a> {
+    type Item = anyhow::Result<Sample>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let sample = self.samples.next()?;
+
+        Some(self.read_sample(sample))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec::h264::nalu::split_annexb;
+    use crate::encoder::CodedBitstreamBuffer;
+    use crate::encoder::FrameMetadata;
+    use crate::utils::ivf::IvfReader;
+    use crate::utils::mkv::MkvCodec;
+    use crate::utils::mkv::MkvWriter;
+    use crate::utils::mp4::Fmp4Muxer;
+    use crate::utils::mp4::Mp4Codec;
+    use crate::Fourcc;
+    use crate::FrameLayout;
+
+    const STREAM_VP9: &[u8] = include_bytes!("../codec/vp9/test_data/test-25fps.vp9");
+    const STREAM_H264: &[u8] = include_bytes!("../codec/h264/test_data/64x64-I-P.h264");
+
+    fn buffer(timestamp: u64, bitstream: &[u8]) -> CodedBitstreamBuffer {
+        CodedBitstreamBuffer::new(
+            FrameMetadata {
+                timestamp,
+                display_resolution: Resolution::from((64, 64)),
+                layout: FrameLayout {
+                    format: (Fourcc::from(b"NV12"), 0),
+                    size: Resolution::from((64, 64)),
+                    planes: vec![],
+                },
+                force_keyframe: false,
+                damage: None,
+            },
+            bitstream.to_vec(),
+        )
+    }
+
+    /// Splits the single slice per picture H.264 `stream` into access units.
+    fn h264_access_units(stream: &[u8]) -> Vec<Vec<u8>> {
+        let mut units = vec![];
+        let mut unit = vec![];
+
+        for nalu in split_annexb(stream) {
+            unit.extend_from_slice(&[0, 0, 0, 1]);
+            unit.extend_from_slice(&nalu);
+            if matches!(nalu[0] & 0x1f, 1 | 5) {
+                units.push(std::mem::take(&mut unit));
+            }
+        }
+
+        units
+    }
+
+    /// Checks that demuxing `file` gives back the H.264 access `units`, with
+    /// timestamps multiple of `duration`.
+    fn check_h264(file: &[u8], units: &[Vec<u8>], duration: u64) {
+        let demuxer = Demuxer::new(file).unwrap();
+        assert_eq!(demuxer.codec(), TrackCodec::H264);
+        assert_eq!(demuxer.resolution(), Resolution::from((64, 64)));
+
+        let samples = demuxer.collect::<anyhow::Result<Vec<_>>>().unwrap();
+        assert_eq!(samples.len(), units.len());
+        for (i, (sample, unit)) in samples.iter().zip(units).enumerate() {
+            assert_eq!(sample.timestamp, duration * i as u64);
+            assert_eq!(sample.keyframe, i == 0);
+
+            // The parameter sets are only kept with the first sample, and access unit delimiters
+            // are dropped.
+            let nalus = split_annexb(unit)
+                .into_iter()
+                .filter(|nalu| nalu[0] & 0x1f != 9)
+                .filter(|nalu| i == 0 || !Mp4Codec::H264.is_out_of_band(nalu[0]))
+                .collect::<Vec<_>>();
+            assert_eq!(split_annexb(&sample.data), nalus);
+        }
+    }
+
+    #[test]
+    fn detect() {
+        assert_eq!(
+            Container::detect(&[0x1a, 0x45, 0xdf, 0xa3, 0x9f]),
+            Some(Container::Matroska)
+        );
+        assert_eq!(
+            Container::detect(b"\0\0\0\x18ftypiso6"),
+            Some(Container::Mp4)
+        );
+        assert_eq!(Container::detect(STREAM_H264), None);
+        assert_eq!(Container::detect(&[]), None);
+    }
+
+    #[test]
+    fn demux_fragmented_mp4_h264() {
+        let units = h264_access_units(STREAM_H264);
+        let mut muxer = Fmp4Muxer::new(Mp4Codec::H264, Resolution::from((64, 64)), 90000).unwrap();
+
+        let mut fragments = vec![];
+        for (i, unit) in units.iter().enumerate() {
+            fragments.extend(muxer.push(&buffer(3000 * i as u64, unit)).unwrap());
+        }
+        fragments.extend(muxer.flush().unwrap());
+
+        let mut file = muxer.init_segment().unwrap();
+        fragments.iter().for_each(|f| file.extend_from_slice(f));
+
+        assert_eq!(Demuxer::new(&file).unwrap().timescale(), 90000);
+        check_h264(&file, &units, 3000);
+    }
+
+    #[test]
+    fn demux_mkv_h264() {
+        let units = h264_access_units(STREAM_H264);
+        let mut writer = MkvWriter::new(
+            Vec::new(),
+            MkvCodec::H264,
+            Resolution::from((64, 64)),
+            1_000_000,
+        )
+        .unwrap();
+        for (i, unit) in units.iter().enumerate() {
+            writer.write_frame(&buffer(40 * i as u64, unit)).unwrap();
+        }
+        let file = writer.finish().unwrap();
+
+        assert_eq!(Demuxer::new(&file).unwrap().timescale(), 1000);
+        check_h264(&file, &units, 40);
+    }
+
+    #[test]
+    fn demux_webm_vp9() {
+        let frames = IvfReader::new(STREAM_VP9).unwrap().collect::<Vec<_>>();
+        let mut writer = MkvWriter::new(
+            Vec::new(),
+            MkvCodec::Vp9,
+            Resolution::from((320, 240)),
+            1_000_000,
+        )
+        .unwrap();
+        for frame in &frames {
+            writer
+                .write_frame(&buffer(frame.timestamp, frame.data))
+                .unwrap();
+        }
+        let file = writer.finish().unwrap();
+
+        let demuxer = Demuxer::new(&file).unwrap();
+        assert_eq!(demuxer.container(), Container::Matroska);
+        assert_eq!(demuxer.codec(), TrackCodec::Vp9);
+        assert_eq!(demuxer.resolution(), Resolution::from((320, 240)));
+
+        let samples = demuxer.collect::<anyhow::Result<Vec<_>>>().unwrap();
+        assert_eq!(samples.len(), frames.len());
+        for (sample, frame) in samples.iter().zip(&frames) {
+            assert_eq!(sample.timestamp, frame.timestamp);
+            assert_eq!(sample.data, frame.data);
+        }
+        assert!(samples[0].keyframe);
+        assert!(!samples[1].keyframe);
+    }
+
+    #[test]
+    fn demux_truncated() {
+        let units = h264_access_units(STREAM_H264);
+        let mut writer = MkvWriter::new(
+            Vec::new(),
+            MkvCodec::H264,
+            Resolution::from((64, 64)),
+            1_000_000,
+        )
+        .unwrap();
+        writer.write_frame(&buffer(0, &units[0])).unwrap();
+        let file = writer.finish().unwrap();
+
+        assert!(Demuxer::new(&file[..file.len() - 1]).is_err());
+    }
+}
@@ -0,0 +1,620 @@
+// Copyright 2024 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Differential testing of the parsers of [`crate::codec`] against a reference
+//! implementation.
+//!
+//! [`summarize`] parses a [`CorpusStream`] and extracts the header fields that
+//! matter the most to a decoder into a [`StreamSummary`]: display resolutions,
+//! bit depth, chroma format and the number of shown and key frames. [`compare`]
+//! cross-checks it against the summary produced by a [`ReferenceParser`] and
+//! reports every [`Divergence`], which usually points to a part of the
+//! specification that is interpreted differently by the two parsers.
+//!
+//! [`Ffprobe`] is the reference used by the tests of this module: it runs the
+//! `ffprobe` tool of FFmpeg, and thus the parsers and decoders of libavcodec,
+//! on the stream. It is optional, and the tests are skipped if `ffprobe` is not
+//! installed.
+
+use std::fmt;
+use std::io::Cursor;
+use std::io::Write;
+use std::process::Command;
+use std::process::Stdio;
+
+use anyhow::anyhow;
+use anyhow::Context;
+
+use crate::codec::av1::parser as av1;
+use crate::codec::fuzz::FuzzTarget;
+use crate::codec::h264::parser as h264;
+use crate::codec::h265::parser as h265;
+use crate::codec::vp8::parser as vp8;
+use crate::codec::vp9::parser as vp9;
+use crate::utils::corpus::CorpusStream;
+use crate::Resolution;
+
+/// Key header fields of a stream, as seen by a parser.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct StreamSummary {
+    /// Display resolutions of the shown frames, in order of appearance.
+    pub resolutions: Vec<Resolution>,
+    /// Bit depth of the luma samples.
+    pub bit_depth: u32,
+    /// Chroma format, following the `chroma_format_idc` semantics of H.264 and
+    /// H.265: 0 for monochrome, 1 for 4:2:0, 2 for 4:2:2 and 3 for 4:4:4.
+    pub chroma_format: u32,
+    /// Number of frames that are shown.
+    pub num_frames: usize,
+    /// Number of frames from which decoding can start.
+    pub num_key_frames: usize,
+}
+
+impl StreamSummary {
+    /// Records a shown frame of `resolution`.
+    fn add_frame(&mut self, resolution: Resolution, key_frame: bool) {
+        if self.resolutions.last() != Some(&resolution) {
+            self.resolutions.push(resolution);
+        }
+        self.num_frames += 1;
+        self.num_key_frames += usize::from(key_frame);
+    }
+}
+
+/// A field of a stream on which a parser and its reference disagree.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Divergence {
+    pub field: &'static str,
+    /// Value found by the parser of this crate.
+    pub parsed: String,
+    /// Value found by the reference parser.
+    pub reference: String,
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}: parsed {}, reference {}",
+            self.field, self.parsed, self.reference
+        )
+    }
+}
+
+/// A parser whose interpretation of streams is trusted.
+pub trait ReferenceParser {
+    /// Returns the summary of `stream`.
+    fn summarize(&self, stream: &CorpusStream) -> anyhow::Result<StreamSummary>;
+}
+
+/// Parses `stream` with the parser of its codec and returns its summary.
+pub fn summarize(stream: &CorpusStream) -> anyhow::Result<StreamSummary> {
+    match stream.target {
+        FuzzTarget::H264 => summarize_h264(&stream.data),
+        FuzzTarget::H265 => summarize_h265(&stream.data),
+        FuzzTarget::Vp8 => summarize_vp8(&stream.units()),
+        FuzzTarget::Vp9 => summarize_vp9(&stream.units()),
+        FuzzTarget::Av1 => summarize_av1(&stream.units()),
+    }
+    .with_context(|| format!("while parsing {}", stream.name))
+}
+
+/// Parses `stream` and returns the fields on which the result differs from
+/// the one of `reference`.
+pub fn compare(
+    stream: &CorpusStream,
+    reference: &dyn ReferenceParser,
+) -> anyhow::Result<Vec<Divergence>> {
+    let parsed = summarize(stream)?;
+    let reference = reference.summarize(stream)?;
+    let mut divergences = vec![];
+
+    let mut check = |field, parsed: &dyn fmt::Debug, reference: &dyn fmt::Debug| {
+        let (parsed, reference) = (format!("{:?}", parsed), format!("{:?}", reference));
+        if parsed != reference {
+            divergences.push(Divergence {
+                field,
+                parsed,
+                reference,
+            });
+        }
+    };
+    check("resolutions", &parsed.resolutions, &reference.resolutions);
+    check("bit_depth", &parsed.bit_depth, &reference.bit_depth);
+    check(
+        "chroma_format",
+        &parsed.chroma_format,
+        &reference.chroma_format,
+    );
+    check("num_frames", &parsed.num_frames, &reference.num_frames);
+    check(
+        "num_key_frames",
+        &parsed.num_key_frames,
+        &reference.num_key_frames,
+    );
+
+    Ok(divergences)
+}
+
+fn summarize_h264(data: &[u8]) -> anyhow::Result<StreamSummary> {
+    let mut parser = h264::Parser::default();
+    let mut cursor = Cursor::new(data);
+    let mut summary = StreamSummary::default();
+    // Frame number and parity of the last field, if it has not been paired yet.
+    let mut unpaired_field = None;
+
+    while let Ok(nalu) = h264::Nalu::next(&mut cursor) {
+        match nalu.header.type_ {
+            h264::NaluType::Sps => {
+                parser.parse_sps(&nalu)?;
+            }
+            h264::NaluType::Pps => {
+                parser.parse_pps(&nalu)?;
+            }
+            h264::NaluType::Slice | h264::NaluType::SliceIdr => {
+                let key_frame = nalu.header.type_ == h264::NaluType::SliceIdr;
+                let header = parser.parse_slice_header(nalu)?.header;
+                if header.first_mb_in_slice != 0 {
+                    continue;
+                }
+
+                // The two fields of a frame are output as a single frame.
+                if header.field_pic_flag {
+                    let field = (header.frame_num, header.bottom_field_flag);
+                    if unpaired_field == Some((field.0, !field.1)) {
+                        unpaired_field = None;
+                        continue;
+                    }
+                    unpaired_field = Some(field);
+                } else {
+                    unpaired_field = None;
+                }
+
+                let sps = &parser
+                    .get_pps(header.pic_parameter_set_id)
+                    .ok_or(anyhow!("missing PPS {}", header.pic_parameter_set_id))?
+                    .sps;
+                let visible = sps.visible_rectangle();
+                summary.bit_depth = u32::from(sps.bit_depth_luma_minus8) + 8;
+                summary.chroma_format = u32::from(sps.chroma_format_idc);
+                summary.add_frame(
+                    Resolution::from((
+                        visible.max.x - visible.min.x,
+                        visible.max.y - visible.min.y,
+                    )),
+                    key_frame,
+                );
+            }
+            _ => (),
+        }
+    }
+
+    Ok(summary)
+}
+
+fn summarize_h265(data: &[u8]) -> anyhow::Result<StreamSummary> {
+    let mut parser = h265::Parser::default();
+    let mut cursor = Cursor::new(data);
+    let mut summary = StreamSummary::default();
+    // NoRaslOutputFlag of the last IRAP picture: when set, its RASL pictures
+    // are not output.
+    let mut no_rasl_output = true;
+
+    while let Ok(nalu) = h265::Nalu::next(&mut cursor) {
+        let type_ = nalu.header.type_;
+        match type_ {
+            h265::NaluType::VpsNut => {
+                parser.parse_vps(&nalu)?;
+            }
+            h265::NaluType::SpsNut => {
+                parser.parse_sps(&nalu)?;
+            }
+            h265::NaluType::PpsNut => {
+                parser.parse_pps(&nalu)?;
+            }
+            _ if type_ as u32 <= h265::NaluType::CraNut as u32 => {
+                let header = parser.parse_slice_header(nalu)?.header;
+                if !header.first_slice_segment_in_pic_flag {
+                    continue;
+                }
+
+                if type_.is_irap() {
+                    no_rasl_output = type_.is_idr() || type_.is_bla() || summary.num_frames == 0;
+                } else if no_rasl_output
+                    && matches!(type_, h265::NaluType::RaslN | h265::NaluType::RaslR)
+                {
+                    continue;
+                }
+
+                let pps = parser
+                    .get_pps(header.pic_parameter_set_id)
+                    .ok_or(anyhow!("missing PPS {}", header.pic_parameter_set_id))?;
+                let sps = parser
+                    .get_sps(pps.seq_parameter_set_id)
+                    .ok_or(anyhow!("missing SPS {}", pps.seq_parameter_set_id))?;
+                let visible = sps.visible_rectangle();
+                summary.bit_depth = u32::from(sps.bit_depth_luma_minus8) + 8;
+                summary.chroma_format = u32::from(sps.chroma_format_idc);
+                summary.add_frame(
+                    Resolution::from((
+                        visible.max.x - visible.min.x,
+                        visible.max.y - visible.min.y,
+                    )),
+                    type_.is_irap(),
+                );
+            }
+            _ => (),
+        }
+    }
+
+    Ok(summary)
+}
+
+fn summarize_vp8(frames: &[&[u8]]) -> anyhow::Result<StreamSummary> {
+    let mut parser = vp8::Parser::default();
+    let mut summary = StreamSummary {
+        bit_depth: 8,
+        chroma_format: 1,
+        ..Default::default()
+    };
+    let mut resolution = Resolution::default();
+
+    for frame in frames {
+        let header = parser.parse_frame(frame)?.header;
+        if header.key_frame {
+            resolution = Resolution::from((u32::from(header.width), u32::from(header.height)));
+        }
+        if header.show_frame {
+            summary.add_frame(resolution, header.key_frame);
+        }
+    }
+
+    Ok(summary)
+}
+
+fn summarize_vp9(chunks: &[&[u8]]) -> anyhow::Result<StreamSummary> {
+    let mut parser = vp9::Parser::default();
+    let mut summary = StreamSummary::default();
+    let mut resolution = Resolution::default();
+
+    for chunk in chunks {
+        for frame in parser.parse_chunk(chunk)? {
+            let header = frame.header;
+            if header.show_existing_frame {
+                summary.add_frame(resolution, false);
+                continue;
+            }
+
+            if header.frame_type == vp9::FrameType::KeyFrame {
+                summary.bit_depth = header.bit_depth as u32;
+                summary.chroma_format = match (header.subsampling_x, header.subsampling_y) {
+                    (true, true) => 1,
+                    (true, false) => 2,
+                    _ => 3,
+                };
+            }
+            resolution = Resolution::from((header.width, header.height));
+            if header.show_frame {
+                summary.add_frame(resolution, header.frame_type == vp9::FrameType::KeyFrame);
+            }
+        }
+    }
+
+    Ok(summary)
+}
+
+fn summarize_av1(temporal_units: &[&[u8]]) -> anyhow::Result<StreamSummary> {
+    let mut parser = av1::Parser::default();
+    let mut summary = StreamSummary::default();
+    let mut resolution = Resolution::default();
+
+    for data in temporal_units {
+        let mut consumed = 0;
+
+        while consumed < data.len() {
+            let obu = match parser.parse_obu(&data[consumed..])? {
+                av1::ParsedObu::Process(obu) => obu,
+                av1::ParsedObu::Drop(length) => {
+                    consumed += std::cmp::max(length as usize, 1);
+                    continue;
+                }
+            };
+            consumed += std::cmp::max(obu.data.len(), 1);
+
+            let header = match obu.header.obu_type {
+                av1::ObuType::SequenceHeader => {
+                    let sequence = parser.parse_sequence_header_obu(&obu)?;
+                    summary.bit_depth = match sequence.bit_depth {
+                        av1::BitDepth::Depth8 => 8,
+                        av1::BitDepth::Depth10 => 10,
+                        av1::BitDepth::Depth12 => 12,
+                    };
+                    let color_config = &sequence.color_config;
+                    summary.chroma_format = match (
+                        color_config.mono_chrome,
+                        color_config.subsampling_x,
+                        color_config.subsampling_y,
+                    ) {
+                        (true, _, _) => 0,
+                        (false, true, true) => 1,
+                        (false, true, false) => 2,
+                        _ => 3,
+                    };
+                    continue;
+                }
+                av1::ObuType::TemporalDelimiter => {
+                    parser.parse_temporal_delimiter_obu(&obu)?;
+                    continue;
+                }
+                av1::ObuType::FrameHeader => parser.parse_frame_header_obu(&obu)?,
+                av1::ObuType::Frame => parser.parse_frame_obu(obu)?.header,
+                av1::ObuType::TileGroup => {
+                    parser.parse_tile_group_obu(obu)?;
+                    continue;
+                }
+                _ => continue,
+            };
+            parser.ref_frame_update(&header)?;
+
+            if header.show_existing_frame {
+                summary.add_frame(resolution, false);
+                continue;
+            }
+
+            resolution = Resolution::from((header.upscaled_width, header.frame_height));
+            if header.show_frame {
+                summary.add_frame(resolution, header.frame_type == av1::FrameType::KeyFrame);
+            }
+        }
+    }
+
+    Ok(summary)
+}
+
+/// Reference parser running the `ffprobe` tool of FFmpeg.
+pub struct Ffprobe;
+
+impl Ffprobe {
+    /// Returns the reference parser if `ffprobe` can be run.
+    pub fn find() -> Option<Self> {
+        Command::new("ffprobe")
+            .arg("-version")
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()
+            .is_ok_and(|status| status.success())
+            .then_some(Ffprobe)
+    }
+
+    /// Returns the bit depth and chroma format of the `pix_fmt` name of
+    /// FFmpeg, e.g. `yuv420p10le`.
+    fn parse_pix_fmt(pix_fmt: &str) -> anyhow::Result<(u32, u32)> {
+        let (chroma_format, depth) = if let Some(depth) = pix_fmt.strip_prefix("gray") {
+            (0, depth)
+        } else {
+            let pix_fmt = pix_fmt
+                .strip_prefix("yuvj")
+                .or_else(|| pix_fmt.strip_prefix("yuv"))
+                .ok_or(anyhow!("unsupported pixel format {}", pix_fmt))?;
+            match pix_fmt.split_at(std::cmp::min(pix_fmt.len(), 4)) {
+                ("420p", depth) => (1, depth),
+                ("422p", depth) => (2, depth),
+                ("444p", depth) => (3, depth),
+                _ => return Err(anyhow!("unsupported pixel format {}", pix_fmt)),
+            }
+        };
+
+        let depth = depth.trim_end_matches("le").trim_end_matches("be");
+        let bit_depth = if depth.is_empty() { 8 } else { depth.parse()? };
+
+        Ok((bit_depth, chroma_format))
+    }
+
+    /// Builds the summary of a stream from the output of `ffprobe` in compact
+    /// format.
+    fn parse_output(output: &str) -> anyhow::Result<StreamSummary> {
+        let mut summary = StreamSummary::default();
+
+        for line in output.lines() {
+            let section = line.split('|').next();
+            let value = |key: &str| {
+                line.split('|')
+                    .find_map(|field| field.strip_prefix(key)?.strip_prefix('='))
+                    .ok_or(anyhow!("no {} in ffprobe output {}", key, line))
+            };
+
+            match section {
+                Some("stream") => {
+                    (summary.bit_depth, summary.chroma_format) =
+                        Self::parse_pix_fmt(value("pix_fmt")?)?;
+                }
+                Some("frame") => {
+                    let resolution =
+                        Resolution::from((value("width")?.parse()?, value("height")?.parse()?));
+                    summary.add_frame(resolution, value("key_frame")? == "1");
+                }
+                _ => (),
+            }
+        }
+
+        Ok(summary)
+    }
+}
+
+impl ReferenceParser for Ffprobe {
+    fn summarize(&self, stream: &CorpusStream) -> anyhow::Result<StreamSummary> {
+        let mut child = Command::new("ffprobe")
+            .args([
+                "-v",
+                "error",
+                "-select_streams",
+                "v:0",
+                "-show_entries",
+                "stream=pix_fmt:frame=width,height,key_frame",
+                "-of",
+                "compact",
+                "pipe:0",
+            ])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .context("while running ffprobe")?;
+
+        // Write the input from a separate thread, so ffprobe cannot block on
+        // a full output pipe while we are still writing.
+        let mut stdin = child.stdin.take().ok_or(anyhow!("no ffprobe stdin"))?;
+        let data = stream.data.clone();
+        let writer = std::thread::spawn(move || stdin.write_all(&data));
+
+        let output = child.wait_with_output()?;
+        // ffprobe may exit before reading all its input, so write errors are
+        // only relevant if it failed.
+        let written = writer
+            .join()
+            .map_err(|_| anyhow!("ffprobe input thread panicked"))?;
+        if !output.status.success() {
+            written?;
+            return Err(anyhow!(
+                "ffprobe failed on {}: {}",
+                stream.name,
+                String::from_utf8_lossy(&output.stderr)
+            ));
+        }
+
+        Self::parse_output(&String::from_utf8_lossy(&output.stdout))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::corpus::load_corpus;
+    use crate::utils::corpus::test_data_dir;
+
+    /// Reference returning a fixed summary.
+    struct Fixed(StreamSummary);
+
+    impl ReferenceParser for Fixed {
+        fn summarize(&self, _: &CorpusStream) -> anyhow::Result<StreamSummary> {
+            Ok(self.0.clone())
+        }
+    }
+
+    fn corpus_stream(target: FuzzTarget, name: &str) -> CorpusStream {
+        load_corpus(target)
+            .unwrap()
+            .into_iter()
+            .find(|stream| stream.name == name)
+            .unwrap()
+    }
+
+    #[test]
+    fn summarize_streams() {
+        for (target, name, resolution, num_frames) in [
+            (FuzzTarget::H264, "test-25fps.h264", (320, 240), 250),
+            (FuzzTarget::H265, "test-25fps.h265", (320, 240), 250),
+            (FuzzTarget::Vp8, "test-25fps.vp8", (320, 240), 250),
+            (FuzzTarget::Vp9, "test-25fps.vp9", (320, 240), 250),
+            (FuzzTarget::Av1, "test-25fps.ivf.av1", (320, 240), 250),
+        ] {
+            let summary = summarize(&corpus_stream(target, name)).unwrap();
+
+            assert_eq!(
+                summary.resolutions,
+                vec![Resolution::from(resolution)],
+                "{}",
+                name
+            );
+            assert_eq!(summary.bit_depth, 8, "{}", name);
+            assert_eq!(summary.chroma_format, 1, "{}", name);
+            assert_eq!(summary.num_frames, num_frames, "{}", name);
+            assert!(summary.num_key_frames >= 1, "{}", name);
+        }
+    }
+
+    #[test]
+    fn summarize_corpus() {
+        for target in FuzzTarget::ALL {
+            for stream in load_corpus(target).unwrap() {
+                let summary = summarize(&stream).unwrap();
+                assert_ne!(summary.num_frames, 0, "{}", stream.name);
+
+                // The CRCs of the test data are generated by FFmpeg, one per
+                // output frame, unless FFmpeg cannot decode the stream.
+                let crcs = test_data_dir(target).join(format!("{}.crc", stream.name));
+                let crcs = std::fs::read_to_string(crcs).unwrap_or_default();
+                if !crcs.is_empty() {
+                    assert_eq!(summary.num_frames, crcs.lines().count(), "{}", stream.name);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn report_divergences() {
+        let stream = corpus_stream(FuzzTarget::Vp9, "test-25fps.vp9");
+        let mut reference = summarize(&stream).unwrap();
+        assert_eq!(compare(&stream, &Fixed(reference.clone())).unwrap(), vec![]);
+
+        reference.bit_depth = 10;
+        reference.num_frames += 1;
+        let divergences = compare(&stream, &Fixed(reference)).unwrap();
+        assert_eq!(
+            divergences
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>(),
+            vec![
+                "bit_depth: parsed 8, reference 10".to_string(),
+                "num_frames: parsed 250, reference 251".to_string(),
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_ffprobe_output() {
+        let output = "\
+            stream|pix_fmt=yuv420p10le\n\
+            frame|key_frame=1|width=64|height=64\n\
+            frame|key_frame=0|width=64|height=64\n\
+            frame|key_frame=1|width=32|height=48\n";
+
+        assert_eq!(
+            Ffprobe::parse_output(output).unwrap(),
+            StreamSummary {
+                resolutions: vec![Resolution::from((64, 64)), Resolution::from((32, 48))],
+                bit_depth: 10,
+                chroma_format: 1,
+                num_frames: 3,
+                num_key_frames: 2,
+            }
+        );
+
+        assert_eq!(Ffprobe::parse_pix_fmt("gray").unwrap(), (8, 0));
+        assert_eq!(Ffprobe::parse_pix_fmt("yuvj422p").unwrap(), (8, 2));
+        assert_eq!(Ffprobe::parse_pix_fmt("yuv444p12be").unwrap(), (12, 3));
+        assert!(Ffprobe::parse_pix_fmt("rgb24").is_err());
+    }
+
+    /// Cross-checks the parsers against FFmpeg on the whole corpus, if
+    /// `ffprobe` is available.
+    #[test]
+    fn differential_ffprobe() {
+        let Some(ffprobe) = Ffprobe::find() else {
+            return;
+        };
+
+        let mut report = vec![];
+        for target in FuzzTarget::ALL {
+            for stream in load_corpus(target).unwrap() {
+                for divergence in compare(&stream, &ffprobe).unwrap() {
+                    report.push(format!("{}: {}", stream.name, divergence));
+                }
+            }
+        }
+
+        assert!(report.is_empty(), "{}", report.join("\n"));
+    }
+}
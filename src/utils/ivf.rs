@@ -0,0 +1,373 @@
+// Copyright 2024 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Reading and writing of IVF files, the simple container used for VP8, VP9
+//! and AV1 streams.
+//!
+//! An IVF file is a 32 bytes file header followed by frames, each preceded by
+//! a 12 bytes frame header giving its size and timestamp. All values are
+//! little-endian.
+
+use std::io::Cursor;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::io::Write;
+
+use anyhow::anyhow;
+use bytes::Buf;
+
+/// Iterator over IVF packets.
+pub struct IvfIterator<'a> {
+    cursor: Cursor<&'a [u8]>,
+}
+
+impl<'a> IvfIterator<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        let mut cursor = Cursor::new(data);
+
+        // Skip the IVH header entirely.
+        cursor.seek(std::io::SeekFrom::Start(32)).unwrap();
+
+        Self { cursor }
+    }
+}
+
+impl<'a> Iterator for IvfIterator<'a> {
+    type Item = &'a [u8];
+
+    fn next(&mut self) -> Option<Self::Item> {
+        next_frame(&mut self.cursor).map(|frame| frame.data)
+    }
+}
+
+/// Reads the frame at the current position of `cursor`, or returns `None` if
+/// there is no complete frame left.
+fn next_frame<'a>(cursor: &mut Cursor<&'a [u8]>) -> Option<IvfFrame<'a>> {
+    // Make sure we have a header.
+    if cursor.remaining() < IvfFrameHeader::SIZE {
+        return None;
+    }
+
+    let len = cursor.get_u32_le() as usize;
+    let timestamp = cursor.get_u64_le();
+
+    if cursor.remaining() < len {
+        return None;
+    }
+
+    let start = cursor.position() as usize;
+    cursor.advance(len);
+
+    Some(IvfFrame {
+        timestamp,
+        data: &cursor.get_ref()[start..start + len],
+    })
+}
+
+fn read_fourcc(cursor: &mut Cursor<&[u8]>) -> [u8; 4] {
+    let mut value = [0u8; 4];
+    cursor.copy_to_slice(&mut value);
+    value
+}
+
+/// Helper struct for synthesizing IVF file header
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct IvfFileHeader {
+    pub magic: [u8; 4],
+    pub version: u16,
+    pub header_size: u16,
+    pub codec: [u8; 4],
+    pub width: u16,
+    pub height: u16,
+    pub framerate: u32,
+    pub timescale: u32,
+    pub frame_count: u32,
+    pub unused: u32,
+}
+
+impl Default for IvfFileHeader {
+    fn default() -> Self {
+        Self {
+            magic: Self::MAGIC,
+            version: 0,
+            header_size: 32,
+            codec: Self::CODEC_VP9,
+            width: 320,
+            height: 240,
+            framerate: 1,
+            timescale: 1000,
+            frame_count: 1,
+            unused: Default::default(),
+        }
+    }
+}
+
+impl IvfFileHeader {
+    pub const MAGIC: [u8; 4] = *b"DKIF";
+    pub const CODEC_VP8: [u8; 4] = *b"VP80";
+    pub const CODEC_VP9: [u8; 4] = *b"VP90";
+    pub const CODEC_AV1: [u8; 4] = *b"AV01";
+
+    /// Size of the file header, and minimum value of `header_size`.
+    pub const SIZE: usize = 32;
+
+    /// Offset of `frame_count` in the file header.
+    const FRAME_COUNT_OFFSET: u64 = 24;
+
+    pub fn new(codec: [u8; 4], width: u16, height: u16, framerate: u32, frame_count: u32) -> Self {
+        let default = Self::default();
+
+        Self {
+            codec,
+            width,
+            height,
+            framerate: framerate * default.timescale,
+            frame_count,
+            ..default
+        }
+    }
+
+    /// Parses the file header at the start of `data`.
+    pub fn parse(data: &[u8]) -> anyhow::Result<Self> {
+        if data.len() < Self::SIZE {
+            return Err(anyhow!("IVF file header is truncated"));
+        }
+
+        let mut cursor = Cursor::new(data);
+        let magic = read_fourcc(&mut cursor);
+        if magic != Self::MAGIC {
+            return Err(anyhow!("Invalid IVF signature {:?}", magic));
+        }
+
+        let header = Self {
+            magic,
+            version: cursor.get_u16_le(),
+            header_size: cursor.get_u16_le(),
+            codec: read_fourcc(&mut cursor),
+            width: cursor.get_u16_le(),
+            height: cursor.get_u16_le(),
+            framerate: cursor.get_u32_le(),
+            timescale: cursor.get_u32_le(),
+            frame_count: cursor.get_u32_le(),
+            unused: cursor.get_u32_le(),
+        };
+
+        if usize::from(header.header_size) < Self::SIZE {
+            return Err(anyhow!("Invalid IVF header size {}", header.header_size));
+        }
+
+        Ok(header)
+    }
+
+    /// Writes header into writer
+    pub fn writo_into(&self, writer: &mut impl std::io::Write) -> std::io::Result<()> {
+        writer.write_all(&self.magic)?;
+        writer.write_all(&self.version.to_le_bytes())?;
+        writer.write_all(&self.header_size.to_le_bytes())?;
+        writer.write_all(&self.codec)?;
+        writer.write_all(&self.width.to_le_bytes())?;
+        writer.write_all(&self.height.to_le_bytes())?;
+        writer.write_all(&self.framerate.to_le_bytes())?;
+        writer.write_all(&self.timescale.to_le_bytes())?;
+        writer.write_all(&self.frame_count.to_le_bytes())?;
+        writer.write_all(&self.unused.to_le_bytes())?;
+
+        Ok(())
+    }
+}
+
+/// Helper struct for synthesizing IVF frame header
+pub struct IvfFrameHeader {
+    pub frame_size: u32,
+    pub timestamp: u64,
+}
+
+impl IvfFrameHeader {
+    /// Size of the frame header.
+    pub const SIZE: usize = 12;
+
+    /// Writes header into writer
+    pub fn writo_into(&self, writer: &mut impl std::io::Write) -> std::io::Result<()> {
+        writer.write_all(&self.frame_size.to_le_bytes())?;
+        writer.write_all(&self.timestamp.to_le_bytes())?;
+        Ok(())
+    }
+}
+
+/// A frame read from an IVF file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct IvfFrame<'a> {
+    /// Presentation timestamp of the frame, in units of
+    /// `timescale / framerate` seconds of the file header.
+    pub timestamp: u64,
+    /// The frame data, e.g. a VP9 superframe or an AV1 temporal unit.
+    pub data: &'a [u8],
+}
+
+/// Reader of IVF files, yielding their frames along with their timestamps.
+///
+/// Unlike [`IvfIterator`], the file header is parsed and validated, and
+/// header sizes larger than 32 bytes are honored. Iteration stops at the
+/// first truncated frame.
+pub struct IvfReader<'a> {
+    header: IvfFileHeader,
+    cursor: Cursor<&'a [u8]>,
+}
+
+impl<'a> IvfReader<'a> {
+    pub fn new(data: &'a [u8]) -> anyhow::Result<Self> {
+        let header = IvfFileHeader::parse(data)?;
+
+        let mut cursor = Cursor::new(data);
+        cursor.set_position(u64::from(header.header_size));
+
+        Ok(Self { header, cursor })
+    }
+
+    /// Returns the file header.
+    pub fn header(&self) -> &IvfFileHeader {
+        &self.header
+    }
+}
+
+impl<'a> Iterator for IvfReader<'a> {
+    type Item = IvfFrame<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        next_frame(&mut self.cursor)
+    }
+}
+
+/// Writer of IVF files, e.g. for the output of an encoder.
+pub struct IvfWriter<W: Write> {
+    writer: W,
+    frame_count: u32,
+}
+
+impl<W: Write> IvfWriter<W> {
+    /// Creates a new writer, and writes `header` into `writer`. The
+    /// `frame_count` of `header` can be updated once all frames are written
+    /// using [`IvfWriter::finish`].
+    pub fn new(mut writer: W, header: &IvfFileHeader) -> std::io::Result<Self> {
+        header.writo_into(&mut writer)?;
+
+        // Pad larger headers.
+        let padding = usize::from(header.header_size).saturating_sub(IvfFileHeader::SIZE);
+        writer.write_all(&vec![0u8; padding])?;
+
+        Ok(Self {
+            writer,
+            frame_count: 0,
+        })
+    }
+
+    /// Writes a frame with its presentation timestamp.
+    pub fn write_frame(&mut self, data: &[u8], timestamp: u64) -> std::io::Result<()> {
+        let frame_size = u32::try_from(data.len()).map_err(|_| {
+            std::io::Error::new(std::io::ErrorKind::InvalidInput, "frame too large for IVF")
+        })?;
+
+        IvfFrameHeader {
+            frame_size,
+            timestamp,
+        }
+        .writo_into(&mut self.writer)?;
+        self.writer.write_all(data)?;
+        self.frame_count = self.frame_count.saturating_add(1);
+
+        Ok(())
+    }
+
+    /// Returns the number of frames written so far.
+    pub fn frame_count(&self) -> u32 {
+        self.frame_count
+    }
+
+    /// Returns the underlying writer, leaving the frame count of the file
+    /// header untouched.
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+impl<W: Write + Seek> IvfWriter<W> {
+    /// Sets the frame count of the file header to the number of frames
+    /// written, and returns the underlying writer positioned at the end of
+    /// the file.
+    pub fn finish(mut self) -> std::io::Result<W> {
+        let end = self.writer.stream_position()?;
+
+        self.writer
+            .seek(SeekFrom::Start(IvfFileHeader::FRAME_COUNT_OFFSET))?;
+        self.writer.write_all(&self.frame_count.to_le_bytes())?;
+        self.writer.seek(SeekFrom::Start(end))?;
+        self.writer.flush()?;
+
+        Ok(self.writer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STREAM_VP9: &[u8] = include_bytes!("../codec/vp9/test_data/test-25fps.vp9");
+
+    #[test]
+    fn read_stream() {
+        let reader = IvfReader::new(STREAM_VP9).unwrap();
+        let header = reader.header().clone();
+        assert_eq!(header.codec, IvfFileHeader::CODEC_VP9);
+        assert_eq!((header.width, header.height), (320, 240));
+        assert_eq!(header.frame_count, 250);
+
+        let frames = reader.collect::<Vec<_>>();
+        assert_eq!(frames.len(), 250);
+        assert!(frames
+            .iter()
+            .zip(IvfIterator::new(STREAM_VP9))
+            .all(|(frame, packet)| frame.data == packet));
+        assert!(frames
+            .iter()
+            .enumerate()
+            .all(|(i, frame)| frame.timestamp == 40 * i as u64));
+
+        // Truncated frames are not returned.
+        let truncated = &STREAM_VP9[..STREAM_VP9.len() - 1];
+        assert_eq!(IvfReader::new(truncated).unwrap().count(), 249);
+        assert_eq!(IvfIterator::new(&truncated[..40]).count(), 0);
+
+        assert!(IvfReader::new(&STREAM_VP9[..31]).is_err());
+        assert!(IvfReader::new(&STREAM_VP9[1..]).is_err());
+    }
+
+    #[test]
+    fn write_and_read() {
+        let header = IvfFileHeader::new(IvfFileHeader::CODEC_AV1, 64, 48, 30, 0);
+        let frames: [&[u8]; 3] = [&[1, 2, 3], &[], &[0xff; 300]];
+
+        let mut writer = IvfWriter::new(Cursor::new(Vec::new()), &header).unwrap();
+        for (i, frame) in frames.iter().enumerate() {
+            writer.write_frame(frame, 1000 * i as u64).unwrap();
+        }
+        assert_eq!(writer.frame_count(), 3);
+        let data = writer.finish().unwrap().into_inner();
+
+        let reader = IvfReader::new(&data).unwrap();
+        assert_eq!(
+            reader.header(),
+            &IvfFileHeader {
+                frame_count: 3,
+                ..header
+            }
+        );
+
+        let read = reader.collect::<Vec<_>>();
+        assert_eq!(read.len(), frames.len());
+        for (i, (frame, data)) in read.iter().zip(frames).enumerate() {
+            assert_eq!(frame.data, data);
+            assert_eq!(frame.timestamp, 1000 * i as u64);
+        }
+    }
+}
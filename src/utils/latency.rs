@@ -0,0 +1,143 @@
+// Copyright 2024 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! End-to-end latency measurement, with the capture time of the frames carried in the stream.
+//!
+//! When [`EncoderConfig::capture_time`] is set, the H.264 encoder embeds the wall clock time at
+//! which each frame was passed to it in a user data unregistered SEI message identified by
+//! [`H264_USER_DATA_UUID`]. Receivers get it back from the decoder with
+//! [`StatelessDecoder::take_capture_time`], or from the coded access units with [`capture_time`]
+//! without decoding them, and compare it to the time the frame is displayed with [`latency`].
+//!
+//! The clocks of the sender and the receiver must be synchronized, e.g. with NTP or PTP, for the
+//! measurements to be meaningful across machines. Decoders ignore the message, so the stream stays
+//! playable by any player.
+//!
+//! [`EncoderConfig::capture_time`]: crate::encoder::stateless::h264::EncoderConfig::capture_time
+//! [`StatelessDecoder::take_capture_time`]: crate::decoder::stateless::StatelessDecoder::take_capture_time
+
+use std::io::Cursor;
+use std::time::Duration;
+use std::time::SystemTime;
+
+use anyhow::anyhow;
+
+use crate::codec::h264::parser::Nalu;
+use crate::codec::h264::parser::NaluType;
+use crate::codec::h264::parser::Parser;
+use crate::codec::h264::parser::SeiMessage;
+
+/// UUID of the user data unregistered SEI messages carrying a capture time in H.264 streams.
+pub const H264_USER_DATA_UUID: [u8; 16] = [
+    0x6a, 0x0e, 0x31, 0xd4, 0x52, 0x7b, 0x4f, 0x8a, 0x9c, 0x15, 0xe3, 0x48, 0x0b, 0xd2, 0x76, 0xc1,
+];
+
+/// Returns the payload of the SEI message carrying `capture_time`, as the number of microseconds
+/// since the UNIX epoch on 64 big-endian bits.
+pub fn payload(capture_time: SystemTime) -> Vec<u8> {
+    let micros = capture_time
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_micros();
+
+    u64::try_from(micros)
+        .unwrap_or(u64::MAX)
+        .to_be_bytes()
+        .to_vec()
+}
+
+/// Parses the payload of an SEI message carrying a capture time.
+pub fn from_payload(payload: &[u8]) -> anyhow::Result<SystemTime> {
+    let micros: [u8; 8] = payload
+        .try_into()
+        .map_err(|_| anyhow!("invalid capture time size {}", payload.len()))?;
+
+    SystemTime::UNIX_EPOCH
+        .checked_add(Duration::from_micros(u64::from_be_bytes(micros)))
+        .ok_or_else(|| anyhow!("capture time out of range"))
+}
+
+/// Returns the capture time carried by the H.264 Annex B `access_unit`, if any.
+pub fn capture_time(access_unit: &[u8]) -> Option<SystemTime> {
+    let mut cursor = Cursor::new(access_unit);
+    // The SPS is needed by the SEI messages related to the HRD, which may precede ours.
+    let mut parser = Parser::default();
+
+    while let Ok(nalu) = Nalu::next(&mut cursor) {
+        match nalu.header.type_ {
+            NaluType::Sps => {
+                let _ = parser.parse_sps(&nalu);
+            }
+            NaluType::Sei => {
+                let Ok(sei) = parser.parse_sei(&nalu) else {
+                    continue;
+                };
+                let capture_time = sei.messages.iter().find_map(|message| match message {
+                    SeiMessage::UserDataUnregistered(user_data)
+                        if user_data.uuid_iso_iec_11578 == H264_USER_DATA_UUID =>
+                    {
+                        from_payload(&user_data.payload).ok()
+                    }
+                    _ => None,
+                });
+                if capture_time.is_some() {
+                    return capture_time;
+                }
+            }
+            _ => (),
+        }
+    }
+
+    None
+}
+
+/// Returns the time elapsed between `capture_time` and `now`, zero if the clocks went backwards.
+pub fn latency(capture_time: SystemTime, now: SystemTime) -> Duration {
+    now.duration_since(capture_time).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec::h264::parser::Sei;
+    use crate::codec::h264::parser::Sps;
+    use crate::codec::h264::parser::UserDataUnregistered;
+    use crate::codec::h264::synthesizer::Synthesizer;
+
+    #[test]
+    fn payload_round_trip() {
+        let capture_time = SystemTime::UNIX_EPOCH + Duration::from_micros(1_700_000_000_123_456);
+        let payload = payload(capture_time);
+        assert_eq!(payload.len(), 8);
+        assert_eq!(from_payload(&payload).unwrap(), capture_time);
+
+        assert!(from_payload(&payload[..7]).is_err());
+    }
+
+    #[test]
+    fn access_unit_capture_time() {
+        let capture_time = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let sei = Sei {
+            messages: vec![SeiMessage::UserDataUnregistered(UserDataUnregistered {
+                uuid_iso_iec_11578: H264_USER_DATA_UUID,
+                payload: super::payload(capture_time),
+            })],
+        };
+        let mut access_unit = vec![];
+        Synthesizer::<Sei, _>::synthesize(0, &sei, &Sps::default(), &mut access_unit, true)
+            .unwrap();
+        // IDR slice
+        access_unit.extend_from_slice(&[0x00, 0x00, 0x00, 0x01, 0x65, 0x88, 0x80]);
+
+        assert_eq!(super::capture_time(&access_unit), Some(capture_time));
+        assert_eq!(
+            super::capture_time(&access_unit[access_unit.len() - 7..]),
+            None
+        );
+
+        let now = capture_time + Duration::from_millis(42);
+        assert_eq!(latency(capture_time, now), Duration::from_millis(42));
+        assert_eq!(latency(now, capture_time), Duration::ZERO);
+    }
+}
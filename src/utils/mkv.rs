@@ -0,0 +1,923 @@
+// Copyright 2024 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Minimal Matroska and WebM writer for encoded video streams.
+//!
+//! [`MkvWriter`] writes a single video track, with one cluster per group of
+//! pictures. The segment has an unknown size and there are no cues, so the
+//! output can be streamed to a non-seekable writer and still be played back
+//! from start to end by common players.
+//!
+//! The first video track of Matroska and WebM files can also be read back
+//! by [`Demuxer`](crate::utils::demux::Demuxer).
+
+use std::collections::VecDeque;
+use std::io::Write;
+
+use anyhow::anyhow;
+use anyhow::Context;
+use bytes::BufMut;
+
+use crate::codec::av1::av1c::Av1CodecConfigurationRecord;
+use crate::codec::av1::parser::ObuFormat;
+use crate::codec::av1::parser::ObuType;
+use crate::codec::av1::parser::ParsedObu;
+use crate::codec::av1::parser::Parser as Av1Parser;
+use crate::codec::h264::avcc::AvcDecoderConfigurationRecord;
+use crate::codec::h264::nalu::split_annexb;
+use crate::codec::h265::hvcc::HevcDecoderConfigurationRecord;
+use crate::encoder::CodedBitstreamBuffer;
+use crate::utils::demux::ByteReader;
+use crate::utils::demux::SampleRef;
+use crate::utils::demux::Track;
+use crate::utils::demux::TrackCodec;
+use crate::utils::mp4::Mp4Codec;
+use crate::Resolution;
+
+/// Size in bytes of the NAL unit length prefix of H.264 and H.265 blocks.
+const NAL_LENGTH_SIZE: usize = 4;
+
+// EBML and Matroska element IDs.
+const EBML: u32 = 0x1a45dfa3;
+const EBML_VERSION: u32 = 0x4286;
+const EBML_READ_VERSION: u32 = 0x42f7;
+const EBML_MAX_ID_LENGTH: u32 = 0x42f2;
+const EBML_MAX_SIZE_LENGTH: u32 = 0x42f3;
+const DOC_TYPE: u32 = 0x4282;
+const DOC_TYPE_VERSION: u32 = 0x4287;
+const DOC_TYPE_READ_VERSION: u32 = 0x4285;
+const SEGMENT: u32 = 0x18538067;
+const INFO: u32 = 0x1549a966;
+const TIMESTAMP_SCALE: u32 = 0x2ad7b1;
+const MUXING_APP: u32 = 0x4d80;
+const WRITING_APP: u32 = 0x5741;
+const TRACKS: u32 = 0x1654ae6b;
+const TRACK_ENTRY: u32 = 0xae;
+const TRACK_NUMBER: u32 = 0xd7;
+const TRACK_UID: u32 = 0x73c5;
+const TRACK_TYPE: u32 = 0x83;
+const FLAG_LACING: u32 = 0x9c;
+const CODEC_ID: u32 = 0x86;
+const CODEC_PRIVATE: u32 = 0x63a2;
+const CONTENT_ENCODINGS: u32 = 0x6d80;
+const VIDEO: u32 = 0xe0;
+const PIXEL_WIDTH: u32 = 0xb0;
+const PIXEL_HEIGHT: u32 = 0xba;
+const CLUSTER: u32 = 0x1f43b675;
+const TIMESTAMP: u32 = 0xe7;
+const SIMPLE_BLOCK: u32 = 0xa3;
+const BLOCK_GROUP: u32 = 0xa0;
+const BLOCK: u32 = 0xa1;
+const REFERENCE_BLOCK: u32 = 0xfb;
+
+/// Size of an element of unknown size.
+const UNKNOWN_SIZE: [u8; 8] = [0x01, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff];
+
+/// Number of the only track of the file.
+const TRACK: u64 = 1;
+
+/// `TrackType` of video tracks.
+const TRACK_TYPE_VIDEO: u64 = 1;
+
+/// Flag of the keyframe blocks.
+const BLOCK_FLAG_KEYFRAME: u8 = 0x80;
+/// Lacing bits of the block flags.
+const BLOCK_FLAG_LACING: u8 = 0x06;
+
+/// `TimestampScale` of files without one, i.e. milliseconds.
+const DEFAULT_TIMESTAMP_SCALE: u64 = 1_000_000;
+
+/// Name of this crate, for the `MuxingApp` and `WritingApp` elements.
+const APP_NAME: &str = "cros-codecs";
+
+/// Codec of the written track.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MkvCodec {
+    Vp8,
+    Vp9,
+    Av1,
+    H264,
+    H265,
+}
+
+impl MkvCodec {
+    /// Returns the `CodecID` of the track.
+    fn codec_id(self) -> &'static str {
+        match self {
+            MkvCodec::Vp8 => "V_VP8",
+            MkvCodec::Vp9 => "V_VP9",
+            MkvCodec::Av1 => "V_AV1",
+            MkvCodec::H264 => "V_MPEG4/ISO/AVC",
+            MkvCodec::H265 => "V_MPEGH/ISO/HEVC",
+        }
+    }
+
+    /// Returns whether the codec can be stored in WebM files, the others
+    /// requiring a Matroska file.
+    pub fn is_webm(self) -> bool {
+        matches!(self, MkvCodec::Vp8 | MkvCodec::Vp9 | MkvCodec::Av1)
+    }
+}
+
+/// Writes an EBML variable size integer of `len` bytes.
+fn put_vint(out: &mut Vec<u8>, value: u64, len: usize) {
+    let marker = 1u64 << (7 * len);
+    out.put_uint(marker | value, len);
+}
+
+/// Writes the size of an element as a variable size integer of the minimal
+/// length.
+fn put_size(out: &mut Vec<u8>, size: usize) {
+    let size = size as u64;
+    // All ones is reserved for the unknown size.
+    let len = (1..8).find(|len| size < (1 << (7 * len)) - 1).unwrap_or(8);
+    put_vint(out, size, len);
+}
+
+/// Writes an element ID, which already includes its length marker.
+fn put_id(out: &mut Vec<u8>, id: u32) {
+    let len = 4 - (id.leading_zeros() / 8) as usize;
+    out.put_uint(u64::from(id), len);
+}
+
+fn put_element(out: &mut Vec<u8>, id: u32, data: &[u8]) {
+    put_id(out, id);
+    put_size(out, data.len());
+    out.put_slice(data);
+}
+
+fn put_uint_element(out: &mut Vec<u8>, id: u32, value: u64) {
+    let len = std::cmp::max(1, 8 - (value.leading_zeros() / 8) as usize);
+    put_element(out, id, &value.to_be_bytes()[8 - len..]);
+}
+
+/// Writes a master element whose children are written by `children`.
+fn put_master(out: &mut Vec<u8>, id: u32, children: impl FnOnce(&mut Vec<u8>)) {
+    let mut data = Vec::new();
+    children(&mut data);
+    put_element(out, id, &data);
+}
+
+/// A cluster being filled with blocks.
+struct Cluster {
+    /// Timestamp of the cluster, in units of the segment timestamp scale.
+    timestamp: u64,
+    /// The children elements of the cluster.
+    data: Vec<u8>,
+}
+
+/// Writer of encoded frames into a Matroska or WebM file.
+///
+/// Frames are added with [`MkvWriter::write_frame`], in presentation order,
+/// with their [`FrameMetadata::timestamp`](crate::encoder::FrameMetadata) in
+/// units of the timestamp scale of the writer. [`MkvWriter::finish`] must be
+/// called once all frames have been written.
+///
+/// The file headers are written along with the first frame, which must be a
+/// keyframe. For H.264, H.265 and AV1, it must carry the parameter sets or
+/// sequence header that are stored in the `CodecPrivate` element.
+pub struct MkvWriter<W: Write> {
+    writer: W,
+    codec: MkvCodec,
+    resolution: Resolution,
+    timestamp_scale: u64,
+    av1_parser: Av1Parser,
+    /// Last AV1 sequence header OBU, for the `CodecPrivate` element.
+    av1_sequence_header: Option<Vec<u8>>,
+    cluster: Option<Cluster>,
+    last_timestamp: Option<u64>,
+}
+
+impl<W: Write> MkvWriter<W> {
+    /// Creates a writer of a `codec` stream of `resolution` into `writer`.
+    /// `timestamp_scale` is the duration in nanoseconds of the unit of the
+    /// frame timestamps, e.g. 1000000 for milliseconds.
+    pub fn new(
+        writer: W,
+        codec: MkvCodec,
+        resolution: Resolution,
+        timestamp_scale: u64,
+    ) -> anyhow::Result<Self> {
+        if timestamp_scale == 0 {
+            return Err(anyhow!("Invalid timestamp scale of 0"));
+        }
+
+        let mut av1_parser = Av1Parser::default();
+        av1_parser.set_format(Some(ObuFormat::LowOverhead));
+
+        Ok(Self {
+            writer,
+            codec,
+            resolution,
+            timestamp_scale,
+            av1_parser,
+            av1_sequence_header: None,
+            cluster: None,
+            last_timestamp: None,
+        })
+    }
+
+    /// Writes the encoded frame of `buffer`.
+    pub fn write_frame(&mut self, buffer: &CodedBitstreamBuffer) -> anyhow::Result<()> {
+        let timestamp = buffer.metadata.timestamp;
+        if let Some(last_timestamp) = self.last_timestamp {
+            if timestamp <= last_timestamp {
+                return Err(anyhow!(
+                    "Timestamp {} is not after the previous one {}",
+                    timestamp,
+                    last_timestamp
+                ));
+            }
+        }
+
+        let (data, keyframe) = self.prepare_block(&buffer.bitstream)?;
+
+        if self.last_timestamp.is_none() {
+            if !keyframe {
+                return Err(anyhow!("The first frame must be a keyframe"));
+            }
+            self.write_headers(&buffer.bitstream)?;
+        }
+
+        // Start a new cluster at each keyframe, or when the relative timestamp
+        // of the block would overflow.
+        let new_cluster = match &self.cluster {
+            Some(cluster) => keyframe || timestamp - cluster.timestamp > i16::MAX as u64,
+            None => true,
+        };
+        if new_cluster {
+            self.write_cluster()?;
+            let mut data = Vec::new();
+            put_uint_element(&mut data, TIMESTAMP, timestamp);
+            self.cluster = Some(Cluster { timestamp, data });
+        }
+
+        let cluster = self.cluster.as_mut().unwrap();
+        let mut block = Vec::with_capacity(data.len() + 4);
+        put_vint(&mut block, TRACK, 1);
+        block.put_i16((timestamp - cluster.timestamp) as i16);
+        block.put_u8(if keyframe { BLOCK_FLAG_KEYFRAME } else { 0 });
+        block.put_slice(&data);
+        put_element(&mut cluster.data, SIMPLE_BLOCK, &block);
+
+        self.last_timestamp = Some(timestamp);
+
+        Ok(())
+    }
+
+    /// Writes the pending cluster and returns the underlying writer.
+    pub fn finish(mut self) -> anyhow::Result<W> {
+        self.write_cluster()?;
+        self.writer.flush()?;
+
+        Ok(self.writer)
+    }
+
+    /// Writes the current cluster, if any.
+    fn write_cluster(&mut self) -> anyhow::Result<()> {
+        if let Some(cluster) = self.cluster.take() {
+            let mut out = Vec::with_capacity(cluster.data.len() + 12);
+            put_element(&mut out, CLUSTER, &cluster.data);
+            self.writer.write_all(&out)?;
+        }
+
+        Ok(())
+    }
+
+    /// Returns the data to store in the block of the frame `bitstream`, and
+    /// whether it is a keyframe.
+    fn prepare_block(&mut self, bitstream: &[u8]) -> anyhow::Result<(Vec<u8>, bool)> {
+        let first_byte = *bitstream.first().ok_or(anyhow!("Empty frame"))?;
+
+        match self.codec {
+            // Inverse key_frame flag of the frame tag.
+            MkvCodec::Vp8 => Ok((bitstream.to_vec(), first_byte & 0x1 == 0)),
+            MkvCodec::Vp9 => {
+                // frame_marker, profile_low_bit and profile_high_bit, then
+                // reserved_zero for profile 3.
+                let profile = (first_byte >> 5) & 0x1 | (first_byte >> 3) & 0x2;
+                let shift = if profile == 3 { 2 } else { 3 };
+                let show_existing_frame = (first_byte >> shift) & 0x1 != 0;
+                let frame_type = (first_byte >> (shift - 1)) & 0x1;
+
+                Ok((bitstream.to_vec(), !show_existing_frame && frame_type == 0))
+            }
+            MkvCodec::Av1 => self.prepare_av1_block(bitstream),
+            MkvCodec::H264 | MkvCodec::H265 => {
+                let codec = match self.codec {
+                    MkvCodec::H264 => Mp4Codec::H264,
+                    _ => Mp4Codec::H265,
+                };
+                let mut data = Vec::with_capacity(bitstream.len());
+                let mut keyframe = false;
+
+                for nalu in split_annexb(bitstream) {
+                    let Some(&header) = nalu.first() else {
+                        continue;
+                    };
+                    keyframe |= codec.is_keyframe(header);
+
+                    if !codec.is_out_of_band(header) {
+                        data.put_u32(u32::try_from(nalu.len())?);
+                        data.put_slice(&nalu);
+                    }
+                }
+
+                Ok((data, keyframe))
+            }
+        }
+    }
+
+    /// Removes the temporal delimiters of the temporal unit `bitstream`, and
+    /// checks whether it starts with a key frame.
+    fn prepare_av1_block(&mut self, bitstream: &[u8]) -> anyhow::Result<(Vec<u8>, bool)> {
+        let mut data = Vec::with_capacity(bitstream.len());
+        let mut keyframe = None;
+        let mut consumed = 0;
+
+        while consumed < bitstream.len() {
+            let obu = match self.av1_parser.parse_obu(&bitstream[consumed..])? {
+                ParsedObu::Process(obu) => obu,
+                ParsedObu::Drop(length) => {
+                    let length = length as usize;
+                    data.extend_from_slice(&bitstream[consumed..consumed + length]);
+                    consumed += length;
+                    continue;
+                }
+            };
+            consumed += obu.data.len();
+
+            match obu.header.obu_type {
+                ObuType::TemporalDelimiter => continue,
+                ObuType::SequenceHeader => {
+                    self.av1_parser.parse_sequence_header_obu(&obu)?;
+                    self.av1_sequence_header = Some(obu.data.to_vec());
+                }
+                ObuType::FrameHeader | ObuType::Frame if keyframe.is_none() => {
+                    let reduced_still_picture_header = self
+                        .av1_parser
+                        .sequence_header
+                        .as_ref()
+                        .ok_or(anyhow!("Frame header before any sequence header"))?
+                        .reduced_still_picture_header;
+                    // show_existing_frame and frame_type start the
+                    // uncompressed header.
+                    let header = obu.as_ref().first().copied().unwrap_or_default();
+                    keyframe = Some(reduced_still_picture_header || header & 0xe0 == 0);
+                }
+                _ => (),
+            }
+
+            data.extend_from_slice(&obu.data);
+        }
+
+        Ok((data, keyframe.unwrap_or(false)))
+    }
+
+    /// Returns the `CodecPrivate` of the track, taken from the first
+    /// keyframe `bitstream` or the last AV1 sequence header.
+    fn codec_private(&self, bitstream: &[u8]) -> anyhow::Result<Option<Vec<u8>>> {
+        let mut codec_private = Vec::new();
+
+        match self.codec {
+            MkvCodec::Vp8 | MkvCodec::Vp9 => return Ok(None),
+            MkvCodec::Av1 => {
+                let sequence_header = self
+                    .av1_sequence_header
+                    .as_ref()
+                    .ok_or(anyhow!("The first keyframe has no sequence header"))?;
+                Av1CodecConfigurationRecord::from_sequence_header_obu(sequence_header)?
+                    .write_into(&mut codec_private)?;
+            }
+            MkvCodec::H264 => {
+                AvcDecoderConfigurationRecord::from_annexb(bitstream, NAL_LENGTH_SIZE)?
+                    .write_into(&mut codec_private)?;
+            }
+            MkvCodec::H265 => {
+                HevcDecoderConfigurationRecord::from_annexb(bitstream, NAL_LENGTH_SIZE)?
+                    .write_into(&mut codec_private)?;
+            }
+        }
+
+        Ok(Some(codec_private))
+    }
+
+    /// Writes the EBML header and the beginning of the segment.
+    fn write_headers(&mut self, bitstream: &[u8]) -> anyhow::Result<()> {
+        let codec_private = self.codec_private(bitstream)?;
+        let mut out = Vec::new();
+
+        put_master(&mut out, EBML, |out| {
+            put_uint_element(out, EBML_VERSION, 1);
+            put_uint_element(out, EBML_READ_VERSION, 1);
+            put_uint_element(out, EBML_MAX_ID_LENGTH, 4);
+            put_uint_element(out, EBML_MAX_SIZE_LENGTH, 8);
+            let doc_type = if self.codec.is_webm() {
+                "webm"
+            } else {
+                "matroska"
+            };
+            put_element(out, DOC_TYPE, doc_type.as_bytes());
+            put_uint_element(out, DOC_TYPE_VERSION, 4);
+            put_uint_element(out, DOC_TYPE_READ_VERSION, 2);
+        });
+
+        // The segment is streamed, so its size is unknown.
+        put_id(&mut out, SEGMENT);
+        out.put_slice(&UNKNOWN_SIZE);
+
+        put_master(&mut out, INFO, |out| {
+            put_uint_element(out, TIMESTAMP_SCALE, self.timestamp_scale);
+            put_element(out, MUXING_APP, APP_NAME.as_bytes());
+            put_element(out, WRITING_APP, APP_NAME.as_bytes());
+        });
+
+        put_master(&mut out, TRACKS, |out| {
+            put_master(out, TRACK_ENTRY, |out| {
+                put_uint_element(out, TRACK_NUMBER, TRACK);
+                put_uint_element(out, TRACK_UID, TRACK);
+                put_uint_element(out, TRACK_TYPE, TRACK_TYPE_VIDEO);
+                put_uint_element(out, FLAG_LACING, 0);
+                put_element(out, CODEC_ID, self.codec.codec_id().as_bytes());
+                if let Some(codec_private) = &codec_private {
+                    put_element(out, CODEC_PRIVATE, codec_private);
+                }
+                put_master(out, VIDEO, |out| {
+                    put_uint_element(out, PIXEL_WIDTH, u64::from(self.resolution.width));
+                    put_uint_element(out, PIXEL_HEIGHT, u64::from(self.resolution.height));
+                });
+            });
+        });
+
+        self.writer.write_all(&out)?;
+
+        Ok(())
+    }
+}
+
+/// An element read from a file.
+struct Element<'a> {
+    id: u32,
+    data: &'a [u8],
+}
+
+/// Reads a variable size integer of at most `max_len` bytes, returning its
+/// value with the length marker and its length.
+fn read_vint(reader: &mut ByteReader, max_len: usize) -> anyhow::Result<(u64, usize)> {
+    let first_byte = *reader
+        .remaining()
+        .first()
+        .ok_or(anyhow!("Truncated variable size integer"))?;
+    let len = first_byte.leading_zeros() as usize + 1;
+    if len > max_len {
+        return Err(anyhow!("Invalid variable size integer"));
+    }
+
+    Ok((reader.uint(len)?, len))
+}
+
+/// Reads a variable size integer without its length marker. Returns `None`
+/// for the reserved value meaning an unknown size.
+fn read_size(reader: &mut ByteReader) -> anyhow::Result<Option<u64>> {
+    let (value, len) = read_vint(reader, 8)?;
+    let mask = (1u64 << (7 * len)) - 1;
+
+    Ok(match value & mask {
+        size if size == mask => None,
+        size => Some(size),
+    })
+}
+
+/// Returns the elements of `data`. Elements of unknown size span the rest of
+/// the data.
+fn read_elements(data: &[u8]) -> anyhow::Result<Vec<Element<'_>>> {
+    let mut elements = vec![];
+    let mut reader = ByteReader::new(data);
+
+    while !reader.remaining().is_empty() {
+        let (id, _) = read_vint(&mut reader, 4)?;
+        let id = id as u32;
+        let size = match read_size(&mut reader)? {
+            Some(size) => usize::try_from(size)?,
+            None => reader.remaining().len(),
+        };
+        let data = reader
+            .bytes(size)
+            .with_context(|| format!("Truncated element {:#x}", id))?;
+
+        elements.push(Element { id, data });
+    }
+
+    Ok(elements)
+}
+
+fn find_element<'a>(elements: &[Element<'a>], id: u32) -> Option<&'a [u8]> {
+    elements.iter().find(|e| e.id == id).map(|e| e.data)
+}
+
+/// Returns the value of the unsigned integer element `data`.
+fn read_uint(data: &[u8]) -> anyhow::Result<u64> {
+    if data.len() > 8 {
+        return Err(anyhow!("Integer element of {} bytes", data.len()));
+    }
+
+    ByteReader::new(data).uint(data.len())
+}
+
+/// Returns the offset of `slice` in `data`, which must contain it.
+fn offset_in(data: &[u8], slice: &[u8]) -> usize {
+    slice.as_ptr() as usize - data.as_ptr() as usize
+}
+
+/// Returns the number, codec, resolution and `CodecPrivate` of the first
+/// video track of `tracks`.
+fn parse_video_track(
+    tracks: &[u8],
+) -> anyhow::Result<(u64, TrackCodec, Resolution, Option<Vec<u8>>)> {
+    for entry in read_elements(tracks)?
+        .iter()
+        .filter(|e| e.id == TRACK_ENTRY)
+    {
+        let entry = read_elements(entry.data)?;
+        let track_type = find_element(&entry, TRACK_TYPE)
+            .map(read_uint)
+            .transpose()?;
+        if track_type != Some(TRACK_TYPE_VIDEO) {
+            continue;
+        }
+
+        if find_element(&entry, CONTENT_ENCODINGS).is_some() {
+            return Err(anyhow!("Compressed or encrypted tracks are not supported"));
+        }
+
+        let number =
+            read_uint(find_element(&entry, TRACK_NUMBER).ok_or(anyhow!("No TrackNumber"))?)?;
+        let codec_id = find_element(&entry, CODEC_ID).ok_or(anyhow!("No CodecID"))?;
+        let codec = match codec_id {
+            b"V_MPEG4/ISO/AVC" => TrackCodec::H264,
+            b"V_MPEGH/ISO/HEVC" => TrackCodec::H265,
+            b"V_VP8" => TrackCodec::Vp8,
+            b"V_VP9" => TrackCodec::Vp9,
+            b"V_AV1" => TrackCodec::Av1,
+            _ => {
+                return Err(anyhow!(
+                    "Unsupported codec {:?}",
+                    String::from_utf8_lossy(codec_id)
+                ))
+            }
+        };
+
+        let video = read_elements(find_element(&entry, VIDEO).unwrap_or_default())?;
+        let dimension = |id| {
+            find_element(&video, id)
+                .map(read_uint)
+                .transpose()
+                .map(|value| value.unwrap_or(0) as u32)
+        };
+        let resolution = Resolution::from((dimension(PIXEL_WIDTH)?, dimension(PIXEL_HEIGHT)?));
+
+        let codec_private = find_element(&entry, CODEC_PRIVATE).map(<[u8]>::to_vec);
+
+        return Ok((number, codec, resolution, codec_private));
+    }
+
+    Err(anyhow!("No video track"))
+}
+
+/// Appends the frame of `block`, a `SimpleBlock` or `Block` of the file
+/// `data`, to `samples` if it belongs to track `track_number`. `keyframe` is
+/// given for blocks of a `BlockGroup`, which have no keyframe flag.
+fn parse_block(
+    data: &[u8],
+    block: &[u8],
+    cluster_timestamp: u64,
+    keyframe: Option<bool>,
+    track_number: u64,
+    samples: &mut Vec<SampleRef>,
+) -> anyhow::Result<()> {
+    let mut reader = ByteReader::new(block);
+    if read_size(&mut reader)? != Some(track_number) {
+        return Ok(());
+    }
+    let relative_timestamp = reader.u16()? as i16;
+    let flags = reader.u8()?;
+    if flags & BLOCK_FLAG_LACING != 0 {
+        return Err(anyhow!("Laced blocks are not supported"));
+    }
+
+    let frame = reader.remaining();
+    samples.push(SampleRef {
+        offset: offset_in(data, frame),
+        size: frame.len(),
+        timestamp: cluster_timestamp.saturating_add_signed(i64::from(relative_timestamp)),
+        keyframe: keyframe.unwrap_or(flags & BLOCK_FLAG_KEYFRAME != 0),
+    });
+
+    Ok(())
+}
+
+/// Appends the frames of track `track_number` in `cluster` to `samples`, and
+/// returns the clusters it contains if it has an unknown size.
+fn parse_cluster<'a>(
+    data: &[u8],
+    cluster: &'a [u8],
+    track_number: u64,
+    samples: &mut Vec<SampleRef>,
+) -> anyhow::Result<Vec<&'a [u8]>> {
+    let mut timestamp = 0;
+    let mut clusters = vec![];
+
+    for element in read_elements(cluster)? {
+        match element.id {
+            TIMESTAMP => timestamp = read_uint(element.data)?,
+            SIMPLE_BLOCK => {
+                parse_block(data, element.data, timestamp, None, track_number, samples)?
+            }
+            BLOCK_GROUP => {
+                let group = read_elements(element.data)?;
+                let block =
+                    find_element(&group, BLOCK).ok_or(anyhow!("BlockGroup without Block"))?;
+                // Blocks referencing no other block are keyframes.
+                let keyframe = find_element(&group, REFERENCE_BLOCK).is_none();
+                parse_block(
+                    data,
+                    block,
+                    timestamp,
+                    Some(keyframe),
+                    track_number,
+                    samples,
+                )?;
+            }
+            CLUSTER => clusters.push(element.data),
+            _ => (),
+        }
+    }
+
+    Ok(clusters)
+}
+
+/// Returns the first video track of the Matroska or WebM file `data`.
+pub(super) fn demux(data: &[u8]) -> anyhow::Result<Track> {
+    let top = read_elements(data)?;
+    let segment = read_elements(find_element(&top, SEGMENT).ok_or(anyhow!("No Segment"))?)?;
+
+    let info = read_elements(find_element(&segment, INFO).unwrap_or_default())?;
+    let timestamp_scale = find_element(&info, TIMESTAMP_SCALE)
+        .map(read_uint)
+        .transpose()?
+        .unwrap_or(DEFAULT_TIMESTAMP_SCALE);
+    if timestamp_scale == 0 || timestamp_scale > 1_000_000_000 {
+        return Err(anyhow!("Unsupported timestamp scale {}", timestamp_scale));
+    }
+
+    let (track_number, codec, resolution, codec_private) =
+        parse_video_track(find_element(&segment, TRACKS).ok_or(anyhow!("No Tracks"))?)?;
+
+    // Clusters of unknown size contain the following ones, which are parsed
+    // right after them to keep the frames in order.
+    let mut clusters = segment
+        .iter()
+        .filter(|e| e.id == CLUSTER)
+        .map(|e| e.data)
+        .collect::<VecDeque<_>>();
+    let mut samples = vec![];
+    while let Some(cluster) = clusters.pop_front() {
+        for nested in parse_cluster(data, cluster, track_number, &mut samples)?
+            .into_iter()
+            .rev()
+        {
+            clusters.push_front(nested);
+        }
+    }
+
+    Ok(Track {
+        codec,
+        resolution,
+        timescale: 1_000_000_000 / timestamp_scale,
+        config: match codec {
+            TrackCodec::H264 | TrackCodec::H265 => codec_private,
+            _ => None,
+        },
+        samples,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encoder::FrameMetadata;
+    use crate::utils::ivf::IvfReader;
+    use crate::Fourcc;
+    use crate::FrameLayout;
+
+    const STREAM_VP9: &[u8] = include_bytes!("../codec/vp9/test_data/test-25fps.vp9");
+    const STREAM_AV1: &[u8] = include_bytes!("../codec/av1/test_data/test-25fps.ivf.av1");
+    const STREAM_H264: &[u8] = include_bytes!("../codec/h264/test_data/64x64-I-P.h264");
+
+    /// Reads the variable size integer at the start of `data`, returning its
+    /// value with the length marker if `keep_marker` is set, and its length.
+    fn read_vint(data: &[u8], keep_marker: bool) -> (u64, usize) {
+        let len = data[0].leading_zeros() as usize + 1;
+        let mut value = data[..len]
+            .iter()
+            .fold(0u64, |value, &byte| value << 8 | u64::from(byte));
+        if !keep_marker {
+            value &= (1 << (7 * len)) - 1;
+        }
+
+        (value, len)
+    }
+
+    /// Returns the ID and contents of the elements of `data`. Elements of
+    /// unknown size span the rest of the data.
+    fn elements(data: &[u8]) -> Vec<(u32, &[u8])> {
+        let mut elements = vec![];
+        let mut pos = 0;
+
+        while pos < data.len() {
+            let (id, id_len) = read_vint(&data[pos..], true);
+            pos += id_len;
+            let unknown_size = data[pos..].starts_with(&UNKNOWN_SIZE);
+            let (size, size_len) = read_vint(&data[pos..], false);
+            pos += size_len;
+            let end = if unknown_size {
+                data.len()
+            } else {
+                pos + size as usize
+            };
+            elements.push((id as u32, &data[pos..end]));
+            pos = end;
+        }
+
+        elements
+    }
+
+    fn find(data: &[u8], id: u32) -> &[u8] {
+        elements(data)
+            .into_iter()
+            .find(|(i, _)| *i == id)
+            .unwrap_or_else(|| panic!("no element {:#x}", id))
+            .1
+    }
+
+    fn buffer(timestamp: u64, bitstream: &[u8]) -> CodedBitstreamBuffer {
+        CodedBitstreamBuffer::new(
+            FrameMetadata {
+                timestamp,
+                display_resolution: Resolution::from((320, 240)),
+                layout: FrameLayout {
+                    format: (Fourcc::from(b"NV12"), 0),
+                    size: Resolution::from((320, 240)),
+                    planes: vec![],
+                },
+                force_keyframe: false,
+                damage: None,
+            },
+            bitstream.to_vec(),
+        )
+    }
+
+    /// Timestamp, keyframe flag and data of a block.
+    type Block = (u64, bool, Vec<u8>);
+
+    /// Writes `frames` with `codec`, and returns the `CodecPrivate` element
+    /// if any, and the blocks.
+    fn write(codec: MkvCodec, frames: &[(u64, Vec<u8>)]) -> (Option<Vec<u8>>, Vec<Block>) {
+        let mut writer =
+            MkvWriter::new(Vec::new(), codec, Resolution::from((320, 240)), 1_000_000).unwrap();
+        for (timestamp, frame) in frames {
+            writer.write_frame(&buffer(*timestamp, frame)).unwrap();
+        }
+        let data = writer.finish().unwrap();
+
+        let top = elements(&data);
+        assert_eq!(top.len(), 2);
+        let doc_type = if codec.is_webm() { "webm" } else { "matroska" };
+        assert_eq!(find(top[0].1, DOC_TYPE), doc_type.as_bytes());
+        assert_eq!(top[1].0, SEGMENT);
+
+        let segment = top[1].1;
+        assert_eq!(
+            find(find(segment, INFO), TIMESTAMP_SCALE),
+            [0x0f, 0x42, 0x40]
+        );
+        let track = find(find(segment, TRACKS), TRACK_ENTRY);
+        assert_eq!(find(track, CODEC_ID), codec.codec_id().as_bytes());
+        assert_eq!(find(find(track, VIDEO), PIXEL_WIDTH), [0x01, 0x40]);
+        let codec_private = elements(track)
+            .into_iter()
+            .find(|(id, _)| *id == CODEC_PRIVATE)
+            .map(|(_, data)| data.to_vec());
+
+        let mut blocks = vec![];
+        for (id, cluster) in elements(segment) {
+            if id != CLUSTER {
+                continue;
+            }
+
+            let cluster_timestamp = find(cluster, TIMESTAMP)
+                .iter()
+                .fold(0u64, |value, &byte| value << 8 | u64::from(byte));
+            for (id, block) in elements(cluster) {
+                if id != SIMPLE_BLOCK {
+                    continue;
+                }
+
+                assert_eq!(block[0], 0x81);
+                let timestamp = i16::from_be_bytes([block[1], block[2]]);
+                let keyframe = block[3] & BLOCK_FLAG_KEYFRAME != 0;
+                // Clusters start with a keyframe.
+                assert_eq!(keyframe, timestamp == 0);
+                blocks.push((
+                    cluster_timestamp + timestamp as u64,
+                    keyframe,
+                    block[4..].to_vec(),
+                ));
+            }
+        }
+
+        (codec_private, blocks)
+    }
+
+    #[test]
+    fn write_vp9() {
+        let frames = IvfReader::new(STREAM_VP9)
+            .unwrap()
+            .map(|frame| (frame.timestamp, frame.data.to_vec()))
+            .collect::<Vec<_>>();
+
+        let (codec_private, blocks) = write(MkvCodec::Vp9, &frames);
+        assert!(codec_private.is_none());
+        assert_eq!(blocks.len(), frames.len());
+        for ((timestamp, frame), (block_timestamp, _, data)) in frames.iter().zip(&blocks) {
+            assert_eq!(timestamp, block_timestamp);
+            assert_eq!(frame, data);
+        }
+        assert!(blocks[0].1);
+        assert!(!blocks[1].1);
+    }
+
+    #[test]
+    fn write_av1() {
+        let frames = IvfReader::new(STREAM_AV1)
+            .unwrap()
+            .map(|frame| (frame.timestamp, frame.data.to_vec()))
+            .collect::<Vec<_>>();
+
+        let (codec_private, blocks) = write(MkvCodec::Av1, &frames);
+        let codec_private = codec_private.unwrap();
+        assert_eq!(codec_private[0], 0x81);
+        // The sequence header OBU follows the av1C fields.
+        assert_eq!(codec_private[4] >> 3, ObuType::SequenceHeader as u8);
+
+        assert_eq!(blocks.len(), frames.len());
+        assert!(blocks[0].1);
+        for ((timestamp, frame), (block_timestamp, _, data)) in frames.iter().zip(&blocks) {
+            assert_eq!(timestamp, block_timestamp);
+            // Only the temporal delimiter is removed.
+            assert_eq!(&frame[..2], [0x12, 0x00]);
+            assert_eq!(&frame[2..], data);
+        }
+    }
+
+    #[test]
+    fn write_h264() {
+        // Each picture of the stream has a single slice.
+        let mut frames = vec![];
+        let mut frame = vec![];
+        for nalu in split_annexb(STREAM_H264) {
+            frame.extend_from_slice(&[0, 0, 0, 1]);
+            frame.extend_from_slice(&nalu);
+            if matches!(nalu[0] & 0x1f, 1 | 5) {
+                frames.push((40 * frames.len() as u64, std::mem::take(&mut frame)));
+            }
+        }
+
+        let (codec_private, blocks) = write(MkvCodec::H264, &frames);
+        let avcc = AvcDecoderConfigurationRecord::parse(&codec_private.unwrap()).unwrap();
+        assert_eq!(avcc.nal_length_size(), NAL_LENGTH_SIZE);
+        assert_eq!(avcc.sps.len(), 1);
+
+        assert_eq!(blocks.len(), frames.len());
+        assert!(blocks[0].1);
+        for ((_, frame), (_, _, data)) in frames.iter().zip(&blocks) {
+            // Only the parameter sets and access unit delimiters are removed.
+            let mut expected = vec![];
+            for nalu in split_annexb(frame) {
+                if !matches!(nalu[0] & 0x1f, 7..=9) {
+                    expected.extend_from_slice(&(nalu.len() as u32).to_be_bytes());
+                    expected.extend_from_slice(&nalu);
+                }
+            }
+            assert_eq!(data, &expected);
+        }
+
+        // Frames without the parameter sets cannot start the stream.
+        let writer = MkvWriter::new(Vec::new(), MkvCodec::H264, Resolution::from((64, 64)), 1);
+        assert!(writer
+            .unwrap()
+            .write_frame(&buffer(0, &frames[1].1))
+            .is_err());
+    }
+}
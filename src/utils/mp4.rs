@@ -0,0 +1,1096 @@
+// Copyright 2024 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Fragmented MP4 (ISO/IEC 14496-12) muxing of H.264 and H.265 encoder
+//! output, as used by CMAF, DASH and HLS.
+//!
+//! [`Fmp4Muxer`] turns the Annex B [`CodedBitstreamBuffer`]s of an encoder
+//! into an initialization segment (`ftyp` and `moov` boxes) and a sequence
+//! of media fragments (`moof` and `mdat` boxes), with one video track. The
+//! parameter sets are moved to the `avcC` or `hvcC` box of the
+//! initialization segment, and the samples are stored with 4 bytes NAL unit
+//! length prefixes.
+//!
+//! The first video track of MP4 files, fragmented or not, can also be read
+//! back by [`Demuxer`](crate::utils::demux::Demuxer).
+
+use anyhow::anyhow;
+use anyhow::Context;
+use bytes::BufMut;
+
+use crate::codec::h264::avcc::AvcDecoderConfigurationRecord;
+use crate::codec::h265::hvcc::HevcDecoderConfigurationRecord;
+use crate::encoder::CodedBitstreamBuffer;
+use crate::utils::demux::ByteReader;
+use crate::utils::demux::SampleRef;
+use crate::utils::demux::Track;
+use crate::utils::demux::TrackCodec;
+use crate::Resolution;
+
+/// Size in bytes of the NAL unit length prefix of the samples.
+const NAL_LENGTH_SIZE: usize = 4;
+
+/// ID of the only track of the file.
+const TRACK_ID: u32 = 1;
+
+/// Identity transformation matrix of the `mvhd` and `tkhd` boxes.
+const MATRIX: [u32; 9] = [0x00010000, 0, 0, 0, 0x00010000, 0, 0, 0, 0x40000000];
+
+/// `sample_flags` of a sync sample: `sample_depends_on` equal to 2.
+const SAMPLE_FLAGS_SYNC: u32 = 0x0200_0000;
+/// `sample_flags` of other samples: `sample_depends_on` equal to 1 and
+/// `sample_is_non_sync_sample` set.
+const SAMPLE_FLAGS_NON_SYNC: u32 = 0x0101_0000;
+
+/// Codec of the muxed stream.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Mp4Codec {
+    H264,
+    H265,
+}
+
+impl Mp4Codec {
+    /// Returns whether the NAL unit starting with `header` is a parameter set
+    /// or access unit delimiter, which are not part of the samples.
+    pub(crate) fn is_out_of_band(self, header: u8) -> bool {
+        match self {
+            // SPS, PPS, AUD and SPS extension.
+            Mp4Codec::H264 => matches!(header & 0x1f, 7..=9 | 13),
+            // VPS, SPS, PPS and AUD.
+            Mp4Codec::H265 => matches!((header >> 1) & 0x3f, 32..=35),
+        }
+    }
+
+    /// Returns whether the NAL unit starting with `header` is an IDR slice
+    /// for H.264, or an IRAP slice segment for H.265.
+    pub(crate) fn is_keyframe(self, header: u8) -> bool {
+        match self {
+            Mp4Codec::H264 => header & 0x1f == 5,
+            Mp4Codec::H265 => matches!((header >> 1) & 0x3f, 16..=23),
+        }
+    }
+}
+
+/// Decoder configuration record of the sample entry.
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum DecoderConfig {
+    Avc(AvcDecoderConfigurationRecord),
+    Hevc(HevcDecoderConfigurationRecord),
+}
+
+/// A sample waiting to be written into a fragment.
+struct Sample {
+    /// Length-prefixed NAL units of the frame.
+    data: Vec<u8>,
+    timestamp: u64,
+    keyframe: bool,
+}
+
+/// Writes a box of type `fourcc` whose contents are written by `contents`.
+fn write_box(
+    out: &mut Vec<u8>,
+    fourcc: &[u8; 4],
+    contents: impl FnOnce(&mut Vec<u8>) -> anyhow::Result<()>,
+) -> anyhow::Result<()> {
+    let start = out.len();
+    out.put_u32(0);
+    out.put_slice(fourcc);
+
+    contents(out)?;
+
+    let size = u32::try_from(out.len() - start)?;
+    out[start..start + 4].copy_from_slice(&size.to_be_bytes());
+
+    Ok(())
+}
+
+/// Writes a full box, i.e. a box starting with a version and flags.
+fn write_full_box(
+    out: &mut Vec<u8>,
+    fourcc: &[u8; 4],
+    version: u8,
+    flags: u32,
+    contents: impl FnOnce(&mut Vec<u8>) -> anyhow::Result<()>,
+) -> anyhow::Result<()> {
+    write_box(out, fourcc, |out| {
+        out.put_u32(u32::from(version) << 24 | (flags & 0x00ff_ffff));
+        contents(out)
+    })
+}
+
+/// Muxer of H.264 or H.265 encoder output into fragmented MP4.
+///
+/// Buffers are added with [`Fmp4Muxer::push`], in presentation order, with
+/// their [`FrameMetadata::timestamp`](crate::encoder::FrameMetadata) in
+/// units of the track timescale. A fragment is returned each time a keyframe
+/// starts a new group of pictures, and the pending samples can be written
+/// at any time with [`Fmp4Muxer::flush`], e.g. for low-latency chunks or at
+/// the end of the stream.
+///
+/// The initialization segment is available from
+/// [`Fmp4Muxer::init_segment`] once the first keyframe, which must carry the
+/// parameter sets, has been pushed.
+pub struct Fmp4Muxer {
+    codec: Mp4Codec,
+    resolution: Resolution,
+    timescale: u32,
+    config: Option<DecoderConfig>,
+    samples: Vec<Sample>,
+    /// Timestamp of the last sample pushed.
+    last_timestamp: Option<u64>,
+    /// `sequence_number` of the next fragment.
+    sequence_number: u32,
+    /// Duration of the last sample written, used for the last sample of a
+    /// fragment when the timestamp of the next one is not known yet.
+    last_duration: u32,
+}
+
+impl Fmp4Muxer {
+    /// Creates a muxer for a `codec` stream of `resolution`, with timestamps
+    /// in units of `1 / timescale` seconds.
+    pub fn new(codec: Mp4Codec, resolution: Resolution, timescale: u32) -> anyhow::Result<Self> {
+        if timescale == 0 {
+            return Err(anyhow!("Invalid timescale of 0"));
+        }
+
+        if resolution.width > u32::from(u16::MAX) || resolution.height > u32::from(u16::MAX) {
+            return Err(anyhow!("Resolution {:?} too large for MP4", resolution));
+        }
+
+        Ok(Self {
+            codec,
+            resolution,
+            timescale,
+            config: None,
+            samples: Vec::new(),
+            last_timestamp: None,
+            sequence_number: 1,
+            last_duration: 0,
+        })
+    }
+
+    /// Adds the encoded frame of `buffer` to the current fragment. If it is a
+    /// keyframe, the previous fragment is completed and returned.
+    pub fn push(&mut self, buffer: &CodedBitstreamBuffer) -> anyhow::Result<Option<Vec<u8>>> {
+        let nalus = match self.codec {
+            Mp4Codec::H264 => buffer.h264_nal_units(),
+            Mp4Codec::H265 => buffer.h265_nal_units(),
+        }
+        .map(|unit| unit.data)
+        .collect::<Vec<_>>();
+        let keyframe = nalus
+            .iter()
+            .any(|nalu| nalu.first().is_some_and(|&h| self.codec.is_keyframe(h)));
+        let timestamp = buffer.metadata.timestamp;
+
+        if let Some(last_timestamp) = self.last_timestamp {
+            if timestamp <= last_timestamp {
+                return Err(anyhow!(
+                    "Timestamp {} is not after the previous one {}",
+                    timestamp,
+                    last_timestamp
+                ));
+            }
+        }
+
+        if keyframe {
+            self.update_config(&buffer.bitstream)?;
+        } else if self.config.is_none() {
+            return Err(anyhow!("The first frame must be a keyframe"));
+        }
+
+        let mut data = Vec::with_capacity(buffer.bitstream.len());
+        for nalu in nalus {
+            match nalu.first() {
+                Some(&header) if !self.codec.is_out_of_band(header) => {
+                    data.put_u32(u32::try_from(nalu.len())?);
+                    data.put_slice(nalu);
+                }
+                _ => continue,
+            }
+        }
+
+        let fragment = if keyframe {
+            self.write_fragment(Some(timestamp))?
+        } else {
+            None
+        };
+
+        self.samples.push(Sample {
+            data,
+            timestamp,
+            keyframe,
+        });
+        self.last_timestamp = Some(timestamp);
+
+        Ok(fragment)
+    }
+
+    /// Writes all pending samples into a fragment, if any. The duration of
+    /// the last sample is assumed to be the same as the previous one.
+    pub fn flush(&mut self) -> anyhow::Result<Option<Vec<u8>>> {
+        self.write_fragment(None)
+    }
+
+    /// Sets the decoder configuration record from the parameter sets of the
+    /// keyframe `bitstream`.
+    fn update_config(&mut self, bitstream: &[u8]) -> anyhow::Result<()> {
+        let config = match self.codec {
+            Mp4Codec::H264 => {
+                AvcDecoderConfigurationRecord::from_annexb(bitstream, NAL_LENGTH_SIZE)
+                    .map(DecoderConfig::Avc)
+            }
+            Mp4Codec::H265 => {
+                HevcDecoderConfigurationRecord::from_annexb(bitstream, NAL_LENGTH_SIZE)
+                    .map(DecoderConfig::Hevc)
+            }
+        };
+
+        match (&self.config, config) {
+            (None, Ok(config)) => self.config = Some(config),
+            (None, Err(e)) => return Err(e.context("The first keyframe has no parameter sets")),
+            (Some(current), Ok(config)) if *current != config => {
+                return Err(anyhow!(
+                    "Parameter sets changed, a new initialization segment is required"
+                ))
+            }
+            // Keyframes without parameter sets keep using the current ones.
+            (Some(_), _) => (),
+        }
+
+        Ok(())
+    }
+
+    /// Returns the initialization segment, i.e. the `ftyp` and `moov` boxes.
+    pub fn init_segment(&self) -> anyhow::Result<Vec<u8>> {
+        let config = self
+            .config
+            .as_ref()
+            .ok_or(anyhow!("No keyframe has been pushed yet"))?;
+        let mut out = Vec::new();
+
+        write_box(&mut out, b"ftyp", |out| {
+            out.put_slice(b"iso6");
+            out.put_u32(0);
+            for brand in [b"iso6", b"cmfc", b"mp41"] {
+                out.put_slice(brand);
+            }
+            Ok(())
+        })?;
+
+        write_box(&mut out, b"moov", |out| {
+            write_full_box(out, b"mvhd", 0, 0, |out| {
+                // creation_time, modification_time, timescale and duration.
+                out.put_u32(0);
+                out.put_u32(0);
+                out.put_u32(self.timescale);
+                out.put_u32(0);
+                // rate, volume and reserved.
+                out.put_u32(0x00010000);
+                out.put_u16(0x0100);
+                out.put_bytes(0, 10);
+                MATRIX.iter().for_each(|&v| out.put_u32(v));
+                // pre_defined and next_track_ID.
+                out.put_bytes(0, 24);
+                out.put_u32(TRACK_ID + 1);
+                Ok(())
+            })?;
+
+            write_box(out, b"trak", |out| {
+                self.write_tkhd(out)?;
+                write_box(out, b"mdia", |out| self.write_mdia(out, config))
+            })?;
+
+            write_box(out, b"mvex", |out| {
+                write_full_box(out, b"trex", 0, 0, |out| {
+                    out.put_u32(TRACK_ID);
+                    // default_sample_description_index, then the default
+                    // duration, size and flags of the samples, which are all
+                    // given by the trun boxes.
+                    out.put_u32(1);
+                    out.put_bytes(0, 12);
+                    Ok(())
+                })
+            })
+        })?;
+
+        Ok(out)
+    }
+
+    fn write_tkhd(&self, out: &mut Vec<u8>) -> anyhow::Result<()> {
+        // track_enabled and track_in_movie.
+        write_full_box(out, b"tkhd", 0, 0x3, |out| {
+            // creation_time, modification_time, track_ID, reserved and
+            // duration.
+            out.put_u32(0);
+            out.put_u32(0);
+            out.put_u32(TRACK_ID);
+            out.put_u32(0);
+            out.put_u32(0);
+            // reserved, layer, alternate_group, volume and reserved.
+            out.put_bytes(0, 16);
+            MATRIX.iter().for_each(|&v| out.put_u32(v));
+            // Width and height in 16.16 fixed point.
+            out.put_u32(self.resolution.width << 16);
+            out.put_u32(self.resolution.height << 16);
+            Ok(())
+        })
+    }
+
+    fn write_mdia(&self, out: &mut Vec<u8>, config: &DecoderConfig) -> anyhow::Result<()> {
+        write_full_box(out, b"mdhd", 0, 0, |out| {
+            // creation_time, modification_time, timescale and duration.
+            out.put_u32(0);
+            out.put_u32(0);
+            out.put_u32(self.timescale);
+            out.put_u32(0);
+            // "und" language and pre_defined.
+            out.put_u16(0x55c4);
+            out.put_u16(0);
+            Ok(())
+        })?;
+
+        write_full_box(out, b"hdlr", 0, 0, |out| {
+            out.put_u32(0);
+            out.put_slice(b"vide");
+            out.put_bytes(0, 12);
+            out.put_slice(b"VideoHandler\0");
+            Ok(())
+        })?;
+
+        write_box(out, b"minf", |out| {
+            write_full_box(out, b"vmhd", 0, 1, |out| {
+                // graphicsmode and opcolor.
+                out.put_bytes(0, 8);
+                Ok(())
+            })?;
+
+            write_box(out, b"dinf", |out| {
+                write_full_box(out, b"dref", 0, 0, |out| {
+                    out.put_u32(1);
+                    // Media data in the same file.
+                    write_full_box(out, b"url ", 0, 1, |_| Ok(()))
+                })
+            })?;
+
+            write_box(out, b"stbl", |out| {
+                write_full_box(out, b"stsd", 0, 0, |out| {
+                    out.put_u32(1);
+                    self.write_sample_entry(out, config)
+                })?;
+
+                // The samples are all in the fragments.
+                for fourcc in [b"stts", b"stsc", b"stco"] {
+                    write_full_box(out, fourcc, 0, 0, |out| {
+                        out.put_u32(0);
+                        Ok(())
+                    })?;
+                }
+                write_full_box(out, b"stsz", 0, 0, |out| {
+                    out.put_u32(0);
+                    out.put_u32(0);
+                    Ok(())
+                })
+            })
+        })
+    }
+
+    fn write_sample_entry(&self, out: &mut Vec<u8>, config: &DecoderConfig) -> anyhow::Result<()> {
+        let fourcc = match config {
+            DecoderConfig::Avc(_) => b"avc1",
+            DecoderConfig::Hevc(_) => b"hvc1",
+        };
+
+        // VisualSampleEntry, see ISO/IEC 14496-12 12.1.3.
+        write_box(out, fourcc, |out| {
+            // reserved and data_reference_index.
+            out.put_bytes(0, 6);
+            out.put_u16(1);
+            // pre_defined and reserved.
+            out.put_bytes(0, 16);
+            out.put_u16(self.resolution.width as u16);
+            out.put_u16(self.resolution.height as u16);
+            // 72 dpi horizontal and vertical resolutions.
+            out.put_u32(0x00480000);
+            out.put_u32(0x00480000);
+            out.put_u32(0);
+            // frame_count, compressorname, depth and pre_defined.
+            out.put_u16(1);
+            out.put_bytes(0, 32);
+            out.put_u16(0x0018);
+            out.put_i16(-1);
+
+            match config {
+                DecoderConfig::Avc(avcc) => write_box(out, b"avcC", |out| avcc.write_into(out)),
+                DecoderConfig::Hevc(hvcc) => write_box(out, b"hvcC", |out| hvcc.write_into(out)),
+            }
+        })
+    }
+
+    /// Writes the pending samples into a `moof` and a `mdat` box.
+    /// `next_timestamp` is the timestamp of the sample following them, if
+    /// known.
+    fn write_fragment(&mut self, next_timestamp: Option<u64>) -> anyhow::Result<Option<Vec<u8>>> {
+        if self.samples.is_empty() {
+            return Ok(None);
+        }
+
+        let samples = std::mem::take(&mut self.samples);
+        let mut durations = Vec::with_capacity(samples.len());
+        for (i, sample) in samples.iter().enumerate() {
+            let duration = match samples.get(i + 1).map(|s| s.timestamp).or(next_timestamp) {
+                Some(next) => u32::try_from(next - sample.timestamp)?,
+                None => self.last_duration,
+            };
+            durations.push(duration);
+            self.last_duration = duration;
+        }
+
+        let mut out = Vec::new();
+        let mut data_offset_pos = 0;
+
+        write_box(&mut out, b"moof", |out| {
+            write_full_box(out, b"mfhd", 0, 0, |out| {
+                out.put_u32(self.sequence_number);
+                Ok(())
+            })?;
+
+            write_box(out, b"traf", |out| {
+                // default-base-is-moof
+                write_full_box(out, b"tfhd", 0, 0x020000, |out| {
+                    out.put_u32(TRACK_ID);
+                    Ok(())
+                })?;
+
+                write_full_box(out, b"tfdt", 1, 0, |out| {
+                    out.put_u64(samples[0].timestamp);
+                    Ok(())
+                })?;
+
+                // data-offset, sample-duration, sample-size and sample-flags
+                // present.
+                write_full_box(out, b"trun", 0, 0x000701, |out| {
+                    out.put_u32(u32::try_from(samples.len())?);
+                    data_offset_pos = out.len();
+                    out.put_u32(0);
+
+                    for (sample, duration) in samples.iter().zip(&durations) {
+                        out.put_u32(*duration);
+                        out.put_u32(u32::try_from(sample.data.len())?);
+                        out.put_u32(if sample.keyframe {
+                            SAMPLE_FLAGS_SYNC
+                        } else {
+                            SAMPLE_FLAGS_NON_SYNC
+                        });
+                    }
+                    Ok(())
+                })
+            })
+        })?;
+
+        // The data offset is relative to the start of the moof box, and
+        // points right after the header of the mdat box.
+        let data_offset = u32::try_from(out.len() + 8)?;
+        out[data_offset_pos..data_offset_pos + 4].copy_from_slice(&data_offset.to_be_bytes());
+
+        write_box(&mut out, b"mdat", |out| {
+            samples
+                .iter()
+                .for_each(|sample| out.put_slice(&sample.data));
+            Ok(())
+        })?;
+
+        self.sequence_number = self.sequence_number.wrapping_add(1);
+
+        Ok(Some(out))
+    }
+}
+
+/// `tf_flags` of the `tfhd` box.
+const TFHD_BASE_DATA_OFFSET: u32 = 0x000001;
+const TFHD_SAMPLE_DESCRIPTION_INDEX: u32 = 0x000002;
+const TFHD_DEFAULT_SAMPLE_DURATION: u32 = 0x000008;
+const TFHD_DEFAULT_SAMPLE_SIZE: u32 = 0x000010;
+const TFHD_DEFAULT_SAMPLE_FLAGS: u32 = 0x000020;
+
+/// `tr_flags` of the `trun` box.
+const TRUN_DATA_OFFSET: u32 = 0x000001;
+const TRUN_FIRST_SAMPLE_FLAGS: u32 = 0x000004;
+const TRUN_SAMPLE_DURATION: u32 = 0x000100;
+const TRUN_SAMPLE_SIZE: u32 = 0x000200;
+const TRUN_SAMPLE_FLAGS: u32 = 0x000400;
+const TRUN_SAMPLE_COMPOSITION_TIME_OFFSET: u32 = 0x000800;
+
+/// `sample_is_non_sync_sample` bit of the sample flags.
+const SAMPLE_FLAGS_IS_NON_SYNC: u32 = 0x0001_0000;
+
+/// A box read from a file.
+struct Mp4Box<'a> {
+    fourcc: [u8; 4],
+    /// Offset of the box header in the parent box.
+    start: usize,
+    /// Contents of the box, after its header.
+    data: &'a [u8],
+}
+
+/// Returns the boxes of `data`, which may be a whole file or the contents of
+/// another box.
+fn read_boxes(data: &[u8]) -> anyhow::Result<Vec<Mp4Box<'_>>> {
+    let mut boxes = vec![];
+    let mut reader = ByteReader::new(data);
+
+    while !reader.remaining().is_empty() {
+        let start = data.len() - reader.remaining().len();
+        let size = reader.u32()?;
+        let fourcc = <[u8; 4]>::try_from(reader.bytes(4)?)?;
+        let (header_size, size) = match size {
+            // The box extends to the end of the data.
+            0 => (8, (data.len() - start) as u64),
+            1 => (16, reader.u64()?),
+            size => (8, u64::from(size)),
+        };
+
+        let contents_size = usize::try_from(size)?
+            .checked_sub(header_size)
+            .ok_or(anyhow!(
+                "Invalid size {} of {:?} box",
+                size,
+                String::from_utf8_lossy(&fourcc)
+            ))?;
+        let data = reader
+            .bytes(contents_size)
+            .with_context(|| format!("Truncated {:?} box", String::from_utf8_lossy(&fourcc)))?;
+
+        boxes.push(Mp4Box {
+            fourcc,
+            start,
+            data,
+        });
+    }
+
+    Ok(boxes)
+}
+
+/// Returns the contents of the first box of type `fourcc` of `boxes`.
+fn find_box<'a>(boxes: &[Mp4Box<'a>], fourcc: &[u8; 4]) -> Option<&'a [u8]> {
+    boxes.iter().find(|b| &b.fourcc == fourcc).map(|b| b.data)
+}
+
+/// Same as [`find_box`], but returns an error if there is no such box.
+fn expect_box<'a>(boxes: &[Mp4Box<'a>], fourcc: &[u8; 4]) -> anyhow::Result<&'a [u8]> {
+    find_box(boxes, fourcc).ok_or(anyhow!("No {:?} box", String::from_utf8_lossy(&fourcc[..])))
+}
+
+/// Reads the version and flags of a full box.
+fn read_full_box_header(reader: &mut ByteReader) -> anyhow::Result<(u8, u32)> {
+    let value = reader.u32()?;
+
+    Ok(((value >> 24) as u8, value & 0x00ff_ffff))
+}
+
+/// Reads the entries of a box made of a version, flags and an
+/// `entry_count`, each entry being read by `read_entry`.
+fn read_table<T>(
+    data: &[u8],
+    mut read_entry: impl FnMut(&mut ByteReader) -> anyhow::Result<T>,
+) -> anyhow::Result<Vec<T>> {
+    let mut reader = ByteReader::new(data);
+    reader.skip(4)?;
+    let entry_count = reader.u32()?;
+
+    (0..entry_count).map(|_| read_entry(&mut reader)).collect()
+}
+
+/// Default values of the fragment samples of a track, from its `trex` box.
+#[derive(Clone, Copy, Default)]
+struct SampleDefaults {
+    duration: u32,
+    size: u32,
+    flags: u32,
+}
+
+/// Returns the codec, resolution and decoder configuration record of the
+/// sample entry of the `stsd` box.
+fn parse_sample_entry(stsd: &[u8]) -> anyhow::Result<(TrackCodec, Resolution, Option<Vec<u8>>)> {
+    let mut reader = ByteReader::new(stsd);
+    reader.skip(4)?;
+    if reader.u32()? == 0 {
+        return Err(anyhow!("No sample entry"));
+    }
+    let entries = read_boxes(reader.remaining())?;
+    let entry = entries.first().ok_or(anyhow!("No sample entry"))?;
+
+    let (codec, config_fourcc) = match &entry.fourcc {
+        b"avc1" | b"avc3" => (TrackCodec::H264, Some(b"avcC")),
+        b"hvc1" | b"hev1" => (TrackCodec::H265, Some(b"hvcC")),
+        b"vp08" => (TrackCodec::Vp8, None),
+        b"vp09" => (TrackCodec::Vp9, None),
+        b"av01" => (TrackCodec::Av1, None),
+        fourcc => {
+            return Err(anyhow!(
+                "Unsupported sample entry {:?}",
+                String::from_utf8_lossy(fourcc)
+            ))
+        }
+    };
+
+    // VisualSampleEntry, see ISO/IEC 14496-12 12.1.3. The width and height
+    // follow the reserved, data_reference_index and pre_defined fields, and
+    // the child boxes start after 78 bytes.
+    let mut reader = ByteReader::new(entry.data);
+    reader.skip(24)?;
+    let width = reader.u16()?;
+    let height = reader.u16()?;
+    reader.skip(50)?;
+
+    let config = match config_fourcc {
+        Some(fourcc) => Some(expect_box(&read_boxes(reader.remaining())?, fourcc)?.to_vec()),
+        None => None,
+    };
+
+    Ok((
+        codec,
+        Resolution::from((u32::from(width), u32::from(height))),
+        config,
+    ))
+}
+
+/// Returns the samples described by the sample table `stbl`, i.e. those of
+/// non-fragmented files.
+fn parse_sample_table(stbl: &[Mp4Box]) -> anyhow::Result<Vec<SampleRef>> {
+    let mut reader = ByteReader::new(expect_box(stbl, b"stsz")?);
+    reader.skip(4)?;
+    let sample_size = reader.u32()?;
+    let sample_count = reader.u32()? as usize;
+    if sample_count == 0 {
+        return Ok(vec![]);
+    }
+    let sizes = (0..sample_count)
+        .map(|_| match sample_size {
+            0 => reader.u32(),
+            size => Ok(size),
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    let chunk_offsets = match (find_box(stbl, b"stco"), find_box(stbl, b"co64")) {
+        (Some(stco), _) => read_table(stco, |r| r.u32().map(u64::from))?,
+        (None, Some(co64)) => read_table(co64, |r| r.u64())?,
+        (None, None) => return Err(anyhow!("No stco or co64 box")),
+    };
+    // first_chunk and samples_per_chunk, ignoring sample_description_index.
+    let chunk_runs = read_table(expect_box(stbl, b"stsc")?, |r| {
+        let run = (r.u32()?, r.u32()?);
+        r.skip(4)?;
+        Ok(run)
+    })?;
+    let timestamps = read_table(expect_box(stbl, b"stts")?, |r| Ok((r.u32()?, r.u32()?)))?
+        .into_iter()
+        .flat_map(|(count, delta)| std::iter::repeat_n(delta, count as usize))
+        .scan(0u64, |timestamp, delta| {
+            let current = *timestamp;
+            *timestamp += u64::from(delta);
+            Some(current)
+        });
+    // All samples are sync samples if there is no stss box.
+    let sync_samples = find_box(stbl, b"stss")
+        .map(|stss| read_table(stss, |r| r.u32()))
+        .transpose()?;
+
+    let mut offsets = Vec::with_capacity(sample_count);
+    for (i, &(first_chunk, samples_per_chunk)) in chunk_runs.iter().enumerate() {
+        let end_chunk = chunk_runs
+            .get(i + 1)
+            .map(|run| run.0)
+            .unwrap_or(chunk_offsets.len() as u32 + 1);
+        if first_chunk == 0 {
+            return Err(anyhow!("Invalid first_chunk of 0"));
+        }
+
+        for chunk in first_chunk..end_chunk {
+            let mut offset = *chunk_offsets
+                .get(chunk as usize - 1)
+                .ok_or(anyhow!("Chunk {} has no offset", chunk))?;
+
+            for _ in 0..samples_per_chunk {
+                let Some(&size) = sizes.get(offsets.len()) else {
+                    break;
+                };
+                offsets.push(offset);
+                offset += u64::from(size);
+            }
+        }
+    }
+    if offsets.len() < sample_count {
+        return Err(anyhow!(
+            "Only {} of the {} samples are in chunks",
+            offsets.len(),
+            sample_count
+        ));
+    }
+
+    offsets
+        .into_iter()
+        .zip(sizes)
+        .zip(timestamps.chain(std::iter::repeat(0)))
+        .enumerate()
+        .map(|(i, ((offset, size), timestamp))| {
+            Ok(SampleRef {
+                offset: usize::try_from(offset)?,
+                size: size as usize,
+                timestamp,
+                keyframe: sync_samples
+                    .as_ref()
+                    .is_none_or(|sync| sync.binary_search(&(i as u32 + 1)).is_ok()),
+            })
+        })
+        .collect()
+}
+
+/// Appends the samples of track `track_id` in the fragment `moof` to
+/// `samples`. `timestamp` is the timestamp of the next sample of the track,
+/// used if the fragment has no `tfdt` box.
+fn parse_fragment(
+    moof: &Mp4Box,
+    track_id: u32,
+    trex: SampleDefaults,
+    samples: &mut Vec<SampleRef>,
+    timestamp: &mut u64,
+) -> anyhow::Result<()> {
+    for traf in read_boxes(moof.data)?
+        .iter()
+        .filter(|b| &b.fourcc == b"traf")
+    {
+        let traf = read_boxes(traf.data)?;
+
+        let mut reader = ByteReader::new(expect_box(&traf, b"tfhd")?);
+        let (_, flags) = read_full_box_header(&mut reader)?;
+        if reader.u32()? != track_id {
+            continue;
+        }
+        // Without an explicit base data offset, use the start of the moof
+        // box as done by the default-base-is-moof flag.
+        let base_offset = match flags & TFHD_BASE_DATA_OFFSET {
+            0 => moof.start as u64,
+            _ => reader.u64()?,
+        };
+        if flags & TFHD_SAMPLE_DESCRIPTION_INDEX != 0 {
+            reader.skip(4)?;
+        }
+        let mut defaults = trex;
+        if flags & TFHD_DEFAULT_SAMPLE_DURATION != 0 {
+            defaults.duration = reader.u32()?;
+        }
+        if flags & TFHD_DEFAULT_SAMPLE_SIZE != 0 {
+            defaults.size = reader.u32()?;
+        }
+        if flags & TFHD_DEFAULT_SAMPLE_FLAGS != 0 {
+            defaults.flags = reader.u32()?;
+        }
+
+        if let Some(tfdt) = find_box(&traf, b"tfdt") {
+            let mut reader = ByteReader::new(tfdt);
+            *timestamp = match read_full_box_header(&mut reader)?.0 {
+                1 => reader.u64()?,
+                _ => u64::from(reader.u32()?),
+            };
+        }
+
+        let mut offset = base_offset;
+        for trun in traf.iter().filter(|b| &b.fourcc == b"trun") {
+            let mut reader = ByteReader::new(trun.data);
+            let (_, flags) = read_full_box_header(&mut reader)?;
+            let sample_count = reader.u32()?;
+            if flags & TRUN_DATA_OFFSET != 0 {
+                let data_offset = reader.u32()? as i32;
+                offset = base_offset
+                    .checked_add_signed(i64::from(data_offset))
+                    .ok_or(anyhow!("Invalid data offset {}", data_offset))?;
+            }
+            let first_sample_flags = match flags & TRUN_FIRST_SAMPLE_FLAGS {
+                0 => None,
+                _ => Some(reader.u32()?),
+            };
+
+            for i in 0..sample_count {
+                let mut read_or_default = |flag: u32, default: u32| match flags & flag {
+                    0 => Ok(default),
+                    _ => reader.u32(),
+                };
+                let duration = read_or_default(TRUN_SAMPLE_DURATION, defaults.duration)?;
+                let size = read_or_default(TRUN_SAMPLE_SIZE, defaults.size)?;
+                let sample_flags = match first_sample_flags {
+                    Some(first) if i == 0 => read_or_default(TRUN_SAMPLE_FLAGS, first)?,
+                    _ => read_or_default(TRUN_SAMPLE_FLAGS, defaults.flags)?,
+                };
+                if flags & TRUN_SAMPLE_COMPOSITION_TIME_OFFSET != 0 {
+                    reader.skip(4)?;
+                }
+
+                samples.push(SampleRef {
+                    offset: usize::try_from(offset)?,
+                    size: size as usize,
+                    timestamp: *timestamp,
+                    keyframe: sample_flags & SAMPLE_FLAGS_IS_NON_SYNC == 0,
+                });
+                offset += u64::from(size);
+                *timestamp += u64::from(duration);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Returns the first video track of the MP4 file `data`, with the samples
+/// of both its sample table and its fragments, if any.
+pub(super) fn demux(data: &[u8]) -> anyhow::Result<Track> {
+    let top = read_boxes(data)?;
+    let moov = read_boxes(expect_box(&top, b"moov")?)?;
+
+    let mut video_track = None;
+    for trak in moov.iter().filter(|b| &b.fourcc == b"trak") {
+        let trak = read_boxes(trak.data)?;
+        let mdia = read_boxes(expect_box(&trak, b"mdia")?)?;
+
+        // pre_defined, then handler_type.
+        let mut reader = ByteReader::new(expect_box(&mdia, b"hdlr")?);
+        reader.skip(8)?;
+        if reader.bytes(4)? == b"vide" {
+            video_track = Some((expect_box(&trak, b"tkhd")?, mdia));
+            break;
+        }
+    }
+    let (tkhd, mdia) = video_track.ok_or(anyhow!("No video track"))?;
+
+    // creation_time and modification_time, then track_ID.
+    let mut reader = ByteReader::new(tkhd);
+    let (version, _) = read_full_box_header(&mut reader)?;
+    reader.skip(if version == 1 { 16 } else { 8 })?;
+    let track_id = reader.u32()?;
+
+    // creation_time and modification_time, then timescale.
+    let mut reader = ByteReader::new(expect_box(&mdia, b"mdhd")?);
+    let (version, _) = read_full_box_header(&mut reader)?;
+    reader.skip(if version == 1 { 16 } else { 8 })?;
+    let timescale = u64::from(reader.u32()?);
+
+    let minf = read_boxes(expect_box(&mdia, b"minf")?)?;
+    let stbl = read_boxes(expect_box(&minf, b"stbl")?)?;
+    let (codec, resolution, config) = parse_sample_entry(expect_box(&stbl, b"stsd")?)?;
+    let mut samples = parse_sample_table(&stbl)?;
+
+    let mut trex = SampleDefaults::default();
+    if let Some(mvex) = find_box(&moov, b"mvex") {
+        for entry in read_boxes(mvex)?.iter().filter(|b| &b.fourcc == b"trex") {
+            // track_ID, default_sample_description_index, then the defaults.
+            let mut reader = ByteReader::new(entry.data);
+            reader.skip(4)?;
+            if reader.u32()? == track_id {
+                reader.skip(4)?;
+                trex = SampleDefaults {
+                    duration: reader.u32()?,
+                    size: reader.u32()?,
+                    flags: reader.u32()?,
+                };
+            }
+        }
+    }
+
+    let mut timestamp = samples.last().map_or(0, |s| s.timestamp);
+    for moof in top.iter().filter(|b| &b.fourcc == b"moof") {
+        parse_fragment(moof, track_id, trex, &mut samples, &mut timestamp)?;
+    }
+
+    Ok(Track {
+        codec,
+        resolution,
+        timescale,
+        config,
+        samples,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec::h264::nalu::split_annexb;
+    use crate::encoder::FrameMetadata;
+    use crate::Fourcc;
+    use crate::FrameLayout;
+
+    const STREAM_H264: &[u8] = include_bytes!("../codec/h264/test_data/64x64-I-P.h264");
+    const STREAM_H265: &[u8] = include_bytes!("../codec/h265/test_data/64x64-I-P.h265");
+
+    /// Splits `stream` into access units, as they would be output by an
+    /// encoder.
+    fn access_units(codec: Mp4Codec, stream: &[u8]) -> Vec<Vec<u8>> {
+        let mut units: Vec<Vec<u8>> = vec![];
+        let mut seen_slice = false;
+
+        for nalu in split_annexb(stream) {
+            let (is_slice, first_slice) = match codec {
+                // first_mb_in_slice equal to 0.
+                Mp4Codec::H264 => (matches!(nalu[0] & 0x1f, 1 | 5), nalu[1] & 0x80 != 0),
+                // first_slice_segment_in_pic_flag
+                Mp4Codec::H265 => ((nalu[0] >> 1) & 0x3f < 32, nalu[2] & 0x80 != 0),
+            };
+
+            if units.is_empty() || (seen_slice && (!is_slice || first_slice)) {
+                units.push(vec![]);
+                seen_slice = false;
+            }
+            seen_slice |= is_slice;
+
+            let unit = units.last_mut().unwrap();
+            unit.extend_from_slice(&[0, 0, 0, 1]);
+            unit.extend_from_slice(&nalu);
+        }
+
+        units
+    }
+
+    /// Returns the type and contents of the boxes of `data`.
+    fn boxes(data: &[u8]) -> Vec<(&[u8], &[u8])> {
+        let mut boxes = vec![];
+        let mut pos = 0;
+
+        while pos < data.len() {
+            let size = u32::from_be_bytes(data[pos..pos + 4].try_into().unwrap()) as usize;
+            boxes.push((&data[pos + 4..pos + 8], &data[pos + 8..pos + size]));
+            pos += size;
+        }
+        assert_eq!(pos, data.len());
+
+        boxes
+    }
+
+    /// Returns the contents of the box found by following `path`.
+    fn find<'a>(data: &'a [u8], path: &[&[u8; 4]]) -> &'a [u8] {
+        path.iter().fold(data, |data, fourcc| {
+            boxes(data)
+                .into_iter()
+                .find(|(t, _)| *t == &fourcc[..])
+                .unwrap_or_else(|| panic!("no {:?} box", std::str::from_utf8(&fourcc[..])))
+                .1
+        })
+    }
+
+    fn u32_at(data: &[u8], pos: usize) -> u32 {
+        u32::from_be_bytes(data[pos..pos + 4].try_into().unwrap())
+    }
+
+    fn mux(codec: Mp4Codec, stream: &[u8]) {
+        // Mux the stream twice to get several fragments.
+        let units = access_units(codec, stream);
+        let units = [units.clone(), units].concat();
+        let mut muxer = Fmp4Muxer::new(codec, Resolution::from((64, 64)), 90000).unwrap();
+        assert!(muxer.init_segment().is_err());
+
+        let mut fragments = vec![];
+        for (i, unit) in units.iter().enumerate() {
+            let buffer = CodedBitstreamBuffer::new(
+                FrameMetadata {
+                    timestamp: 3000 * i as u64,
+                    display_resolution: Resolution::from((64, 64)),
+                    layout: FrameLayout {
+                        format: (Fourcc::from(b"NV12"), 0),
+                        size: Resolution::from((64, 64)),
+                        planes: vec![],
+                    },
+                    force_keyframe: false,
+                    damage: None,
+                },
+                unit.clone(),
+            );
+            fragments.extend(muxer.push(&buffer).unwrap());
+        }
+        fragments.extend(muxer.flush().unwrap());
+        assert!(muxer.flush().unwrap().is_none());
+
+        let init = muxer.init_segment().unwrap();
+        let top = boxes(&init);
+        assert_eq!(top[0].0, b"ftyp");
+        assert_eq!(top[1].0, b"moov");
+
+        let stsd = find(
+            &init,
+            &[b"moov", b"trak", b"mdia", b"minf", b"stbl", b"stsd"],
+        );
+        // Skip the version, flags and entry_count, then the sample entry
+        // fields before the configuration box.
+        let entry = boxes(&stsd[8..]);
+        let config = find(
+            &entry[0].1[78..],
+            &[match codec {
+                Mp4Codec::H264 => b"avcC",
+                Mp4Codec::H265 => b"hvcC",
+            }],
+        );
+        let parameter_sets = match codec {
+            Mp4Codec::H264 => {
+                assert_eq!(entry[0].0, b"avc1");
+                AvcDecoderConfigurationRecord::parse(config)
+                    .unwrap()
+                    .to_annexb()
+            }
+            Mp4Codec::H265 => {
+                assert_eq!(entry[0].0, b"hvc1");
+                HevcDecoderConfigurationRecord::parse(config)
+                    .unwrap()
+                    .to_annexb()
+            }
+        };
+        assert!(!parameter_sets.is_empty());
+
+        // Rebuild the stream from the fragments.
+        let mut samples = vec![];
+        for (sequence_number, fragment) in fragments.iter().enumerate() {
+            let top = boxes(fragment);
+            assert_eq!(top.len(), 2);
+            let (moof, mdat) = (top[0].1, top[1].1);
+            assert_eq!(
+                u32_at(find(moof, &[b"mfhd"]), 4),
+                sequence_number as u32 + 1
+            );
+
+            let trun = find(moof, &[b"traf", b"trun"]);
+            let sample_count = u32_at(trun, 4) as usize;
+            assert_eq!(u32_at(trun, 8) as usize, moof.len() + 16);
+
+            let mut offset = 0;
+            for i in 0..sample_count {
+                let entry = &trun[12 + 12 * i..];
+                assert_eq!(u32_at(entry, 0), 3000);
+                let size = u32_at(entry, 4) as usize;
+                // Each fragment starts with a sync sample.
+                let sync = u32_at(entry, 8) == SAMPLE_FLAGS_SYNC;
+                assert_eq!(sync, i == 0);
+
+                samples.push(&mdat[offset..offset + size]);
+                offset += size;
+            }
+            assert_eq!(offset, mdat.len());
+        }
+        assert_eq!(samples.len(), units.len());
+
+        for (sample, unit) in samples.iter().zip(&units) {
+            let nalus = split_annexb(unit)
+                .into_iter()
+                .filter(|nalu| !codec.is_out_of_band(nalu[0]))
+                .collect::<Vec<_>>();
+            let annexb = crate::codec::h264::nalu::length_prefixed_to_annexb(sample, 4).unwrap();
+            assert_eq!(split_annexb(&annexb), nalus);
+        }
+    }
+
+    #[test]
+    fn mux_h264() {
+        mux(Mp4Codec::H264, STREAM_H264);
+    }
+
+    #[test]
+    fn mux_h265() {
+        mux(Mp4Codec::H265, STREAM_H265);
+    }
+}
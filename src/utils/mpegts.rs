@@ -0,0 +1,572 @@
+// Copyright 2024 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Minimal MPEG-2 transport stream packetizer for H.264 and H.265 encoder
+//! output.
+//!
+//! [`TsMuxer`] writes a single program with a single video elementary stream.
+//! The PAT and PMT are repeated before each keyframe so that receivers can
+//! join the stream at any random access point, and the PCR is carried in the
+//! video PID.
+
+use std::collections::HashMap;
+use std::io::Write;
+
+use anyhow::anyhow;
+use bytes::BufMut;
+
+use crate::encoder::CodedBitstreamBuffer;
+use crate::utils::mp4::Mp4Codec;
+
+/// Size of a transport stream packet.
+pub const TS_PACKET_SIZE: usize = 188;
+
+/// Frequency of the PTS and DTS clock.
+pub const TS_CLOCK_HZ: u64 = 90_000;
+
+const SYNC_BYTE: u8 = 0x47;
+const PAT_PID: u16 = 0x0000;
+const PMT_PID: u16 = 0x1000;
+const VIDEO_PID: u16 = 0x0100;
+const TRANSPORT_STREAM_ID: u16 = 1;
+const PROGRAM_NUMBER: u16 = 1;
+const VIDEO_STREAM_ID: u8 = 0xe0;
+
+/// Table IDs of the program association and program map sections.
+const TABLE_ID_PAT: u8 = 0x00;
+const TABLE_ID_PMT: u8 = 0x02;
+
+/// Mask of the 33 bits PTS and DTS.
+const TIMESTAMP_MASK: u64 = (1 << 33) - 1;
+
+/// Codec of the video elementary stream.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TsCodec {
+    H264,
+    H265,
+}
+
+impl TsCodec {
+    /// Returns the `stream_type` of the elementary stream in the PMT.
+    fn stream_type(self) -> u8 {
+        match self {
+            TsCodec::H264 => 0x1b,
+            TsCodec::H265 => 0x24,
+        }
+    }
+
+    /// Returns the access unit delimiter to insert before access units that
+    /// lack one, allowing any picture type.
+    fn access_unit_delimiter(self) -> &'static [u8] {
+        match self {
+            TsCodec::H264 => &[0x00, 0x00, 0x00, 0x01, 0x09, 0xf0],
+            TsCodec::H265 => &[0x00, 0x00, 0x00, 0x01, 0x46, 0x01, 0x50],
+        }
+    }
+
+    fn nal_codec(self) -> Mp4Codec {
+        match self {
+            TsCodec::H264 => Mp4Codec::H264,
+            TsCodec::H265 => Mp4Codec::H265,
+        }
+    }
+
+    /// Returns whether the NAL unit starting with `header` is an access unit
+    /// delimiter.
+    fn is_access_unit_delimiter(self, header: u8) -> bool {
+        match self {
+            TsCodec::H264 => header & 0x1f == 9,
+            TsCodec::H265 => (header >> 1) & 0x3f == 35,
+        }
+    }
+}
+
+/// Computes the CRC32 of PSI sections, as per Annex A of ITU-T H.222.0.
+fn crc32_mpeg2(data: &[u8]) -> u32 {
+    let mut crc = 0xffff_ffffu32;
+
+    for &byte in data {
+        crc ^= u32::from(byte) << 24;
+        for _ in 0..8 {
+            crc = if crc & 0x8000_0000 != 0 {
+                (crc << 1) ^ 0x04c1_1db7
+            } else {
+                crc << 1
+            };
+        }
+    }
+
+    crc
+}
+
+/// Writes a 33 bits PTS or DTS with its 4 bits `prefix` and marker bits.
+fn put_timestamp(out: &mut Vec<u8>, prefix: u8, timestamp: u64) {
+    let timestamp = timestamp & TIMESTAMP_MASK;
+
+    out.put_u8(prefix << 4 | ((timestamp >> 29) as u8 & 0x0e) | 1);
+    out.put_u16(((timestamp >> 14) as u16 & 0xfffe) | 1);
+    out.put_u16(((timestamp << 1) as u16 & 0xfffe) | 1);
+}
+
+/// Packetizer of an H.264 or H.265 elementary stream into a transport stream.
+///
+/// Each [`CodedBitstreamBuffer`] is written as one PES packet, with its
+/// [`FrameMetadata::timestamp`](crate::encoder::FrameMetadata) as PTS and the
+/// DTS computed by the caller, both in units of [`TS_CLOCK_HZ`]. The DTS
+/// differs from the PTS only when frames are reordered, e.g. with B frames.
+pub struct TsMuxer<W: Write> {
+    writer: W,
+    codec: TsCodec,
+    /// Continuity counter of each PID.
+    continuity_counters: HashMap<u16, u8>,
+    last_dts: Option<u64>,
+}
+
+impl<W: Write> TsMuxer<W> {
+    pub fn new(writer: W, codec: TsCodec) -> Self {
+        Self {
+            writer,
+            codec,
+            continuity_counters: Default::default(),
+            last_dts: None,
+        }
+    }
+
+    /// Writes the access unit of `buffer`, to be decoded at `dts`.
+    pub fn write_frame(&mut self, buffer: &CodedBitstreamBuffer, dts: u64) -> anyhow::Result<()> {
+        let pts = buffer.metadata.timestamp;
+        if dts > pts {
+            return Err(anyhow!("DTS {} is after PTS {}", dts, pts));
+        }
+        if let Some(last_dts) = self.last_dts {
+            if dts <= last_dts {
+                return Err(anyhow!(
+                    "DTS {} is not after the previous one {}",
+                    dts,
+                    last_dts
+                ));
+            }
+        }
+
+        let codec = self.codec.nal_codec();
+        let nalus = match self.codec {
+            TsCodec::H264 => buffer.h264_nal_units(),
+            TsCodec::H265 => buffer.h265_nal_units(),
+        };
+        let mut keyframe = false;
+        let mut first_nalu = None;
+        for nalu in nalus {
+            let header = nalu.data[0];
+            first_nalu.get_or_insert(header);
+            keyframe |= codec.is_keyframe(header);
+        }
+        let Some(first_nalu) = first_nalu else {
+            return Err(anyhow!("The access unit has no NAL unit"));
+        };
+
+        if self.last_dts.is_none() && !keyframe {
+            return Err(anyhow!("The first access unit must be a keyframe"));
+        }
+
+        if keyframe {
+            self.write_psi()?;
+        }
+
+        // Access unit delimiters are mandatory in transport streams.
+        let mut es = Vec::with_capacity(buffer.bitstream.len() + 8);
+        if !self.codec.is_access_unit_delimiter(first_nalu) {
+            es.put_slice(self.codec.access_unit_delimiter());
+        }
+        es.put_slice(&buffer.bitstream);
+
+        let pes = Self::pes_packet(&es, pts, dts);
+        self.write_packets(VIDEO_PID, &pes, Some(dts), keyframe)?;
+
+        self.last_dts = Some(dts);
+
+        Ok(())
+    }
+
+    /// Flushes and returns the underlying writer.
+    pub fn finish(mut self) -> anyhow::Result<W> {
+        self.writer.flush()?;
+
+        Ok(self.writer)
+    }
+
+    /// Builds the PES packet of the elementary stream data `es`.
+    fn pes_packet(es: &[u8], pts: u64, dts: u64) -> Vec<u8> {
+        let with_dts = pts != dts;
+        let header_data_length: u8 = if with_dts { 10 } else { 5 };
+
+        let mut pes = Vec::with_capacity(es.len() + 19);
+        pes.put_slice(&[0x00, 0x00, 0x01, VIDEO_STREAM_ID]);
+        // PES_packet_length counts the bytes after it. A length of 0 is
+        // allowed for video streams when it would not fit.
+        let length = 3 + usize::from(header_data_length) + es.len();
+        pes.put_u16(u16::try_from(length).unwrap_or(0));
+        // '10', not scrambled, no priority, data_alignment_indicator set.
+        pes.put_u8(0x84);
+        pes.put_u8(if with_dts { 0xc0 } else { 0x80 });
+        pes.put_u8(header_data_length);
+        if with_dts {
+            put_timestamp(&mut pes, 0b0011, pts);
+            put_timestamp(&mut pes, 0b0001, dts);
+        } else {
+            put_timestamp(&mut pes, 0b0010, pts);
+        }
+        pes.put_slice(es);
+
+        pes
+    }
+
+    /// Writes the PAT and PMT.
+    fn write_psi(&mut self) -> anyhow::Result<()> {
+        let mut pat = Vec::new();
+        pat.put_u16(PROGRAM_NUMBER);
+        pat.put_u16(0xe000 | PMT_PID);
+        let pat = Self::psi_section(TABLE_ID_PAT, TRANSPORT_STREAM_ID, &pat);
+        self.write_packets(PAT_PID, &pat, None, false)?;
+
+        let mut pmt = Vec::new();
+        // PCR_PID, then no program_info.
+        pmt.put_u16(0xe000 | VIDEO_PID);
+        pmt.put_u16(0xf000);
+        pmt.put_u8(self.codec.stream_type());
+        pmt.put_u16(0xe000 | VIDEO_PID);
+        // No ES_info.
+        pmt.put_u16(0xf000);
+        let pmt = Self::psi_section(TABLE_ID_PMT, PROGRAM_NUMBER, &pmt);
+        self.write_packets(PMT_PID, &pmt, None, false)?;
+
+        Ok(())
+    }
+
+    /// Builds a PSI section with its pointer field, for a table fitting in a
+    /// single section.
+    fn psi_section(table_id: u8, table_id_extension: u16, data: &[u8]) -> Vec<u8> {
+        let mut section = Vec::with_capacity(data.len() + 13);
+        // pointer_field.
+        section.put_u8(0);
+        section.put_u8(table_id);
+        // section_syntax_indicator, '0', reserved, then section_length which
+        // includes the header after it and the CRC.
+        section.put_u16(0xb000 | (5 + data.len() + 4) as u16);
+        section.put_u16(table_id_extension);
+        // reserved, version_number 0, current_next_indicator set.
+        section.put_u8(0xc1);
+        // section_number and last_section_number.
+        section.put_u16(0);
+        section.put_slice(data);
+        let crc = crc32_mpeg2(&section[1..]);
+        section.put_u32(crc);
+
+        section
+    }
+
+    /// Splits `payload` into transport stream packets of `pid`. The first
+    /// packet carries the PCR if `pcr` is set, and the random access
+    /// indicator if `random_access` is set.
+    fn write_packets(
+        &mut self,
+        pid: u16,
+        payload: &[u8],
+        pcr: Option<u64>,
+        random_access: bool,
+    ) -> anyhow::Result<()> {
+        let mut out =
+            Vec::with_capacity((payload.len() / (TS_PACKET_SIZE - 4) + 1) * TS_PACKET_SIZE);
+        let mut remaining = payload;
+        let mut first = true;
+
+        while first || !remaining.is_empty() {
+            let counter = self.continuity_counters.entry(pid).or_insert(0);
+            let continuity_counter = *counter;
+            *counter = (*counter + 1) & 0xf;
+
+            let mut adaptation_field = Vec::new();
+            if first && (pcr.is_some() || random_access) {
+                let mut flags = 0u8;
+                if random_access {
+                    flags |= 0x40;
+                }
+                if pcr.is_some() {
+                    flags |= 0x10;
+                }
+                adaptation_field.put_u8(flags);
+                if let Some(pcr) = pcr {
+                    // program_clock_reference_base, reserved, then a zero
+                    // extension as the PCR is derived from the 90 kHz DTS.
+                    let pcr = pcr & TIMESTAMP_MASK;
+                    adaptation_field.put_u32((pcr >> 1) as u32);
+                    adaptation_field.put_u8(((pcr & 1) as u8) << 7 | 0x7e);
+                    adaptation_field.put_u8(0);
+                }
+            }
+
+            // Stuff the adaptation field if the payload does not fill the
+            // packet.
+            let available = TS_PACKET_SIZE - 4;
+            let header_len = if adaptation_field.is_empty() {
+                0
+            } else {
+                1 + adaptation_field.len()
+            };
+            let payload_len = std::cmp::min(remaining.len(), available - header_len);
+            let stuffing = available - header_len - payload_len;
+            if stuffing > 0 {
+                if adaptation_field.is_empty() {
+                    // A single byte adaptation field is just its length.
+                    if stuffing > 1 {
+                        adaptation_field.put_u8(0);
+                    }
+                    adaptation_field.put_bytes(0xff, stuffing.saturating_sub(2));
+                } else {
+                    adaptation_field.put_bytes(0xff, stuffing);
+                }
+            }
+            let has_adaptation_field = header_len + stuffing > 0;
+
+            out.put_u8(SYNC_BYTE);
+            out.put_u16(if first { 0x4000 } else { 0 } | pid);
+            let adaptation_field_control = if has_adaptation_field { 0x30 } else { 0x10 };
+            out.put_u8(adaptation_field_control | continuity_counter);
+            if has_adaptation_field {
+                out.put_u8(adaptation_field.len() as u8);
+                out.put_slice(&adaptation_field);
+            }
+            out.put_slice(&remaining[..payload_len]);
+
+            remaining = &remaining[payload_len..];
+            first = false;
+        }
+
+        self.writer.write_all(&out)?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec::h264::nalu::split_annexb;
+    use crate::encoder::FrameMetadata;
+    use crate::Fourcc;
+    use crate::FrameLayout;
+    use crate::Resolution;
+
+    const STREAM_H264: &[u8] = include_bytes!("../codec/h264/test_data/64x64-I-P.h264");
+    const STREAM_H265: &[u8] = include_bytes!("../codec/h265/test_data/64x64-I-P.h265");
+
+    /// Splits `stream` into access units, as they would be output by an
+    /// encoder.
+    fn access_units(codec: TsCodec, stream: &[u8]) -> Vec<Vec<u8>> {
+        let mut units: Vec<Vec<u8>> = vec![];
+        let mut seen_slice = false;
+
+        for nalu in split_annexb(stream) {
+            let (is_slice, first_slice) = match codec {
+                // first_mb_in_slice equal to 0.
+                TsCodec::H264 => (matches!(nalu[0] & 0x1f, 1 | 5), nalu[1] & 0x80 != 0),
+                // first_slice_segment_in_pic_flag
+                TsCodec::H265 => ((nalu[0] >> 1) & 0x3f < 32, nalu[2] & 0x80 != 0),
+            };
+
+            if units.is_empty() || (seen_slice && (!is_slice || first_slice)) {
+                units.push(vec![]);
+                seen_slice = false;
+            }
+            seen_slice |= is_slice;
+
+            let unit = units.last_mut().unwrap();
+            unit.extend_from_slice(&[0, 0, 0, 1]);
+            unit.extend_from_slice(&nalu);
+        }
+
+        units
+    }
+
+    fn buffer(timestamp: u64, bitstream: &[u8]) -> CodedBitstreamBuffer {
+        CodedBitstreamBuffer::new(
+            FrameMetadata {
+                timestamp,
+                display_resolution: Resolution::from((64, 64)),
+                layout: FrameLayout {
+                    format: (Fourcc::from(b"NV12"), 0),
+                    size: Resolution::from((64, 64)),
+                    planes: vec![],
+                },
+                force_keyframe: false,
+                damage: None,
+            },
+            bitstream.to_vec(),
+        )
+    }
+
+    fn read_timestamp(data: &[u8]) -> u64 {
+        u64::from(data[0] & 0x0e) << 29
+            | u64::from(u16::from_be_bytes([data[1], data[2]]) >> 1) << 15
+            | u64::from(u16::from_be_bytes([data[3], data[4]]) >> 1)
+    }
+
+    /// A PES packet reassembled from the transport stream.
+    struct Pes {
+        pts: u64,
+        dts: u64,
+        pcr: Option<u64>,
+        random_access: bool,
+        es: Vec<u8>,
+    }
+
+    /// Checks the packets of `data` and returns the PES packets of the video
+    /// PID, and the number of PAT and PMT.
+    fn demux(codec: TsCodec, data: &[u8]) -> (Vec<Pes>, usize) {
+        assert_eq!(data.len() % TS_PACKET_SIZE, 0);
+
+        let mut payloads: Vec<(u16, Option<u64>, bool, Vec<u8>)> = vec![];
+        let mut counters = HashMap::new();
+        for packet in data.chunks(TS_PACKET_SIZE) {
+            assert_eq!(packet[0], SYNC_BYTE);
+            let pid = u16::from_be_bytes([packet[1], packet[2]]) & 0x1fff;
+            let payload_unit_start = packet[1] & 0x40 != 0;
+            let counter = packet[3] & 0xf;
+            if let Some(previous) = counters.insert(pid, counter) {
+                assert_eq!(counter, (previous + 1) & 0xf);
+            }
+
+            let mut pos = 4;
+            let mut pcr = None;
+            let mut random_access = false;
+            if packet[3] & 0x20 != 0 {
+                let length = usize::from(packet[4]);
+                if length > 0 {
+                    random_access = packet[5] & 0x40 != 0;
+                    if packet[5] & 0x10 != 0 {
+                        let base = u64::from(u32::from_be_bytes(packet[6..10].try_into().unwrap()));
+                        pcr = Some(base << 1 | u64::from(packet[10] >> 7));
+                    }
+                }
+                pos += 1 + length;
+            }
+            assert_ne!(packet[3] & 0x10, 0);
+
+            if payload_unit_start {
+                payloads.push((pid, pcr, random_access, vec![]));
+            } else {
+                assert!(pcr.is_none() && !random_access);
+            }
+            let payload = payloads.iter_mut().rev().find(|p| p.0 == pid).unwrap();
+            payload.3.extend_from_slice(&packet[pos..]);
+        }
+
+        let mut pes_packets = vec![];
+        let mut psi_count = 0;
+        for (pid, pcr, random_access, payload) in payloads {
+            match pid {
+                PAT_PID | PMT_PID => {
+                    assert_eq!(payload[0], 0);
+                    let length = usize::from(u16::from_be_bytes([payload[2], payload[3]]) & 0xfff);
+                    let section = &payload[1..4 + length];
+                    // The CRC of a section including its CRC is 0.
+                    assert_eq!(crc32_mpeg2(section), 0);
+                    if pid == PMT_PID {
+                        assert_eq!(section[0], TABLE_ID_PMT);
+                        assert_eq!(section[12], codec.stream_type());
+                    } else {
+                        assert_eq!(section[0], TABLE_ID_PAT);
+                        assert_eq!(
+                            u16::from_be_bytes([section[10], section[11]]),
+                            0xe000 | PMT_PID
+                        );
+                    }
+                    psi_count += 1;
+                }
+                VIDEO_PID => {
+                    assert_eq!(payload[..4], [0, 0, 1, VIDEO_STREAM_ID]);
+                    let length = usize::from(u16::from_be_bytes([payload[4], payload[5]]));
+                    assert_eq!(length, payload.len() - 6);
+                    let header_data_length = usize::from(payload[8]);
+                    let pts = read_timestamp(&payload[9..]);
+                    let dts = match payload[7] {
+                        0xc0 => read_timestamp(&payload[14..]),
+                        0x80 => pts,
+                        flags => panic!("unexpected PES flags {:#x}", flags),
+                    };
+                    pes_packets.push(Pes {
+                        pts,
+                        dts,
+                        pcr,
+                        random_access,
+                        es: payload[9 + header_data_length..].to_vec(),
+                    });
+                }
+                pid => panic!("unexpected PID {:#x}", pid),
+            }
+        }
+
+        (pes_packets, psi_count)
+    }
+
+    fn mux(codec: TsCodec, stream: &[u8], delay: u64) {
+        // Mux the stream twice to get several keyframes.
+        let units = access_units(codec, stream);
+        let units = [units.clone(), units].concat();
+
+        let mut muxer = TsMuxer::new(Vec::new(), codec);
+        for (i, unit) in units.iter().enumerate() {
+            let dts = 3000 * i as u64;
+            muxer.write_frame(&buffer(dts + delay, unit), dts).unwrap();
+        }
+        let data = muxer.finish().unwrap();
+
+        let (pes_packets, psi_count) = demux(codec, &data);
+        assert_eq!(pes_packets.len(), units.len());
+        // One PAT and PMT per keyframe.
+        assert_eq!(psi_count, 4);
+
+        for (i, (pes, unit)) in pes_packets.iter().zip(&units).enumerate() {
+            let dts = 3000 * i as u64;
+            assert_eq!((pes.pts, pes.dts, pes.pcr), (dts + delay, dts, Some(dts)));
+            assert_eq!(pes.random_access, i % (units.len() / 2) == 0);
+
+            // An access unit delimiter is prepended if missing.
+            let es = &pes.es[..];
+            let es = es.strip_prefix(codec.access_unit_delimiter()).unwrap_or(es);
+            assert_eq!(es, unit);
+            let first_nalu = split_annexb(&pes.es).remove(0);
+            assert!(codec.is_access_unit_delimiter(first_nalu[0]));
+        }
+    }
+
+    #[test]
+    fn mux_h264() {
+        mux(TsCodec::H264, STREAM_H264, 0);
+    }
+
+    #[test]
+    fn mux_h265() {
+        mux(TsCodec::H265, STREAM_H265, 6000);
+    }
+
+    #[test]
+    fn crc() {
+        assert_eq!(crc32_mpeg2(b"123456789"), 0x0376_e6e7);
+    }
+
+    #[test]
+    fn invalid_frames() {
+        let units = access_units(TsCodec::H264, STREAM_H264);
+        let mut muxer = TsMuxer::new(Vec::new(), TsCodec::H264);
+
+        // The stream must start with a keyframe.
+        assert!(muxer.write_frame(&buffer(0, &units[1]), 0).is_err());
+        assert!(muxer.write_frame(&buffer(0, &[]), 0).is_err());
+        // DTS cannot be after PTS.
+        assert!(muxer.write_frame(&buffer(0, &units[0]), 1).is_err());
+        muxer.write_frame(&buffer(3000, &units[0]), 0).unwrap();
+        // DTS must increase.
+        assert!(muxer.write_frame(&buffer(3000, &units[1]), 0).is_err());
+    }
+}
@@ -0,0 +1,272 @@
+// Copyright 2024 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Decoded picture hashes, allowing to check that a decoder output is bit-exact with the
+//! reconstructed picture of the encoder.
+//!
+//! The hashes are computed as specified for the decoded picture hash SEI message of H.265 (D.3.19),
+//! over each component of the picture. H.265 carries them in the message of the same name, while
+//! this crate carries them in a user data unregistered SEI message identified by
+//! [`H264_USER_DATA_UUID`] for H.264, which has no equivalent message.
+//!
+//! Frames are described as for [`crate::utils::quality`], and the same formats are supported.
+
+use anyhow::anyhow;
+use enumn::N;
+
+use crate::utils::quality::split_planes;
+use crate::utils::quality::Plane;
+use crate::FrameLayout;
+
+/// UUID of the user data unregistered SEI messages carrying a [`PictureHash`] in H.264 streams.
+pub const H264_USER_DATA_UUID: [u8; 16] = [
+    0x2d, 0x8f, 0x4b, 0x6e, 0x91, 0x3a, 0x4c, 0x27, 0xb5, 0x60, 0x1e, 0xd9, 0x7c, 0x03, 0xa4, 0x58,
+];
+
+/// The `hash_type` of a decoded picture hash, see H.265 D.3.19.
+#[derive(N, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PictureHashType {
+    Md5 = 0,
+    Crc = 1,
+    Checksum = 2,
+}
+
+/// Hash of every component of a picture.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PictureHash {
+    Md5(Vec<[u8; 16]>),
+    Crc(Vec<u16>),
+    Checksum(Vec<u32>),
+}
+
+impl PictureHash {
+    /// Computes the hash of type `hash_type` of the frame `data` described by `layout`.
+    pub fn from_frame(
+        hash_type: PictureHashType,
+        data: &[u8],
+        layout: &FrameLayout,
+    ) -> anyhow::Result<Self> {
+        let (planes, bit_depth) = split_planes(data, layout)?;
+
+        Ok(match hash_type {
+            PictureHashType::Md5 => Self::Md5(
+                planes
+                    .iter()
+                    .map(|plane| md5::compute(picture_data(plane, bit_depth)).0)
+                    .collect(),
+            ),
+            PictureHashType::Crc => Self::Crc(
+                planes
+                    .iter()
+                    .map(|plane| crc(&picture_data(plane, bit_depth)))
+                    .collect(),
+            ),
+            PictureHashType::Checksum => Self::Checksum(
+                planes
+                    .iter()
+                    .map(|plane| checksum(plane, bit_depth))
+                    .collect(),
+            ),
+        })
+    }
+
+    /// Returns true if this is the hash of the frame `data` described by `layout`.
+    pub fn verify(&self, data: &[u8], layout: &FrameLayout) -> anyhow::Result<bool> {
+        Ok(Self::from_frame(self.hash_type(), data, layout)? == *self)
+    }
+
+    pub fn hash_type(&self) -> PictureHashType {
+        match self {
+            Self::Md5(_) => PictureHashType::Md5,
+            Self::Crc(_) => PictureHashType::Crc,
+            Self::Checksum(_) => PictureHashType::Checksum,
+        }
+    }
+
+    /// Returns the payload of the decoded picture hash SEI message carrying this hash.
+    pub fn payload(&self) -> Vec<u8> {
+        let mut payload = vec![self.hash_type() as u8];
+
+        match self {
+            Self::Md5(hashes) => hashes.iter().for_each(|h| payload.extend_from_slice(h)),
+            Self::Crc(hashes) => hashes
+                .iter()
+                .for_each(|h| payload.extend_from_slice(&h.to_be_bytes())),
+            Self::Checksum(hashes) => hashes
+                .iter()
+                .for_each(|h| payload.extend_from_slice(&h.to_be_bytes())),
+        }
+
+        payload
+    }
+
+    /// Parses the payload of a decoded picture hash SEI message. The number of components is
+    /// deduced from the size of the payload.
+    pub fn from_payload(payload: &[u8]) -> anyhow::Result<Self> {
+        let (&hash_type, hashes) = payload
+            .split_first()
+            .ok_or_else(|| anyhow!("empty decoded picture hash"))?;
+        let hash_type = PictureHashType::n(hash_type)
+            .ok_or_else(|| anyhow!("invalid decoded picture hash type {}", hash_type))?;
+
+        let hash_size = match hash_type {
+            PictureHashType::Md5 => 16,
+            PictureHashType::Crc => 2,
+            PictureHashType::Checksum => 4,
+        };
+        if !matches!(hashes.len() / hash_size, 1 | 3) || hashes.len() % hash_size != 0 {
+            return Err(anyhow!(
+                "invalid decoded picture hash size {} for type {:?}",
+                hashes.len(),
+                hash_type
+            ));
+        }
+
+        let hashes = hashes.chunks_exact(hash_size);
+        Ok(match hash_type {
+            PictureHashType::Md5 => Self::Md5(hashes.map(|h| h.try_into().unwrap()).collect()),
+            PictureHashType::Crc => {
+                Self::Crc(hashes.map(|h| u16::from_be_bytes([h[0], h[1]])).collect())
+            }
+            PictureHashType::Checksum => Self::Checksum(
+                hashes
+                    .map(|h| u32::from_be_bytes([h[0], h[1], h[2], h[3]]))
+                    .collect(),
+            ),
+        })
+    }
+}
+
+/// Returns the samples of `plane` in raster order, on one byte each or two little-endian bytes
+/// if `bit_depth` is above 8.
+fn picture_data(plane: &Plane, bit_depth: u32) -> Vec<u8> {
+    let sample_size = if bit_depth > 8 { 2 } else { 1 };
+    let mut data = Vec::with_capacity(plane.width * plane.height * sample_size);
+
+    for y in 0..plane.height {
+        for x in 0..plane.width {
+            let sample = plane.sample(x, y).to_le_bytes();
+            data.extend_from_slice(&sample[..sample_size]);
+        }
+    }
+
+    data
+}
+
+/// Returns the CRC of `data` as specified for the decoded picture hash SEI message, i.e. the
+/// CRC-16 of polynomial 0x1021 of `data` followed by two zero bytes, with an initial value of
+/// 0xffff.
+fn crc(data: &[u8]) -> u16 {
+    let mut crc = 0xffffu16;
+
+    for &byte in data.iter().chain(&[0, 0]) {
+        for bit in (0..8).rev() {
+            let msb = crc >> 15;
+            crc = ((crc << 1) | u16::from((byte >> bit) & 1)) ^ (msb * 0x1021);
+        }
+    }
+
+    crc
+}
+
+/// Returns the checksum of `plane` as specified for the decoded picture hash SEI message.
+fn checksum(plane: &Plane, bit_depth: u32) -> u32 {
+    let mut sum = 0u32;
+
+    for y in 0..plane.height {
+        for x in 0..plane.width {
+            let xor_mask = ((x & 0xff) ^ (y & 0xff) ^ (x >> 8) ^ (y >> 8)) as u32;
+            let sample = plane.sample(x, y);
+            sum = sum.wrapping_add((sample & 0xff) ^ xor_mask);
+            if bit_depth > 8 {
+                sum = sum.wrapping_add((sample >> 8) ^ xor_mask);
+            }
+        }
+    }
+
+    sum
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::quality::packed_layout;
+    use crate::Fourcc;
+    use crate::Resolution;
+
+    /// Returns a 4x2 NV12 frame, along with the same frame in I420.
+    fn frames() -> ((Vec<u8>, FrameLayout), (Vec<u8>, FrameLayout)) {
+        let size = Resolution {
+            width: 4,
+            height: 2,
+        };
+        let luma = [1, 2, 3, 4, 5, 6, 7, 8];
+
+        (
+            (
+                [&luma[..], &[10, 20, 11, 21]].concat(),
+                packed_layout(Fourcc::from(b"NV12"), size).unwrap(),
+            ),
+            (
+                [&luma[..], &[10, 11, 20, 21]].concat(),
+                packed_layout(Fourcc::from(b"I420"), size).unwrap(),
+            ),
+        )
+    }
+
+    #[test]
+    fn hash_types() {
+        let ((data, layout), (i420, i420_layout)) = frames();
+
+        let md5 = PictureHash::from_frame(PictureHashType::Md5, &data, &layout).unwrap();
+        let PictureHash::Md5(hashes) = &md5 else {
+            panic!("MD5 hash expected");
+        };
+        assert_eq!(hashes[0], md5::compute([1, 2, 3, 4, 5, 6, 7, 8]).0);
+        assert_eq!(hashes[2], md5::compute([20, 21]).0);
+
+        let crc = PictureHash::from_frame(PictureHashType::Crc, &data, &layout).unwrap();
+        assert_eq!(crc, PictureHash::Crc(vec![0x9795, 0xda60, 0x09e3]));
+
+        // The xor mask of each sample is x ^ y, so the luma samples are hashed as
+        // [1, 3, 1, 7, 4, 6, 4, 10].
+        let checksum = PictureHash::from_frame(PictureHashType::Checksum, &data, &layout).unwrap();
+        assert_eq!(
+            checksum,
+            PictureHash::Checksum(vec![36, 10 + (11 ^ 1), 20 + (21 ^ 1)])
+        );
+
+        // The hashes do not depend on the layout.
+        for hash in [md5, crc, checksum] {
+            assert!(hash.verify(&data, &layout).unwrap());
+            assert!(hash.verify(&i420, &i420_layout).unwrap());
+            assert!(!hash.verify(&vec![0; data.len()], &layout).unwrap());
+        }
+    }
+
+    #[test]
+    fn payload() {
+        let ((data, layout), _) = frames();
+
+        for hash_type in [
+            PictureHashType::Md5,
+            PictureHashType::Crc,
+            PictureHashType::Checksum,
+        ] {
+            let hash = PictureHash::from_frame(hash_type, &data, &layout).unwrap();
+            let payload = hash.payload();
+            assert_eq!(payload[0], hash_type as u8);
+            assert_eq!(PictureHash::from_payload(&payload).unwrap(), hash);
+            assert!(PictureHash::from_payload(&payload[..payload.len() - 1]).is_err());
+        }
+
+        // Monochrome pictures only have a luma hash.
+        assert_eq!(
+            PictureHash::from_payload(&[1, 0x12, 0x34]).unwrap(),
+            PictureHash::Crc(vec![0x1234])
+        );
+        assert!(PictureHash::from_payload(&[3, 0, 0]).is_err());
+        assert!(PictureHash::from_payload(&[]).is_err());
+    }
+}
@@ -0,0 +1,380 @@
+// Copyright 2024 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Detection of the codec and framing of an encoded stream.
+//!
+//! [`probe`] inspects the first bytes of a stream to tell MP4 and Matroska
+//! files, IVF files, H.264 and H.265 Annex B streams and AV1 low-overhead OBU
+//! streams apart. The returned [`ProbedStream`] can then split the stream into
+//! decoder input and create the matching stateless decoder, so the codec does
+//! not need to be known beforehand.
+
+use std::borrow::Cow;
+#[cfg(feature = "vaapi")]
+use std::rc::Rc;
+
+use anyhow::anyhow;
+
+#[cfg(feature = "vaapi")]
+use crate::backend::vaapi::decoder::VaapiBackend;
+use crate::codec::av1::annexb::read_leb128;
+use crate::codec::av1::parser::ObuType;
+use crate::codec::h264::parser::Nalu as H264Nalu;
+use crate::codec::h265::parser::Nalu as H265Nalu;
+use crate::decoder::stateless::av1::Av1;
+use crate::decoder::stateless::av1::StatelessAV1DecoderBackend;
+use crate::decoder::stateless::h264::StatelessH264DecoderBackend;
+use crate::decoder::stateless::h264::H264;
+use crate::decoder::stateless::h265::StatelessH265DecoderBackend;
+use crate::decoder::stateless::h265::H265;
+use crate::decoder::stateless::vp8::StatelessVp8DecoderBackend;
+use crate::decoder::stateless::vp8::Vp8;
+use crate::decoder::stateless::vp9::StatelessVp9DecoderBackend;
+use crate::decoder::stateless::vp9::Vp9;
+use crate::decoder::stateless::StatelessDecoder;
+use crate::decoder::stateless::StatelessVideoDecoder;
+use crate::decoder::stateless::TryFormat;
+use crate::decoder::BlockingMode;
+use crate::utils::demux::Container;
+use crate::utils::demux::Demuxer;
+use crate::utils::demux::TrackCodec;
+use crate::utils::IvfFileHeader;
+use crate::utils::IvfIterator;
+use crate::utils::NalIterator;
+
+/// Number of NAL units inspected to tell H.264 and H.265 Annex B streams
+/// apart.
+const MAX_PROBED_NALUS: usize = 16;
+
+/// How the frames of a stream are delimited.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StreamFraming {
+    /// MP4 or Matroska file.
+    Container(Container),
+    /// IVF file.
+    Ivf,
+    /// H.264 or H.265 Annex B stream, i.e. NAL units preceded by start codes.
+    AnnexB,
+    /// AV1 stream in the low-overhead bitstream format of section 5, i.e.
+    /// OBUs with their size field set.
+    Obu,
+}
+
+/// Codec and framing of a stream, as detected by [`probe`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ProbedStream {
+    pub codec: TrackCodec,
+    pub framing: StreamFraming,
+}
+
+/// Detects the codec and framing of the stream starting with `data`, or
+/// returns `None` if it is not recognized.
+///
+/// Streams within an MP4 or Matroska file are identified from the file's
+/// first video track, and streams within an IVF file from the fourcc of the
+/// file header. Elementary streams are identified from their first NAL units
+/// or OBUs.
+pub fn probe(data: &[u8]) -> Option<ProbedStream> {
+    if let Some(container) = Container::detect(data) {
+        let demuxer = Demuxer::with_container(data, container).ok()?;
+        return Some(ProbedStream {
+            codec: demuxer.codec(),
+            framing: StreamFraming::Container(container),
+        });
+    }
+
+    if data.starts_with(&IvfFileHeader::MAGIC) {
+        let codec = match data.get(8..12)? {
+            b"VP80" => TrackCodec::Vp8,
+            b"VP90" => TrackCodec::Vp9,
+            b"AV01" => TrackCodec::Av1,
+            _ => return None,
+        };
+        return Some(ProbedStream {
+            codec,
+            framing: StreamFraming::Ivf,
+        });
+    }
+
+    if data.starts_with(&[0, 0, 1]) || data.starts_with(&[0, 0, 0, 1]) {
+        return probe_annexb(data).map(|codec| ProbedStream {
+            codec,
+            framing: StreamFraming::AnnexB,
+        });
+    }
+
+    match parse_obu_header(data) {
+        Some((ObuType::TemporalDelimiter | ObuType::SequenceHeader, _)) => Some(ProbedStream {
+            codec: TrackCodec::Av1,
+            framing: StreamFraming::Obu,
+        }),
+        _ => None,
+    }
+}
+
+/// Identifies the codec of an Annex B stream from the header of its first
+/// parameter set, access unit delimiter or IDR NAL unit.
+fn probe_annexb(data: &[u8]) -> Option<TrackCodec> {
+    let headers = data
+        .windows(3)
+        .enumerate()
+        .filter(|(_, window)| *window == [0, 0, 1])
+        .filter_map(|(pos, _)| data.get(pos + 3..pos + 5))
+        .take(MAX_PROBED_NALUS);
+
+    for header in headers {
+        // The forbidden zero bit must be clear in both codecs.
+        if header[0] & 0x80 != 0 {
+            return None;
+        }
+
+        // H.265: VPS, SPS, PPS or AUD, with nuh_layer_id of 0 and a non-zero
+        // nuh_temporal_id_plus1. These headers would be reserved or invalid
+        // H.264 NAL units.
+        let h265_type = (header[0] >> 1) & 0x3f;
+        if (32..=35).contains(&h265_type) && header[0] & 0x1 == 0 && header[1] & 0x7 != 0 {
+            return Some(TrackCodec::H265);
+        }
+
+        // H.264: IDR slice, SPS, PPS or AUD.
+        if matches!(header[0] & 0x1f, 5 | 7 | 8 | 9) {
+            return Some(TrackCodec::H264);
+        }
+    }
+
+    None
+}
+
+/// Parses the header of the OBU at the start of `data`, returning its type
+/// and its total size, header included.
+///
+/// Only OBUs with their size field set, as found in low-overhead streams, are
+/// accepted.
+fn parse_obu_header(data: &[u8]) -> Option<(ObuType, usize)> {
+    let header = *data.first()?;
+    let forbidden_bit = header >> 7;
+    let extension_flag = (header >> 2) & 0x1;
+    let has_size_field = (header >> 1) & 0x1;
+    let reserved_bit = header & 0x1;
+    if forbidden_bit != 0 || reserved_bit != 0 || has_size_field == 0 {
+        return None;
+    }
+
+    let obu_type = ObuType::n((header >> 3) & 0xf)?;
+    let header_size = 1 + usize::from(extension_flag);
+    let (obu_size, leb128_size) = read_leb128(data.get(header_size..)?).ok()?;
+
+    Some((obu_type, header_size + leb128_size + obu_size))
+}
+
+/// Iterator over the temporal units of an AV1 low-overhead stream, each
+/// starting with a temporal delimiter OBU.
+struct TemporalUnitIterator<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Iterator for TemporalUnitIterator<'a> {
+    type Item = &'a [u8];
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut size = 0;
+
+        while let Some((obu_type, obu_size)) = parse_obu_header(&self.data[size..]) {
+            if size > 0 && obu_type == ObuType::TemporalDelimiter {
+                break;
+            }
+            size = std::cmp::min(size + obu_size, self.data.len());
+        }
+
+        // Whatever could not be parsed is returned as-is, for the decoder to
+        // report the error.
+        if size == 0 {
+            size = self.data.len();
+        }
+        if size == 0 {
+            return None;
+        }
+
+        let (unit, rest) = self.data.split_at(size);
+        self.data = rest;
+
+        Some(unit)
+    }
+}
+
+impl ProbedStream {
+    /// Returns an iterator over the input chunks of the stream in `data`, in
+    /// the form expected by the decoder returned by
+    /// [`ProbedStream::new_vaapi_decoder`].
+    ///
+    /// Samples of MP4 and Matroska files are demuxed upfront, so demuxing
+    /// errors are returned here.
+    pub fn chunks<'a>(
+        &self,
+        data: &'a [u8],
+    ) -> anyhow::Result<Box<dyn Iterator<Item = Cow<'a, [u8]>> + 'a>> {
+        Ok(match (self.framing, self.codec) {
+            (StreamFraming::Container(container), _) => {
+                let samples = Demuxer::with_container(data, container)?
+                    .map(|sample| sample.map(|sample| Cow::Owned(sample.data)))
+                    .collect::<anyhow::Result<Vec<_>>>()?;
+                Box::new(samples.into_iter())
+            }
+            (StreamFraming::Ivf, _) => Box::new(IvfIterator::new(data).map(Cow::Borrowed)),
+            (StreamFraming::AnnexB, TrackCodec::H264) => {
+                Box::new(NalIterator::<H264Nalu>::new(data).map(Cow::Borrowed))
+            }
+            (StreamFraming::AnnexB, TrackCodec::H265) => {
+                Box::new(NalIterator::<H265Nalu>::new(data).map(Cow::Borrowed))
+            }
+            (StreamFraming::Obu, TrackCodec::Av1) => {
+                Box::new(TemporalUnitIterator { data }.map(Cow::Borrowed))
+            }
+            (framing, codec) => {
+                return Err(anyhow!(
+                    "{:?} streams cannot use {:?} framing",
+                    codec,
+                    framing
+                ))
+            }
+        })
+    }
+
+    /// Creates a stateless decoder for the stream, using `backend`.
+    pub fn new_decoder<B>(
+        &self,
+        backend: B,
+        blocking_mode: BlockingMode,
+    ) -> Box<dyn StatelessVideoDecoder<B>>
+    where
+        B: StatelessH264DecoderBackend
+            + StatelessH265DecoderBackend
+            + StatelessVp8DecoderBackend
+            + StatelessVp9DecoderBackend
+            + StatelessAV1DecoderBackend
+            + TryFormat<H264>
+            + TryFormat<H265>
+            + TryFormat<Vp8>
+            + TryFormat<Vp9>
+            + TryFormat<Av1>
+            + 'static,
+        B::Handle: Clone,
+    {
+        match self.codec {
+            TrackCodec::H264 => Box::new(StatelessDecoder::<H264, _>::new(backend, blocking_mode)),
+            TrackCodec::H265 => Box::new(StatelessDecoder::<H265, _>::new(backend, blocking_mode)),
+            TrackCodec::Vp8 => Box::new(StatelessDecoder::<Vp8, _>::new(backend, blocking_mode)),
+            TrackCodec::Vp9 => Box::new(StatelessDecoder::<Vp9, _>::new(backend, blocking_mode)),
+            TrackCodec::Av1 => Box::new(StatelessDecoder::<Av1, _>::new(backend, blocking_mode)),
+        }
+    }
+
+    /// Creates a stateless VAAPI decoder for the stream.
+    #[cfg(feature = "vaapi")]
+    pub fn new_vaapi_decoder<M>(
+        &self,
+        display: Rc<libva::Display>,
+        blocking_mode: BlockingMode,
+    ) -> Box<dyn StatelessVideoDecoder<VaapiBackend<M>>>
+    where
+        M: libva::SurfaceMemoryDescriptor + 'static,
+    {
+        self.new_decoder(VaapiBackend::new(display, false), blocking_mode)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::dummy::decoder::Backend;
+    use crate::decoder::stateless::simple_decode_loop;
+    use crate::DecodedFormat;
+
+    const STREAM_H264: &[u8] = include_bytes!("../codec/h264/test_data/test-25fps.h264");
+    const STREAM_H265: &[u8] = include_bytes!("../codec/h265/test_data/test-25fps.h265");
+    const STREAM_VP9: &[u8] = include_bytes!("../codec/vp9/test_data/test-25fps.vp9");
+    const STREAM_AV1: &[u8] = include_bytes!("../codec/av1/test_data/test-25fps.ivf.av1");
+
+    #[test]
+    fn probe_elementary_streams() {
+        let probed = |data| probe(data).map(|probed| (probed.codec, probed.framing));
+
+        assert_eq!(
+            probed(STREAM_H264),
+            Some((TrackCodec::H264, StreamFraming::AnnexB))
+        );
+        assert_eq!(
+            probed(STREAM_H265),
+            Some((TrackCodec::H265, StreamFraming::AnnexB))
+        );
+        assert_eq!(
+            probed(STREAM_VP9),
+            Some((TrackCodec::Vp9, StreamFraming::Ivf))
+        );
+        assert_eq!(
+            probed(STREAM_AV1),
+            Some((TrackCodec::Av1, StreamFraming::Ivf))
+        );
+
+        let obus = IvfIterator::new(STREAM_AV1)
+            .flatten()
+            .copied()
+            .collect::<Vec<_>>();
+        assert_eq!(probed(&obus), Some((TrackCodec::Av1, StreamFraming::Obu)));
+
+        assert_eq!(probed(&[]), None);
+        assert_eq!(probed(b"not a video stream"), None);
+        // Start code followed by a slice without any parameter set.
+        assert_eq!(probed(&[0, 0, 0, 1, 0x41, 0x9a, 0, 0, 1, 0x41, 0x9a]), None);
+    }
+
+    #[test]
+    fn obu_temporal_units() {
+        let units = IvfIterator::new(STREAM_AV1).collect::<Vec<_>>();
+        let obus = units.concat();
+
+        let probed = probe(&obus).unwrap();
+        let chunks = probed.chunks(&obus).unwrap().collect::<Vec<_>>();
+        assert_eq!(chunks, units);
+    }
+
+    #[test]
+    fn decode_probed_streams() {
+        for (data, crcs) in [
+            (
+                STREAM_H264,
+                include_str!("../codec/h264/test_data/test-25fps.h264.crc"),
+            ),
+            (
+                STREAM_H265,
+                include_str!("../codec/h265/test_data/test-25fps.h265.crc"),
+            ),
+            (
+                STREAM_VP9,
+                include_str!("../codec/vp9/test_data/test-25fps.vp9.crc"),
+            ),
+            (
+                STREAM_AV1,
+                include_str!("../codec/av1/test_data/test-25fps.ivf.av1.crc"),
+            ),
+        ] {
+            let probed = probe(data).unwrap();
+            let mut decoder = probed.new_decoder(Backend::new(), BlockingMode::Blocking);
+            let mut provider =
+                |_: &_, nb_frames| -> anyhow::Result<Vec<()>> { Ok(vec![(); nb_frames]) };
+            let mut num_frames = 0;
+
+            simple_decode_loop(
+                decoder.as_mut(),
+                probed.chunks(data).unwrap(),
+                DecodedFormat::NV12,
+                &mut provider,
+                |_| num_frames += 1,
+            )
+            .unwrap();
+
+            assert_eq!(num_frames, crcs.lines().count(), "{:?}", probed);
+        }
+    }
+}
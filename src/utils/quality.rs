@@ -0,0 +1,437 @@
+// Copyright 2024 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Objective quality metrics (PSNR and SSIM) between an original frame and its
+//! reconstructed or decoded counterpart.
+//!
+//! Frames are given as a single buffer and the [`FrameLayout`] describing it. Supported formats
+//! are `NV12` and `I420` (8 bits per sample), as well as `I010` and `P010` (10 bits per sample,
+//! stored in 16-bit little-endian words). Both frames may use different formats and strides, but
+//! must have the same bit depth.
+
+use anyhow::anyhow;
+
+use crate::Fourcc;
+use crate::FrameLayout;
+use crate::PlaneLayout;
+use crate::Resolution;
+
+/// PSNR reported for identical planes, for which the actual value is infinite.
+pub const MAX_PSNR: f64 = 100.0;
+
+/// Size of the side of the square window SSIM is computed over.
+const SSIM_WINDOW: usize = 8;
+/// Distance between two SSIM windows, in both directions.
+const SSIM_STRIDE: usize = 4;
+
+/// Quality of a frame compared to its original.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct FrameQuality {
+    /// PSNR in dB of the Y, U and V planes.
+    pub psnr: [f64; 3],
+    /// PSNR in dB of the whole frame, computed from the mean squared error of all its samples.
+    pub psnr_frame: f64,
+    /// SSIM of the Y, U and V planes.
+    pub ssim: [f64; 3],
+    /// SSIM of the whole frame, as the average of the planes weighted by their number of samples.
+    pub ssim_frame: f64,
+}
+
+/// A single component of a frame, i.e. either Y, U or V.
+#[derive(Clone, Copy)]
+pub(super) struct Plane<'a> {
+    data: &'a [u8],
+    pub(super) width: usize,
+    pub(super) height: usize,
+    stride: usize,
+    /// Distance in bytes between two consecutive samples of a line.
+    step: usize,
+    /// Whether samples are 16-bit words rather than bytes.
+    wide: bool,
+    /// Number of bits to shift a 16-bit word right by to obtain the sample.
+    shift: u32,
+}
+
+impl<'a> Plane<'a> {
+    pub(super) fn sample(&self, x: usize, y: usize) -> u32 {
+        let pos = y * self.stride + x * self.step;
+        if self.wide {
+            (u16::from_le_bytes([self.data[pos], self.data[pos + 1]]) >> self.shift) as u32
+        } else {
+            self.data[pos] as u32
+        }
+    }
+
+    /// Returns this plane restricted to its top-left `width`x`height` area.
+    fn crop(self, width: usize, height: usize) -> Self {
+        Self {
+            width: std::cmp::min(self.width, width),
+            height: std::cmp::min(self.height, height),
+            ..self
+        }
+    }
+}
+
+/// Splits `data` into its Y, U and V components according to `layout`, and returns them along
+/// with the bit depth of the samples.
+pub(super) fn split_planes<'a>(
+    data: &'a [u8],
+    layout: &FrameLayout,
+) -> anyhow::Result<([Plane<'a>; 3], u32)> {
+    let fourcc = layout.format.0;
+    let (num_planes, interleaved, wide, shift, bit_depth) = match &<[u8; 4]>::from(fourcc) {
+        b"NV12" => (2, true, false, 0, 8),
+        b"I420" | b"YU12" => (3, false, false, 0, 8),
+        b"P010" => (2, true, true, 6, 10),
+        b"I010" => (3, false, true, 0, 10),
+        _ => return Err(anyhow!("unsupported format {}", fourcc)),
+    };
+
+    if layout.planes.len() < num_planes {
+        return Err(anyhow!(
+            "layout has {} planes, but format {} needs {}",
+            layout.planes.len(),
+            fourcc,
+            num_planes
+        ));
+    }
+    if layout.planes.iter().any(|p| p.buffer_index != 0) {
+        return Err(anyhow!("only single-buffer frames are supported"));
+    }
+
+    let sample_size = if wide { 2 } else { 1 };
+    let width = layout.size.width as usize;
+    let height = layout.size.height as usize;
+    let chroma_width = width.div_ceil(2);
+    let chroma_height = height.div_ceil(2);
+
+    let plane = |index: usize, offset: usize, width: usize, height: usize, step: usize| {
+        let layout = &layout.planes[index];
+        let start = layout.offset + offset;
+        let needed = match (width, height) {
+            (0, _) | (_, 0) => 0,
+            _ => (height - 1) * layout.stride + (width - 1) * step + sample_size,
+        };
+        let data = data
+            .get(start..start + needed)
+            .ok_or_else(|| anyhow!("plane {} does not fit in the frame buffer", index))?;
+
+        Ok::<_, anyhow::Error>(Plane {
+            data,
+            width,
+            height,
+            stride: layout.stride,
+            step,
+            wide,
+            shift,
+        })
+    };
+
+    let planes = if interleaved {
+        [
+            plane(0, 0, width, height, sample_size)?,
+            plane(1, 0, chroma_width, chroma_height, sample_size * 2)?,
+            plane(1, sample_size, chroma_width, chroma_height, sample_size * 2)?,
+        ]
+    } else {
+        [
+            plane(0, 0, width, height, sample_size)?,
+            plane(1, 0, chroma_width, chroma_height, sample_size)?,
+            plane(2, 0, chroma_width, chroma_height, sample_size)?,
+        ]
+    };
+
+    Ok((planes, bit_depth))
+}
+
+/// Returns the sum of squared differences between `a` and `b`.
+fn sse(a: &Plane, b: &Plane) -> u64 {
+    let mut sse = 0u64;
+    for y in 0..a.height {
+        for x in 0..a.width {
+            let diff = a.sample(x, y) as i64 - b.sample(x, y) as i64;
+            sse += (diff * diff) as u64;
+        }
+    }
+
+    sse
+}
+
+/// Converts a mean squared error into a PSNR for samples of `bit_depth` bits.
+fn mse_to_psnr(mse: f64, bit_depth: u32) -> f64 {
+    let max = ((1u64 << bit_depth) - 1) as f64;
+    if mse == 0.0 {
+        MAX_PSNR
+    } else {
+        (10.0 * (max * max / mse).log10()).min(MAX_PSNR)
+    }
+}
+
+/// Computes the SSIM of the `size`x`size` window at (`x`, `y`).
+fn ssim_window(
+    a: &Plane,
+    b: &Plane,
+    x: usize,
+    y: usize,
+    size: (usize, usize),
+    c: (f64, f64),
+) -> f64 {
+    let (mut sum_a, mut sum_b, mut sum_aa, mut sum_bb, mut sum_ab) = (0u64, 0u64, 0u64, 0u64, 0u64);
+    for j in y..y + size.1 {
+        for i in x..x + size.0 {
+            let sa = a.sample(i, j) as u64;
+            let sb = b.sample(i, j) as u64;
+            sum_a += sa;
+            sum_b += sb;
+            sum_aa += sa * sa;
+            sum_bb += sb * sb;
+            sum_ab += sa * sb;
+        }
+    }
+
+    let n = (size.0 * size.1) as f64;
+    let mean_a = sum_a as f64 / n;
+    let mean_b = sum_b as f64 / n;
+    let var_a = sum_aa as f64 / n - mean_a * mean_a;
+    let var_b = sum_bb as f64 / n - mean_b * mean_b;
+    let cov = sum_ab as f64 / n - mean_a * mean_b;
+
+    ((2.0 * mean_a * mean_b + c.0) * (2.0 * cov + c.1))
+        / ((mean_a * mean_a + mean_b * mean_b + c.0) * (var_a + var_b + c.1))
+}
+
+/// Computes the mean SSIM of `a` and `b` over overlapping windows. Planes smaller than a window
+/// are treated as a single window.
+fn ssim(a: &Plane, b: &Plane, bit_depth: u32) -> f64 {
+    if a.width == 0 || a.height == 0 {
+        return 1.0;
+    }
+
+    let max = ((1u64 << bit_depth) - 1) as f64;
+    let c = ((0.01 * max).powi(2), (0.03 * max).powi(2));
+    let size = (
+        std::cmp::min(SSIM_WINDOW, a.width),
+        std::cmp::min(SSIM_WINDOW, a.height),
+    );
+
+    let mut total = 0.0;
+    let mut count = 0;
+    for y in (0..=a.height - size.1).step_by(SSIM_STRIDE) {
+        for x in (0..=a.width - size.0).step_by(SSIM_STRIDE) {
+            total += ssim_window(a, b, x, y, size, c);
+            count += 1;
+        }
+    }
+
+    total / count as f64
+}
+
+/// Computes the PSNR and SSIM of `distorted` relative to `original`.
+///
+/// Only the area common to both frames is compared, which allows e.g. comparing a frame to its
+/// reconstruction in a coded-size surface.
+pub fn compare(
+    original: &[u8],
+    original_layout: &FrameLayout,
+    distorted: &[u8],
+    distorted_layout: &FrameLayout,
+) -> anyhow::Result<FrameQuality> {
+    let (original, original_depth) = split_planes(original, original_layout)?;
+    let (distorted, distorted_depth) = split_planes(distorted, distorted_layout)?;
+
+    if original_depth != distorted_depth {
+        return Err(anyhow!(
+            "cannot compare {}-bit frame to {}-bit frame",
+            original_depth,
+            distorted_depth
+        ));
+    }
+
+    let size = Resolution {
+        width: std::cmp::min(original_layout.size.width, distorted_layout.size.width),
+        height: std::cmp::min(original_layout.size.height, distorted_layout.size.height),
+    };
+    let chroma_size = Resolution {
+        width: size.width.div_ceil(2),
+        height: size.height.div_ceil(2),
+    };
+
+    let mut quality = FrameQuality::default();
+    let mut total_sse = 0u64;
+    let mut total_samples = 0usize;
+    let mut weighted_ssim = 0.0;
+
+    for i in 0..3 {
+        let plane_size = if i == 0 { size } else { chroma_size };
+        let (width, height) = (plane_size.width as usize, plane_size.height as usize);
+        let a = original[i].crop(width, height);
+        let b = distorted[i].crop(width, height);
+        let samples = width * height;
+
+        let plane_sse = sse(&a, &b);
+        quality.psnr[i] = match samples {
+            0 => MAX_PSNR,
+            _ => mse_to_psnr(plane_sse as f64 / samples as f64, original_depth),
+        };
+        quality.ssim[i] = ssim(&a, &b, original_depth);
+
+        total_sse += plane_sse;
+        total_samples += samples;
+        weighted_ssim += quality.ssim[i] * samples as f64;
+    }
+
+    if total_samples > 0 {
+        quality.psnr_frame = mse_to_psnr(total_sse as f64 / total_samples as f64, original_depth);
+        quality.ssim_frame = weighted_ssim / total_samples as f64;
+    } else {
+        quality.psnr_frame = MAX_PSNR;
+        quality.ssim_frame = 1.0;
+    }
+
+    Ok(quality)
+}
+
+/// Returns the layout of a tightly packed frame of `format` and `size`, if `format` is supported
+/// by [`compare`].
+pub fn packed_layout(format: Fourcc, size: Resolution) -> Option<FrameLayout> {
+    let fourcc = <[u8; 4]>::from(format);
+    let sample_size = match &fourcc {
+        b"NV12" | b"I420" | b"YU12" => 1,
+        b"P010" | b"I010" => 2,
+        _ => return None,
+    };
+
+    let width = size.width as usize;
+    let height = size.height as usize;
+    let chroma_stride = width.div_ceil(2) * sample_size;
+    let chroma_height = height.div_ceil(2);
+    let luma_size = width * sample_size * height;
+
+    let planes = match &fourcc {
+        b"NV12" | b"P010" => vec![
+            PlaneLayout {
+                buffer_index: 0,
+                offset: 0,
+                stride: width * sample_size,
+            },
+            PlaneLayout {
+                buffer_index: 0,
+                offset: luma_size,
+                stride: chroma_stride * 2,
+            },
+        ],
+        _ => vec![
+            PlaneLayout {
+                buffer_index: 0,
+                offset: 0,
+                stride: width * sample_size,
+            },
+            PlaneLayout {
+                buffer_index: 0,
+                offset: luma_size,
+                stride: chroma_stride,
+            },
+            PlaneLayout {
+                buffer_index: 0,
+                offset: luma_size + chroma_stride * chroma_height,
+                stride: chroma_stride,
+            },
+        ],
+    };
+
+    Some(FrameLayout {
+        format: (format, 0),
+        size,
+        planes,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SIZE: Resolution = Resolution {
+        width: 16,
+        height: 16,
+    };
+
+    /// Generates a gradient frame of `format`.
+    fn gradient(format: &[u8; 4]) -> (Vec<u8>, FrameLayout) {
+        let layout = packed_layout(Fourcc::from(format), SIZE).unwrap();
+        let wide = matches!(format, b"P010" | b"I010");
+        let samples = (SIZE.width * SIZE.height * 3 / 2) as usize;
+
+        let data = (0..samples)
+            .flat_map(|i| {
+                let value = (i * 7 % 200) as u16 + 16;
+                match format {
+                    b"P010" => ((value << 2) << 6).to_le_bytes().to_vec(),
+                    b"I010" => (value << 2).to_le_bytes().to_vec(),
+                    _ => vec![value as u8],
+                }
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(data.len(), samples * if wide { 2 } else { 1 });
+
+        (data, layout)
+    }
+
+    #[test]
+    fn identical_frames() {
+        for format in [b"NV12", b"I420", b"P010", b"I010"] {
+            let (data, layout) = gradient(format);
+            let quality = compare(&data, &layout, &data, &layout).unwrap();
+
+            assert_eq!(quality.psnr, [MAX_PSNR; 3]);
+            assert_eq!(quality.psnr_frame, MAX_PSNR);
+            for ssim in quality.ssim {
+                assert!((ssim - 1.0).abs() < 1e-9);
+            }
+            assert!((quality.ssim_frame - 1.0).abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn constant_error() {
+        let (original, layout) = gradient(b"I420");
+        let distorted = original.iter().map(|v| v + 2).collect::<Vec<_>>();
+        let quality = compare(&original, &layout, &distorted, &layout).unwrap();
+
+        // MSE is 4 for every plane: 10 * log10(255^2 / 4) ~= 42.11 dB
+        let expected = 10.0 * (255.0f64 * 255.0 / 4.0).log10();
+        for psnr in quality.psnr {
+            assert!((psnr - expected).abs() < 1e-9);
+        }
+        assert!((quality.psnr_frame - expected).abs() < 1e-9);
+        // A small luminance shift of a high-variance signal barely affects its structure.
+        assert!(quality.ssim_frame > 0.99 && quality.ssim_frame < 1.0);
+    }
+
+    #[test]
+    fn different_layouts() {
+        let (i420, i420_layout) = gradient(b"I420");
+
+        // Interleave the chroma planes of the I420 frame into a NV12 frame.
+        let luma_size = (SIZE.width * SIZE.height) as usize;
+        let chroma_size = luma_size / 4;
+        let mut nv12 = i420[..luma_size].to_vec();
+        for i in 0..chroma_size {
+            nv12.push(i420[luma_size + i]);
+            nv12.push(i420[luma_size + chroma_size + i]);
+        }
+        let nv12_layout = packed_layout(Fourcc::from(b"NV12"), SIZE).unwrap();
+
+        let quality = compare(&i420, &i420_layout, &nv12, &nv12_layout).unwrap();
+        assert_eq!(quality.psnr_frame, MAX_PSNR);
+
+        let (p010, p010_layout) = gradient(b"P010");
+        assert!(compare(&i420, &i420_layout, &p010, &p010_layout).is_err());
+    }
+
+    #[test]
+    fn truncated_frame() {
+        let (data, layout) = gradient(b"NV12");
+        assert!(compare(&data, &layout, &data[..data.len() - 1], &layout).is_err());
+    }
+}
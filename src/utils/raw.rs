@@ -0,0 +1,487 @@
+// Copyright 2024 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Frame producer reading raw NV12 or I420 files, e.g. camera captures.
+
+use std::fs::File;
+use std::path::Path;
+
+use anyhow::anyhow;
+use anyhow::Context;
+use memmap2::Mmap;
+
+use crate::encoder::FrameMetadata;
+use crate::Fourcc;
+use crate::FrameLayout;
+use crate::PlaneLayout;
+use crate::Resolution;
+
+/// Formats of the raw frames supported by [`RawFrameProducer`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum RawFormat {
+    Nv12,
+    I420,
+}
+
+impl RawFormat {
+    fn from_fourcc(fourcc: Fourcc) -> anyhow::Result<Self> {
+        match &<[u8; 4]>::from(fourcc) {
+            b"NV12" => Ok(RawFormat::Nv12),
+            b"I420" => Ok(RawFormat::I420),
+            _ => Err(anyhow!("Unsupported format {}", fourcc)),
+        }
+    }
+
+    fn from_layout(layout: &FrameLayout) -> anyhow::Result<Self> {
+        let format = Self::from_fourcc(layout.format.0)?;
+
+        if layout.planes.len() != format.num_planes() {
+            return Err(anyhow!(
+                "{} layouts must have {} planes, got {}",
+                layout.format.0,
+                format.num_planes(),
+                layout.planes.len()
+            ));
+        }
+        if layout.planes.iter().any(|plane| plane.buffer_index != 0) {
+            return Err(anyhow!("All planes must be in the same buffer"));
+        }
+
+        Ok(format)
+    }
+
+    fn num_planes(self) -> usize {
+        match self {
+            RawFormat::Nv12 => 2,
+            RawFormat::I420 => 3,
+        }
+    }
+
+    /// Returns the width in bytes and the height of the planes of a frame of
+    /// `resolution`.
+    fn plane_sizes(self, resolution: Resolution) -> Vec<(usize, usize)> {
+        let width = resolution.width as usize;
+        let height = resolution.height as usize;
+        let (uv_width, uv_height) = (width.div_ceil(2), height.div_ceil(2));
+
+        match self {
+            RawFormat::Nv12 => vec![(width, height), (2 * uv_width, uv_height)],
+            RawFormat::I420 => vec![
+                (width, height),
+                (uv_width, uv_height),
+                (uv_width, uv_height),
+            ],
+        }
+    }
+
+    /// Returns the position of the chroma samples at `row` and `col` in the
+    /// frame, as laid out by `planes`.
+    fn chroma_offsets(self, planes: &[PlaneLayout], row: usize, col: usize) -> (usize, usize) {
+        match self {
+            RawFormat::Nv12 => {
+                let offset = planes[1].offset + row * planes[1].stride + 2 * col;
+                (offset, offset + 1)
+            }
+            RawFormat::I420 => (
+                planes[1].offset + row * planes[1].stride + col,
+                planes[2].offset + row * planes[2].stride + col,
+            ),
+        }
+    }
+}
+
+/// Returns the layout of NV12 or I420 frames of `resolution` with no padding
+/// between rows and planes, as usually found in raw files.
+pub fn packed_layout(fourcc: Fourcc, resolution: Resolution) -> anyhow::Result<FrameLayout> {
+    let mut layout = FrameLayout {
+        format: (fourcc, 0),
+        size: resolution,
+        planes: vec![],
+    };
+    let format = RawFormat::from_fourcc(fourcc)?;
+
+    let mut offset = 0;
+    for (width, height) in format.plane_sizes(resolution) {
+        layout.planes.push(PlaneLayout {
+            buffer_index: 0,
+            offset,
+            stride: width,
+        });
+        offset += width * height;
+    }
+
+    Ok(layout)
+}
+
+/// Returns the size of a frame of `layout`, or an error if its strides are
+/// too small for its size.
+fn frame_size(format: RawFormat, layout: &FrameLayout) -> anyhow::Result<usize> {
+    let mut size = 0;
+
+    for ((width, height), plane) in format
+        .plane_sizes(layout.size)
+        .into_iter()
+        .zip(&layout.planes)
+    {
+        if plane.stride < width {
+            return Err(anyhow!(
+                "Stride {} is smaller than the plane width {}",
+                plane.stride,
+                width
+            ));
+        }
+        size = size.max(plane.offset + plane.stride * height);
+    }
+
+    Ok(size)
+}
+
+/// Copies the visible area of `resolution` of the frame `src`, laid out as
+/// `src_layout`, into `dst` laid out as `dst_layout`. The padding up to the
+/// size of `dst_layout` is filled by replicating the last visible column and
+/// row.
+fn convert_frame(
+    src: &[u8],
+    (src_format, src_layout): (RawFormat, &FrameLayout),
+    dst: &mut [u8],
+    (dst_format, dst_layout): (RawFormat, &FrameLayout),
+    resolution: Resolution,
+) {
+    let width = resolution.width as usize;
+    let height = resolution.height as usize;
+    let dst_width = dst_layout.size.width as usize;
+    let dst_height = dst_layout.size.height as usize;
+
+    let (src_y, dst_y) = (&src_layout.planes[0], &dst_layout.planes[0]);
+    for row in 0..dst_height {
+        let src = &src[src_y.offset + row.min(height - 1) * src_y.stride..][..width];
+        let dst = &mut dst[dst_y.offset + row * dst_y.stride..][..dst_width];
+        dst[..width].copy_from_slice(src);
+        dst[width..].fill(src[width - 1]);
+    }
+
+    let (uv_width, uv_height) = (width.div_ceil(2), height.div_ceil(2));
+    for row in 0..dst_height.div_ceil(2) {
+        for col in 0..dst_width.div_ceil(2) {
+            let (src_row, src_col) = (row.min(uv_height - 1), col.min(uv_width - 1));
+            let (src_u, src_v) = src_format.chroma_offsets(&src_layout.planes, src_row, src_col);
+            let (dst_u, dst_v) = dst_format.chroma_offsets(&dst_layout.planes, row, col);
+            dst[dst_u] = src[src_u];
+            dst[dst_v] = src[src_v];
+        }
+    }
+}
+
+/// Frame producer reading the frames of a raw NV12 or I420 file, to be passed
+/// to [`simple_encode_loop`](crate::encoder::stateless::simple_encode_loop).
+///
+/// The file is memory-mapped, and made of frames laid out according to an
+/// input [`FrameLayout`], possibly with padded strides or rows. Each frame is
+/// copied into a single buffer laid out according to the output layout
+/// expected by the encoder, converting between NV12 and I420 if needed, and
+/// turned into a handle by the `upload` closure. The timestamp of the frames
+/// is their index in the file.
+pub struct RawFrameProducer<F> {
+    mmap: Mmap,
+    input: (RawFormat, FrameLayout),
+    input_frame_size: usize,
+    output: (RawFormat, FrameLayout),
+    resolution: Resolution,
+    upload: F,
+    buffer: Vec<u8>,
+    frame_count: usize,
+    counter: usize,
+}
+
+impl<H, F: FnMut(&FrameMetadata, &[u8]) -> H> RawFrameProducer<F> {
+    /// Creates a producer of the frames of `file`, of visible `resolution`
+    /// and laid out as `input_layout`, into buffers laid out as
+    /// `output_layout`. A trailing incomplete frame is ignored.
+    pub fn new(
+        file: &File,
+        input_layout: FrameLayout,
+        resolution: Resolution,
+        output_layout: FrameLayout,
+        upload: F,
+    ) -> anyhow::Result<Self> {
+        let input_format = RawFormat::from_layout(&input_layout)?;
+        let output_format = RawFormat::from_layout(&output_layout)?;
+        if resolution.width == 0 || resolution.height == 0 {
+            return Err(anyhow!("Invalid resolution {:?}", resolution));
+        }
+        for layout in [&input_layout, &output_layout] {
+            if !layout.size.can_contain(resolution) {
+                return Err(anyhow!(
+                    "Layout size {:?} cannot contain frames of {:?}",
+                    layout.size,
+                    resolution
+                ));
+            }
+        }
+        let input_frame_size = frame_size(input_format, &input_layout)?;
+        let output_frame_size = frame_size(output_format, &output_layout)?;
+
+        // SAFETY: the file is mapped read-only. Its content may still change
+        // if other processes write to it, which would only result in garbage
+        // frames, or in a SIGBUS if it is truncated.
+        let mmap = unsafe { Mmap::map(file) }.context("Failed to map the raw file")?;
+        let frame_count = mmap.len() / input_frame_size;
+        if mmap.len() % input_frame_size != 0 {
+            log::warn!(
+                "Raw file size {} is not a multiple of the frame size {}",
+                mmap.len(),
+                input_frame_size
+            );
+        }
+
+        Ok(Self {
+            mmap,
+            input: (input_format, input_layout),
+            input_frame_size,
+            output: (output_format, output_layout),
+            resolution,
+            upload,
+            buffer: vec![0u8; output_frame_size],
+            frame_count,
+            counter: 0,
+        })
+    }
+
+    /// Opens the file at `path` and creates a producer of its frames, which
+    /// are packed without any padding. See [`RawFrameProducer::new`].
+    pub fn open(
+        path: impl AsRef<Path>,
+        fourcc: Fourcc,
+        resolution: Resolution,
+        output_layout: FrameLayout,
+        upload: F,
+    ) -> anyhow::Result<Self> {
+        let file = File::open(path).context("Failed to open the raw file")?;
+        let input_layout = packed_layout(fourcc, resolution)?;
+
+        Self::new(&file, input_layout, resolution, output_layout, upload)
+    }
+
+    /// Returns the number of frames of the file.
+    pub fn frame_count(&self) -> usize {
+        self.frame_count
+    }
+}
+
+impl<H, F: FnMut(&FrameMetadata, &[u8]) -> H> Iterator for RawFrameProducer<F> {
+    type Item = (FrameMetadata, H);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.counter >= self.frame_count {
+            return None;
+        }
+
+        let src = &self.mmap[self.counter * self.input_frame_size..][..self.input_frame_size];
+        convert_frame(
+            src,
+            (self.input.0, &self.input.1),
+            &mut self.buffer,
+            (self.output.0, &self.output.1),
+            self.resolution,
+        );
+
+        let meta = FrameMetadata {
+            timestamp: self.counter as u64,
+            display_resolution: self.resolution,
+            layout: self.output.1.clone(),
+            force_keyframe: false,
+            damage: None,
+        };
+        self.counter += 1;
+
+        let handle = (self.upload)(&meta, &self.buffer);
+
+        Some((meta, handle))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let remaining = self.frame_count - self.counter;
+        (remaining, Some(remaining))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Seek;
+    use std::io::Write;
+
+    use super::*;
+
+    /// Returns a temporary file containing `data`.
+    fn temp_file(data: &[u8]) -> File {
+        let path = std::env::temp_dir().join(format!(
+            "cros-codecs-raw-{}-{:?}",
+            std::process::id(),
+            std::thread::current().id()
+        ));
+        let mut file = File::options()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .unwrap();
+        std::fs::remove_file(&path).unwrap();
+        file.write_all(data).unwrap();
+        file.rewind().unwrap();
+
+        file
+    }
+
+    /// Returns packed I420 frames of `resolution` whose samples depend on
+    /// their position, plane and frame.
+    fn test_frames(resolution: Resolution, count: usize) -> Vec<Vec<u8>> {
+        let format = RawFormat::I420;
+
+        (0..count)
+            .map(|index| {
+                let mut frame = vec![];
+                for (plane, (w, h)) in format.plane_sizes(resolution).into_iter().enumerate() {
+                    for row in 0..h {
+                        for col in 0..w {
+                            frame.push((row * 16 + col + plane * 64 + index * 7) as u8);
+                        }
+                    }
+                }
+                frame
+            })
+            .collect()
+    }
+
+    fn nv12_layout(size: Resolution, stride: usize, uv_offset: usize) -> FrameLayout {
+        FrameLayout {
+            format: (Fourcc::from(b"NV12"), 0),
+            size,
+            planes: vec![
+                PlaneLayout {
+                    buffer_index: 0,
+                    offset: 0,
+                    stride,
+                },
+                PlaneLayout {
+                    buffer_index: 0,
+                    offset: uv_offset,
+                    stride,
+                },
+            ],
+        }
+    }
+
+    #[test]
+    fn packed_i420_to_padded_nv12() {
+        let resolution = Resolution::from((5, 3));
+        let frames = test_frames(resolution, 3);
+        // Add an incomplete frame.
+        let data = [frames.concat(), vec![0u8; 4]].concat();
+        let file = temp_file(&data);
+
+        let output_layout = nv12_layout(Resolution::from((8, 4)), 16, 64);
+        let producer = RawFrameProducer::new(
+            &file,
+            packed_layout(Fourcc::from(b"I420"), resolution).unwrap(),
+            resolution,
+            output_layout.clone(),
+            |_, buffer| buffer.to_vec(),
+        )
+        .unwrap();
+        assert_eq!(producer.frame_count(), 3);
+
+        let produced = producer.collect::<Vec<_>>();
+        assert_eq!(produced.len(), 3);
+        for (i, ((meta, buffer), frame)) in produced.iter().zip(&frames).enumerate() {
+            assert_eq!(meta.timestamp, i as u64);
+            assert_eq!(meta.display_resolution, resolution);
+            assert_eq!(meta.layout, output_layout);
+            assert_eq!(buffer.len(), 64 + 2 * 16);
+
+            let (y, uv) = frame.split_at(15);
+            let (u, v) = uv.split_at(6);
+            for row in 0..4 {
+                for col in 0..8 {
+                    // The last row and column are replicated.
+                    assert_eq!(buffer[row * 16 + col], y[row.min(2) * 5 + col.min(4)]);
+                }
+            }
+            for row in 0..2 {
+                for col in 0..4 {
+                    let src = row * 3 + col.min(2);
+                    assert_eq!(buffer[64 + row * 16 + 2 * col], u[src]);
+                    assert_eq!(buffer[64 + row * 16 + 2 * col + 1], v[src]);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn padded_nv12_to_packed_i420() {
+        let resolution = Resolution::from((6, 4));
+        let frames = test_frames(resolution, 2);
+
+        // Write the frames as NV12 with 8 bytes strides and 6 rows per plane.
+        let input_layout = nv12_layout(Resolution::from((6, 6)), 8, 48);
+        let mut data = vec![];
+        for frame in &frames {
+            let mut padded = vec![0xffu8; 48 + 3 * 8];
+            let (y, uv) = frame.split_at(24);
+            let (u, v) = uv.split_at(6);
+            for row in 0..4 {
+                padded[row * 8..][..6].copy_from_slice(&y[row * 6..][..6]);
+            }
+            for row in 0..2 {
+                for col in 0..3 {
+                    padded[48 + row * 8 + 2 * col] = u[row * 3 + col];
+                    padded[48 + row * 8 + 2 * col + 1] = v[row * 3 + col];
+                }
+            }
+            data.extend_from_slice(&padded);
+        }
+        let file = temp_file(&data);
+
+        let output_layout = packed_layout(Fourcc::from(b"I420"), resolution).unwrap();
+        let produced = RawFrameProducer::new(
+            &file,
+            input_layout,
+            resolution,
+            output_layout,
+            |_, buffer| buffer.to_vec(),
+        )
+        .unwrap()
+        .map(|(_, buffer)| buffer)
+        .collect::<Vec<_>>();
+        assert_eq!(produced, frames);
+    }
+
+    #[test]
+    fn invalid_layouts() {
+        let resolution = Resolution::from((8, 8));
+        let file = temp_file(&test_frames(resolution, 1).concat());
+        let packed = packed_layout(Fourcc::from(b"NV12"), resolution).unwrap();
+        let new = |input: &FrameLayout, output: &FrameLayout| {
+            RawFrameProducer::new(&file, input.clone(), resolution, output.clone(), |_, _| ())
+                .map(|_| ())
+        };
+
+        assert!(new(&packed, &packed).is_ok());
+        // Output too small.
+        assert!(new(&packed, &nv12_layout(Resolution::from((8, 4)), 8, 64)).is_err());
+        // Stride too small.
+        assert!(new(&packed, &nv12_layout(resolution, 4, 64)).is_err());
+        // Wrong number of planes.
+        let mut three_planes = packed.clone();
+        three_planes.planes.push(three_planes.planes[1].clone());
+        assert!(new(&three_planes, &packed).is_err());
+        // Unsupported format.
+        let yuyv = FrameLayout {
+            format: (Fourcc::from(b"YUYV"), 0),
+            ..packed.clone()
+        };
+        assert!(new(&packed, &yuyv).is_err());
+        assert!(packed_layout(Fourcc::from(b"YUYV"), resolution).is_err());
+    }
+}
@@ -0,0 +1,816 @@
+// Copyright 2024 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! RTP packetization of encoded video frames.
+//!
+//! [`RtpPacketizer`] splits each [`CodedBitstreamBuffer`] into RTP packets no
+//! larger than a given MTU, using the payload format of its codec:
+//!
+//! * H.264: RFC 6184 in non-interleaved mode, with STAP-A and FU-A packets.
+//! * H.265: RFC 7798 without DONL, with aggregation and fragmentation units.
+//! * VP8: RFC 7741, with a 15 bits picture ID.
+//! * VP9: RFC 9628 in non-flexible mode, with a 15 bits picture ID and the
+//!   scalability structure on keyframes.
+//! * AV1: the AV1 RTP payload specification, with OBU elements always
+//!   preceded by their length.
+//!
+//! The marker bit is set on the last packet of each frame.
+
+use anyhow::anyhow;
+use bytes::BufMut;
+use bytes::Bytes;
+
+use crate::codec::av1::annexb::leb128_len;
+use crate::codec::av1::annexb::write_leb128;
+use crate::codec::av1::annexb::RawObu;
+use crate::codec::av1::parser::ObuType;
+use crate::codec::vp9::superframe::split_superframe;
+use crate::encoder::CodedBitstreamBuffer;
+use crate::Resolution;
+
+/// Size of the fixed RTP header, without CSRC nor extension.
+pub const RTP_HEADER_SIZE: usize = 12;
+
+/// Frequency of the RTP timestamps of video payload formats.
+pub const RTP_VIDEO_CLOCK_HZ: u64 = 90_000;
+
+/// Smallest accepted MTU, leaving room for the payload headers and some data.
+const MIN_MTU: usize = RTP_HEADER_SIZE + 16;
+
+/// NAL unit types of the H.264 payload format.
+const H264_STAP_A: u8 = 24;
+const H264_FU_A: u8 = 28;
+
+/// NAL unit types of the H.265 payload format.
+const H265_AP: u8 = 48;
+const H265_FU: u8 = 49;
+
+/// Codec of the packetized stream.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RtpCodec {
+    H264,
+    H265,
+    Vp8,
+    Vp9,
+    Av1,
+}
+
+/// An RTP packet.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RtpPacket {
+    pub payload_type: u8,
+    pub sequence_number: u16,
+    pub timestamp: u32,
+    pub ssrc: u32,
+    /// Set on the last packet of a frame.
+    pub marker: bool,
+    /// The payload, starting with the payload header of the codec. Packets of
+    /// a single NAL unit share the storage of the coded buffer.
+    pub payload: Bytes,
+}
+
+impl RtpPacket {
+    /// Returns the packet with its RTP header.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(RTP_HEADER_SIZE + self.payload.len());
+
+        // Version 2, no padding, no extension and no CSRC.
+        out.put_u8(0x80);
+        out.put_u8(u8::from(self.marker) << 7 | (self.payload_type & 0x7f));
+        out.put_u16(self.sequence_number);
+        out.put_u32(self.timestamp);
+        out.put_u32(self.ssrc);
+        out.put_slice(&self.payload);
+
+        out
+    }
+}
+
+/// Returns whether the VP9 `frame` is a keyframe, from the first bits of its
+/// uncompressed header.
+fn vp9_is_keyframe(frame: &[u8]) -> bool {
+    let Some(&first_byte) = frame.first() else {
+        return false;
+    };
+
+    // frame_marker, profile_low_bit and profile_high_bit, then reserved_zero
+    // for profile 3.
+    let profile = (first_byte >> 5) & 0x1 | (first_byte >> 3) & 0x2;
+    let shift = if profile == 3 { 2 } else { 3 };
+    let show_existing_frame = (first_byte >> shift) & 0x1 != 0;
+    let frame_type = (first_byte >> (shift - 1)) & 0x1;
+
+    !show_existing_frame && frame_type == 0
+}
+
+/// Splits frames into RTP packets.
+pub struct RtpPacketizer {
+    codec: RtpCodec,
+    payload_type: u8,
+    ssrc: u32,
+    mtu: usize,
+    sequence_number: u16,
+    /// Picture ID of the next VP8 or VP9 frame.
+    picture_id: u16,
+}
+
+impl RtpPacketizer {
+    /// Creates a packetizer of `codec` frames into packets of `payload_type`
+    /// and `ssrc`, no larger than `mtu` bytes including the RTP header.
+    pub fn new(codec: RtpCodec, payload_type: u8, ssrc: u32, mtu: usize) -> anyhow::Result<Self> {
+        // Aggregated NAL units have a 16 bits size.
+        if !(MIN_MTU..=usize::from(u16::MAX)).contains(&mtu) {
+            return Err(anyhow!(
+                "MTU {} is not between {} and {}",
+                mtu,
+                MIN_MTU,
+                u16::MAX
+            ));
+        }
+        if payload_type > 0x7f {
+            return Err(anyhow!("Invalid payload type {}", payload_type));
+        }
+
+        Ok(Self {
+            codec,
+            payload_type,
+            ssrc,
+            mtu,
+            sequence_number: 0,
+            picture_id: 0,
+        })
+    }
+
+    /// Sets the sequence number of the next packet. RFC 3550 recommends
+    /// starting from a random value.
+    pub fn set_sequence_number(&mut self, sequence_number: u16) {
+        self.sequence_number = sequence_number;
+    }
+
+    /// Sets the picture ID of the next VP8 or VP9 frame, of which only the
+    /// lower 15 bits are used.
+    pub fn set_picture_id(&mut self, picture_id: u16) {
+        self.picture_id = picture_id & 0x7fff;
+    }
+
+    /// Returns the packets of the frame of `buffer`. The RTP timestamp is the
+    /// [`FrameMetadata::timestamp`](crate::encoder::FrameMetadata) of the
+    /// frame, which must be in units of [`RTP_VIDEO_CLOCK_HZ`], truncated to
+    /// 32 bits.
+    pub fn packetize(&mut self, buffer: &CodedBitstreamBuffer) -> anyhow::Result<Vec<RtpPacket>> {
+        let bitstream = &buffer.bitstream[..];
+        if bitstream.is_empty() {
+            return Err(anyhow!("Empty frame"));
+        }
+
+        let max_payload = self.mtu - RTP_HEADER_SIZE;
+        let payloads = match self.codec {
+            RtpCodec::H264 => Self::h264_payloads(buffer, max_payload),
+            RtpCodec::H265 => Self::h265_payloads(buffer, max_payload),
+            RtpCodec::Vp8 => self.vp8_payloads(bitstream, max_payload),
+            RtpCodec::Vp9 => {
+                self.vp9_payloads(bitstream, buffer.metadata.display_resolution, max_payload)?
+            }
+            RtpCodec::Av1 => Self::av1_payloads(bitstream, max_payload)?,
+        };
+        if payloads.is_empty() {
+            return Err(anyhow!("The frame has no data to send"));
+        }
+
+        let last = payloads.len() - 1;
+        let packets = payloads
+            .into_iter()
+            .enumerate()
+            .map(|(i, payload)| {
+                let sequence_number = self.sequence_number;
+                self.sequence_number = self.sequence_number.wrapping_add(1);
+
+                RtpPacket {
+                    payload_type: self.payload_type,
+                    sequence_number,
+                    timestamp: buffer.metadata.timestamp as u32,
+                    ssrc: self.ssrc,
+                    marker: i == last,
+                    payload,
+                }
+            })
+            .collect();
+
+        Ok(packets)
+    }
+
+    /// Returns the payloads of the NAL units of `bitstream`, with aggregation
+    /// and fragmentation as per RFC 6184 and RFC 7798. `header_len` is the
+    /// size of the NAL unit header, and `aggregate` and `fragment` build the
+    /// payloads of several NAL units, and of a fragment of one.
+    fn nal_payloads(
+        nalus: Vec<Bytes>,
+        header_len: usize,
+        max_payload: usize,
+        aggregate: impl Fn(&[Bytes]) -> Vec<u8>,
+        fragment: impl Fn(&[u8], &[u8], bool, bool) -> Vec<u8>,
+    ) -> Vec<Bytes> {
+        let mut payloads = vec![];
+        let mut pending: Vec<Bytes> = vec![];
+        // Size of the aggregation packet of the pending NAL units.
+        let mut pending_size = header_len;
+
+        let flush = |pending: &mut Vec<Bytes>, payloads: &mut Vec<Bytes>| {
+            match pending.len() {
+                0 => (),
+                // A single NAL unit is sent as is.
+                1 => payloads.push(pending.remove(0)),
+                _ => payloads.push(aggregate(pending).into()),
+            }
+            pending.clear();
+        };
+
+        for nalu in nalus {
+            if nalu.len() <= header_len {
+                continue;
+            }
+
+            if nalu.len() > max_payload {
+                flush(&mut pending, &mut payloads);
+                pending_size = header_len;
+
+                // The fragmentation unit header takes one more byte than the
+                // NAL unit header it replaces.
+                let (header, data) = nalu.split_at(header_len);
+                let chunk_size = max_payload - header_len - 1;
+                let chunks = data.chunks(chunk_size).collect::<Vec<_>>();
+                let last = chunks.len() - 1;
+                for (i, chunk) in chunks.into_iter().enumerate() {
+                    payloads.push(fragment(header, chunk, i == 0, i == last).into());
+                }
+                continue;
+            }
+
+            // Each aggregated NAL unit is preceded by its 16 bits size.
+            if pending_size + 2 + nalu.len() > max_payload {
+                flush(&mut pending, &mut payloads);
+                pending_size = header_len;
+            }
+            pending_size += 2 + nalu.len();
+            pending.push(nalu);
+        }
+        flush(&mut pending, &mut payloads);
+
+        payloads
+    }
+
+    fn h264_payloads(buffer: &CodedBitstreamBuffer, max_payload: usize) -> Vec<Bytes> {
+        // Access unit delimiters are not sent.
+        let nalus = buffer
+            .h264_nal_units()
+            .filter(|unit| unit.data[0] & 0x1f != 9)
+            .map(|unit| buffer.unit_bytes(&unit))
+            .collect();
+
+        Self::nal_payloads(
+            nalus,
+            1,
+            max_payload,
+            |nalus| {
+                // F is set if any NAL unit has it, and NRI is the highest one.
+                let f = nalus.iter().fold(0, |f, nalu| f | (nalu[0] & 0x80));
+                let nri = nalus.iter().map(|nalu| nalu[0] & 0x60).max().unwrap_or(0);
+
+                let mut payload = vec![f | nri | H264_STAP_A];
+                for nalu in nalus {
+                    payload.put_u16(nalu.len() as u16);
+                    payload.put_slice(nalu);
+                }
+                payload
+            },
+            |header, chunk, start, end| {
+                let mut payload = Vec::with_capacity(chunk.len() + 2);
+                payload.put_u8((header[0] & 0xe0) | H264_FU_A);
+                payload.put_u8(u8::from(start) << 7 | u8::from(end) << 6 | (header[0] & 0x1f));
+                payload.put_slice(chunk);
+                payload
+            },
+        )
+    }
+
+    fn h265_payloads(buffer: &CodedBitstreamBuffer, max_payload: usize) -> Vec<Bytes> {
+        // Access unit delimiters are not sent.
+        let nalus = buffer
+            .h265_nal_units()
+            .filter(|unit| unit.data.len() >= 2 && (unit.data[0] >> 1) & 0x3f != 35)
+            .map(|unit| buffer.unit_bytes(&unit))
+            .collect();
+
+        Self::nal_payloads(
+            nalus,
+            2,
+            max_payload,
+            |nalus| {
+                // F is set if any NAL unit has it, and the layer ID and
+                // temporal ID are the lowest ones.
+                let f = nalus.iter().fold(0, |f, nalu| f | (nalu[0] & 0x80));
+                let layer_id = nalus
+                    .iter()
+                    .map(|nalu| u16::from(nalu[0] & 0x1) << 5 | u16::from(nalu[1] >> 3))
+                    .min()
+                    .unwrap_or(0);
+                let tid = nalus.iter().map(|nalu| nalu[1] & 0x7).min().unwrap_or(0);
+
+                let mut payload = vec![
+                    f | H265_AP << 1 | (layer_id >> 5) as u8,
+                    ((layer_id & 0x1f) as u8) << 3 | tid,
+                ];
+                for nalu in nalus {
+                    payload.put_u16(nalu.len() as u16);
+                    payload.put_slice(nalu);
+                }
+                payload
+            },
+            |header, chunk, start, end| {
+                let mut payload = Vec::with_capacity(chunk.len() + 3);
+                payload.put_u8((header[0] & 0x81) | H265_FU << 1);
+                payload.put_u8(header[1]);
+                payload.put_u8(u8::from(start) << 7 | u8::from(end) << 6 | (header[0] >> 1) & 0x3f);
+                payload.put_slice(chunk);
+                payload
+            },
+        )
+    }
+
+    /// Returns the next picture ID as a 15 bits field with its M bit set.
+    fn next_picture_id(&mut self) -> [u8; 2] {
+        let picture_id = self.picture_id;
+        self.picture_id = (self.picture_id + 1) & 0x7fff;
+
+        (0x8000 | picture_id).to_be_bytes()
+    }
+
+    fn vp8_payloads(&mut self, frame: &[u8], max_payload: usize) -> Vec<Bytes> {
+        let picture_id = self.next_picture_id();
+        // X, S and PID, then I, then the picture ID.
+        let descriptor_len = 4;
+
+        frame
+            .chunks(max_payload - descriptor_len)
+            .enumerate()
+            .map(|(i, chunk)| {
+                let mut payload = Vec::with_capacity(descriptor_len + chunk.len());
+                payload.put_u8(if i == 0 { 0x90 } else { 0x80 });
+                payload.put_u8(0x80);
+                payload.put_slice(&picture_id);
+                payload.put_slice(chunk);
+                Bytes::from(payload)
+            })
+            .collect()
+    }
+
+    /// Packetizes each frame of the superframe `data` separately, as per
+    /// section 4.2 of RFC 9628, with all frames sharing the same picture ID.
+    fn vp9_payloads(
+        &mut self,
+        data: &[u8],
+        resolution: Resolution,
+        max_payload: usize,
+    ) -> anyhow::Result<Vec<Bytes>> {
+        let picture_id = self.next_picture_id();
+        let mut payloads = vec![];
+
+        for frame in split_superframe(data)? {
+            let keyframe = vp9_is_keyframe(frame);
+
+            // The scalability structure, describing a single spatial layer,
+            // is sent in the first packet of keyframes.
+            let mut scalability_structure = vec![];
+            if keyframe {
+                // N_S 0, Y set and G unset.
+                scalability_structure.put_u8(0x10);
+                scalability_structure.put_u16(resolution.width as u16);
+                scalability_structure.put_u16(resolution.height as u16);
+            }
+
+            let mut remaining = frame;
+            let mut first = true;
+            while first || !remaining.is_empty() {
+                // I, P, B, E and V, then the picture ID, then the scalability
+                // structure.
+                let ss = if first {
+                    &scalability_structure[..]
+                } else {
+                    &[]
+                };
+                let descriptor_len = 3 + ss.len();
+                let chunk_len = std::cmp::min(remaining.len(), max_payload - descriptor_len);
+                let (chunk, rest) = remaining.split_at(chunk_len);
+
+                let mut flags = 0x80;
+                if !keyframe {
+                    flags |= 0x40;
+                }
+                if first {
+                    flags |= 0x08;
+                }
+                if rest.is_empty() {
+                    flags |= 0x04;
+                }
+                if !ss.is_empty() {
+                    flags |= 0x02;
+                }
+
+                let mut payload = Vec::with_capacity(descriptor_len + chunk.len());
+                payload.put_u8(flags);
+                payload.put_slice(&picture_id);
+                payload.put_slice(ss);
+                payload.put_slice(chunk);
+                payloads.push(payload.into());
+
+                remaining = rest;
+                first = false;
+            }
+        }
+
+        Ok(payloads)
+    }
+
+    /// Returns the payloads of the temporal unit `data`, in the low-overhead
+    /// format. Temporal delimiters and tile lists are not sent, and the size
+    /// field of the OBUs is replaced by the length of their OBU element.
+    fn av1_payloads(mut data: &[u8], max_payload: usize) -> anyhow::Result<Vec<Bytes>> {
+        let mut elements = vec![];
+        let mut new_sequence = false;
+
+        while !data.is_empty() {
+            let (obu, len) = RawObu::split(data, None)?;
+            data = &data[len..];
+
+            match obu.obu_type() {
+                Some(ObuType::TemporalDelimiter) | Some(ObuType::TileList) => continue,
+                Some(ObuType::SequenceHeader) => new_sequence = true,
+                _ => (),
+            }
+
+            let mut element = Vec::with_capacity(obu.header.len() + obu.payload.len());
+            element.put_u8(obu.header[0] & !0x2);
+            element.put_slice(&obu.header[1..]);
+            element.put_slice(obu.payload);
+            elements.push(element);
+        }
+
+        let mut payloads = vec![];
+        // Aggregation header, with Z and Y to be set on fragments.
+        let mut payload = vec![0u8];
+
+        for element in &elements {
+            let mut remaining = &element[..];
+
+            loop {
+                let space = max_payload - payload.len();
+                if leb128_len(remaining.len()) + remaining.len() <= space {
+                    write_leb128(remaining.len(), &mut payload);
+                    payload.put_slice(remaining);
+                    break;
+                }
+
+                // Send as much of the element as possible, and continue it in
+                // the next packet.
+                let fragment_len = space.saturating_sub(leb128_len(space));
+                if fragment_len > 0 {
+                    write_leb128(fragment_len, &mut payload);
+                    payload.put_slice(&remaining[..fragment_len]);
+                    remaining = &remaining[fragment_len..];
+                    // Y
+                    payload[0] |= 0x40;
+                }
+
+                let continuation = payload[0] & 0x40 != 0;
+                payloads.push(std::mem::replace(&mut payload, vec![0u8]));
+                if continuation {
+                    // Z
+                    payload[0] |= 0x80;
+                }
+            }
+        }
+        if payload.len() > 1 {
+            payloads.push(payload);
+        }
+
+        // N is set on the first packet of a coded video sequence.
+        if new_sequence {
+            if let Some(first) = payloads.first_mut() {
+                first[0] |= 0x08;
+            }
+        }
+
+        Ok(payloads.into_iter().map(Bytes::from).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec::av1::annexb::read_leb128;
+    use crate::codec::h264::nalu::split_annexb;
+    use crate::encoder::FrameMetadata;
+    use crate::utils::ivf::IvfReader;
+    use crate::Fourcc;
+    use crate::FrameLayout;
+
+    const STREAM_H264: &[u8] = include_bytes!("../codec/h264/test_data/64x64-I-P.h264");
+    const STREAM_H265: &[u8] = include_bytes!("../codec/h265/test_data/64x64-I-P.h265");
+    const STREAM_VP8: &[u8] = include_bytes!("../codec/vp8/test_data/test-25fps.vp8");
+    const STREAM_VP9: &[u8] = include_bytes!("../codec/vp9/test_data/test-25fps.vp9");
+    const STREAM_AV1: &[u8] = include_bytes!("../codec/av1/test_data/test-25fps.ivf.av1");
+
+    fn buffer(timestamp: u64, bitstream: &[u8]) -> CodedBitstreamBuffer {
+        CodedBitstreamBuffer::new(
+            FrameMetadata {
+                timestamp,
+                display_resolution: Resolution::from((320, 240)),
+                layout: FrameLayout {
+                    format: (Fourcc::from(b"NV12"), 0),
+                    size: Resolution::from((320, 240)),
+                    planes: vec![],
+                },
+                force_keyframe: false,
+                damage: None,
+            },
+            bitstream.to_vec(),
+        )
+    }
+
+    /// Packetizes `frames` with an MTU of `mtu`, checks the RTP headers and
+    /// returns the payloads of each frame.
+    fn packetize(codec: RtpCodec, frames: &[&[u8]], mtu: usize) -> Vec<Vec<Vec<u8>>> {
+        let mut packetizer = RtpPacketizer::new(codec, 96, 0x1234_5678, mtu).unwrap();
+        packetizer.set_sequence_number(u16::MAX - 1);
+
+        let mut sequence_number = u16::MAX - 1;
+        let mut payloads = vec![];
+        for (i, frame) in frames.iter().enumerate() {
+            let packets = packetizer
+                .packetize(&buffer(3000 * i as u64, frame))
+                .unwrap();
+
+            for (j, packet) in packets.iter().enumerate() {
+                let bytes = packet.to_bytes();
+                assert!(bytes.len() <= mtu);
+                assert_eq!(bytes[0], 0x80);
+                assert_eq!(bytes[1], u8::from(j == packets.len() - 1) << 7 | 96);
+                assert_eq!(u16::from_be_bytes([bytes[2], bytes[3]]), sequence_number);
+                assert_eq!(bytes[4..8], (3000 * i as u32).to_be_bytes());
+                assert_eq!(bytes[8..12], [0x12, 0x34, 0x56, 0x78]);
+                assert_eq!(bytes[12..], packet.payload);
+                sequence_number = sequence_number.wrapping_add(1);
+            }
+
+            payloads.push(
+                packets
+                    .into_iter()
+                    .map(|packet| packet.payload.into())
+                    .collect(),
+            );
+        }
+
+        payloads
+    }
+
+    /// Returns the NAL units of the H.264 or H.265 `payloads`, and the number
+    /// of aggregation and fragmentation packets.
+    fn depacketize_nal(
+        payloads: &[Vec<u8>],
+        header_len: usize,
+        nalu_type: impl Fn(&[u8]) -> u8,
+        aggregation: u8,
+        fragmentation: u8,
+    ) -> (Vec<Vec<u8>>, usize, usize) {
+        let mut nalus = vec![];
+        let mut fragmented: Option<Vec<u8>> = None;
+        let (mut aggregated_count, mut fragment_count) = (0, 0);
+
+        for payload in payloads {
+            match nalu_type(payload) {
+                t if t == aggregation => {
+                    aggregated_count += 1;
+                    let mut data = &payload[header_len..];
+                    while !data.is_empty() {
+                        let size = usize::from(u16::from_be_bytes([data[0], data[1]]));
+                        nalus.push(data[2..2 + size].to_vec());
+                        data = &data[2 + size..];
+                    }
+                }
+                t if t == fragmentation => {
+                    fragment_count += 1;
+                    let fu_header = payload[header_len];
+                    let (start, end) = (fu_header & 0x80 != 0, fu_header & 0x40 != 0);
+                    let data = &payload[header_len + 1..];
+
+                    if start {
+                        assert!(fragmented.is_none());
+                        // Rebuild the NAL unit header from the FU one.
+                        let nalu = if header_len == 1 {
+                            vec![(payload[0] & 0xe0) | (fu_header & 0x1f)]
+                        } else {
+                            vec![(payload[0] & 0x81) | (fu_header & 0x3f) << 1, payload[1]]
+                        };
+                        fragmented = Some(nalu);
+                    }
+                    fragmented.as_mut().unwrap().extend_from_slice(data);
+                    if end {
+                        nalus.push(fragmented.take().unwrap());
+                    }
+                }
+                _ => nalus.push(payload.clone()),
+            }
+        }
+        assert!(fragmented.is_none());
+
+        (nalus, aggregated_count, fragment_count)
+    }
+
+    fn check_nal(codec: RtpCodec, stream: &[u8], mtu: usize) -> (usize, usize) {
+        let (header_len, nalu_type, aggregation, fragmentation, aud): (
+            _,
+            fn(&[u8]) -> u8,
+            _,
+            _,
+            _,
+        ) = match codec {
+            RtpCodec::H264 => (1, |nalu| nalu[0] & 0x1f, H264_STAP_A, H264_FU_A, 9),
+            _ => (2, |nalu| (nalu[0] >> 1) & 0x3f, H265_AP, H265_FU, 35),
+        };
+
+        let payloads = packetize(codec, &[stream], mtu).remove(0);
+        let (nalus, aggregated_count, fragment_count) =
+            depacketize_nal(&payloads, header_len, nalu_type, aggregation, fragmentation);
+
+        let expected = split_annexb(stream)
+            .into_iter()
+            .filter(|nalu| nalu_type(nalu) != aud)
+            .collect::<Vec<_>>();
+        assert_eq!(nalus, expected);
+
+        (aggregated_count, fragment_count)
+    }
+
+    #[test]
+    fn packetize_h264() {
+        // The parameter sets are aggregated, and the largest slices are
+        // fragmented.
+        let (aggregated_count, fragment_count) = check_nal(RtpCodec::H264, STREAM_H264, 1200);
+        assert!(aggregated_count > 0 && fragment_count > 0);
+
+        // Everything fits in aggregation packets.
+        let (aggregated_count, fragment_count) = check_nal(RtpCodec::H264, STREAM_H264, 9000);
+        assert!(aggregated_count > 0 && fragment_count == 0);
+
+        let (_, fragment_count) = check_nal(RtpCodec::H264, STREAM_H264, 40);
+        assert!(fragment_count > 0);
+    }
+
+    #[test]
+    fn packetize_h265() {
+        // The parameter sets are aggregated, and the largest slices are
+        // fragmented.
+        let (aggregated_count, fragment_count) = check_nal(RtpCodec::H265, STREAM_H265, 1200);
+        assert!(aggregated_count > 0 && fragment_count > 0);
+
+        // Everything fits in aggregation packets.
+        let (aggregated_count, fragment_count) = check_nal(RtpCodec::H265, STREAM_H265, 9000);
+        assert!(aggregated_count > 0 && fragment_count == 0);
+
+        let (_, fragment_count) = check_nal(RtpCodec::H265, STREAM_H265, 40);
+        assert!(fragment_count > 0);
+    }
+
+    #[test]
+    fn packetize_vp8() {
+        let frames = IvfReader::new(STREAM_VP8)
+            .unwrap()
+            .map(|frame| frame.data)
+            .collect::<Vec<_>>();
+
+        let payloads = packetize(RtpCodec::Vp8, &frames, 300);
+        for (i, (frame, payloads)) in frames.iter().zip(payloads).enumerate() {
+            let mut data = vec![];
+            for (j, payload) in payloads.iter().enumerate() {
+                // S is only set on the first packet.
+                assert_eq!(payload[0], if j == 0 { 0x90 } else { 0x80 });
+                assert_eq!(payload[1], 0x80);
+                assert_eq!(
+                    u16::from_be_bytes([payload[2], payload[3]]),
+                    0x8000 | i as u16
+                );
+                data.extend_from_slice(&payload[4..]);
+            }
+            assert_eq!(&data, frame);
+        }
+    }
+
+    #[test]
+    fn packetize_vp9() {
+        let frames = IvfReader::new(STREAM_VP9)
+            .unwrap()
+            .map(|frame| frame.data)
+            .collect::<Vec<_>>();
+
+        let payloads = packetize(RtpCodec::Vp9, &frames, 300);
+        let mut keyframes = 0;
+        for (i, (chunk, payloads)) in frames.iter().zip(payloads).enumerate() {
+            let mut frames = vec![];
+            for payload in &payloads {
+                let flags = payload[0];
+                assert_eq!(flags & 0xb1, 0x80);
+                assert_eq!(
+                    u16::from_be_bytes([payload[1], payload[2]]),
+                    0x8000 | i as u16
+                );
+
+                let mut data = &payload[3..];
+                // V
+                if flags & 0x02 != 0 {
+                    assert_ne!(flags & 0x08, 0);
+                    assert_eq!(data[..5], [0x10, 0x01, 0x40, 0x00, 0xf0]);
+                    data = &data[5..];
+                    keyframes += 1;
+                }
+                // B
+                if flags & 0x08 != 0 {
+                    frames.push(vec![]);
+                }
+                frames.last_mut().unwrap().extend_from_slice(data);
+                // P is unset only for keyframes.
+                assert_eq!(flags & 0x40 == 0, vp9_is_keyframe(frames.last().unwrap()));
+            }
+            // E
+            assert_ne!(payloads.last().unwrap()[0] & 0x04, 0);
+
+            assert_eq!(frames, split_superframe(chunk).unwrap());
+        }
+        assert!(keyframes > 0);
+    }
+
+    #[test]
+    fn packetize_av1() {
+        let frames = IvfReader::new(STREAM_AV1)
+            .unwrap()
+            .map(|frame| frame.data)
+            .collect::<Vec<_>>();
+
+        for mtu in [1200, 100] {
+            let payloads = packetize(RtpCodec::Av1, &frames, mtu);
+            for (i, (temporal_unit, payloads)) in frames.iter().zip(payloads).enumerate() {
+                let mut elements: Vec<Vec<u8>> = vec![];
+                let mut continued = false;
+                for payload in &payloads {
+                    let header = payload[0];
+                    // Z matches the Y bit of the previous packet.
+                    assert_eq!(header & 0x80 != 0, continued);
+                    // N is only set on the first packet of the first frame.
+                    assert_eq!(
+                        header & 0x08 != 0,
+                        i == 0 && std::ptr::eq(payload, &payloads[0])
+                    );
+
+                    let mut data = &payload[1..];
+                    let mut first = true;
+                    while !data.is_empty() {
+                        let (len, len_len) = read_leb128(data).unwrap();
+                        let element = &data[len_len..len_len + len];
+                        if first && continued {
+                            elements.last_mut().unwrap().extend_from_slice(element);
+                        } else {
+                            elements.push(element.to_vec());
+                        }
+                        data = &data[len_len + len..];
+                        first = false;
+                    }
+                    continued = header & 0x40 != 0;
+                }
+                assert!(!continued);
+
+                // Restore the OBU size fields.
+                let mut data = vec![];
+                for element in elements {
+                    let (obu, _) = RawObu::split(&element, Some(element.len())).unwrap();
+                    data.push(obu.header[0] | 0x2);
+                    data.extend_from_slice(&obu.header[1..]);
+                    write_leb128(obu.payload.len(), &mut data);
+                    data.extend_from_slice(obu.payload);
+                }
+                // Only the temporal delimiter is removed.
+                assert_eq!(temporal_unit[..2], [0x12, 0x00]);
+                assert_eq!(data, temporal_unit[2..]);
+            }
+        }
+    }
+
+    #[test]
+    fn invalid_parameters() {
+        assert!(RtpPacketizer::new(RtpCodec::H264, 96, 0, MIN_MTU - 1).is_err());
+        assert!(RtpPacketizer::new(RtpCodec::H264, 96, 0, 70000).is_err());
+        assert!(RtpPacketizer::new(RtpCodec::H264, 128, 0, 1200).is_err());
+
+        let mut packetizer = RtpPacketizer::new(RtpCodec::Vp8, 96, 0, 1200).unwrap();
+        assert!(packetizer.packetize(&buffer(0, &[])).is_err());
+    }
+}
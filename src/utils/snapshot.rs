@@ -0,0 +1,97 @@
+// Copyright 2024 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Serialization of the session snapshots, which carry the codec state of the encoders and
+//! decoders so that another session can resume their stream, e.g. after a live migration.
+//!
+//! A snapshot starts with a 4 bytes tag identifying its type and a version byte, followed by its
+//! fields. All values are big-endian, and byte strings are preceded by their 32 bits length.
+
+use anyhow::anyhow;
+use bytes::Buf;
+use bytes::BufMut;
+
+/// Version of the snapshot format.
+const VERSION: u8 = 1;
+
+/// Writes the header of a snapshot of type `tag` to `out`.
+pub(crate) fn put_header(out: &mut Vec<u8>, tag: &[u8; 4]) {
+    out.put_slice(tag);
+    out.put_u8(VERSION);
+}
+
+/// Reads the header of a snapshot of type `tag` from `input`.
+pub(crate) fn get_header(input: &mut &[u8], tag: &[u8; 4]) -> anyhow::Result<()> {
+    check_remaining(input, 5)?;
+    let (found, version) = (input.copy_to_bytes(4), input.get_u8());
+    if found[..] != tag[..] {
+        return Err(anyhow!("not a {} snapshot", String::from_utf8_lossy(tag)));
+    }
+    if version != VERSION {
+        return Err(anyhow!("unsupported snapshot version {}", version));
+    }
+
+    Ok(())
+}
+
+pub(crate) fn put_bytes(out: &mut Vec<u8>, data: &[u8]) {
+    out.put_u32(data.len() as u32);
+    out.put_slice(data);
+}
+
+pub(crate) fn get_bytes(input: &mut &[u8]) -> anyhow::Result<Vec<u8>> {
+    let len = get_u32(input)? as usize;
+    check_remaining(input, len)?;
+    Ok(input.copy_to_bytes(len).to_vec())
+}
+
+/// Writes `value`, preceded by a byte telling whether it is present.
+pub(crate) fn put_option<T>(out: &mut Vec<u8>, value: Option<T>, put: impl Fn(&mut Vec<u8>, T)) {
+    out.put_u8(value.is_some().into());
+    if let Some(value) = value {
+        put(out, value);
+    }
+}
+
+pub(crate) fn get_option<T>(
+    input: &mut &[u8],
+    get: impl Fn(&mut &[u8]) -> anyhow::Result<T>,
+) -> anyhow::Result<Option<T>> {
+    match get_u8(input)? {
+        0 => Ok(None),
+        _ => Ok(Some(get(input)?)),
+    }
+}
+
+pub(crate) fn get_u8(input: &mut &[u8]) -> anyhow::Result<u8> {
+    check_remaining(input, 1)?;
+    Ok(input.get_u8())
+}
+
+pub(crate) fn get_u32(input: &mut &[u8]) -> anyhow::Result<u32> {
+    check_remaining(input, 4)?;
+    Ok(input.get_u32())
+}
+
+pub(crate) fn get_u64(input: &mut &[u8]) -> anyhow::Result<u64> {
+    check_remaining(input, 8)?;
+    Ok(input.get_u64())
+}
+
+/// Checks that the whole snapshot was read.
+pub(crate) fn check_end(input: &[u8]) -> anyhow::Result<()> {
+    if !input.is_empty() {
+        return Err(anyhow!("{} trailing bytes in snapshot", input.len()));
+    }
+
+    Ok(())
+}
+
+fn check_remaining(input: &[u8], len: usize) -> anyhow::Result<()> {
+    if input.len() < len {
+        return Err(anyhow!("truncated snapshot"));
+    }
+
+    Ok(())
+}
@@ -0,0 +1,671 @@
+// Copyright 2024 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Reading and writing of YUV4MPEG2 (y4m) files, the format of most raw test
+//! sequences.
+//!
+//! A y4m file is a single text header line, giving the resolution, frame rate
+//! and colorspace of the stream, followed by frames each made of a `FRAME`
+//! line and the planes of the frame without any padding.
+
+use std::io::Read;
+use std::io::Write;
+
+use anyhow::anyhow;
+use anyhow::Context;
+
+use crate::decoder::DecodedHandle;
+use crate::encoder::FrameMetadata;
+use crate::DecodedFormat;
+use crate::FrameLayout;
+use crate::Resolution;
+
+const SIGNATURE: &str = "YUV4MPEG2";
+const FRAME_TAG: &str = "FRAME";
+
+/// Maximum length of the header lines, to bail out early on invalid files.
+const MAX_LINE_LENGTH: usize = 1024;
+
+/// Returns the colorspace tag of `format`.
+fn colorspace_tag(format: DecodedFormat) -> &'static str {
+    match format {
+        // NV12 frames are converted to I420.
+        DecodedFormat::I420 | DecodedFormat::NV12 => "420jpeg",
+        DecodedFormat::I422 => "422",
+        DecodedFormat::I444 => "444",
+        DecodedFormat::I010 => "420p10",
+        DecodedFormat::I012 => "420p12",
+        DecodedFormat::I210 => "422p10",
+        DecodedFormat::I212 => "422p12",
+        DecodedFormat::I410 => "444p10",
+        DecodedFormat::I412 => "444p12",
+    }
+}
+
+/// Returns the planar format of the colorspace `tag`.
+fn format_from_colorspace_tag(tag: &str) -> Option<DecodedFormat> {
+    match tag {
+        "420jpeg" | "420paldv" | "420mpeg2" | "420" => Some(DecodedFormat::I420),
+        "422" => Some(DecodedFormat::I422),
+        "444" => Some(DecodedFormat::I444),
+        "420p10" => Some(DecodedFormat::I010),
+        "420p12" => Some(DecodedFormat::I012),
+        "422p10" => Some(DecodedFormat::I210),
+        "422p12" => Some(DecodedFormat::I212),
+        "444p10" => Some(DecodedFormat::I410),
+        "444p12" => Some(DecodedFormat::I412),
+        _ => None,
+    }
+}
+
+/// Returns the size of a frame of `format` and `resolution` without padding,
+/// as produced by [`MappableHandle::read`](crate::decoder::MappableHandle).
+pub fn frame_size(format: DecodedFormat, resolution: Resolution) -> usize {
+    let width = resolution.width as usize;
+    let height = resolution.height as usize;
+
+    let (sub_h, sub_v, bytes_per_sample) = match format {
+        DecodedFormat::I420 | DecodedFormat::NV12 => (true, true, 1),
+        DecodedFormat::I422 => (true, false, 1),
+        DecodedFormat::I444 => (false, false, 1),
+        DecodedFormat::I010 | DecodedFormat::I012 => (true, true, 2),
+        DecodedFormat::I210 | DecodedFormat::I212 => (true, false, 2),
+        DecodedFormat::I410 | DecodedFormat::I412 => (false, false, 2),
+    };
+    let uv_width = if sub_h { width.div_ceil(2) } else { width };
+    let uv_height = if sub_v { height.div_ceil(2) } else { height };
+
+    (width * height + 2 * uv_width * uv_height) * bytes_per_sample
+}
+
+/// Reads a line of at most [`MAX_LINE_LENGTH`] bytes, without its line feed.
+/// Returns `None` if the end of the file is reached before any byte.
+fn read_line(reader: &mut impl Read) -> anyhow::Result<Option<String>> {
+    let mut line = Vec::new();
+    let mut byte = [0u8];
+
+    loop {
+        if reader.read(&mut byte)? == 0 {
+            if line.is_empty() {
+                return Ok(None);
+            }
+            return Err(anyhow!("Truncated y4m line"));
+        }
+
+        match byte[0] {
+            b'\n' => break,
+            _ if line.len() == MAX_LINE_LENGTH => return Err(anyhow!("y4m line too long")),
+            byte => line.push(byte),
+        }
+    }
+
+    Ok(Some(String::from_utf8(line)?))
+}
+
+/// Parses a `num:den` ratio.
+fn parse_ratio(value: &str) -> anyhow::Result<(u32, u32)> {
+    let (num, den) = value
+        .split_once(':')
+        .ok_or_else(|| anyhow!("Invalid y4m ratio {}", value))?;
+
+    Ok((num.parse()?, den.parse()?))
+}
+
+/// Parameters of a y4m stream.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Y4MHeader {
+    pub resolution: Resolution,
+    /// Frame rate, as a numerator and denominator.
+    pub frame_rate: (u32, u32),
+    /// Planar format of the frames.
+    pub format: DecodedFormat,
+}
+
+impl Y4MHeader {
+    /// Parses the header line `line`, without its line feed.
+    pub fn parse(line: &str) -> anyhow::Result<Self> {
+        let mut params = line.split(' ');
+        if params.next() != Some(SIGNATURE) {
+            return Err(anyhow!("Invalid y4m signature"));
+        }
+
+        let mut width = None;
+        let mut height = None;
+        let mut frame_rate = None;
+        // The default colorspace is 4:2:0 with JPEG chroma siting.
+        let mut format = DecodedFormat::I420;
+
+        for param in params.filter(|param| !param.is_empty()) {
+            let tag_len = param.chars().next().map_or(0, char::len_utf8);
+            let (tag, value) = param.split_at(tag_len);
+            match tag {
+                "W" => width = Some(value.parse().context("Invalid y4m width")?),
+                "H" => height = Some(value.parse().context("Invalid y4m height")?),
+                "F" => frame_rate = Some(parse_ratio(value)?),
+                "C" => {
+                    format = format_from_colorspace_tag(value)
+                        .ok_or_else(|| anyhow!("Unsupported y4m colorspace {}", value))?
+                }
+                "I" if value != "p" && value != "?" => {
+                    return Err(anyhow!("Unsupported y4m interlacing {}", value))
+                }
+                // Pixel aspect ratio, interlacing and extensions.
+                _ => (),
+            }
+        }
+
+        let (Some(width), Some(height)) = (width, height) else {
+            return Err(anyhow!("y4m header without resolution"));
+        };
+        let frame_rate = frame_rate.ok_or_else(|| anyhow!("y4m header without frame rate"))?;
+        if frame_rate.0 == 0 || frame_rate.1 == 0 {
+            return Err(anyhow!("Invalid y4m frame rate {:?}", frame_rate));
+        }
+
+        Ok(Self {
+            resolution: Resolution { width, height },
+            frame_rate,
+            format,
+        })
+    }
+
+    /// Writes the header line into `writer`.
+    pub fn write_into(&self, writer: &mut impl Write) -> anyhow::Result<()> {
+        writeln!(
+            writer,
+            "{} W{} H{} F{}:{} Ip A1:1 C{}",
+            SIGNATURE,
+            self.resolution.width,
+            self.resolution.height,
+            self.frame_rate.0,
+            self.frame_rate.1,
+            colorspace_tag(self.format)
+        )?;
+
+        Ok(())
+    }
+
+    /// Returns the size of the frames of the stream.
+    pub fn frame_size(&self) -> usize {
+        frame_size(self.format, self.resolution)
+    }
+}
+
+/// Reader of the frames of a y4m file.
+pub struct Y4MReader<R: Read> {
+    reader: R,
+    header: Y4MHeader,
+}
+
+impl<R: Read> Y4MReader<R> {
+    /// Creates a reader, and parses the header of the file.
+    pub fn new(mut reader: R) -> anyhow::Result<Self> {
+        let line = read_line(&mut reader)?.ok_or_else(|| anyhow!("Empty y4m file"))?;
+        let header = Y4MHeader::parse(&line)?;
+
+        Ok(Self { reader, header })
+    }
+
+    pub fn header(&self) -> &Y4MHeader {
+        &self.header
+    }
+
+    /// Reads the next frame into `frame`, which is resized to the frame
+    /// size. Returns `false` if the end of the file has been reached.
+    pub fn read_frame(&mut self, frame: &mut Vec<u8>) -> anyhow::Result<bool> {
+        let Some(line) = read_line(&mut self.reader)? else {
+            return Ok(false);
+        };
+        // The frame parameters, if any, are ignored.
+        if line.split(' ').next() != Some(FRAME_TAG) {
+            return Err(anyhow!("Invalid y4m frame header"));
+        }
+
+        frame.resize(self.header.frame_size(), 0);
+        self.reader
+            .read_exact(frame)
+            .context("Truncated y4m frame")?;
+
+        Ok(true)
+    }
+}
+
+/// Copies the I420 `frame` of `resolution` into `dst` as NV12 laid out
+/// according to `layout`. The padding up to the size of `layout` is filled
+/// by replicating the last column and row of the frame.
+pub fn i420_to_nv12(frame: &[u8], resolution: Resolution, layout: &FrameLayout, dst: &mut [u8]) {
+    let width = resolution.width as usize;
+    let height = resolution.height as usize;
+    let coded_width = layout.size.width as usize;
+    let coded_height = layout.size.height as usize;
+    let uv_width = width.div_ceil(2);
+    let uv_height = height.div_ceil(2);
+
+    let (y_plane, uv_planes) = frame.split_at(width * height);
+    let (u_plane, v_plane) = uv_planes.split_at(uv_width * uv_height);
+
+    let y_layout = &layout.planes[0];
+    for row in 0..coded_height {
+        let src = &y_plane[row.min(height - 1) * width..][..width];
+        let dst = &mut dst[y_layout.offset + row * y_layout.stride..][..coded_width];
+        dst[..width].copy_from_slice(src);
+        dst[width..].fill(src[width - 1]);
+    }
+
+    let uv_layout = &layout.planes[1];
+    for row in 0..coded_height.div_ceil(2) {
+        let src_row = row.min(uv_height - 1) * uv_width;
+        let u = &u_plane[src_row..][..uv_width];
+        let v = &v_plane[src_row..][..uv_width];
+        let dst = &mut dst[uv_layout.offset + row * uv_layout.stride..];
+
+        for col in 0..coded_width.div_ceil(2) {
+            let src_col = col.min(uv_width - 1);
+            dst[2 * col] = u[src_col];
+            dst[2 * col + 1] = v[src_col];
+        }
+    }
+}
+
+/// Returns the size of a single buffer holding a NV12 frame of `layout`.
+fn nv12_buffer_size(layout: &FrameLayout) -> usize {
+    let coded_height = layout.size.height as usize;
+    let y_end = layout.planes[0].offset + layout.planes[0].stride * coded_height;
+    let uv_end = layout.planes[1].offset + layout.planes[1].stride * coded_height.div_ceil(2);
+
+    std::cmp::max(y_end, uv_end)
+}
+
+/// Frame producer reading the frames of a 4:2:0 y4m file, to be passed to
+/// [`simple_encode_loop`](crate::encoder::stateless::simple_encode_loop).
+///
+/// Each frame is converted to NV12 in a single buffer laid out according to
+/// the [`FrameLayout`] expected by the encoder, and turned into a handle by
+/// the `upload` closure, e.g. by copying it into a surface. The timestamp of
+/// the frames is their index in the file.
+///
+/// Iteration stops at the end of the file or at the first error, which can
+/// then be retrieved using [`Y4MFrameProducer::take_error`].
+pub struct Y4MFrameProducer<R: Read, F> {
+    reader: Y4MReader<R>,
+    layout: FrameLayout,
+    upload: F,
+    frame: Vec<u8>,
+    buffer: Vec<u8>,
+    counter: u64,
+    error: Option<anyhow::Error>,
+}
+
+impl<R: Read, H, F: FnMut(&FrameMetadata, &[u8]) -> H> Y4MFrameProducer<R, F> {
+    /// Creates a producer of the frames of `reader` laid out according to
+    /// `layout`, which must be a NV12 layout at least as large as the frames.
+    pub fn new(reader: Y4MReader<R>, layout: FrameLayout, upload: F) -> anyhow::Result<Self> {
+        let header = reader.header();
+        if header.format != DecodedFormat::I420 {
+            return Err(anyhow!(
+                "Unsupported y4m format {:?}, only 4:2:0 is supported",
+                header.format
+            ));
+        }
+        if layout.format.0 != b"NV12".into() || layout.planes.len() < 2 {
+            return Err(anyhow!("Unsupported layout {:?}, NV12 expected", layout));
+        }
+        if !layout.size.can_contain(header.resolution) {
+            return Err(anyhow!(
+                "Layout size {:?} cannot contain frames of {:?}",
+                layout.size,
+                header.resolution
+            ));
+        }
+        if header.resolution.width == 0 || header.resolution.height == 0 {
+            return Err(anyhow!("Invalid y4m resolution {:?}", header.resolution));
+        }
+        let width = layout.size.width as usize;
+        if layout.planes[0].stride < width || layout.planes[1].stride < width.next_multiple_of(2) {
+            return Err(anyhow!("Strides of {:?} are too small", layout));
+        }
+
+        let buffer = vec![0u8; nv12_buffer_size(&layout)];
+
+        Ok(Self {
+            reader,
+            layout,
+            upload,
+            frame: Vec::new(),
+            buffer,
+            counter: 0,
+            error: None,
+        })
+    }
+
+    /// Returns the error that stopped the iteration, if any.
+    pub fn take_error(&mut self) -> Option<anyhow::Error> {
+        self.error.take()
+    }
+}
+
+impl<R: Read, H, F: FnMut(&FrameMetadata, &[u8]) -> H> Iterator for Y4MFrameProducer<R, F> {
+    type Item = (FrameMetadata, H);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.error.is_some() {
+            return None;
+        }
+
+        match self.reader.read_frame(&mut self.frame) {
+            Ok(true) => (),
+            Ok(false) => return None,
+            Err(e) => {
+                log::error!("Failed to read y4m frame: {:#}", e);
+                self.error = Some(e);
+                return None;
+            }
+        }
+
+        let resolution = self.reader.header().resolution;
+        i420_to_nv12(&self.frame, resolution, &self.layout, &mut self.buffer);
+
+        let meta = FrameMetadata {
+            timestamp: self.counter,
+            display_resolution: resolution,
+            layout: self.layout.clone(),
+            force_keyframe: false,
+            damage: None,
+        };
+        self.counter += 1;
+
+        let handle = (self.upload)(&meta, &self.buffer);
+
+        Some((meta, handle))
+    }
+}
+
+/// Writer of decoded frames into a y4m file.
+pub struct Y4MWriter<W: Write> {
+    writer: W,
+    header: Y4MHeader,
+    /// Format of the frames passed to the writer.
+    format: DecodedFormat,
+    /// Scratch buffer for the conversion of NV12 frames.
+    buffer: Vec<u8>,
+}
+
+impl<W: Write> Y4MWriter<W> {
+    /// Creates a writer of frames of `format` and `resolution`, and writes
+    /// the header of the file. NV12 frames are stored as I420.
+    pub fn new(
+        mut writer: W,
+        format: DecodedFormat,
+        resolution: Resolution,
+        frame_rate: (u32, u32),
+    ) -> anyhow::Result<Self> {
+        let header = Y4MHeader {
+            resolution,
+            frame_rate,
+            format: match format {
+                DecodedFormat::NV12 => DecodedFormat::I420,
+                format => format,
+            },
+        };
+        header.write_into(&mut writer)?;
+
+        Ok(Self {
+            writer,
+            header,
+            format,
+            buffer: Vec::new(),
+        })
+    }
+
+    /// Writes `frame`, of the format of the writer and without any padding,
+    /// as read from a [`MappableHandle`](crate::decoder::MappableHandle).
+    pub fn write_frame(&mut self, frame: &[u8]) -> anyhow::Result<()> {
+        let frame_size = self.header.frame_size();
+        if frame.len() != frame_size {
+            return Err(anyhow!(
+                "Frame size is {} while {} is expected",
+                frame.len(),
+                frame_size
+            ));
+        }
+
+        writeln!(self.writer, "{}", FRAME_TAG)?;
+
+        if self.format == DecodedFormat::NV12 {
+            let width = self.header.resolution.width as usize;
+            let height = self.header.resolution.height as usize;
+            let (y_plane, uv_plane) = frame.split_at(width * height);
+
+            // Deinterleave the chroma samples.
+            self.buffer.clear();
+            self.buffer.extend(
+                uv_plane
+                    .iter()
+                    .step_by(2)
+                    .chain(uv_plane.iter().skip(1).step_by(2)),
+            );
+
+            self.writer.write_all(y_plane)?;
+            self.writer.write_all(&self.buffer)?;
+        } else {
+            self.writer.write_all(frame)?;
+        }
+
+        Ok(())
+    }
+
+    /// Reads the decoded frame of `handle` and writes it. The decoder must
+    /// output frames of the format and display resolution of the writer.
+    pub fn write_handle(&mut self, handle: &impl DecodedHandle) -> anyhow::Result<()> {
+        let picture = handle.dyn_picture();
+        let mut mappable = picture.dyn_mappable_handle()?;
+
+        let mut frame = vec![0u8; mappable.image_size()];
+        mappable.read(&mut frame)?;
+
+        self.write_frame(&frame)
+    }
+
+    /// Flushes and returns the underlying writer.
+    pub fn finish(mut self) -> anyhow::Result<W> {
+        self.writer.flush()?;
+
+        Ok(self.writer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Fourcc;
+    use crate::PlaneLayout;
+
+    /// Returns an I420 frame of `resolution` whose samples depend on their
+    /// position and plane.
+    fn test_frame(resolution: Resolution, index: u8) -> Vec<u8> {
+        let width = resolution.width as usize;
+        let height = resolution.height as usize;
+        let (uv_width, uv_height) = (width.div_ceil(2), height.div_ceil(2));
+
+        let mut frame = vec![];
+        for (plane, (w, h)) in [
+            (width, height),
+            (uv_width, uv_height),
+            (uv_width, uv_height),
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            for row in 0..h {
+                for col in 0..w {
+                    frame.push((row * 16 + col + plane * 64) as u8 ^ index);
+                }
+            }
+        }
+
+        frame
+    }
+
+    #[test]
+    fn parse_header() {
+        let header =
+            Y4MHeader::parse("YUV4MPEG2 W352 H288 F30000:1001 Ip A128:117 C420mpeg2 XYSCSS=420")
+                .unwrap();
+        assert_eq!(
+            header,
+            Y4MHeader {
+                resolution: Resolution::from((352, 288)),
+                frame_rate: (30000, 1001),
+                format: DecodedFormat::I420,
+            }
+        );
+        assert_eq!(header.frame_size(), 352 * 288 * 3 / 2);
+
+        let header = Y4MHeader::parse("YUV4MPEG2 W3 H3 F25:1 C444p10").unwrap();
+        assert_eq!(header.format, DecodedFormat::I410);
+        assert_eq!(header.frame_size(), 3 * 3 * 3 * 2);
+
+        for invalid in [
+            "YUV4MPEG W352 H288 F25:1",
+            "YUV4MPEG2 H288 F25:1",
+            "YUV4MPEG2 W352 H288",
+            "YUV4MPEG2 W352 H288 F25:0",
+            "YUV4MPEG2 W352 H288 F25:1 Cmono",
+            "YUV4MPEG2 W352 H288 F25:1 It",
+            "YUV4MPEG2 W352 H288 F25:1 Wé",
+        ] {
+            assert!(Y4MHeader::parse(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn write_and_read() {
+        let resolution = Resolution::from((5, 3));
+        let frames = [test_frame(resolution, 0), test_frame(resolution, 0xff)];
+
+        let mut writer =
+            Y4MWriter::new(Vec::new(), DecodedFormat::I420, resolution, (25, 1)).unwrap();
+        for frame in &frames {
+            writer.write_frame(frame).unwrap();
+        }
+        assert!(writer.write_frame(&frames[0][1..]).is_err());
+        let data = writer.finish().unwrap();
+        assert!(data.starts_with(b"YUV4MPEG2 W5 H3 F25:1 Ip A1:1 C420jpeg\nFRAME\n"));
+
+        let mut reader = Y4MReader::new(&data[..]).unwrap();
+        assert_eq!(reader.header().resolution, resolution);
+        let mut frame = vec![];
+        for expected in &frames {
+            assert!(reader.read_frame(&mut frame).unwrap());
+            assert_eq!(&frame, expected);
+        }
+        assert!(!reader.read_frame(&mut frame).unwrap());
+
+        // Truncated frames are errors.
+        let mut reader = Y4MReader::new(&data[..data.len() - 1]).unwrap();
+        assert!(reader.read_frame(&mut frame).unwrap());
+        assert!(reader.read_frame(&mut frame).is_err());
+    }
+
+    #[test]
+    fn write_nv12() {
+        let resolution = Resolution::from((5, 3));
+        let i420 = test_frame(resolution, 0);
+        let (y, uv) = i420.split_at(15);
+        let (u, v) = uv.split_at(6);
+        let nv12 = [
+            y.to_vec(),
+            u.iter().zip(v).flat_map(|(&u, &v)| [u, v]).collect(),
+        ]
+        .concat();
+
+        let mut writer =
+            Y4MWriter::new(Vec::new(), DecodedFormat::NV12, resolution, (25, 1)).unwrap();
+        writer.write_frame(&nv12).unwrap();
+        let data = writer.finish().unwrap();
+
+        let mut reader = Y4MReader::new(&data[..]).unwrap();
+        assert_eq!(reader.header().format, DecodedFormat::I420);
+        let mut frame = vec![];
+        assert!(reader.read_frame(&mut frame).unwrap());
+        assert_eq!(frame, i420);
+    }
+
+    #[test]
+    fn produce_nv12_frames() {
+        let resolution = Resolution::from((5, 3));
+        let frames = [test_frame(resolution, 0), test_frame(resolution, 1)];
+        let mut writer =
+            Y4MWriter::new(Vec::new(), DecodedFormat::I420, resolution, (30, 1)).unwrap();
+        for frame in &frames {
+            writer.write_frame(frame).unwrap();
+        }
+        let data = writer.finish().unwrap();
+
+        // Coded size of 8x4, with 16 bytes strides and the chroma plane at
+        // offset 128.
+        let layout = FrameLayout {
+            format: (Fourcc::from(b"NV12"), 0),
+            size: Resolution::from((8, 4)),
+            planes: vec![
+                PlaneLayout {
+                    buffer_index: 0,
+                    offset: 0,
+                    stride: 16,
+                },
+                PlaneLayout {
+                    buffer_index: 0,
+                    offset: 128,
+                    stride: 16,
+                },
+            ],
+        };
+
+        let reader = Y4MReader::new(&data[..]).unwrap();
+        let mut producer =
+            Y4MFrameProducer::new(reader, layout.clone(), |_, buffer| buffer.to_vec()).unwrap();
+        let produced = producer.by_ref().collect::<Vec<_>>();
+        assert!(producer.take_error().is_none());
+        assert_eq!(produced.len(), 2);
+
+        for (i, ((meta, buffer), frame)) in produced.iter().zip(&frames).enumerate() {
+            assert_eq!(meta.timestamp, i as u64);
+            assert_eq!(meta.display_resolution, resolution);
+            assert_eq!(meta.layout, layout);
+            assert_eq!(buffer.len(), 128 + 2 * 16);
+
+            let (y, uv) = frame.split_at(15);
+            let (u, v) = uv.split_at(6);
+            for row in 0..4 {
+                for col in 0..8 {
+                    // The last row and column are replicated.
+                    let src = row.min(2) * 5 + col.min(4);
+                    assert_eq!(buffer[row * 16 + col], y[src]);
+                }
+            }
+            for row in 0..2 {
+                for col in 0..4 {
+                    let src = row * 3 + col.min(2);
+                    assert_eq!(buffer[128 + row * 16 + 2 * col], u[src]);
+                    assert_eq!(buffer[128 + row * 16 + 2 * col + 1], v[src]);
+                }
+            }
+        }
+
+        // Frames larger than the layout are rejected.
+        let reader = Y4MReader::new(&data[..]).unwrap();
+        let small_layout = FrameLayout {
+            size: Resolution::from((4, 4)),
+            ..layout.clone()
+        };
+        assert!(Y4MFrameProducer::new(reader, small_layout, |_, _| ()).is_err());
+
+        // Truncated files stop the iteration with an error.
+        let reader = Y4MReader::new(&data[..data.len() - 1]).unwrap();
+        let mut producer =
+            Y4MFrameProducer::new(reader, layout, |_, buffer| buffer.to_vec()).unwrap();
+        assert_eq!(producer.by_ref().count(), 1);
+        assert!(producer.take_error().is_some());
+    }
+}
@@ -0,0 +1,626 @@
+// Copyright 2024 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! Adapters exposing the stateless decoders and encoders with the session model of virtio-video
+//! and of the ChromeOS video decoder (VD) interface, for virtual machine monitors like crosvm.
+//!
+//! A session has an input and an output queue of resources, identified by the resource ids of the
+//! client. Resources are queued by the client and given back through the [`SessionEvent`]s
+//! retrieved with `next_event`, once the session is done with them:
+//!
+//! * [`DecoderSession`] takes bitstream resources as input, and decodes them into the output
+//!   resources provided by the client after a [`SessionEvent::ProvidePictureBuffers`] event. The
+//!   decoded pictures are lent to the client until it returns them with
+//!   [`DecoderSession::reuse_output_resource`].
+//! * [`EncoderSession`] takes frames as input, and passes their coded bitstream with the output
+//!   resources queued by the client, to be copied into them.
+//!
+//! Processing is synchronous: the events are queued from within the methods of the sessions.
+//! Timestamps are passed from each input to its output.
+
+use std::collections::BTreeMap;
+use std::collections::VecDeque;
+
+use thiserror::Error;
+
+use crate::decoder::stateless::DecodeError;
+use crate::decoder::stateless::PoolLayer;
+use crate::decoder::stateless::StatelessDecoderBackend;
+use crate::decoder::stateless::StatelessVideoDecoder;
+use crate::decoder::DecodedHandle;
+use crate::decoder::DecoderEvent;
+use crate::decoder::FramePool;
+use crate::encoder::stateless::EncodeError;
+use crate::encoder::stateless::StatelessVideoEncoder;
+use crate::encoder::FrameMetadata;
+use crate::DecodedFormat;
+use crate::FrameLayout;
+use crate::Resolution;
+
+/// Errors of the session methods, after which the session should be destroyed, except for
+/// [`SessionError::InvalidState`] and [`SessionError::InvalidResource`].
+#[derive(Debug, Error)]
+pub enum SessionError {
+    #[error("invalid state: {0}")]
+    InvalidState(&'static str),
+    #[error("unknown resource {0}")]
+    InvalidResource(u32),
+    #[error(transparent)]
+    DecodeError(#[from] DecodeError),
+    #[error(transparent)]
+    EncodeError(#[from] EncodeError),
+    #[error(transparent)]
+    Other(#[from] anyhow::Error),
+}
+
+/// Events of a session, named after their virtio-video counterparts.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SessionEvent {
+    /// The stream requires at least `min_num_buffers` output resources of `format` and
+    /// `coded_resolution`, to be provided with [`DecoderSession::use_output_resources`]. Decoding
+    /// is suspended until then.
+    ProvidePictureBuffers {
+        min_num_buffers: usize,
+        format: DecodedFormat,
+        coded_resolution: Resolution,
+        display_resolution: Resolution,
+    },
+    /// The input resource is not used by the session anymore.
+    InputDone { resource_id: u32, timestamp: u64 },
+    /// A picture was decoded into the output resource, which is lent to the client until returned
+    /// with [`DecoderSession::reuse_output_resource`].
+    PictureReady {
+        resource_id: u32,
+        timestamp: u64,
+        corrupted: bool,
+    },
+    /// The coded frame `data` is to be copied into the output resource, which is then returned to
+    /// the client. It is empty for skipped frames.
+    BitstreamReady {
+        resource_id: u32,
+        timestamp: u64,
+        data: Vec<u8>,
+    },
+    /// All the inputs queued before the drain request were processed and their outputs returned.
+    DrainCompleted,
+}
+
+type ResourceIdFn<B> =
+    Box<dyn FnMut(&<<B as StatelessDecoderBackend>::Handle as DecodedHandle>::Descriptor) -> u32>;
+
+/// Input resource queued to a [`DecoderSession`].
+struct PendingInput {
+    resource_id: u32,
+    timestamp: u64,
+    data: Vec<u8>,
+    /// Number of bytes of `data` already decoded
+    offset: usize,
+}
+
+/// A decoder session following the virtio-video model.
+pub struct DecoderSession<B: StatelessDecoderBackend> {
+    decoder: Box<dyn StatelessVideoDecoder<B>>,
+    output_format: DecodedFormat,
+    resource_id: ResourceIdFn<B>,
+    input_queue: VecDeque<PendingInput>,
+    /// Decoded pictures lent to the client, by resource id
+    pictures: BTreeMap<u32, B::Handle>,
+    events: VecDeque<SessionEvent>,
+    /// True if decoding is suspended until the client provides output resources
+    awaiting_resources: bool,
+    /// True if a drain completes once the input queue is empty
+    draining: bool,
+}
+
+impl<B: StatelessDecoderBackend> DecoderSession<B> {
+    /// Creates a session decoding with `decoder` into frames of `output_format`.
+    /// `resource_id` returns the resource id of the output resources provided by the client,
+    /// from their descriptor.
+    pub fn new(
+        decoder: Box<dyn StatelessVideoDecoder<B>>,
+        output_format: DecodedFormat,
+        resource_id: ResourceIdFn<B>,
+    ) -> Self {
+        Self {
+            decoder,
+            output_format,
+            resource_id,
+            input_queue: Default::default(),
+            pictures: Default::default(),
+            events: Default::default(),
+            awaiting_resources: false,
+            draining: false,
+        }
+    }
+
+    /// Returns the next event of the session, if any.
+    pub fn next_event(&mut self) -> Option<SessionEvent> {
+        self.events.pop_front()
+    }
+
+    /// Returns the decoded picture lent to the client in the output resource `resource_id`.
+    pub fn picture(&self, resource_id: u32) -> Option<&B::Handle> {
+        self.pictures.get(&resource_id)
+    }
+
+    /// Queues the bitstream `data` of the input resource `resource_id` for decoding. Its pictures
+    /// carry `timestamp`.
+    pub fn queue_input(
+        &mut self,
+        resource_id: u32,
+        timestamp: u64,
+        data: Vec<u8>,
+    ) -> Result<(), SessionError> {
+        self.input_queue.push_back(PendingInput {
+            resource_id,
+            timestamp,
+            data,
+            offset: 0,
+        });
+
+        self.process()
+    }
+
+    /// Adds output resources to decode into, as requested by a
+    /// [`SessionEvent::ProvidePictureBuffers`] event, and resumes decoding.
+    pub fn use_output_resources(
+        &mut self,
+        descriptors: Vec<<B::Handle as DecodedHandle>::Descriptor>,
+    ) -> Result<(), SessionError> {
+        let pool = self
+            .decoder
+            .frame_pool(PoolLayer::Highest)
+            .into_iter()
+            .next()
+            .ok_or(SessionError::InvalidState(
+                "the stream format is not known yet",
+            ))?;
+        pool.add_frames(descriptors)?;
+        self.awaiting_resources = false;
+
+        self.process()
+    }
+
+    /// Returns the picture of the output resource `resource_id` to the session, which can decode
+    /// into it again.
+    pub fn reuse_output_resource(&mut self, resource_id: u32) -> Result<(), SessionError> {
+        self.pictures
+            .remove(&resource_id)
+            .ok_or(SessionError::InvalidResource(resource_id))?;
+
+        self.process()
+    }
+
+    /// Requests the pictures of all the queued inputs, followed by a
+    /// [`SessionEvent::DrainCompleted`] event.
+    pub fn drain(&mut self) -> Result<(), SessionError> {
+        if self.draining {
+            return Err(SessionError::InvalidState("a drain is already in progress"));
+        }
+        self.draining = true;
+
+        self.process()
+    }
+
+    /// Discards the queued inputs, e.g. to seek, as well as the pictures decoded but not returned
+    /// yet. The input resources are returned with [`SessionEvent::InputDone`] events, and a
+    /// pending drain is cancelled. The next input should start at a keyframe.
+    pub fn clear_input_queue(&mut self) -> Result<(), SessionError> {
+        for input in self.input_queue.drain(..) {
+            self.events.push_back(SessionEvent::InputDone {
+                resource_id: input.resource_id,
+                timestamp: input.timestamp,
+            });
+        }
+        self.draining = false;
+
+        self.decoder.flush()?;
+        self.process_events(true)?;
+
+        Ok(())
+    }
+
+    /// Decodes the queued inputs as far as the output resources allow.
+    fn process(&mut self) -> Result<(), SessionError> {
+        while !self.awaiting_resources {
+            let Some(input) = self.input_queue.front_mut() else {
+                break;
+            };
+            if input.offset == input.data.len() {
+                self.events.push_back(SessionEvent::InputDone {
+                    resource_id: input.resource_id,
+                    timestamp: input.timestamp,
+                });
+                self.input_queue.pop_front();
+                continue;
+            }
+
+            match self
+                .decoder
+                .decode(input.timestamp, &input.data[input.offset..])
+            {
+                Ok(processed) => input.offset += processed,
+                Err(DecodeError::CheckEvents) => (),
+                // Wait for the client to return some pictures if they are all in use.
+                Err(DecodeError::NotEnoughOutputBuffers(_)) => {
+                    if !self.process_events(false)? {
+                        break;
+                    }
+                }
+                Err(e) => return Err(e.into()),
+            }
+            self.process_events(false)?;
+        }
+
+        if self.draining && self.input_queue.is_empty() && !self.awaiting_resources {
+            self.decoder.flush()?;
+            self.process_events(false)?;
+            self.draining = false;
+            self.events.push_back(SessionEvent::DrainCompleted);
+        }
+
+        Ok(())
+    }
+
+    /// Turns the events of the decoder into session events, dropping the decoded pictures if
+    /// `discard` is true. Returns false if there was no event.
+    fn process_events(&mut self, discard: bool) -> Result<bool, SessionError> {
+        let mut processed = false;
+        while let Some(event) = self.decoder.next_event() {
+            processed = true;
+            match event {
+                DecoderEvent::FrameReady(handle) if !discard => {
+                    handle.sync()?;
+                    let resource_id = (self.resource_id)(&handle.resource());
+                    self.events.push_back(SessionEvent::PictureReady {
+                        resource_id,
+                        timestamp: handle.timestamp(),
+                        corrupted: handle.is_corrupted(),
+                    });
+                    self.pictures.insert(resource_id, handle);
+                }
+                DecoderEvent::FrameReady(_) => (),
+                DecoderEvent::FormatChanged(mut format_setter) => {
+                    format_setter.try_format(self.output_format)?;
+                    let stream_info = format_setter.stream_info();
+                    self.events.push_back(SessionEvent::ProvidePictureBuffers {
+                        min_num_buffers: stream_info.min_num_frames,
+                        format: stream_info.format,
+                        coded_resolution: stream_info.coded_resolution,
+                        display_resolution: stream_info.display_resolution,
+                    });
+                    self.awaiting_resources = true;
+                }
+            }
+        }
+
+        Ok(processed)
+    }
+}
+
+/// An encoder session following the virtio-video model.
+pub struct EncoderSession<H> {
+    encoder: Box<dyn StatelessVideoEncoder<H>>,
+    /// Memory layout of the input frames
+    layout: FrameLayout,
+    display_resolution: Resolution,
+    /// Input resources being encoded, in submission order, with their timestamp
+    in_flight: VecDeque<(u32, u64)>,
+    /// Coded frames waiting for an output resource, with the timestamp of their input
+    coded_queue: VecDeque<(u64, Vec<u8>)>,
+    output_queue: VecDeque<u32>,
+    events: VecDeque<SessionEvent>,
+    /// True if a drain completes once all the coded frames are returned
+    draining: bool,
+}
+
+impl<H> EncoderSession<H> {
+    /// Creates a session encoding with `encoder` frames of `layout`, whose visible part is
+    /// `display_resolution`.
+    pub fn new(
+        encoder: Box<dyn StatelessVideoEncoder<H>>,
+        layout: FrameLayout,
+        display_resolution: Resolution,
+    ) -> Self {
+        Self {
+            encoder,
+            layout,
+            display_resolution,
+            in_flight: Default::default(),
+            coded_queue: Default::default(),
+            output_queue: Default::default(),
+            events: Default::default(),
+            draining: false,
+        }
+    }
+
+    /// Returns the next event of the session, if any.
+    pub fn next_event(&mut self) -> Option<SessionEvent> {
+        self.events.pop_front()
+    }
+
+    /// Encodes the frame `handle` of the input resource `resource_id`, as a keyframe if
+    /// `force_keyframe` is true. Its coded frame carries `timestamp`.
+    ///
+    /// Fails with [`EncodeError::WouldBlock`] if the encoder cannot accept more frames, in which
+    /// case `handle` is dropped without being encoded and the session does not keep track of
+    /// `resource_id`.
+    pub fn queue_input(
+        &mut self,
+        resource_id: u32,
+        timestamp: u64,
+        handle: H,
+        force_keyframe: bool,
+    ) -> Result<(), SessionError> {
+        if self.draining {
+            return Err(SessionError::InvalidState("a drain is in progress"));
+        }
+
+        // The encoders output the frames in submission order, so the input timestamps can be
+        // restored from `in_flight` whatever the encoder does to them.
+        let meta = FrameMetadata {
+            timestamp,
+            display_resolution: self.display_resolution,
+            layout: self.layout.clone(),
+            force_keyframe,
+            damage: None,
+        };
+        self.encoder.encode(meta, handle)?;
+        self.in_flight.push_back((resource_id, timestamp));
+
+        self.process()
+    }
+
+    /// Queues the output resource `resource_id`, to receive the next coded frame.
+    pub fn queue_output(&mut self, resource_id: u32) -> Result<(), SessionError> {
+        self.output_queue.push_back(resource_id);
+
+        self.process()
+    }
+
+    /// Requests the coded frames of all the queued inputs, followed by a
+    /// [`SessionEvent::DrainCompleted`] event once they are all returned.
+    pub fn drain(&mut self) -> Result<(), SessionError> {
+        if self.draining {
+            return Err(SessionError::InvalidState("a drain is already in progress"));
+        }
+        self.encoder.drain()?;
+        self.draining = true;
+
+        self.process()
+    }
+
+    /// Collects the coded frames completed so far, for encoders created in non-blocking mode.
+    pub fn poll(&mut self) -> Result<(), SessionError> {
+        self.process()
+    }
+
+    /// Returns the completed coded frames into the queued output resources.
+    fn process(&mut self) -> Result<(), SessionError> {
+        while let Some(coded) = self.encoder.poll()? {
+            let (resource_id, timestamp) = self
+                .in_flight
+                .pop_front()
+                .ok_or_else(|| anyhow::anyhow!("coded buffer without a matching frame"))?;
+            self.events.push_back(SessionEvent::InputDone {
+                resource_id,
+                timestamp,
+            });
+            self.coded_queue
+                .push_back((timestamp, coded.bitstream.into()));
+        }
+
+        while !self.coded_queue.is_empty() {
+            let Some(resource_id) = self.output_queue.pop_front() else {
+                break;
+            };
+            let (timestamp, data) = self.coded_queue.pop_front().unwrap();
+            self.events.push_back(SessionEvent::BitstreamReady {
+                resource_id,
+                timestamp,
+                data,
+            });
+        }
+
+        if self.draining && self.in_flight.is_empty() && self.coded_queue.is_empty() {
+            self.draining = false;
+            self.events.push_back(SessionEvent::DrainCompleted);
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::decoder::stateless::h264::tests::DECODE_TEST_25FPS;
+    use crate::decoder::stateless::h264::H264;
+    use crate::decoder::stateless::StatelessDecoder;
+    use crate::encoder::stateless::EncodeResult;
+    use crate::encoder::CodedBitstreamBuffer;
+    use crate::BlockingMode;
+    use crate::Fourcc;
+
+    /// Encoder outputting each frame after the next one, with its timestamp as bitstream.
+    #[derive(Default)]
+    struct DelayingEncoder {
+        pending: VecDeque<FrameMetadata>,
+        output: VecDeque<CodedBitstreamBuffer>,
+    }
+
+    impl DelayingEncoder {
+        fn output(&mut self, meta: FrameMetadata) {
+            let bitstream = vec![meta.timestamp as u8];
+            self.output
+                .push_back(CodedBitstreamBuffer::new(meta, bitstream));
+        }
+    }
+
+    impl StatelessVideoEncoder<()> for DelayingEncoder {
+        fn encode(&mut self, meta: FrameMetadata, _: ()) -> EncodeResult<()> {
+            self.pending.push_back(meta);
+            if self.pending.len() > 1 {
+                let meta = self.pending.pop_front().unwrap();
+                self.output(meta);
+            }
+
+            Ok(())
+        }
+
+        fn drain(&mut self) -> EncodeResult<()> {
+            while let Some(meta) = self.pending.pop_front() {
+                self.output(meta);
+            }
+
+            Ok(())
+        }
+
+        fn poll(&mut self) -> EncodeResult<Option<CodedBitstreamBuffer>> {
+            Ok(self.output.pop_front())
+        }
+
+        fn request_segment_point(&mut self) {}
+    }
+
+    fn events(events: impl FnMut() -> Option<SessionEvent>) -> Vec<SessionEvent> {
+        std::iter::from_fn(events).collect()
+    }
+
+    #[test]
+    fn decoder_session() {
+        let decoder = StatelessDecoder::<H264, _>::new_dummy(BlockingMode::Blocking);
+        // The frames of the dummy backend cannot be told apart, number them in decoding order.
+        let mut next_resource_id = 0;
+        let mut session = DecoderSession::new(
+            Box::new(decoder),
+            DecodedFormat::NV12,
+            Box::new(move |_| {
+                next_resource_id += 1;
+                next_resource_id
+            }),
+        );
+        assert!(matches!(
+            session.reuse_output_resource(1),
+            Err(SessionError::InvalidResource(1))
+        ));
+
+        session
+            .queue_input(10, 0, DECODE_TEST_25FPS.stream.to_vec())
+            .unwrap();
+        session.drain().unwrap();
+        let SessionEvent::ProvidePictureBuffers {
+            min_num_buffers, ..
+        } = session.next_event().unwrap()
+        else {
+            panic!("output resources were not requested");
+        };
+        // Decoding is suspended until then.
+        assert!(session.next_event().is_none());
+
+        session
+            .use_output_resources(vec![(); min_num_buffers])
+            .unwrap();
+        let events = events(|| session.next_event());
+        let pictures = events
+            .iter()
+            .filter_map(|event| match event {
+                SessionEvent::PictureReady { resource_id, .. } => Some(*resource_id),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(pictures.len(), DECODE_TEST_25FPS.crcs.lines().count());
+        assert!(events.contains(&SessionEvent::InputDone {
+            resource_id: 10,
+            timestamp: 0
+        }));
+        assert_eq!(events.last(), Some(&SessionEvent::DrainCompleted));
+
+        assert!(session.picture(pictures[0]).is_some());
+        session.reuse_output_resource(pictures[0]).unwrap();
+        assert!(session.picture(pictures[0]).is_none());
+    }
+
+    #[test]
+    fn decoder_session_clear() {
+        let decoder = StatelessDecoder::<H264, _>::new_dummy(BlockingMode::Blocking);
+        let mut session =
+            DecoderSession::new(Box::new(decoder), DecodedFormat::NV12, Box::new(|_| 0));
+
+        session
+            .queue_input(1, 0, DECODE_TEST_25FPS.stream.to_vec())
+            .unwrap();
+        session.queue_input(2, 1, vec![]).unwrap();
+        assert!(matches!(
+            session.next_event(),
+            Some(SessionEvent::ProvidePictureBuffers { .. })
+        ));
+
+        // The inputs waiting for output resources are returned.
+        session.clear_input_queue().unwrap();
+        assert_eq!(
+            events(|| session.next_event()),
+            [
+                SessionEvent::InputDone {
+                    resource_id: 1,
+                    timestamp: 0
+                },
+                SessionEvent::InputDone {
+                    resource_id: 2,
+                    timestamp: 1
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn encoder_session() {
+        let layout = FrameLayout {
+            format: (Fourcc::from(b"NV12"), 0),
+            size: Resolution::from((64, 64)),
+            planes: vec![],
+        };
+        let mut session = EncoderSession::new(
+            Box::new(DelayingEncoder::default()),
+            layout,
+            Resolution::from((64, 64)),
+        );
+
+        session.queue_output(100).unwrap();
+        session.queue_input(1, 10, (), false).unwrap();
+        assert!(session.next_event().is_none());
+        session.queue_input(2, 20, (), false).unwrap();
+        session.queue_input(3, 30, (), false).unwrap();
+        session.drain().unwrap();
+        assert!(matches!(
+            session.queue_input(4, 40, (), false),
+            Err(SessionError::InvalidState(_))
+        ));
+
+        // The coded frames wait for output resources.
+        session.queue_output(101).unwrap();
+        session.queue_output(102).unwrap();
+        let bitstream = |resource_id, timestamp: u64| SessionEvent::BitstreamReady {
+            resource_id,
+            timestamp,
+            data: vec![timestamp as u8],
+        };
+        let input_done = |resource_id, timestamp| SessionEvent::InputDone {
+            resource_id,
+            timestamp,
+        };
+        assert_eq!(
+            events(|| session.next_event()),
+            [
+                input_done(1, 10),
+                bitstream(100, 10),
+                input_done(2, 20),
+                input_done(3, 30),
+                bitstream(101, 20),
+                bitstream(102, 30),
+                SessionEvent::DrainCompleted,
+            ]
+        );
+    }
+}
@@ -0,0 +1,1150 @@
+// Copyright 2024 The ChromiumOS Authors
+// Use of this source code is governed by a BSD-style license that can be
+// found in the LICENSE file.
+
+//! A video decoder and encoder API shaped after WebCodecs, layered on the stateless decoders and
+//! encoders, to ease porting browser-oriented code.
+//!
+//! [`VideoDecoder`] and [`VideoEncoder`] follow the state machine of their WebCodecs counterparts:
+//! they must be configured before use, and can then be flushed, reset, reconfigured or closed.
+//! Misuse, like decoding with an unconfigured decoder, fails synchronously as it would throw in
+//! WebCodecs, while processing errors are reported to the error callback and close the codec.
+//!
+//! Unlike WebCodecs, processing is synchronous: the output callback is called from within the
+//! `decode`, `encode` and `flush` methods, so there is no queue of pending requests. Decoded frames
+//! keep their buffer in use by the decoder until they are dropped, which takes the role of
+//! `VideoFrame.close()`.
+//!
+//! Timestamps and durations are in microseconds. They are passed from each input to its output
+//! and are independent from the timestamps used internally by the decoders and encoders.
+
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+
+use thiserror::Error;
+
+use crate::codec::av1::parser::FrameType;
+use crate::codec::av1::parser::ObuType;
+use crate::codec::av1::parser::ParsedObu;
+use crate::codec::av1::parser::Parser;
+use crate::codec::h264::avcc::AvcDecoderConfigurationRecord;
+use crate::codec::h264::nalu::annexb_to_length_prefixed;
+use crate::codec::h264::nalu::length_prefixed_to_annexb;
+use crate::codec::h264::parser::Level;
+use crate::codec::h264::parser::NaluType as H264NaluType;
+use crate::codec::h264::parser::Profile;
+use crate::codec::h265::hvcc::HevcDecoderConfigurationRecord;
+use crate::decoder::stateless::DecodeError;
+use crate::decoder::stateless::PoolLayer;
+use crate::decoder::stateless::StatelessDecoderBackend;
+use crate::decoder::stateless::StatelessVideoDecoder;
+use crate::decoder::DecodedHandle;
+use crate::decoder::DecoderEvent;
+use crate::decoder::FramePool;
+use crate::decoder::StreamInfo;
+use crate::encoder::stateless::StatelessVideoEncoder;
+use crate::encoder::units::UnitType;
+use crate::encoder::CodedBitstreamBuffer;
+use crate::encoder::FrameMetadata;
+use crate::utils::demux::TrackCodec;
+use crate::DecodedFormat;
+use crate::FrameLayout;
+use crate::Resolution;
+
+#[cfg(feature = "vaapi")]
+use crate::backend::vaapi::decoder::VaapiBackend as VaapiDecoderBackend;
+
+/// State of a [`VideoDecoder`] or [`VideoEncoder`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CodecState {
+    Unconfigured,
+    Configured,
+    Closed,
+}
+
+/// Errors of the WebCodecs API, named after the `DOMException` they correspond to.
+#[derive(Debug, Error)]
+pub enum WebCodecsError {
+    #[error("invalid state: {0}")]
+    InvalidState(&'static str),
+    #[error("configuration not supported: {0}")]
+    NotSupported(String),
+    #[error("invalid data: {0}")]
+    Data(&'static str),
+    #[error("codec error: {0:#}")]
+    Encoding(anyhow::Error),
+}
+
+/// Callback receiving the processing errors of a codec, after which the codec is closed.
+pub type ErrorCallback = Box<dyn FnMut(&WebCodecsError)>;
+
+/// Type of an [`EncodedVideoChunk`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EncodedVideoChunkType {
+    /// The chunk can be decoded without any previous chunk.
+    Key,
+    /// The chunk depends on previous chunks.
+    Delta,
+}
+
+/// A chunk of encoded video, i.e. one frame, or one access unit for H.264 and H.265.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct EncodedVideoChunk {
+    pub type_: EncodedVideoChunkType,
+    pub timestamp: i64,
+    pub duration: Option<u64>,
+    pub data: Vec<u8>,
+}
+
+/// A frame with its timing information. `handle` is a decoded handle for frames output by a
+/// [`VideoDecoder`], or the input handle of the encoder for frames passed to a [`VideoEncoder`].
+pub struct VideoFrame<H> {
+    pub timestamp: i64,
+    pub duration: Option<u64>,
+    pub handle: H,
+}
+
+/// Returns the codec of the WebCodecs codec string `codec`, e.g. `"avc1.42E01E"` or
+/// `"vp09.00.10.08"`.
+pub fn codec_from_string(codec: &str) -> Option<TrackCodec> {
+    let fourcc = codec.split('.').next()?;
+    match fourcc {
+        "avc1" | "avc3" => Some(TrackCodec::H264),
+        "hvc1" | "hev1" => Some(TrackCodec::H265),
+        "vp8" => Some(TrackCodec::Vp8),
+        "vp09" => Some(TrackCodec::Vp9),
+        "av01" => Some(TrackCodec::Av1),
+        _ => None,
+    }
+}
+
+/// Returns the profile and level of the H.264 codec string `codec`, e.g. `"avc1.42E01E"`.
+pub fn avc_profile_level(codec: &str) -> Option<(Profile, Level)> {
+    let (fourcc, params) = codec.split_once('.')?;
+    if !matches!(fourcc, "avc1" | "avc3") || params.len() != 6 {
+        return None;
+    }
+
+    let profile_idc = u8::from_str_radix(params.get(0..2)?, 16).ok()?;
+    let level_idc = u8::from_str_radix(params.get(4..6)?, 16).ok()?;
+
+    Some((Profile::n(profile_idc)?, Level::n(level_idc)?))
+}
+
+/// Timing of an input, to be given back to its output.
+struct Timing {
+    timestamp: i64,
+    duration: Option<u64>,
+}
+
+/// Assigns internal timestamps to inputs and keeps track of their timing until their output.
+#[derive(Default)]
+struct TimingMap {
+    next_timestamp: u64,
+    pending: BTreeMap<u64, Timing>,
+}
+
+impl TimingMap {
+    /// Records the timing of a new input, returning its internal timestamp.
+    fn push(&mut self, timestamp: i64, duration: Option<u64>) -> u64 {
+        let internal = self.next_timestamp;
+        self.next_timestamp += 1;
+        self.pending.insert(
+            internal,
+            Timing {
+                timestamp,
+                duration,
+            },
+        );
+
+        internal
+    }
+
+    fn take(&mut self, internal: u64) -> Option<Timing> {
+        self.pending.remove(&internal)
+    }
+
+    fn clear(&mut self) {
+        self.pending.clear();
+    }
+}
+
+/// Configuration of a [`VideoDecoder`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct VideoDecoderConfig {
+    /// WebCodecs codec string, e.g. `"avc1.42E01E"`.
+    pub codec: String,
+    /// `avcC` or `hvcC` record of H.264 or H.265 streams. If present, the chunks are in
+    /// length-prefixed format, otherwise they are in Annex B format.
+    pub description: Option<Vec<u8>>,
+}
+
+type DecoderFactory<B> =
+    Box<dyn FnMut(TrackCodec) -> anyhow::Result<Box<dyn StatelessVideoDecoder<B>>>>;
+type FrameAllocator<B> = Box<
+    dyn FnMut(
+        &StreamInfo,
+        usize,
+    ) -> anyhow::Result<
+        Vec<<<B as StatelessDecoderBackend>::Handle as DecodedHandle>::Descriptor>,
+    >,
+>;
+
+/// Output side of a [`VideoDecoder`], split from the decoder so both can be borrowed at the same
+/// time.
+struct DecoderSink<B: StatelessDecoderBackend> {
+    output: Box<dyn FnMut(VideoFrame<B::Handle>)>,
+    allocate_new_frames: FrameAllocator<B>,
+    output_format: DecodedFormat,
+    timings: TimingMap,
+}
+
+impl<B: StatelessDecoderBackend> DecoderSink<B> {
+    fn process_events(&mut self, decoder: &mut dyn StatelessVideoDecoder<B>) -> anyhow::Result<()> {
+        while let Some(event) = decoder.next_event() {
+            match event {
+                DecoderEvent::FrameReady(handle) => {
+                    let timing = self
+                        .timings
+                        .take(handle.timestamp())
+                        .ok_or_else(|| anyhow::anyhow!("decoded frame without a matching chunk"))?;
+                    (self.output)(VideoFrame {
+                        timestamp: timing.timestamp,
+                        duration: timing.duration,
+                        handle,
+                    });
+                }
+                DecoderEvent::FormatChanged(mut format_setter) => {
+                    format_setter.try_format(self.output_format)?;
+                    let stream_info = format_setter.stream_info().clone();
+                    let min_num_frames = stream_info.min_num_frames;
+                    let pools = format_setter.frame_pool(PoolLayer::All);
+                    let nb_pools = pools.len();
+                    for pool in pools {
+                        let pool_num_frames = pool.num_managed_frames();
+                        if pool_num_frames < (min_num_frames / nb_pools) {
+                            let frames = (self.allocate_new_frames)(
+                                &stream_info,
+                                min_num_frames - pool_num_frames,
+                            )?;
+                            pool.add_frames(frames)?;
+                        }
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// A decoder following the semantics of the WebCodecs `VideoDecoder`.
+pub struct VideoDecoder<B: StatelessDecoderBackend> {
+    state: CodecState,
+    error: ErrorCallback,
+    create_decoder: DecoderFactory<B>,
+    decoder: Option<Box<dyn StatelessVideoDecoder<B>>>,
+    sink: DecoderSink<B>,
+    /// Size of the length prefix of the NAL units, or 0 if chunks are in Annex B format.
+    nal_length_size: usize,
+    /// Parameter sets of the decoder configuration, to be submitted with the next key chunk.
+    parameter_sets: Vec<u8>,
+    key_chunk_required: bool,
+}
+
+impl<B: StatelessDecoderBackend + 'static> VideoDecoder<B> {
+    /// Creates an unconfigured decoder.
+    ///
+    /// `output` is called with every decoded frame and `error` with processing errors.
+    /// `create_decoder` creates the decoder of each configuration, whose frames are allocated
+    /// with `allocate_new_frames` and decoded in `output_format`.
+    pub fn new(
+        output: Box<dyn FnMut(VideoFrame<B::Handle>)>,
+        error: ErrorCallback,
+        create_decoder: DecoderFactory<B>,
+        allocate_new_frames: FrameAllocator<B>,
+        output_format: DecodedFormat,
+    ) -> Self {
+        Self {
+            state: CodecState::Unconfigured,
+            error,
+            create_decoder,
+            decoder: None,
+            sink: DecoderSink {
+                output,
+                allocate_new_frames,
+                output_format,
+                timings: Default::default(),
+            },
+            nal_length_size: 0,
+            parameter_sets: Vec::new(),
+            key_chunk_required: true,
+        }
+    }
+
+    pub fn state(&self) -> CodecState {
+        self.state
+    }
+
+    /// Closes the decoder with `error`, reporting it to the error callback.
+    fn fail(&mut self, error: WebCodecsError) -> WebCodecsError {
+        self.close();
+        (self.error)(&error);
+        error
+    }
+
+    /// Configures the decoder, discarding all frames of a previous configuration. The next chunk
+    /// must be a key chunk.
+    pub fn configure(&mut self, config: &VideoDecoderConfig) -> Result<(), WebCodecsError> {
+        if self.state == CodecState::Closed {
+            return Err(WebCodecsError::InvalidState("decoder is closed"));
+        }
+
+        let codec = match codec_from_string(&config.codec) {
+            Some(codec) => codec,
+            None => {
+                let error =
+                    WebCodecsError::NotSupported(format!("unknown codec {:?}", config.codec));
+                return Err(self.fail(error));
+            }
+        };
+
+        let description = match (codec, &config.description) {
+            (TrackCodec::H264, Some(description)) => {
+                AvcDecoderConfigurationRecord::parse(description)
+                    .map(|record| (record.nal_length_size(), record.to_annexb()))
+            }
+            (TrackCodec::H265, Some(description)) => {
+                HevcDecoderConfigurationRecord::parse(description)
+                    .map(|record| (record.nal_length_size(), record.to_annexb()))
+            }
+            (_, Some(_)) => Err(anyhow::anyhow!("{:?} streams take no description", codec)),
+            (_, None) => Ok((0, Vec::new())),
+        };
+        let (nal_length_size, parameter_sets) = match description {
+            Ok(description) => description,
+            Err(e) => {
+                let error = WebCodecsError::NotSupported(format!("invalid description: {:#}", e));
+                return Err(self.fail(error));
+            }
+        };
+
+        // Drop the previous decoder and its frames before creating a new one.
+        self.decoder = None;
+        self.sink.timings.clear();
+        self.decoder = match (self.create_decoder)(codec) {
+            Ok(decoder) => Some(decoder),
+            Err(e) => return Err(self.fail(WebCodecsError::NotSupported(format!("{:#}", e)))),
+        };
+        self.nal_length_size = nal_length_size;
+        self.parameter_sets = parameter_sets;
+        self.key_chunk_required = true;
+        self.state = CodecState::Configured;
+
+        Ok(())
+    }
+
+    fn decode_chunk(&mut self, timestamp: u64, chunk: &EncodedVideoChunk) -> anyhow::Result<()> {
+        let data = if self.nal_length_size > 0 {
+            let mut annexb = std::mem::take(&mut self.parameter_sets);
+            annexb.extend(length_prefixed_to_annexb(
+                &chunk.data,
+                self.nal_length_size,
+            )?);
+            Cow::Owned(annexb)
+        } else {
+            Cow::Borrowed(&chunk.data[..])
+        };
+
+        let decoder = self
+            .decoder
+            .as_deref_mut()
+            .ok_or_else(|| anyhow::anyhow!("no decoder"))?;
+        let mut bitstream = &data[..];
+        while !bitstream.is_empty() {
+            match decoder.decode(timestamp, bitstream) {
+                Ok(processed) => {
+                    bitstream = &bitstream[processed..];
+                    self.sink.process_events(decoder)?;
+                }
+                Err(DecodeError::CheckEvents) | Err(DecodeError::NotEnoughOutputBuffers(_)) => {
+                    self.sink.process_events(decoder)?
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Decodes `chunk`. Frames decoded from it are passed to the output callback with its
+    /// timestamp and duration, possibly during a later call because of reordering.
+    ///
+    /// Errors while decoding are reported to the error callback and close the decoder.
+    pub fn decode(&mut self, chunk: &EncodedVideoChunk) -> Result<(), WebCodecsError> {
+        if self.state != CodecState::Configured {
+            return Err(WebCodecsError::InvalidState("decoder is not configured"));
+        }
+        if self.key_chunk_required {
+            if chunk.type_ != EncodedVideoChunkType::Key {
+                return Err(WebCodecsError::Data("a key chunk is required"));
+            }
+            self.key_chunk_required = false;
+        }
+
+        let timestamp = self.sink.timings.push(chunk.timestamp, chunk.duration);
+        if let Err(e) = self.decode_chunk(timestamp, chunk) {
+            self.fail(WebCodecsError::Encoding(e));
+        }
+
+        Ok(())
+    }
+
+    /// Outputs all the frames of the chunks decoded so far. The next chunk must be a key chunk.
+    pub fn flush(&mut self) -> Result<(), WebCodecsError> {
+        if self.state != CodecState::Configured {
+            return Err(WebCodecsError::InvalidState("decoder is not configured"));
+        }
+
+        let result = match self.decoder.as_deref_mut() {
+            Some(decoder) => decoder
+                .flush()
+                .map_err(anyhow::Error::from)
+                .and_then(|()| self.sink.process_events(decoder)),
+            None => Ok(()),
+        };
+        // Chunks that did not produce any frame will never be output.
+        self.sink.timings.clear();
+        self.key_chunk_required = true;
+
+        result.map_err(|e| self.fail(WebCodecsError::Encoding(e)))
+    }
+
+    /// Discards the decoder and all its pending frames, returning to the unconfigured state.
+    pub fn reset(&mut self) -> Result<(), WebCodecsError> {
+        if self.state == CodecState::Closed {
+            return Err(WebCodecsError::InvalidState("decoder is closed"));
+        }
+
+        self.decoder = None;
+        self.sink.timings.clear();
+        self.state = CodecState::Unconfigured;
+
+        Ok(())
+    }
+
+    /// Discards the decoder and all its pending frames. The decoder cannot be used anymore.
+    pub fn close(&mut self) {
+        self.decoder = None;
+        self.sink.timings.clear();
+        self.state = CodecState::Closed;
+    }
+}
+
+#[cfg(feature = "vaapi")]
+impl VideoDecoder<VaapiDecoderBackend<()>> {
+    /// Creates an unconfigured decoder using VAAPI on `display`, outputting NV12 frames allocated
+    /// by the decoder.
+    pub fn new_vaapi(
+        display: std::rc::Rc<libva::Display>,
+        output: Box<
+            dyn FnMut(VideoFrame<<VaapiDecoderBackend<()> as StatelessDecoderBackend>::Handle>),
+        >,
+        error: ErrorCallback,
+    ) -> Self {
+        use crate::decoder::stateless::av1::Av1;
+        use crate::decoder::stateless::h264::H264;
+        use crate::decoder::stateless::h265::H265;
+        use crate::decoder::stateless::vp8::Vp8;
+        use crate::decoder::stateless::vp9::Vp9;
+        use crate::decoder::stateless::StatelessDecoder;
+        use crate::utils::simple_playback_loop_owned_frames;
+        use crate::BlockingMode;
+
+        let create_decoder = move |codec| {
+            let display = std::rc::Rc::clone(&display);
+            let blocking_mode = BlockingMode::Blocking;
+            Ok(match codec {
+                TrackCodec::H264 => Box::new(StatelessDecoder::<H264, _>::new_vaapi(
+                    display,
+                    blocking_mode,
+                )) as Box<dyn StatelessVideoDecoder<_>>,
+                TrackCodec::H265 => Box::new(StatelessDecoder::<H265, _>::new_vaapi(
+                    display,
+                    blocking_mode,
+                )) as Box<dyn StatelessVideoDecoder<_>>,
+                TrackCodec::Vp8 => Box::new(StatelessDecoder::<Vp8, _>::new_vaapi(
+                    display,
+                    blocking_mode,
+                )) as Box<dyn StatelessVideoDecoder<_>>,
+                TrackCodec::Vp9 => Box::new(StatelessDecoder::<Vp9, _>::new_vaapi(
+                    display,
+                    blocking_mode,
+                )) as Box<dyn StatelessVideoDecoder<_>>,
+                TrackCodec::Av1 => Box::new(StatelessDecoder::<Av1, _>::new_vaapi(
+                    display,
+                    blocking_mode,
+                )) as Box<dyn StatelessVideoDecoder<_>>,
+            })
+        };
+
+        Self::new(
+            output,
+            error,
+            Box::new(create_decoder),
+            Box::new(simple_playback_loop_owned_frames),
+            DecodedFormat::NV12,
+        )
+    }
+}
+
+/// Bitstream format of H.264 chunks produced by a [`VideoEncoder`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum AvcBitstreamFormat {
+    /// Annex B chunks with in-band parameter sets.
+    #[default]
+    Annexb,
+    /// Length-prefixed chunks, with the parameter sets also provided as an `avcC` record in the
+    /// description of the decoder configuration.
+    Avc,
+}
+
+/// Configuration of a [`VideoEncoder`].
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct VideoEncoderConfig {
+    /// WebCodecs codec string, e.g. `"avc1.42E01E"`.
+    pub codec: String,
+    pub width: u32,
+    pub height: u32,
+    /// Target bitrate in bits per second.
+    pub bitrate: Option<u64>,
+    pub framerate: Option<f64>,
+    pub avc_format: AvcBitstreamFormat,
+}
+
+/// Metadata passed with an [`EncodedVideoChunk`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct EncodedVideoChunkMetadata {
+    /// Configuration to decode this chunk and the following ones, set on the first chunk after
+    /// a configuration and whenever it changes.
+    pub decoder_config: Option<VideoDecoderConfig>,
+}
+
+/// Options of [`VideoEncoder::encode`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct VideoEncoderEncodeOptions {
+    /// Whether the frame must be encoded as a key chunk.
+    pub key_frame: bool,
+}
+
+type EncoderFactory<H> =
+    Box<dyn FnMut(&VideoEncoderConfig) -> anyhow::Result<Box<dyn StatelessVideoEncoder<H>>>>;
+type ChunkCallback = Box<dyn FnMut(EncodedVideoChunk, EncodedVideoChunkMetadata)>;
+
+/// Returns whether `coded` can be decoded independently of the previous chunks.
+fn is_key_chunk(codec: TrackCodec, coded: &CodedBitstreamBuffer) -> bool {
+    match codec {
+        // An IDR picture for H.264, an IRAP picture for H.265.
+        TrackCodec::H264 => coded
+            .h264_nal_units()
+            .any(|nalu| nalu.unit_type == Some(UnitType::H264(H264NaluType::SliceIdr))),
+        TrackCodec::H265 => coded
+            .h265_nal_units()
+            .any(|nalu| matches!(nalu.unit_type, Some(UnitType::H265(type_)) if type_.is_irap())),
+        // The first bit of the frame tag is 0 for key frames.
+        TrackCodec::Vp8 => coded.bitstream.first().map(|b| b & 0x1) == Some(0),
+        // frame_type follows the frame marker, profile and show_existing_frame bits.
+        TrackCodec::Vp9 => match coded.bitstream.first() {
+            Some(b) if (b >> 4) & 0x3 == 0x3 => b & 0x2 == 0,
+            Some(b) => b & 0x4 == 0,
+            None => false,
+        },
+        TrackCodec::Av1 => is_av1_key_temporal_unit(&coded.bitstream),
+    }
+}
+
+/// Returns whether the AV1 temporal unit `data` can be decoded on its own, i.e. holds a sequence
+/// header followed by a shown key frame.
+fn is_av1_key_temporal_unit(data: &[u8]) -> bool {
+    let mut parser = Parser::default();
+    let mut sequence_header = false;
+    let mut consumed = 0;
+
+    while consumed < data.len() {
+        let obu = match parser.parse_obu(&data[consumed..]) {
+            Ok(ParsedObu::Process(obu)) => obu,
+            Ok(ParsedObu::Drop(length)) => {
+                consumed += length as usize;
+                continue;
+            }
+            Err(_) => return false,
+        };
+
+        match obu.header.obu_type {
+            ObuType::SequenceHeader => {
+                sequence_header = parser.parse_sequence_header_obu(&obu).is_ok();
+            }
+            // Only the first frame of the temporal unit matters.
+            ObuType::Frame | ObuType::FrameHeader => {
+                return sequence_header
+                    && parser.parse_frame_header_obu(&obu).is_ok_and(|header| {
+                        header.frame_type == FrameType::KeyFrame && header.show_frame
+                    });
+            }
+            _ => (),
+        }
+
+        consumed += obu.data.len();
+    }
+
+    false
+}
+
+/// An encoder following the semantics of the WebCodecs `VideoEncoder`.
+pub struct VideoEncoder<H> {
+    state: CodecState,
+    output: ChunkCallback,
+    error: ErrorCallback,
+    create_encoder: EncoderFactory<H>,
+    encoder: Option<Box<dyn StatelessVideoEncoder<H>>>,
+    config: VideoEncoderConfig,
+    codec: TrackCodec,
+    /// Decoder configuration passed with the last chunk, or `None` if it must be passed with the
+    /// next one.
+    decoder_config: Option<VideoDecoderConfig>,
+    timings: TimingMap,
+}
+
+impl<H: 'static> VideoEncoder<H> {
+    /// Creates an unconfigured encoder.
+    ///
+    /// `output` is called with every encoded chunk and `error` with processing errors.
+    /// `create_encoder` creates the encoder of each configuration.
+    pub fn new(
+        output: ChunkCallback,
+        error: ErrorCallback,
+        create_encoder: EncoderFactory<H>,
+    ) -> Self {
+        Self {
+            state: CodecState::Unconfigured,
+            output,
+            error,
+            create_encoder,
+            encoder: None,
+            config: Default::default(),
+            codec: TrackCodec::H264,
+            decoder_config: None,
+            timings: Default::default(),
+        }
+    }
+
+    pub fn state(&self) -> CodecState {
+        self.state
+    }
+
+    /// Closes the encoder with `error`, reporting it to the error callback.
+    fn fail(&mut self, error: WebCodecsError) -> WebCodecsError {
+        self.close();
+        (self.error)(&error);
+        error
+    }
+
+    /// Configures the encoder. Pending frames of a previous configuration are discarded.
+    pub fn configure(&mut self, config: &VideoEncoderConfig) -> Result<(), WebCodecsError> {
+        if self.state == CodecState::Closed {
+            return Err(WebCodecsError::InvalidState("encoder is closed"));
+        }
+
+        let codec = match codec_from_string(&config.codec) {
+            Some(codec) => codec,
+            None => {
+                let error =
+                    WebCodecsError::NotSupported(format!("unknown codec {:?}", config.codec));
+                return Err(self.fail(error));
+            }
+        };
+
+        self.encoder = None;
+        self.timings.clear();
+        self.encoder = match (self.create_encoder)(config) {
+            Ok(encoder) => Some(encoder),
+            Err(e) => return Err(self.fail(WebCodecsError::NotSupported(format!("{:#}", e)))),
+        };
+        self.config = config.clone();
+        self.codec = codec;
+        self.decoder_config = None;
+        self.state = CodecState::Configured;
+
+        Ok(())
+    }
+
+    /// Returns the chunk and its metadata for `coded`.
+    fn make_chunk(
+        &mut self,
+        coded: CodedBitstreamBuffer,
+    ) -> anyhow::Result<(EncodedVideoChunk, EncodedVideoChunkMetadata)> {
+        let timing = self
+            .timings
+            .take(coded.metadata.timestamp)
+            .ok_or_else(|| anyhow::anyhow!("coded buffer without a matching frame"))?;
+        let type_ = if is_key_chunk(self.codec, &coded) {
+            EncodedVideoChunkType::Key
+        } else {
+            EncodedVideoChunkType::Delta
+        };
+
+        let avc =
+            self.codec == TrackCodec::H264 && self.config.avc_format == AvcBitstreamFormat::Avc;
+        let mut decoder_config =
+            self.decoder_config
+                .clone()
+                .unwrap_or_else(|| VideoDecoderConfig {
+                    codec: self.config.codec.clone(),
+                    description: None,
+                });
+        let data = if avc {
+            if type_ == EncodedVideoChunkType::Key {
+                let record = AvcDecoderConfigurationRecord::from_annexb(&coded.bitstream, 4)?;
+                let mut description = Vec::new();
+                record.write_into(&mut description)?;
+                decoder_config.description = Some(description);
+            }
+            annexb_to_length_prefixed(&coded.bitstream, 4)?
+        } else {
+            coded.bitstream.into()
+        };
+
+        let mut metadata = EncodedVideoChunkMetadata::default();
+        if self.decoder_config.as_ref() != Some(&decoder_config) {
+            self.decoder_config = Some(decoder_config.clone());
+            metadata.decoder_config = Some(decoder_config);
+        }
+
+        let chunk = EncodedVideoChunk {
+            type_,
+            timestamp: timing.timestamp,
+            duration: timing.duration,
+            data,
+        };
+
+        Ok((chunk, metadata))
+    }
+
+    fn poll_output(&mut self) -> anyhow::Result<()> {
+        loop {
+            let encoder = self
+                .encoder
+                .as_deref_mut()
+                .ok_or_else(|| anyhow::anyhow!("no encoder"))?;
+            let Some(coded) = encoder.poll()? else {
+                return Ok(());
+            };
+
+            let (chunk, metadata) = self.make_chunk(coded)?;
+            (self.output)(chunk, metadata);
+        }
+    }
+
+    fn encode_frame(
+        &mut self,
+        frame: VideoFrame<H>,
+        layout: FrameLayout,
+        options: VideoEncoderEncodeOptions,
+    ) -> anyhow::Result<()> {
+        let meta = FrameMetadata {
+            timestamp: self.timings.push(frame.timestamp, frame.duration),
+            display_resolution: Resolution::from((self.config.width, self.config.height)),
+            layout,
+            force_keyframe: options.key_frame,
+            damage: None,
+        };
+
+        let encoder = self
+            .encoder
+            .as_deref_mut()
+            .ok_or_else(|| anyhow::anyhow!("no encoder"))?;
+        encoder.encode(meta, frame.handle)?;
+
+        self.poll_output()
+    }
+
+    /// Encodes `frame`, whose memory layout is `layout`. The resulting chunk is passed to the
+    /// output callback with its timestamp and duration, possibly during a later call.
+    ///
+    /// Errors while encoding are reported to the error callback and close the encoder.
+    pub fn encode(
+        &mut self,
+        frame: VideoFrame<H>,
+        layout: FrameLayout,
+        options: VideoEncoderEncodeOptions,
+    ) -> Result<(), WebCodecsError> {
+        if self.state != CodecState::Configured {
+            return Err(WebCodecsError::InvalidState("encoder is not configured"));
+        }
+
+        if let Err(e) = self.encode_frame(frame, layout, options) {
+            self.fail(WebCodecsError::Encoding(e));
+        }
+
+        Ok(())
+    }
+
+    /// Outputs the chunks of all the frames encoded so far.
+    pub fn flush(&mut self) -> Result<(), WebCodecsError> {
+        if self.state != CodecState::Configured {
+            return Err(WebCodecsError::InvalidState("encoder is not configured"));
+        }
+
+        let result = match self.encoder.as_deref_mut() {
+            Some(encoder) => encoder
+                .drain()
+                .map_err(anyhow::Error::from)
+                .and_then(|()| self.poll_output()),
+            None => Ok(()),
+        };
+
+        result.map_err(|e| self.fail(WebCodecsError::Encoding(e)))
+    }
+
+    /// Discards the encoder and all its pending frames, returning to the unconfigured state.
+    pub fn reset(&mut self) -> Result<(), WebCodecsError> {
+        if self.state == CodecState::Closed {
+            return Err(WebCodecsError::InvalidState("encoder is closed"));
+        }
+
+        self.encoder = None;
+        self.timings.clear();
+        self.state = CodecState::Unconfigured;
+
+        Ok(())
+    }
+
+    /// Discards the encoder and all its pending frames. The encoder cannot be used anymore.
+    pub fn close(&mut self) {
+        self.encoder = None;
+        self.timings.clear();
+        self.state = CodecState::Closed;
+    }
+
+    /// Creates an unconfigured H.264 encoder using VAAPI on `display`, encoding NV12 frames.
+    #[cfg(feature = "vaapi")]
+    pub fn new_vaapi<M>(
+        display: std::rc::Rc<libva::Display>,
+        output: ChunkCallback,
+        error: ErrorCallback,
+    ) -> Self
+    where
+        M: libva::SurfaceMemoryDescriptor + 'static,
+        H: std::borrow::Borrow<libva::Surface<M>>,
+    {
+        use crate::backend::vaapi::encoder::VaapiBackend;
+        use crate::encoder::stateless::h264::EncoderConfig;
+        use crate::encoder::stateless::h264::StatelessEncoder;
+        use crate::encoder::Bitrate;
+        use crate::BlockingMode;
+        use crate::Fourcc;
+
+        let create_encoder = move |config: &VideoEncoderConfig| {
+            anyhow::ensure!(
+                codec_from_string(&config.codec) == Some(TrackCodec::H264),
+                "only H.264 encoding is supported"
+            );
+
+            let resolution = Resolution::from((config.width, config.height));
+            let mut encoder_config = EncoderConfig {
+                resolution,
+                ..Default::default()
+            };
+            if let Some((profile, level)) = avc_profile_level(&config.codec) {
+                encoder_config.profile = profile;
+                encoder_config.level = level;
+            }
+            if let Some(bitrate) = config.bitrate {
+                encoder_config.bitrate = Bitrate::Constant(bitrate);
+            }
+            if let Some(framerate) = config.framerate {
+                encoder_config.framerate = (framerate.round() as u32).max(1);
+            }
+
+            let encoder = StatelessEncoder::<H, VaapiBackend<M, H>>::new_vaapi(
+                std::rc::Rc::clone(&display),
+                encoder_config,
+                Fourcc::from(b"NV12"),
+                resolution,
+                false,
+                BlockingMode::Blocking,
+            )?;
+
+            Ok(Box::new(encoder) as Box<dyn StatelessVideoEncoder<H>>)
+        };
+
+        Self::new(output, error, Box::new(create_encoder))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::collections::VecDeque;
+    use std::rc::Rc;
+
+    use super::*;
+    use crate::codec::h264::nalu::split_annexb;
+    use crate::encoder::stateless::EncodeResult;
+    use crate::utils::ivf::IvfReader;
+    use crate::Fourcc;
+    use crate::PlaneLayout;
+
+    const KEY_FRAME: &[u8] = include_bytes!("codec/h264/test_data/64x64-I.h264");
+    const STREAM_AV1: &[u8] = include_bytes!("codec/av1/test_data/test-25fps.ivf.av1");
+    const DELTA_FRAME: &[u8] = &[0, 0, 0, 1, 0x41, 0x9a, 0x00];
+
+    /// Encoder outputting each frame after the next one, as a keyframe when forced.
+    struct ReorderingEncoder {
+        pending: VecDeque<FrameMetadata>,
+        output: VecDeque<CodedBitstreamBuffer>,
+    }
+
+    impl ReorderingEncoder {
+        fn output(&mut self, meta: FrameMetadata) {
+            let bitstream = if meta.force_keyframe {
+                KEY_FRAME
+            } else {
+                DELTA_FRAME
+            };
+            self.output
+                .push_back(CodedBitstreamBuffer::new(meta, bitstream.to_vec()));
+        }
+    }
+
+    impl StatelessVideoEncoder<()> for ReorderingEncoder {
+        fn encode(&mut self, meta: FrameMetadata, _: ()) -> EncodeResult<()> {
+            self.pending.push_back(meta);
+            if self.pending.len() > 1 {
+                let meta = self.pending.pop_front().unwrap();
+                self.output(meta);
+            }
+
+            Ok(())
+        }
+
+        fn drain(&mut self) -> EncodeResult<()> {
+            while let Some(meta) = self.pending.pop_front() {
+                self.output(meta);
+            }
+
+            Ok(())
+        }
+
+        fn poll(&mut self) -> EncodeResult<Option<CodedBitstreamBuffer>> {
+            Ok(self.output.pop_front())
+        }
+
+        fn request_segment_point(&mut self) {}
+    }
+
+    type Chunks = Rc<RefCell<Vec<(EncodedVideoChunk, EncodedVideoChunkMetadata)>>>;
+
+    fn test_encoder(chunks: &Chunks) -> VideoEncoder<()> {
+        let chunks = Rc::clone(chunks);
+        VideoEncoder::new(
+            Box::new(move |chunk, metadata| chunks.borrow_mut().push((chunk, metadata))),
+            Box::new(|e| panic!("unexpected error: {}", e)),
+            Box::new(|_| {
+                Ok(Box::new(ReorderingEncoder {
+                    pending: Default::default(),
+                    output: Default::default(),
+                }))
+            }),
+        )
+    }
+
+    fn layout() -> FrameLayout {
+        FrameLayout {
+            format: (Fourcc::from(b"NV12"), 0),
+            size: Resolution::from((64, 64)),
+            planes: vec![
+                PlaneLayout {
+                    buffer_index: 0,
+                    offset: 0,
+                    stride: 64,
+                },
+                PlaneLayout {
+                    buffer_index: 0,
+                    offset: 64 * 64,
+                    stride: 64,
+                },
+            ],
+        }
+    }
+
+    fn frame(timestamp: i64) -> VideoFrame<()> {
+        VideoFrame {
+            timestamp,
+            duration: Some(33_333),
+            handle: (),
+        }
+    }
+
+    #[test]
+    fn codec_strings() {
+        assert_eq!(codec_from_string("avc1.42E01E"), Some(TrackCodec::H264));
+        assert_eq!(codec_from_string("hev1.1.6.L93.B0"), Some(TrackCodec::H265));
+        assert_eq!(codec_from_string("vp8"), Some(TrackCodec::Vp8));
+        assert_eq!(codec_from_string("vp09.00.10.08"), Some(TrackCodec::Vp9));
+        assert_eq!(codec_from_string("av01.0.04M.08"), Some(TrackCodec::Av1));
+        assert_eq!(codec_from_string("mp4a.40.2"), None);
+
+        let (profile, level) = avc_profile_level("avc1.64001F").unwrap();
+        assert_eq!(profile as u8, 100);
+        assert_eq!(level, Level::L3_1);
+        assert!(avc_profile_level("avc1.64001").is_none());
+        assert!(avc_profile_level("vp8").is_none());
+    }
+
+    #[test]
+    fn av1_key_temporal_units() {
+        let temporal_units = IvfReader::new(STREAM_AV1)
+            .unwrap()
+            .map(|frame| frame.data.to_vec())
+            .collect::<Vec<_>>();
+
+        // Only the first temporal unit starts with a sequence header and a key frame.
+        let keys = temporal_units
+            .iter()
+            .map(|data| is_av1_key_temporal_unit(data))
+            .collect::<Vec<_>>();
+        assert!(keys[0]);
+        assert!(keys[1..].iter().all(|&key| !key));
+
+        // A key frame cannot be decoded on its own without the sequence header.
+        let mut parser = Parser::default();
+        let ParsedObu::Process(obu) = parser.parse_obu(&temporal_units[0]).unwrap() else {
+            panic!("temporal delimiter dropped");
+        };
+        let ParsedObu::Process(sequence_header) = parser
+            .parse_obu(&temporal_units[0][obu.data.len()..])
+            .unwrap()
+        else {
+            panic!("sequence header dropped");
+        };
+        assert_eq!(sequence_header.header.obu_type, ObuType::SequenceHeader);
+        let mut without_sequence_header = obu.data.to_vec();
+        without_sequence_header
+            .extend(&temporal_units[0][obu.data.len() + sequence_header.data.len()..]);
+        assert!(!is_av1_key_temporal_unit(&without_sequence_header));
+    }
+
+    #[test]
+    fn encoder_state() {
+        let chunks = Chunks::default();
+        let mut encoder = test_encoder(&chunks);
+        let options = VideoEncoderEncodeOptions::default();
+
+        assert!(matches!(
+            encoder.encode(frame(0), layout(), options),
+            Err(WebCodecsError::InvalidState(_))
+        ));
+
+        let config = VideoEncoderConfig {
+            codec: "avc1.42E01E".into(),
+            width: 64,
+            height: 64,
+            ..Default::default()
+        };
+        encoder.configure(&config).unwrap();
+        assert_eq!(encoder.state(), CodecState::Configured);
+
+        encoder.reset().unwrap();
+        assert_eq!(encoder.state(), CodecState::Unconfigured);
+        assert!(encoder.flush().is_err());
+
+        encoder.close();
+        assert!(matches!(
+            encoder.configure(&config),
+            Err(WebCodecsError::InvalidState(_))
+        ));
+    }
+
+    #[test]
+    fn encoder_chunks() {
+        let chunks = Chunks::default();
+        let mut encoder = test_encoder(&chunks);
+        encoder
+            .configure(&VideoEncoderConfig {
+                codec: "avc1.42E01E".into(),
+                width: 64,
+                height: 64,
+                avc_format: AvcBitstreamFormat::Avc,
+                ..Default::default()
+            })
+            .unwrap();
+
+        let key = VideoEncoderEncodeOptions { key_frame: true };
+        encoder.encode(frame(1000), layout(), key).unwrap();
+        assert!(chunks.borrow().is_empty());
+        encoder
+            .encode(frame(2000), layout(), Default::default())
+            .unwrap();
+        encoder.flush().unwrap();
+
+        let chunks = chunks.borrow();
+        assert_eq!(chunks.len(), 2);
+
+        let (chunk, metadata) = &chunks[0];
+        assert_eq!(chunk.type_, EncodedVideoChunkType::Key);
+        assert_eq!(chunk.timestamp, 1000);
+        assert_eq!(chunk.duration, Some(33_333));
+        let description = metadata
+            .decoder_config
+            .as_ref()
+            .and_then(|config| config.description.as_ref())
+            .unwrap();
+        let record = AvcDecoderConfigurationRecord::parse(description).unwrap();
+        assert_eq!(record.nal_length_size(), 4);
+        // The chunk is length-prefixed.
+        assert_eq!(
+            split_annexb(&length_prefixed_to_annexb(&chunk.data, 4).unwrap()),
+            split_annexb(KEY_FRAME)
+        );
+
+        let (chunk, metadata) = &chunks[1];
+        assert_eq!(chunk.type_, EncodedVideoChunkType::Delta);
+        assert_eq!(chunk.timestamp, 2000);
+        assert!(metadata.decoder_config.is_none());
+    }
+
+    #[test]
+    fn decoder_state() {
+        let mut decoder = VideoDecoder::<crate::backend::dummy::decoder::Backend>::new(
+            Box::new(|_| ()),
+            Box::new(|_| ()),
+            Box::new(|_| Err(anyhow::anyhow!("no decoder available"))),
+            Box::new(|_, _| Ok(Vec::new())),
+            DecodedFormat::NV12,
+        );
+
+        let chunk = EncodedVideoChunk {
+            type_: EncodedVideoChunkType::Key,
+            timestamp: 0,
+            duration: None,
+            data: KEY_FRAME.to_vec(),
+        };
+        assert!(matches!(
+            decoder.decode(&chunk),
+            Err(WebCodecsError::InvalidState(_))
+        ));
+
+        // Failing to create the decoder closes it.
+        let config = VideoDecoderConfig {
+            codec: "avc1.42E01E".into(),
+            description: None,
+        };
+        assert!(matches!(
+            decoder.configure(&config),
+            Err(WebCodecsError::NotSupported(_))
+        ));
+        assert_eq!(decoder.state(), CodecState::Closed);
+        assert!(decoder.reset().is_err());
+    }
+}